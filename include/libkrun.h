@@ -18,6 +18,32 @@
  */
 int32_t krun_set_log_level(uint32_t level);
 
+/* Formats of the log lines */
+#define KRUN_LOG_FORMAT_TEXT 0
+#define KRUN_LOG_FORMAT_JSON 1
+/**
+ * Initializes the logging of the library. Either this or krun_set_log_level may be called,
+ * only once.
+ *
+ * Arguments:
+ *  "level"   - the default log level, with the same values as in krun_set_log_level.
+ *  "format"  - KRUN_LOG_FORMAT_TEXT for human readable lines, or KRUN_LOG_FORMAT_JSON for one
+ *              JSON object per line, with the "timestamp", "level", "target", "message" and
+ *              "fields" keys.
+ *  "filters" - a null-terminated string overriding the level of some modules, such as
+ *              "vmm::linux::tee::amdsev=trace,devices=warn", or NULL.
+ *
+ * Notes:
+ * The values of the fields holding secrets, such as tokens or keys, are redacted from the JSON
+ * lines. As with krun_set_log_level, the RUST_LOG environment variable takes precedence over
+ * "level".
+ *
+ * Returns:
+ *  Zero on success, -EEXIST if the logging was already initialized, or a negative error number
+ *  on failure.
+ */
+int32_t krun_init_log(uint32_t level, uint32_t format, const char *filters);
+
 /**
  * Creates a configuration context.
  *
@@ -50,6 +76,76 @@ int32_t krun_free_ctx(uint32_t ctx_id);
  */
 int32_t krun_set_vm_config(uint32_t ctx_id, uint8_t num_vcpus, uint32_t ram_mib);
 
+/**
+ * Sets how the vCPUs are laid out in sockets, cores and threads, as reported to the guest
+ * through CPUID. Only available on x86_64.
+ *
+ * Arguments:
+ *  "ctx_id"           - the configuration context ID.
+ *  "sockets"          - the number of sockets.
+ *  "cores_per_socket" - the number of cores in each socket.
+ *  "threads_per_core" - the number of threads in each core.
+ *
+ * Notes:
+ * The product of the three values must be the number of vCPUs. If krun_set_vm_config() is called
+ * afterwards, its vCPU number must still match it; otherwise, the number of vCPUs is set to the
+ * product. The APIC ID of each vCPU is its index, so "threads_per_core" must be a power of 2, as
+ * well as "cores_per_socket" when there is more than one socket. There is no ACPI MADT in the
+ * guest, which finds the vCPUs through the MP table instead.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if the topology is invalid or
+ *  doesn't match the number of vCPUs and -ENOTSUP on other architectures.
+ */
+int32_t krun_set_cpu_topology(uint32_t ctx_id,
+                              uint8_t sockets,
+                              uint8_t cores_per_socket,
+                              uint8_t threads_per_core);
+
+/**
+ * Masks the CPU features the guest sees through CPUID with a template, so it gets the same ones
+ * on any host providing them. Only available on x86_64.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "template" - the name of the template: "x86-64-v2" or "x86-64-v3" for the microarchitecture
+ *               levels of the x86-64 psABI, masking the instruction set extensions beyond them,
+ *               or "T2" or "C3" for the features of those EC2 instances.
+ *
+ * Notes:
+ * The template only masks features, the guest still lacks those the host doesn't provide. For
+ * SEV-SNP guests, the CPUID page measured at launch is masked the same way.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if the template is unknown and
+ *  -ENOTSUP on other architectures.
+ */
+int32_t krun_set_cpu_template(uint32_t ctx_id, const char *template);
+
+/**
+ * Backs the guest RAM with hugepages. Only available on Linux.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "page_size_kib"  - the size of the hugepages in KiB, either 2048 (2 MiB) or 1048576 (1 GiB).
+ *  "hugetlbfs_path" - a null-terminated string with a directory in a hugetlbfs mount with pages
+ *                     of that size to allocate the memory from, or NULL to use anonymous
+ *                     hugepages.
+ *
+ * Notes:
+ * The hugepages must be reserved in the host beforehand, for instance through
+ * /proc/sys/vm/nr_hugepages or the nr_hugepages file of the page size in
+ * /sys/kernel/mm/hugepages, and starting the microVM fails if there aren't enough of them. The
+ * guest RAM is made of a few regions, laid out around the kernel and the 32-bit MMIO hole, and
+ * each one is rounded up to whole hugepages, so 1 GiB pages are only worth it for large guests.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if the page size isn't
+ *  supported, -ENOTDIR if "hugetlbfs_path" isn't a directory and -ENOTSUP on other operating
+ *  systems.
+ */
+int32_t krun_set_hugepages(uint32_t ctx_id, uint32_t page_size_kib, const char *hugetlbfs_path);
+
 /**
  * Sets the path to be use as root for the microVM. Not available in libkrun-SEV.
  *
@@ -62,6 +158,90 @@ int32_t krun_set_vm_config(uint32_t ctx_id, uint8_t num_vcpus, uint32_t ram_mib)
  */
 int32_t krun_set_root(uint32_t ctx_id, const char *root_path);
 
+/**
+ * Sets the initramfs image to boot the kernel with.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "initrd_path" - a null-terminated string with the path of the image, in any format the
+ *                  kernel can unpack, such as a compressed cpio archive.
+ *
+ * Notes:
+ * The image is loaded as high as possible in the guest RAM, below 2 GiB on x86_64 or right below
+ * the device tree on aarch64, so the microVM needs enough memory to hold it. In libkrun-SEV it
+ * replaces the initrd bundled with the firmware and is part of the launch measurement.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if the image is empty.
+ */
+int32_t krun_set_initrd(uint32_t ctx_id, const char *initrd_path);
+
+/**
+ * Boots the microVM with a UEFI firmware, such as OVMF, that loads the guest OS from the disks,
+ * for guests needing UEFI services like secure boot or EFI variables. Only available on x86_64.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "firmware_path" - a null-terminated string with the path of the firmware image, either a
+ *                    unified one (OVMF.fd) or its code part (OVMF_CODE.fd).
+ *  "varstore_path" - a null-terminated string with the path of the NVRAM varstore holding the EFI
+ *                    variables (OVMF_VARS.fd), or NULL to have none.
+ *
+ * Notes:
+ * The firmware image is mapped right below 4 GiB, where the vCPUs start running from the reset
+ * vector, and the varstore right below it. The varstore is mapped shared, so the variables the
+ * guest sets are kept in the file. Both must be a multiple of 4 KiB in size, and 16 MiB at most
+ * together.
+ *
+ * In libkrun-SEV the firmware replaces the one bundled with libkrunfw and is part of the launch
+ * measurement. A varstore can't be used there, as its contents wouldn't be measured.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if the image or the varstore
+ *  has an invalid size and -ENOTSUP if a varstore is given in libkrun-SEV.
+ */
+int32_t krun_set_firmware(uint32_t ctx_id, const char *firmware_path, const char *varstore_path);
+
+/* Guest clocksources, set by krun_set_clocksource. */
+#define KRUN_CLOCKSOURCE_KVM_CLOCK 0
+#define KRUN_CLOCKSOURCE_TSC 1
+
+/**
+ * Selects the clocksource the guest kernel keeps time with, through the kernel command line. By
+ * default, the guest kernel picks one itself, usually the KVM paravirtualized clock. Only
+ * available on x86_64.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "clocksource" - KRUN_CLOCKSOURCE_KVM_CLOCK for the KVM paravirtualized clock, or
+ *                  KRUN_CLOCKSOURCE_TSC for the TSC, which is cheaper to read. With the latter,
+ *                  the guest trusts the TSC without checking it against another clock, so its
+ *                  frequency should be pinned with "krun_set_tsc_khz" if the microVM may be
+ *                  restored on another host.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_clocksource(uint32_t ctx_id, uint32_t clocksource);
+
+/**
+ * Pins the frequency of the guest TSC, instead of exposing the one of the host. Only available on
+ * x86_64.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "tsc_khz" - the frequency of the guest TSC, in kHz.
+ *
+ * Notes:
+ * A frequency other than the host one needs TSC scaling, supported by recent Intel and AMD
+ * CPUs.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. Starting the microVM fails if the
+ *  host can't scale the TSC to the given frequency.
+ */
+int32_t krun_set_tsc_khz(uint32_t ctx_id, uint32_t tsc_khz);
+
 /**
  * DEPRECATED. Use krun_add_disk instead.
  *
@@ -112,8 +292,14 @@ int32_t krun_set_data_disk(uint32_t ctx_id, const char *disk_path);
  *  "read_only" - whether the mount should be read-only. Required if the caller does not have
  *                write permissions (for disk images in /usr/share).
  *
+ * Notes:
+ * Several disks may be added, each one with a unique "block_id". The guest sees them in the
+ * order they were added, across krun_add_disk and krun_add_disk2: the first one as /dev/vda,
+ * the second one as /dev/vdb, and so on.
+ *
  * Returns:
- *  Zero on success or a negative error number on failure.
+ *  Zero on success, -EEXIST if another disk uses the same "block_id", or a negative error
+ *  number on failure.
  */
 int32_t krun_add_disk(uint32_t ctx_id, const char *block_id, const char *disk_path, bool read_only);
 
@@ -161,8 +347,14 @@ int32_t krun_add_disk(uint32_t ctx_id, const char *block_id, const char *disk_pa
  *  "read_only"   - whether the mount should be read-only. Required if the caller does not have
  *                  write permissions (for disk images in /usr/share).
  *
+ * Notes:
+ * Disks are exposed to the guest in the order they were added, see krun_add_disk.
+ * Writable raw images support discard: ranges the guest discards are punched out of the
+ * image file, releasing the host storage backing them.
+ *
  * Returns:
- *  Zero on success or a negative error number on failure.
+ *  Zero on success, -EEXIST if another disk uses the same "block_id", or a negative error
+ *  number on failure.
  */
 int32_t krun_add_disk2(uint32_t ctx_id,
                        const char *block_id,
@@ -170,6 +362,28 @@ int32_t krun_add_disk2(uint32_t ctx_id,
                        uint32_t disk_format,
                        bool read_only);
 
+/**
+ * Limits the rate of the I/O the guest performs on a disk.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "block_id"      - a null-terminated string representing the "block_id" the disk was added with,
+ *                    or "root"/"data" for the disks set with krun_set_root_disk/krun_set_data_disk.
+ *  "bytes_per_sec" - the maximum number of bytes per second, or zero for no limit.
+ *  "ops_per_sec"   - the maximum number of requests per second, or zero for no limit.
+ *
+ * Notes:
+ * Up to one second worth of I/O may be performed in a burst.
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if there's no disk with that "block_id", or a negative error
+ *  number on failure.
+ */
+int32_t krun_set_disk_rate_limit(uint32_t ctx_id,
+                                 const char *block_id,
+                                 uint64_t bytes_per_sec,
+                                 uint64_t ops_per_sec);
+
 /**
  * NO LONGER SUPPORTED. DO NOT USE.
  *
@@ -194,8 +408,12 @@ int32_t krun_set_mapped_volumes(uint32_t ctx_id, const char *const mapped_volume
  *  "c_tag"          - tag to identify the filesystem in the guest.
  *  "c_path"         - full path to the directory in the host to be exposed to the guest.
  *
+ * Notes:
+ * Several devices may be added, each one with a unique tag of at most 36 bytes.
+ *
  * Returns:
- *  Zero on success or a negative error number on failure.
+ *  Zero on success, -EEXIST if another device uses the same tag, or a negative error
+ *  number on failure.
  */
 int32_t krun_add_virtiofs(uint32_t ctx_id,
                           const char *c_tag,
@@ -209,16 +427,99 @@ int32_t krun_add_virtiofs(uint32_t ctx_id,
  *  "ctx_id"         - the configuration context ID.
  *  "c_tag"          - tag to identify the filesystem in the guest.
  *  "c_path"         - full path to the directory in the host to be exposed to the guest.
- *  "shm_size"       - size of the DAX SHM window in bytes.
+ *  "shm_size"       - size of the DAX SHM window in bytes, rounded up to 2 MiB.
+ *
+ * Notes:
+ * Several devices may be added, each one with a unique tag of at most 36 bytes.
+ * The DAX windows are placed past the guest RAM and must all fit in the guest
+ * physical address space, otherwise krun_start_enter fails.
  *
  * Returns:
- *  Zero on success or a negative error number on failure.
+ *  Zero on success, -EEXIST if another device uses the same tag, or a negative error
+ *  number on failure.
  */
 int32_t krun_add_virtiofs2(uint32_t ctx_id,
                            const char *c_tag,
                            const char *c_path,
                            uint64_t shm_size);
 
+/**
+ * Adds an independent virtio-fs device pointing to a host's directory with a tag. This
+ * variant allows specifying the size of the DAX window and making the device read-only.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "c_tag"          - tag to identify the filesystem in the guest.
+ *  "c_path"         - full path to the directory in the host to be exposed to the guest.
+ *  "shm_size"       - size of the DAX SHM window in bytes, rounded up to 2 MiB.
+ *  "read_only"      - whether any modification from the guest must fail with EROFS,
+ *                     regardless of the permissions of the files in the host.
+ *
+ * Notes:
+ * Several devices may be added, each one with a unique tag of at most 36 bytes.
+ * The DAX windows are placed past the guest RAM and must all fit in the guest
+ * physical address space, otherwise krun_start_enter fails.
+ *
+ * Returns:
+ *  Zero on success, -EEXIST if another device uses the same tag, or a negative error
+ *  number on failure.
+ */
+int32_t krun_add_virtiofs3(uint32_t ctx_id,
+                           const char *c_tag,
+                           const char *c_path,
+                           uint64_t shm_size,
+                           bool read_only);
+
+/**
+ * Maps a range of user IDs between the guest and the host on a virtio-fs device. The owners of
+ * the files are translated to guest IDs, and the guest processes access the files, and own the
+ * ones they create, with the host IDs they map to. For instance, mapping the guest root and the
+ * users after it to an unprivileged range of host IDs lets a rootless container own its files.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "c_tag"     - the tag of a device added with one of the krun_add_virtiofs functions.
+ *  "guest_uid" - the first user ID of the range in the guest.
+ *  "host_uid"  - the first user ID of the range on the host.
+ *  "count"     - the number of IDs in the range.
+ *
+ * Notes:
+ * It may be called several times to map multiple ranges, which can't overlap either in the
+ * guest or on the host. Without any range, the IDs are left untouched. Otherwise, files owned by
+ * unmapped host IDs appear as owned by 65534 (nobody), guest processes with an unmapped ID act
+ * as host ID 65534, and changing the owner of a file to an unmapped ID fails with EINVAL.
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if no device uses the tag, -EINVAL if the range is empty or overlaps
+ *  another one, or a negative error number on failure.
+ */
+int32_t krun_add_virtiofs_uid_map(uint32_t ctx_id,
+                                  const char *c_tag,
+                                  uint32_t guest_uid,
+                                  uint32_t host_uid,
+                                  uint32_t count);
+
+/**
+ * Maps a range of group IDs between the guest and the host on a virtio-fs device, as
+ * "krun_add_virtiofs_uid_map" does for user IDs.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "c_tag"     - the tag of a device added with one of the krun_add_virtiofs functions.
+ *  "guest_gid" - the first group ID of the range in the guest.
+ *  "host_gid"  - the first group ID of the range on the host.
+ *  "count"     - the number of IDs in the range.
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if no device uses the tag, -EINVAL if the range is empty or overlaps
+ *  another one, or a negative error number on failure.
+ */
+int32_t krun_add_virtiofs_gid_map(uint32_t ctx_id,
+                                  const char *c_tag,
+                                  uint32_t guest_gid,
+                                  uint32_t host_gid,
+                                  uint32_t count);
+
 /**
  * Configures the networking to use passt.
  * Call to this function disables TSI backend to use passt instead.
@@ -266,6 +567,149 @@ int32_t krun_set_gvproxy_path(uint32_t ctx_id, char *c_path);
  */
 int32_t krun_set_net_mac(uint32_t ctx_id, uint8_t *const c_mac);
 
+/**
+ * Sets the MTU the guest uses for the virtio-net device configured with krun_set_passt_fd
+ * or krun_set_gvproxy_path.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "mtu"    - the MTU, at least 68 and no more than the backend supports (65520 for passt).
+ *
+ * Notes:
+ * The MTU is checked against the backend when the microVM starts, and krun_start_enter
+ * fails with -EINVAL if it's too big.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_net_mtu(uint32_t ctx_id, uint16_t mtu);
+
+/**
+ * Adds a virtio-net interface using passt as its backend.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "fd"     - a file descriptor to communicate with passt.
+ *
+ * Notes:
+ * Interfaces are exposed to the guest in the order they were added, after the one configured
+ * with krun_set_passt_fd or krun_set_gvproxy_path, if any. Adding an interface disables the
+ * TSI backend. Each interface gets a distinct default MAC address, which can be changed with
+ * krun_set_net_iface_mac.
+ *
+ * Returns:
+ *  The index of the new interface on success, -ENOSPC if the maximum number of interfaces
+ *  (16) was already added, or a negative error number on failure.
+ */
+int32_t krun_add_net_passt(uint32_t ctx_id, int fd);
+
+/**
+ * Adds a virtio-net interface using gvproxy in vfkit mode as its backend.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_path" - a null-terminated string representing the path for gvproxy's
+ *             listen-vfkit unixdgram socket.
+ *
+ * Notes:
+ * See krun_add_net_passt.
+ *
+ * Returns:
+ *  The index of the new interface on success, -ENOSPC if the maximum number of interfaces
+ *  (16) was already added, or a negative error number on failure.
+ */
+int32_t krun_add_net_gvproxy(uint32_t ctx_id, const char *c_path);
+
+/**
+ * Adds a virtio-net interface using a tap device of the host as its backend. Linux only.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "c_tap_name" - a null-terminated string with the name of the tap device, which is
+ *                 created if it doesn't exist.
+ *
+ * Notes:
+ * See krun_add_net_passt. Creating the tap device, or attaching to one owned by another
+ * user, requires CAP_NET_ADMIN. The datapath can be moved to the kernel with
+ * krun_set_net_iface_vhost.
+ *
+ * Returns:
+ *  The index of the new interface on success, -ENOSPC if the maximum number of interfaces
+ *  (16) was already added, or a negative error number on failure.
+ */
+int32_t krun_add_net_tap(uint32_t ctx_id, const char *c_tap_name);
+
+/**
+ * Sets the MAC address of an interface added with krun_add_net_passt, krun_add_net_gvproxy
+ * or krun_add_net_tap.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "index"  - the index returned when the interface was added.
+ *  "c_mac"  - MAC address as an array of 6 uint8_t entries. Must be a unicast address.
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if there's no interface with that index, or a negative error
+ *  number on failure.
+ */
+int32_t krun_set_net_iface_mac(uint32_t ctx_id, uint32_t index, const uint8_t *c_mac);
+
+/**
+ * Sets the MTU the guest uses for an interface added with krun_add_net_passt,
+ * krun_add_net_gvproxy or krun_add_net_tap.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "index"  - the index returned when the interface was added.
+ *  "mtu"    - the MTU, at least 68 and no more than the backend supports (65520 for passt).
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if there's no interface with that index, -EINVAL if the
+ *  backend can't carry the MTU, or a negative error number on failure.
+ */
+int32_t krun_set_net_iface_mtu(uint32_t ctx_id, uint32_t index, uint16_t mtu);
+
+/**
+ * Moves the datapath of an interface added with krun_add_net_tap to the kernel's vhost-net,
+ * for a higher throughput. Linux only.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "index"  - the index returned when the interface was added.
+ *  "enable" - whether to use vhost-net.
+ *
+ * Notes:
+ * If /dev/vhost-net can't be used when the guest activates the interface, or the interface
+ * has a rate limit, the datapath stays in userspace.
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if there's no interface with that index, -EINVAL if the
+ *  interface doesn't use a tap device, or a negative error number on failure.
+ */
+int32_t krun_set_net_iface_vhost(uint32_t ctx_id, uint32_t index, bool enable);
+
+/**
+ * Limits the rate of the traffic of an interface added with krun_add_net_passt,
+ * krun_add_net_gvproxy or krun_add_net_tap. The limits apply to each direction separately.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "index"         - the index returned when the interface was added.
+ *  "bytes_per_sec" - the maximum number of bytes per second, or zero for no limit.
+ *  "ops_per_sec"   - the maximum number of frames per second, or zero for no limit.
+ *
+ * Notes:
+ * Up to one second worth of traffic may be transferred in a burst.
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if there's no interface with that index, or a negative error
+ *  number on failure.
+ */
+int32_t krun_set_net_iface_rate_limit(uint32_t ctx_id,
+                                      uint32_t index,
+                                      uint64_t bytes_per_sec,
+                                      uint64_t ops_per_sec);
+
 /**
  * Configures a map of host to guest TCP ports for the microVM.
  *
@@ -333,6 +777,25 @@ int32_t krun_set_gpu_options2(uint32_t ctx_id,
                               uint32_t virgl_flags,
                               uint64_t shm_size);
 
+/**
+ * Sets the DRM render node (e.g. "/dev/dri/renderD128") the virtio-gpu device renders with,
+ * instead of the first usable one. This requires VIRGLRENDERER_USE_EGL in the virgl flags, or
+ * krun_start_enter() fails.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "render_node" - a null-terminated string with the path to the render node.
+ *
+ * Notes:
+ *  On Linux, if the render node, or with none set, any render node on the host, can't be
+ *  opened, the virtio-gpu device falls back to 2D, without 3D acceleration, and a warning is
+ *  logged.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_gpu_render_node(uint32_t ctx_id, const char *render_node);
+
 /**
  * Enables or disables a virtio-snd device.
  *
@@ -358,67 +821,246 @@ int32_t krun_set_snd_device(uint32_t ctx_id, bool enable);
 int32_t krun_set_rlimits(uint32_t ctx_id, const char *const rlimits[]);
 
 /**
- * Sets the SMBIOS OEM Strings.
+ * Sets a resource limit of the VMM process itself, applied before the microVM is built. Setting
+ * a limit on the same resource again replaces the previous one.
  *
  * Arguments:
- *  "ctx_id"      - the configuration context ID.
- *  "oem_strings" - an array of string pointers. Must be terminated with an additional NULL pointer.
+ *  "ctx_id"   - the configuration context ID.
+ *  "resource" - one of the host's RLIMIT_* resources, as defined in <sys/resource.h>.
+ *  "cur"      - the soft limit.
+ *  "max"      - the hard limit, which can't be below the soft one.
+ *
+ * Notes:
+ *  Locking the guest memory of SEV-SNP microVMs may need a higher RLIMIT_MEMLOCK. Failing to apply
+ *  a limit makes krun_start_enter() fail.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  */
-int32_t krun_set_smbios_oem_strings(uint32_t ctx_id, const char *const oem_strings[]);
+int32_t krun_set_host_rlimit(uint32_t ctx_id, uint32_t resource, uint64_t cur, uint64_t max);
 
 /**
- * Sets the working directory for the executable to be run inside the microVM.
+ * Pins the thread of a vCPU to a set of host CPUs. Only available on Linux.
  *
  * Arguments:
- *  "ctx_id"        - the configuration context ID.
- *  "workdir_path"  - the path to the working directory, relative to the root configured with
- *                    "krun_set_root".
+ *  "ctx_id"   - the configuration context ID.
+ *  "vcpu"     - the index of the vCPU, starting from zero.
+ *  "cpus"     - an array of host CPU numbers, below CPU_SETSIZE.
+ *  "num_cpus" - the number of entries of "cpus", which can't be zero.
+ *
+ * Notes:
+ *  krun_start_enter() fails if the microVM doesn't have the vCPU, or if the thread can't be
+ *  pinned to the CPUs.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  */
-int32_t krun_set_workdir(uint32_t ctx_id,
-                         const char *workdir_path);
+int32_t krun_set_vcpu_affinity(uint32_t ctx_id, uint8_t vcpu, const uint32_t *cpus, size_t num_cpus);
 
 /**
- * Sets the path to the executable to be run inside the microVM, the arguments to be passed to the
- * executable, and the environment variables to be configured in the context of the executable.
+ * Adds a NUMA node to the guest, after the ones already added. Only available on Linux x86_64,
+ * and not in the TEE flavors.
  *
  * Arguments:
- *  "ctx_id"    - the configuration context ID.
- *  "exec_path" - the path to the executable, relative to the root configured with "krun_set_root".
- *  "argv"      - an array of string pointers to be passed as arguments.
- *  "envp"      - an array of string pointers to be injected as environment variables into the
- *                context of the executable. If NULL, it will auto-generate an array collecting the
- *                the variables currently present in the environment.
+ *  "ctx_id"       - the configuration context ID.
+ *  "mem_size_mib" - the amount of guest RAM of the node, in MiB.
+ *  "vcpus"        - an array with the indexes of the vCPUs of the node.
+ *  "num_vcpus"    - the number of entries of "vcpus", which can't be zero.
+ *  "host_node"    - the host NUMA node the memory of the node is bound to, or -1 to leave it
+ *                   unbound.
+ *
+ * Notes:
+ *  The guest RAM is split between the nodes in the order they were added, the last one also
+ *  getting the memory the VMM adds to the configured size. The node sizes must add up to the
+ *  memory set with krun_set_vm_config(), and each vCPU must be part of exactly one node, or
+ *  krun_start_enter() fails. The topology is described to the guest through the ACPI SRAT and
+ *  SLIT tables.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  */
-int32_t krun_set_exec(uint32_t ctx_id,
-                      const char *exec_path,
-                      const char *const argv[],
-                      const char *const envp[]);
+int32_t krun_add_numa_node(uint32_t ctx_id, uint32_t mem_size_mib, const uint8_t *vcpus,
+                           size_t num_vcpus, int32_t host_node);
 
 /**
- * Sets environment variables to be configured in the context of the executable.
+ * Sets the distances between the NUMA nodes of the guest. If not set, the distance between
+ * different nodes is 20.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
- *  "envp"      - an array of string pointers to be injected as environment variables into the
- *                context of the executable. If NULL, it will auto-generate an array collecting the
- *                the variables currently present in the environment.
+ *  "distances" - a "num_nodes" x "num_nodes" matrix, row by row, where the distance from a node
+ *                to itself is 10 and the others are between 11 and 254.
+ *  "num_nodes" - the number of NUMA nodes, which must match the ones added with
+ *                krun_add_numa_node() by the time krun_start_enter() is called.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  */
-int32_t krun_set_env(uint32_t ctx_id, const char *const envp[]);
+int32_t krun_set_numa_distances(uint32_t ctx_id, const uint8_t *distances, size_t num_nodes);
 
 /**
- * Sets the file path to the TEE configuration file. Only available in libkrun-sev.
+ * Sets the SMBIOS OEM Strings.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "oem_strings" - an array of up to 255 non-empty string pointers. Must be terminated with an
+ *                  additional NULL pointer.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_smbios_oem_strings(uint32_t ctx_id, const char *const oem_strings[]);
+
+#define KRUN_SMBIOS_BIOS_VENDOR 0
+#define KRUN_SMBIOS_BIOS_VERSION 1
+#define KRUN_SMBIOS_BIOS_RELEASE_DATE 2
+#define KRUN_SMBIOS_SYSTEM_MANUFACTURER 3
+#define KRUN_SMBIOS_SYSTEM_PRODUCT_NAME 4
+#define KRUN_SMBIOS_SYSTEM_VERSION 5
+#define KRUN_SMBIOS_SYSTEM_SERIAL_NUMBER 6
+#define KRUN_SMBIOS_SYSTEM_UUID 7
+#define KRUN_SMBIOS_SYSTEM_SKU_NUMBER 8
+#define KRUN_SMBIOS_SYSTEM_FAMILY 9
+
+/**
+ * Sets a field of the SMBIOS BIOS Information (Type 0) or System Information (Type 1) structures,
+ * overriding the default value if it has one.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "field"  - one of the KRUN_SMBIOS_* fields.
+ *  "value"  - the value of the field. KRUN_SMBIOS_BIOS_RELEASE_DATE takes a date in the mm/dd/yyyy
+ *             format and KRUN_SMBIOS_SYSTEM_UUID a UUID in the xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx
+ *             format. Other fields take any non-empty string.
+ *
+ * Notes:
+ *  The SMBIOS tables are only built for EFI guests.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_smbios_field(uint32_t ctx_id, uint32_t field, const char *value);
+
+/**
+ * Sets the working directory for the executable to be run inside the microVM.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "workdir_path"  - the path to the working directory, relative to the root configured with
+ *                    "krun_set_root".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_workdir(uint32_t ctx_id,
+                         const char *workdir_path);
+
+/**
+ * Sets the path to the executable to be run inside the microVM, the arguments to be passed to the
+ * executable, and the environment variables to be configured in the context of the executable.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "exec_path" - the path to the executable, relative to the root configured with "krun_set_root".
+ *  "argv"      - an array of string pointers to be passed as arguments.
+ *  "envp"      - an array of string pointers to be injected as environment variables into the
+ *                context of the executable. If NULL, it will auto-generate an array collecting the
+ *                the variables currently present in the environment.
+ *
+ * Notes:
+ *  These are passed to the guest init through the kernel command line. When there are more than 24
+ *  arguments or environment variables, or they take more than 1 KiB, they are handed to it through
+ *  a "krun-config" virtio-console port instead, so there's no practical limit on them.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_exec(uint32_t ctx_id,
+                      const char *exec_path,
+                      const char *const argv[],
+                      const char *const envp[]);
+
+/**
+ * Sets environment variables to be configured in the context of the executable.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "envp"      - an array of string pointers to be injected as environment variables into the
+ *                context of the executable. If NULL, it will auto-generate an array collecting the
+ *                the variables currently present in the environment.
+ *
+ * Notes:
+ *  Only the variables of an executable set with krun_set_exec can go beyond the limits of the
+ *  kernel command line.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_env(uint32_t ctx_id, const char *const envp[]);
+
+/**
+ * Sets the hostname of the guest, applied by its init before running the executable.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "hostname" - a null-terminated hostname, made of dot-separated RFC 1123 labels (letters,
+ *               digits and hyphens, not starting or ending with a hyphen) and up to 64
+ *               characters long.
+ *
+ * Notes:
+ *  It takes precedence over a HOSTNAME environment variable, which init otherwise uses, falling
+ *  back to "localhost".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if the hostname isn't valid.
+ */
+int32_t krun_set_hostname(uint32_t ctx_id, const char *hostname);
+
+/**
+ * Sets the DNS servers of the guest, which its init writes to /etc/resolv.conf before running
+ * the executable.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "servers" - a NULL-terminated array of up to 3 IPv4 or IPv6 addresses, such as "10.0.2.3", in
+ *              the order the guest resolver should try them. An empty array leaves the guest
+ *              without name servers.
+ *
+ * Notes:
+ *  Without calling this, /etc/resolv.conf is left as found in the root filesystem. Any previous
+ *  content is replaced otherwise.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if an address isn't valid or
+ *  there are more than 3 of them.
+ */
+int32_t krun_set_dns_servers(uint32_t ctx_id, const char *const servers[]);
+
+/**
+ * Appends parameters to the kernel command line built by libkrun, instead of replacing it.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "params" - a null-terminated string with space-separated kernel parameters, such as
+ *             "console=ttyS0 loglevel=7". Values containing spaces must be double-quoted.
+ *
+ * Notes:
+ * The parameters are added after the ones libkrun sets: its defaults (console=hvc0, rw, quiet...),
+ * "init=", and the environment variables set with krun_set_exec or krun_set_env. When a parameter
+ * has the same key as one of those, or as one appended earlier, the last one wins and the earlier
+ * one is dropped. For flags without a value, such as "quiet", the flag itself is the key.
+ * Overriding "init=" or the environment variables may prevent the executable from starting.
+ *
+ * It may be called several times, each call appending to the previous ones.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if "params" contains "--",
+ *  which separates the kernel parameters from the executable arguments.
+ */
+int32_t krun_append_kernel_cmdline(uint32_t ctx_id, const char *params);
+
+/**
+ * Sets the file path to the TEE configuration file. Only available in libkrun-sev.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
@@ -429,6 +1071,185 @@ int32_t krun_set_env(uint32_t ctx_id, const char *const envp[]);
  */
 int32_t krun_set_tee_config_file(uint32_t ctx_id, const char *filepath);
 
+/* Supported TEE types */
+#define KRUN_TEE_TYPE_SEV 0
+#define KRUN_TEE_TYPE_SNP 1
+/**
+ * Sets the TEE backend of the context, as an alternative to providing a TEE configuration file.
+ * Only available in libkrun-sev.
+ *
+ * The TEE configuration is built from the krun_set_tee_* calls, with the number of vCPUs and the
+ * amount of RAM set by krun_set_vm_config(), and must not be combined with
+ * krun_set_tee_config_file().
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "tee_type"  - the TEE type, one of the KRUN_TEE_TYPE_* constants.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tee_type(uint32_t ctx_id, uint32_t tee_type);
+
+/**
+ * Sets the attestation server URL, or a comma-separated list of them to be tried in order.
+ * Only available in libkrun-sev.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "url"       - a null-terminated string representing the attestation server URL.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tee_attestation_url(uint32_t ctx_id, const char *url);
+
+/**
+ * Sets the workload ID the attestation server knows this guest by. Only available in libkrun-sev.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "workload_id" - a null-terminated string representing the workload ID.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tee_workload_id(uint32_t ctx_id, const char *workload_id);
+
+/**
+ * Sets the TEE-specific data, such as the vendor chain and the launch policy for SEV.
+ * Only available in libkrun-sev.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "tee_data"  - a null-terminated string holding the JSON document of the TEE-specific data,
+ *                as in the "tee_data" field of the TEE configuration file.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tee_data(uint32_t ctx_id, const char *tee_data);
+
+/**
+ * Sets the SEV certificate chain, for embedders already holding it in memory. Only available in
+ * libkrun-sev, for SEV guests.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "chain"     - the encoded certificate chain, in the format of the "vendor_chain" file of the
+ *                TEE-specific data.
+ *  "chain_len" - the size of "chain" in bytes.
+ *
+ * Notes:
+ * The chain is copied, and takes precedence over the "vendor_chain" file. It's kept along with
+ * the rest of the TEE configuration, whether that comes from the krun_set_tee_* calls or from
+ * krun_set_tee_config_file().
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tee_vendor_chain(uint32_t ctx_id, const uint8_t *chain, size_t chain_len);
+
+/* Phases of the SEV launch, as reported to the progress callback */
+#define KRUN_TEE_PHASE_CHAIN_FETCHED        0
+#define KRUN_TEE_PHASE_SESSION_ESTABLISHED  1
+#define KRUN_TEE_PHASE_REGIONS_MEASURED     2
+#define KRUN_TEE_PHASE_MEASUREMENT_COMPUTED 3
+#define KRUN_TEE_PHASE_SECRET_INJECTED      4
+#define KRUN_TEE_PHASE_FINISHED             5
+/**
+ * Sets a callback to be invoked on each phase of the SEV launch, to show its progress. Only
+ * available in libkrun-sev, for SEV guests.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "callback"  - the function to invoke with one of the KRUN_TEE_PHASE_* codes, the time elapsed
+ *                since the launch started being set up in microseconds and "user_data", or NULL
+ *                to unset a previous one.
+ *  "user_data" - an opaque pointer passed back to "callback".
+ *
+ * Notes:
+ * The callback runs synchronously on the thread calling krun_start_enter(), before the guest
+ * starts running, so it must return promptly. KRUN_TEE_PHASE_CHAIN_FETCHED isn't reported when
+ * no certificate chain is used.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tee_progress_callback(uint32_t ctx_id,
+                                       void (*callback)(uint32_t phase, uint64_t elapsed_us,
+                                                        void *user_data),
+                                       void *user_data);
+
+/* Size of a buffer large enough for any hex-encoded TEE launch measurement or nonce */
+#define KRUN_TEE_MEASUREMENT_MAX_LEN 129
+/**
+ * Gets the launch measurement computed while attesting the guest, for logging or auditing.
+ * Only available in libkrun-sev, for SEV guests.
+ *
+ * As krun_start_enter() doesn't return once the guest is running, this must be called from
+ * another thread. The measurement is available as soon as the launch finished.
+ *
+ * Arguments:
+ *  "ctx_id"          - the configuration context ID.
+ *  "measurement"     - a buffer receiving the measurement, as a null-terminated lowercase hex
+ *                      string.
+ *  "measurement_len" - the size of the "measurement" buffer, including room for the terminating
+ *                      null byte. KRUN_TEE_MEASUREMENT_MAX_LEN is always enough.
+ *  "nonce"           - a buffer receiving the nonce mixed into the measurement by the firmware,
+ *                      as a null-terminated lowercase hex string, or NULL if not needed.
+ *  "nonce_len"       - the size of the "nonce" buffer, including room for the terminating null
+ *                      byte. KRUN_TEE_MEASUREMENT_MAX_LEN is always enough.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. -ENOENT is returned if the context
+ *  didn't finish a measured launch (yet), and -ERANGE if a buffer is too small.
+ */
+int32_t krun_get_tee_measurement(uint32_t ctx_id, char *measurement, size_t measurement_len,
+                                 char *nonce, size_t nonce_len);
+
+/**
+ * Reads the memory of a running SEV guest in clear, for debugging it. Only available in
+ * libkrun-sev.
+ *
+ * The guest must have been launched with "allow_debug" in its TEE configuration and a policy
+ * without NO_DEBUG. Every access is logged, as it defeats the confidentiality of the guest.
+ * As krun_start_enter() doesn't return once the guest is running, this must be called from
+ * another thread.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "guest_addr" - the guest physical address to read from, 16-byte aligned.
+ *  "buf"        - a buffer receiving the decrypted memory.
+ *  "len"        - the number of bytes to read, a non-zero multiple of 16.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. -ENOENT is returned if the context
+ *  isn't running (yet), and -EPERM if the memory can't be decrypted, as when the guest wasn't
+ *  launched with debugging allowed.
+ */
+int32_t krun_debug_read_guest_memory(uint32_t ctx_id, uint64_t guest_addr, uint8_t *buf,
+                                     size_t len);
+
+/**
+ * Writes to the memory of a running SEV guest in clear, for debugging it. Only available in
+ * libkrun-sev.
+ *
+ * Same requirements as krun_debug_read_guest_memory().
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "guest_addr" - the guest physical address to write to, 16-byte aligned.
+ *  "buf"        - the data to encrypt into the guest memory.
+ *  "len"        - the number of bytes to write, a non-zero multiple of 16.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, as for
+ *  krun_debug_read_guest_memory().
+ */
+int32_t krun_debug_write_guest_memory(uint32_t ctx_id, uint64_t guest_addr, const uint8_t *buf,
+                                      size_t len);
+
 /**
  * Adds a port-path pairing for guest IPC with a process in the host.
  *
@@ -456,28 +1277,511 @@ int32_t krun_add_vsock_port2(uint32_t ctx_id,
                              uint32_t port,
                              const char *c_filepath,
                              bool listen);
+
+/**
+ * Sets the host entropy source of the virtio-rng device, instead of the host's default one.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_path" - a null-terminated string with the path of the entropy source, such as
+ *             "/dev/urandom" or "/dev/hwrng".
+ *
+ * Notes:
+ * The source is checked by reading from it. Not available in TEE builds, where there's no
+ * virtio-rng device.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, including the error that prevented
+ *  reading from the source.
+ */
+int32_t krun_set_rng_source(uint32_t ctx_id, const char *c_path);
+
+/**
+ * Exposes a TPM 2.0 device to the guest, backed by a swtpm process started with the microVM.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "c_state_dir" - a null-terminated string with the path of the directory swtpm keeps the TPM
+ *                  state, including its NVRAM, in. The state persists across runs.
+ *
+ * Notes:
+ * The "swtpm" binary must be in PATH. The device uses the TIS interface without interrupts, so
+ * the guest kernel needs CONFIG_TCG_TIS. x86_64 guests are told to probe it at its standard
+ * address, aarch64 guests find it in the FDT. Only available on Linux, and not in TEE builds:
+ * the TPM is emulated by the host, which SEV/SNP guests don't trust, and its state can't be
+ * covered by the launch measurement.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tpm(uint32_t ctx_id, const char *c_state_dir);
+
+/**
+ * Limits how much entropy the guest can get from the virtio-rng device. Requests beyond the
+ * limit are delayed until the next period.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "bytes"     - the maximum number of bytes served per period.
+ *  "period_ms" - the length of the period, in milliseconds.
+ *
+ * Notes:
+ * Not available in TEE builds, where there's no virtio-rng device.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_rng_rate_limit(uint32_t ctx_id, uint64_t bytes, uint32_t period_ms);
+
+/**
+ * Sets how much memory the guest should give back to the host through the balloon device.
+ * The guest inflates or deflates the balloon asynchronously, and the memory it puts in the
+ * balloon is released to the host.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID, of a context started with krun_start_enter,
+ *                 which is usually running in another thread.
+ *  "target_mib" - the target size of the balloon in MiB. Zero deflates it completely.
+ *
+ * Notes:
+ * Not available in TEE builds, where there's no balloon device.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. -ENOENT is returned if the context
+ *  wasn't started (yet).
+ */
+int32_t krun_set_balloon_target(uint32_t ctx_id, uint32_t target_mib);
+
+/**
+ * Gets the current and target sizes of the balloon.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID, of a context started with krun_start_enter.
+ *  "actual_mib" - receives the memory currently in the balloon, in MiB, as reported by the
+ *                 guest. May be NULL.
+ *  "target_mib" - receives the target set with krun_set_balloon_target, in MiB. May be NULL.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. -ENOENT is returned if the context
+ *  wasn't started (yet).
+ */
+int32_t krun_get_balloon_size(uint32_t ctx_id, uint32_t *actual_mib, uint32_t *target_mib);
+
+#define KRUN_BALLOON_STAT_SWAP_IN 0
+#define KRUN_BALLOON_STAT_SWAP_OUT 1
+#define KRUN_BALLOON_STAT_MAJOR_FAULTS 2
+#define KRUN_BALLOON_STAT_MINOR_FAULTS 3
+#define KRUN_BALLOON_STAT_FREE_MEMORY 4
+#define KRUN_BALLOON_STAT_TOTAL_MEMORY 5
+#define KRUN_BALLOON_STAT_AVAILABLE_MEMORY 6
+#define KRUN_BALLOON_STAT_DISK_CACHES 7
+#define KRUN_BALLOON_STAT_HUGETLB_ALLOCATIONS 8
+#define KRUN_BALLOON_STAT_HUGETLB_FAILURES 9
+#define KRUN_BALLOON_STATS_NUM 10
+
+/**
+ * Gets the latest guest memory statistics sample, reported through the balloon device, and
+ * asks the guest for a new one.
+ *
+ * Arguments:
+ *  "ctx_id"       - the configuration context ID, of a context started with krun_start_enter.
+ *  "stats"        - an array receiving the statistics, indexed by KRUN_BALLOON_STAT_*. Memory
+ *                   sizes are in bytes. Statistics the guest didn't report are set to UINT64_MAX.
+ *  "nstats"       - the number of entries in "stats". Entries past KRUN_BALLOON_STATS_NUM are
+ *                   left untouched.
+ *  "timestamp_ms" - receives when the sample was received, in milliseconds since the UNIX
+ *                   epoch. May be NULL.
+ *
+ * Notes:
+ * The guest sends samples asynchronously, so a call returns the sample requested by the
+ * previous one. Not available in TEE builds, where there's no balloon device.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. -ENOENT is returned if the context
+ *  wasn't started (yet), and -EAGAIN if the guest didn't send any sample yet.
+ */
+int32_t krun_get_balloon_stats(uint32_t ctx_id, uint64_t *stats, size_t nstats,
+                               uint64_t *timestamp_ms);
+
+/**
+ * Adds several port-path pairings for guest IPC with processes in the host at once.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "ports"       - an array of "count" vsock ports.
+ *  "c_filepaths" - an array of "count" null-terminated strings, the path of the UNIX socket
+ *                  in the host for the port at the same index.
+ *  "count"       - the number of pairings.
+ *  "listen"      - true if the guest listens on the ports and connections are initiated from
+ *                  the host side, see krun_add_vsock_port2.
+ *
+ * Notes:
+ * Either all the pairings are added or none of them. When "listen" is true, the sockets are
+ * created once the guest initializes its vsock device and removed when the VM stops.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. Documented errors:
+ *       -EEXIST     when a port is already mapped, or appears more than once in "ports"
+ *       -EADDRINUSE when "listen" is true and a path already exists, is already used by another
+ *                   listening port, or appears more than once in "c_filepaths"
+ */
+int32_t krun_add_vsock_ports(uint32_t ctx_id,
+                             const uint32_t *ports,
+                             const char *const c_filepaths[],
+                             size_t count,
+                             bool listen);
+
+/**
+ * Opens a host-initiated connection to a vsock port the guest listens on. The port must have
+ * been added with krun_add_vsock_port2 and "listen" set to true, and the context must have
+ * been started with krun_start_enter, which is usually running in another thread.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "port"   - the vsock port the guest listens on.
+ *
+ * Notes:
+ * The host side of the port is set up once the guest initializes its vsock device, so calls
+ * made too early fail with -ENOENT or -ECONNREFUSED and may be retried. If nothing in the guest
+ * listens on "port", the connection is reset and reads on the returned descriptor return EOF.
+ *
+ * Returns:
+ *  A connected stream socket file descriptor, owned by the caller, on success. -ENOENT if the
+ *  context wasn't started or "port" isn't a listening port, or another negative error number
+ *  on failure.
+ */
+int32_t krun_vsock_connect(uint32_t ctx_id, uint32_t port);
+/**
+ * Takes a snapshot of the metrics of the VMM running in this process, to diagnose performance
+ * problems such as excessive vCPU exits. May be called from any thread while the microVM runs.
+ *
+ * Arguments:
+ *  "buf"     - a buffer where the snapshot is written as a null-terminated string.
+ *  "buf_len" - the size of "buf".
+ *
+ * Notes:
+ * The snapshot is in the Prometheus text format, with one "name{labels} value" line per metric:
+ *  - vcpu_exits{reason="..."}: the exits of all the vCPUs to the VMM, by reason.
+ *  - device_interrupts{device="..."}: the interrupts the guest acknowledged, per virtio device.
+ *  - device_queue_depth{device="...",queue="..."}: the requests the guest made that the device
+ *    hasn't completed yet, sampled when taking the snapshot.
+ *
+ * Returns:
+ *  The length of the snapshot, without the terminating null character, on success, or -ENOSPC
+ *  if it doesn't fit in "buf".
+ */
+int32_t krun_get_metrics(char *buf, size_t buf_len);
+
+/**
+ * Cancels the start of a microVM, aborting "krun_start_enter" while it builds the microVM,
+ * including the TEE attestation. Meant to be called from another thread.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *
+ * Notes:
+ *  The build stops at the next step it can leave cleanly, and in-flight requests to the
+ *  attestation servers are aborted. "krun_start_enter" then tears down what was already set up
+ *  and returns -ECANCELED. If called before "krun_start_enter", the start fails right away.
+ *  Once the guest is running, this has no effect: use "krun_get_shutdown_eventfd" instead.
+ *
+ * Returns:
+ *  Zero on success or -ENOENT if the context doesn't exist or already finished building.
+ */
+int32_t krun_cancel_start(uint32_t ctx_id);
+
 /**
  * Returns the eventfd file descriptor to signal the guest to shut down orderly. This must be
- * called before starting the microVM with "krun_start_event". Only available in libkrun-efi.
+ * called before starting the microVM with "krun_start_event". Available in libkrun-efi and,
+ * on x86_64, in libkrun.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
  *
+ * Notes:
+ *  On x86_64, writing to the eventfd injects a CTRL+ALT+DEL key press, which the init process
+ *  in the guest turns into a SIGTERM for the workload. The microVM exits once the workload
+ *  terminates. Use "krun_set_shutdown_timeout" to bound how long the guest may take.
+ *
  * Returns:
  *  The eventfd file descriptor or a negative error number on failure.
  */
 int32_t krun_get_shutdown_eventfd(uint32_t ctx_id);
 
 /**
- * Configures the console device to ignore stdin and write the output to "c_filepath".
+ * Sets how long the guest is given to shut down after a request through the eventfd returned
+ * by "krun_get_shutdown_eventfd". If the guest is still running when the timeout expires, the
+ * microVM is stopped forcefully. By default, there is no timeout. Only available on x86_64.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "timeout_ms" - the timeout in milliseconds. Must be greater than zero.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_shutdown_timeout(uint32_t ctx_id, uint32_t timeout_ms);
+
+/* Values read from the eventfd returned by krun_get_guest_ready_eventfd. */
+#define KRUN_GUEST_READY 1
+#define KRUN_GUEST_READY_TIMEOUT 2
+
+/**
+ * Returns an eventfd file descriptor signaled once the guest reached userspace, as reported by
+ * the init process in the guest over vsock right before it runs the workload. This must be
+ * called before starting the microVM with "krun_start_enter".
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "timeout_ms" - how long the guest has to report it's ready, in milliseconds, or zero to wait
+ *                 for as long as it runs.
+ *
+ * Notes:
+ *  Reading the eventfd yields KRUN_GUEST_READY once the guest reported it's ready, or
+ *  KRUN_GUEST_READY_TIMEOUT if it didn't before the timeout expired, which is counted from the
+ *  call to "krun_start_enter" so it includes building the microVM and, in TEE builds, its
+ *  attestation. The eventfd is only signaled once. The handshake goes through vsock port 1026,
+ *  which can't be mapped with "krun_add_vsock_port" then. Calling this again only updates the
+ *  timeout.
+ *
+ * Returns:
+ *  The eventfd file descriptor or a negative error number on failure.
+ */
+int32_t krun_get_guest_ready_eventfd(uint32_t ctx_id, uint32_t timeout_ms);
+
+/* Watchdog actions, set by krun_set_watchdog. */
+#define KRUN_WATCHDOG_ACTION_RESET 0
+#define KRUN_WATCHDOG_ACTION_POWEROFF 1
+#define KRUN_WATCHDOG_ACTION_NOTIFY 2
+
+/**
+ * Exposes an IB700 watchdog to the guest, and sets what happens if the guest stops kicking it.
+ * This must be called before starting the microVM with "krun_start_enter". By default, there is
+ * no watchdog. Only available on x86_64.
+ *
+ * Arguments:
+ *  "ctx_id"       - the configuration context ID.
+ *  "timeout_secs" - how long the guest may go without kicking the watchdog, from 1 to 30 seconds.
+ *  "action"       - what happens when the watchdog expires:
+ *                   KRUN_WATCHDOG_ACTION_RESET stops the microVM as a guest reboot does, with a
+ *                   zero exit status, as libkrun doesn't reboot guests in place.
+ *                   KRUN_WATCHDOG_ACTION_POWEROFF stops the microVM with a non-zero exit status.
+ *                   KRUN_WATCHDOG_ACTION_NOTIFY leaves the guest running, and signals the eventfd
+ *                   this function returns.
+ *
+ * Notes:
+ * The guest kernel needs CONFIG_IB700_WDT, and the timeout is passed to the driver through the
+ * kernel command line. As with any watchdog, the timer only starts once a process in the guest,
+ * such as systemd with RuntimeWatchdogSec set, opens /dev/watchdog and keeps kicking it. After
+ * expiring, the watchdog is disarmed until the guest kicks it again.
+ *
+ * Returns:
+ *  With KRUN_WATCHDOG_ACTION_NOTIFY, the eventfd file descriptor, owned by the context, on
+ *  success. Otherwise zero on success. -EEXIST if it was already called for this context, or
+ *  another negative error number on failure.
+ */
+int32_t krun_set_watchdog(uint32_t ctx_id, uint32_t timeout_secs, uint32_t action);
+
+/* Seccomp modes, set by krun_set_seccomp_mode. */
+#define KRUN_SECCOMP_DISABLED 0
+#define KRUN_SECCOMP_LOG 1
+#define KRUN_SECCOMP_KILL 2
+
+/**
+ * Confines the vCPU threads, the device threads and the thread calling "krun_start_enter" to
+ * the syscalls the VMM needs, using a seccomp filter. By default, the threads aren't confined.
+ * Only available on Linux.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "mode"   - KRUN_SECCOMP_DISABLED to not install the filter, KRUN_SECCOMP_LOG to only have the
+ *             kernel log the syscalls out of the allowlist, meant to debug the filter, or
+ *             KRUN_SECCOMP_KILL to terminate the VMM when a thread makes one of them.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_seccomp_mode(uint32_t ctx_id, uint32_t mode);
+
+/**
+ * Replaces the default seccomp filter with a custom BPF program, installed on the same threads.
+ * The program is installed regardless of the mode set with "krun_set_seccomp_mode". Only
+ * available on Linux.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "c_filepath" - the path to a file made of "struct sock_filter" instructions in host byte
+ *                 order, such as the ones produced by "seccomp_export_bpf".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_seccomp_filter(uint32_t ctx_id, const char *c_filepath);
+
+/**
+ * Confines the vCPU threads, the device threads and the thread calling "krun_start_enter" to the
+ * host paths the microVM uses, using Landlock. By default, the threads aren't confined. Only
+ * available on Linux.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "enable" - whether to confine the threads.
+ *
+ * Notes:
+ * The ruleset is applied once the microVM is built, right before the vCPUs start, so the kernel,
+ * the disks, the firmware and the TEE certificates and caches, which are opened while building
+ * it, don't need to be allowed. From then on, the threads may only access the virtio-fs shared
+ * directories, read-only if they're exported as such, create the sockets of the vsock ports the
+ * guest listens on, and create the file set with "krun_set_dump_on_crash".
+ *
+ * The threads aren't confined if the host kernel lacks Landlock, or if the virtio-gpu device is
+ * enabled, as virglrenderer loads the host GPU drivers once the guest activates it. With
+ * Landlock ABI v1, from Linux 5.13 to 5.18, the guest can't move files between directories of
+ * the virtio-fs shared directories.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_landlock(uint32_t ctx_id, bool enable);
+
+/* Guest panic events, reported by krun_get_panic_fd. */
+#define KRUN_PVPANIC_PANICKED 1 << 0
+#define KRUN_PVPANIC_CRASH_LOADED 1 << 1
+
+/**
+ * Exposes a pvpanic device to the guest and returns a file descriptor to learn about its kernel
+ * panics. This must be called before starting the microVM with "krun_start_enter".
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Notes:
+ * Each time the guest kernel panics, a single byte is written to the returned descriptor, holding
+ * the KRUN_PVPANIC_* events reported by the guest: KRUN_PVPANIC_PANICKED if it panicked, or
+ * KRUN_PVPANIC_CRASH_LOADED if it panicked and is booting a crash kernel. Reports are dropped
+ * while the descriptor is left unread and its buffer is full. The descriptor must be kept open
+ * as long as the microVM runs, or the process must ignore SIGPIPE.
+ *
+ * The device is discovered by the guest through its device tree on aarch64, and through ACPI on
+ * x86_64, where it's not available in TEE builds. The guest kernel must be built with
+ * CONFIG_PVPANIC_MMIO, and with CONFIG_ACPI on x86_64.
+ *
+ * Returns:
+ *  The read end of a pipe, owned by the caller, on success. -ENOTSUP in x86_64 TEE builds,
+ *  -EEXIST if it was already called for this context, or another negative error number on
+ *  failure.
+ */
+int32_t krun_get_panic_fd(uint32_t ctx_id);
+
+/**
+ * Sets a file to write a dump of the guest to when its kernel panics or it crashes, as an ELF core
+ * file holding the guest RAM at its physical addresses and the registers of each vCPU. The dump
+ * can be analyzed with the crash utility or gdb. By default, no dump is written.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_path" - a null-terminated string with the path of the dump. It's overwritten if it exists.
+ *
+ * Notes:
+ * The guest kernel reports its panics through the pvpanic device, which is exposed to the guest
+ * when this is set, as described in "krun_get_panic_fd". The guest is dumped on each
+ * KRUN_PVPANIC_PANICKED event, and then goes on with whatever its kernel does after a panic, such
+ * as rebooting. The guest is also dumped when it crashes, that is on a triple fault or when KVM
+ * fails to run it, right before the microVM stops. Only available on x86_64 Linux, and not in TEE
+ * builds: the memory of SEV/SNP guests is encrypted, so it can't be dumped.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_dump_on_crash(uint32_t ctx_id, const char *c_path);
+
+/**
+ * Sets whether the guest reboots in place instead of stopping the microVM when it reboots.
+ * The vCPUs and the devices are reset, and the kernel, initrd and firmware are loaded again, but
+ * the process and the microVM, including its guest memory, are kept. By default, a guest reboot
+ * stops the microVM.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "enable" - whether to reboot the guest in place.
+ *
+ * Notes:
+ * With the default kernel command line, a kernel panic reboots the guest too. If a device
+ * doesn't support being reset, which is the case of virtio-net and virtio-gpu, the microVM is
+ * stopped instead. Only available on x86_64 Linux, and not in TEE builds: the memory of a
+ * SEV/SNP guest is measured when it's launched, so it can only be booted again by launching and
+ * attesting a new microVM.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_reboot_in_place(uint32_t ctx_id, bool enable);
+
+/**
+ * Returns the eventfd file descriptor to reboot the guest in place, without it shutting down
+ * first. This must be called after "krun_set_reboot_in_place" enabled it, and before starting
+ * the microVM with "krun_start_enter". Only available on x86_64 Linux, and not in TEE builds.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  The eventfd file descriptor on success, -EINVAL if rebooting in place isn't enabled, or
+ *  another negative error number on failure.
+ */
+int32_t krun_get_reboot_eventfd(uint32_t ctx_id);
+
+/**
+ * Redirects the console device away from the stdio of the process. If "c_filepath" is a unix
+ * socket, the VMM connects to it, writes the console output to it and reads the console input
+ * from it. Otherwise, the console ignores stdin and writes the output to the file, which is
+ * created or truncated.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
- *  "filepath"  - a null-terminated string representing the path of the file to write the
- *                console output.
+ *  "filepath"  - a null-terminated string representing the path of the file or unix socket to
+ *                redirect the console to.
+ *
+ * Notes:
+ * The guest process uses the console as its stdin, stdout and stderr, unless some of them are
+ * redirected with krun_set_stdout_output() and krun_set_stderr_output(). When any of these
+ * functions is used, the VMM doesn't use its own stdio anymore, and the console output that isn't
+ * redirected goes to the log.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if it was already called for
+ *  this context.
  */
 int32_t krun_set_console_output(uint32_t ctx_id, const char *c_filepath);
 
+/**
+ * Redirects the stdout of the guest process to a file or unix socket, separately from the console.
+ * See krun_set_console_output() for how "path" is used.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "path"   - a null-terminated string representing the path of the file or unix socket.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if it was already called for
+ *  this context.
+ */
+int32_t krun_set_stdout_output(uint32_t ctx_id, const char *path);
+
+/**
+ * Redirects the stderr of the guest process to a file or unix socket, separately from the console.
+ * See krun_set_console_output() for how "path" is used.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "path"   - a null-terminated string representing the path of the file or unix socket.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if it was already called for
+ *  this context.
+ */
+int32_t krun_set_stderr_output(uint32_t ctx_id, const char *path);
+
 /**
  * Starts and enters the microVM with the configured parameters. The VMM will attempt to take over
  * stdin/stdout to manage them on behalf of the process running inside the isolated environment,
@@ -489,8 +1793,9 @@ int32_t krun_set_console_output(uint32_t ctx_id, const char *c_filepath);
  *  "ctx_id" - the configuration context ID.
  *
  * Returns:
- *  This function only returns if an error happens before starting the microVM. Otherwise, the
- *  VMM assumes it has full control of the process, and will call to exit() once the microVM shuts
- *  down.
+ *  This function only returns if an error happens before starting the microVM, or with zero when
+ *  the TEE configuration requests a "dry_run". Otherwise, the VMM assumes it has full control of
+ *  the process, and will call to exit() once the microVM shuts down. Returns -ECANCELED if the
+ *  start was cancelled with "krun_cancel_start".
  */
 int32_t krun_start_enter(uint32_t ctx_id);
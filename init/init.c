@@ -5,6 +5,7 @@
 #include <stdio.h>
 #include <stdint.h>
 #include <stdlib.h>
+#include <signal.h>
 #include <string.h>
 #include <time.h>
 #include <dirent.h>
@@ -13,6 +14,7 @@
 #include <net/if.h>
 #include <sys/ioctl.h>
 #include <sys/mount.h>
+#include <sys/reboot.h>
 #include <sys/resource.h>
 #include <sys/socket.h>
 #include <sys/stat.h>
@@ -451,6 +453,66 @@ static int mount_filesystems()
 	return 0;
 }
 
+/* Decodes the escape sequences of a JSON string in place. */
+static void json_unescape(char *s)
+{
+	char *out = s;
+	unsigned int cp, lo;
+
+	while (*s) {
+		if (*s != '\\' || !s[1]) {
+			*out++ = *s++;
+			continue;
+		}
+
+		s++;
+		switch (*s) {
+		case 'b': *out++ = '\b'; break;
+		case 'f': *out++ = '\f'; break;
+		case 'n': *out++ = '\n'; break;
+		case 'r': *out++ = '\r'; break;
+		case 't': *out++ = '\t'; break;
+		case 'u':
+			if (sscanf(s + 1, "%4x", &cp) != 1) {
+				*out++ = *s;
+				break;
+			}
+			s += 4;
+			/* A surrogate pair encodes a code point beyond the BMP. */
+			if (cp >= 0xd800 && cp < 0xdc00 && s[1] == '\\' && s[2] == 'u' &&
+			    sscanf(s + 3, "%4x", &lo) == 1 && lo >= 0xdc00 && lo < 0xe000) {
+				cp = 0x10000 + ((cp - 0xd800) << 10) + (lo - 0xdc00);
+				s += 6;
+			}
+			if (cp < 0x80) {
+				*out++ = cp;
+			} else if (cp < 0x800) {
+				*out++ = 0xc0 | (cp >> 6);
+				*out++ = 0x80 | (cp & 0x3f);
+			} else if (cp < 0x10000) {
+				*out++ = 0xe0 | (cp >> 12);
+				*out++ = 0x80 | ((cp >> 6) & 0x3f);
+				*out++ = 0x80 | (cp & 0x3f);
+			} else {
+				*out++ = 0xf0 | (cp >> 18);
+				*out++ = 0x80 | ((cp >> 12) & 0x3f);
+				*out++ = 0x80 | ((cp >> 6) & 0x3f);
+				*out++ = 0x80 | (cp & 0x3f);
+			}
+			break;
+		default: *out++ = *s; break;
+		}
+		s++;
+	}
+	*out = '\0';
+}
+
+/* Returns the number of tokens of a value that doesn't nest arrays or objects. */
+static int config_value_tokens(jsmntok_t *token)
+{
+	return token->type == JSMN_ARRAY ? token->size + 1 : 1;
+}
+
 static void config_parse_env(char *data, jsmntok_t *token)
 {
 	jsmntok_t *tenv;
@@ -463,13 +525,14 @@ static void config_parse_env(char *data, jsmntok_t *token)
 
 		env = data + tenv->start;
 		len = tenv->end - tenv->start;
+		env[len] = '\0';
+		json_unescape(env);
 
 		env_val = strstr(env, "=");
 		if (!env_val) {
 			continue;
 		}
 
-		env[len] = '\0';
 		*env_val = '\0';
 		env_val++;
 
@@ -490,7 +553,7 @@ static char ** config_parse_args(char *data, jsmntok_t *token)
 	int len;
 	int i, j;
 
-	argv = malloc(MAX_ARGS * sizeof(char *));
+	argv = malloc((token->size + 1) * sizeof(char *));
 	j = 0;
 
 	for (i = 0; i < token->size; i++) {
@@ -502,6 +565,7 @@ static char ** config_parse_args(char *data, jsmntok_t *token)
 		arg = malloc(len + 1);
 		memcpy(arg, value, len);
 		arg[len] = '\0';
+		json_unescape(arg);
 
 		argv[j] = arg;
 		j++;
@@ -536,6 +600,7 @@ static char * config_parse_string(char *data, jsmntok_t *token)
 	}
 	memcpy(string, val, len);
 	string[len] = '\0';
+	json_unescape(string);
 
 	return string;
 }
@@ -568,12 +633,51 @@ char ** concat_entrypoint_argv(char **entrypoint, char **config_argv)
 	return argv;
 }
 
+/* Gets the device path of the virtio console port with the given name. */
+static int find_port(const char *name, char *path, size_t size)
+{
+	DIR *ports_dir;
+	struct dirent *entry;
+	char name_path[1024];
+	char name_buf[1024];
+	FILE *name_file;
+	int ret = -1;
+
+	ports_dir = opendir("/sys/class/virtio-ports");
+	if (!ports_dir) {
+		return -1;
+	}
+
+	while (ret < 0 && (entry = readdir(ports_dir))) {
+		snprintf(name_path, sizeof(name_path),
+			 "/sys/class/virtio-ports/%s/name", entry->d_name);
+		name_file = fopen(name_path, "r");
+		if (!name_file) {
+			continue;
+		}
+
+		if (fgets(name_buf, sizeof(name_buf), name_file)) {
+			name_buf[strcspn(name_buf, "\n")] = '\0';
+			if (strcmp(name_buf, name) == 0) {
+				snprintf(path, size, "/dev/%s", entry->d_name);
+				ret = 0;
+			}
+		}
+		fclose(name_file);
+	}
+
+	closedir(ports_dir);
+	return ret;
+}
+
 static int config_parse_file(char ***argv, char **workdir)
 {
 	jsmn_parser parser;
 	jsmntok_t *tokens;
-	struct stat stat;
-	char *data;
+	char port_path[1024];
+	char *data, *new_data;
+	size_t size, capacity;
+	ssize_t len;
 	char *config_file;
 	char **config_argv;
 	char **entrypoint;
@@ -584,6 +688,14 @@ static int config_parse_file(char ***argv, char **workdir)
 	int i;
 
 	config_file = getenv("KRUN_CONFIG");
+	/*
+	 * libkrun hands the executable's config through a port when it doesn't
+	 * fit in the kernel command line.
+	 */
+	if (!config_file &&
+	    find_port("krun-config", port_path, sizeof(port_path)) == 0) {
+		config_file = port_path;
+	}
 	if (!config_file) {
 		config_file = CONFIG_FILE_PATH;
 	}
@@ -593,21 +705,32 @@ static int config_parse_file(char ***argv, char **workdir)
 		return ret;
 	}
 
-	if (fstat(fd, &stat) != 0) {
-		perror("Couldn't stat config file");
-		goto cleanup_fd;
-	}
-
-	data = malloc(stat.st_size);
+	/* A port has no size, so read until EOF. */
+	size = 0;
+	capacity = 4096;
+	data = malloc(capacity);
 	if (!data) {
 		perror("Couldn't allocate memory");
 		goto cleanup_fd;
 	}
 
-	if (read(fd, data, stat.st_size) < 0) {
+	while ((len = read(fd, data + size, capacity - size - 1)) > 0) {
+		size += len;
+		if (size == capacity - 1) {
+			capacity *= 2;
+			new_data = realloc(data, capacity);
+			if (!new_data) {
+				perror("Couldn't allocate memory");
+				goto cleanup_data;
+			}
+			data = new_data;
+		}
+	}
+	if (len < 0) {
 		perror("Error reading config file");
 		goto cleanup_data;
 	}
+	data[size] = '\0';
 
 	tokens = malloc(MAX_TOKENS * sizeof(jsmntok_t));
 	if (!tokens) {
@@ -616,7 +739,7 @@ static int config_parse_file(char ***argv, char **workdir)
 	}
 
 	jsmn_init(&parser);
-	num_tokens = jsmn_parse(&parser, data, strlen(data),
+	num_tokens = jsmn_parse(&parser, data, size,
 				tokens, MAX_TOKENS);
 	if (num_tokens < 0) {
 		printf("Error parsing config file\n");
@@ -637,30 +760,40 @@ static int config_parse_file(char ***argv, char **workdir)
 			(i + 1) < num_tokens && tokens[i + 1].type == JSMN_ARRAY) {
 			config_parse_env(data, &tokens[i + 1]);
 			parsed_env = 1;
+			i += config_value_tokens(&tokens[i + 1]);
+			continue;
 		}
 
 		if (!parsed_args && jsoneq(data, &tokens[i], "args") == 0 &&
 			(i + 1) < num_tokens) {
 			config_argv = config_parse_args(data, &tokens[i + 1]);
 			parsed_args = 1;
+			i += config_value_tokens(&tokens[i + 1]);
+			continue;
 		}
 
 		if (!parsed_args && jsoneq(data, &tokens[i], "Cmd") == 0 &&
 			(i + 1) < num_tokens) {
 			config_argv = config_parse_args(data, &tokens[i + 1]);
 			parsed_args = 1;
+			i += config_value_tokens(&tokens[i + 1]);
+			continue;
 		}
 
 		if (!parsed_workdir && jsoneq(data, &tokens[i], "WorkingDir") == 0 &&
 			(i + 1) < num_tokens) {
 			*workdir = config_parse_string(data, &tokens[i + 1]);
 			parsed_workdir = 1;
+			i += config_value_tokens(&tokens[i + 1]);
+			continue;
 		}
 
 		if (!parsed_entrypoint && jsoneq(data, &tokens[i], "Entrypoint") == 0 &&
 			(i + 1) < num_tokens) {
 			entrypoint = config_parse_args(data, &tokens[i + 1]);
 			parsed_workdir = 1;
+			i += config_value_tokens(&tokens[i + 1]);
+			continue;
 		}
 	}
 
@@ -808,6 +941,66 @@ int setup_redirects()
     return 0;
 }
 
+/*
+ * Replaces /etc/resolv.conf with the comma-separated name servers in
+ * KRUN_DNS, which may be empty.
+ */
+static void set_dns_servers(char *servers)
+{
+    FILE *resolv;
+    char *server;
+
+    resolv = fopen("/etc/resolv.conf", "w");
+    if (resolv == NULL) {
+        perror("Couldn't write /etc/resolv.conf");
+        return;
+    }
+
+    for (server = strtok(servers, ","); server != NULL; server = strtok(NULL, ",")) {
+        fprintf(resolv, "nameserver %s\n", server);
+    }
+    fclose(resolv);
+}
+
+#define KRUN_READY_PORT 1026
+#define KRUN_READY_MESSAGE "READY\n"
+
+/*
+ * Tells libkrun the guest reached userspace, for krun_get_guest_ready_eventfd.
+ * Nobody may be listening, so failures are silently ignored.
+ */
+static void notify_ready()
+{
+    struct sockaddr_vm addr;
+    int fd;
+
+    fd = socket(AF_VSOCK, SOCK_STREAM, 0);
+    if (fd < 0) {
+        return;
+    }
+
+    memset(&addr, 0, sizeof(addr));
+    addr.svm_family = AF_VSOCK;
+    addr.svm_port = KRUN_READY_PORT;
+    addr.svm_cid = VMADDR_CID_HOST;
+
+    if (connect(fd, (struct sockaddr *)&addr, sizeof(addr)) == 0) {
+        write(fd, KRUN_READY_MESSAGE, strlen(KRUN_READY_MESSAGE));
+    }
+    close(fd);
+}
+
+static pid_t workload_pid;
+
+static void forward_shutdown(int signo)
+{
+    // Ctrl-Alt-Del has been disabled, so the kernel delivers it to us as
+    // SIGINT. Ask the workload to terminate so the VM can shut down cleanly.
+    if (workload_pid > 0) {
+        kill(workload_pid, SIGTERM);
+    }
+}
+
 int main(int argc, char **argv)
 {
 	struct ifreq ifr;
@@ -819,6 +1012,7 @@ int main(int argc, char **argv)
 	char *krun_init;
 	char *config_workdir, *env_workdir;
 	char *rlimits;
+	char *dns_servers;
 	char **config_argv, **exec_argv;
 
 #ifdef SEV
@@ -835,6 +1029,9 @@ int main(int argc, char **argv)
 	setsid();
 	ioctl(0, TIOCSCTTY, 1);
 
+	// Have the kernel send us SIGINT on Ctrl-Alt-Del instead of rebooting.
+	reboot(RB_DISABLE_CAD);
+
 	sockfd = socket(AF_INET, SOCK_DGRAM, 0);
 	if (sockfd >= 0) {
 		memset(&ifr, 0, sizeof ifr);
@@ -859,13 +1056,21 @@ int main(int argc, char **argv)
 		setenv("TERM", krun_term, 1);
 	}
 
-	hostname = getenv("HOSTNAME");
+	hostname = getenv("KRUN_HOSTNAME");
+	if (!hostname) {
+		hostname = getenv("HOSTNAME");
+	}
 	if (hostname) {
 		sethostname(hostname, strlen(hostname));
 	} else {
 		sethostname(&localhost[0], strlen(localhost));
 	}
 
+	dns_servers = getenv("KRUN_DNS");
+	if (dns_servers) {
+		set_dns_servers(dns_servers);
+	}
+
 	rlimits = getenv("KRUN_RLIMITS");
 	if (rlimits) {
 		set_rlimits(rlimits);
@@ -908,11 +1113,16 @@ int main(int argc, char **argv)
             exit(-3);
         }
     } else { // parent
+        workload_pid = pid;
+        notify_ready();
+        signal(SIGINT, forward_shutdown);
         // tell the kernel we don't want to be notified on SIGCHLD so it'll reap
         // our children for us
         signal(SIGCHLD, SIG_IGN);
         // wait for children since we can't exit init
-        waitpid(pid, NULL, 0);
+        while (waitpid(pid, NULL, 0) < 0 && errno == EINTR)
+            ;
+        sync();
     }
 
 	return 0;
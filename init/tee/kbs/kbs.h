@@ -37,7 +37,9 @@ int json_parse_str(char *, char *, char *);
 // kbs_types.c
 int kbs_request_marshal(char *, int, char *);
 int kbs_challenge(CURL *, char *, char *, char *);
-int kbs_attest(CURL *, char *, struct snp_report *, BIGNUM *, BIGNUM *, char *);
+int kbs_attest(CURL *, char *, struct snp_report *, BIGNUM *, BIGNUM *, char *,
+        char *, char *);
+char *kbs_cert_chain_marshal(uint8_t *, size_t);
 int kbs_get_key(CURL *, char *, char *, EVP_PKEY *, char *);
 
 // kbs_curl.c
@@ -89,6 +89,18 @@ ctx_free:
 /*
  * Create a SHA512 hash of the nonce and TEE public key to send to the
  * attestation server.
+ *
+ * This hash is used as the 64-byte REPORT_DATA of the SNP attestation report,
+ * binding the report to the KBS challenge so replayed reports can be detected.
+ * Verifiers must compute it as:
+ *
+ *   SHA512(nonce || base64(N) || base64(E))
+ *
+ * where "nonce" is the string received in the KBS challenge, used as-is, and
+ * base64(N)/base64(E) are the standard base64 encodings (padded, no newlines)
+ * of the big-endian bytes of the TEE public key's modulus and exponent, the
+ * same strings sent as "k-mod" and "k-exp" in the attestation's TEE public
+ * key.
  */
 int
 kbs_nonce_pubkey_hash(char *nonce, EVP_PKEY *pkey, unsigned char **hash,
@@ -14,8 +14,28 @@
 #include "../snp_attest.h"
 
 static void kbs_attestation_marshal(struct snp_report *, char *, BIGNUM *,
-        BIGNUM *, char *);
+        BIGNUM *, char *, char *, char *);
 static void kbs_attestation_marshal_tee_pubkey(char *, BIGNUM *, BIGNUM *);
+static const char *cert_type_str(uuid_t);
+static int guid_is_null(uuid_t);
+
+/*
+ * GUIDs of the certificates in an SNP certificate table, as defined in the
+ * AMD GHCB specification.
+ */
+static const struct {
+        uuid_t guid;
+        const char *type;
+} cert_types[] = {
+        { { 0xc0, 0xb4, 0x06, 0xa4, 0xa8, 0x03, 0x49, 0x52,
+            0x97, 0x43, 0x3f, 0xb6, 0x01, 0x4c, 0xd0, 0xae }, "ARK" },
+        { { 0x4a, 0xb7, 0xb3, 0x79, 0xbb, 0xac, 0x4f, 0xe4,
+            0xa0, 0x2f, 0x05, 0xae, 0xf3, 0x27, 0xc7, 0x82 }, "ASK" },
+        { { 0x63, 0xda, 0x75, 0x8d, 0xe6, 0x64, 0x45, 0x64,
+            0xad, 0xc5, 0xf4, 0xb9, 0x3b, 0xe8, 0xac, 0xcd }, "VCEK" },
+        { { 0xa8, 0x07, 0x4b, 0xc2, 0xa2, 0x5a, 0x48, 0x3e,
+            0xaa, 0xe6, 0x39, 0xc0, 0x45, 0xa0, 0xb8, 0xa1 }, "VLEK" },
+};
 
 /*
  * Given a TEE architecture and workload ID, write the JSON string of the
@@ -95,13 +115,18 @@ out:
  */
 int
 kbs_attest(CURL *curl, char *url, struct snp_report *report, BIGNUM *mod,
-                BIGNUM *exp, char *gen)
+                BIGNUM *exp, char *gen, char *nonce, char *cert_chain)
 {
         int rc;
         char *json, errmsg[200];
 
         rc = -1;
-        json = (char *) malloc(0x1000);
+
+        /*
+         * Each character of the certificate chain takes up to four once
+         * escaped in the TEE evidence.
+         */
+        json = (char *) malloc(0x1000 + 4 * strlen(cert_chain));
         if (json == NULL) {
                 printf("ERROR: unable to allocate JSON buffer\n");
 
@@ -110,9 +135,9 @@ kbs_attest(CURL *curl, char *url, struct snp_report *report, BIGNUM *mod,
 
         /*
          * Marshal the kbs_types Attestation JSON struct with the given
-         * attestation report and certificate chain.
+         * attestation report, certificate chain and challenge nonce.
          */
-        kbs_attestation_marshal(report, json, mod, exp, gen);
+        kbs_attestation_marshal(report, json, mod, exp, gen, nonce, cert_chain);
 
         /*
          * Ensure the error messaging string is empty, because we will
@@ -185,15 +210,107 @@ kbs_get_key(CURL *curl, char *url, char *wid, EVP_PKEY *pkey, char *pass)
         return 0;
 }
 
+/*
+ * Marshal the certificate table filled by SNP_GET_EXT_REPORT as a JSON list of
+ * the certificates it holds, each one with its type and DER bytes. The list is
+ * empty if the host didn't provision any certificates.
+ *
+ * The returned string must be freed by the caller. NULL is returned if the
+ * table is malformed.
+ */
+char *
+kbs_cert_chain_marshal(uint8_t *certs, size_t certs_len)
+{
+        struct cert_table_entry *entry, *end;
+        const char *type;
+        char *json, *p;
+        size_t max;
+
+        end = (struct cert_table_entry *) (certs + certs_len);
+
+        /*
+         * Each byte of a certificate takes up to four characters as a JSON
+         * number.
+         */
+        max = 3;
+        for (entry = (struct cert_table_entry *) certs;
+             entry + 1 <= end && !guid_is_null(entry->guid); entry++) {
+                if (entry->offset > certs_len ||
+                    entry->len > certs_len - entry->offset) {
+                        printf("ERROR: certificate out of the table bounds\n");
+
+                        return NULL;
+                }
+                max += 64 + 4 * entry->len;
+        }
+
+        json = (char *) malloc(max);
+        if (json == NULL) {
+                printf("ERROR: unable to allocate certificate chain buffer\n");
+
+                return NULL;
+        }
+
+        p = json;
+        p += sprintf(p, "[");
+        for (entry = (struct cert_table_entry *) certs;
+             entry + 1 <= end && !guid_is_null(entry->guid); entry++) {
+                type = cert_type_str(entry->guid);
+                if (type == NULL)
+                        continue;
+
+                if (p != json + 1)
+                        p += sprintf(p, ",");
+
+                p += sprintf(p, "{\"cert_type\":\"%s\",\"data\":[", type);
+                for (uint32_t i = 0; i < entry->len; i++) {
+                        p += sprintf(p, i == 0 ? "%u" : ",%u",
+                                certs[entry->offset + i]);
+                }
+                p += sprintf(p, "]}");
+        }
+        sprintf(p, "]");
+
+        return json;
+}
+
+/*
+ * Return the type of a certificate from its GUID, or NULL if it isn't one the
+ * attestation server uses.
+ */
+static const char *
+cert_type_str(uuid_t guid)
+{
+        for (int i = 0; i < sizeof(cert_types) / sizeof(cert_types[0]); i++) {
+                if (memcmp(guid, cert_types[i].guid, sizeof(uuid_t)) == 0)
+                        return cert_types[i].type;
+        }
+
+        return NULL;
+}
+
+/*
+ * The certificate table ends with an entry whose GUID is all zeroes.
+ */
+static int
+guid_is_null(uuid_t guid)
+{
+        static const uuid_t null_guid;
+
+        return memcmp(guid, null_guid, sizeof(uuid_t)) == 0;
+}
+
 /*
  * Marshal a JSON string of the kbs_types Attestation struct from the given
- * attestation report and certificate data.
+ * attestation report and certificate data. The nonce is included so the
+ * server can check it against the REPORT_DATA of the report (see
+ * kbs_nonce_pubkey_hash()).
  */
 static void
 kbs_attestation_marshal(struct snp_report *report, char *json, BIGNUM *mod,
-                BIGNUM *exp, char *gen)
+                BIGNUM *exp, char *gen, char *nonce, char *cert_chain)
 {
-        char buf[4096], *report_hexstr;
+        char buf[4096], *report_hexstr, *p;
         size_t report_hexstr_len;
 
         report_hexstr = (char *) malloc(0x1000);
@@ -211,13 +328,28 @@ kbs_attestation_marshal(struct snp_report *report, char *json, BIGNUM *mod,
         sprintf(buf, "\\\"gen\\\":\\\"%s\\\",", gen);
         strcat(json, buf);
 
+        sprintf(buf, "\\\"nonce\\\":\\\"%s\\\",", nonce);
+        strcat(json, buf);
+
         OPENSSL_buf2hexstr_ex(report_hexstr, 0x1000, &report_hexstr_len,
                 (unsigned char *) report, sizeof(*report), '\0');
         report_hexstr[report_hexstr_len] = '\0';
         sprintf(buf, "\\\"report\\\":\\\"%s\\\",", report_hexstr);
         strcat(json, buf);
 
-        strcat(json, "\\\"cert_chain\\\":\\\"[]\\\"}");
+        /*
+         * The chain is a JSON string within the TEE evidence string, so its
+         * quotes are escaped twice.
+         */
+        strcat(json, "\\\"cert_chain\\\":\\\"");
+        p = json + strlen(json);
+        for (; *cert_chain != '\0'; cert_chain++) {
+                if (*cert_chain == '"')
+                        p += sprintf(p, "\\\\\\\"");
+                else
+                        *p++ = *cert_chain;
+        }
+        strcpy(p, "\\\"}");
 
         strcat(json, "\"}");
 }
@@ -24,7 +24,8 @@
 #define JSON_MAX        1024
 #define GEN_MAX         32
 
-static int snp_get_report(const uint8_t *, size_t, struct snp_report *);
+static int snp_get_report(const uint8_t *, size_t, struct snp_report *,
+        uint8_t *, size_t *);
 static int SNP_ATTEST_ERR(char *);
 static void json_fmt(char *);
 
@@ -32,8 +33,11 @@ int
 snp_attest(char *pass, char *url, char *wid, char *tee_data)
 {
         CURL *curl;
-        char nonce[NONCE_MAX], json[JSON_MAX], gen[GEN_MAX];
+        char nonce[NONCE_MAX], json[JSON_MAX], gen[GEN_MAX], *cert_chain;
         struct snp_report report;
+        uint8_t certs[SNP_CERTS_MAX];
+        size_t certs_len;
+        int rc;
         EVP_PKEY *pkey;
         BIGNUM *n, *e;
         unsigned int hash_size;
@@ -57,13 +61,30 @@ snp_attest(char *pass, char *url, char *wid, char *tee_data)
         if (kbs_tee_pubkey_create(&pkey, &n, &e) < 0)
                 return SNP_ATTEST_ERR("Unable to create TEE public key");
 
+        /*
+         * Bind the report to this challenge by using the hash of the nonce
+         * and the TEE public key as the report's REPORT_DATA.
+         */
         if (kbs_nonce_pubkey_hash(nonce, pkey, &hash, &hash_size) < 0)
                 return SNP_ATTEST_ERR("Unable to hash nonce and public key");
 
-        if (snp_get_report(hash, hash_size, &report) != EXIT_SUCCESS)
+        certs_len = sizeof(certs);
+        if (snp_get_report(hash, hash_size, &report, certs, &certs_len) != EXIT_SUCCESS)
                 return SNP_ATTEST_ERR("Unable to retrieve attestation report");
 
-        if (kbs_attest(curl, url, &report, n, e, gen) < 0)
+        /*
+         * Send the certificates staged by the host along with the report, so
+         * the server doesn't need to fetch them from the AMD KDS. The chain is
+         * empty if the host didn't provision any, and the server falls back to
+         * the KDS.
+         */
+        cert_chain = kbs_cert_chain_marshal(certs, certs_len);
+        if (cert_chain == NULL)
+                return SNP_ATTEST_ERR("Unable to marshal certificate chain");
+
+        rc = kbs_attest(curl, url, &report, n, e, gen, nonce, cert_chain);
+        free(cert_chain);
+        if (rc < 0)
                 return SNP_ATTEST_ERR("Unable to complete KBS ATTESTATION");
 
         curl_easy_reset(curl);
@@ -75,17 +96,21 @@ snp_attest(char *pass, char *url, char *wid, char *tee_data)
 }
 
 /*
- * A function for the SNP_GET_REPORT ioctl.
+ * A function for the SNP_GET_EXT_REPORT ioctl.
  *
- * SNP_GET_REPORT fills both the attestation report and the certificate
- * data.
+ * SNP_GET_EXT_REPORT fills both the attestation report and the certificate
+ * table staged by the host, of at most "certs_len" bytes. If the kernel doesn't
+ * support extended reports, the report is requested with SNP_GET_REPORT and
+ * "certs_len" is set to zero.
  */
 static int
-snp_get_report(const uint8_t *data, size_t data_sz, struct snp_report *report)
+snp_get_report(const uint8_t *data, size_t data_sz, struct snp_report *report,
+                uint8_t *certs, size_t *certs_len)
 {
         int rc = EXIT_FAILURE;
         int fd = -1;
-        struct snp_report_req req;
+        struct snp_ext_report_req ext_req;
+        struct snp_report_req *req = &ext_req.data;
         struct snp_report_resp resp;
         struct snp_guest_request_ioctl guest_req;
         struct msg_report_resp *report_resp = (struct msg_report_resp *)&resp.data;
@@ -105,7 +130,7 @@ snp_get_report(const uint8_t *data, size_t data_sz, struct snp_report *report)
          * We will be filling the user_data field of the request with "data".
          * Ensure that the data is valid and can fit in the user_data field.
          */
-        if (data && (data_sz > sizeof(req.user_data) || data_sz == 0)) {
+        if (data && (data_sz > sizeof(req->user_data) || data_sz == 0)) {
                 rc = EINVAL;
 
                 goto out;
@@ -114,19 +139,22 @@ snp_get_report(const uint8_t *data, size_t data_sz, struct snp_report *report)
         /*
          * Initialize data structures.
          */
-        memset(&req, 0, sizeof(req));
+        memset(&ext_req, 0, sizeof(ext_req));
+        memset(certs, 0, *certs_len);
+        ext_req.certs_address = (__u64) certs;
+        ext_req.certs_len = *certs_len;
 
         /*
          * Copy the data into user_data if it exists.
          */
         if (data)
-                memcpy(&req.user_data, data, data_sz);
+                memcpy(&req->user_data, data, data_sz);
 
         memset(&resp, 0, sizeof(resp));
 
         memset(&guest_req, 0, sizeof(guest_req));
         guest_req.msg_version = 1;
-        guest_req.req_data = (__u64) &req;
+        guest_req.req_data = (__u64) &ext_req;
         guest_req.resp_data = (__u64) &resp;
 
         /*
@@ -142,10 +170,18 @@ snp_get_report(const uint8_t *data, size_t data_sz, struct snp_report *report)
         }
 
         /*
-         * Retrieve the SNP attestation report.
+         * Retrieve the SNP attestation report, along with the certificates.
          */
         errno = 0;
-        rc = ioctl(fd, SNP_GET_REPORT, &guest_req);
+        rc = ioctl(fd, SNP_GET_EXT_REPORT, &guest_req);
+        if (rc == -1 && errno == ENOTTY) {
+                printf("Extended reports unsupported, requesting a plain report\n");
+                *certs_len = 0;
+                guest_req.req_data = (__u64) req;
+
+                errno = 0;
+                rc = ioctl(fd, SNP_GET_REPORT, &guest_req);
+        }
         if (rc == -1) {
                 rc = errno;
                 perror("ioctl");
@@ -9,6 +9,12 @@
 
 #define SEV_GUEST_DEV "/dev/sev-guest"
 
+/*
+ * Size of the buffer receiving the certificates from SNP_GET_EXT_REPORT, which
+ * is the most the kernel accepts.
+ */
+#define SNP_CERTS_MAX   0x4000
+
 /*
  * Cryptographic signature (should be signed by the VCEK).
  */
@@ -355,6 +355,32 @@ fn create_rtc_node<T: DeviceInfoForFDT + Clone + Debug>(
     Ok(())
 }
 
+fn create_pvpanic_node<T: DeviceInfoForFDT + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> Result<()> {
+    let pvpanic_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
+    let pvpanic_node = fdt.begin_node(&format!("pvpanic@{:x}", dev_info.addr()))?;
+    fdt.property_string("compatible", "qemu,pvpanic-mmio")?;
+    fdt.property("reg", &pvpanic_reg_prop)?;
+    fdt.end_node(pvpanic_node)?;
+
+    Ok(())
+}
+
+fn create_tpm_node<T: DeviceInfoForFDT + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> Result<()> {
+    let tpm_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
+    let tpm_node = fdt.begin_node(&format!("tpm@{:x}", dev_info.addr()))?;
+    fdt.property_string("compatible", "tcg,tpm-tis-mmio")?;
+    fdt.property("reg", &tpm_reg_prop)?;
+    fdt.end_node(tpm_node)?;
+
+    Ok(())
+}
+
 fn create_gpio_node<T: DeviceInfoForFDT + Clone + Debug>(
     fdt: &mut FdtWriter,
     dev_info: &T,
@@ -407,6 +433,8 @@ fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug>(
         match device_type {
             DeviceType::Gpio => create_gpio_node(fdt, info)?,
             DeviceType::RTC => create_rtc_node(fdt, info)?,
+            DeviceType::PvPanic => create_pvpanic_node(fdt, info)?,
+            DeviceType::Tpm => create_tpm_node(fdt, info)?,
             DeviceType::Serial => create_serial_node(fdt, info)?,
             DeviceType::Virtio(_) => {
                 ordered_virtio_device.push(info);
@@ -91,7 +91,7 @@ pub fn configure_system<T: DeviceInfoForFDT + Clone + Debug>(
     device_info: &HashMap<(DeviceType, String), T>,
     gic_device: &Box<dyn GICDevice>,
     initrd: &Option<super::InitrdConfig>,
-    _smbios_oem_strings: &Option<Vec<String>>,
+    _smbios: &smbios::SmbiosConfig,
 ) -> super::Result<()> {
     fdt::create_fdt(
         guest_mem,
@@ -105,8 +105,7 @@ pub fn configure_system<T: DeviceInfoForFDT + Clone + Debug>(
     .map_err(Error::SetupFDT)?;
 
     #[cfg(feature = "efi")]
-    smbios::setup_smbios(guest_mem, layout::SMBIOS_START, _smbios_oem_strings)
-        .map_err(Error::Smbios)?;
+    smbios::setup_smbios(guest_mem, layout::SMBIOS_START, _smbios).map_err(Error::Smbios)?;
 
     Ok(())
 }
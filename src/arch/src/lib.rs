@@ -52,6 +52,11 @@ pub enum DeviceType {
     /// Device Type: RTC.
     #[cfg(target_arch = "aarch64")]
     RTC,
+    /// Device Type: pvpanic.
+    PvPanic,
+    /// Device Type: TPM.
+    #[cfg(target_arch = "aarch64")]
+    Tpm,
 }
 
 /// Type for passing information about the initrd in the guest memory.
@@ -62,6 +67,17 @@ pub struct InitrdConfig {
     pub size: usize,
 }
 
+/// Type for describing a NUMA node of the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NumaNode {
+    /// Guest physical ranges of the RAM of the node.
+    pub memory: Vec<(vm_memory::GuestAddress, u64)>,
+    /// Indexes of the vCPUs of the node, which are also their APIC IDs.
+    pub vcpus: Vec<u8>,
+    /// Distance to each node, the local one being 10.
+    pub distances: Vec<u8>,
+}
+
 /// Default (smallest) memory page size for the supported architectures.
 pub const PAGE_SIZE: usize = 4096;
 
@@ -0,0 +1,336 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal ACPI tables describing the NUMA topology of the guest and its pvpanic device: an RSDP
+//! pointing to an XSDT that lists the SRAT and the SLIT when there are NUMA nodes, and the FADT
+//! when there is a pvpanic device. The FADT points to the DSDT, which only holds the pvpanic
+//! device. The kernel finds the RSDP by scanning the BIOS area, and keeps relying on the MP table
+//! for everything else.
+
+use std::result;
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::layout::{ACPI_MAX_SIZE, ACPI_START};
+use crate::NumaNode;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The tables don't fit in their area.
+    TooBig,
+    /// Failure to write the tables to guest memory.
+    Write,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+const OEM_ID: &[u8; 6] = b"LIBKRN";
+const OEM_TABLE_ID: &[u8; 8] = b"LIBKRUN ";
+const CREATOR_ID: &[u8; 4] = b"KRUN";
+
+const RSDP_LEN: usize = 36;
+const HEADER_LEN: usize = 36;
+
+// SRAT structure types and flags.
+const SRAT_LAPIC_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_ENABLED: u32 = 1;
+
+const FADT_LEN: usize = 276;
+const FADT_REVISION: u8 = 6;
+const FADT_MINOR_REVISION: u8 = 5;
+// The guest has an i8042 controller, which it resets to reboot, and no VGA.
+const FADT_IAPC_BOOT_ARCH: u16 = (1 << 1) | (1 << 2);
+// No power and sleep buttons. The guest has none of the fixed hardware of full
+// ACPI, but it isn't hardware-reduced either, as the kernel would then skip
+// setting up the legacy PIC and timers.
+const FADT_FLAGS: u32 = (1 << 4) | (1 << 5);
+// The SCI is never raised, but the kernel needs to attach a handler to it to
+// enable ACPI. Without an SMI command port, ACPI is always enabled.
+const FADT_SCI_INT: u16 = 9;
+
+// AML opcodes and prefixes.
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_STRING_PREFIX: u8 = 0x0d;
+const AML_SCOPE_OP: &[u8] = &[0x10];
+const AML_BUFFER_OP: &[u8] = &[0x11];
+const AML_DEVICE_OP: &[u8] = &[0x5b, 0x82];
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_sub(*b))
+}
+
+// Prepends the common header to the body of a table.
+fn table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut table = Vec::with_capacity(HEADER_LEN + body.len());
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&((HEADER_LEN + body.len()) as u32).to_le_bytes());
+    table.push(revision);
+    table.push(0); // checksum
+    table.extend_from_slice(OEM_ID);
+    table.extend_from_slice(OEM_TABLE_ID);
+    table.extend_from_slice(&1u32.to_le_bytes()); // OEM revision
+    table.extend_from_slice(CREATOR_ID);
+    table.extend_from_slice(&1u32.to_le_bytes()); // creator revision
+    table.extend_from_slice(body);
+    table[9] = checksum(&table);
+    table
+}
+
+fn rsdp(xsdt_addr: u64) -> Vec<u8> {
+    let mut rsdp = Vec::with_capacity(RSDP_LEN);
+    rsdp.extend_from_slice(b"RSD PTR ");
+    rsdp.push(0); // checksum of the ACPI 1.0 part
+    rsdp.extend_from_slice(OEM_ID);
+    rsdp.push(2); // revision
+    rsdp.extend_from_slice(&0u32.to_le_bytes()); // no RSDT
+    rsdp.extend_from_slice(&(RSDP_LEN as u32).to_le_bytes());
+    rsdp.extend_from_slice(&xsdt_addr.to_le_bytes());
+    rsdp.push(0); // extended checksum
+    rsdp.extend_from_slice(&[0; 3]);
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+fn srat(nodes: &[NumaNode]) -> Vec<u8> {
+    // The first reserved field must be 1 for compatibility.
+    let mut body = 1u32.to_le_bytes().to_vec();
+    body.extend_from_slice(&0u64.to_le_bytes());
+
+    for (node, proximity) in nodes.iter().zip(0u32..) {
+        for &vcpu in &node.vcpus {
+            body.extend_from_slice(&[SRAT_LAPIC_AFFINITY, 16, proximity as u8, vcpu]);
+            body.extend_from_slice(&SRAT_ENABLED.to_le_bytes());
+            body.push(0); // local SAPIC EID
+            body.extend_from_slice(&proximity.to_le_bytes()[1..]);
+            body.extend_from_slice(&0u32.to_le_bytes()); // clock domain
+        }
+        for &(start, len) in &node.memory {
+            body.extend_from_slice(&[SRAT_MEMORY_AFFINITY, 40]);
+            body.extend_from_slice(&proximity.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&start.0.to_le_bytes());
+            body.extend_from_slice(&len.to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&SRAT_ENABLED.to_le_bytes());
+            body.extend_from_slice(&0u64.to_le_bytes());
+        }
+    }
+
+    table(b"SRAT", 3, &body)
+}
+
+fn slit(nodes: &[NumaNode]) -> Vec<u8> {
+    let mut body = (nodes.len() as u64).to_le_bytes().to_vec();
+    for node in nodes {
+        body.extend_from_slice(&node.distances);
+    }
+
+    table(b"SLIT", 1, &body)
+}
+
+fn fadt(dsdt_addr: u64) -> Vec<u8> {
+    let mut fadt = table(b"FACP", FADT_REVISION, &[0; FADT_LEN - HEADER_LEN]);
+    fadt[46..48].copy_from_slice(&FADT_SCI_INT.to_le_bytes());
+    fadt[109..111].copy_from_slice(&FADT_IAPC_BOOT_ARCH.to_le_bytes());
+    fadt[112..116].copy_from_slice(&FADT_FLAGS.to_le_bytes());
+    fadt[131] = FADT_MINOR_REVISION;
+    fadt[140..148].copy_from_slice(&dsdt_addr.to_le_bytes()); // X_DSDT
+    fadt[9] = 0;
+    fadt[9] = checksum(&fadt);
+    fadt
+}
+
+// Prepends the opcode and the length of a package to its body. The length counts its own
+// encoding, which takes a byte below 64 and two up to 4096.
+fn aml_package(op: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut aml = op.to_vec();
+    if body.len() + 1 < 0x40 {
+        aml.push((body.len() + 1) as u8);
+    } else {
+        let len = body.len() + 2;
+        aml.extend_from_slice(&[0x40 | (len & 0xf) as u8, (len >> 4) as u8]);
+    }
+    aml.extend_from_slice(body);
+    aml
+}
+
+// Holds the pvpanic device as:
+// Scope (\_SB) {
+//     Device (PEVT) {
+//         Name (_HID, "QEMU0001")
+//         Name (_CRS, ResourceTemplate () { Memory32Fixed (ReadWrite, start, len) })
+//     }
+// }
+fn dsdt(pvpanic: (GuestAddress, u64)) -> Vec<u8> {
+    let (start, len) = pvpanic;
+    // Memory32Fixed descriptor, followed by the end tag.
+    let mut resources = vec![0x86, 9, 0, 1];
+    resources.extend_from_slice(&(start.0 as u32).to_le_bytes());
+    resources.extend_from_slice(&(len as u32).to_le_bytes());
+    resources.extend_from_slice(&[0x79, 0]);
+    let mut buffer = vec![AML_BYTE_PREFIX, resources.len() as u8];
+    buffer.extend_from_slice(&resources);
+
+    let mut device = b"PEVT".to_vec();
+    device.push(AML_NAME_OP);
+    device.extend_from_slice(b"_HID");
+    device.push(AML_STRING_PREFIX);
+    device.extend_from_slice(b"QEMU0001\0");
+    device.push(AML_NAME_OP);
+    device.extend_from_slice(b"_CRS");
+    device.extend_from_slice(&aml_package(AML_BUFFER_OP, &buffer));
+
+    let mut scope = b"\\_SB_".to_vec();
+    scope.extend_from_slice(&aml_package(AML_DEVICE_OP, &device));
+
+    table(b"DSDT", 2, &aml_package(AML_SCOPE_OP, &scope))
+}
+
+/// Writes the tables describing the NUMA nodes of the guest, if any, and its
+/// pvpanic device, if it has one at the `pvpanic` address range.
+pub fn setup_tables(
+    mem: &GuestMemoryMmap,
+    nodes: &[NumaNode],
+    pvpanic: Option<(GuestAddress, u64)>,
+) -> Result<()> {
+    // The RSDP is 16-byte aligned, and the XSDT follows it, with room for the
+    // FADT, the SRAT and the SLIT. The other tables come after, 8-byte aligned.
+    let xsdt_addr = ACPI_START + 64;
+    let mut next_addr = xsdt_addr + 64;
+    let mut tables = Vec::new();
+    let mut add_table = |data: Vec<u8>| {
+        let addr = next_addr;
+        next_addr += crate::round_up(data.len(), 8) as u64;
+        tables.push((addr, data));
+        addr
+    };
+
+    let mut entries = Vec::new();
+    if let Some(pvpanic) = pvpanic {
+        // The DSDT is only reachable through the FADT.
+        let dsdt_addr = add_table(dsdt(pvpanic));
+        entries.push(add_table(fadt(dsdt_addr)));
+    }
+    if !nodes.is_empty() {
+        entries.push(add_table(srat(nodes)));
+        entries.push(add_table(slit(nodes)));
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+    if tables
+        .iter()
+        .any(|(addr, data)| addr + data.len() as u64 > ACPI_START + ACPI_MAX_SIZE as u64)
+    {
+        return Err(Error::TooBig);
+    }
+
+    let entries: Vec<u8> = entries.iter().flat_map(|addr| addr.to_le_bytes()).collect();
+    tables.push((ACPI_START, rsdp(xsdt_addr)));
+    tables.push((xsdt_addr, table(b"XSDT", 1, &entries)));
+    for (addr, data) in tables {
+        mem.write_slice(&data, GuestAddress(addr))
+            .map_err(|_| Error::Write)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes() -> Vec<NumaNode> {
+        vec![
+            NumaNode {
+                memory: vec![(GuestAddress(0), 0x4000_0000)],
+                vcpus: vec![0, 1],
+                distances: vec![10, 20],
+            },
+            NumaNode {
+                memory: vec![(GuestAddress(0x4000_0000), 0x4000_0000)],
+                vcpus: vec![2, 3],
+                distances: vec![20, 10],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_tables_checksum() {
+        let srat = srat(&nodes());
+        assert_eq!(&srat[..4], b"SRAT");
+        assert_eq!(srat.len(), HEADER_LEN + 12 + 4 * 16 + 2 * 40);
+        assert_eq!(checksum(&srat), 0);
+
+        let slit = slit(&nodes());
+        assert_eq!(&slit[HEADER_LEN + 8..], [10, 20, 20, 10]);
+        assert_eq!(checksum(&slit), 0);
+
+        let rsdp = rsdp(ACPI_START + 64);
+        assert_eq!(checksum(&rsdp[..20]), 0);
+        assert_eq!(checksum(&rsdp), 0);
+    }
+
+    #[test]
+    fn test_pvpanic_tables() {
+        let dsdt = dsdt((GuestAddress(0xd000_0000), 0x1000));
+        assert_eq!(&dsdt[..4], b"DSDT");
+        assert_eq!(checksum(&dsdt), 0);
+        // The Memory32Fixed descriptor and the end tag close the table.
+        assert_eq!(
+            &dsdt[dsdt.len() - 14..],
+            [0x86, 9, 0, 1, 0, 0, 0, 0xd0, 0, 0x10, 0, 0, 0x79, 0]
+        );
+        // The scope spans the rest of the table.
+        assert_eq!(dsdt[HEADER_LEN], AML_SCOPE_OP[0]);
+        assert_eq!(dsdt[HEADER_LEN + 1] as usize, dsdt.len() - HEADER_LEN - 1);
+
+        let fadt = fadt(0xe0100);
+        assert_eq!(&fadt[..4], b"FACP");
+        assert_eq!(fadt.len(), FADT_LEN);
+        assert_eq!(checksum(&fadt), 0);
+        assert_eq!(fadt[140..148], 0xe0100u64.to_le_bytes());
+        // Not hardware-reduced, and without an SMI command port.
+        assert_eq!(fadt[112..116], [0x30, 0, 0, 0]);
+        assert_eq!(fadt[48..52], [0; 4]);
+        assert_eq!(fadt[46..48], [9, 0]);
+    }
+
+    #[test]
+    fn test_aml_package_length() {
+        assert_eq!(aml_package(AML_SCOPE_OP, &[0; 62])[..2], [0x10, 63]);
+        let package = aml_package(AML_SCOPE_OP, &[0; 63]);
+        assert_eq!(package[..3], [0x10, 0x41, 4]);
+        assert_eq!(package.len(), 66);
+    }
+
+    #[test]
+    fn test_setup_tables() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        setup_tables(&mem, &nodes(), Some((GuestAddress(0xd000_0000), 0x1000))).unwrap();
+
+        let mut signature = [0u8; 8];
+        mem.read_slice(&mut signature, GuestAddress(ACPI_START))
+            .unwrap();
+        assert_eq!(&signature, b"RSD PTR ");
+        let xsdt_addr: u64 = mem.read_obj(GuestAddress(ACPI_START + 24)).unwrap();
+        let xsdt_len: u32 = mem.read_obj(GuestAddress(xsdt_addr + 4)).unwrap();
+        assert_eq!(xsdt_len as usize, HEADER_LEN + 3 * 8);
+        for (index, expected) in [b"FACP", b"SRAT", b"SLIT"].into_iter().enumerate() {
+            let addr: u64 = mem
+                .read_obj(GuestAddress(xsdt_addr + 36 + 8 * index as u64))
+                .unwrap();
+            mem.read_slice(&mut signature[..4], GuestAddress(addr))
+                .unwrap();
+            assert_eq!(&signature[..4], expected);
+        }
+
+        let fadt_addr: u64 = mem.read_obj(GuestAddress(xsdt_addr + 36)).unwrap();
+        let dsdt_addr: u64 = mem.read_obj(GuestAddress(fadt_addr + 140)).unwrap();
+        mem.read_slice(&mut signature[..4], GuestAddress(dsdt_addr))
+            .unwrap();
+        assert_eq!(&signature[..4], b"DSDT");
+    }
+}
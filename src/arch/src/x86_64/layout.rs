@@ -14,26 +14,55 @@ pub const BOOT_STACK_POINTER: u64 = 0x8ff0;
 pub const CMDLINE_START: u64 = 0x20000;
 /// Kernel command line start address maximum size.
 pub const CMDLINE_MAX_SIZE: usize = 0x10000;
-/// Kernel command line static size on SEV.
-pub const CMDLINE_SEV_SIZE: usize = 0x200;
+/// Start of the area the launch secrets are injected at on SEV, right after
+/// the command line so the measured command line is never overwritten.
+///
+/// This is the contract with the guest: the area is reserved in the e820 map
+/// and measured while zeroed, so anything the guest finds in it was injected
+/// by the firmware after attestation. The n-th secret requested is injected at
+/// `SEV_SECRET_START + n * SEV_SECRET_SLOT_SIZE`, and slots without a secret
+/// are left zeroed.
+pub const SEV_SECRET_START: u64 = CMDLINE_START + CMDLINE_MAX_SIZE as u64;
+/// Size of the area the launch secrets are injected at on SEV.
+pub const SEV_SECRET_SIZE: usize = 0x10000;
+/// Size of each secret slot in the SEV secret area, a page.
+pub const SEV_SECRET_SLOT_SIZE: usize = 0x1000;
 /// Initrd start address on SEV.
 pub const INITRD_SEV_START: u64 = 0xa00000;
 
 /// Start of the high memory.
 pub const HIMEM_START: u64 = 0x0010_0000; //1 MB.
 
+/// ACPI tables, in the BIOS area the kernel scans for the RSDP.
+pub const ACPI_START: u64 = 0xe_0000;
+/// Size of the area of the ACPI tables, up to the high memory.
+pub const ACPI_MAX_SIZE: usize = 0x2_0000;
+
+/// End of the guest physical address space, as wide as the narrowest physical
+/// addresses of x86_64 CPUs (39 bits), so shared memory is reachable on any host.
+pub const GUEST_PHYS_END: u64 = 1 << 39;
+
 // Typically, on x86 systems 16 IRQs are used (0-15).
 /// First usable IRQ ID for virtio device interrupts on x86_64.
 pub const IRQ_BASE: u32 = 5;
 /// Last usable IRQ ID for virtio device interrupts on x86_64.
 pub const IRQ_MAX: u32 = 15;
 
-/// Address for the TSS setup.
-pub const KVM_TSS_ADDRESS: u64 = 0xfffb_d000;
+/// Address for the TSS setup, right below the top 16 MiB of the 32-bit address
+/// space where firmware images are mapped.
+pub const KVM_TSS_ADDRESS: u64 = 0xfeff_d000;
+
+/// Address of the TPM TIS registers, where the guest driver looks for them when forced.
+pub const TPM_TIS_START: u64 = 0xfed4_0000;
 
 /// The 'zero page', a.k.a linux kernel bootparams.
 pub const ZERO_PAGE_START: u64 = 0x7000;
 
+/// SEV-ES: AP jump table page, reserved in the e820 map.
+pub const SEV_AP_JUMP_TABLE_START: u64 = 0x4000;
+/// SEV-ES: AP jump table page size.
+pub const SEV_AP_JUMP_TABLE_SIZE: usize = 0x1000;
+
 /// SNP: space for the initial LIDT
 pub const SNP_LIDT_START: u64 = 0x0;
 /// SNP: Secrets page.
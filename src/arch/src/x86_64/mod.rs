@@ -5,6 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+mod acpi;
 mod gdt;
 /// Contains logic for setting up Advanced Programmable Interrupt Controller (local version).
 pub mod interrupts;
@@ -17,7 +18,11 @@ pub mod msr;
 /// Logic for configuring x86_64 registers.
 pub mod regs;
 
-use crate::{round_up, ArchMemoryInfo, InitrdConfig};
+use std::cmp::{max, min};
+
+use crate::{round_up, ArchMemoryInfo, InitrdConfig, NumaNode};
+#[cfg(feature = "tee")]
+use arch_gen::x86::bootparam::E820_RESERVED;
 use arch_gen::x86::bootparam::{boot_params, E820_RAM};
 use vm_memory::Bytes;
 use vm_memory::{
@@ -38,6 +43,8 @@ unsafe impl ByteValued for BootParamsWrapper {}
 /// Errors thrown while configuring x86_64 system.
 #[derive(Debug, Eq, PartialEq)]
 pub enum Error {
+    /// Error writing the ACPI tables describing the NUMA nodes and the pvpanic device.
+    AcpiSetup(acpi::Error),
     /// Invalid e820 setup params.
     E820Configuration,
     /// Error writing MP table to memory.
@@ -47,18 +54,26 @@ pub enum Error {
     ZeroPageSetup,
     /// Failed to compute initrd address.
     InitrdAddress,
+    /// Error writing the SEV-ES AP jump table to guest memory.
+    #[cfg(feature = "tee")]
+    ApJumpTableSetup,
 }
 
 // Where BIOS/VGA magic would live on a real PC.
 const EBDA_START: u64 = 0x9fc00;
 pub const RESET_VECTOR: u64 = 0xfff0;
 pub const RESET_VECTOR_SEV_AP: u64 = 0xfff3;
+/// Real mode code segment of the reset vector.
+pub const RESET_SEGMENT: u16 = 0xf000;
 pub const BIOS_START: u64 = 0xffff_0000;
 pub const BIOS_SIZE: usize = 65536;
-const FIRST_ADDR_PAST_32BITS: u64 = 1 << 32;
+/// The first address past the 32-bit address space, where the firmware ends.
+pub const FIRST_ADDR_PAST_32BITS: u64 = 1 << 32;
 const MEM_32BIT_GAP_SIZE: u64 = 768 << 20;
 /// The start of the memory area reserved for MMIO devices.
 pub const MMIO_MEM_START: u64 = FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE;
+/// Last address the initrd may span, as the kernel may not reach it higher.
+const INITRD_ADDR_MAX: u64 = 0x7fff_ffff;
 
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemoryMmap structure for the platform.
@@ -122,13 +137,14 @@ pub fn arch_memory_regions(
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemoryMmap structure for the platform.
 /// For SEV, don't make a hole for the kernel, as it needs to be copied instead of injected,
-/// don't reserve an SHM region, as virtio-fs is not supported, and reserve a small 64K
-/// region for the BIOS.
+/// don't reserve an SHM region, as virtio-fs is not supported, and reserve a region
+/// for the firmware, ending at 4 GiB.
 #[cfg(feature = "tee")]
 pub fn arch_memory_regions(
     size: usize,
     kernel_load_addr: u64,
     kernel_size: usize,
+    firmware_size: usize,
 ) -> (ArchMemoryInfo, Vec<(GuestAddress, usize)>) {
     let page_size: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap() };
 
@@ -149,7 +165,10 @@ pub fn arch_memory_regions(
                 shm_start_addr,
                 vec![
                     (GuestAddress(0), size),
-                    (GuestAddress(BIOS_START), BIOS_SIZE),
+                    (
+                        GuestAddress(firmware_load_addr(firmware_size)),
+                        firmware_size,
+                    ),
                 ],
             )
         }
@@ -162,7 +181,10 @@ pub fn arch_memory_regions(
                 shm_start_addr,
                 vec![
                     (GuestAddress(0), MMIO_MEM_START as usize),
-                    (GuestAddress(BIOS_START), BIOS_SIZE),
+                    (
+                        GuestAddress(firmware_load_addr(firmware_size)),
+                        firmware_size,
+                    ),
                     (GuestAddress(FIRST_ADDR_PAST_32BITS), remaining),
                 ],
             )
@@ -176,25 +198,35 @@ pub fn arch_memory_regions(
     (info, regions)
 }
 
+/// Returns the address a firmware image of `size` bytes is loaded at, so it
+/// ends at 4 GiB and covers the reset vector.
+pub fn firmware_load_addr(size: usize) -> u64 {
+    FIRST_ADDR_PAST_32BITS - size as u64
+}
+
 /// Returns the memory address where the kernel could be loaded.
 pub fn get_kernel_start() -> u64 {
     layout::HIMEM_START
 }
 
 /// Returns the memory address where the initrd could be loaded.
+///
+/// The initrd is placed as high as possible in the RAM below `INITRD_ADDR_MAX`,
+/// away from the boot structures in low memory, and never overlapping a region
+/// boundary, so it stays clear of the kernel.
 pub fn initrd_load_addr(guest_mem: &GuestMemoryMmap, initrd_size: usize) -> super::Result<u64> {
-    let first_region = guest_mem
-        .find_region(GuestAddress::new(0))
-        .ok_or(Error::InitrdAddress)?;
-    // It's safe to cast to usize because the size of a region can't be greater than usize.
-    let lowmem_size = first_region.len() as usize;
-
-    if lowmem_size < initrd_size {
-        return Err(Error::InitrdAddress);
-    }
+    let align_to_pagesize = |address| address & !(super::PAGE_SIZE as u64 - 1);
 
-    let align_to_pagesize = |address| address & !(super::PAGE_SIZE - 1);
-    Ok(align_to_pagesize(lowmem_size - initrd_size) as u64)
+    guest_mem
+        .iter()
+        .filter_map(|region| {
+            let start = max(region.start_addr().raw_value(), layout::HIMEM_START);
+            let end = min(region.last_addr().raw_value(), INITRD_ADDR_MAX) + 1;
+            let address = align_to_pagesize(end.checked_sub(initrd_size as u64)?);
+            (address >= start).then_some(address)
+        })
+        .max()
+        .ok_or(Error::InitrdAddress)
 }
 
 /// Configures the system and should be called once per vm before starting vcpu threads.
@@ -206,7 +238,9 @@ pub fn initrd_load_addr(guest_mem: &GuestMemoryMmap, initrd_size: usize) -> supe
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `initrd` - Information about where the ramdisk image was loaded in the `guest_mem`.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
-#[allow(unused_variables)]
+/// * `numa_nodes` - NUMA topology of the guest, described through ACPI when not empty.
+/// * `pvpanic` - Address range of the pvpanic device, described through ACPI when set.
+#[allow(clippy::too_many_arguments, unused_variables)]
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
     arch_memory_info: &ArchMemoryInfo,
@@ -214,6 +248,8 @@ pub fn configure_system(
     cmdline_size: usize,
     initrd: &Option<InitrdConfig>,
     num_cpus: u8,
+    numa_nodes: &[NumaNode],
+    pvpanic: Option<(GuestAddress, u64)>,
 ) -> super::Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
@@ -228,6 +264,8 @@ pub fn configure_system(
     #[cfg(not(feature = "tee"))]
     mptable::setup_mptable(guest_mem, num_cpus).map_err(Error::MpTableSetup)?;
 
+    acpi::setup_tables(guest_mem, numa_nodes, pvpanic).map_err(Error::AcpiSetup)?;
+
     let mut params: BootParamsWrapper = BootParamsWrapper(boot_params::default());
 
     params.0.hdr.type_of_loader = KERNEL_LOADER_OTHER;
@@ -247,8 +285,38 @@ pub fn configure_system(
         params.0.hdr.syssize = num_cpus as u32;
     }
 
+    #[cfg(not(feature = "tee"))]
     add_e820_entry(&mut params.0, 0, EBDA_START, E820_RAM)?;
 
+    // Keep the guest from reusing the SEV-ES AP jump table and the SEV secret
+    // area as regular RAM.
+    #[cfg(feature = "tee")]
+    {
+        let jump_table_end =
+            layout::SEV_AP_JUMP_TABLE_START + layout::SEV_AP_JUMP_TABLE_SIZE as u64;
+        let secret_end = layout::SEV_SECRET_START + layout::SEV_SECRET_SIZE as u64;
+        add_e820_entry(&mut params.0, 0, layout::SEV_AP_JUMP_TABLE_START, E820_RAM)?;
+        add_e820_entry(
+            &mut params.0,
+            layout::SEV_AP_JUMP_TABLE_START,
+            layout::SEV_AP_JUMP_TABLE_SIZE as u64,
+            E820_RESERVED,
+        )?;
+        add_e820_entry(
+            &mut params.0,
+            jump_table_end,
+            layout::SEV_SECRET_START - jump_table_end,
+            E820_RAM,
+        )?;
+        add_e820_entry(
+            &mut params.0,
+            layout::SEV_SECRET_START,
+            layout::SEV_SECRET_SIZE as u64,
+            E820_RESERVED,
+        )?;
+        add_e820_entry(&mut params.0, secret_end, EBDA_START - secret_end, E820_RAM)?;
+    }
+
     let last_addr = GuestAddress(arch_memory_info.ram_last_addr);
     if last_addr < end_32bit_gap_start {
         add_e820_entry(
@@ -289,6 +357,33 @@ pub fn configure_system(
     Ok(())
 }
 
+/// Writes the initial SEV-ES AP jump table.
+///
+/// SEV-ES APs can't be started with INIT-SIPI, as their register state lives in
+/// the encrypted VMSA. Instead, they start at `RESET_VECTOR_SEV_AP` in the firmware,
+/// which registers this page with the hypervisor through the GHCB AP jump table
+/// NAE event and far jumps to the CS:IP stored in its first four bytes. The guest
+/// kernel later overwrites that entry to point at its own trampoline.
+///
+/// The page must be written before the BSP VMSA is measured, so it's part of the
+/// launch measurement.
+#[cfg(feature = "tee")]
+pub fn setup_sev_ap_jump_table(guest_mem: &GuestMemoryMmap) -> super::Result<()> {
+    let jump_table_addr = GuestAddress(layout::SEV_AP_JUMP_TABLE_START);
+
+    guest_mem
+        .write_slice(&[0u8; layout::SEV_AP_JUMP_TABLE_SIZE], jump_table_addr)
+        .map_err(|_| Error::ApJumpTableSetup)?;
+    guest_mem
+        .write_obj(RESET_VECTOR_SEV_AP as u16, jump_table_addr)
+        .map_err(|_| Error::ApJumpTableSetup)?;
+    guest_mem
+        .write_obj(RESET_SEGMENT, jump_table_addr.unchecked_add(2))
+        .map_err(|_| Error::ApJumpTableSetup)?;
+
+    Ok(())
+}
+
 /// Add an e820 region to the e820 map.
 /// Returns Ok(()) if successful, or an error if there is no space left in the map.
 fn add_e820_entry(
@@ -344,12 +439,33 @@ mod tests {
         assert_eq!(GuestAddress(1u64 << 32), regions[2].0);
     }
 
+    #[test]
+    fn test_initrd_load_addr() {
+        let (_info, regions) = arch_memory_regions(1usize << 29, KERNEL_LOAD_ADDR, KERNEL_SIZE);
+        let gm = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        let ram_end = KERNEL_LOAD_ADDR + KERNEL_SIZE as u64 + (1u64 << 29);
+
+        // Loaded at the end of the RAM past the kernel, page aligned.
+        assert_eq!(initrd_load_addr(&gm, 0x1800).unwrap(), ram_end - 0x2000);
+        // Too big for any region.
+        assert!(initrd_load_addr(&gm, 1usize << 30).is_err());
+
+        // Kept below INITRD_ADDR_MAX even when the RAM extends past it.
+        let (_info, regions) =
+            arch_memory_regions((1usize << 32) + 0x8000, KERNEL_LOAD_ADDR, KERNEL_SIZE);
+        let gm = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        assert_eq!(
+            initrd_load_addr(&gm, 0x1000).unwrap(),
+            INITRD_ADDR_MAX + 1 - 0x1000
+        );
+    }
+
     #[test]
     fn test_system_configuration() {
         let no_vcpus = 4;
         let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let info = ArchMemoryInfo::default();
-        let config_err = configure_system(&gm, &info, GuestAddress(0), 0, &None, 1);
+        let config_err = configure_system(&gm, &info, GuestAddress(0), 0, &None, 1, &[], None);
         assert!(config_err.is_err());
         #[cfg(not(feature = "tee"))]
         assert_eq!(
@@ -362,21 +478,51 @@ mod tests {
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            no_vcpus,
+            &[],
+            None,
+        )
+        .unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            no_vcpus,
+            &[],
+            None,
+        )
+        .unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            no_vcpus,
+            &[],
+            None,
+        )
+        .unwrap();
     }
 
     #[test]
@@ -51,7 +51,13 @@ pub mod leaf_0x1 {
         // 18 = DCA Direct Cache Access (prefetch data from a memory mapped device)
         pub const MOVBE_BITINDEX: u32 = 22;
         pub const TSC_DEADLINE_TIMER_BITINDEX: u32 = 24;
+        // XSAVE = XSAVE/XRSTOR processor extended states
+        pub const XSAVE_BITINDEX: u32 = 26;
         pub const OSXSAVE_BITINDEX: u32 = 27;
+        // AVX = Advanced Vector Extensions
+        pub const AVX_BITINDEX: u32 = 28;
+        // F16C = 16-bit floating-point conversion instructions
+        pub const F16C_BITINDEX: u32 = 29;
         // Cpu is running on a hypervisor.
         pub const HYPERVISOR_BITINDEX: u32 = 31;
     }
@@ -172,9 +178,16 @@ pub mod leaf_0x7 {
             // OSPKE = If 1, OS has set CR4.PKE to enable protection keys
             pub const OSPKE_BITINDEX: u32 = 4;
             // 5 = WAITPKG
-            // 7-6 reserved
+            // AVX512_VBMI2 = AVX-512 Vector Byte Manipulation Instructions 2
+            pub const AVX512_VBMI2_BITINDEX: u32 = 6;
+            // 7 reserved
             // 8 = GFNI
-            // 13-09 reserved
+            // 10-09 reserved
+            // AVX512_VNNI = AVX-512 Vector Neural Network Instructions
+            pub const AVX512_VNNI_BITINDEX: u32 = 11;
+            // AVX512_BITALG = AVX-512 Bit Algorithms
+            pub const AVX512_BITALG_BITINDEX: u32 = 12;
+            // 13 reserved
             // AVX512_VPOPCNTDQ = Vector population count instruction (Intel® Xeon Phi™ only.)
             pub const AVX512_VPOPCNTDQ_BITINDEX: u32 = 14;
             // 21 - 17 = The value of MAWAU used by the BNDLDX and BNDSTX instructions in 64-bit mode.
@@ -240,6 +253,7 @@ pub mod leaf_0xd {
         pub mod eax {
             use crate::bit_helper::BitRange;
 
+            pub const AVX_STATE_BITINDEX: u32 = 2;
             pub const MPX_STATE_BITRANGE: BitRange = bit_range!(4, 3);
             pub const AVX512_STATE_BITRANGE: BitRange = bit_range!(7, 5);
         }
@@ -21,6 +21,8 @@ pub mod bit_helper;
 mod template;
 pub use crate::template::c3;
 pub use crate::template::t2;
+pub use crate::template::x86_64_v2;
+pub use crate::template::x86_64_v3;
 
 mod cpu_leaf;
 
@@ -5,3 +5,7 @@
 pub mod c3;
 /// Follows a T2 template in setting up the CPUID.
 pub mod t2;
+/// Masks the CPUID features beyond the x86-64-v2 microarchitecture level.
+pub mod x86_64_v2;
+/// Masks the CPUID features beyond the x86-64-v3 microarchitecture level.
+pub mod x86_64_v3;
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::x86_64_v3;
+use crate::bit_helper::BitHelper;
+use crate::cpu_leaf::*;
+use crate::transformer::*;
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
+
+// The x86-64-v2 level of the x86-64 psABI goes up to SSE4.2 and POPCNT, so on
+// top of what x86-64-v3 masks, the AVX, AVX2, FMA, BMI and XSAVE families are.
+
+fn update_feature_info_entry(entry: &mut kvm_cpuid_entry2, _vm_spec: &VmSpec) -> Result<(), Error> {
+    use crate::cpu_leaf::leaf_0x1::*;
+
+    entry
+        .ecx
+        .write_bit(ecx::FMA_BITINDEX, false)
+        .write_bit(ecx::MOVBE_BITINDEX, false)
+        .write_bit(ecx::XSAVE_BITINDEX, false)
+        .write_bit(ecx::OSXSAVE_BITINDEX, false)
+        .write_bit(ecx::AVX_BITINDEX, false)
+        .write_bit(ecx::F16C_BITINDEX, false);
+
+    Ok(())
+}
+
+fn update_structured_extended_entry(
+    entry: &mut kvm_cpuid_entry2,
+    vm_spec: &VmSpec,
+) -> Result<(), Error> {
+    use crate::cpu_leaf::leaf_0x7::index0::*;
+
+    x86_64_v3::update_structured_extended_entry(entry, vm_spec)?;
+
+    if entry.index == 0 {
+        entry
+            .ebx
+            .write_bit(ebx::BMI1_BITINDEX, false)
+            .write_bit(ebx::AVX2_BITINDEX, false)
+            .write_bit(ebx::BMI2_BITINDEX, false);
+    }
+
+    Ok(())
+}
+
+fn update_xsave_features_entry(
+    entry: &mut kvm_cpuid_entry2,
+    vm_spec: &VmSpec,
+) -> Result<(), Error> {
+    use crate::cpu_leaf::leaf_0xd::*;
+
+    x86_64_v3::update_xsave_features_entry(entry, vm_spec)?;
+
+    if entry.index == 0 {
+        entry.eax.write_bit(index0::eax::AVX_STATE_BITINDEX, false);
+    }
+
+    Ok(())
+}
+
+fn update_extended_feature_info_entry(
+    entry: &mut kvm_cpuid_entry2,
+    _vm_spec: &VmSpec,
+) -> Result<(), Error> {
+    use crate::cpu_leaf::leaf_0x80000001::*;
+
+    entry.ecx.write_bit(ecx::LZCNT_BITINDEX, false);
+
+    Ok(())
+}
+
+/// Sets up the cpuid entries for a given VCPU following the x86-64-v2 template.
+struct X86_64V2CpuidTransformer {}
+
+impl CpuidTransformer for X86_64V2CpuidTransformer {
+    fn entry_transformer_fn(&self, entry: &mut kvm_cpuid_entry2) -> Option<EntryTransformerFn> {
+        match entry.function {
+            leaf_0x1::LEAF_NUM => Some(update_feature_info_entry),
+            leaf_0x7::LEAF_NUM => Some(update_structured_extended_entry),
+            leaf_0xd::LEAF_NUM => Some(update_xsave_features_entry),
+            leaf_0x80000001::LEAF_NUM => Some(update_extended_feature_info_entry),
+            _ => None,
+        }
+    }
+}
+
+/// Sets up the cpuid entries for a given VCPU following the x86-64-v2 template.
+pub fn set_cpuid_entries(kvm_cpuid: &mut CpuId, vm_spec: &VmSpec) -> Result<(), Error> {
+    X86_64V2CpuidTransformer {}.process_cpuid(kvm_cpuid, vm_spec)
+}
@@ -67,12 +67,18 @@ pub fn update_amd_features_entry(
 ) -> Result<(), Error> {
     use crate::cpu_leaf::leaf_0x80000008::*;
 
-    // We don't support more then 64 threads right now.
-    // It's safe to put them all on the same processor.
+    // We don't support more then 64 threads right now, so a single socket
+    // can hold them all.
     entry
         .ecx
-        .write_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE, THREAD_ID_MAX_SIZE)
-        .write_bits_in_range(&ecx::NUM_THREADS_BITRANGE, u32::from(vm_spec.cpu_count - 1));
+        .write_bits_in_range(
+            &ecx::THREAD_ID_SIZE_BITRANGE,
+            vm_spec.socket_id_shift().unwrap_or(THREAD_ID_MAX_SIZE),
+        )
+        .write_bits_in_range(
+            &ecx::NUM_THREADS_BITRANGE,
+            u32::from(vm_spec.cpus_per_socket() - 1),
+        );
 
     Ok(())
 }
@@ -92,17 +98,13 @@ pub fn update_extended_apic_id_entry(
 ) -> Result<(), Error> {
     use crate::cpu_leaf::leaf_0x8000001e::*;
 
-    let mut core_id = u32::from(vm_spec.cpu_id);
-    // When hyper-threading is enabled each pair of 2 consecutive logical CPUs
-    // will have the same core id since they represent 2 threads in the same core.
-    // For Example:
+    // Consecutive logical CPUs are the threads of the same core, so they share
+    // the same core id. For Example, with 2 threads per core:
     // logical CPU 0 -> core id: 0
     // logical CPU 1 -> core id: 0
     // logical CPU 2 -> core id: 1
     // logical CPU 3 -> core id: 1
-    if vm_spec.ht_enabled {
-        core_id /= 2;
-    }
+    let core_id = u32::from(vm_spec.cpu_id / vm_spec.threads_per_core);
 
     entry
         .eax
@@ -114,7 +116,7 @@ pub fn update_extended_apic_id_entry(
         .write_bits_in_range(&ebx::CORE_ID_BITRANGE, core_id)
         .write_bits_in_range(
             &ebx::THREADS_PER_CORE_BITRANGE,
-            u32::from(vm_spec.ht_enabled),
+            u32::from(vm_spec.threads_per_core - 1),
         );
 
     entry
@@ -352,4 +354,41 @@ mod tests {
         check_update_extended_apic_id_entry(0, 2, true, 0, 1);
         check_update_extended_apic_id_entry(1, 2, true, 0, 1);
     }
+
+    #[test]
+    fn test_multi_socket_topology() {
+        use crate::cpu_leaf::leaf_0x80000008::*;
+
+        // 2 sockets of 2 cores with 2 threads each.
+        let vm_spec = VmSpec::with_topology(5, 8, 2, 2).expect("Error creating vm_spec");
+        let mut entry = kvm_cpuid_entry2 {
+            function: LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+
+        assert!(update_amd_features_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(entry.ecx.read_bits_in_range(&ecx::NUM_THREADS_BITRANGE), 3);
+        assert_eq!(
+            entry.ecx.read_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE),
+            2
+        );
+
+        let mut entry = kvm_cpuid_entry2 {
+            function: leaf_0x8000001e::LEAF_NUM,
+            ..entry
+        };
+        assert!(update_extended_apic_id_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(
+            entry
+                .ebx
+                .read_bits_in_range(&leaf_0x8000001e::ebx::CORE_ID_BITRANGE),
+            2
+        );
+    }
 }
@@ -30,10 +30,9 @@ fn update_deterministic_cache_entry(
 
     common::update_cache_parameters_entry(entry, vm_spec)?;
 
-    // Put all the cores in the same socket
     entry.eax.write_bits_in_range(
         &eax::MAX_CORES_PER_PACKAGE_BITRANGE,
-        u32::from(vm_spec.cpu_count - 1),
+        u32::from(vm_spec.cpus_per_socket() - 1),
     );
 
     Ok(())
@@ -79,17 +78,15 @@ fn update_extended_cache_topology_entry(
     match entry.index {
         // Thread Level Topology; index = 0
         0 => {
-            // To get the next level APIC ID, shift right with at most 1 because we have
-            // maximum 2 hyperthreads per core that can be represented by 1 bit.
-            entry.eax.write_bits_in_range(
-                &eax::APICID_BITRANGE,
-                (vm_spec.cpu_count > 1 && vm_spec.ht_enabled) as u32,
-            );
-            // When cpu_count == 1 or HT is disabled, there is 1 logical core at this level
-            // Otherwise there are 2
+            // To get the next level APIC ID, shift right by the number of bits needed to
+            // represent the hyperthreads of a core.
+            entry
+                .eax
+                .write_bits_in_range(&eax::APICID_BITRANGE, ceil_log2(vm_spec.cpus_per_core()));
+            // There are as many logical cores at this level as hyperthreads per core
             entry.ebx.write_bits_in_range(
                 &ebx::NUM_LOGICAL_PROCESSORS_BITRANGE,
-                1 + (vm_spec.cpu_count > 1 && vm_spec.ht_enabled) as u32,
+                u32::from(vm_spec.cpus_per_core()),
             );
 
             entry.ecx.write_bits_in_range(&ecx::LEVEL_TYPE_BITRANGE, {
@@ -103,9 +100,10 @@ fn update_extended_cache_topology_entry(
         }
         // Core Level Processor Topology; index = 1
         1 => {
-            entry
-                .eax
-                .write_bits_in_range(&eax::APICID_BITRANGE, LEAFBH_INDEX1_APICID);
+            entry.eax.write_bits_in_range(
+                &eax::APICID_BITRANGE,
+                vm_spec.socket_id_shift().unwrap_or(LEAFBH_INDEX1_APICID),
+            );
             entry
                 .ecx
                 .write_bits_in_range(&ecx::LEVEL_NUMBER_BITRANGE, entry.index);
@@ -117,7 +115,7 @@ fn update_extended_cache_topology_entry(
             } else {
                 entry.ebx.write_bits_in_range(
                     &ebx::NUM_LOGICAL_PROCESSORS_BITRANGE,
-                    u32::from(vm_spec.cpu_count),
+                    u32::from(vm_spec.cpus_per_socket()),
                 );
                 entry
                     .ecx
@@ -364,4 +362,46 @@ mod tests {
             LEVEL_TYPE_CORE,
         );
     }
+
+    #[test]
+    fn test_multi_socket_topology() {
+        use crate::cpu_leaf::leaf_0xb::*;
+
+        // 2 sockets of 2 cores with 2 threads each.
+        let vm_spec = VmSpec::with_topology(5, 8, 2, 2).expect("Error creating vm_spec");
+        let mut entry = kvm_cpuid_entry2 {
+            function: 0x0,
+            index: 0,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+
+        assert!(update_extended_cache_topology_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(entry.eax.read_bits_in_range(&eax::APICID_BITRANGE), 1);
+        assert_eq!(
+            entry
+                .ebx
+                .read_bits_in_range(&ebx::NUM_LOGICAL_PROCESSORS_BITRANGE),
+            2
+        );
+        assert_eq!(entry.edx, 5);
+
+        entry.index = 1;
+        assert!(update_extended_cache_topology_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(entry.eax.read_bits_in_range(&eax::APICID_BITRANGE), 2);
+        assert_eq!(
+            entry
+                .ebx
+                .read_bits_in_range(&ebx::NUM_LOGICAL_PROCESSORS_BITRANGE),
+            4
+        );
+        assert_eq!(
+            entry.ecx.read_bits_in_range(&ecx::LEVEL_TYPE_BITRANGE),
+            LEVEL_TYPE_CORE
+        );
+    }
 }
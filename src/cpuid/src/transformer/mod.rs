@@ -19,8 +19,10 @@ pub struct VmSpec {
     cpu_id: u8,
     /// The total number of logical cpus.
     cpu_count: u8,
-    /// Specifies whether hyper-threading is enabled.
-    ht_enabled: bool,
+    /// The number of logical cpus per core.
+    threads_per_core: u8,
+    /// The number of cores per socket.
+    cores_per_socket: u8,
     /// The desired brand string for the guest.
     brand_string: BrandString,
 }
@@ -28,14 +30,36 @@ pub struct VmSpec {
 impl VmSpec {
     /// Creates a new instance of VmSpec with the specified parameters
     /// The brand string is deduced from the vendor_id
+    /// All the cpus are put in a single socket, with 2 threads per core if hyper-threading is
+    /// enabled.
     pub fn new(cpu_id: u8, cpu_count: u8, ht_enabled: bool) -> Result<VmSpec, Error> {
+        let threads_per_core = 1 + u8::from(ht_enabled);
+        VmSpec::with_topology(
+            cpu_id,
+            cpu_count,
+            threads_per_core,
+            cpu_count.div_ceil(threads_per_core),
+        )
+    }
+
+    /// Creates a new instance of VmSpec with the cpus laid out in sockets of `cores_per_socket`
+    /// cores of `threads_per_core` threads each. The APIC ID of each cpu is its id, so the number
+    /// of threads per core, and the number of cores per socket when there are several sockets,
+    /// must be powers of 2.
+    pub fn with_topology(
+        cpu_id: u8,
+        cpu_count: u8,
+        threads_per_core: u8,
+        cores_per_socket: u8,
+    ) -> Result<VmSpec, Error> {
         let cpu_vendor_id = get_vendor_id().map_err(Error::InternalError)?;
 
         Ok(VmSpec {
             cpu_vendor_id,
             cpu_id,
             cpu_count,
-            ht_enabled,
+            threads_per_core,
+            cores_per_socket,
             brand_string: BrandString::from_vendor_id(&cpu_vendor_id),
         })
     }
@@ -44,6 +68,36 @@ impl VmSpec {
     pub fn cpu_vendor_id(&self) -> &[u8; 12] {
         &self.cpu_vendor_id
     }
+
+    /// Returns the number of logical cpus sharing a core.
+    fn cpus_per_core(&self) -> u8 {
+        if self.cpu_count > 1 {
+            self.threads_per_core
+        } else {
+            1
+        }
+    }
+
+    /// Returns the number of logical cpus sharing a socket.
+    fn cpus_per_socket(&self) -> u8 {
+        let cpus_per_socket = u16::from(self.threads_per_core) * u16::from(self.cores_per_socket);
+        cpus_per_socket.min(u16::from(self.cpu_count)) as u8
+    }
+
+    /// Returns how many bits an APIC ID must be shifted right to get the id of its socket, if the
+    /// cpus are spread over several sockets.
+    fn socket_id_shift(&self) -> Option<u32> {
+        if self.cpus_per_socket() < self.cpu_count {
+            Some(ceil_log2(self.cpus_per_socket()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the number of bits needed to represent `count` different ids.
+fn ceil_log2(count: u8) -> u32 {
+    u32::from(count).next_power_of_two().trailing_zeros()
 }
 
 /// Errors associated with processing the CPUID leaves.
@@ -8,13 +8,17 @@
 #[cfg(target_os = "macos")]
 mod gicv3;
 mod i8042;
+mod pvpanic;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
+mod tpm;
 #[cfg(target_os = "macos")]
 mod vcpu;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 #[cfg(target_arch = "x86_64")]
+use x86_64::ib700;
+#[cfg(target_arch = "x86_64")]
 use x86_64::serial;
 #[cfg(target_arch = "aarch64")]
 mod aarch64;
@@ -29,9 +33,13 @@ pub use self::gicv3::GicV3;
 pub use self::gpio::Gpio;
 pub use self::i8042::Error as I8042DeviceError;
 pub use self::i8042::I8042Device;
+#[cfg(target_arch = "x86_64")]
+pub use self::ib700::{Ib700Watchdog, IB700_BASE, IB700_LEN};
+pub use self::pvpanic::{PvPanic, PVPANIC_CRASH_LOADED, PVPANIC_PANICKED};
 #[cfg(target_arch = "aarch64")]
 pub use self::rtc_pl031::RTC;
 pub use self::serial::Serial;
+pub use self::tpm::{start_swtpm, Tpm, TPM_TIS_SIZE};
 #[cfg(target_os = "macos")]
 pub use self::vcpu::VcpuList;
 
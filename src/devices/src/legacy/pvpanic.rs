@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! pvpanic device
+//!
+//! This module implements the MMIO flavour of the QEMU pvpanic device, through which the guest
+//! kernel reports it panicked. The guest reads the register at offset 0 to learn which events are
+//! supported and writes the events it wants to report to the same register. Each report is
+//! forwarded to the host as a single byte holding the event bits, and panics can also be signaled
+//! to the VMM through an eventfd.
+
+use std::fs::File;
+use std::io::Write;
+
+use utils::eventfd::EventFd;
+
+use crate::BusDevice;
+
+/// The guest kernel panicked.
+pub const PVPANIC_PANICKED: u8 = 1 << 0;
+/// The guest kernel panicked and is about to boot a crash kernel.
+pub const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+const PVPANIC_EVENTS: u8 = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+
+pub struct PvPanic {
+    output: Option<File>,
+    panic_evt: Option<EventFd>,
+}
+
+impl PvPanic {
+    /// Constructs a pvpanic device forwarding the events to `output`, and
+    /// signaling `panic_evt` when the guest kernel panics.
+    pub fn new(output: Option<File>, panic_evt: Option<EventFd>) -> PvPanic {
+        PvPanic { output, panic_evt }
+    }
+}
+
+impl BusDevice for PvPanic {
+    fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        if offset != 0 || data.len() != 1 {
+            warn!(
+                "Invalid pvpanic read: offset {}, data length {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        data[0] = PVPANIC_EVENTS;
+    }
+
+    fn write(&mut self, _vcpuid: u64, offset: u64, data: &[u8]) {
+        if offset != 0 || data.len() != 1 {
+            warn!(
+                "Invalid pvpanic write: offset {}, data length {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        let events = data[0] & PVPANIC_EVENTS;
+        if events == 0 {
+            return;
+        }
+
+        error!("Guest kernel panicked, events={:#x}", events);
+        if let Some(output) = self.output.as_mut() {
+            if let Err(e) = output.write_all(&[events]) {
+                error!("Failed to report the guest panic: {}", e);
+            }
+        }
+        if events & PVPANIC_PANICKED != 0 {
+            if let Some(panic_evt) = self.panic_evt.as_ref() {
+                if let Err(e) = panic_evt.write(1) {
+                    error!("Failed to signal the guest panic: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    #[test]
+    fn test_pvpanic_read_write() {
+        let (reader, writer) = nix::unistd::pipe().unwrap();
+        let mut reader = unsafe { File::from_raw_fd(reader) };
+        let mut pvpanic = PvPanic::new(Some(unsafe { File::from_raw_fd(writer) }), None);
+        let mut data = [0u8; 1];
+
+        pvpanic.read(0, 0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+
+        // Unsupported events and invalid accesses aren't reported.
+        pvpanic.write(0, 0, &[1 << 7]);
+        pvpanic.write(0, 1, &[PVPANIC_PANICKED]);
+        pvpanic.write(0, 0, &[PVPANIC_PANICKED, 0]);
+
+        pvpanic.write(0, 0, &[PVPANIC_PANICKED]);
+        pvpanic.write(0, 0, &[PVPANIC_CRASH_LOADED | 1 << 7]);
+
+        drop(pvpanic);
+        let mut reported = Vec::new();
+        reader.read_to_end(&mut reported).unwrap();
+        assert_eq!(reported, vec![PVPANIC_PANICKED, PVPANIC_CRASH_LOADED]);
+    }
+
+    #[test]
+    fn test_pvpanic_panic_evt() {
+        let panic_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap();
+        let mut pvpanic = PvPanic::new(None, Some(panic_evt.try_clone().unwrap()));
+
+        // Only the panics themselves are signaled.
+        pvpanic.write(0, 0, &[PVPANIC_CRASH_LOADED]);
+        assert!(panic_evt.read().is_err());
+
+        pvpanic.write(0, 0, &[PVPANIC_PANICKED]);
+        assert_eq!(panic_evt.read().unwrap(), 1);
+    }
+}
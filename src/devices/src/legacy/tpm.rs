@@ -0,0 +1,386 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! TPM 2.0 device
+//!
+//! This module implements the FIFO (TIS) MMIO interface of a TPM 2.0, as described by the TCG PC
+//! Client Platform TPM Profile specification, for locality 0 only. The guest writes a command to
+//! the data FIFO and sets `tpmGo`, the command is forwarded to a software TPM (swtpm) over a unix
+//! socket, and the response is then read back from the data FIFO. Interrupts aren't supported, so
+//! the guest driver has to poll the status register.
+
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::BusDevice;
+
+/// Size of the register space, one page per locality.
+pub const TPM_TIS_SIZE: u64 = 0x5000;
+
+const TPM_ACCESS: u64 = 0x00;
+const TPM_INT_ENABLE: u64 = 0x08;
+const TPM_INTF_CAPS: u64 = 0x14;
+const TPM_STS: u64 = 0x18;
+const TPM_DATA_FIFO: u64 = 0x24;
+const TPM_INTERFACE_ID: u64 = 0x30;
+const TPM_XDATA_FIFO: u64 = 0x80;
+const TPM_DID_VID: u64 = 0xf00;
+const TPM_RID: u64 = 0xf04;
+const TPM_LOCALITY_SIZE: u64 = 0x1000;
+
+const TPM_ACCESS_VALID: u8 = 0x80;
+const TPM_ACCESS_ACTIVE_LOCALITY: u8 = 0x20;
+const TPM_ACCESS_REQUEST_USE: u8 = 0x02;
+
+const TPM_STS_VALID: u32 = 0x80;
+const TPM_STS_COMMAND_READY: u32 = 0x40;
+const TPM_STS_GO: u32 = 0x20;
+const TPM_STS_DATA_AVAIL: u32 = 0x10;
+const TPM_STS_EXPECT: u32 = 0x08;
+const TPM_STS_RESPONSE_RETRY: u32 = 0x02;
+const TPM_STS_FAMILY_TPM2: u32 = 1 << 26;
+
+// Interface version 1.3 for TPM 2.0, 64 bytes transfers.
+const TPM_INTF_CAPABILITIES: u32 = (3 << 28) | (3 << 9);
+// FIFO interface, with the TIS registers.
+const TPM_INTERFACE_FIFO: u32 = 1 << 13;
+// IBM, as reported by swtpm.
+const TPM_VENDOR_ID: u32 = 0x1014;
+const TPM_DEVICE_ID: u32 = 0x0001;
+
+/// Largest command or response the TPM handles.
+const TPM_BUFFER_MAX: usize = 4096;
+const TPM_HEADER_SIZE: usize = 10;
+// TPM_ST_NO_SESSIONS, TPM_RC_FAILURE.
+const TPM_FAILURE_RESPONSE: [u8; TPM_HEADER_SIZE] = [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0x01, 0x01];
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    Ready,
+    Reception,
+    Completion,
+}
+
+pub struct Tpm {
+    backend: UnixStream,
+    state: State,
+    active: bool,
+    int_enable: u32,
+    command: Vec<u8>,
+    response: Vec<u8>,
+    response_offset: usize,
+}
+
+/// Starts a swtpm process keeping its state in `state_dir`, and returns the socket its commands
+/// are sent through. The process terminates when the socket is closed.
+pub fn start_swtpm(state_dir: &Path) -> io::Result<UnixStream> {
+    let (vmm_sock, tpm_sock) = UnixStream::pair()?;
+    let fd = tpm_sock.as_raw_fd();
+
+    let mut cmd = Command::new("swtpm");
+    cmd.arg("socket")
+        .arg("--tpm2")
+        .arg("--tpmstate")
+        .arg(format!("dir={}", state_dir.display()))
+        .arg("--server")
+        .arg(format!("type=unixio,fd={fd}"))
+        .arg("--flags")
+        .arg("not-need-init,startup-clear")
+        .arg("--terminate");
+    // Safe because the closure only calls fcntl, which is async-signal-safe.
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    cmd.spawn()?;
+
+    Ok(vmm_sock)
+}
+
+impl Tpm {
+    /// Constructs a TPM device forwarding the commands to the software TPM behind `backend`.
+    pub fn new(backend: UnixStream) -> Tpm {
+        Tpm {
+            backend,
+            state: State::Idle,
+            active: false,
+            int_enable: 0,
+            command: Vec::new(),
+            response: Vec::new(),
+            response_offset: 0,
+        }
+    }
+
+    fn expected_len(&self) -> Option<usize> {
+        if self.command.len() < 6 {
+            return None;
+        }
+        let len = u32::from_be_bytes(self.command[2..6].try_into().unwrap());
+        Some(len as usize)
+    }
+
+    fn expects_data(&self) -> bool {
+        match self.expected_len() {
+            Some(len) => self.command.len() < len,
+            None => true,
+        }
+    }
+
+    fn status(&self) -> u32 {
+        let (flags, burst) = match self.state {
+            State::Idle => (0, 0),
+            State::Ready => (TPM_STS_COMMAND_READY | TPM_STS_EXPECT, TPM_BUFFER_MAX),
+            State::Reception => {
+                let flags = if self.expects_data() {
+                    TPM_STS_EXPECT
+                } else {
+                    0
+                };
+                (flags, TPM_BUFFER_MAX - self.command.len())
+            }
+            State::Completion => {
+                let remaining = self.response.len() - self.response_offset;
+                let flags = if remaining > 0 { TPM_STS_DATA_AVAIL } else { 0 };
+                (flags, remaining)
+            }
+        };
+
+        TPM_STS_FAMILY_TPM2 | TPM_STS_VALID | flags | ((burst.min(0xffff) as u32) << 8)
+    }
+
+    fn register(&self, offset: u64) -> u32 {
+        match offset {
+            TPM_ACCESS => {
+                let active = if self.active {
+                    TPM_ACCESS_ACTIVE_LOCALITY
+                } else {
+                    0
+                };
+                u32::from(TPM_ACCESS_VALID | active)
+            }
+            TPM_INT_ENABLE => self.int_enable,
+            TPM_INTF_CAPS => TPM_INTF_CAPABILITIES,
+            TPM_STS => self.status(),
+            TPM_INTERFACE_ID => TPM_INTERFACE_FIFO,
+            TPM_DID_VID => (TPM_DEVICE_ID << 16) | TPM_VENDOR_ID,
+            TPM_RID => 1,
+            _ => 0,
+        }
+    }
+
+    fn execute(&mut self) {
+        self.response = match self.exchange() {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Failed to execute TPM command: {}", e);
+                TPM_FAILURE_RESPONSE.to_vec()
+            }
+        };
+        self.response_offset = 0;
+        self.command.clear();
+        self.state = State::Completion;
+    }
+
+    fn exchange(&mut self) -> io::Result<Vec<u8>> {
+        self.backend.write_all(&self.command)?;
+
+        let mut response = vec![0u8; TPM_HEADER_SIZE];
+        self.backend.read_exact(&mut response)?;
+        let len = u32::from_be_bytes(response[2..6].try_into().unwrap()) as usize;
+        if !(TPM_HEADER_SIZE..=TPM_BUFFER_MAX).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid response length {len}"),
+            ));
+        }
+        response.resize(len, 0);
+        self.backend.read_exact(&mut response[TPM_HEADER_SIZE..])?;
+
+        Ok(response)
+    }
+
+    fn write_status(&mut self, value: u8) {
+        let value = u32::from(value);
+        if value & TPM_STS_COMMAND_READY != 0 {
+            // Aborts the command being received or drops the response.
+            self.command.clear();
+            self.response.clear();
+            self.response_offset = 0;
+            self.state = State::Ready;
+        } else if value & TPM_STS_GO != 0 {
+            if self.state == State::Reception && !self.expects_data() {
+                self.execute();
+            }
+        } else if value & TPM_STS_RESPONSE_RETRY != 0 && self.state == State::Completion {
+            self.response_offset = 0;
+        }
+    }
+
+    fn write_fifo(&mut self, data: &[u8]) {
+        match self.state {
+            State::Ready | State::Reception => {
+                if self.command.len() + data.len() > TPM_BUFFER_MAX {
+                    warn!("TPM command exceeds {} bytes", TPM_BUFFER_MAX);
+                    return;
+                }
+                self.command.extend_from_slice(data);
+                self.state = State::Reception;
+            }
+            _ => warn!("Unexpected TPM FIFO write in state {:?}", self.state),
+        }
+    }
+
+    fn read_fifo(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = if self.state == State::Completion && self.response_offset < self.response.len()
+            {
+                self.response_offset += 1;
+                self.response[self.response_offset - 1]
+            } else {
+                0xff
+            };
+        }
+    }
+}
+
+fn is_fifo(offset: u64) -> bool {
+    (TPM_DATA_FIFO..TPM_DATA_FIFO + 4).contains(&offset)
+        || (TPM_XDATA_FIFO..TPM_XDATA_FIFO + 4).contains(&offset)
+}
+
+impl BusDevice for Tpm {
+    fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        if offset / TPM_LOCALITY_SIZE != 0 {
+            // Only locality 0 is implemented.
+            data.fill(0xff);
+            return;
+        }
+
+        if is_fifo(offset) {
+            self.read_fifo(data);
+            return;
+        }
+
+        let base = offset & !3;
+        let shift = (offset - base) as usize;
+        if shift + data.len() > 4 {
+            warn!(
+                "Invalid TPM read: offset {:#x}, data length {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+        let value = self.register(base).to_le_bytes();
+        data.copy_from_slice(&value[shift..shift + data.len()]);
+    }
+
+    fn write(&mut self, _vcpuid: u64, offset: u64, data: &[u8]) {
+        if offset / TPM_LOCALITY_SIZE != 0 || data.is_empty() {
+            return;
+        }
+
+        if is_fifo(offset) {
+            self.write_fifo(data);
+            return;
+        }
+
+        match offset {
+            TPM_ACCESS => {
+                if data[0] & TPM_ACCESS_REQUEST_USE != 0 {
+                    self.active = true;
+                } else if data[0] & TPM_ACCESS_ACTIVE_LOCALITY != 0 {
+                    self.active = false;
+                }
+            }
+            TPM_INT_ENABLE if data.len() == 4 => {
+                self.int_enable = u32::from_le_bytes(data.try_into().unwrap());
+            }
+            TPM_STS => self.write_status(data[0]),
+            _ => debug!("Ignoring TPM write: offset {:#x}", offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn read32(tpm: &mut Tpm, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        tpm.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_tpm_command() {
+        let (vmm_sock, mut tpm_sock) = UnixStream::pair().unwrap();
+        let command = [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x7b, 0, 0];
+        let response = [0x80, 0x01, 0, 0, 0, 11, 0, 0, 0, 0, 0x42];
+        let swtpm = thread::spawn(move || {
+            let mut received = [0u8; 12];
+            tpm_sock.read_exact(&mut received).unwrap();
+            tpm_sock.write_all(&response).unwrap();
+            received
+        });
+
+        let mut tpm = Tpm::new(vmm_sock);
+        assert_eq!(read32(&mut tpm, TPM_DID_VID), 0x0001_1014);
+
+        tpm.write(0, TPM_ACCESS, &[TPM_ACCESS_REQUEST_USE]);
+        let mut access = [0u8; 1];
+        tpm.read(0, TPM_ACCESS, &mut access);
+        assert_eq!(access[0], TPM_ACCESS_VALID | TPM_ACCESS_ACTIVE_LOCALITY);
+
+        tpm.write(0, TPM_STS, &[TPM_STS_COMMAND_READY as u8]);
+        assert_ne!(read32(&mut tpm, TPM_STS) & TPM_STS_COMMAND_READY, 0);
+
+        tpm.write(0, TPM_DATA_FIFO, &command[..8]);
+        assert_ne!(read32(&mut tpm, TPM_STS) & TPM_STS_EXPECT, 0);
+        tpm.write(0, TPM_DATA_FIFO, &command[8..]);
+        assert_eq!(read32(&mut tpm, TPM_STS) & TPM_STS_EXPECT, 0);
+
+        tpm.write(0, TPM_STS, &[TPM_STS_GO as u8]);
+        assert_eq!(swtpm.join().unwrap(), command);
+
+        let status = read32(&mut tpm, TPM_STS);
+        assert_ne!(status & TPM_STS_DATA_AVAIL, 0);
+        assert_eq!((status >> 8) & 0xffff, response.len() as u32);
+
+        let mut received = [0u8; 11];
+        for chunk in received.chunks_mut(4) {
+            tpm.read(0, TPM_DATA_FIFO, chunk);
+        }
+        assert_eq!(received, response);
+        assert_eq!(read32(&mut tpm, TPM_STS) & TPM_STS_DATA_AVAIL, 0);
+    }
+
+    #[test]
+    fn test_tpm_backend_failure() {
+        let (vmm_sock, tpm_sock) = UnixStream::pair().unwrap();
+        drop(tpm_sock);
+
+        let mut tpm = Tpm::new(vmm_sock);
+        tpm.write(0, TPM_STS, &[TPM_STS_COMMAND_READY as u8]);
+        tpm.write(
+            0,
+            TPM_DATA_FIFO,
+            &[0x80, 0x01, 0, 0, 0, 10, 0, 0, 0x01, 0x44],
+        );
+        tpm.write(0, TPM_STS, &[TPM_STS_GO as u8]);
+
+        let mut received = [0u8; TPM_HEADER_SIZE];
+        tpm.read(0, TPM_DATA_FIFO, &mut received);
+        assert_eq!(received, TPM_FAILURE_RESPONSE);
+    }
+}
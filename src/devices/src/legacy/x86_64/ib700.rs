@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! IB700 watchdog
+//!
+//! This module implements the watchdog of the iBASE IB700 single board computer, as QEMU does,
+//! which the guest drives through the ib700wdt driver. Writing a margin to the start register
+//! (re)arms the timer with a timeout of `30 - 2 * margin` seconds, and writing anything to the
+//! stop register disarms it. If the guest doesn't rearm the timer before it expires, the
+//! expiry eventfd is signaled and the watchdog stays disarmed until the next write to the start
+//! register.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use utils::eventfd::EventFd;
+
+use crate::BusDevice;
+
+/// First port of the device, where the stop register is.
+pub const IB700_BASE: u64 = 0x441;
+/// Number of ports of the device, up to and including the start register.
+pub const IB700_LEN: u64 = 3;
+
+const STOP_OFFSET: u64 = 0;
+const START_OFFSET: u64 = 2;
+
+#[derive(Default)]
+struct TimerState {
+    deadline: Option<Instant>,
+    stopped: bool,
+}
+
+#[derive(Default)]
+struct Timer {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+
+impl Timer {
+    fn set_deadline(&self, deadline: Option<Instant>) {
+        self.state.lock().unwrap().deadline = deadline;
+        self.changed.notify_one();
+    }
+
+    fn run(&self, expired_evt: EventFd) {
+        let mut state = self.state.lock().unwrap();
+        while !state.stopped {
+            state = match state.deadline {
+                None => self.changed.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now < deadline {
+                        self.changed.wait_timeout(state, deadline - now).unwrap().0
+                    } else {
+                        error!("Guest watchdog expired");
+                        state.deadline = None;
+                        if let Err(e) = expired_evt.write(1) {
+                            error!("Failed to signal the watchdog expiry: {}", e);
+                        }
+                        state
+                    }
+                }
+            };
+        }
+    }
+}
+
+pub struct Ib700Watchdog {
+    timer: Arc<Timer>,
+}
+
+impl Ib700Watchdog {
+    /// Constructs a disarmed watchdog, signaling `expired_evt` when it expires.
+    pub fn new(expired_evt: EventFd) -> std::io::Result<Ib700Watchdog> {
+        let timer = Arc::new(Timer::default());
+        let thread_timer = timer.clone();
+        thread::Builder::new()
+            .name("watchdog".into())
+            .spawn(move || thread_timer.run(expired_evt))?;
+
+        Ok(Ib700Watchdog { timer })
+    }
+}
+
+impl Drop for Ib700Watchdog {
+    fn drop(&mut self) {
+        self.timer.state.lock().unwrap().stopped = true;
+        self.timer.changed.notify_one();
+    }
+}
+
+impl BusDevice for Ib700Watchdog {
+    fn write(&mut self, _vcpuid: u64, offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            return;
+        }
+
+        match offset {
+            STOP_OFFSET => {
+                debug!("Guest watchdog disarmed");
+                self.timer.set_deadline(None);
+            }
+            START_OFFSET => {
+                let timeout = Duration::from_secs(30 - 2 * u64::from(data[0] & 0xf));
+                self.timer.set_deadline(Some(Instant::now() + timeout));
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::eventfd::EFD_NONBLOCK;
+
+    fn wait_expired(expired_evt: &EventFd) -> bool {
+        for _ in 0..100 {
+            if expired_evt.read().is_ok() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_ib700_expire() {
+        let expired_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut watchdog = Ib700Watchdog::new(expired_evt.try_clone().unwrap()).unwrap();
+
+        // A margin of 15 gives a timeout of 0 seconds.
+        watchdog.write(0, START_OFFSET, &[15]);
+        assert!(wait_expired(&expired_evt));
+        // The watchdog is disarmed once expired.
+        thread::sleep(Duration::from_millis(50));
+        assert!(expired_evt.read().is_err());
+
+        // Rearming it makes it expire again.
+        watchdog.write(0, START_OFFSET, &[15]);
+        assert!(wait_expired(&expired_evt));
+    }
+
+    #[test]
+    fn test_ib700_stop() {
+        let expired_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut watchdog = Ib700Watchdog::new(expired_evt.try_clone().unwrap()).unwrap();
+
+        // 30 seconds, then disarmed.
+        watchdog.write(0, START_OFFSET, &[0]);
+        watchdog.write(0, STOP_OFFSET, &[0]);
+        assert_eq!(watchdog.timer.state.lock().unwrap().deadline, None);
+        assert!(expired_evt.read().is_err());
+
+        watchdog.write(0, START_OFFSET, &[0]);
+        let deadline = watchdog.timer.state.lock().unwrap().deadline.unwrap();
+        assert!(deadline > Instant::now() + Duration::from_secs(29));
+    }
+}
@@ -1 +1,2 @@
+pub mod ib700;
 pub mod serial;
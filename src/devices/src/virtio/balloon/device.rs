@@ -4,13 +4,14 @@ use std::io::Write;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use utils::eventfd::EventFd;
-use vm_memory::{ByteValued, GuestMemory, GuestMemoryMmap};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
 
 use super::super::{
     ActivateError, ActivateResult, BalloonError, DeviceState, Queue as VirtQueue, VirtioDevice,
-    VIRTIO_MMIO_INT_VRING,
+    VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
 };
 use super::{defs, defs::uapi};
 use crate::legacy::GicV3;
@@ -30,6 +31,7 @@ pub(crate) const FRQ_INDEX: usize = 4;
 // Supported features.
 pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64
     | 1 << uapi::VIRTIO_BALLOON_F_STATS_VQ as u64
+    | 1 << uapi::VIRTIO_BALLOON_F_DEFLATE_ON_OOM as u64
     | 1 << uapi::VIRTIO_BALLOON_F_FREE_PAGE_HINT as u64
     | 1 << uapi::VIRTIO_BALLOON_F_REPORTING as u64;
 
@@ -49,6 +51,51 @@ pub struct VirtioBalloonConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioBalloonConfig {}
 
+/// Number of statistics defined by the virtio spec. They are indexed by their
+/// tag: swap in, swap out, major faults, minor faults, free memory, total
+/// memory, available memory, disk caches, hugetlb allocations and failures.
+pub const BALLOON_STATS_NUM: usize = 10;
+
+#[derive(Copy, Clone, Default)]
+#[repr(C, packed)]
+struct VirtioBalloonStat {
+    tag: u16,
+    val: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioBalloonStat {}
+
+/// A sample of the guest memory statistics.
+#[derive(Clone, Debug)]
+pub struct BalloonStats {
+    /// Values indexed by their virtio tag, `None` if the guest didn't report it.
+    pub values: [Option<u64>; BALLOON_STATS_NUM],
+    /// When the sample was received.
+    pub timestamp: SystemTime,
+}
+
+// Offset of the only field the guest may write, `actual`.
+const CONFIG_ACTUAL_OFFSET: u64 = 4;
+
+const BALLOON_PAGE_SIZE: usize = 1 << defs::PFN_SHIFT;
+
+/// Returns the memory range to the host. The guest gets zeroed pages if it
+/// touches the range again.
+fn release_memory(host_addr: *mut u8, len: usize) {
+    // Safe because the range belongs to the guest memory mapping, which the
+    // guest has just given up.
+    let ret = unsafe { libc::madvise(host_addr as *mut libc::c_void, len, libc::MADV_DONTNEED) };
+    if ret < 0 {
+        debug!(
+            "balloon: failed to release host_addr={:p} len={}: {}",
+            host_addr,
+            len,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
 pub struct Balloon {
     pub(crate) queues: Vec<VirtQueue>,
     pub(crate) queue_events: Vec<EventFd>,
@@ -59,6 +106,9 @@ pub struct Balloon {
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
     config: VirtioBalloonConfig,
+    // Descriptor chain of the stats buffer, held until a new sample is requested.
+    stats_desc_index: Option<u16>,
+    stats: Option<BalloonStats>,
     intc: Option<GicV3>,
     irq_line: Option<u32>,
 }
@@ -85,6 +135,8 @@ impl Balloon {
                 .map_err(BalloonError::EventFd)?,
             device_state: DeviceState::Inactive,
             config,
+            stats_desc_index: None,
+            stats: None,
             intc: None,
             irq_line: None,
         })
@@ -106,6 +158,37 @@ impl Balloon {
         self.intc = Some(intc);
     }
 
+    /// Returns the number of 4 KiB pages the host wants the guest to give up.
+    pub fn target_pages(&self) -> u32 {
+        self.config.num_pages
+    }
+
+    /// Returns the number of 4 KiB pages the guest has put in the balloon.
+    pub fn actual_pages(&self) -> u32 {
+        self.config.actual
+    }
+
+    /// Asks the guest to grow or shrink the balloon to `pages` 4 KiB pages.
+    pub fn set_target_pages(&mut self, pages: u32) -> result::Result<(), DeviceError> {
+        self.config.num_pages = pages;
+        if !self.is_activated() {
+            // The driver reads the target when it initializes.
+            return Ok(());
+        }
+
+        debug!("balloon: raising config change IRQ");
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
+        if let Some(intc) = &self.intc {
+            intc.set_irq(self.irq_line.unwrap());
+            Ok(())
+        } else {
+            self.interrupt_evt
+                .write(1)
+                .map_err(DeviceError::FailedSignalingUsedQueue)
+        }
+    }
+
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         debug!("balloon: raising IRQ");
         self.interrupt_status
@@ -121,6 +204,163 @@ impl Balloon {
         }
     }
 
+    /// Returns the latest memory statistics sample sent by the guest.
+    pub fn stats(&self) -> Option<&BalloonStats> {
+        self.stats.as_ref()
+    }
+
+    /// Asks the guest for a new statistics sample by giving it back the stats
+    /// buffer. Does nothing if the guest hasn't sent the previous one yet.
+    pub fn request_stats(&mut self) -> result::Result<(), DeviceError> {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            DeviceState::Inactive => return Ok(()),
+        };
+
+        if let Some(index) = self.stats_desc_index.take() {
+            if let Err(e) = self.queues[STQ_INDEX].add_used(mem, index, 0) {
+                error!("failed to add used elements to the queue: {:?}", e);
+            }
+            self.signal_used_queue()?;
+        }
+        Ok(())
+    }
+
+    /// Records the statistics the guest sent. The buffer is kept until the
+    /// next sample is requested.
+    pub fn process_stq(&mut self) -> bool {
+        debug!("balloon: process_stq()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[STQ_INDEX].pop(mem) {
+            let index = head.index;
+            let mut values = [None; BALLOON_STATS_NUM];
+            for desc in head.into_iter() {
+                let stat_len = std::mem::size_of::<VirtioBalloonStat>() as u64;
+                for i in 0..(desc.len as u64) / stat_len {
+                    let stat: VirtioBalloonStat = match desc
+                        .addr
+                        .checked_add(i * stat_len)
+                        .and_then(|addr| mem.read_obj(addr).ok())
+                    {
+                        Some(stat) => stat,
+                        None => {
+                            error!("balloon: invalid stats descriptor");
+                            break;
+                        }
+                    };
+                    if let Some(value) = values.get_mut(stat.tag as usize) {
+                        *value = Some(stat.val);
+                    }
+                }
+            }
+            self.stats = Some(BalloonStats {
+                values,
+                timestamp: SystemTime::now(),
+            });
+
+            // The driver only ever has one stats buffer in flight, give back
+            // any stale one.
+            if let Some(stale) = self.stats_desc_index.replace(index) {
+                have_used = true;
+                if let Err(e) = self.queues[STQ_INDEX].add_used(mem, stale, 0) {
+                    error!("failed to add used elements to the queue: {:?}", e);
+                }
+            }
+        }
+
+        have_used
+    }
+
+    /// Releases the pages the guest put in the balloon. Each descriptor holds
+    /// an array of page frame numbers, contiguous ones are released at once.
+    pub fn process_ifq(&mut self) -> bool {
+        debug!("balloon: process_ifq()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[IFQ_INDEX].pop(mem) {
+            let index = head.index;
+            for desc in head.into_iter() {
+                let mut range: Option<(*mut u8, usize)> = None;
+                for i in 0..(desc.len as u64) / 4 {
+                    let pfn: u32 = match desc
+                        .addr
+                        .checked_add(i * 4)
+                        .and_then(|addr| mem.read_obj(addr).ok())
+                    {
+                        Some(pfn) => pfn,
+                        None => {
+                            error!("balloon: invalid inflate descriptor");
+                            break;
+                        }
+                    };
+                    let guest_addr = GuestAddress((pfn as u64) << defs::PFN_SHIFT);
+                    let host_addr = match mem.get_host_address(guest_addr) {
+                        Ok(addr) => addr,
+                        Err(_) => {
+                            warn!("balloon: guest gave an invalid page: {:#x}", pfn);
+                            continue;
+                        }
+                    };
+                    range = match range {
+                        Some((start, len)) if start.wrapping_add(len) == host_addr => {
+                            Some((start, len + BALLOON_PAGE_SIZE))
+                        }
+                        Some((start, len)) => {
+                            release_memory(start, len);
+                            Some((host_addr, BALLOON_PAGE_SIZE))
+                        }
+                        None => Some((host_addr, BALLOON_PAGE_SIZE)),
+                    };
+                }
+                if let Some((start, len)) = range {
+                    release_memory(start, len);
+                }
+            }
+
+            have_used = true;
+            if let Err(e) = self.queues[IFQ_INDEX].add_used(mem, index, 0) {
+                error!("failed to add used elements to the queue: {:?}", e);
+            }
+        }
+
+        have_used
+    }
+
+    /// Acknowledges the pages the guest took back from the balloon. Nothing
+    /// needs to be done, they are faulted in again as the guest touches them.
+    pub fn process_dfq(&mut self) -> bool {
+        debug!("balloon: process_dfq()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[DFQ_INDEX].pop(mem) {
+            have_used = true;
+            if let Err(e) = self.queues[DFQ_INDEX].add_used(mem, head.index, 0) {
+                error!("failed to add used elements to the queue: {:?}", e);
+            }
+        }
+
+        have_used
+    }
+
     pub fn process_frq(&mut self) -> bool {
         debug!("balloon: process_frq()");
         let mem = match self.device_state {
@@ -139,13 +379,7 @@ impl Balloon {
                     "balloon: should release guest_addr={:?} host_addr={:p} len={}",
                     desc.addr, host_addr, desc.len
                 );
-                unsafe {
-                    libc::madvise(
-                        host_addr as *mut libc::c_void,
-                        desc.len.try_into().unwrap(),
-                        libc::MADV_DONTNEED,
-                    )
-                };
+                release_memory(host_addr, desc.len.try_into().unwrap());
             }
 
             have_used = true;
@@ -215,6 +449,12 @@ impl VirtioDevice for Balloon {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if offset == CONFIG_ACTUAL_OFFSET && data.len() == 4 {
+            self.config.actual = u32::from_le_bytes(data.try_into().unwrap());
+            debug!("balloon: guest reports {} pages", { self.config.actual });
+            return;
+        }
+
         warn!(
             "balloon: guest driver attempted to write device config (offset={:x}, len={:x})",
             offset,
@@ -248,4 +488,11 @@ impl VirtioDevice for Balloon {
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn reset(&mut self) -> bool {
+        // The queues are only processed while activated, and the transport
+        // hands new ones over on the next activation.
+        self.device_state = DeviceState::Inactive;
+        true
+    }
 }
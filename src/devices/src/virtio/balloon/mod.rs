@@ -2,17 +2,20 @@ mod device;
 mod event_handler;
 
 pub use self::defs::uapi::VIRTIO_ID_BALLOON as TYPE_BALLOON;
-pub use self::device::Balloon;
+pub use self::device::{Balloon, BalloonStats, BALLOON_STATS_NUM};
 
 mod defs {
     pub const BALLOON_DEV_ID: &str = "virtio_balloon";
     pub const NUM_QUEUES: usize = 5;
     pub const QUEUE_SIZES: &[u16] = &[256; NUM_QUEUES];
+    /// Balloon page frame numbers are always expressed in 4 KiB units.
+    pub const PFN_SHIFT: u64 = 12;
 
     pub mod uapi {
         pub const VIRTIO_F_VERSION_1: u32 = 32;
         pub const VIRTIO_ID_BALLOON: u32 = 5;
         pub const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
+        pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
         pub const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u32 = 3;
         pub const VIRTIO_BALLOON_F_REPORTING: u32 = 5;
     }
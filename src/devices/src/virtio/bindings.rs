@@ -6,6 +6,8 @@ pub const LINUX_ENODATA: libc::c_int = 61;
 pub const LINUX_ENOSYS: libc::c_int = 38;
 pub const LINUX_ENOTEMPTY: libc::c_int = 39;
 
+pub const LINUX_O_ACCMODE: libc::c_int = 3;
+pub const LINUX_O_RDONLY: libc::c_int = 0;
 pub const LINUX_O_APPEND: libc::c_int = 1024;
 pub const LINUX_O_CLOEXEC: libc::c_int = 0x80000;
 pub const LINUX_O_DIRECT: libc::c_int = 0x4000;
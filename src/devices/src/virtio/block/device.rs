@@ -10,6 +10,8 @@ use std::convert::From;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
 #[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+#[cfg(target_os = "linux")]
 use std::os::linux::fs::MetadataExt;
 #[cfg(target_os = "macos")]
 use std::os::macos::fs::MetadataExt;
@@ -32,11 +34,13 @@ use vm_memory::{ByteValued, GuestMemoryMmap};
 use super::worker::BlockWorker;
 use super::{
     super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK},
-    Error, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
+    Error, DISCARD_SECTOR_ALIGNMENT, MAX_DISCARD_SECTORS, MAX_DISCARD_SEG, QUEUE_SIZES,
+    SECTOR_SHIFT, SECTOR_SIZE,
 };
 
 use crate::legacy::GicV3;
-use crate::virtio::{block::ImageType, ActivateError};
+use crate::virtio::rate_limiter::RateLimiter;
+use crate::virtio::{block::ImageType, ActivateError, IoRateLimit};
 
 /// Configuration options for disk caching.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
@@ -55,6 +59,7 @@ pub enum CacheType {
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
     pub(crate) file: Arc<SyncFormatAccess<ImagoFile>>,
+    discard_file: Option<Arc<File>>,
     nsectors: u64,
     image_id: Vec<u8>,
 }
@@ -62,6 +67,7 @@ pub(crate) struct DiskProperties {
 impl DiskProperties {
     pub fn new(
         disk_image: Arc<SyncFormatAccess<ImagoFile>>,
+        discard_file: Option<Arc<File>>,
         disk_image_id: Vec<u8>,
         cache_type: CacheType,
     ) -> io::Result<Self> {
@@ -82,6 +88,7 @@ impl DiskProperties {
             nsectors: disk_size >> SECTOR_SHIFT,
             image_id: disk_image_id,
             file: disk_image,
+            discard_file,
         })
     }
 
@@ -97,6 +104,42 @@ impl DiskProperties {
         &self.image_id
     }
 
+    pub fn supports_discard(&self) -> bool {
+        self.discard_file.is_some()
+    }
+
+    /// Releases the host storage backing `len` bytes at `offset` by punching a
+    /// hole in the disk image. The range reads back as zeroes afterwards.
+    pub fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        let file = self
+            .discard_file
+            .as_ref()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EOPNOTSUPP))?;
+
+        #[cfg(target_os = "linux")]
+        {
+            // Safe because the file descriptor is valid and the kernel only
+            // reads the arguments.
+            let ret = unsafe {
+                libc::fallocate64(
+                    file.as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    offset as libc::off64_t,
+                    len as libc::off64_t,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (file, offset, len);
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
+    }
+
     fn build_device_id(disk_file: &File) -> result::Result<String, Error> {
         let blk_metadata = disk_file.metadata().map_err(Error::GetFileMetadata)?;
         // This is how kvmtool does it.
@@ -157,6 +200,18 @@ struct VirtioBlkConfig {
     capacity: u64,
     size_max: u32,
     seg_max: u32,
+    geometry: [u8; 4],
+    blk_size: u32,
+    physical_block_exp: u8,
+    alignment_offset: u8,
+    min_io_size: u16,
+    opt_io_size: u32,
+    writeback: u8,
+    unused0: u8,
+    num_queues: u16,
+    max_discard_sectors: u32,
+    max_discard_seg: u32,
+    discard_sector_alignment: u32,
 }
 
 // Safe because it only has data and has no implicit padding.
@@ -168,9 +223,11 @@ pub struct Block {
     disk: Option<DiskProperties>,
     cache_type: CacheType,
     disk_image: Arc<SyncFormatAccess<ImagoFile>>,
+    discard_file: Option<Arc<File>>,
     disk_image_id: Vec<u8>,
     worker_thread: Option<JoinHandle<()>>,
     worker_stopfd: EventFd,
+    rate_limit: Option<IoRateLimit>,
 
     // Virtio fields.
     pub(crate) avail_features: u64,
@@ -205,12 +262,23 @@ impl Block {
         disk_image_format: ImageType,
         is_disk_read_only: bool,
     ) -> io::Result<Block> {
-        let disk_image = OpenOptions::new()
+        let disk_file = OpenOptions::new()
             .read(true)
             .write(!is_disk_read_only)
             .open(PathBuf::from(&disk_image_path))?;
 
-        let disk_image_id = DiskProperties::build_disk_image_id(&disk_image);
+        let disk_image_id = DiskProperties::build_disk_image_id(&disk_file);
+
+        // Discarded ranges are punched out of the host file directly, which is
+        // only correct when guest sectors map 1:1 to the file, i.e. raw images.
+        let discard_file = if cfg!(target_os = "linux")
+            && disk_image_format == ImageType::Raw
+            && !is_disk_read_only
+        {
+            Some(Arc::new(disk_file))
+        } else {
+            None
+        };
 
         let disk_image = match disk_image_format {
             ImageType::Qcow2 => {
@@ -226,8 +294,12 @@ impl Block {
         };
         let disk_image = Arc::new(disk_image);
 
-        let disk_properties =
-            DiskProperties::new(Arc::clone(&disk_image), disk_image_id.clone(), cache_type)?;
+        let disk_properties = DiskProperties::new(
+            Arc::clone(&disk_image),
+            discard_file.clone(),
+            disk_image_id.clone(),
+            cache_type,
+        )?;
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
             | (1u64 << VIRTIO_BLK_F_FLUSH)
@@ -238,6 +310,10 @@ impl Block {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
         };
 
+        if disk_properties.supports_discard() {
+            avail_features |= 1u64 << VIRTIO_BLK_F_DISCARD;
+        }
+
         let queue_evts = [EventFd::new(EFD_NONBLOCK)?];
 
         let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
@@ -247,6 +323,10 @@ impl Block {
             size_max: 0,
             // QUEUE_SIZE - 2
             seg_max: 254,
+            max_discard_sectors: MAX_DISCARD_SECTORS,
+            max_discard_seg: MAX_DISCARD_SEG,
+            discard_sector_alignment: DISCARD_SECTOR_ALIGNMENT,
+            ..Default::default()
         };
 
         Ok(Block {
@@ -256,6 +336,7 @@ impl Block {
             disk: Some(disk_properties),
             cache_type,
             disk_image,
+            discard_file,
             disk_image_id,
             avail_features,
             acked_features: 0u64,
@@ -268,6 +349,7 @@ impl Block {
             irq_line: None,
             worker_thread: None,
             worker_stopfd: EventFd::new(EFD_NONBLOCK)?,
+            rate_limit: None,
         })
     }
 
@@ -275,6 +357,11 @@ impl Block {
         self.intc = Some(intc);
     }
 
+    /// Limits the rate of the I/O requests the guest makes to this device.
+    pub fn set_rate_limit(&mut self, rate_limit: IoRateLimit) {
+        self.rate_limit = Some(rate_limit);
+    }
+
     /// Provides the ID of this block device.
     pub fn id(&self) -> &String {
         &self.id
@@ -371,6 +458,7 @@ impl VirtioDevice for Block {
             Some(d) => d,
             None => DiskProperties::new(
                 Arc::clone(&self.disk_image),
+                self.discard_file.clone(),
                 self.disk_image_id.clone(),
                 self.cache_type,
             )
@@ -386,6 +474,7 @@ impl VirtioDevice for Block {
             self.irq_line,
             mem.clone(),
             disk,
+            self.rate_limit.map(RateLimiter::new),
             self.worker_stopfd.try_clone().unwrap(),
         );
         self.worker_thread = Some(worker.run());
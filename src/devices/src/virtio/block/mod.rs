@@ -14,6 +14,12 @@ pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
 pub const QUEUE_SIZE: u16 = 256;
 pub const NUM_QUEUES: usize = 1;
 pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+/// Largest range, in sectors, a single discard segment may cover.
+pub const MAX_DISCARD_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
+/// Maximum number of segments in a discard request.
+pub const MAX_DISCARD_SEG: u32 = 32;
+/// Discard granularity advertised to the guest, in sectors (4 KiB).
+pub const DISCARD_SECTOR_ALIGNMENT: u32 = 8;
 
 #[derive(Debug)]
 pub enum Error {
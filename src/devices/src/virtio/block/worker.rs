@@ -2,10 +2,13 @@ use crate::legacy::GicV3;
 use crate::virtio::descriptor_utils::{Reader, Writer};
 use crate::Error as DeviceError;
 
+use super::super::rate_limiter::RateLimiter;
 use super::super::{Queue, VIRTIO_MMIO_INT_VRING};
 use super::device::{CacheType, DiskProperties};
+use super::{MAX_DISCARD_SECTORS, MAX_DISCARD_SEG};
 
 use std::io::{self, Write};
+use std::mem::size_of;
 use std::os::fd::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -19,7 +22,9 @@ use vm_memory::{ByteValued, GuestMemoryMmap};
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum RequestError {
+    Discarding(io::Error),
     FlushingToDisk(io::Error),
+    InvalidDiscardSegment,
     InvalidDataLength,
     ReadingFromDescriptor(io::Error),
     WritingToDescriptor(io::Error),
@@ -46,6 +51,18 @@ pub struct RequestHeader {
 // Safe because RequestHeader only contains plain data.
 unsafe impl ByteValued for RequestHeader {}
 
+/// A range of sectors to discard, as carried in the data of a discard request.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardSegment only contains plain data.
+unsafe impl ByteValued for DiscardSegment {}
+
 pub struct BlockWorker {
     queue: Queue,
     queue_evt: EventFd,
@@ -56,6 +73,7 @@ pub struct BlockWorker {
 
     mem: GuestMemoryMmap,
     disk: DiskProperties,
+    rate_limiter: Option<RateLimiter>,
     stop_fd: EventFd,
 }
 
@@ -70,6 +88,7 @@ impl BlockWorker {
         irq_line: Option<u32>,
         mem: GuestMemoryMmap,
         disk: DiskProperties,
+        rate_limiter: Option<RateLimiter>,
         stop_fd: EventFd,
     ) -> Self {
         Self {
@@ -82,6 +101,7 @@ impl BlockWorker {
 
             mem,
             disk,
+            rate_limiter,
             stop_fd,
         }
     }
@@ -158,16 +178,29 @@ impl BlockWorker {
         loop {
             self.queue.disable_notification(&mem).unwrap();
 
-            self.process_queue(&mem);
+            let throttled = self.process_queue(&mem);
 
-            if !self.queue.enable_notification(&mem).unwrap() {
+            // A throttled queue is kicked again once the rate limiter allows it.
+            if !self.queue.enable_notification(&mem).unwrap() || throttled {
                 break;
             }
         }
     }
 
-    fn process_queue(&mut self, mem: &GuestMemoryMmap) {
+    /// Processes the available requests, returning whether the rate limiter
+    /// stopped it before the queue was empty.
+    fn process_queue(&mut self, mem: &GuestMemoryMmap) -> bool {
         while let Some(head) = self.queue.pop(mem) {
+            if let Some(ref mut limiter) = self.rate_limiter {
+                let len = head.clone().into_iter().map(|desc| desc.len as u64).sum();
+                if !limiter.consume(len) {
+                    // Leave the request in the queue until the limiter allows it.
+                    self.queue.undo_pop();
+                    limiter.schedule_retry(len, &self.queue_evt);
+                    return true;
+                }
+            }
+
             let mut reader = match Reader::new(mem, head.clone()) {
                 Ok(r) => r,
                 Err(e) => {
@@ -213,6 +246,8 @@ impl BlockWorker {
                 }
             }
         }
+
+        false
     }
 
     fn process_request(
@@ -263,10 +298,45 @@ impl BlockWorker {
                     Ok(disk_id.len())
                 }
             }
+            VIRTIO_BLK_T_DISCARD if self.disk.supports_discard() => {
+                let data_len = reader.available_bytes();
+                let segments = data_len / size_of::<DiscardSegment>();
+                if segments == 0
+                    || segments > MAX_DISCARD_SEG as usize
+                    || segments * size_of::<DiscardSegment>() != data_len
+                {
+                    return Err(RequestError::InvalidDataLength);
+                }
+
+                for _ in 0..segments {
+                    let segment: DiscardSegment = reader
+                        .read_obj()
+                        .map_err(RequestError::ReadingFromDescriptor)?;
+                    self.discard(segment)?;
+                }
+                Ok(0)
+            }
             _ => Err(RequestError::UnknownRequest),
         }
     }
 
+    fn discard(&self, segment: DiscardSegment) -> result::Result<(), RequestError> {
+        // No flags are defined for discard requests.
+        if segment.flags != 0
+            || segment.num_sectors > MAX_DISCARD_SECTORS
+            || !matches!(
+                segment.sector.checked_add(segment.num_sectors as u64),
+                Some(end) if end <= self.disk.nsectors()
+            )
+        {
+            return Err(RequestError::InvalidDiscardSegment);
+        }
+
+        self.disk
+            .discard(segment.sector * 512, segment.num_sectors as u64 * 512)
+            .map_err(RequestError::Discarding)
+    }
+
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
@@ -38,10 +38,20 @@ pub fn stderr() -> Result<Box<dyn PortOutput + Send>, nix::Error> {
     output_to_raw_fd_dup(STDERR_FILENO)
 }
 
+pub fn input_from_raw_fd_dup(fd: RawFd) -> Result<Box<dyn PortInput + Send>, nix::Error> {
+    let fd = dup_raw_fd_into_owned(fd)?;
+    make_non_blocking(&fd)?;
+    Ok(Box::new(PortInputFd(fd)))
+}
+
 pub fn input_empty() -> Result<Box<dyn PortInput + Send>, nix::Error> {
     Ok(Box::new(PortInputEmpty {}))
 }
 
+pub fn input_from_bytes(data: Vec<u8>) -> Box<dyn PortInput + Send> {
+    Box::new(PortInputBytes { data, pos: 0 })
+}
+
 pub fn output_file(file: File) -> Result<Box<dyn PortOutput + Send>, nix::Error> {
     output_to_raw_fd_dup(file.as_raw_fd())
 }
@@ -255,3 +265,20 @@ impl PortInput for PortInputEmpty {
         std::thread::sleep(std::time::Duration::MAX);
     }
 }
+
+// Hands a fixed buffer to the guest, followed by EOF.
+struct PortInputBytes {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl PortInput for PortInputBytes {
+    fn read_volatile(&mut self, buf: &mut VolatileSlice) -> Result<usize, io::Error> {
+        let len = buf.len().min(self.data.len() - self.pos);
+        buf.copy_from(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+
+    fn wait_until_readable(&self, _stopfd: Option<&EventFd>) {}
+}
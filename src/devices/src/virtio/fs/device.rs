@@ -17,8 +17,8 @@ use super::super::{
 };
 use super::passthrough;
 use super::worker::FsWorker;
-use super::ExportTable;
 use super::{defs, defs::uapi};
+use super::{ExportTable, IdMap};
 use crate::legacy::GicV3;
 
 #[derive(Copy, Clone)]
@@ -52,6 +52,9 @@ pub struct Fs {
     config: VirtioFsConfig,
     shm_region: Option<VirtioShmRegion>,
     passthrough_cfg: passthrough::Config,
+    read_only: bool,
+    uid_map: IdMap,
+    gid_map: IdMap,
     worker_thread: Option<JoinHandle<()>>,
     worker_stopfd: EventFd,
     #[cfg(target_os = "macos")]
@@ -95,6 +98,9 @@ impl Fs {
             config,
             shm_region: None,
             passthrough_cfg: fs_cfg,
+            read_only: false,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
             worker_thread: None,
             worker_stopfd: EventFd::new(EFD_NONBLOCK).map_err(FsError::EventFd)?,
             #[cfg(target_os = "macos")]
@@ -122,6 +128,19 @@ impl Fs {
         self.shm_region = Some(shm_region);
     }
 
+    /// Makes the guest unable to modify the shared directory, regardless of the
+    /// permissions on the host.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Translates the owners of the files, and the credentials the guest
+    /// processes access them with, between the guest and the host IDs.
+    pub fn set_id_maps(&mut self, uid_map: IdMap, gid_map: IdMap) {
+        self.uid_map = uid_map;
+        self.gid_map = gid_map;
+    }
+
     pub fn set_export_table(&mut self, export_table: ExportTable) -> u64 {
         static FS_UNIQUE_ID: AtomicU64 = AtomicU64::new(0);
 
@@ -225,6 +244,9 @@ impl VirtioDevice for Fs {
             mem.clone(),
             self.shm_region.clone(),
             self.passthrough_cfg.clone(),
+            self.read_only,
+            self.uid_map.clone(),
+            self.gid_map.clone(),
             self.worker_stopfd.try_clone().unwrap(),
             #[cfg(target_os = "macos")]
             self.map_sender.clone(),
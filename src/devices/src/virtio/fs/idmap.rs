@@ -0,0 +1,158 @@
+use std::fmt;
+
+/// ID the guest sees for host IDs that aren't mapped, as the kernel's default overflowuid and
+/// overflowgid.
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// A range of `count` IDs, starting at `guest_id` in the guest and at `host_id` on the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdRange {
+    pub guest_id: u32,
+    pub host_id: u32,
+    pub count: u32,
+}
+
+impl IdRange {
+    fn guest_end(&self) -> u64 {
+        u64::from(self.guest_id) + u64::from(self.count)
+    }
+
+    fn host_end(&self) -> u64 {
+        u64::from(self.host_id) + u64::from(self.count)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IdMapError {
+    /// The range doesn't contain any ID.
+    EmptyRange,
+    /// The range goes past the largest ID.
+    RangeOverflow,
+    /// The range overlaps another one of the map, in the guest or on the host.
+    RangeOverlap,
+}
+
+impl fmt::Display for IdMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::IdMapError::*;
+        match self {
+            EmptyRange => write!(f, "The ID range is empty"),
+            RangeOverflow => write!(f, "The ID range goes past the largest ID"),
+            RangeOverlap => write!(f, "The ID range overlaps another one"),
+        }
+    }
+}
+
+/// Translation of the user or group IDs between the guest and the host, in the spirit of
+/// `/proc/<pid>/uid_map`. An empty map leaves the IDs untouched.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdMap {
+    ranges: Vec<IdRange>,
+}
+
+impl IdMap {
+    /// Adds a range to the map. Ranges can't overlap, so each ID is mapped to a single one.
+    pub fn add(&mut self, range: IdRange) -> Result<(), IdMapError> {
+        if range.count == 0 {
+            return Err(IdMapError::EmptyRange);
+        }
+        // The last ID, u32::MAX, is the invalid (uid_t)-1 and can't be mapped.
+        if range.guest_end() > u64::from(u32::MAX) || range.host_end() > u64::from(u32::MAX) {
+            return Err(IdMapError::RangeOverflow);
+        }
+        if self.ranges.iter().any(|r| {
+            (u64::from(r.guest_id) < range.guest_end() && u64::from(range.guest_id) < r.guest_end())
+                || (u64::from(r.host_id) < range.host_end()
+                    && u64::from(range.host_id) < r.host_end())
+        }) {
+            return Err(IdMapError::RangeOverlap);
+        }
+
+        self.ranges.push(range);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns the host ID of a guest one, if mapped.
+    pub fn to_host(&self, id: u32) -> Option<u32> {
+        if self.is_empty() {
+            return Some(id);
+        }
+        self.ranges
+            .iter()
+            .find(|r| id >= r.guest_id && u64::from(id) < r.guest_end())
+            .map(|r| r.host_id + (id - r.guest_id))
+    }
+
+    /// Returns the guest ID of a host one, if mapped.
+    pub fn to_guest(&self, id: u32) -> Option<u32> {
+        if self.is_empty() {
+            return Some(id);
+        }
+        self.ranges
+            .iter()
+            .find(|r| id >= r.host_id && u64::from(id) < r.host_end())
+            .map(|r| r.guest_id + (id - r.host_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(guest_id: u32, host_id: u32, count: u32) -> IdRange {
+        IdRange {
+            guest_id,
+            host_id,
+            count,
+        }
+    }
+
+    #[test]
+    fn test_empty_map() {
+        let map = IdMap::default();
+        assert_eq!(map.to_host(1000), Some(1000));
+        assert_eq!(map.to_guest(0), Some(0));
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut map = IdMap::default();
+        map.add(range(0, 100000, 65536)).unwrap();
+        map.add(range(65536, 1000, 1)).unwrap();
+
+        assert_eq!(map.to_host(0), Some(100000));
+        assert_eq!(map.to_host(1000), Some(101000));
+        assert_eq!(map.to_host(65536), Some(1000));
+        assert_eq!(map.to_host(65537), None);
+
+        assert_eq!(map.to_guest(100000), Some(0));
+        assert_eq!(map.to_guest(165535), Some(65535));
+        assert_eq!(map.to_guest(1000), Some(65536));
+        assert_eq!(map.to_guest(0), None);
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        let mut map = IdMap::default();
+        assert_eq!(map.add(range(0, 0, 0)), Err(IdMapError::EmptyRange));
+        assert_eq!(
+            map.add(range(u32::MAX - 1, 0, 2)),
+            Err(IdMapError::RangeOverflow)
+        );
+
+        map.add(range(0, 100000, 1000)).unwrap();
+        assert_eq!(
+            map.add(range(999, 200000, 10)),
+            Err(IdMapError::RangeOverlap)
+        );
+        assert_eq!(
+            map.add(range(1000, 100999, 10)),
+            Err(IdMapError::RangeOverlap)
+        );
+        map.add(range(1000, 101000, 10)).unwrap();
+    }
+}
@@ -2,6 +2,7 @@ mod device;
 #[allow(dead_code)]
 mod filesystem;
 pub mod fuse;
+mod idmap;
 #[allow(dead_code)]
 mod multikey;
 mod server;
@@ -26,6 +27,7 @@ use super::descriptor_utils;
 pub use self::defs::uapi::VIRTIO_ID_FS as TYPE_FS;
 pub use self::device::Fs;
 pub use self::filesystem::ExportTable;
+pub use self::idmap::{IdMap, IdMapError, IdRange};
 
 mod defs {
     pub const FS_DEV_ID: &str = "virtio_fs";
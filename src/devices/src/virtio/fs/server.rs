@@ -25,6 +25,7 @@ use super::filesystem::{
 };
 use super::fs_utils::einval;
 use super::fuse::*;
+use super::idmap::{IdMap, OVERFLOW_ID};
 use super::{FsError as Error, Result};
 use crate::virtio::VirtioShmRegion;
 
@@ -64,19 +65,82 @@ impl io::Write for ZCWriter<'_> {
     }
 }
 
+/// Whether requests with this opcode always modify the file system.
+fn modifies_fs(opcode: u32) -> bool {
+    [
+        Opcode::Setattr,
+        Opcode::Symlink,
+        Opcode::Mknod,
+        Opcode::Mkdir,
+        Opcode::Unlink,
+        Opcode::Rmdir,
+        Opcode::Rename,
+        Opcode::Link,
+        Opcode::Write,
+        Opcode::Setxattr,
+        Opcode::Removexattr,
+        Opcode::Create,
+        Opcode::Fallocate,
+        Opcode::Rename2,
+        Opcode::CopyFileRange,
+    ]
+    .iter()
+    .any(|op| *op as u32 == opcode)
+}
+
+fn erofs(unique: u64, w: Writer) -> Result<usize> {
+    reply_error(
+        linux_error(io::Error::from_raw_os_error(libc::EROFS)),
+        unique,
+        w,
+    )
+}
+
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
     options: AtomicU64,
+    /// Reject any request modifying the file system with EROFS, regardless of
+    /// the permissions on the host.
+    read_only: bool,
+    /// Translation of the owners of the files, and of the credentials of the
+    /// guest processes, between the guest and the host.
+    uid_map: IdMap,
+    gid_map: IdMap,
 }
 
 impl<F: FileSystem + Sync> Server<F> {
-    pub fn new(fs: F) -> Server<F> {
+    pub fn new(fs: F, read_only: bool, uid_map: IdMap, gid_map: IdMap) -> Server<F> {
         Server {
             fs,
             options: AtomicU64::new(FsOptions::empty().bits()),
+            read_only,
+            uid_map,
+            gid_map,
+        }
+    }
+
+    /// Returns the context of a request, with the credentials of the guest
+    /// process translated to host ones.
+    fn context(&self, in_header: InHeader) -> Context {
+        Context {
+            uid: self.uid_map.to_host(in_header.uid).unwrap_or(OVERFLOW_ID),
+            gid: self.gid_map.to_host(in_header.gid).unwrap_or(OVERFLOW_ID),
+            pid: in_header.pid as i32,
         }
     }
 
+    /// Translates the owner of a file to guest IDs.
+    fn attr_to_guest(&self, mut st: bindings::stat64) -> bindings::stat64 {
+        st.st_uid = self.uid_map.to_guest(st.st_uid).unwrap_or(OVERFLOW_ID);
+        st.st_gid = self.gid_map.to_guest(st.st_gid).unwrap_or(OVERFLOW_ID);
+        st
+    }
+
+    fn entry_to_guest(&self, mut entry: Entry) -> Entry {
+        entry.attr = self.attr_to_guest(entry.attr);
+        entry
+    }
+
     #[allow(clippy::cognitive_complexity)]
     pub fn handle_message(
         &self,
@@ -95,6 +159,10 @@ impl<F: FileSystem + Sync> Server<F> {
             );
         }
         debug!("opcode: {}", in_header.opcode);
+        if self.read_only && modifies_fs(in_header.opcode) {
+            return erofs(in_header.unique, w);
+        }
+
         match in_header.opcode {
             x if x == Opcode::Lookup as u32 => self.lookup(in_header, r, w),
             x if x == Opcode::Forget as u32 => self.forget(in_header, r), // No reply.
@@ -194,10 +262,10 @@ impl<F: FileSystem + Sync> Server<F> {
 
         match self
             .fs
-            .lookup(Context::from(in_header), in_header.nodeid.into(), name)
+            .lookup(self.context(in_header), in_header.nodeid.into(), name)
         {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                let out = EntryOut::from(self.entry_to_guest(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -209,7 +277,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let ForgetIn { nlookup } = r.read_obj().map_err(Error::DecodeMessage)?;
 
         self.fs
-            .forget(Context::from(in_header), in_header.nodeid.into(), nlookup);
+            .forget(self.context(in_header), in_header.nodeid.into(), nlookup);
 
         // There is no reply for forget messages.
         Ok(0)
@@ -226,14 +294,14 @@ impl<F: FileSystem + Sync> Server<F> {
 
         match self
             .fs
-            .getattr(Context::from(in_header), in_header.nodeid.into(), handle)
+            .getattr(self.context(in_header), in_header.nodeid.into(), handle)
         {
             Ok((st, timeout)) => {
                 let out = AttrOut {
                     attr_valid: timeout.as_secs(),
                     attr_valid_nsec: timeout.subsec_nanos(),
                     dummy: 0,
-                    attr: st.into(),
+                    attr: self.attr_to_guest(st).into(),
                 };
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -252,10 +320,22 @@ impl<F: FileSystem + Sync> Server<F> {
 
         let valid = SetattrValid::from_bits_truncate(setattr_in.valid);
 
-        let st: bindings::stat64 = setattr_in.into();
+        let mut st: bindings::stat64 = setattr_in.into();
+        if valid.contains(SetattrValid::UID) {
+            match self.uid_map.to_host(st.st_uid) {
+                Some(uid) => st.st_uid = uid,
+                None => return reply_error(einval(), in_header.unique, w),
+            }
+        }
+        if valid.contains(SetattrValid::GID) {
+            match self.gid_map.to_host(st.st_gid) {
+                Some(gid) => st.st_gid = gid,
+                None => return reply_error(einval(), in_header.unique, w),
+            }
+        }
 
         match self.fs.setattr(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             st,
             handle,
@@ -266,7 +346,7 @@ impl<F: FileSystem + Sync> Server<F> {
                     attr_valid: timeout.as_secs(),
                     attr_valid_nsec: timeout.subsec_nanos(),
                     dummy: 0,
-                    attr: st.into(),
+                    attr: self.attr_to_guest(st).into(),
                 };
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -277,7 +357,7 @@ impl<F: FileSystem + Sync> Server<F> {
     fn readlink(&self, in_header: InHeader, w: Writer) -> Result<usize> {
         match self
             .fs
-            .readlink(Context::from(in_header), in_header.nodeid.into())
+            .readlink(self.context(in_header), in_header.nodeid.into())
         {
             Ok(linkname) => {
                 // We need to disambiguate the option type here even though it is `None`.
@@ -307,14 +387,14 @@ impl<F: FileSystem + Sync> Server<F> {
         let extensions = get_extensions(options, name.len() + linkname.len(), buf.as_slice())?;
 
         match self.fs.symlink(
-            Context::from(in_header),
+            self.context(in_header),
             bytes_to_cstr(linkname)?,
             in_header.nodeid.into(),
             bytes_to_cstr(name)?,
             extensions,
         ) {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                let out = EntryOut::from(self.entry_to_guest(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -342,7 +422,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let extensions = get_extensions(options, name.len(), buf.as_slice())?;
 
         match self.fs.mknod(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(name)?,
             mode,
@@ -351,7 +431,7 @@ impl<F: FileSystem + Sync> Server<F> {
             extensions,
         ) {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                let out = EntryOut::from(self.entry_to_guest(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -377,7 +457,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let extensions = get_extensions(options, name.len(), buf.as_slice())?;
 
         match self.fs.mkdir(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(name)?,
             mode,
@@ -385,7 +465,7 @@ impl<F: FileSystem + Sync> Server<F> {
             extensions,
         ) {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                let out = EntryOut::from(self.entry_to_guest(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -402,7 +482,7 @@ impl<F: FileSystem + Sync> Server<F> {
         r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
 
         match self.fs.unlink(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(&name)?,
         ) {
@@ -420,7 +500,7 @@ impl<F: FileSystem + Sync> Server<F> {
         r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
 
         match self.fs.rmdir(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(&name)?,
         ) {
@@ -456,7 +536,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let (oldname, newname) = buf.split_at(split_pos);
 
         match self.fs.rename(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(oldname)?,
             newdir.into(),
@@ -495,13 +575,13 @@ impl<F: FileSystem + Sync> Server<F> {
         r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
 
         match self.fs.link(
-            Context::from(in_header),
+            self.context(in_header),
             oldnodeid.into(),
             in_header.nodeid.into(),
             bytes_to_cstr(&name)?,
         ) {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                let out = EntryOut::from(self.entry_to_guest(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -512,9 +592,16 @@ impl<F: FileSystem + Sync> Server<F> {
     fn open(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let OpenIn { flags, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        if self.read_only
+            && (flags as i32 & bindings::LINUX_O_ACCMODE != bindings::LINUX_O_RDONLY
+                || flags as i32 & bindings::LINUX_O_TRUNC != 0)
+        {
+            return erofs(in_header.unique, w);
+        }
+
         match self
             .fs
-            .open(Context::from(in_header), in_header.nodeid.into(), flags)
+            .open(self.context(in_header), in_header.nodeid.into(), flags)
         {
             Ok((handle, opts)) => {
                 let out = OpenOut {
@@ -558,7 +645,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let data_writer = ZCWriter(w.split_at(size_of::<OutHeader>()).unwrap());
 
         match self.fs.read(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             fh.into(),
             data_writer,
@@ -614,7 +701,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let data_reader = ZCReader(r);
 
         match self.fs.write(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             fh.into(),
             data_reader,
@@ -640,7 +727,7 @@ impl<F: FileSystem + Sync> Server<F> {
     fn statfs(&self, in_header: InHeader, w: Writer) -> Result<usize> {
         match self
             .fs
-            .statfs(Context::from(in_header), in_header.nodeid.into())
+            .statfs(self.context(in_header), in_header.nodeid.into())
         {
             Ok(st) => reply_ok(Some(Kstatfs::from(st)), None, in_header.unique, w),
             Err(e) => reply_error(e, in_header.unique, w),
@@ -664,7 +751,7 @@ impl<F: FileSystem + Sync> Server<F> {
         };
 
         match self.fs.release(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             flags,
             fh.into(),
@@ -684,7 +771,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let datasync = fsync_flags & 0x1 != 0;
 
         match self.fs.fsync(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             datasync,
             fh.into(),
@@ -720,7 +807,7 @@ impl<F: FileSystem + Sync> Server<F> {
         }
 
         match self.fs.setxattr(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(name)?,
             value,
@@ -751,7 +838,7 @@ impl<F: FileSystem + Sync> Server<F> {
         }
 
         match self.fs.getxattr(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(&name)?,
             size,
@@ -782,7 +869,7 @@ impl<F: FileSystem + Sync> Server<F> {
 
         match self
             .fs
-            .listxattr(Context::from(in_header), in_header.nodeid.into(), size)
+            .listxattr(self.context(in_header), in_header.nodeid.into(), size)
         {
             Ok(ListxattrReply::Names(val)) => reply_ok(None::<u8>, Some(&val), in_header.unique, w),
             Ok(ListxattrReply::Count(count)) => {
@@ -810,7 +897,7 @@ impl<F: FileSystem + Sync> Server<F> {
 
         match self
             .fs
-            .removexattr(Context::from(in_header), in_header.nodeid.into(), name)
+            .removexattr(self.context(in_header), in_header.nodeid.into(), name)
         {
             Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
             Err(e) => reply_error(e, in_header.unique, w),
@@ -821,7 +908,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let FlushIn { fh, lock_owner, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
         match self.fs.flush(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             fh.into(),
             lock_owner,
@@ -933,7 +1020,7 @@ impl<F: FileSystem + Sync> Server<F> {
 
         match self
             .fs
-            .opendir(Context::from(in_header), in_header.nodeid.into(), flags)
+            .opendir(self.context(in_header), in_header.nodeid.into(), flags)
         {
             Ok((handle, opts)) => {
                 let out = OpenOut {
@@ -981,16 +1068,16 @@ impl<F: FileSystem + Sync> Server<F> {
 
         let res = if plus {
             self.fs.readdirplus(
-                Context::from(in_header),
+                self.context(in_header),
                 in_header.nodeid.into(),
                 fh.into(),
                 size,
                 offset,
-                |d, e| add_dirent(&mut cursor, size, d, Some(e)),
+                |d, e| add_dirent(&mut cursor, size, d, Some(self.entry_to_guest(e))),
             )
         } else {
             self.fs.readdir(
-                Context::from(in_header),
+                self.context(in_header),
                 in_header.nodeid.into(),
                 fh.into(),
                 size,
@@ -1027,7 +1114,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let ReleaseIn { fh, flags, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
         match self.fs.releasedir(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             flags,
             fh.into(),
@@ -1044,7 +1131,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let datasync = fsync_flags & 0x1 != 0;
 
         match self.fs.fsyncdir(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             datasync,
             fh.into(),
@@ -1083,7 +1170,7 @@ impl<F: FileSystem + Sync> Server<F> {
 
         match self
             .fs
-            .access(Context::from(in_header), in_header.nodeid.into(), mask)
+            .access(self.context(in_header), in_header.nodeid.into(), mask)
         {
             Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
             Err(e) => reply_error(e, in_header.unique, w),
@@ -1111,7 +1198,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let extensions = get_extensions(options, name.len(), buf.as_slice())?;
 
         match self.fs.create(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(name)?,
             mode,
@@ -1120,6 +1207,7 @@ impl<F: FileSystem + Sync> Server<F> {
             extensions,
         ) {
             Ok((entry, handle, opts)) => {
+                let entry = self.entry_to_guest(entry);
                 let entry_out = EntryOut {
                     nodeid: entry.inode,
                     generation: entry.generation,
@@ -1177,7 +1265,7 @@ impl<F: FileSystem + Sync> Server<F> {
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
         match self.fs.ioctl(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             fh.into(),
             flags,
@@ -1241,7 +1329,7 @@ impl<F: FileSystem + Sync> Server<F> {
             );
         }
 
-        self.fs.batch_forget(Context::from(in_header), requests);
+        self.fs.batch_forget(self.context(in_header), requests);
 
         // No reply for forget messages.
         Ok(0)
@@ -1257,7 +1345,7 @@ impl<F: FileSystem + Sync> Server<F> {
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
         match self.fs.fallocate(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             fh.into(),
             mode,
@@ -1275,7 +1363,7 @@ impl<F: FileSystem + Sync> Server<F> {
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
         match self.fs.lseek(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             fh.into(),
             offset,
@@ -1303,7 +1391,7 @@ impl<F: FileSystem + Sync> Server<F> {
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
         match self.fs.copyfilerange(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             fh_in.into(),
             off_in,
@@ -1342,8 +1430,12 @@ impl<F: FileSystem + Sync> Server<F> {
             moffset,
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        if self.read_only && flags & SetupmappingFlags::WRITE.bits() != 0 {
+            return erofs(in_header.unique, w);
+        }
+
         match self.fs.setupmapping(
-            Context::from(in_header),
+            self.context(in_header),
             in_header.nodeid.into(),
             fh.into(),
             foffset,
@@ -1396,7 +1488,7 @@ impl<F: FileSystem + Sync> Server<F> {
         }
 
         match self.fs.removemapping(
-            Context::from(in_header),
+            self.context(in_header),
             requests,
             host_shm_base,
             shm_size,
@@ -1635,3 +1727,34 @@ fn get_extensions(options: FsOptions, skip: usize, request_bytes: &[u8]) -> Resu
 
     Ok(extensions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modifies_fs() {
+        for opcode in [
+            Opcode::Setattr,
+            Opcode::Write,
+            Opcode::Create,
+            Opcode::Unlink,
+            Opcode::Rename,
+            Opcode::Rename2,
+            Opcode::Setxattr,
+        ] {
+            assert!(modifies_fs(opcode as u32), "{:?}", opcode);
+        }
+        for opcode in [
+            Opcode::Lookup,
+            Opcode::Getattr,
+            Opcode::Open,
+            Opcode::Read,
+            Opcode::Readdir,
+            Opcode::Release,
+            Opcode::Statfs,
+        ] {
+            assert!(!modifies_fs(opcode as u32), "{:?}", opcode);
+        }
+    }
+}
@@ -17,6 +17,7 @@ use super::defs::{HPQ_INDEX, REQ_INDEX};
 use super::descriptor_utils::{Reader, Writer};
 use super::passthrough::{self, PassthroughFs};
 use super::server::Server;
+use super::IdMap;
 use crate::legacy::GicV3;
 use crate::virtio::VirtioShmRegion;
 
@@ -48,6 +49,9 @@ impl FsWorker {
         mem: GuestMemoryMmap,
         shm_region: Option<VirtioShmRegion>,
         passthrough_cfg: passthrough::Config,
+        read_only: bool,
+        uid_map: IdMap,
+        gid_map: IdMap,
         stop_fd: EventFd,
         #[cfg(target_os = "macos")] map_sender: Option<Sender<MemoryMapping>>,
     ) -> Self {
@@ -61,7 +65,12 @@ impl FsWorker {
 
             mem,
             shm_region,
-            server: Server::new(PassthroughFs::new(passthrough_cfg).unwrap()),
+            server: Server::new(
+                PassthroughFs::new(passthrough_cfg).unwrap(),
+                read_only,
+                uid_map,
+                gid_map,
+            ),
             stop_fd,
             #[cfg(target_os = "macos")]
             map_sender,
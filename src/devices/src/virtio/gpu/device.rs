@@ -1,4 +1,11 @@
+#[cfg(target_os = "linux")]
+use std::fs::{self, OpenOptions};
+#[cfg(target_os = "linux")]
+use std::io;
 use std::io::Write;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
@@ -32,6 +39,43 @@ pub(crate) const AVAIL_FEATURES: u64 = 1u64 << uapi::VIRTIO_F_VERSION_1
     | 1u64 << uapi::VIRTIO_GPU_F_RESOURCE_BLOB
     | 1u64 << uapi::VIRTIO_GPU_F_CONTEXT_INIT;
 
+// Supported features when falling back to 2D.
+pub(crate) const AVAIL_FEATURES_2D: u64 = 1u64 << uapi::VIRTIO_F_VERSION_1;
+
+/// Checks there's a render node virglrenderer can render with: `render_node`
+/// if set, any of the host ones otherwise.
+#[cfg(target_os = "linux")]
+fn render_node_available(render_node: Option<&Path>) -> io::Result<()> {
+    let open = |path: &Path| {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map(|_| ())
+    };
+
+    if let Some(render_node) = render_node {
+        return open(render_node);
+    }
+
+    let mut result = Err(io::Error::from_raw_os_error(libc::ENOENT));
+    for entry in fs::read_dir("/dev/dri")? {
+        let path = entry?.path();
+        let is_render_node = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("renderD"));
+        if is_render_node {
+            result = open(&path);
+            if result.is_ok() {
+                break;
+            }
+        }
+    }
+
+    result
+}
+
 pub struct Gpu {
     pub(crate) queue_ctl: Arc<Mutex<VirtQueue>>,
     pub(crate) queue_cur: Arc<Mutex<VirtQueue>>,
@@ -48,6 +92,8 @@ pub struct Gpu {
     irq_line: Option<u32>,
     pub(crate) sender: Option<Sender<u64>>,
     virgl_flags: u32,
+    render_node: Option<PathBuf>,
+    virgl: bool,
     #[cfg(target_os = "macos")]
     map_sender: Sender<MemoryMapping>,
     export_table: Option<ExportTable>,
@@ -57,8 +103,20 @@ impl Gpu {
     pub(crate) fn with_queues(
         queues: Vec<VirtQueue>,
         virgl_flags: u32,
+        render_node: Option<PathBuf>,
         #[cfg(target_os = "macos")] map_sender: Sender<MemoryMapping>,
     ) -> super::Result<Gpu> {
+        #[cfg(target_os = "linux")]
+        let virgl = match render_node_available(render_node.as_deref()) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("virtio_gpu: no usable render node ({e}), falling back to 2D");
+                false
+            }
+        };
+        #[cfg(target_os = "macos")]
+        let virgl = true;
+
         let mut queue_events = Vec::new();
         for _ in 0..queues.len() {
             queue_events
@@ -73,7 +131,11 @@ impl Gpu {
             queue_cur,
             queues,
             queue_events,
-            avail_features: AVAIL_FEATURES,
+            avail_features: if virgl {
+                AVAIL_FEATURES
+            } else {
+                AVAIL_FEATURES_2D
+            },
             acked_features: 0,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(GpuError::EventFd)?,
@@ -84,6 +146,8 @@ impl Gpu {
             irq_line: None,
             sender: None,
             virgl_flags,
+            render_node,
+            virgl,
             #[cfg(target_os = "macos")]
             map_sender,
             export_table: None,
@@ -92,6 +156,7 @@ impl Gpu {
 
     pub fn new(
         virgl_flags: u32,
+        render_node: Option<PathBuf>,
         #[cfg(target_os = "macos")] map_sender: Sender<MemoryMapping>,
     ) -> super::Result<Gpu> {
         let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
@@ -101,6 +166,7 @@ impl Gpu {
         Self::with_queues(
             queues,
             virgl_flags,
+            render_node,
             #[cfg(target_os = "macos")]
             map_sender,
         )
@@ -241,7 +307,7 @@ impl VirtioDevice for Gpu {
             events_read: 0,
             events_clear: 0,
             num_scanouts: 0,
-            num_capsets: 5,
+            num_capsets: if self.virgl { 5 } else { 0 },
         };
 
         let config_slice = config.as_slice();
@@ -294,6 +360,8 @@ impl VirtioDevice for Gpu {
             self.irq_line,
             shm_region,
             self.virgl_flags,
+            self.virgl,
+            self.render_node.clone(),
             #[cfg(target_os = "macos")]
             self.map_sender.clone(),
             self.export_table.take(),
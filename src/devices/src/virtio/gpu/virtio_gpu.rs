@@ -181,6 +181,8 @@ impl VirtioGpu {
         intc: Option<GicV3>,
         irq_line: Option<u32>,
         virgl_flags: u32,
+        virgl: bool,
+        render_node: Option<PathBuf>,
         #[cfg(target_os = "macos")] map_sender: Sender<MemoryMapping>,
         export_table: Option<ExportTable>,
     ) -> Self {
@@ -212,12 +214,19 @@ impl VirtioGpu {
         }
         let rutabaga_channels_opt = Some(rutabaga_channels);
 
-        let builder = RutabagaBuilder::new(
-            rutabaga_gfx::RutabagaComponentType::VirglRenderer,
-            virgl_flags,
-            0,
-        )
-        .set_rutabaga_channels(rutabaga_channels_opt);
+        let component = if virgl {
+            rutabaga_gfx::RutabagaComponentType::VirglRenderer
+        } else {
+            rutabaga_gfx::RutabagaComponentType::Rutabaga2D
+        };
+        let builder = RutabagaBuilder::new(component, virgl_flags, 0)
+            .set_rutabaga_channels(rutabaga_channels_opt);
+
+        // Falling back to 2D leaves the render node unused.
+        let builder = match render_node {
+            Some(render_node) if virgl => builder.set_render_node(render_node),
+            _ => builder,
+        };
 
         let builder = if let Some(export_table) = export_table {
             builder.set_export_table(export_table)
@@ -1,4 +1,5 @@
 use std::io::Read;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{result, thread};
@@ -38,6 +39,8 @@ pub struct Worker {
     irq_line: Option<u32>,
     shm_region: VirtioShmRegion,
     virgl_flags: u32,
+    virgl: bool,
+    render_node: Option<PathBuf>,
     #[cfg(target_os = "macos")]
     map_sender: Sender<MemoryMapping>,
     export_table: Option<ExportTable>,
@@ -55,6 +58,8 @@ impl Worker {
         irq_line: Option<u32>,
         shm_region: VirtioShmRegion,
         virgl_flags: u32,
+        virgl: bool,
+        render_node: Option<PathBuf>,
         #[cfg(target_os = "macos")] map_sender: Sender<MemoryMapping>,
         export_table: Option<ExportTable>,
     ) -> Self {
@@ -68,6 +73,8 @@ impl Worker {
             irq_line,
             shm_region,
             virgl_flags,
+            virgl,
+            render_node,
             #[cfg(target_os = "macos")]
             map_sender,
             export_table,
@@ -90,6 +97,8 @@ impl Worker {
             self.intc.clone(),
             self.irq_line,
             self.virgl_flags,
+            self.virgl,
+            self.render_node.take(),
             #[cfg(target_os = "macos")]
             self.map_sender.clone(),
             self.export_table.take(),
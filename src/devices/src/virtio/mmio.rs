@@ -6,7 +6,7 @@
 // found in the THIRD-PARTY file.
 
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
 
 use utils::byte_order;
@@ -53,6 +53,7 @@ pub struct MmioTransport {
     pub(crate) interrupt_status: Arc<AtomicUsize>,
     queue_evts: HashMap<u32, EventFd>,
     shm_region_select: u32,
+    interrupt_acks: Arc<AtomicU64>,
 }
 
 impl MmioTransport {
@@ -74,6 +75,7 @@ impl MmioTransport {
             interrupt_status,
             queue_evts: HashMap::new(),
             shm_region_select: 0,
+            interrupt_acks: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -86,6 +88,11 @@ impl MmioTransport {
         self.device.clone()
     }
 
+    /// Counter of the interrupts the guest driver acknowledged.
+    pub fn interrupt_acks(&self) -> Arc<AtomicU64> {
+        self.interrupt_acks.clone()
+    }
+
     pub fn register_queue_evt(&mut self, queue_evt: EventFd, id: u32) {
         self.queue_evts.insert(id, queue_evt);
     }
@@ -150,6 +157,20 @@ impl MmioTransport {
         }
     }
 
+    /// Resets the device as its driver does by clearing the device status, so a
+    /// rebooted guest finds it as it was at boot. Returns false if the device
+    /// doesn't support being reset.
+    pub fn reset_device(&mut self) -> bool {
+        {
+            let mut device = self.locked_device();
+            if device.is_activated() && !device.reset() {
+                return false;
+            }
+        }
+        self.reset();
+        true
+    }
+
     /// Update device status according to the state machine defined by VirtIO Spec 1.0.
     /// Please refer to VirtIO Spec 1.0, section 2.1.1 and 3.1.1.
     ///
@@ -308,6 +329,7 @@ impl BusDevice for MmioTransport {
                         if self.check_device_status(device_status::DRIVER_OK, 0) {
                             self.interrupt_status
                                 .fetch_and(!(v as usize), Ordering::SeqCst);
+                            self.interrupt_acks.fetch_add(1, Ordering::Relaxed);
                         }
                     }
                     0x70 => self.set_device_status(v),
@@ -30,6 +30,8 @@ mod mmio;
 #[cfg(feature = "net")]
 pub mod net;
 mod queue;
+#[cfg(any(feature = "blk", feature = "net"))]
+pub mod rate_limiter;
 #[cfg(not(feature = "tee"))]
 pub mod rng;
 #[cfg(feature = "snd")]
@@ -50,6 +52,8 @@ pub use self::mmio::*;
 #[cfg(feature = "net")]
 pub use self::net::Net;
 pub use self::queue::{Descriptor, DescriptorChain, Queue};
+#[cfg(any(feature = "blk", feature = "net"))]
+pub use self::rate_limiter::IoRateLimit;
 #[cfg(not(feature = "tee"))]
 pub use self::rng::*;
 #[cfg(feature = "snd")]
@@ -7,6 +7,8 @@ pub enum ConnectError {
     CreateSocket(nix::Error),
     Binding(nix::Error),
     SendingMagic(nix::Error),
+    #[cfg(target_os = "linux")]
+    OpenTap(std::io::Error),
 }
 
 #[allow(dead_code)]
@@ -8,10 +8,12 @@ use crate::legacy::GicV3;
 use crate::virtio::net::{Error, Result};
 use crate::virtio::net::{QUEUE_SIZES, RX_INDEX, TX_INDEX};
 use crate::virtio::queue::Error as QueueError;
-use crate::virtio::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_NET};
+use crate::virtio::{ActivateResult, DeviceState, IoRateLimit, Queue, VirtioDevice, TYPE_NET};
 use crate::Error as DeviceError;
 
 use super::backend::{ReadError, WriteError};
+#[cfg(target_os = "linux")]
+use super::vhost::{Interrupt, VhostNet};
 use super::worker::NetWorker;
 
 use std::cmp;
@@ -23,13 +25,16 @@ use std::sync::Arc;
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
 use virtio_bindings::virtio_net::{
     VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
-    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MTU,
 };
 use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{ByteValued, GuestMemoryError, GuestMemoryMmap};
 
 const VIRTIO_F_VERSION_1: u32 = 32;
 
+/// Smallest MTU of an Ethernet device.
+pub const MIN_MTU: u16 = 68;
+
 #[derive(Debug)]
 pub enum FrontendError {
     DescriptorChainTooSmall,
@@ -58,6 +63,7 @@ struct VirtioNetConfig {
     mac: [u8; 6],
     status: u16,
     max_virtqueue_pairs: u16,
+    mtu: u16,
 }
 
 // Safe because it only has data and has no implicit padding.
@@ -67,6 +73,21 @@ unsafe impl ByteValued for VirtioNetConfig {}
 pub enum VirtioNetBackend {
     Passt(RawFd),
     Gvproxy(PathBuf),
+    #[cfg(target_os = "linux")]
+    Tap(String),
+}
+
+impl VirtioNetBackend {
+    /// Largest MTU the backend can carry.
+    pub fn max_mtu(&self) -> u16 {
+        match self {
+            // The largest MTU passt supports.
+            VirtioNetBackend::Passt(_) => 65520,
+            VirtioNetBackend::Gvproxy(_) => u16::MAX,
+            #[cfg(target_os = "linux")]
+            VirtioNetBackend::Tap(_) => u16::MAX,
+        }
+    }
 }
 
 pub struct Net {
@@ -88,6 +109,10 @@ pub struct Net {
     irq_line: Option<u32>,
 
     config: VirtioNetConfig,
+    rate_limit: Option<IoRateLimit>,
+    vhost: bool,
+    #[cfg(target_os = "linux")]
+    vhost_net: Option<VhostNet>,
 }
 
 impl Net {
@@ -114,6 +139,7 @@ impl Net {
             mac,
             status: 0,
             max_virtqueue_pairs: 0,
+            mtu: 0,
         };
 
         Ok(Net {
@@ -135,9 +161,83 @@ impl Net {
             irq_line: None,
 
             config,
+            rate_limit: None,
+            vhost: false,
+            #[cfg(target_os = "linux")]
+            vhost_net: None,
         })
     }
 
+    /// Limits the rate of the traffic in each direction of this device.
+    pub fn set_rate_limit(&mut self, rate_limit: IoRateLimit) {
+        self.rate_limit = Some(rate_limit);
+    }
+
+    /// Advertises an MTU to the guest, which must be within what the backend can carry.
+    pub fn set_mtu(&mut self, mtu: u16) -> Result<()> {
+        if !(MIN_MTU..=self.cfg_backend.max_mtu()).contains(&mtu) {
+            return Err(Error::InvalidMtu(mtu));
+        }
+        self.config.mtu = mtu;
+        self.avail_features |= 1 << VIRTIO_NET_F_MTU;
+        Ok(())
+    }
+
+    /// Moves the datapath of this device to the kernel's vhost-net, if available when the
+    /// device is activated. Only tap devices support it.
+    pub fn set_vhost(&mut self, enabled: bool) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        let is_tap = matches!(self.cfg_backend, VirtioNetBackend::Tap(_));
+        #[cfg(not(target_os = "linux"))]
+        let is_tap = false;
+        if enabled && !is_tap {
+            return Err(Error::VhostWithoutTap);
+        }
+        self.vhost = enabled;
+        Ok(())
+    }
+
+    /// Tries to start the datapath in vhost-net, returning whether it did.
+    #[cfg(target_os = "linux")]
+    fn start_vhost(&mut self, mem: &GuestMemoryMmap) -> bool {
+        let tap_name = match &self.cfg_backend {
+            VirtioNetBackend::Tap(name) if self.vhost => name,
+            _ => return false,
+        };
+        // vhost-net can't limit the rate of the traffic.
+        if self.rate_limit.is_some() {
+            warn!("net: rate limit set, not using vhost-net for {}", self.id);
+            return false;
+        }
+
+        let interrupt = Interrupt {
+            status: self.interrupt_status.clone(),
+            evt: self.interrupt_evt.try_clone().unwrap(),
+            intc: self.intc.clone(),
+            irq_line: self.irq_line,
+        };
+        match VhostNet::start(
+            tap_name,
+            self.acked_features,
+            mem,
+            &self.queues,
+            &self.queue_evts,
+            interrupt,
+        ) {
+            Ok(vhost_net) => {
+                self.vhost_net = Some(vhost_net);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "net: vhost-net unavailable for {}, using the userspace datapath: {}",
+                    self.id, e
+                );
+                false
+            }
+        }
+    }
+
     /// Provides the ID of this net device.
     pub fn id(&self) -> &str {
         &self.id
@@ -217,6 +317,12 @@ impl VirtioDevice for Net {
         self.queues[RX_INDEX].set_event_idx(event_idx);
         self.queues[TX_INDEX].set_event_idx(event_idx);
 
+        #[cfg(target_os = "linux")]
+        if self.start_vhost(&mem) {
+            self.device_state = DeviceState::Activated(mem);
+            return Ok(());
+        }
+
         let queue_evts = self
             .queue_evts
             .iter()
@@ -231,6 +337,7 @@ impl VirtioDevice for Net {
             self.irq_line,
             mem.clone(),
             self.cfg_backend.clone(),
+            self.rate_limit,
         );
         worker.run();
 
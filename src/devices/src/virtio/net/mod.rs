@@ -15,6 +15,10 @@ mod backend;
 pub mod device;
 mod gvproxy;
 mod passt;
+#[cfg(target_os = "linux")]
+mod tap;
+#[cfg(target_os = "linux")]
+pub mod vhost;
 mod worker;
 
 pub use self::device::Net;
@@ -22,6 +26,10 @@ pub use self::device::Net;
 pub enum Error {
     /// EventFd error.
     EventFd(io::Error),
+    /// The MTU is out of the range the backend supports.
+    InvalidMtu(u16),
+    /// vhost-net only works with tap devices.
+    VhostWithoutTap,
 }
 
 pub type Result<T> = result::Result<T, Error>;
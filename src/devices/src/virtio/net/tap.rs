@@ -0,0 +1,141 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::raw::{c_char, c_int, c_short, c_uint};
+use std::os::unix::fs::OpenOptionsExt;
+
+use virtio_bindings::virtio_net::virtio_net_hdr_v1;
+
+use super::backend::{ConnectError, NetBackend, ReadError, WriteError};
+
+/// Size of the virtio-net header prepended to the frames exchanged with the tap device.
+pub const TAP_VNET_HDR_LEN: usize = mem::size_of::<virtio_net_hdr_v1>();
+
+pub struct Tap {
+    file: File,
+}
+
+impl Tap {
+    /// Opens the tap interface `name`, creating it if it doesn't exist. Frames carry a
+    /// virtio-net header, and the kernel only hands us frames using the `offloads` (TUN_F_*).
+    pub fn new(name: &str, offloads: c_uint) -> Result<Self, ConnectError> {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(ConnectError::OpenTap(io::Error::from_raw_os_error(
+                libc::EINVAL,
+            )));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")
+            .map_err(ConnectError::OpenTap)?;
+
+        // SAFETY: ifreq is a plain C struct, for which all zeroes is a valid value.
+        let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+        for (dst, src) in ifreq.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as c_char;
+        }
+        ifreq.ifr_ifru.ifru_flags =
+            (libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR) as c_short;
+
+        let hdr_len = TAP_VNET_HDR_LEN as c_int;
+        // SAFETY: the fd is a tun device, and each argument is of the type its ioctl expects.
+        let ret = unsafe {
+            if libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &ifreq) < 0
+                || libc::ioctl(file.as_raw_fd(), libc::TUNSETVNETHDRSZ, &hdr_len) < 0
+            {
+                -1
+            } else {
+                libc::ioctl(
+                    file.as_raw_fd(),
+                    libc::TUNSETOFFLOAD,
+                    offloads as libc::c_ulong,
+                )
+            }
+        };
+        if ret < 0 {
+            return Err(ConnectError::OpenTap(io::Error::last_os_error()));
+        }
+
+        Ok(Self { file })
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl NetBackend for Tap {
+    /// Try to read a frame from the tap device, dropping its virtio-net header. If no frame is
+    /// available reports ReadError::NothingRead
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let mut hdr = [0u8; TAP_VNET_HDR_LEN];
+        let iovs = [
+            libc::iovec {
+                iov_base: hdr.as_mut_ptr() as *mut libc::c_void,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            },
+        ];
+        // SAFETY: the iovecs point to buffers we own, of the lengths they say.
+        let ret = unsafe { libc::readv(self.file.as_raw_fd(), iovs.as_ptr(), iovs.len() as c_int) };
+        if ret < 0 {
+            return match nix::Error::last() {
+                nix::Error::EAGAIN => Err(ReadError::NothingRead),
+                e => Err(ReadError::Internal(e)),
+            };
+        }
+
+        let frame_length = (ret as usize).saturating_sub(TAP_VNET_HDR_LEN);
+        log::trace!("Read eth frame from tap: {} bytes", frame_length);
+        Ok(frame_length)
+    }
+
+    /// Try to write a frame to the tap device, along with the virtio-net header the guest
+    /// prepended to it.
+    ///
+    /// * `hdr_len` - the size of the virtio-net header, must be TAP_VNET_HDR_LEN
+    /// * `buf` - the buffer to write, including the header
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+        assert_eq!(
+            hdr_len, TAP_VNET_HDR_LEN,
+            "Unexpected virtio-net header size"
+        );
+        // SAFETY: buf is a valid buffer of buf.len() bytes.
+        let ret = unsafe {
+            libc::write(
+                self.file.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            return match nix::Error::last() {
+                nix::Error::EAGAIN => Err(WriteError::NothingWritten),
+                e => Err(WriteError::Internal(e)),
+            };
+        }
+        Ok(())
+    }
+
+    /// Tap devices take whole frames, so writes are never partial.
+    fn has_unfinished_write(&self) -> bool {
+        false
+    }
+
+    fn try_finish_write(&mut self, _hdr_len: usize, _buf: &[u8]) -> Result<(), WriteError> {
+        Ok(())
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -0,0 +1,379 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::raw::{c_int, c_uint, c_ulong};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use utils::ioctl::{ioctl_expr, _IOC_NONE, _IOC_READ, _IOC_WRITE};
+use virtio_bindings::virtio_net::{
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+};
+use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use super::backend::ConnectError;
+use super::tap::Tap;
+use crate::legacy::GicV3;
+use crate::virtio::{Queue, VIRTIO_MMIO_INT_VRING};
+
+// From linux/vhost.h.
+const VHOST_VIRTIO: c_uint = 0xAF;
+
+const fn vhost_ioctl(dir: c_uint, nr: c_uint, size: usize) -> c_ulong {
+    ioctl_expr(dir, VHOST_VIRTIO, nr, size as c_uint)
+}
+
+const VHOST_GET_FEATURES: c_ulong = vhost_ioctl(_IOC_READ, 0x00, mem::size_of::<u64>());
+const VHOST_SET_FEATURES: c_ulong = vhost_ioctl(_IOC_WRITE, 0x00, mem::size_of::<u64>());
+const VHOST_SET_OWNER: c_ulong = vhost_ioctl(_IOC_NONE, 0x01, 0);
+const VHOST_SET_MEM_TABLE: c_ulong = vhost_ioctl(_IOC_WRITE, 0x03, mem::size_of::<u64>());
+const VHOST_SET_VRING_NUM: c_ulong =
+    vhost_ioctl(_IOC_WRITE, 0x10, mem::size_of::<VhostVringState>());
+const VHOST_SET_VRING_ADDR: c_ulong =
+    vhost_ioctl(_IOC_WRITE, 0x11, mem::size_of::<VhostVringAddr>());
+const VHOST_SET_VRING_BASE: c_ulong =
+    vhost_ioctl(_IOC_WRITE, 0x12, mem::size_of::<VhostVringState>());
+const VHOST_SET_VRING_KICK: c_ulong =
+    vhost_ioctl(_IOC_WRITE, 0x20, mem::size_of::<VhostVringFile>());
+const VHOST_SET_VRING_CALL: c_ulong =
+    vhost_ioctl(_IOC_WRITE, 0x21, mem::size_of::<VhostVringFile>());
+const VHOST_NET_SET_BACKEND: c_ulong =
+    vhost_ioctl(_IOC_WRITE, 0x30, mem::size_of::<VhostVringFile>());
+
+const VIRTIO_F_VERSION_1: u32 = 32;
+
+#[repr(C)]
+struct VhostVringState {
+    index: c_uint,
+    num: c_uint,
+}
+
+#[repr(C)]
+struct VhostVringFile {
+    index: c_uint,
+    fd: c_int,
+}
+
+#[repr(C)]
+struct VhostVringAddr {
+    index: c_uint,
+    flags: c_uint,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Opening /dev/vhost-net failed.
+    Open(io::Error),
+    /// Opening the tap device failed.
+    Tap(ConnectError),
+    /// A vhost ioctl failed.
+    Ioctl(&'static str, io::Error),
+    /// Creating an eventfd failed.
+    EventFd(io::Error),
+    /// Setting up the epoll instance of the interrupt thread failed.
+    Epoll(io::Error),
+    /// Spawning the interrupt thread failed.
+    SpawnThread(io::Error),
+    /// The guest acked features vhost-net doesn't support.
+    UnsupportedFeatures(u64),
+    /// A queue isn't valid or isn't backed by guest memory.
+    InvalidQueue(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            Open(e) => write!(f, "couldn't open /dev/vhost-net: {e}"),
+            Tap(e) => write!(f, "couldn't open the tap device: {e:?}"),
+            Ioctl(name, e) => write!(f, "{name} failed: {e}"),
+            EventFd(e) => write!(f, "couldn't create an eventfd: {e}"),
+            Epoll(e) => write!(f, "couldn't set up the epoll instance: {e}"),
+            SpawnThread(e) => write!(f, "couldn't spawn the interrupt thread: {e}"),
+            UnsupportedFeatures(features) => {
+                write!(f, "vhost-net doesn't support features {features:#x}")
+            }
+            InvalidQueue(index) => write!(f, "queue {index} is invalid"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Features the guest may ack which the vhost-net datapath has to implement.
+const DATAPATH_FEATURES: u64 = 1 << VIRTIO_RING_F_EVENT_IDX | 1 << VIRTIO_F_VERSION_1;
+
+/// Offloads of the frames the tap device may hand to the guest.
+fn tap_offloads(acked_features: u64) -> c_uint {
+    let mut offloads = 0;
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_CSUM) != 0 {
+        offloads |= libc::TUN_F_CSUM;
+        if acked_features & (1 << VIRTIO_NET_F_GUEST_TSO4) != 0 {
+            offloads |= libc::TUN_F_TSO4;
+        }
+        if acked_features & (1 << VIRTIO_NET_F_GUEST_UFO) != 0 {
+            offloads |= libc::TUN_F_UFO;
+        }
+    }
+    offloads
+}
+
+/// Where the interrupts of the device go.
+pub struct Interrupt {
+    pub status: Arc<AtomicUsize>,
+    pub evt: EventFd,
+    pub intc: Option<GicV3>,
+    pub irq_line: Option<u32>,
+}
+
+/// The datapath of a tap-backed device, running in the kernel. The guest kicks the queues
+/// through their ioeventfds directly, and the kernel signals used buffers through eventfds
+/// which a thread turns into interrupts, as virtio-mmio needs the interrupt status set.
+pub struct VhostNet {
+    file: File,
+    tap: Tap,
+    // Stops the interrupt thread.
+    stop_evt: EventFd,
+}
+
+impl VhostNet {
+    pub fn start(
+        tap_name: &str,
+        acked_features: u64,
+        mem: &GuestMemoryMmap,
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+        interrupt: Interrupt,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open("/dev/vhost-net")
+            .map_err(Error::Open)?;
+        let tap = Tap::new(tap_name, tap_offloads(acked_features)).map_err(Error::Tap)?;
+        let stop_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let vhost = VhostNet {
+            file,
+            tap,
+            stop_evt,
+        };
+
+        vhost.ioctl("VHOST_SET_OWNER", VHOST_SET_OWNER, 0)?;
+
+        let mut vhost_features = 0u64;
+        vhost.ioctl(
+            "VHOST_GET_FEATURES",
+            VHOST_GET_FEATURES,
+            &mut vhost_features as *mut u64 as c_ulong,
+        )?;
+        let unsupported = acked_features & DATAPATH_FEATURES & !vhost_features;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedFeatures(unsupported));
+        }
+        let features = acked_features & vhost_features;
+        vhost.ioctl(
+            "VHOST_SET_FEATURES",
+            VHOST_SET_FEATURES,
+            &features as *const u64 as c_ulong,
+        )?;
+
+        // struct vhost_memory: the number of regions, padded to 64 bits, then the regions.
+        let mut mem_table = vec![mem.num_regions() as u64];
+        for region in mem.iter() {
+            mem_table.extend_from_slice(&[
+                region.start_addr().raw_value(),
+                region.len(),
+                region.as_ptr() as u64,
+                0,
+            ]);
+        }
+        vhost.ioctl(
+            "VHOST_SET_MEM_TABLE",
+            VHOST_SET_MEM_TABLE,
+            mem_table.as_ptr() as c_ulong,
+        )?;
+
+        let mut call_evts = Vec::with_capacity(queues.len());
+        for (index, queue) in queues.iter().enumerate() {
+            let host_addr = |addr| {
+                mem.get_host_address(addr)
+                    .map(|addr| addr as u64)
+                    .map_err(|_| Error::InvalidQueue(index))
+            };
+            if !queue.is_valid(mem) {
+                return Err(Error::InvalidQueue(index));
+            }
+            let addr = VhostVringAddr {
+                index: index as c_uint,
+                flags: 0,
+                desc_user_addr: host_addr(queue.desc_table)?,
+                used_user_addr: host_addr(queue.used_ring)?,
+                avail_user_addr: host_addr(queue.avail_ring)?,
+                log_guest_addr: 0,
+            };
+            let call_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+
+            vhost.set_vring_state(
+                "VHOST_SET_VRING_NUM",
+                VHOST_SET_VRING_NUM,
+                index,
+                queue.actual_size() as c_uint,
+            )?;
+            vhost.set_vring_state(
+                "VHOST_SET_VRING_BASE",
+                VHOST_SET_VRING_BASE,
+                index,
+                queue.next_avail.0 as c_uint,
+            )?;
+            vhost.ioctl(
+                "VHOST_SET_VRING_ADDR",
+                VHOST_SET_VRING_ADDR,
+                &addr as *const VhostVringAddr as c_ulong,
+            )?;
+            vhost.set_vring_file(
+                "VHOST_SET_VRING_KICK",
+                VHOST_SET_VRING_KICK,
+                index,
+                queue_evts[index].as_raw_fd(),
+            )?;
+            vhost.set_vring_file(
+                "VHOST_SET_VRING_CALL",
+                VHOST_SET_VRING_CALL,
+                index,
+                call_evt.as_raw_fd(),
+            )?;
+            call_evts.push(call_evt);
+        }
+
+        // The queues only start moving frames once they have a backend.
+        for index in 0..queues.len() {
+            vhost.set_vring_file(
+                "VHOST_NET_SET_BACKEND",
+                VHOST_NET_SET_BACKEND,
+                index,
+                vhost.tap.as_raw_fd(),
+            )?;
+        }
+
+        let epoll = Epoll::new().map_err(Error::Epoll)?;
+        for fd in call_evts
+            .iter()
+            .chain([&vhost.stop_evt])
+            .map(|evt| evt.as_raw_fd())
+        {
+            epoll
+                .ctl(
+                    ControlOperation::Add,
+                    fd,
+                    &EpollEvent::new(EventSet::IN, fd as u64),
+                )
+                .map_err(Error::Epoll)?;
+        }
+        let stop_evt = vhost.stop_evt.try_clone().map_err(Error::EventFd)?;
+        thread::Builder::new()
+            .name("vhost-net irq".into())
+            .spawn(move || relay_interrupts(epoll, call_evts, stop_evt, interrupt))
+            .map_err(Error::SpawnThread)?;
+
+        Ok(vhost)
+    }
+
+    fn ioctl(&self, name: &'static str, req: c_ulong, arg: c_ulong) -> Result<()> {
+        // SAFETY: the fd is a vhost-net device, and the callers pass the argument each
+        // request expects, pointing to memory that outlives the call.
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), req, arg) };
+        if ret < 0 {
+            return Err(Error::Ioctl(name, io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn set_vring_state(
+        &self,
+        name: &'static str,
+        req: c_ulong,
+        index: usize,
+        num: c_uint,
+    ) -> Result<()> {
+        let state = VhostVringState {
+            index: index as c_uint,
+            num,
+        };
+        self.ioctl(name, req, &state as *const VhostVringState as c_ulong)
+    }
+
+    fn set_vring_file(
+        &self,
+        name: &'static str,
+        req: c_ulong,
+        index: usize,
+        fd: RawFd,
+    ) -> Result<()> {
+        let file = VhostVringFile {
+            index: index as c_uint,
+            fd,
+        };
+        self.ioctl(name, req, &file as *const VhostVringFile as c_ulong)
+    }
+}
+
+impl Drop for VhostNet {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_evt.write(1) {
+            error!("vhost-net: failed to stop the interrupt thread: {}", e);
+        }
+    }
+}
+
+// Turns the used queue notifications of vhost-net into interrupts, until `stop_evt` is signaled.
+fn relay_interrupts(
+    epoll: Epoll,
+    call_evts: Vec<EventFd>,
+    stop_evt: EventFd,
+    interrupt: Interrupt,
+) {
+    let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); call_evts.len() + 1];
+    loop {
+        let ev_cnt = match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
+            Ok(ev_cnt) => ev_cnt,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("vhost-net: failed to wait for used queues: {}", e);
+                return;
+            }
+        };
+
+        let mut signal = false;
+        for event in &epoll_events[0..ev_cnt] {
+            if event.fd() == stop_evt.as_raw_fd() {
+                return;
+            }
+            if let Some(evt) = call_evts.iter().find(|evt| evt.as_raw_fd() == event.fd()) {
+                signal |= evt.read().is_ok();
+            }
+        }
+
+        if signal {
+            interrupt
+                .status
+                .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+            if let Some(intc) = &interrupt.intc {
+                intc.set_irq(interrupt.irq_line.unwrap());
+            } else if let Err(e) = interrupt.evt.write(1) {
+                error!("Failed to signal used queue: {:?}", e);
+            }
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use crate::legacy::GicV3;
 use crate::virtio::net::gvproxy::Gvproxy;
 use crate::virtio::net::passt::Passt;
+#[cfg(target_os = "linux")]
+use crate::virtio::net::tap::Tap;
 use crate::virtio::net::{MAX_BUFFER_SIZE, QUEUE_SIZE, RX_INDEX, TX_INDEX};
-use crate::virtio::{Queue, VIRTIO_MMIO_INT_VRING};
+use crate::virtio::rate_limiter::RateLimiter;
+use crate::virtio::{IoRateLimit, Queue, VIRTIO_MMIO_INT_VRING};
 use crate::Error as DeviceError;
 
 use super::backend::{NetBackend, ReadError, WriteError};
@@ -49,6 +52,9 @@ pub struct NetWorker {
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
     tx_frame_len: usize,
+
+    rx_rate_limiter: Option<RateLimiter>,
+    tx_rate_limiter: Option<RateLimiter>,
 }
 
 impl NetWorker {
@@ -62,12 +68,19 @@ impl NetWorker {
         irq_line: Option<u32>,
         mem: GuestMemoryMmap,
         cfg_backend: VirtioNetBackend,
+        rate_limit: Option<IoRateLimit>,
     ) -> Self {
         let backend = match cfg_backend {
             VirtioNetBackend::Passt(fd) => Box::new(Passt::new(fd)) as Box<dyn NetBackend + Send>,
             VirtioNetBackend::Gvproxy(path) => {
                 Box::new(Gvproxy::new(path).unwrap()) as Box<dyn NetBackend + Send>
             }
+            // The frames from the tap device don't use any offload, as the header we hand
+            // to the guest is always zeroed.
+            #[cfg(target_os = "linux")]
+            VirtioNetBackend::Tap(name) => {
+                Box::new(Tap::new(&name, 0).unwrap()) as Box<dyn NetBackend + Send>
+            }
         };
 
         Self {
@@ -88,6 +101,9 @@ impl NetWorker {
             tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_frame_len: 0,
             tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
+
+            rx_rate_limiter: rate_limit.map(RateLimiter::new),
+            tx_rate_limiter: rate_limit.map(RateLimiter::new),
         }
     }
 
@@ -267,20 +283,30 @@ impl NetWorker {
                 .disable_notification(&self.mem)
                 .unwrap();
 
-            if let Err(e) = self.process_tx() {
-                log::error!("Failed to process rx: {e:?} (triggered by backend socket readable)");
+            let throttled = match self.process_tx() {
+                Ok(throttled) => throttled,
+                Err(e) => {
+                    log::error!(
+                        "Failed to process rx: {e:?} (triggered by backend socket readable)"
+                    );
+                    false
+                }
             };
 
+            // A throttled queue is kicked again once the rate limiter allows it.
             if !self.queues[TX_INDEX]
                 .enable_notification(&self.mem)
                 .unwrap()
+                || throttled
             {
                 break;
             }
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), TxError> {
+    /// Sends the available frames to the backend, returning whether the rate
+    /// limiter stopped it before the queue was empty.
+    fn process_tx(&mut self) -> result::Result<bool, TxError> {
         let tx_queue = &mut self.queues[TX_INDEX];
 
         if self.backend.has_unfinished_write()
@@ -290,10 +316,11 @@ impl NetWorker {
                 .is_err()
         {
             log::trace!("Cannot process tx because of unfinished partial write!");
-            return Ok(());
+            return Ok(false);
         }
 
         let mut raise_irq = false;
+        let mut throttled = false;
 
         while let Some(head) = tx_queue.pop(&self.mem) {
             let head_index = head.index;
@@ -311,6 +338,15 @@ impl NetWorker {
                 next_desc = desc.next_descriptor();
             }
 
+            if let Some(ref mut limiter) = self.tx_rate_limiter {
+                if !limiter.consume(read_count as u64) {
+                    tx_queue.undo_pop();
+                    limiter.schedule_retry(read_count as u64, &self.queue_evts[TX_INDEX]);
+                    throttled = true;
+                    break;
+                }
+            }
+
             // Copy buffer from across multiple descriptors.
             read_count = 0;
             for (desc_addr, desc_len) in self.tx_iovec.drain(..) {
@@ -376,7 +412,7 @@ impl NetWorker {
             self.signal_used_queue().map_err(TxError::DeviceError)?;
         }
 
-        Ok(())
+        Ok(throttled)
     }
 
     fn signal_used_queue(&mut self) -> result::Result<(), DeviceError> {
@@ -445,6 +481,15 @@ impl NetWorker {
     // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
     // the operation if possible. Returns true if the operation was successfull.
     fn write_frame_to_guest(&mut self) -> bool {
+        // The frame stays deferred until the rate limiter allows it.
+        if let Some(ref mut limiter) = self.rx_rate_limiter {
+            let len = self.rx_frame_buf_len as u64;
+            if !limiter.consume(len) {
+                limiter.schedule_retry(len, &self.queue_evts[RX_INDEX]);
+                return false;
+            }
+        }
+
         let max_iterations = self.queues[RX_INDEX].actual_size();
         for _ in 0..max_iterations {
             match self.write_frame_to_guest_impl() {
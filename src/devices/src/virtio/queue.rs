@@ -446,6 +446,20 @@ impl Queue {
         (self.avail_idx(mem, Ordering::Acquire).unwrap() - self.next_avail).0
     }
 
+    /// Returns the number of descriptor chains the driver made available that the device
+    /// hasn't returned in the used ring yet, as seen in guest memory.
+    pub fn in_flight(&self, mem: &GuestMemoryMmap) -> Result<u16, Error> {
+        let used_idx: u16 = mem
+            .load(
+                self.used_ring
+                    .checked_add(2)
+                    .ok_or(Error::AddressOverflow)?,
+                Ordering::Acquire,
+            )
+            .map_err(Error::GuestMemory)?;
+        Ok((self.avail_idx(mem, Ordering::Acquire)? - Wrapping(used_idx)).0)
+    }
+
     /// Checks if the driver has made any descriptor chains available in the avail ring.
     pub fn is_empty(&self, mem: &GuestMemoryMmap) -> bool {
         self.len(mem) == 0
@@ -1145,4 +1159,22 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_in_flight() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        assert_eq!(q.in_flight(m).unwrap(), 0);
+
+        vq.avail.idx.set(3);
+        assert_eq!(q.in_flight(m).unwrap(), 3);
+
+        // Popping doesn't complete a request, only using it does.
+        q.pop(m);
+        assert_eq!(q.in_flight(m).unwrap(), 3);
+        q.add_used(m, 0, 0).unwrap();
+        assert_eq!(q.in_flight(m).unwrap(), 2);
+    }
 }
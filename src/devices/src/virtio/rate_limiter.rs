@@ -0,0 +1,208 @@
+//! Token bucket rate limiting of the I/O performed by the block and net devices.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use utils::eventfd::EventFd;
+
+/// Maximum rate of I/O a device may perform, zero meaning unlimited. Up to one
+/// second worth of I/O may be performed in a burst.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoRateLimit {
+    pub bytes_per_sec: u64,
+    pub ops_per_sec: u64,
+}
+
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Option<Self> {
+        if rate == 0 {
+            return None;
+        }
+
+        Some(TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    // Operations larger than the bucket take all of it, so they still go through.
+    fn cost(&self, tokens: u64) -> f64 {
+        tokens.min(self.rate) as f64
+    }
+
+    fn has(&self, tokens: u64) -> bool {
+        self.tokens >= self.cost(tokens)
+    }
+
+    fn take(&mut self, tokens: u64) {
+        self.tokens -= self.cost(tokens);
+    }
+
+    fn delay(&self, tokens: u64) -> Duration {
+        let missing = (self.cost(tokens) - self.tokens).max(0.0);
+        Duration::from_secs_f64(missing / self.rate as f64)
+    }
+}
+
+pub(crate) struct RateLimiter {
+    bytes: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    // Whether a thread will kick the queue once the buckets are refilled.
+    retry_pending: Arc<AtomicBool>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: IoRateLimit) -> Self {
+        RateLimiter {
+            bytes: TokenBucket::new(limit.bytes_per_sec),
+            ops: TokenBucket::new(limit.ops_per_sec),
+            retry_pending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Takes an operation of `len` bytes from the buckets, if both of them
+    /// hold enough tokens for it.
+    pub(crate) fn consume(&mut self, len: u64) -> bool {
+        for (bucket, tokens) in [(&mut self.bytes, len), (&mut self.ops, 1)] {
+            if let Some(bucket) = bucket {
+                bucket.refill();
+                if !bucket.has(tokens) {
+                    return false;
+                }
+            }
+        }
+
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.take(len);
+        }
+        if let Some(ref mut bucket) = self.ops {
+            bucket.take(1);
+        }
+        true
+    }
+
+    /// Time left until an operation of `len` bytes can be performed.
+    fn delay(&self, len: u64) -> Duration {
+        let bytes = self.bytes.as_ref().map_or(Duration::ZERO, |b| b.delay(len));
+        let ops = self.ops.as_ref().map_or(Duration::ZERO, |b| b.delay(1));
+        bytes.max(ops)
+    }
+
+    /// Kicks `queue_evt` once an operation of `len` bytes can be performed.
+    pub(crate) fn schedule_retry(&self, len: u64, queue_evt: &EventFd) {
+        if self.retry_pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let queue_evt = match queue_evt.try_clone() {
+            Ok(evt) => evt,
+            Err(e) => {
+                error!("Failed to clone the queue event: {:?}", e);
+                self.retry_pending.store(false, Ordering::SeqCst);
+                return;
+            }
+        };
+        let delay = self.delay(len);
+        let retry_pending = self.retry_pending.clone();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            retry_pending.store(false, Ordering::SeqCst);
+            if let Err(e) = queue_evt.write(1) {
+                error!("Failed to kick the rate limited queue: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        let mut limiter = RateLimiter::new(IoRateLimit::default());
+        for _ in 0..1000 {
+            assert!(limiter.consume(u64::MAX));
+        }
+        assert_eq!(limiter.delay(u64::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_bytes_limit() {
+        let mut limiter = RateLimiter::new(IoRateLimit {
+            bytes_per_sec: 4096,
+            ops_per_sec: 0,
+        });
+        assert!(limiter.consume(3072));
+        assert!(!limiter.consume(2048));
+        assert!(limiter.delay(2048) > Duration::from_millis(200));
+        assert!(limiter.consume(1000));
+    }
+
+    #[test]
+    fn test_ops_limit() {
+        let mut limiter = RateLimiter::new(IoRateLimit {
+            bytes_per_sec: 0,
+            ops_per_sec: 2,
+        });
+        assert!(limiter.consume(1 << 20));
+        assert!(limiter.consume(1 << 20));
+        assert!(!limiter.consume(1));
+        assert!(limiter.delay(1) > Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_oversized_operation() {
+        let mut limiter = RateLimiter::new(IoRateLimit {
+            bytes_per_sec: 512,
+            ops_per_sec: 0,
+        });
+        // An operation larger than the bucket goes through once it's full.
+        assert!(limiter.consume(4096));
+        assert!(!limiter.consume(1));
+    }
+
+    #[test]
+    fn test_failed_consume_takes_nothing() {
+        let mut limiter = RateLimiter::new(IoRateLimit {
+            bytes_per_sec: 1024,
+            ops_per_sec: 2,
+        });
+        assert!(limiter.consume(1024));
+        // Out of bytes, so the operation token isn't taken either.
+        assert!(!limiter.consume(1));
+        let ops = limiter.ops.as_ref().unwrap().tokens;
+        assert!((1.0..1.5).contains(&ops));
+    }
+
+    #[test]
+    fn test_schedule_retry() {
+        let mut limiter = RateLimiter::new(IoRateLimit {
+            bytes_per_sec: 0,
+            ops_per_sec: 100,
+        });
+        while limiter.consume(0) {}
+
+        let evt = EventFd::new(0).unwrap();
+        limiter.schedule_retry(0, &evt);
+        // A retry is already pending.
+        limiter.schedule_retry(0, &evt);
+        assert_eq!(evt.read().unwrap(), 1);
+    }
+}
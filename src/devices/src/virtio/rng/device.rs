@@ -1,6 +1,10 @@
+use std::fs::File;
+use std::io::Read;
 use std::result;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use rand::{rngs::OsRng, RngCore};
 use utils::eventfd::EventFd;
@@ -24,6 +28,46 @@ pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64;
 #[repr(C, packed)]
 pub struct VirtioRng {}
 
+/// Maximum amount of entropy a guest may consume over a period of time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RngRateLimit {
+    pub bytes: u64,
+    pub period: Duration,
+}
+
+struct RateLimiter {
+    limit: RngRateLimit,
+    budget: u64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(limit: RngRateLimit) -> Self {
+        RateLimiter {
+            limit,
+            budget: limit.bytes,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes up to `len` bytes from the budget, returning how many were granted.
+    fn consume(&mut self, len: u64) -> u64 {
+        if self.last_refill.elapsed() >= self.limit.period {
+            self.budget = self.limit.bytes;
+            self.last_refill = Instant::now();
+        }
+
+        let granted = len.min(self.budget);
+        self.budget -= granted;
+        granted
+    }
+
+    /// Time left until the budget is refilled.
+    fn refill_delay(&self) -> Duration {
+        self.limit.period.saturating_sub(self.last_refill.elapsed())
+    }
+}
+
 pub struct Rng {
     pub(crate) queues: Vec<VirtQueue>,
     pub(crate) queue_events: Vec<EventFd>,
@@ -33,12 +77,21 @@ pub struct Rng {
     pub(crate) interrupt_evt: EventFd,
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
+    // Entropy source, the host's default one if not set.
+    source: Option<File>,
+    rate_limiter: Option<RateLimiter>,
+    // Whether a thread will kick the queue once the rate limiter is refilled.
+    retry_pending: Arc<AtomicBool>,
     intc: Option<GicV3>,
     irq_line: Option<u32>,
 }
 
 impl Rng {
-    pub(crate) fn with_queues(queues: Vec<VirtQueue>) -> super::Result<Rng> {
+    pub(crate) fn with_queues(
+        queues: Vec<VirtQueue>,
+        source: Option<File>,
+        rate_limit: Option<RngRateLimit>,
+    ) -> super::Result<Rng> {
         let mut queue_events = Vec::new();
         for _ in 0..queues.len() {
             queue_events
@@ -54,17 +107,62 @@ impl Rng {
             interrupt_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(RngError::EventFd)?,
             activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(RngError::EventFd)?,
             device_state: DeviceState::Inactive,
+            source,
+            rate_limiter: rate_limit.map(RateLimiter::new),
+            retry_pending: Arc::new(AtomicBool::new(false)),
             intc: None,
             irq_line: None,
         })
     }
 
-    pub fn new() -> super::Result<Rng> {
+    /// Creates a RNG device reading from `source`, or the host's default
+    /// entropy source if `None`, and serving at most `rate_limit` to the guest.
+    pub fn new(source: Option<File>, rate_limit: Option<RngRateLimit>) -> super::Result<Rng> {
         let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
             .iter()
             .map(|&max_size| VirtQueue::new(max_size))
             .collect();
-        Self::with_queues(queues)
+        Self::with_queues(queues, source, rate_limit)
+    }
+
+    fn fill_bytes(&mut self, buf: &mut [u8]) -> usize {
+        match self.source {
+            Some(ref mut file) => match file.read(buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("rng: failed to read from the entropy source: {:?}", e);
+                    0
+                }
+            },
+            None => {
+                OsRng.fill_bytes(buf);
+                buf.len()
+            }
+        }
+    }
+
+    /// Kicks the request queue once the rate limiter budget is refilled.
+    fn schedule_retry(&self, delay: Duration) {
+        if self.retry_pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let queue_evt = match self.queue_events[REQ_INDEX].try_clone() {
+            Ok(evt) => evt,
+            Err(e) => {
+                error!("rng: failed to clone the queue event: {:?}", e);
+                self.retry_pending.store(false, Ordering::SeqCst);
+                return;
+            }
+        };
+        let retry_pending = self.retry_pending.clone();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            retry_pending.store(false, Ordering::SeqCst);
+            if let Err(e) = queue_evt.write(1) {
+                error!("rng: failed to kick the request queue: {:?}", e);
+            }
+        });
     }
 
     pub fn id(&self) -> &str {
@@ -98,24 +196,48 @@ impl Rng {
             DeviceState::Inactive => unreachable!(),
         };
 
+        let mem = mem.clone();
         let mut have_used = false;
 
-        while let Some(head) = self.queues[REQ_INDEX].pop(mem) {
+        while let Some(head) = self.queues[REQ_INDEX].pop(&mem) {
             let index = head.index;
+
+            let len = head.clone().into_iter().map(|desc| desc.len as u64).sum();
+            let granted = match self.rate_limiter {
+                Some(ref mut limiter) => limiter.consume(len),
+                None => len,
+            };
+            if granted == 0 {
+                // Leave the request in the queue until the budget is refilled.
+                self.queues[REQ_INDEX].go_to_previous_position();
+                let delay = self.rate_limiter.as_ref().unwrap().refill_delay();
+                self.schedule_retry(delay);
+                break;
+            }
+
             let mut written = 0;
+            let mut remaining = granted as usize;
             for desc in head.into_iter() {
-                let mut rand_bytes = vec![0u8; desc.len as usize];
-                OsRng.fill_bytes(&mut rand_bytes);
-                if let Err(e) = mem.write_slice(&rand_bytes[..], desc.addr) {
+                if remaining == 0 {
+                    break;
+                }
+                let mut rand_bytes = vec![0u8; (desc.len as usize).min(remaining)];
+                let len = self.fill_bytes(&mut rand_bytes);
+                if let Err(e) = mem.write_slice(&rand_bytes[..len], desc.addr) {
                     error!("Failed to write slice: {:?}", e);
                     self.queues[REQ_INDEX].go_to_previous_position();
                     break;
                 }
-                written += desc.len;
+                written += len as u32;
+                remaining -= len;
+                if len < rand_bytes.len() {
+                    // The source is drained, serve what we have.
+                    break;
+                }
             }
 
             have_used = true;
-            if let Err(e) = self.queues[REQ_INDEX].add_used(mem, index, written) {
+            if let Err(e) = self.queues[REQ_INDEX].add_used(&mem, index, written) {
                 error!("failed to add used elements to the queue: {:?}", e);
             }
         }
@@ -2,7 +2,7 @@ mod device;
 mod event_handler;
 
 pub use self::defs::uapi::VIRTIO_ID_RNG as TYPE_RNG;
-pub use self::device::Rng;
+pub use self::device::{Rng, RngRateLimit};
 
 mod defs {
     pub const RNG_DEV_ID: &str = "virtio_rng";
@@ -17,8 +17,8 @@ use vm_memory::GuestMemoryMmap;
 
 use super::super::super::Error as DeviceError;
 use super::super::{
-    ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice, VsockError,
-    VIRTIO_MMIO_INT_VRING,
+    ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice, VmmExitObserver,
+    VsockError, VIRTIO_MMIO_INT_VRING,
 };
 use super::muxer::VsockMuxer;
 use super::packet::VsockPacket;
@@ -124,6 +124,11 @@ impl Vsock {
         self.cid
     }
 
+    /// Paths of the host sockets created when the guest listens on their ports.
+    pub fn listening_socket_paths(&self) -> Vec<PathBuf> {
+        self.muxer.listening_socket_paths().cloned().collect()
+    }
+
     /// Signal the guest driver that we've used some virtio buffers that it had previously made
     /// available.
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
@@ -317,14 +322,17 @@ impl VirtioDevice for Vsock {
             return Err(ActivateError::BadActivate);
         }
 
-        self.queue_tx = Arc::new(Mutex::new(self.queues[TXQ_INDEX].clone()));
-        self.queue_rx = Arc::new(Mutex::new(self.queues[RXQ_INDEX].clone()));
-        self.muxer.activate(
-            mem.clone(),
-            self.queue_rx.clone(),
-            self.intc.clone(),
-            self.irq_line,
-        );
+        // The queues are shared with the muxer, which keeps running across resets.
+        *self.queue_tx.lock().unwrap() = self.queues[TXQ_INDEX].clone();
+        *self.queue_rx.lock().unwrap() = self.queues[RXQ_INDEX].clone();
+        if !self.muxer.is_activated() {
+            self.muxer.activate(
+                mem.clone(),
+                self.queue_rx.clone(),
+                self.intc.clone(),
+                self.irq_line,
+            );
+        }
 
         self.device_state = DeviceState::Activated(mem);
 
@@ -337,4 +345,20 @@ impl VirtioDevice for Vsock {
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn reset(&mut self) -> bool {
+        // Nothing may be written to the rings of the previous driver, so the
+        // muxer only finds empty queues until the device is activated again.
+        *self.queue_tx.lock().unwrap() = VirtQueue::new(self.queues[TXQ_INDEX].get_max_size());
+        *self.queue_rx.lock().unwrap() = VirtQueue::new(self.queues[RXQ_INDEX].get_max_size());
+        self.muxer.reset();
+        self.device_state = DeviceState::Inactive;
+        true
+    }
+}
+
+impl VmmExitObserver for Vsock {
+    fn on_vmm_exit(&mut self) {
+        self.muxer.remove_listening_sockets();
+    }
 }
@@ -47,6 +47,9 @@ mod defs {
     pub const CONN_TX_BUF_SIZE: usize = 8 * 1024 * 1024;
     pub const SOCK_STREAM: u16 = 1;
     pub const SOCK_DGRAM: u16 = 2;
+    /// Address families, as the guest (Linux) defines them.
+    pub const AF_INET: u16 = 2;
+    pub const AF_INET6: u16 = 10;
 
     /// Misc
     pub const TSI_PROXY_PORT: u32 = 620;
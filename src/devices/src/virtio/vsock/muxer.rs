@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -12,7 +12,7 @@ use super::defs::uapi;
 use super::muxer_rxq::{rx_to_pkt, MuxerRxQ};
 use super::muxer_thread::MuxerThread;
 use super::packet::{TsiConnectReq, TsiGetnameRsp, VsockPacket};
-use super::proxy::{Proxy, ProxyRemoval, ProxyUpdate};
+use super::proxy::{host_family, Proxy, ProxyRemoval, ProxyUpdate};
 use super::reaper::ReaperThread;
 use super::tcp::TcpProxy;
 #[cfg(target_os = "macos")]
@@ -25,7 +25,7 @@ use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
 use vm_memory::GuestMemoryMmap;
 
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
 pub type ProxyMap = Arc<RwLock<HashMap<u64, Mutex<Box<dyn Proxy>>>>>;
 
@@ -139,6 +139,48 @@ impl VsockMuxer {
         }
     }
 
+    /// Paths of the host sockets created for the ports the guest listens on.
+    pub(crate) fn listening_socket_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.unix_ipc_port_map
+            .iter()
+            .flatten()
+            .map(|(_, entry)| entry)
+            .filter(|(_, listen)| *listen)
+            .map(|(path, _)| path)
+    }
+
+    /// Removes the host sockets created for the ports the guest listens on.
+    pub(crate) fn remove_listening_sockets(&self) {
+        for path in self.listening_socket_paths() {
+            if let Err(e) = std::fs::remove_file(path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to remove listening socket {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn is_activated(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Drops the connections of the guest and the packets pending for it, as
+    /// it's rebooting. Only the sockets listening for IPC ports are kept.
+    pub(crate) fn reset(&self) {
+        let listening: HashSet<u64> = self
+            .unix_ipc_port_map
+            .iter()
+            .flatten()
+            .filter(|(_, (_, listen))| *listen)
+            .map(|(port, _)| (*port as u64) << 32 | defs::TSI_PROXY_PORT as u64)
+            .collect();
+        self.proxy_map
+            .write()
+            .unwrap()
+            .retain(|id, _| listening.contains(id));
+        *self.rxq.lock().unwrap() = MuxerRxQ::new();
+    }
+
     pub(crate) fn activate(
         &mut self,
         mem: GuestMemoryMmap,
@@ -253,9 +295,16 @@ impl VsockMuxer {
         debug!("vsock: proxy create request");
         if let Some(req) = pkt.read_proxy_create() {
             debug!(
-                "vsock: proxy create request: peer_port={}, type={}",
-                req.peer_port, req._type
+                "vsock: proxy create request: peer_port={}, type={}, family={}",
+                req.peer_port, req._type, req.family
             );
+            let family = match host_family(req.family) {
+                Ok(family) => family,
+                Err(e) => {
+                    debug!("vsock: proxy create request: {}", e);
+                    return;
+                }
+            };
             let mem = match self.mem.as_ref() {
                 Some(m) => m,
                 None => {
@@ -280,6 +329,7 @@ impl VsockMuxer {
                         defs::TSI_PROXY_PORT,
                         req.peer_port,
                         pkt.src_port(),
+                        family,
                         mem.clone(),
                         queue.clone(),
                         self.rxq.clone(),
@@ -300,6 +350,7 @@ impl VsockMuxer {
                         id,
                         self.cid,
                         req.peer_port,
+                        family,
                         mem.clone(),
                         queue.clone(),
                         self.rxq.clone(),
@@ -527,7 +578,7 @@ impl VsockMuxer {
                 .unwrap();
                 let tsi = TsiConnectReq {
                     peer_port: 0,
-                    addr: Ipv4Addr::new(0, 0, 0, 0),
+                    addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
                     port: 0,
                 };
                 let update = unix.connect(pkt, tsi);
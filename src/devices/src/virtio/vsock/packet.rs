@@ -17,7 +17,7 @@
 /// to temporary buffers, before passing it on to the vsock backend.
 use std::convert::TryInto;
 use std::ffi::CStr;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::os::raw::c_char;
 use std::result;
 
@@ -97,12 +97,13 @@ const HDROFF_FWD_CNT: usize = 40;
 pub struct TsiProxyCreate {
     pub peer_port: u32,
     pub _type: u16,
+    pub family: u16,
 }
 
 #[repr(C)]
 pub struct TsiConnectReq {
     pub peer_port: u32,
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub port: u16,
 }
 
@@ -121,7 +122,7 @@ pub struct TsiGetnameReq {
 #[repr(C)]
 #[derive(Debug)]
 pub struct TsiGetnameRsp {
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub port: u16,
     pub result: i32,
 }
@@ -129,7 +130,7 @@ pub struct TsiGetnameRsp {
 impl Default for TsiGetnameRsp {
     fn default() -> Self {
         TsiGetnameRsp {
-            addr: Ipv4Addr::new(0, 0, 0, 0),
+            addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             port: 0,
             result: -1,
         }
@@ -140,7 +141,7 @@ impl Default for TsiGetnameRsp {
 #[derive(Debug)]
 pub struct TsiSendtoAddr {
     pub peer_port: u32,
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub port: u16,
 }
 
@@ -148,7 +149,7 @@ pub struct TsiSendtoAddr {
 #[derive(Debug)]
 pub struct TsiListenReq {
     pub peer_port: u32,
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub port: u16,
     pub vm_port: u32,
     pub backlog: i32,
@@ -479,12 +480,37 @@ impl VsockPacket {
         }
     }
 
+    /// Reads the address of a TSI request, with the IPv4 address at `offset`. Guests
+    /// supporting IPv6 append the family and a 16-byte address at `ext_offset`.
+    fn read_tsi_addr(&self, offset: usize, ext_offset: usize) -> IpAddr {
+        let buf = self.buf().unwrap();
+        if buf.len() >= ext_offset + 18
+            && byte_order::read_le_u16(&buf[ext_offset..]) == defs::AF_INET6
+        {
+            let octets: [u8; 16] = buf[ext_offset + 2..ext_offset + 18].try_into().unwrap();
+            IpAddr::V6(Ipv6Addr::from(octets))
+        } else {
+            let octets: [u8; 4] = buf[offset..offset + 4].try_into().unwrap();
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+    }
+
     pub fn read_proxy_create(&self) -> Option<TsiProxyCreate> {
         if self.buf_size >= 6 {
             let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
             let _type: u16 = byte_order::read_le_u16(&self.buf().unwrap()[4..]);
-
-            Some(TsiProxyCreate { peer_port, _type })
+            // Guests without IPv6 support don't send the family.
+            let family = if self.buf_size >= 8 {
+                byte_order::read_le_u16(&self.buf().unwrap()[6..])
+            } else {
+                defs::AF_INET
+            };
+
+            Some(TsiProxyCreate {
+                peer_port,
+                _type,
+                family,
+            })
         } else {
             None
         }
@@ -495,9 +521,7 @@ impl VsockPacket {
             let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
             let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[8..]);
 
-            let ptr = &self.buf().unwrap()[4];
-            let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, 4) };
-            let addr = Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]);
+            let addr = self.read_tsi_addr(4, 10);
 
             Some(TsiConnectReq {
                 peer_port,
@@ -535,11 +559,17 @@ impl VsockPacket {
     pub fn write_getname_rsp(&mut self, rsp: TsiGetnameRsp) {
         if self.buf_size >= 10 {
             if let Some(buf) = self.buf_mut() {
-                for (i, b) in rsp.addr.octets().iter().enumerate() {
-                    buf[i] = *b;
-                }
+                let (addr, family, addr6) = match rsp.addr {
+                    IpAddr::V4(addr) => (addr, defs::AF_INET, [0; 16]),
+                    IpAddr::V6(addr) => (Ipv4Addr::UNSPECIFIED, defs::AF_INET6, addr.octets()),
+                };
+                buf[0..4].copy_from_slice(&addr.octets());
                 byte_order::write_be_u16(&mut buf[4..], rsp.port);
                 byte_order::write_le_u32(&mut buf[6..], rsp.result as u32);
+                if buf.len() >= 28 {
+                    byte_order::write_le_u16(&mut buf[10..], family);
+                    buf[12..28].copy_from_slice(&addr6);
+                }
             }
         }
     }
@@ -549,9 +579,7 @@ impl VsockPacket {
             let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
             let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[8..]);
 
-            let ptr = &self.buf().unwrap()[4];
-            let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, 4) };
-            let addr = Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]);
+            let addr = self.read_tsi_addr(4, 10);
 
             Some(TsiSendtoAddr {
                 peer_port,
@@ -567,9 +595,7 @@ impl VsockPacket {
         if self.buf_size >= 18 {
             let peer_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[0..]);
 
-            let ptr = &self.buf().unwrap()[4];
-            let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, 4) };
-            let addr = Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]);
+            let addr = self.read_tsi_addr(4, 18);
 
             let port: u16 = byte_order::read_be_u16(&self.buf().unwrap()[8..]);
             let vm_port: u32 = byte_order::read_le_u32(&self.buf().unwrap()[10..]);
@@ -1,7 +1,11 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::io::{AsRawFd, RawFd};
 
+use nix::sys::socket::{AddressFamily, SockaddrLike, SockaddrStorage};
+
+use super::defs;
 use super::muxer::MuxerRx;
 use super::packet::{TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiSendtoAddr, VsockPacket};
 use utils::epoll::EventSet;
@@ -19,6 +23,7 @@ pub enum RecvPkt {
 pub enum ProxyError {
     CreatingSocket(nix::errno::Errno),
     SettingReusePort(nix::errno::Errno),
+    UnsupportedFamily(u16),
 }
 
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
@@ -64,6 +69,38 @@ impl fmt::Display for ProxyError {
     }
 }
 
+/// Returns the host address family for a family requested by the guest.
+pub fn host_family(family: u16) -> Result<AddressFamily, ProxyError> {
+    match family {
+        defs::AF_INET => Ok(AddressFamily::Inet),
+        defs::AF_INET6 => Ok(AddressFamily::Inet6),
+        _ => Err(ProxyError::UnsupportedFamily(family)),
+    }
+}
+
+/// Builds the address to use with a socket of `family`. IPv4 addresses are mapped into
+/// IPv6 ones for IPv6 sockets, which are dual-stack.
+pub fn inet_sockaddr(family: AddressFamily, addr: IpAddr, port: u16) -> SockaddrStorage {
+    let addr = match (family, addr) {
+        (AddressFamily::Inet6, IpAddr::V4(addr)) => IpAddr::V6(addr.to_ipv6_mapped()),
+        (_, addr) => addr,
+    };
+    SockaddrStorage::from(SocketAddr::new(addr, port))
+}
+
+/// Returns the address and port of a socket name.
+pub fn inet_name(name: &SockaddrStorage) -> Option<(IpAddr, u16)> {
+    match name.family() {
+        Some(AddressFamily::Inet) => name
+            .as_sockaddr_in()
+            .map(|name| (IpAddr::V4(Ipv4Addr::from(name.ip())), name.port())),
+        Some(AddressFamily::Inet6) => name
+            .as_sockaddr_in6()
+            .map(|name| (IpAddr::V6(name.ip()), name.port())),
+        _ => None,
+    }
+}
+
 pub trait Proxy: Send + AsRawFd {
     fn id(&self) -> u64;
     #[allow(dead_code)]
@@ -1,13 +1,14 @@
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::{Arc, Mutex};
 
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::{
-    accept, bind, connect, getpeername, listen, recv, send, setsockopt, shutdown, socket, sockopt,
-    AddressFamily, MsgFlags, Shutdown, SockFlag, SockType, SockaddrIn,
+    accept, bind, connect, getpeername, getsockname, listen, recv, send, setsockopt, shutdown,
+    socket, sockopt, AddressFamily, MsgFlags, Shutdown, SockFlag, SockType, SockaddrLike,
+    SockaddrStorage,
 };
 use nix::unistd::close;
 
@@ -22,7 +23,8 @@ use super::packet::{
     TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, VsockPacket,
 };
 use super::proxy::{
-    NewProxyType, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt,
+    inet_name, inet_sockaddr, NewProxyType, Proxy, ProxyError, ProxyRemoval, ProxyStatus,
+    ProxyUpdate, RecvPkt,
 };
 use utils::epoll::EventSet;
 
@@ -35,6 +37,7 @@ pub struct TcpProxy {
     local_port: u32,
     peer_port: u32,
     control_port: u32,
+    family: AddressFamily,
     fd: RawFd,
     pub status: ProxyStatus,
     mem: GuestMemoryMmap,
@@ -57,17 +60,13 @@ impl TcpProxy {
         local_port: u32,
         peer_port: u32,
         control_port: u32,
+        family: AddressFamily,
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
     ) -> Result<Self, ProxyError> {
-        let fd = socket(
-            AddressFamily::Inet,
-            SockType::Stream,
-            SockFlag::empty(),
-            None,
-        )
-        .map_err(ProxyError::CreatingSocket)?;
+        let fd = socket(family, SockType::Stream, SockFlag::empty(), None)
+            .map_err(ProxyError::CreatingSocket)?;
 
         // macOS forces us to do this here instead of just using SockFlag::SOCK_NONBLOCK above.
         match fcntl(fd, FcntlArg::F_GETFL) {
@@ -105,6 +104,7 @@ impl TcpProxy {
             local_port,
             peer_port,
             control_port,
+            family,
             fd,
             status: ProxyStatus::Idle,
             mem,
@@ -136,6 +136,10 @@ impl TcpProxy {
             "new_reverse: id={} local_port={} peer_port={}",
             id, local_port, peer_port
         );
+        let family = getsockname::<SockaddrStorage>(fd)
+            .ok()
+            .and_then(|name| name.family())
+            .unwrap_or(AddressFamily::Inet);
         TcpProxy {
             id,
             cid,
@@ -143,6 +147,7 @@ impl TcpProxy {
             local_port,
             peer_port,
             control_port: 0,
+            family,
             fd,
             status: ProxyStatus::ReverseInit,
             mem,
@@ -188,10 +193,14 @@ impl TcpProxy {
             req.port
         };
 
-        match bind(
-            self.fd,
-            &SockaddrIn::from(SocketAddrV4::new(req.addr, port)),
-        ) {
+        // Listening on any IPv6 address accepts IPv4 connections too.
+        if self.family == AddressFamily::Inet6 && req.addr == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+            if let Err(e) = setsockopt(self.fd, sockopt::Ipv6V6Only, &false) {
+                warn!("tcp: couldn't enable dual-stack: id={} err={}", self.id, e);
+            }
+        }
+
+        match bind(self.fd, &inet_sockaddr(self.family, req.addr, port)) {
             Ok(_) => {
                 debug!("tcp bind: id={}", self.id);
                 match listen(self.fd, req.backlog as usize) {
@@ -368,10 +377,7 @@ impl Proxy for TcpProxy {
     fn connect(&mut self, _pkt: &VsockPacket, req: TsiConnectReq) -> ProxyUpdate {
         let mut update = ProxyUpdate::default();
 
-        let result = match connect(
-            self.fd,
-            &SockaddrIn::from(SocketAddrV4::new(req.addr, req.port)),
-        ) {
+        let result = match connect(self.fd, &inet_sockaddr(self.family, req.addr, req.port)) {
             Ok(()) => {
                 debug!("vsock: connect: Connected");
                 self.switch_to_connected();
@@ -430,17 +436,17 @@ impl Proxy for TcpProxy {
     fn getpeername(&mut self, pkt: &VsockPacket) {
         debug!("getpeername: id={}", self.id);
 
-        let (result, addr, port) = match getpeername::<SockaddrIn>(self.fd) {
-            Ok(name) => {
-                let addr = Ipv4Addr::from(name.ip());
-                (0, addr, name.port())
-            }
+        let (result, addr, port) = match getpeername::<SockaddrStorage>(self.fd) {
+            Ok(name) => match inet_name(&name) {
+                Some((addr, port)) => (0, addr, port),
+                None => (-libc::EAFNOSUPPORT, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            },
             Err(e) => {
                 #[cfg(target_os = "macos")]
                 let errno = -linux_errno_raw(e as i32);
                 #[cfg(target_os = "linux")]
                 let errno = -(e as i32);
-                (errno, Ipv4Addr::new(0, 0, 0, 0), 0)
+                (errno, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
             }
         };
 
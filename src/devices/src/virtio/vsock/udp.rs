@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::{Arc, Mutex};
@@ -7,7 +7,7 @@ use std::sync::{Arc, Mutex};
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::{
     bind, connect, getpeername, recv, send, sendto, socket, AddressFamily, MsgFlags, SockFlag,
-    SockType, SockaddrIn,
+    SockType, SockaddrStorage,
 };
 use nix::unistd::close;
 
@@ -21,7 +21,9 @@ use super::muxer_rxq::MuxerRxQ;
 use super::packet::{
     TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, VsockPacket,
 };
-use super::proxy::{Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt};
+use super::proxy::{
+    inet_name, inet_sockaddr, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt,
+};
 use utils::epoll::EventSet;
 
 use vm_memory::GuestMemoryMmap;
@@ -31,9 +33,10 @@ pub struct UdpProxy {
     cid: u64,
     local_port: u32,
     peer_port: u32,
+    family: AddressFamily,
     fd: RawFd,
     pub status: ProxyStatus,
-    sendto_addr: Option<SockaddrIn>,
+    sendto_addr: Option<SockaddrStorage>,
     listening: bool,
     mem: GuestMemoryMmap,
     queue: Arc<Mutex<VirtQueue>>,
@@ -49,17 +52,13 @@ impl UdpProxy {
         id: u64,
         cid: u64,
         peer_port: u32,
+        family: AddressFamily,
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
     ) -> Result<Self, ProxyError> {
-        let fd = socket(
-            AddressFamily::Inet,
-            SockType::Datagram,
-            SockFlag::empty(),
-            None,
-        )
-        .map_err(ProxyError::CreatingSocket)?;
+        let fd = socket(family, SockType::Datagram, SockFlag::empty(), None)
+            .map_err(ProxyError::CreatingSocket)?;
 
         // macOS forces us to do this here instead of just using SockFlag::SOCK_NONBLOCK above.
         match fcntl(fd, FcntlArg::F_GETFL) {
@@ -94,6 +93,7 @@ impl UdpProxy {
             cid,
             local_port: 0,
             peer_port,
+            family,
             fd,
             status: ProxyStatus::Idle,
             sendto_addr: None,
@@ -237,10 +237,7 @@ impl Proxy for UdpProxy {
 
     fn connect(&mut self, pkt: &VsockPacket, req: TsiConnectReq) -> ProxyUpdate {
         debug!("vsock: udp: connect: addr={}, port={}", req.addr, req.port);
-        let res = match connect(
-            self.fd,
-            &SockaddrIn::from(SocketAddrV4::new(req.addr, req.port)),
-        ) {
+        let res = match connect(self.fd, &inet_sockaddr(self.family, req.addr, req.port)) {
             Ok(()) => {
                 debug!("vsock: connect: Connected");
                 self.status = ProxyStatus::Connected;
@@ -277,12 +274,26 @@ impl Proxy for UdpProxy {
     fn getpeername(&mut self, pkt: &VsockPacket) {
         debug!("vsock: udp: process_getpeername");
 
-        let name = getpeername::<SockaddrIn>(self.fd).unwrap();
-        let addr = Ipv4Addr::from(name.ip());
-        let data = TsiGetnameRsp {
-            addr,
-            port: name.port(),
-            result: 0,
+        let data = match getpeername::<SockaddrStorage>(self.fd).map(|name| inet_name(&name)) {
+            Ok(Some((addr, port))) => TsiGetnameRsp {
+                addr,
+                port,
+                result: 0,
+            },
+            Ok(None) => TsiGetnameRsp {
+                result: -libc::EAFNOSUPPORT,
+                ..Default::default()
+            },
+            Err(e) => {
+                #[cfg(target_os = "macos")]
+                let errno = -linux_errno_raw(e as i32);
+                #[cfg(target_os = "linux")]
+                let errno = -(e as i32);
+                TsiGetnameRsp {
+                    result: errno,
+                    ..Default::default()
+                }
+            }
         };
 
         // This response goes to the connection.
@@ -327,9 +338,13 @@ impl Proxy for UdpProxy {
 
         let mut update = ProxyUpdate::default();
 
-        self.sendto_addr = Some(SockaddrIn::from(SocketAddrV4::new(req.addr, req.port)));
+        self.sendto_addr = Some(inet_sockaddr(self.family, req.addr, req.port));
         if !self.listening {
-            match bind(self.fd, &SockaddrIn::new(0, 0, 0, 0, 0)) {
+            let any = match self.family {
+                AddressFamily::Inet6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            };
+            match bind(self.fd, &inet_sockaddr(self.family, any, 0)) {
                 Ok(_) => {
                     self.listening = true;
                     update.polling = Some((self.id, self.fd, EventSet::IN));
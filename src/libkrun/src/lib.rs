@@ -9,55 +9,114 @@ use std::ffi::CStr;
 #[cfg(target_os = "linux")]
 use std::ffi::CString;
 #[cfg(target_os = "linux")]
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
 use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::IntoRawFd;
 use std::os::fd::RawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
 use std::slice;
 use std::sync::atomic::{AtomicI32, Ordering};
+#[cfg(any(not(feature = "tee"), feature = "amd-sev"))]
+use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "macos")]
 use crossbeam_channel::unbounded;
 #[cfg(feature = "blk")]
 use devices::virtio::block::ImageType;
 #[cfg(feature = "net")]
-use devices::virtio::net::device::VirtioNetBackend;
+use devices::virtio::net::device::{VirtioNetBackend, MIN_MTU};
 #[cfg(feature = "blk")]
 use devices::virtio::CacheType;
+#[cfg(any(feature = "blk", feature = "net"))]
+use devices::virtio::IoRateLimit;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::{IdMap, IdRange, RngRateLimit};
 use env_logger::Env;
 #[cfg(target_os = "macos")]
 use hvf::MemoryMapping;
+#[cfg(feature = "amd-sev")]
+use libc::c_void;
 #[cfg(not(feature = "efi"))]
 use libc::size_t;
 use libc::{c_char, c_int};
 use once_cell::sync::Lazy;
 use polly::event_manager::EventManager;
+use smbios::SmbiosField;
+use utils::cancel::CancelToken;
 use utils::eventfd::EventFd;
+use utils::sensitive::is_sensitive;
+use vmm::metrics::METRICS;
 use vmm::resources::VmResources;
+#[cfg(feature = "tee")]
+use vmm::resources::{Tee, TeeConfig};
+#[cfg(target_os = "linux")]
+use vmm::seccomp::SeccompAction;
 #[cfg(feature = "blk")]
 use vmm::vmm_config::block::BlockDeviceConfig;
 use vmm::vmm_config::boot_source::{BootSourceConfig, DEFAULT_KERNEL_CMDLINE};
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use vmm::vmm_config::firmware::FirmwareConfig;
 #[cfg(not(feature = "tee"))]
-use vmm::vmm_config::fs::FsDeviceConfig;
+use vmm::vmm_config::fs::{FsConfigError, FsDeviceConfig};
+use vmm::vmm_config::host_limits::HostRlimit;
 #[cfg(not(feature = "efi"))]
 use vmm::vmm_config::kernel_bundle::KernelBundle;
 #[cfg(feature = "tee")]
 use vmm::vmm_config::kernel_bundle::{InitrdBundle, QbootBundle};
-use vmm::vmm_config::machine_config::VmConfig;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use vmm::vmm_config::machine_config::Clocksource;
+use vmm::vmm_config::machine_config::{
+    CpuFeaturesTemplate, CpuTopology, HugePageSize, HugePagesConfig, VmConfig,
+};
 #[cfg(feature = "net")]
 use vmm::vmm_config::net::NetworkInterfaceConfig;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use vmm::vmm_config::numa::NumaNodeConfig;
 use vmm::vmm_config::vsock::VsockDeviceConfig;
+#[cfg(target_arch = "x86_64")]
+use vmm::vmm_config::watchdog::{WatchdogAction, WatchdogConfig};
 
 // Minimum krunfw version we require.
 #[cfg(not(feature = "efi"))]
 const KRUNFW_MIN_VERSION: u32 = 4;
 // Value returned on success. We use libc's errors otherwise.
 const KRUN_SUCCESS: i32 = 0;
+// Formats of the log lines, as passed to krun_init_log.
+const KRUN_LOG_FORMAT_TEXT: u32 = 0;
+const KRUN_LOG_FORMAT_JSON: u32 = 1;
 // Maximum number of arguments/environment variables we allow
 const MAX_ARGS: usize = 4096;
 
 // Path to the init binary to be executed inside the VM.
 const INIT_PATH: &str = "/init.krun";
+// The kernel hands init up to 32 arguments and 32 environment variables, some
+// of them already taken, and the whole command line is 2 KiB on aarch64.
+const MAX_CMDLINE_ARGS: usize = 24;
+const MAX_CMDLINE_ENVS: usize = 24;
+const MAX_CMDLINE_EXEC_SIZE: usize = 1024;
+// Longest hostname the guest kernel accepts, HOST_NAME_MAX.
+const MAX_HOSTNAME_LEN: usize = 64;
+// Most name servers the guest resolver uses, MAXNS in <resolv.h>.
+const MAX_DNS_SERVERS: usize = 3;
+
+// Port the init process in the guest reports it's ready on, as KRUN_READY_PORT
+// in init/init.c.
+const GUEST_READY_PORT: u32 = 1026;
+// Message the init process sends right before running the workload.
+const GUEST_READY_MESSAGE: &[u8] = b"READY\n";
+// Values written to the guest ready eventfd, as defined in libkrun.h.
+const KRUN_GUEST_READY: u64 = 1;
+const KRUN_GUEST_READY_TIMEOUT: u64 = 2;
+
+// Maximum number of network interfaces added with krun_add_net_*.
+#[cfg(feature = "net")]
+const MAX_NET_IFACES: usize = 16;
 
 #[derive(Default)]
 struct TsiConfig {
@@ -77,16 +136,29 @@ impl Default for NetworkConfig {
     }
 }
 
+/// Where to report the guest reached userspace, for krun_get_guest_ready_eventfd().
+struct GuestReadyConfig {
+    efd: EventFd,
+    timeout: Option<Duration>,
+}
+
 #[derive(Default)]
 struct ContextConfig {
     vmr: VmResources,
     workdir: Option<String>,
     exec_path: Option<String>,
-    env: Option<String>,
-    args: Option<String>,
+    env: Option<Vec<String>>,
+    args: Option<Vec<String>>,
     rlimits: Option<String>,
+    hostname: Option<String>,
+    dns_servers: Option<Vec<IpAddr>>,
+    kernel_cmdline_append: Option<String>,
     net_cfg: NetworkConfig,
     mac: Option<[u8; 6]>,
+    #[cfg(feature = "net")]
+    mtu: Option<u16>,
+    #[cfg(feature = "net")]
+    net_ifaces: Vec<NetworkInterfaceConfig>,
     #[cfg(feature = "blk")]
     block_cfgs: Vec<BlockDeviceConfig>,
     #[cfg(feature = "blk")]
@@ -95,8 +167,15 @@ struct ContextConfig {
     data_block_cfg: Option<BlockDeviceConfig>,
     #[cfg(feature = "tee")]
     tee_config_file: Option<PathBuf>,
+    #[cfg(feature = "tee")]
+    tee_config: Option<TeeConfig>,
+    #[cfg(feature = "amd-sev")]
+    tee_vendor_chain: Option<Vec<u8>>,
+    #[cfg(feature = "amd-sev")]
+    tee_progress_callback: Option<(TeeProgressCallback, usize)>,
     unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
     shutdown_efd: Option<EventFd>,
+    guest_ready: Option<GuestReadyConfig>,
     gpu_virgl_flags: Option<u32>,
     gpu_shm_size: Option<usize>,
     enable_snd: bool,
@@ -126,28 +205,49 @@ impl ContextConfig {
         }
     }
 
-    fn set_env(&mut self, env: String) {
+    fn set_env(&mut self, env: Vec<String>) {
         self.env = Some(env);
     }
 
     fn get_env(&self) -> String {
         match &self.env {
-            Some(env) => env.clone(),
+            Some(env) => quote_str_array(env),
             None => "".to_string(),
         }
     }
 
-    fn set_args(&mut self, args: String) {
+    fn set_args(&mut self, args: Vec<String>) {
         self.args = Some(args);
     }
 
     fn get_args(&self) -> String {
         match &self.args {
-            Some(args) => args.clone(),
+            Some(args) => quote_str_array(args),
             None => "".to_string(),
         }
     }
 
+    /// Returns the configuration of the executable for the guest init to read
+    /// from the "krun-config" port, when its arguments or environment don't fit
+    /// in the kernel command line.
+    fn get_exec_config(&self) -> Option<Vec<u8>> {
+        let exec_path = self.exec_path.as_ref()?;
+        let env = self.env.as_deref().unwrap_or_default();
+        let args = self.args.as_deref().unwrap_or_default();
+        if env.len() <= MAX_CMDLINE_ENVS
+            && args.len() <= MAX_CMDLINE_ARGS
+            && self.get_env().len() + self.get_args().len() <= MAX_CMDLINE_EXEC_SIZE
+        {
+            return None;
+        }
+
+        let argv: Vec<&str> = std::iter::once(exec_path.as_str())
+            .chain(args.iter().map(String::as_str))
+            .collect();
+        let config = serde_json::json!({ "Env": env, "args": argv });
+        Some(config.to_string().into_bytes())
+    }
+
     fn set_rlimits(&mut self, rlimits: String) {
         self.rlimits = Some(rlimits);
     }
@@ -159,9 +259,57 @@ impl ContextConfig {
         }
     }
 
+    fn set_hostname(&mut self, hostname: String) {
+        self.hostname = Some(hostname);
+    }
+
+    fn get_hostname(&self) -> String {
+        match &self.hostname {
+            Some(hostname) => format!("KRUN_HOSTNAME={hostname}"),
+            None => "".to_string(),
+        }
+    }
+
+    fn set_dns_servers(&mut self, dns_servers: Vec<IpAddr>) {
+        self.dns_servers = Some(dns_servers);
+    }
+
+    fn get_dns_servers(&self) -> String {
+        match &self.dns_servers {
+            Some(servers) => format!(
+                "KRUN_DNS={}",
+                servers
+                    .iter()
+                    .map(IpAddr::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            None => "".to_string(),
+        }
+    }
+
+    fn append_kernel_cmdline(&mut self, params: &str) {
+        match &mut self.kernel_cmdline_append {
+            Some(cmdline) => {
+                cmdline.push(' ');
+                cmdline.push_str(params);
+            }
+            None => self.kernel_cmdline_append = Some(params.to_string()),
+        }
+    }
+
     #[cfg(feature = "blk")]
-    fn add_block_cfg(&mut self, block_cfg: BlockDeviceConfig) {
+    fn add_block_cfg(&mut self, block_cfg: BlockDeviceConfig) -> i32 {
+        if self
+            .block_cfgs
+            .iter()
+            .any(|cfg| cfg.block_id == block_cfg.block_id)
+        {
+            return -libc::EEXIST;
+        }
+
         self.block_cfgs.push(block_cfg);
+        KRUN_SUCCESS
     }
 
     #[cfg(feature = "blk")]
@@ -174,6 +322,23 @@ impl ContextConfig {
         self.data_block_cfg = Some(block_cfg);
     }
 
+    #[cfg(feature = "blk")]
+    fn set_block_rate_limit(&mut self, block_id: &str, rate_limit: IoRateLimit) -> i32 {
+        match self
+            .block_cfgs
+            .iter_mut()
+            .chain(self.root_block_cfg.iter_mut())
+            .chain(self.data_block_cfg.iter_mut())
+            .find(|cfg| cfg.block_id == block_id)
+        {
+            Some(cfg) => {
+                cfg.rate_limit = Some(rate_limit);
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
     #[cfg(feature = "blk")]
     fn get_block_cfg(&self) -> Vec<BlockDeviceConfig> {
         // For backwards compat, when cfgs is empty (the new API is not used), this needs to be
@@ -199,6 +364,83 @@ impl ContextConfig {
         self.mac = Some(mac);
     }
 
+    #[cfg(feature = "net")]
+    fn set_net_mtu(&mut self, mtu: u16) {
+        self.mtu = Some(mtu);
+    }
+
+    #[cfg(feature = "net")]
+    fn add_net_iface(&mut self, backend: VirtioNetBackend) -> i32 {
+        let index = self.net_ifaces.len();
+        if index >= MAX_NET_IFACES {
+            return -libc::ENOSPC;
+        }
+
+        self.net_ifaces.push(NetworkInterfaceConfig {
+            iface_id: format!("net{index}"),
+            backend,
+            // Locally administered, distinct from the default MAC of the
+            // interface configured with krun_set_passt_fd/krun_set_gvproxy_path.
+            mac: [0x5a, 0x94, 0xef, 0xe4, 0x0d, index as u8],
+            rate_limit: None,
+            mtu: None,
+            vhost: false,
+        });
+        index as i32
+    }
+
+    #[cfg(feature = "net")]
+    fn set_net_iface_mac(&mut self, index: usize, mac: [u8; 6]) -> i32 {
+        match self.net_ifaces.get_mut(index) {
+            Some(iface) => {
+                iface.mac = mac;
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
+    #[cfg(feature = "net")]
+    fn set_net_iface_mtu(&mut self, index: usize, mtu: u16) -> i32 {
+        match self.net_ifaces.get_mut(index) {
+            Some(iface) => {
+                if mtu > iface.backend.max_mtu() {
+                    error!("MTU {mtu} is too big for the network backend");
+                    return -libc::EINVAL;
+                }
+                iface.mtu = Some(mtu);
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
+    #[cfg(all(feature = "net", target_os = "linux"))]
+    fn set_net_iface_vhost(&mut self, index: usize, enabled: bool) -> i32 {
+        match self.net_ifaces.get_mut(index) {
+            Some(iface) => {
+                if enabled && !matches!(iface.backend, VirtioNetBackend::Tap(_)) {
+                    error!("vhost-net needs an interface added with krun_add_net_tap");
+                    return -libc::EINVAL;
+                }
+                iface.vhost = enabled;
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
+    #[cfg(feature = "net")]
+    fn set_net_iface_rate_limit(&mut self, index: usize, rate_limit: IoRateLimit) -> i32 {
+        match self.net_ifaces.get_mut(index) {
+            Some(iface) => {
+                iface.rate_limit = Some(rate_limit);
+                KRUN_SUCCESS
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
     fn set_port_map(&mut self, new_port_map: HashMap<u16, u16>) -> Result<(), ()> {
         match &mut self.net_cfg {
             NetworkConfig::Tsi(tsi_config) => {
@@ -220,6 +462,12 @@ impl ContextConfig {
         self.tee_config_file.clone()
     }
 
+    /// Returns the TEE config being built through the `krun_set_tee_*` calls.
+    #[cfg(feature = "tee")]
+    fn tee_config_mut(&mut self) -> &mut TeeConfig {
+        self.tee_config.get_or_insert_with(TeeConfig::default)
+    }
+
     fn add_vsock_port(&mut self, port: u32, filepath: PathBuf, listen: bool) {
         if let Some(ref mut map) = &mut self.unix_ipc_port_map {
             map.insert(port, (filepath, listen));
@@ -230,6 +478,29 @@ impl ContextConfig {
         }
     }
 
+    fn add_vsock_ports(&mut self, ports: Vec<(u32, PathBuf)>, listen: bool) -> i32 {
+        let empty = HashMap::new();
+        let map = self.unix_ipc_port_map.as_ref().unwrap_or(&empty);
+
+        for (i, (port, path)) in ports.iter().enumerate() {
+            if map.contains_key(port) || ports[..i].iter().any(|(p, _)| p == port) {
+                return -libc::EEXIST;
+            }
+            if listen
+                && (map.values().any(|(p, _)| p == path)
+                    || ports[..i].iter().any(|(_, p)| p == path))
+            {
+                return -libc::EADDRINUSE;
+            }
+        }
+
+        let map = self.unix_ipc_port_map.get_or_insert_with(HashMap::new);
+        for (port, path) in ports {
+            map.insert(port, (path, listen));
+        }
+        KRUN_SUCCESS
+    }
+
     fn set_gpu_virgl_flags(&mut self, virgl_flags: u32) {
         self.gpu_virgl_flags = Some(virgl_flags);
     }
@@ -241,6 +512,24 @@ impl ContextConfig {
 
 static CTX_MAP: Lazy<Mutex<HashMap<u32, ContextConfig>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 static CTX_IDS: AtomicI32 = AtomicI32::new(0);
+// Host sockets of the vsock ports the guest listens on, for the contexts already
+// started, for krun_vsock_connect().
+static VSOCK_LISTEN_PATHS: Lazy<Mutex<HashMap<u32, HashMap<u32, PathBuf>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Cancellation tokens of the contexts being built, for krun_cancel_start().
+static BUILDING: Lazy<Mutex<HashMap<u32, CancelToken>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Balloon devices of the contexts already started, for krun_set_balloon_target().
+#[cfg(not(feature = "tee"))]
+static BALLOONS: Lazy<Mutex<HashMap<u32, Arc<Mutex<devices::virtio::Balloon>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Launch measurements of the TEE contexts already started, for krun_get_tee_measurement().
+#[cfg(feature = "amd-sev")]
+static TEE_MEASUREMENTS: Lazy<Mutex<HashMap<u32, vmm::LaunchMeasurement>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// VMMs of the TEE contexts already started, for krun_debug_*_guest_memory().
+#[cfg(feature = "amd-sev")]
+static TEE_VMMS: Lazy<Mutex<HashMap<u32, Arc<Mutex<vmm::Vmm>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 #[cfg(all(not(feature = "tee"), not(feature = "efi")))]
 #[link(name = "krunfw")]
@@ -266,20 +555,87 @@ extern "C" {
     fn krunfw_get_version() -> u32;
 }
 
-#[no_mangle]
-pub extern "C" fn krun_set_log_level(level: u32) -> i32 {
-    let log_level = match level {
+fn log_level_filter(level: u32) -> &'static str {
+    match level {
         0 => "off",
         1 => "error",
         2 => "warn",
         3 => "info",
         4 => "debug",
         _ => "trace",
-    };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_log_level(level: u32) -> i32 {
+    let log_level = log_level_filter(level);
     env_logger::Builder::from_env(Env::default().default_filter_or(log_level)).init();
     KRUN_SUCCESS
 }
 
+/// Collects the structured fields of a log record, redacting the sensitive ones.
+struct JsonLogFields(serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonLogFields {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = if is_sensitive(key.as_str()) {
+            "<redacted>".to_string()
+        } else {
+            value.to_string()
+        };
+        self.0
+            .insert(key.to_string(), serde_json::Value::String(value));
+        Ok(())
+    }
+}
+
+fn format_json_log(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let mut fields = JsonLogFields(serde_json::Map::new());
+    let _ = record.key_values().visit(&mut fields);
+
+    let line = serde_json::json!({
+        "timestamp": buf.timestamp_micros().to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "fields": fields.0,
+    });
+    writeln!(buf, "{line}")
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_init_log(level: u32, format: u32, c_filters: *const c_char) -> i32 {
+    let mut builder =
+        env_logger::Builder::from_env(Env::default().default_filter_or(log_level_filter(level)));
+
+    match format {
+        KRUN_LOG_FORMAT_TEXT => {}
+        KRUN_LOG_FORMAT_JSON => {
+            builder.format(format_json_log);
+        }
+        _ => return -libc::EINVAL,
+    }
+
+    if !c_filters.is_null() {
+        match CStr::from_ptr(c_filters).to_str() {
+            Ok(filters) => {
+                builder.parse_filters(filters);
+            }
+            Err(_) => return -libc::EINVAL,
+        }
+    }
+
+    match builder.try_init() {
+        Ok(()) => KRUN_SUCCESS,
+        Err(_) => -libc::EEXIST,
+    }
+}
+
 #[no_mangle]
 #[cfg(not(feature = "efi"))]
 pub extern "C" fn krun_create_ctx() -> i32 {
@@ -310,6 +666,11 @@ pub extern "C" fn krun_create_ctx() -> i32 {
     };
     ctx_cfg.vmr.set_kernel_bundle(kernel_bundle).unwrap();
 
+    #[cfg(target_arch = "x86_64")]
+    {
+        ctx_cfg.shutdown_efd = Some(EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap());
+    }
+
     #[cfg(feature = "tee")]
     {
         let mut qboot_size: usize = 0;
@@ -380,6 +741,7 @@ pub extern "C" fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -
         mem_size_mib: Some(mem_size_mib),
         ht_enabled: Some(false),
         cpu_template: None,
+        cpu_topology: None,
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
@@ -394,6 +756,239 @@ pub extern "C" fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -
     KRUN_SUCCESS
 }
 
+#[no_mangle]
+pub extern "C" fn krun_set_cpu_topology(
+    ctx_id: u32,
+    sockets: u8,
+    cores_per_socket: u8,
+    threads_per_core: u8,
+) -> i32 {
+    if cfg!(not(target_arch = "x86_64")) {
+        return -libc::ENOTSUP;
+    }
+
+    let vm_config = VmConfig {
+        vcpu_count: None,
+        mem_size_mib: None,
+        ht_enabled: None,
+        cpu_template: None,
+        cpu_topology: Some(CpuTopology {
+            sockets,
+            cores_per_socket,
+            threads_per_core,
+        }),
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            if let Err(e) = ctx_cfg.get_mut().vmr.set_vm_config(&vm_config) {
+                warn!("Error setting the CPU topology: {e}");
+                return -libc::EINVAL;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_cpu_template(ctx_id: u32, c_template: *const c_char) -> i32 {
+    if cfg!(not(target_arch = "x86_64")) {
+        return -libc::ENOTSUP;
+    }
+
+    let cpu_template = match CStr::from_ptr(c_template)
+        .to_str()
+        .ok()
+        .and_then(CpuFeaturesTemplate::from_name)
+    {
+        Some(cpu_template) => cpu_template,
+        None => return -libc::EINVAL,
+    };
+
+    let vm_config = VmConfig {
+        vcpu_count: None,
+        mem_size_mib: None,
+        ht_enabled: None,
+        cpu_template: Some(cpu_template),
+        cpu_topology: None,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            if let Err(e) = ctx_cfg.get_mut().vmr.set_vm_config(&vm_config) {
+                warn!("Error setting the CPU template: {e}");
+                return -libc::EINVAL;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_hugepages(
+    ctx_id: u32,
+    page_size_kib: u32,
+    c_hugetlbfs_path: *const c_char,
+) -> i32 {
+    if cfg!(not(target_os = "linux")) {
+        return -libc::ENOTSUP;
+    }
+
+    let page_size = match HugePageSize::from_kib(page_size_kib) {
+        Some(page_size) => page_size,
+        None => return -libc::EINVAL,
+    };
+
+    let hugetlbfs_path = if c_hugetlbfs_path.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(c_hugetlbfs_path).to_str() {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => return -libc::EINVAL,
+        }
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => match ctx_cfg.get_mut().vmr.set_hugepages(HugePagesConfig {
+            page_size,
+            hugetlbfs_path,
+        }) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                error!("Cannot use the hugetlbfs path: {e}");
+                -e.raw_os_error().unwrap_or(libc::EINVAL)
+            }
+        },
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_shutdown_timeout(ctx_id: u32, timeout_ms: u32) -> i32 {
+    if cfg!(not(target_arch = "x86_64")) {
+        return -libc::ENOTSUP;
+    }
+
+    if timeout_ms == 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.shutdown_timeout = Some(Duration::from_millis(timeout_ms.into()));
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(target_arch = "x86_64")]
+pub extern "C" fn krun_set_watchdog(ctx_id: u32, timeout_secs: u32, action: u32) -> i32 {
+    if !(1..=30).contains(&timeout_secs) {
+        return -libc::EINVAL;
+    }
+
+    let action = match action {
+        0 => WatchdogAction::Reset,
+        1 => WatchdogAction::PowerOff,
+        2 => match EventFd::new(utils::eventfd::EFD_NONBLOCK) {
+            Ok(evt) => WatchdogAction::Notify(evt),
+            Err(e) => return -e.raw_os_error().unwrap_or(libc::EINVAL),
+        },
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.vmr.watchdog.is_some() {
+                return -libc::EEXIST;
+            }
+
+            let ret = match action {
+                WatchdogAction::Notify(ref evt) => evt.as_raw_fd(),
+                _ => KRUN_SUCCESS,
+            };
+            cfg.vmr.watchdog = Some(WatchdogConfig {
+                timeout_secs,
+                action,
+            });
+            ret
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[no_mangle]
+pub extern "C" fn krun_set_seccomp_mode(ctx_id: u32, mode: u32) -> i32 {
+    let action = match mode {
+        0 => SeccompAction::Disabled,
+        1 => SeccompAction::Log,
+        2 => SeccompAction::Trap,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.seccomp_action = action;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_seccomp_filter(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => match ctx_cfg.get_mut().vmr.set_seccomp_filter(&path) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                error!("Error loading the seccomp filter: {e}");
+                -libc::EINVAL
+            }
+        },
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[no_mangle]
+pub extern "C" fn krun_set_landlock(ctx_id: u32, enable: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.landlock = enable;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[cfg(not(feature = "tee"))]
+fn fs_config_errno(e: FsConfigError) -> i32 {
+    match e {
+        FsConfigError::DuplicateTag(_) => -libc::EEXIST,
+        FsConfigError::InvalidTag(_)
+        | FsConfigError::InvalidShmSize
+        | FsConfigError::InvalidIdMapping(_) => -libc::EINVAL,
+        FsConfigError::UnknownTag(_) => -libc::ENOENT,
+    }
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 #[cfg(not(feature = "tee"))]
@@ -409,12 +1004,18 @@ pub unsafe extern "C" fn krun_set_root(ctx_id: u32, c_root_path: *const c_char)
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.vmr.add_fs_device(FsDeviceConfig {
+            if let Err(e) = cfg.vmr.add_fs_device(FsDeviceConfig {
                 fs_id,
                 shared_dir,
                 // Default to a conservative 512 MB window.
                 shm_size: Some(1 << 29),
-            });
+                read_only: false,
+                uid_map: IdMap::default(),
+                gid_map: IdMap::default(),
+            }) {
+                error!("Error configuring the root filesystem: {}", e);
+                return fs_config_errno(e);
+            }
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -442,11 +1043,17 @@ pub unsafe extern "C" fn krun_add_virtiofs(
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.vmr.add_fs_device(FsDeviceConfig {
+            if let Err(e) = cfg.vmr.add_fs_device(FsDeviceConfig {
                 fs_id: tag.to_string(),
                 shared_dir: path.to_string(),
                 shm_size: None,
-            });
+                read_only: false,
+                uid_map: IdMap::default(),
+                gid_map: IdMap::default(),
+            }) {
+                error!("Error adding virtio-fs device: {}", e);
+                return fs_config_errno(e);
+            }
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -475,11 +1082,17 @@ pub unsafe extern "C" fn krun_add_virtiofs2(
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.vmr.add_fs_device(FsDeviceConfig {
+            if let Err(e) = cfg.vmr.add_fs_device(FsDeviceConfig {
                 fs_id: tag.to_string(),
                 shared_dir: path.to_string(),
                 shm_size: Some(shm_size.try_into().unwrap()),
-            });
+                read_only: false,
+                uid_map: IdMap::default(),
+                gid_map: IdMap::default(),
+            }) {
+                error!("Error adding virtio-fs device: {}", e);
+                return fs_config_errno(e);
+            }
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -490,9 +1103,114 @@ pub unsafe extern "C" fn krun_add_virtiofs2(
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 #[cfg(not(feature = "tee"))]
-pub unsafe extern "C" fn krun_set_mapped_volumes(
-    _ctx_id: u32,
-    _c_mapped_volumes: *const *const c_char,
+pub unsafe extern "C" fn krun_add_virtiofs3(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_path: *const c_char,
+    shm_size: u64,
+    read_only: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if let Err(e) = cfg.vmr.add_fs_device(FsDeviceConfig {
+                fs_id: tag.to_string(),
+                shared_dir: path.to_string(),
+                shm_size: Some(shm_size.try_into().unwrap()),
+                read_only,
+                uid_map: IdMap::default(),
+                gid_map: IdMap::default(),
+            }) {
+                error!("Error adding virtio-fs device: {}", e);
+                return fs_config_errno(e);
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_add_virtiofs_uid_map(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    guest_uid: u32,
+    host_uid: u32,
+    count: u32,
+) -> i32 {
+    add_virtiofs_id_map(ctx_id, c_tag, guest_uid, host_uid, count, false)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_add_virtiofs_gid_map(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    guest_gid: u32,
+    host_gid: u32,
+    count: u32,
+) -> i32 {
+    add_virtiofs_id_map(ctx_id, c_tag, guest_gid, host_gid, count, true)
+}
+
+#[cfg(not(feature = "tee"))]
+unsafe fn add_virtiofs_id_map(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    guest_id: u32,
+    host_id: u32,
+    count: u32,
+    gid: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let range = IdRange {
+        guest_id,
+        host_id,
+        count,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let vmr = &mut ctx_cfg.get_mut().vmr;
+            let result = if gid {
+                vmr.add_fs_gid_mapping(tag, range)
+            } else {
+                vmr.add_fs_uid_mapping(tag, range)
+            };
+            match result {
+                Ok(()) => KRUN_SUCCESS,
+                Err(e) => {
+                    error!("Error mapping virtio-fs IDs: {}", e);
+                    fs_config_errno(e)
+                }
+            }
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_mapped_volumes(
+    _ctx_id: u32,
+    _c_mapped_volumes: *const *const c_char,
 ) -> i32 {
     -libc::EINVAL
 }
@@ -525,13 +1243,12 @@ pub unsafe extern "C" fn krun_add_disk(
                 disk_image_path: disk_path.to_string(),
                 disk_image_format: ImageType::Raw,
                 is_disk_read_only: read_only,
+                rate_limit: None,
             };
-            cfg.add_block_cfg(block_device_config);
+            cfg.add_block_cfg(block_device_config)
         }
-        Entry::Vacant(_) => return -libc::ENOENT,
+        Entry::Vacant(_) => -libc::ENOENT,
     }
-
-    KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
@@ -572,13 +1289,108 @@ pub unsafe extern "C" fn krun_add_disk2(
                 disk_image_path: disk_path.to_string(),
                 disk_image_format: format,
                 is_disk_read_only: read_only,
+                rate_limit: None,
             };
-            cfg.add_block_cfg(block_device_config);
+            cfg.add_block_cfg(block_device_config)
         }
-        Entry::Vacant(_) => return -libc::ENOENT,
+        Entry::Vacant(_) => -libc::ENOENT,
     }
+}
 
-    KRUN_SUCCESS
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_initrd(ctx_id: u32, c_initrd_path: *const c_char) -> i32 {
+    let initrd_path = match CStr::from_ptr(c_initrd_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => match ctx_cfg.get_mut().vmr.set_initrd_path(initrd_path) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                error!("Cannot read the initrd image: {e}");
+                -e.raw_os_error().unwrap_or(libc::EINVAL)
+            }
+        },
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub unsafe extern "C" fn krun_set_firmware(
+    ctx_id: u32,
+    c_firmware_path: *const c_char,
+    c_varstore_path: *const c_char,
+) -> i32 {
+    let firmware_path = match CStr::from_ptr(c_firmware_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+    let varstore_path = if c_varstore_path.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(c_varstore_path).to_str() {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => return -libc::EINVAL,
+        }
+    };
+
+    let firmware = match FirmwareConfig::new(firmware_path, varstore_path) {
+        Ok(firmware) => firmware,
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            error!("A firmware varstore can't be used in a TEE");
+            return -libc::ENOTSUP;
+        }
+        Err(e) => {
+            error!("Cannot use the firmware image or varstore: {e}");
+            return -e.raw_os_error().unwrap_or(libc::EINVAL);
+        }
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.set_firmware(firmware);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub extern "C" fn krun_set_clocksource(ctx_id: u32, clocksource: u32) -> i32 {
+    let clocksource = match clocksource {
+        0 => Clocksource::KvmClock,
+        1 => Clocksource::Tsc,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.clocksource = Some(clocksource);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub extern "C" fn krun_set_tsc_khz(ctx_id: u32, tsc_khz: u32) -> i32 {
+    if tsc_khz == 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.tsc_khz = Some(tsc_khz);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
 }
 
 #[allow(clippy::missing_safety_doc)]
@@ -599,6 +1411,7 @@ pub unsafe extern "C" fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_c
                 disk_image_path: disk_path.to_string(),
                 disk_image_format: ImageType::Raw,
                 is_disk_read_only: false,
+                rate_limit: None,
             };
             cfg.set_root_block_cfg(block_device_config);
         }
@@ -626,6 +1439,7 @@ pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_c
                 disk_image_path: disk_path.to_string(),
                 disk_image_format: ImageType::Raw,
                 is_disk_read_only: false,
+                rate_limit: None,
             };
             cfg.set_data_block_cfg(block_device_config);
         }
@@ -635,6 +1449,33 @@ pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_c
     KRUN_SUCCESS
 }
 
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_set_disk_rate_limit(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    bytes_per_sec: u64,
+    ops_per_sec: u64,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let rate_limit = IoRateLimit {
+        bytes_per_sec,
+        ops_per_sec,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().set_block_rate_limit(block_id, rate_limit)
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_passt_fd(ctx_id: u32, fd: c_int) -> i32 {
@@ -701,6 +1542,145 @@ pub unsafe extern "C" fn krun_set_net_mac(ctx_id: u32, c_mac: *const u8) -> i32
     KRUN_SUCCESS
 }
 
+#[no_mangle]
+#[cfg(feature = "net")]
+pub extern "C" fn krun_set_net_mtu(ctx_id: u32, mtu: u16) -> i32 {
+    if mtu < MIN_MTU {
+        error!("MTU {mtu} is too small");
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().set_net_mtu(mtu);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_add_net_passt(ctx_id: u32, fd: c_int) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().add_net_iface(VirtioNetBackend::Passt(fd))
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_add_net_gvproxy(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(e) => {
+            debug!("Error parsing gvproxy_path: {:?}", e);
+            return -libc::EINVAL;
+        }
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg
+            .get_mut()
+            .add_net_iface(VirtioNetBackend::Gvproxy(path)),
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(all(feature = "net", target_os = "linux"))]
+pub unsafe extern "C" fn krun_add_net_tap(ctx_id: u32, c_tap_name: *const c_char) -> i32 {
+    let tap_name = match CStr::from_ptr(c_tap_name).to_str() {
+        Ok(name) if !name.is_empty() && name.len() < libc::IFNAMSIZ => name.to_string(),
+        _ => {
+            error!("Invalid tap device name");
+            return -libc::EINVAL;
+        }
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg
+            .get_mut()
+            .add_net_iface(VirtioNetBackend::Tap(tap_name)),
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_net_iface_mac(ctx_id: u32, index: u32, c_mac: *const u8) -> i32 {
+    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
+        Ok(m) => m,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    // Multicast addresses can't be assigned to an interface.
+    if mac[0] & 1 != 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().set_net_iface_mac(index as usize, mac),
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(feature = "net")]
+pub extern "C" fn krun_set_net_iface_mtu(ctx_id: u32, index: u32, mtu: u16) -> i32 {
+    if mtu < MIN_MTU {
+        error!("MTU {mtu} is too small");
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().set_net_iface_mtu(index as usize, mtu),
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(all(feature = "net", target_os = "linux"))]
+pub extern "C" fn krun_set_net_iface_vhost(ctx_id: u32, index: u32, enable: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg
+            .get_mut()
+            .set_net_iface_vhost(index as usize, enable),
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(feature = "net")]
+pub extern "C" fn krun_set_net_iface_rate_limit(
+    ctx_id: u32,
+    index: u32,
+    bytes_per_sec: u64,
+    ops_per_sec: u64,
+) -> i32 {
+    let rate_limit = IoRateLimit {
+        bytes_per_sec,
+        ops_per_sec,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg
+            .get_mut()
+            .set_net_iface_rate_limit(index as usize, rate_limit),
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *const c_char) -> i32 {
@@ -788,15 +1768,23 @@ pub unsafe extern "C" fn krun_set_rlimits(ctx_id: u32, c_rlimits: *const *const
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_char) -> i32 {
-    let workdir_path = match CStr::from_ptr(c_workdir_path).to_str() {
-        Ok(workdir) => workdir,
-        Err(_) => return -libc::EINVAL,
+pub unsafe extern "C" fn krun_set_host_rlimit(
+    ctx_id: u32,
+    resource: u32,
+    cur: u64,
+    max: u64,
+) -> i32 {
+    let rlimit = match HostRlimit::new(resource, cur, max) {
+        Ok(rlimit) => rlimit,
+        Err(e) => {
+            error!("Invalid host rlimit: {e}");
+            return -libc::EINVAL;
+        }
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().set_workdir(workdir_path.to_string());
+            ctx_cfg.get_mut().vmr.set_host_rlimit(rlimit);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -804,7 +1792,120 @@ pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_
     KRUN_SUCCESS
 }
 
-unsafe fn collapse_str_array(array: &[*const c_char]) -> Result<String, std::str::Utf8Error> {
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(target_os = "linux")]
+pub unsafe extern "C" fn krun_set_vcpu_affinity(
+    ctx_id: u32,
+    vcpu: u8,
+    cpus: *const u32,
+    num_cpus: libc::size_t,
+) -> i32 {
+    if cpus.is_null() {
+        return -libc::EINVAL;
+    }
+    let cpus = slice::from_raw_parts(cpus, num_cpus)
+        .iter()
+        .map(|&cpu| cpu as usize)
+        .collect();
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            if let Err(e) = ctx_cfg.get_mut().vmr.set_vcpu_affinity(vcpu, cpus) {
+                error!("Invalid affinity for vCPU {vcpu}: {e}");
+                return -libc::EINVAL;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+pub unsafe extern "C" fn krun_add_numa_node(
+    ctx_id: u32,
+    mem_size_mib: u32,
+    vcpus: *const u8,
+    num_vcpus: libc::size_t,
+    host_node: i32,
+) -> i32 {
+    if vcpus.is_null() {
+        return -libc::EINVAL;
+    }
+    let vcpus = slice::from_raw_parts(vcpus, num_vcpus).to_vec();
+    let host_node = u32::try_from(host_node).ok();
+
+    let node = match NumaNodeConfig::new(mem_size_mib as usize, vcpus, host_node) {
+        Ok(node) => node,
+        Err(e) => {
+            error!("Invalid NUMA node: {e}");
+            return -libc::EINVAL;
+        }
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().vmr.add_numa_node(node),
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+pub unsafe extern "C" fn krun_set_numa_distances(
+    ctx_id: u32,
+    distances: *const u8,
+    num_nodes: libc::size_t,
+) -> i32 {
+    if distances.is_null() {
+        return -libc::EINVAL;
+    }
+    let distances = match num_nodes.checked_mul(num_nodes) {
+        Some(len) => slice::from_raw_parts(distances, len).to_vec(),
+        None => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            if let Err(e) = ctx_cfg
+                .get_mut()
+                .vmr
+                .set_numa_distances(distances, num_nodes)
+            {
+                error!("Invalid NUMA distances: {e}");
+                return -libc::EINVAL;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_char) -> i32 {
+    let workdir_path = match CStr::from_ptr(c_workdir_path).to_str() {
+        Ok(workdir) => workdir,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().set_workdir(workdir_path.to_string());
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+unsafe fn collect_str_array(array: &[*const c_char]) -> Result<Vec<String>, std::str::Utf8Error> {
     let mut strvec = Vec::new();
 
     for item in array.iter().take(MAX_ARGS) {
@@ -812,14 +1913,27 @@ unsafe fn collapse_str_array(array: &[*const c_char]) -> Result<String, std::str
             break;
         } else {
             let s = CStr::from_ptr(*item).to_str()?;
-            strvec.push(format!("\"{s}\""));
+            strvec.push(s.to_string());
         }
     }
 
-    Ok(strvec.join(" "))
+    Ok(strvec)
+}
+
+fn quote_str_array(array: &[String]) -> String {
+    array
+        .iter()
+        .map(|s| format!("\"{s}\""))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn host_env() -> Vec<String> {
+    env::vars()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect()
 }
 
-#[allow(clippy::format_collect)]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_exec(
@@ -838,7 +1952,7 @@ pub unsafe extern "C" fn krun_set_exec(
 
     let args = if !c_argv.is_null() {
         let argv_array: &[*const c_char] = slice::from_raw_parts(c_argv, MAX_ARGS);
-        match collapse_str_array(argv_array) {
+        match collect_str_array(argv_array) {
             Ok(s) => s,
             Err(e) => {
                 debug!("Error parsing args: {:?}", e);
@@ -846,12 +1960,12 @@ pub unsafe extern "C" fn krun_set_exec(
             }
         }
     } else {
-        "".to_string()
+        Vec::new()
     };
 
     let env = if !c_envp.is_null() {
         let envp_array: &[*const c_char] = slice::from_raw_parts(c_envp, MAX_ARGS);
-        match collapse_str_array(envp_array) {
+        match collect_str_array(envp_array) {
             Ok(s) => s,
             Err(e) => {
                 debug!("Error parsing args: {:?}", e);
@@ -859,9 +1973,7 @@ pub unsafe extern "C" fn krun_set_exec(
             }
         }
     } else {
-        env::vars()
-            .map(|(key, value)| format!(" {key}=\"{value}\""))
-            .collect()
+        host_env()
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
@@ -877,13 +1989,12 @@ pub unsafe extern "C" fn krun_set_exec(
     KRUN_SUCCESS
 }
 
-#[allow(clippy::format_collect)]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_env(ctx_id: u32, c_envp: *const *const c_char) -> i32 {
     let env = if !c_envp.is_null() {
         let envp_array: &[*const c_char] = slice::from_raw_parts(c_envp, MAX_ARGS);
-        match collapse_str_array(envp_array) {
+        match collect_str_array(envp_array) {
             Ok(s) => s,
             Err(e) => {
                 debug!("Error parsing args: {:?}", e);
@@ -891,9 +2002,7 @@ pub unsafe extern "C" fn krun_set_env(ctx_id: u32, c_envp: *const *const c_char)
             }
         }
     } else {
-        env::vars()
-            .map(|(key, value)| format!(" {key}=\"{value}\""))
-            .collect()
+        host_env()
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
@@ -907,6 +2016,106 @@ pub unsafe extern "C" fn krun_set_env(ctx_id: u32, c_envp: *const *const c_char)
     KRUN_SUCCESS
 }
 
+/// Checks the hostname is made of RFC 1123 labels, which also keeps it from
+/// breaking the kernel command line it's passed on.
+fn valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= MAX_HOSTNAME_LEN
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_hostname(ctx_id: u32, c_hostname: *const c_char) -> i32 {
+    if c_hostname.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let hostname = match CStr::from_ptr(c_hostname).to_str() {
+        Ok(hostname) if valid_hostname(hostname) => hostname,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().set_hostname(hostname.to_string());
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_dns_servers(ctx_id: u32, c_servers: *const *const c_char) -> i32 {
+    if c_servers.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let servers_array: &[*const c_char] = slice::from_raw_parts(c_servers, MAX_ARGS);
+    let servers = match collect_str_array(servers_array) {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("Error parsing DNS servers: {:?}", e);
+            return -libc::EINVAL;
+        }
+    };
+    if servers.len() > MAX_DNS_SERVERS {
+        return -libc::EINVAL;
+    }
+
+    let mut dns_servers = Vec::new();
+    for server in servers {
+        match server.parse::<IpAddr>() {
+            Ok(addr) => dns_servers.push(addr),
+            Err(e) => {
+                debug!("Invalid DNS server {server}: {e}");
+                return -libc::EINVAL;
+            }
+        }
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().set_dns_servers(dns_servers);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_append_kernel_cmdline(ctx_id: u32, c_params: *const c_char) -> i32 {
+    let params = match CStr::from_ptr(c_params).to_str() {
+        Ok(params) => params,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    // Everything past "--" is handed to the executable instead of the kernel.
+    if params.split_whitespace().any(|param| param == "--") {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().append_kernel_cmdline(params);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 #[cfg(feature = "tee")]
@@ -927,6 +2136,262 @@ pub unsafe extern "C" fn krun_set_tee_config_file(ctx_id: u32, c_filepath: *cons
     KRUN_SUCCESS
 }
 
+#[no_mangle]
+#[cfg(feature = "tee")]
+pub extern "C" fn krun_set_tee_type(ctx_id: u32, tee_type: u32) -> i32 {
+    let tee = match tee_type {
+        0 => Tee::Sev,
+        1 => Tee::Snp,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().tee_config_mut().tee = tee;
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "tee")]
+pub unsafe extern "C" fn krun_set_tee_attestation_url(ctx_id: u32, c_url: *const c_char) -> i32 {
+    let url = match CStr::from_ptr(c_url).to_str() {
+        Ok(u) => u,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().tee_config_mut().attestation_url = url.to_string();
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "tee")]
+pub unsafe extern "C" fn krun_set_tee_workload_id(
+    ctx_id: u32,
+    c_workload_id: *const c_char,
+) -> i32 {
+    let workload_id = match CStr::from_ptr(c_workload_id).to_str() {
+        Ok(w) => w,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().tee_config_mut().workload_id = workload_id.to_string();
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "tee")]
+pub unsafe extern "C" fn krun_set_tee_data(ctx_id: u32, c_tee_data: *const c_char) -> i32 {
+    let tee_data = match CStr::from_ptr(c_tee_data).to_str() {
+        Ok(t) => t,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().tee_config_mut().tee_data = tee_data.to_string();
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "amd-sev")]
+pub unsafe extern "C" fn krun_set_tee_vendor_chain(
+    ctx_id: u32,
+    chain: *const u8,
+    chain_len: size_t,
+) -> i32 {
+    if chain.is_null() || chain_len == 0 {
+        return -libc::EINVAL;
+    }
+    let chain = slice::from_raw_parts(chain, chain_len);
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().tee_vendor_chain = Some(chain.to_vec());
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "amd-sev")]
+type TeeProgressCallback =
+    unsafe extern "C" fn(phase: u32, elapsed_us: u64, user_data: *mut c_void);
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "amd-sev")]
+pub unsafe extern "C" fn krun_set_tee_progress_callback(
+    ctx_id: u32,
+    callback: Option<TeeProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            // The pointer is only handed back to the embedder's callback.
+            ctx_cfg.get_mut().tee_progress_callback = callback.map(|cb| (cb, user_data as usize));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// Forwards a launch phase to the C callback, as one of the KRUN_TEE_PHASE_* codes.
+#[cfg(feature = "amd-sev")]
+fn report_tee_phase(callback: TeeProgressCallback, user_data: usize, phase: vmm::Phase) {
+    let (code, elapsed) = match phase {
+        vmm::Phase::ChainFetched(elapsed) => (0, elapsed),
+        vmm::Phase::SessionEstablished(elapsed) => (1, elapsed),
+        vmm::Phase::RegionsMeasured(elapsed) => (2, elapsed),
+        vmm::Phase::MeasurementComputed(elapsed) => (3, elapsed),
+        vmm::Phase::SecretInjected(elapsed) => (4, elapsed),
+        vmm::Phase::Finished(elapsed) => (5, elapsed),
+    };
+    unsafe { callback(code, elapsed.as_micros() as u64, user_data as *mut c_void) };
+}
+
+/// Writes `bytes` into `buf` as a null-terminated lowercase hex string, if it fits in `len` bytes.
+#[cfg(feature = "amd-sev")]
+unsafe fn write_hex(bytes: &[u8], buf: *mut c_char, len: size_t) -> bool {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    if hex.len() >= len {
+        return false;
+    }
+
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, hex.len() + 1);
+    buf[..hex.len()].copy_from_slice(hex.as_bytes());
+    buf[hex.len()] = 0;
+    true
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "amd-sev")]
+pub unsafe extern "C" fn krun_get_tee_measurement(
+    ctx_id: u32,
+    c_measurement: *mut c_char,
+    measurement_len: size_t,
+    c_nonce: *mut c_char,
+    nonce_len: size_t,
+) -> i32 {
+    if c_measurement.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let measurements = TEE_MEASUREMENTS.lock().unwrap();
+    let launch_measurement = match measurements.get(&ctx_id) {
+        Some(m) => m,
+        None => return -libc::ENOENT,
+    };
+
+    if !write_hex(
+        &launch_measurement.measurement,
+        c_measurement,
+        measurement_len,
+    ) {
+        return -libc::ERANGE;
+    }
+
+    if !c_nonce.is_null() && !write_hex(&launch_measurement.nonce, c_nonce, nonce_len) {
+        return -libc::ERANGE;
+    }
+
+    KRUN_SUCCESS
+}
+
+/// Checks a guest memory range is suitable for the SEV debug commands, which
+/// work on 16-byte blocks.
+#[cfg(feature = "amd-sev")]
+fn valid_debug_range(guest_addr: u64, len: size_t) -> bool {
+    len > 0 && guest_addr.is_multiple_of(16) && len.is_multiple_of(16)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "amd-sev")]
+pub unsafe extern "C" fn krun_debug_read_guest_memory(
+    ctx_id: u32,
+    guest_addr: u64,
+    c_buf: *mut u8,
+    len: size_t,
+) -> i32 {
+    if c_buf.is_null() || !valid_debug_range(guest_addr, len) {
+        return -libc::EINVAL;
+    }
+
+    let vmm = match TEE_VMMS.lock().unwrap().get(&ctx_id) {
+        Some(vmm) => vmm.clone(),
+        None => return -libc::ENOENT,
+    };
+
+    let buf = slice::from_raw_parts_mut(c_buf, len);
+    let result = vmm.lock().unwrap().debug_read_guest_memory(guest_addr, buf);
+    match result {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            error!("Cannot read the guest memory: {e}");
+            -libc::EPERM
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "amd-sev")]
+pub unsafe extern "C" fn krun_debug_write_guest_memory(
+    ctx_id: u32,
+    guest_addr: u64,
+    c_buf: *const u8,
+    len: size_t,
+) -> i32 {
+    if c_buf.is_null() || !valid_debug_range(guest_addr, len) {
+        return -libc::EINVAL;
+    }
+
+    let vmm = match TEE_VMMS.lock().unwrap().get(&ctx_id) {
+        Some(vmm) => vmm.clone(),
+        None => return -libc::ENOENT,
+    };
+
+    let buf = slice::from_raw_parts(c_buf, len);
+    let result = vmm
+        .lock()
+        .unwrap()
+        .debug_write_guest_memory(guest_addr, buf);
+    match result {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            error!("Cannot write the guest memory: {e}");
+            -libc::EPERM
+        }
+    }
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_add_vsock_port(
@@ -961,7 +2426,196 @@ pub unsafe extern "C" fn krun_add_vsock_port2(
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.add_vsock_port(port, filepath, listen);
+            cfg.add_vsock_port(port, filepath, listen);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+// Balloon pages are always 4 KiB.
+#[cfg(not(feature = "tee"))]
+const BALLOON_PAGES_PER_MIB: u32 = 256;
+
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub extern "C" fn krun_set_balloon_target(ctx_id: u32, target_mib: u32) -> i32 {
+    let pages = match target_mib.checked_mul(BALLOON_PAGES_PER_MIB) {
+        Some(pages) => pages,
+        None => return -libc::EINVAL,
+    };
+
+    match BALLOONS.lock().unwrap().get(&ctx_id) {
+        Some(balloon) => match balloon.lock().unwrap().set_target_pages(pages) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                error!("Failed to notify the balloon target: {:?}", e);
+                -libc::EIO
+            }
+        },
+        None => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_metrics(c_buf: *mut c_char, buf_len: size_t) -> i32 {
+    let snapshot = METRICS.snapshot();
+    // Leave room for the terminating NUL.
+    if c_buf.is_null() || snapshot.len() >= buf_len {
+        return -libc::ENOSPC;
+    }
+
+    let buf = slice::from_raw_parts_mut(c_buf as *mut u8, buf_len);
+    buf[..snapshot.len()].copy_from_slice(snapshot.as_bytes());
+    buf[snapshot.len()] = 0;
+    snapshot.len() as i32
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_get_balloon_size(
+    ctx_id: u32,
+    actual_mib: *mut u32,
+    target_mib: *mut u32,
+) -> i32 {
+    match BALLOONS.lock().unwrap().get(&ctx_id) {
+        Some(balloon) => {
+            let balloon = balloon.lock().unwrap();
+            if !actual_mib.is_null() {
+                *actual_mib = balloon.actual_pages() / BALLOON_PAGES_PER_MIB;
+            }
+            if !target_mib.is_null() {
+                *target_mib = balloon.target_pages() / BALLOON_PAGES_PER_MIB;
+            }
+            KRUN_SUCCESS
+        }
+        None => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_get_balloon_stats(
+    ctx_id: u32,
+    c_stats: *mut u64,
+    nstats: size_t,
+    timestamp_ms: *mut u64,
+) -> i32 {
+    if c_stats.is_null() && nstats > 0 {
+        return -libc::EINVAL;
+    }
+
+    let balloon = match BALLOONS.lock().unwrap().get(&ctx_id) {
+        Some(balloon) => balloon.clone(),
+        None => return -libc::ENOENT,
+    };
+    let mut balloon = balloon.lock().unwrap();
+
+    let ret = match balloon.stats() {
+        Some(sample) => {
+            if nstats > 0 {
+                let stats = slice::from_raw_parts_mut(c_stats, nstats);
+                for (stat, value) in stats.iter_mut().zip(sample.values.iter()) {
+                    *stat = value.unwrap_or(u64::MAX);
+                }
+            }
+            if !timestamp_ms.is_null() {
+                *timestamp_ms = sample
+                    .timestamp
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0);
+            }
+            KRUN_SUCCESS
+        }
+        None => -libc::EAGAIN,
+    };
+
+    // Ask for a fresh sample for the next call.
+    if let Err(e) = balloon.request_stats() {
+        error!("Failed to request balloon statistics: {:?}", e);
+    }
+
+    ret
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_vsock_ports(
+    ctx_id: u32,
+    c_ports: *const u32,
+    c_filepaths: *const *const c_char,
+    count: size_t,
+    listen: bool,
+) -> i32 {
+    if count == 0 {
+        return KRUN_SUCCESS;
+    }
+    if c_ports.is_null() || c_filepaths.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let ports = slice::from_raw_parts(c_ports, count);
+    let c_filepaths = slice::from_raw_parts(c_filepaths, count);
+
+    let mut mappings = Vec::with_capacity(count);
+    for (port, c_filepath) in ports.iter().zip(c_filepaths) {
+        if c_filepath.is_null() {
+            return -libc::EINVAL;
+        }
+        let filepath = match CStr::from_ptr(*c_filepath).to_str() {
+            Ok(f) => PathBuf::from(f),
+            Err(_) => return -libc::EINVAL,
+        };
+
+        if listen {
+            match filepath.try_exists() {
+                Ok(true) => return -libc::EADDRINUSE,
+                Err(_) => return -libc::EINVAL,
+                _ => {}
+            }
+        }
+
+        mappings.push((*port, filepath));
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().add_vsock_ports(mappings, listen),
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_vsock_connect(ctx_id: u32, port: u32) -> i32 {
+    let path = match VSOCK_LISTEN_PATHS
+        .lock()
+        .unwrap()
+        .get(&ctx_id)
+        .and_then(|ports| ports.get(&port))
+    {
+        Some(path) => path.clone(),
+        None => return -libc::ENOENT,
+    };
+
+    // The host end of the port forwards the connection into the guest, with
+    // the vsock credit flow applying backpressure to the stream.
+    match UnixStream::connect(&path) {
+        Ok(stream) => stream.into_raw_fd(),
+        Err(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_gpu_options(ctx_id: u32, virgl_flags: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_gpu_virgl_flags(virgl_flags);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -971,11 +2625,16 @@ pub unsafe extern "C" fn krun_add_vsock_port2(
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_gpu_options(ctx_id: u32, virgl_flags: u32) -> i32 {
+pub unsafe extern "C" fn krun_set_gpu_options2(
+    ctx_id: u32,
+    virgl_flags: u32,
+    shm_size: u64,
+) -> i32 {
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
             cfg.set_gpu_virgl_flags(virgl_flags);
+            cfg.set_gpu_shm_size(shm_size.try_into().unwrap());
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -985,16 +2644,19 @@ pub unsafe extern "C" fn krun_set_gpu_options(ctx_id: u32, virgl_flags: u32) ->
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_gpu_options2(
+pub unsafe extern "C" fn krun_set_gpu_render_node(
     ctx_id: u32,
-    virgl_flags: u32,
-    shm_size: u64,
+    c_render_node: *const c_char,
 ) -> i32 {
+    let render_node = match CStr::from_ptr(c_render_node).to_str() {
+        Ok(render_node) => render_node,
+        Err(_) => return -libc::EINVAL,
+    };
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.set_gpu_virgl_flags(virgl_flags);
-            cfg.set_gpu_shm_size(shm_size.try_into().unwrap());
+            cfg.vmr.set_gpu_render_node(PathBuf::from(render_node));
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1017,6 +2679,22 @@ pub unsafe extern "C" fn krun_set_snd_device(ctx_id: u32, enable: bool) -> i32 {
 }
 
 #[allow(unused_assignments)]
+#[no_mangle]
+pub extern "C" fn krun_cancel_start(ctx_id: u32) -> i32 {
+    if let Some(ctx_cfg) = CTX_MAP.lock().unwrap().get(&ctx_id) {
+        ctx_cfg.vmr.cancel.cancel();
+        return KRUN_SUCCESS;
+    }
+
+    match BUILDING.lock().unwrap().get(&ctx_id) {
+        Some(cancel) => {
+            cancel.cancel();
+            KRUN_SUCCESS
+        }
+        None => -libc::ENOENT,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32 {
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
@@ -1035,6 +2713,201 @@ pub extern "C" fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32 {
     }
 }
 
+#[no_mangle]
+pub extern "C" fn krun_get_guest_ready_eventfd(ctx_id: u32, timeout_ms: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            let timeout = (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms.into()));
+            if let Some(guest_ready) = cfg.guest_ready.as_mut() {
+                guest_ready.timeout = timeout;
+                return guest_ready.efd.as_raw_fd();
+            }
+
+            match EventFd::new(utils::eventfd::EFD_NONBLOCK) {
+                Ok(efd) => {
+                    let fd = efd.as_raw_fd();
+                    cfg.guest_ready = Some(GuestReadyConfig { efd, timeout });
+                    fd
+                }
+                Err(e) => {
+                    error!("Failed to create the guest ready eventfd: {e}");
+                    -e.raw_os_error().unwrap_or(libc::EINVAL)
+                }
+            }
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+/// Listens on a Unix socket for the init process in the guest to report it's
+/// ready, the vsock device forwarding its connection to `GUEST_READY_PORT`.
+fn listen_guest_ready(ctx_id: u32) -> io::Result<(UnixListener, PathBuf)> {
+    let path = env::temp_dir().join(format!("krun-{}-{}-ready.sock", std::process::id(), ctx_id));
+    // Left behind by a previous context with the same ID.
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    Ok((listener, path))
+}
+
+/// Waits for the init process in the guest to send `GUEST_READY_MESSAGE`
+/// through `listener`, up to `deadline`. Returns whether it did.
+fn wait_guest_ready(listener: &UnixListener, deadline: Option<Instant>) -> bool {
+    loop {
+        let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let poll_timeout = match left {
+            Some(left) if left.is_zero() => return false,
+            Some(left) => left.as_millis().try_into().unwrap_or(i32::MAX),
+            None => -1,
+        };
+
+        let mut pollfd = libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pollfd, 1, poll_timeout) } {
+            0 => return false,
+            ret if ret < 0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Failed to wait for the guest ready handshake: {e}");
+                return false;
+            }
+            _ => {}
+        }
+
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("Failed to accept the guest ready handshake: {e}");
+                continue;
+            }
+        };
+        // A guest connecting without reporting anything mustn't hold the
+        // handshake past the deadline.
+        let left = deadline.map(|deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1))
+        });
+        let mut message = [0u8; GUEST_READY_MESSAGE.len()];
+        if stream.set_read_timeout(left).is_ok()
+            && stream.read_exact(&mut message).is_ok()
+            && message == GUEST_READY_MESSAGE
+        {
+            return true;
+        }
+        warn!("Ignoring an invalid guest ready handshake");
+    }
+}
+
+/// Signals the guest ready eventfd once the guest reported it's ready, or its
+/// timeout expired.
+fn guest_ready_worker(listener: UnixListener, path: PathBuf, guest_ready: GuestReadyConfig) {
+    let deadline = guest_ready.timeout.map(|timeout| Instant::now() + timeout);
+    let ready = wait_guest_ready(&listener, deadline);
+    let _ = std::fs::remove_file(&path);
+
+    let value = if ready {
+        info!("The guest reported it's ready");
+        KRUN_GUEST_READY
+    } else {
+        warn!("The guest didn't report it's ready in time");
+        KRUN_GUEST_READY_TIMEOUT
+    };
+    if let Err(e) = guest_ready.efd.write(value) {
+        error!("Failed to signal the guest ready eventfd: {e}");
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_get_panic_fd(ctx_id: u32) -> i32 {
+    // TEE guests on x86_64 aren't told about the device.
+    if cfg!(all(target_arch = "x86_64", feature = "tee")) {
+        return -libc::ENOTSUP;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.vmr.panic_output.is_some() {
+                return -libc::EEXIST;
+            }
+
+            let mut fds = [-1; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+                return -std::io::Error::last_os_error().raw_os_error().unwrap();
+            }
+            for fd in fds {
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            }
+            // Never stall the vCPU reporting the panic if the pipe is full.
+            unsafe { libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK) };
+
+            cfg.vmr.panic_output = Some(unsafe { File::from_raw_fd(fds[1]) });
+            fds[0]
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+pub unsafe extern "C" fn krun_set_dump_on_crash(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.dump_on_crash = Some(path);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+pub extern "C" fn krun_set_reboot_in_place(ctx_id: u32, enable: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let vmr = &mut ctx_cfg.get_mut().vmr;
+            if !enable {
+                vmr.reboot_evt = None;
+            } else if vmr.reboot_evt.is_none() {
+                match EventFd::new(utils::eventfd::EFD_NONBLOCK) {
+                    Ok(efd) => vmr.reboot_evt = Some(efd),
+                    Err(e) => {
+                        error!("Failed to create the reboot eventfd: {e}");
+                        return -e.raw_os_error().unwrap_or(libc::EINVAL);
+                    }
+                }
+            }
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+pub extern "C" fn krun_get_reboot_eventfd(ctx_id: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(ctx_cfg) => match ctx_cfg.get().vmr.reboot_evt.as_ref() {
+            Some(efd) => efd.as_raw_fd(),
+            None => -libc::EINVAL,
+        },
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_console_output(ctx_id: u32, c_filepath: *const c_char) -> i32 {
@@ -1057,6 +2930,111 @@ pub unsafe extern "C" fn krun_set_console_output(ctx_id: u32, c_filepath: *const
     }
 }
 
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_stdout_output(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.vmr.stdout_output.is_some() {
+                -libc::EINVAL
+            } else {
+                cfg.vmr.stdout_output = Some(path);
+                KRUN_SUCCESS
+            }
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_stderr_output(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.vmr.stderr_output.is_some() {
+                -libc::EINVAL
+            } else {
+                cfg.vmr.stderr_output = Some(path);
+                KRUN_SUCCESS
+            }
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_rng_source(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => match ctx_cfg.get_mut().vmr.set_rng_source(path) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                error!("Cannot read from the RNG entropy source: {e}");
+                -e.raw_os_error().unwrap_or(libc::EINVAL)
+            }
+        },
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(all(target_os = "linux", not(feature = "tee")))]
+pub unsafe extern "C" fn krun_set_tpm(ctx_id: u32, c_state_dir: *const c_char) -> i32 {
+    let state_dir = match CStr::from_ptr(c_state_dir).to_str() {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => match ctx_cfg.get_mut().vmr.set_tpm_state_dir(state_dir) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                error!("Invalid vTPM state directory: {e}");
+                -e.raw_os_error().unwrap_or(libc::EINVAL)
+            }
+        },
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub extern "C" fn krun_set_rng_rate_limit(ctx_id: u32, bytes: u64, period_ms: u32) -> i32 {
+    if bytes == 0 || period_ms == 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.set_rng_rate_limit(RngRateLimit {
+                bytes,
+                period: Duration::from_millis(period_ms as u64),
+            });
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_smbios_oem_strings(
@@ -1080,8 +3058,51 @@ pub unsafe extern "C" fn krun_set_smbios_oem_strings(
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().vmr.smbios_oem_strings =
-                (!oem_strings.is_empty()).then_some(oem_strings)
+            if let Err(e) = ctx_cfg.get_mut().vmr.smbios.set_oem_strings(oem_strings) {
+                error!("Invalid SMBIOS OEM strings: {e}");
+                return -libc::EINVAL;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_smbios_field(
+    ctx_id: u32,
+    field: u32,
+    c_value: *const c_char,
+) -> i32 {
+    let field = match field {
+        0 => SmbiosField::BiosVendor,
+        1 => SmbiosField::BiosVersion,
+        2 => SmbiosField::BiosReleaseDate,
+        3 => SmbiosField::SystemManufacturer,
+        4 => SmbiosField::SystemProductName,
+        5 => SmbiosField::SystemVersion,
+        6 => SmbiosField::SystemSerialNumber,
+        7 => SmbiosField::SystemUuid,
+        8 => SmbiosField::SystemSkuNumber,
+        9 => SmbiosField::SystemFamily,
+        _ => return -libc::EINVAL,
+    };
+
+    if c_value.is_null() {
+        return -libc::EINVAL;
+    }
+    let Ok(value) = CStr::from_ptr(c_value).to_str() else {
+        return -libc::EINVAL;
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            if let Err(e) = ctx_cfg.get_mut().vmr.smbios.set_field(field, value) {
+                error!("Invalid SMBIOS field {field:?}: {e}");
+                return -libc::EINVAL;
+            }
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1090,18 +3111,28 @@ pub unsafe extern "C" fn krun_set_smbios_oem_strings(
 }
 
 #[cfg(feature = "net")]
-fn create_virtio_net(ctx_cfg: &mut ContextConfig, backend: VirtioNetBackend) {
+fn create_virtio_net(ctx_cfg: &mut ContextConfig, backend: VirtioNetBackend) -> i32 {
     let mac = ctx_cfg.mac.unwrap_or([0x5a, 0x94, 0xef, 0xe4, 0x0c, 0xee]);
 
+    // The backend isn't known yet when krun_set_net_mtu is called.
+    if let Some(mtu) = ctx_cfg.mtu.filter(|&mtu| mtu > backend.max_mtu()) {
+        error!("MTU {mtu} is too big for the network backend");
+        return -libc::EINVAL;
+    }
+
     let network_interface_config = NetworkInterfaceConfig {
         iface_id: "eth0".to_string(),
         backend,
         mac,
+        rate_limit: None,
+        mtu: ctx_cfg.mtu,
+        vhost: false,
     };
     ctx_cfg
         .vmr
         .add_network_interface(network_interface_config)
         .expect("Failed to create network interface");
+    KRUN_SUCCESS
 }
 
 #[no_mangle]
@@ -1138,32 +3169,87 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
 
     /*
      * Before krun_start_enter() is called in an encrypted context, the TEE
-     * config must have been set via krun_set_tee_config_file(). If the TEE
-     * config is not set by this point, print the relevant error message and
-     * fail.
+     * config must have been set either via krun_set_tee_config_file() or the
+     * krun_set_tee_* calls, but not both. If the TEE config is not set by this
+     * point, print the relevant error message and fail.
      */
     #[cfg(feature = "tee")]
-    if let Some(tee_config) = ctx_cfg.get_tee_config_file() {
-        if let Err(e) = ctx_cfg.vmr.set_tee_config(tee_config) {
+    {
+        let result = match (ctx_cfg.get_tee_config_file(), ctx_cfg.tee_config.take()) {
+            (Some(tee_config_file), None) => ctx_cfg.vmr.set_tee_config(tee_config_file),
+            (None, Some(mut tee_config)) => {
+                // The guest size comes from krun_set_vm_config() in this case.
+                let vm_config = ctx_cfg.vmr.vm_config();
+                tee_config.cpus = vm_config.vcpu_count.unwrap_or_default();
+                tee_config.ram_mib = vm_config.mem_size_mib.unwrap_or_default();
+                ctx_cfg.vmr.apply_tee_config(tee_config)
+            }
+            (Some(_), Some(_)) => {
+                error!("TEE config set both from a file and through krun_set_tee_*");
+                return -libc::EINVAL;
+            }
+            (None, None) => {
+                error!("Missing TEE config");
+                return -libc::EINVAL;
+            }
+        };
+        // Whichever way the rest of the config was set.
+        #[cfg(feature = "amd-sev")]
+        let result = result.and_then(|()| match ctx_cfg.tee_vendor_chain.take() {
+            Some(chain) => {
+                let mut tee_config = ctx_cfg.vmr.tee_config().clone();
+                tee_config.vendor_chain_data = chain;
+                ctx_cfg.vmr.apply_tee_config(tee_config)
+            }
+            None => Ok(()),
+        });
+        #[cfg(feature = "amd-sev")]
+        let result = result.and_then(|()| match ctx_cfg.tee_progress_callback.take() {
+            Some((callback, user_data)) => {
+                let mut tee_config = ctx_cfg.vmr.tee_config().clone();
+                tee_config.progress_callback =
+                    Some(vmm::resources::ProgressCallback(Arc::new(move |phase| {
+                        report_tee_phase(callback, user_data, phase)
+                    })));
+                ctx_cfg.vmr.apply_tee_config(tee_config)
+            }
+            None => Ok(()),
+        });
+
+        if let Err(e) = result {
             error!("Error setting up TEE config: {:?}", e);
             return -libc::EINVAL;
         }
-    } else {
-        error!("Missing TEE config file");
-        return -libc::EINVAL;
     }
 
+    // Everything about the executable goes through the side channel once it's
+    // too long for the command line, as init then ignores KRUN_INIT.
+    let exec_config = ctx_cfg.get_exec_config();
+    let (exec_path, env, args) = if exec_config.is_some() {
+        (String::new(), String::new(), String::new())
+    } else {
+        (
+            ctx_cfg.get_exec_path(),
+            ctx_cfg.get_env(),
+            ctx_cfg.get_args(),
+        )
+    };
+    ctx_cfg.vmr.init_config = exec_config;
+
     let boot_source = BootSourceConfig {
         kernel_cmdline_prolog: Some(format!(
-            "{} init={} {} {} {} {}",
+            "{} init={} {} {} {} {} {} {}",
             DEFAULT_KERNEL_CMDLINE,
             INIT_PATH,
-            ctx_cfg.get_exec_path(),
+            exec_path,
             ctx_cfg.get_workdir(),
             ctx_cfg.get_rlimits(),
-            ctx_cfg.get_env(),
+            ctx_cfg.get_hostname(),
+            ctx_cfg.get_dns_servers(),
+            env,
         )),
-        kernel_cmdline_epilog: Some(format!(" -- {}", ctx_cfg.get_args())),
+        kernel_cmdline_append: ctx_cfg.kernel_cmdline_append.clone(),
+        kernel_cmdline_epilog: Some(format!(" -- {args}")),
     };
 
     if ctx_cfg.vmr.set_boot_source(boot_source).is_err() {
@@ -1178,6 +3264,33 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         unix_ipc_port_map: None,
     };
 
+    // The timeout runs from here, so it covers building the microVM too.
+    if let Some(guest_ready) = ctx_cfg.guest_ready.take() {
+        if ctx_cfg
+            .unix_ipc_port_map
+            .as_ref()
+            .is_some_and(|map| map.contains_key(&GUEST_READY_PORT))
+        {
+            error!("vsock port {GUEST_READY_PORT} is reserved for the guest ready handshake");
+            return -libc::EEXIST;
+        }
+        let (listener, path) = match listen_guest_ready(ctx_id) {
+            Ok(listening) => listening,
+            Err(e) => {
+                error!("Cannot listen for the guest ready handshake: {e}");
+                return -e.raw_os_error().unwrap_or(libc::EINVAL);
+            }
+        };
+        ctx_cfg.add_vsock_port(GUEST_READY_PORT, path.clone(), false);
+        if let Err(e) = std::thread::Builder::new()
+            .name("guest ready".into())
+            .spawn(move || guest_ready_worker(listener, path, guest_ready))
+        {
+            error!("Cannot start the guest ready worker: {e}");
+            return -libc::EINVAL;
+        }
+    }
+
     if let Some(ref map) = ctx_cfg.unix_ipc_port_map {
         vsock_config.unix_ipc_port_map = Some(map.clone());
         vsock_set = true;
@@ -1192,18 +3305,35 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
             #[cfg(feature = "net")]
             {
                 let backend = VirtioNetBackend::Passt(_fd);
-                create_virtio_net(&mut ctx_cfg, backend);
+                let ret = create_virtio_net(&mut ctx_cfg, backend);
+                if ret != KRUN_SUCCESS {
+                    return ret;
+                }
             }
         }
         NetworkConfig::VirtioNetGvproxy(ref _path) => {
             #[cfg(feature = "net")]
             {
                 let backend = VirtioNetBackend::Gvproxy(_path.clone());
-                create_virtio_net(&mut ctx_cfg, backend);
+                let ret = create_virtio_net(&mut ctx_cfg, backend);
+                if ret != KRUN_SUCCESS {
+                    return ret;
+                }
             }
         }
     }
 
+    // Interfaces are attached in order, after the one configured with
+    // krun_set_passt_fd/krun_set_gvproxy_path, so the guest enumerates them
+    // in the same order.
+    #[cfg(feature = "net")]
+    for iface in std::mem::take(&mut ctx_cfg.net_ifaces) {
+        ctx_cfg
+            .vmr
+            .add_network_interface(iface)
+            .expect("Failed to create network interface");
+    }
+
     if vsock_set {
         ctx_cfg.vmr.set_vsock_device(vsock_config).unwrap();
     }
@@ -1225,20 +3355,68 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
     #[cfg(target_os = "macos")]
     let (sender, receiver) = unbounded();
 
-    let _vmm = match vmm::builder::build_microvm(
+    BUILDING
+        .lock()
+        .unwrap()
+        .insert(ctx_id, ctx_cfg.vmr.cancel.clone());
+    let result = vmm::builder::build_microvm(
         &ctx_cfg.vmr,
         &mut event_manager,
         ctx_cfg.shutdown_efd,
         #[cfg(target_os = "macos")]
         sender,
-    ) {
+    );
+    BUILDING.lock().unwrap().remove(&ctx_id);
+
+    let _vmm = match result {
         Ok(vmm) => vmm,
+        #[cfg(feature = "tee")]
+        Err(vmm::builder::StartMicrovmError::TeeDryRun) => {
+            info!("TEE dry run completed");
+            return KRUN_SUCCESS;
+        }
+        // Whatever step noticed it, the failure comes from the cancellation.
+        Err(e) if ctx_cfg.vmr.cancel.is_cancelled() => {
+            info!("Building the microVM was cancelled: {:?}", e);
+            return -libc::ECANCELED;
+        }
         Err(e) => {
             error!("Building the microVM failed: {:?}", e);
             return -libc::EINVAL;
         }
     };
 
+    if let Some(ref map) = ctx_cfg.unix_ipc_port_map {
+        let listen_paths = map
+            .iter()
+            .filter(|(_, (_, listen))| *listen)
+            .map(|(port, (path, _))| (*port, path.clone()))
+            .collect();
+        VSOCK_LISTEN_PATHS
+            .lock()
+            .unwrap()
+            .insert(ctx_id, listen_paths);
+    }
+
+    #[cfg(not(feature = "tee"))]
+    if let Some(balloon) = _vmm.lock().unwrap().balloon() {
+        BALLOONS.lock().unwrap().insert(ctx_id, balloon);
+    }
+
+    #[cfg(feature = "amd-sev")]
+    match _vmm.lock().unwrap().kvm_vm().launch_measurement() {
+        Ok(launch_measurement) => {
+            TEE_MEASUREMENTS
+                .lock()
+                .unwrap()
+                .insert(ctx_id, launch_measurement);
+        }
+        Err(e) => debug!("No TEE launch measurement to record: {:?}", e),
+    }
+
+    #[cfg(feature = "amd-sev")]
+    TEE_VMMS.lock().unwrap().insert(ctx_id, _vmm.clone());
+
     #[cfg(target_os = "macos")]
     let mapper_vmm = _vmm.clone();
 
@@ -296,6 +296,7 @@ impl Gfxstream {
         let use_debug = debug_handler.is_some();
         let mut cookie = Box::new(RutabagaCookie {
             render_server_fd: None,
+            render_node_fd: None,
             fence_handler: Some(fence_handler),
             debug_handler,
         });
@@ -6,9 +6,12 @@
 use std::collections::BTreeMap as Map;
 use std::convert::TryInto;
 use std::fs::File;
+#[cfg(feature = "virgl_renderer")]
+use std::fs::OpenOptions;
 use std::io::IoSliceMut;
 use std::io::Read;
 use std::io::Write;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use crate::cross_domain::CrossDomain;
@@ -1050,6 +1053,7 @@ pub struct RutabagaBuilder {
     channels: Option<Vec<RutabagaChannel>>,
     debug_handler: Option<RutabagaDebugHandler>,
     export_table: Option<ExportTable>,
+    render_node: Option<PathBuf>,
 }
 
 impl RutabagaBuilder {
@@ -1070,6 +1074,7 @@ impl RutabagaBuilder {
             channels: None,
             debug_handler: None,
             export_table: None,
+            render_node: None,
         }
     }
 
@@ -1174,6 +1179,13 @@ impl RutabagaBuilder {
         self
     }
 
+    /// Set the DRM render node virglrenderer renders with, instead of letting it pick one.
+    /// Building fails unless virglrenderer is used with EGL.
+    pub fn set_render_node(mut self, render_node: PathBuf) -> RutabagaBuilder {
+        self.render_node = Some(render_node);
+        self
+    }
+
     /// Builds Rutabaga and returns a handle to it.
     ///
     /// This should be only called once per every virtual machine instance.  Rutabaga tries to
@@ -1253,6 +1265,16 @@ impl RutabagaBuilder {
             ));
         }
 
+        // The render node is only handed to virglrenderer, which only uses it with EGL.
+        if self.render_node.is_some()
+            && (self.default_component != RutabagaComponentType::VirglRenderer
+                || !self.virglrenderer_flags.uses_egl())
+        {
+            return Err(RutabagaError::InvalidRutabagaBuild(
+                "render node requires virglrenderer with EGL",
+            ));
+        }
+
         if self.default_component == RutabagaComponentType::Rutabaga2D {
             let rutabaga_2d = Rutabaga2D::init(fence_handler.clone())?;
             rutabaga_components.insert(RutabagaComponentType::Rutabaga2D, rutabaga_2d);
@@ -1262,10 +1284,18 @@ impl RutabagaBuilder {
                 #[cfg(not(feature = "virgl_renderer_next"))]
                 let rutabaga_server_descriptor = None;
 
+                let render_node_descriptor = match &self.render_node {
+                    Some(path) => Some(SafeDescriptor::from(
+                        OpenOptions::new().read(true).write(true).open(path)?,
+                    )),
+                    None => None,
+                };
+
                 let virgl = VirglRenderer::init(
                     self.virglrenderer_flags,
                     fence_handler.clone(),
                     rutabaga_server_descriptor,
+                    render_node_descriptor,
                 )?;
                 rutabaga_components.insert(RutabagaComponentType::VirglRenderer, virgl);
 
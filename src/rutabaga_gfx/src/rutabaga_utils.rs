@@ -422,6 +422,11 @@ impl VirglRendererFlags {
         self.set_flag(VIRGLRENDERER_USE_EGL, v)
     }
 
+    /// Whether EGL is used for context creation.
+    pub fn uses_egl(self) -> bool {
+        self.0 & VIRGLRENDERER_USE_EGL != 0
+    }
+
     /// Use a dedicated thread for fence synchronization.
     pub fn use_thread_sync(self, v: bool) -> VirglRendererFlags {
         self.set_flag(VIRGLRENDERER_THREAD_SYNC, v)
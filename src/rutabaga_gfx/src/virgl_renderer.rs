@@ -250,16 +250,32 @@ unsafe extern "C" fn get_server_fd(cookie: *mut c_void, version: u32) -> c_int {
     .unwrap_or_else(|_| abort())
 }
 
+unsafe extern "C" fn get_drm_fd(cookie: *mut c_void) -> c_int {
+    catch_unwind(|| {
+        assert!(!cookie.is_null());
+        let cookie = &mut *(cookie as *mut RutabagaCookie);
+
+        // Transfer the fd ownership to virglrenderer, which picks a render node
+        // by itself on -1.
+        cookie
+            .render_node_fd
+            .take()
+            .map(SafeDescriptor::into_raw_descriptor)
+            .unwrap_or(-1)
+    })
+    .unwrap_or_else(|_| abort())
+}
+
 const VIRGL_RENDERER_CALLBACKS: &virgl_renderer_callbacks = &virgl_renderer_callbacks {
     #[cfg(not(feature = "virgl_renderer_next"))]
-    version: 1,
+    version: 2,
     #[cfg(feature = "virgl_renderer_next")]
     version: 3,
     write_fence: Some(write_fence),
     create_gl_context: None,
     destroy_gl_context: None,
     make_current: None,
-    get_drm_fd: None,
+    get_drm_fd: Some(get_drm_fd),
     #[cfg(not(feature = "virgl_renderer_next"))]
     write_context_fence: None,
     #[cfg(feature = "virgl_renderer_next")]
@@ -293,6 +309,7 @@ impl VirglRenderer {
         virglrenderer_flags: VirglRendererFlags,
         fence_handler: RutabagaFenceHandler,
         render_server_fd: Option<SafeDescriptor>,
+        render_node_fd: Option<SafeDescriptor>,
     ) -> RutabagaResult<Box<dyn RutabagaComponent>> {
         if cfg!(debug_assertions) {
             let ret = unsafe { libc::dup2(libc::STDOUT_FILENO, libc::STDERR_FILENO) };
@@ -323,6 +340,7 @@ impl VirglRenderer {
         // library.
         let cookie = Box::into_raw(Box::new(RutabagaCookie {
             render_server_fd,
+            render_node_fd,
             fence_handler: Some(fence_handler),
             debug_handler: None,
         }));
@@ -0,0 +1,176 @@
+use crate::{Error, Result};
+
+/// Fields of the BIOS Information (Type 0) and System Information (Type 1) structures that can
+/// be customized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmbiosField {
+    BiosVendor,
+    BiosVersion,
+    /// In the mm/dd/yyyy format.
+    BiosReleaseDate,
+    SystemManufacturer,
+    SystemProductName,
+    SystemVersion,
+    SystemSerialNumber,
+    /// In the usual xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx format.
+    SystemUuid,
+    SystemSkuNumber,
+    SystemFamily,
+}
+
+/// Contents of the SMBIOS tables. Fields left unset keep their default values, or aren't
+/// present at all.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SmbiosConfig {
+    pub(crate) bios_vendor: Option<String>,
+    pub(crate) bios_version: Option<String>,
+    pub(crate) bios_release_date: Option<String>,
+    pub(crate) system_manufacturer: Option<String>,
+    pub(crate) system_product_name: Option<String>,
+    pub(crate) system_version: Option<String>,
+    pub(crate) system_serial_number: Option<String>,
+    pub(crate) system_uuid: Option<[u8; 16]>,
+    pub(crate) system_sku_number: Option<String>,
+    pub(crate) system_family: Option<String>,
+    pub(crate) oem_strings: Vec<String>,
+}
+
+impl SmbiosConfig {
+    /// Sets one of the fields of the BIOS or system information.
+    pub fn set_field(&mut self, field: SmbiosField, value: &str) -> Result<()> {
+        use self::SmbiosField::*;
+
+        if field == SystemUuid {
+            self.system_uuid = Some(parse_uuid(value)?);
+            return Ok(());
+        }
+
+        check_string(value)?;
+        if field == BiosReleaseDate && !is_release_date(value) {
+            return Err(Error::InvalidReleaseDate);
+        }
+
+        let value = Some(value.to_string());
+        match field {
+            BiosVendor => self.bios_vendor = value,
+            BiosVersion => self.bios_version = value,
+            BiosReleaseDate => self.bios_release_date = value,
+            SystemManufacturer => self.system_manufacturer = value,
+            SystemProductName => self.system_product_name = value,
+            SystemVersion => self.system_version = value,
+            SystemSerialNumber => self.system_serial_number = value,
+            SystemSkuNumber => self.system_sku_number = value,
+            SystemFamily => self.system_family = value,
+            SystemUuid => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Sets the OEM Strings (Type 11). The structure is omitted if there are none.
+    pub fn set_oem_strings(&mut self, oem_strings: Vec<String>) -> Result<()> {
+        if u8::try_from(oem_strings.len()).is_err() {
+            return Err(Error::OEMStringsOverflow);
+        }
+        for s in &oem_strings {
+            check_string(s)?;
+        }
+
+        self.oem_strings = oem_strings;
+        Ok(())
+    }
+}
+
+// Strings are null-terminated and an empty one would end the string set of the structure.
+fn check_string(s: &str) -> Result<()> {
+    if s.is_empty() {
+        return Err(Error::EmptyString);
+    }
+    if s.contains('\0') {
+        return Err(Error::InvalidString);
+    }
+    Ok(())
+}
+
+fn is_release_date(s: &str) -> bool {
+    s.len() == 10
+        && s.bytes().enumerate().all(|(i, c)| {
+            if i == 2 || i == 5 {
+                c == b'/'
+            } else {
+                c.is_ascii_digit()
+            }
+        })
+}
+
+// Since SMBIOS 2.6, the first three fields of the UUID are encoded in little-endian.
+fn parse_uuid(s: &str) -> Result<[u8; 16]> {
+    let groups: Vec<&str> = s.split('-').collect();
+    if groups.iter().map(|g| g.len()).ne([8, 4, 4, 4, 12])
+        || !groups
+            .iter()
+            .all(|g| g.bytes().all(|c| c.is_ascii_hexdigit()))
+    {
+        return Err(Error::InvalidUuid);
+    }
+
+    let hex: String = groups.concat();
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = hex
+            .get(2 * i..2 * i + 2)
+            .and_then(|b| u8::from_str_radix(b, 16).ok())
+            .ok_or(Error::InvalidUuid)?;
+    }
+    uuid[0..4].reverse();
+    uuid[4..6].reverse();
+    uuid[6..8].reverse();
+    Ok(uuid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uuid() {
+        assert_eq!(
+            parse_uuid("00112233-4455-6677-8899-aabbccddeeff").unwrap(),
+            [
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff
+            ]
+        );
+        assert!(parse_uuid("00112233-4455-6677-8899-aabbccddeef").is_err());
+        assert!(parse_uuid("00112233445566778899aabbccddeeff").is_err());
+        assert!(parse_uuid("0011223g-4455-6677-8899-aabbccddeeff").is_err());
+        assert!(parse_uuid("+0112233-4455-6677-8899-aabbccddeeff").is_err());
+    }
+
+    #[test]
+    fn test_set_field() {
+        let mut config = SmbiosConfig::default();
+        config
+            .set_field(SmbiosField::SystemSerialNumber, "ABC123")
+            .unwrap();
+        assert_eq!(config.system_serial_number.as_deref(), Some("ABC123"));
+
+        assert!(config.set_field(SmbiosField::SystemFamily, "").is_err());
+        assert!(config.set_field(SmbiosField::SystemFamily, "a\0b").is_err());
+        config
+            .set_field(SmbiosField::BiosReleaseDate, "10/16/2026")
+            .unwrap();
+        assert!(config
+            .set_field(SmbiosField::BiosReleaseDate, "2026-10-16")
+            .is_err());
+    }
+
+    #[test]
+    fn test_set_oem_strings() {
+        let mut config = SmbiosConfig::default();
+        assert!(config.set_oem_strings(vec!["a".to_string(); 256]).is_err());
+        assert!(config
+            .set_oem_strings(vec!["a".to_string(), String::new()])
+            .is_err());
+        config.set_oem_strings(vec!["a".to_string(); 255]).unwrap();
+    }
+}
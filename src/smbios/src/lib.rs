@@ -3,8 +3,11 @@ use std::fmt::Display;
 use std::{fmt, mem, result};
 use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
+mod config;
 mod table;
 
+pub use crate::config::{SmbiosConfig, SmbiosField};
+
 #[derive(Debug)]
 pub enum Error {
     /// The size of the SMBIOS table is too big.
@@ -17,6 +20,14 @@ pub enum Error {
     WriteData,
     /// There was too many OEM Strings
     OEMStringsOverflow,
+    /// A string is empty
+    EmptyString,
+    /// A string contains a null character
+    InvalidString,
+    /// The BIOS release date isn't in the mm/dd/yyyy format
+    InvalidReleaseDate,
+    /// The system UUID can't be parsed
+    InvalidUuid,
 }
 
 impl std::error::Error for Error {}
@@ -24,7 +35,8 @@ impl std::error::Error for Error {}
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Error::{
-            NotEnoughMemory, OEMStringsOverflow, SmBiosOverflow, WriteData, WriteSmbiosEp,
+            EmptyString, InvalidReleaseDate, InvalidString, InvalidUuid, NotEnoughMemory,
+            OEMStringsOverflow, SmBiosOverflow, WriteData, WriteSmbiosEp,
         };
 
         let description = match self {
@@ -33,6 +45,12 @@ impl Display for Error {
             WriteSmbiosEp => "Failure to write SMBIOS entrypoint structure".to_string(),
             WriteData => "Failure to write additional data to memory".to_string(),
             OEMStringsOverflow => "There was too many OEM Strings".to_string(),
+            EmptyString => "A string is empty".to_string(),
+            InvalidString => "A string contains a null character".to_string(),
+            InvalidReleaseDate => {
+                "The BIOS release date isn't in the mm/dd/yyyy format".to_string()
+            }
+            InvalidUuid => "The system UUID can't be parsed".to_string(),
         };
 
         write!(f, "SMBIOS error: {description}")
@@ -41,11 +59,7 @@ impl Display for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
-pub fn setup_smbios(
-    mem: &GuestMemoryMmap,
-    start_addr: u64,
-    oem_strings: &Option<Vec<String>>,
-) -> Result<u64> {
+pub fn setup_smbios(mem: &GuestMemoryMmap, start_addr: u64, config: &SmbiosConfig) -> Result<u64> {
     let start_addr = GuestAddress(start_addr);
     let table_starting_addr = start_addr
         .checked_add(mem::size_of::<Entrypoint30>() as u64)
@@ -55,13 +69,13 @@ pub fn setup_smbios(
     // Required structures and data
 
     // BIOS Information (Type 0)
-    next_write_addr = write_type_0_table(mem, next_write_addr)?;
+    next_write_addr = write_type_0_table(mem, next_write_addr, config)?;
 
     // System Information (Type 1)
-    next_write_addr = write_type_1_table(mem, next_write_addr)?;
+    next_write_addr = write_type_1_table(mem, next_write_addr, config)?;
 
     // OEM Strings (Type 11)
-    next_write_addr = write_type_11_table(mem, next_write_addr, oem_strings)?;
+    next_write_addr = write_type_11_table(mem, next_write_addr, &config.oem_strings)?;
 
     next_write_addr = write_end_of_table(mem, next_write_addr)?;
 
@@ -89,30 +103,72 @@ fn write_entry_point(
     Ok(ep_size + u64::from(table_max_size))
 }
 
-fn write_type_0_table(mem: &GuestMemoryMmap, mut current: GuestAddress) -> Result<GuestAddress> {
+fn write_type_0_table(
+    mem: &GuestMemoryMmap,
+    mut current: GuestAddress,
+    config: &SmbiosConfig,
+) -> Result<GuestAddress> {
     // One and only one structure is present in the structure-table. BIOS Version and
     // BIOS Release Date strings are non-null; the date field uses a 4-digit year (for
     // example, 1999). All other fields reflect full BIOS support information
     let biosinfo = BiosInfo::new(1, 2, 3);
 
     current = write_obj(mem, biosinfo, current)?;
-    current = write_string(mem, "libkrun", current)?; // vendor string
-    current = write_string(mem, "0", current)?; // version string
-    current = write_string(mem, "01/05/2024", current)?; // release date string
+    // vendor string
+    current = write_string(
+        mem,
+        config.bios_vendor.as_deref().unwrap_or("libkrun"),
+        current,
+    )?;
+    // version string
+    current = write_string(mem, config.bios_version.as_deref().unwrap_or("0"), current)?;
+    // release date string
+    current = write_string(
+        mem,
+        config.bios_release_date.as_deref().unwrap_or("01/05/2024"),
+        current,
+    )?;
 
     // the set of strings is terminated with an additional null (00h) byte
     current = write_obj(mem, 0u8, current)?;
     Ok(current)
 }
 
-fn write_type_1_table(mem: &GuestMemoryMmap, mut current: GuestAddress) -> Result<GuestAddress> {
+fn write_type_1_table<'a>(
+    mem: &GuestMemoryMmap,
+    mut current: GuestAddress,
+    config: &'a SmbiosConfig,
+) -> Result<GuestAddress> {
     // Manufacturer and Product Name strings are non-null. One and only one structure
     // is present in the structure-table.
-    let sysinfo = SystemInfo::new(1, 2);
+    let mut sysinfo = SystemInfo::new(1, 2);
+    let mut strings = vec![
+        config.system_manufacturer.as_deref().unwrap_or("Libkrun"),
+        config
+            .system_product_name
+            .as_deref()
+            .unwrap_or("libkrun Virtual Machine"),
+    ];
+
+    // Optional strings are numbered in the order they are appended.
+    let mut add_string = |s: Option<&'a str>| {
+        s.map_or(0, |s| {
+            strings.push(s);
+            strings.len() as u8
+        })
+    };
+    sysinfo.set_version(add_string(config.system_version.as_deref()));
+    sysinfo.set_serial_number(add_string(config.system_serial_number.as_deref()));
+    sysinfo.set_sku_number(add_string(config.system_sku_number.as_deref()));
+    sysinfo.set_family(add_string(config.system_family.as_deref()));
+    if let Some(uuid) = config.system_uuid {
+        sysinfo.set_uuid(uuid);
+    }
 
     current = write_obj(mem, sysinfo, current)?;
-    current = write_string(mem, "Libkrun", current)?;
-    current = write_string(mem, "libkrun Virtual Machine", current)?;
+    for s in strings {
+        current = write_string(mem, s, current)?;
+    }
 
     // the set of strings is terminated with an additional null (00h) byte
     current = write_obj(mem, 0u8, current)?;
@@ -122,11 +178,11 @@ fn write_type_1_table(mem: &GuestMemoryMmap, mut current: GuestAddress) -> Resul
 fn write_type_11_table(
     mem: &GuestMemoryMmap,
     mut current: GuestAddress,
-    oem_strings: &Option<Vec<String>>,
+    oem_strings: &[String],
 ) -> Result<GuestAddress> {
-    let Some(oem_strings) = oem_strings else {
+    if oem_strings.is_empty() {
         return Ok(current);
-    };
+    }
 
     let number_of_strings = oem_strings
         .len()
@@ -185,3 +241,44 @@ fn write_obj<T: ByteValued>(
         .ok_or(Error::NotEnoughMemory)?;
     Ok(next)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setup_smbios() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut config = SmbiosConfig::default();
+        config
+            .set_field(SmbiosField::SystemSerialNumber, "ABC123")
+            .unwrap();
+        config
+            .set_field(
+                SmbiosField::SystemUuid,
+                "00112233-4455-6677-8899-aabbccddeeff",
+            )
+            .unwrap();
+        config.set_oem_strings(vec!["oem".to_string()]).unwrap();
+
+        let size = setup_smbios(&mem, 0, &config).unwrap() as usize;
+        let mut table = vec![0u8; size];
+        mem.read_slice(&mut table, GuestAddress(0)).unwrap();
+
+        let sysinfo_addr = table
+            .windows(b"01/05/2024\0\0".len())
+            .position(|w| w == b"01/05/2024\0\0")
+            .unwrap()
+            + b"01/05/2024\0\0".len();
+        let sysinfo = &table[sysinfo_addr..];
+        // The serial number is the first string after the manufacturer and product name.
+        assert_eq!(sysinfo[7], 3);
+        assert_eq!(sysinfo[8..12], [0x33, 0x22, 0x11, 0x00]);
+        let strings = &sysinfo[mem::size_of::<SystemInfo>()..];
+        assert!(strings.starts_with(b"Libkrun\0libkrun Virtual Machine\0ABC123\0\0"));
+
+        let oem_strings = &strings[b"Libkrun\0libkrun Virtual Machine\0ABC123\0\0".len()..];
+        assert_eq!(oem_strings[0], 11);
+        assert!(oem_strings[mem::size_of::<OemStrings>()..].starts_with(b"oem\0\0"));
+    }
+}
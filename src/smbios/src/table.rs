@@ -164,6 +164,26 @@ impl SystemInfo {
             ..Default::default()
         }
     }
+
+    pub fn set_version(&mut self, version_str_idx: u8) {
+        self.version = version_str_idx;
+    }
+
+    pub fn set_serial_number(&mut self, serial_number_str_idx: u8) {
+        self.serial_number = serial_number_str_idx;
+    }
+
+    pub fn set_uuid(&mut self, uuid: [u8; 16]) {
+        self.uuid = uuid;
+    }
+
+    pub fn set_sku_number(&mut self, sku_number_str_idx: u8) {
+        self.sku_number = sku_number_str_idx;
+    }
+
+    pub fn set_family(&mut self, family_str_idx: u8) {
+        self.family = family_str_idx;
+    }
 }
 
 // OEM Strings (Type 11)
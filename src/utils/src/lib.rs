@@ -6,6 +6,7 @@ pub use vmm_sys_util::{errno, tempdir, tempfile, terminal};
 pub use vmm_sys_util::{eventfd, ioctl};
 
 pub mod byte_order;
+pub mod cancel;
 #[cfg(target_os = "linux")]
 pub mod linux;
 #[cfg(target_os = "linux")]
@@ -17,6 +18,7 @@ pub use macos::epoll;
 #[cfg(target_os = "macos")]
 pub use macos::eventfd;
 pub mod rand;
+pub mod sensitive;
 #[cfg(target_os = "linux")]
 pub mod signal;
 pub mod sm;
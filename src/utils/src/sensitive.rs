@@ -0,0 +1,36 @@
+//! Keys of values that must never reach the logs.
+
+/// Keys of JSON objects and log fields whose values must never reach the logs.
+pub const SENSITIVE_KEYS: &[&str] = &[
+    "ciphertext",
+    "cookie",
+    "encrypted_key",
+    "iv",
+    "key",
+    "private_key",
+    "secret",
+    "session_id",
+    "tag",
+    "token",
+];
+
+/// Whether the value of `key` must be redacted from the logs.
+pub fn is_sensitive(key: &str) -> bool {
+    SENSITIVE_KEYS.contains(&key.to_lowercase().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sensitive() {
+        assert!(is_sensitive("token"));
+        assert!(is_sensitive("Session_ID"));
+        assert!(is_sensitive("encrypted_key"));
+        assert!(is_sensitive("iv"));
+        assert!(is_sensitive("tag"));
+        assert!(!is_sensitive("tokens"));
+        assert!(!is_sensitive("workload_id"));
+    }
+}
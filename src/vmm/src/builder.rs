@@ -8,17 +8,34 @@ use crossbeam_channel::{unbounded, Sender};
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io;
-#[cfg(target_os = "linux")]
 use std::os::fd::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+#[cfg(any(
+    feature = "gpu",
+    all(target_os = "linux", target_arch = "x86_64", not(feature = "tee"))
+))]
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use super::BootState;
 use super::{Error, Vmm};
+#[cfg(target_arch = "x86_64")]
+use super::{FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK};
 
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
+#[cfg(target_os = "linux")]
+use crate::landlock::{self, LandlockRuleset};
+use crate::metrics::METRICS;
 use crate::resources::VmResources;
+#[cfg(target_os = "linux")]
+use crate::seccomp;
 use devices::legacy::GicV3;
 use devices::legacy::Serial;
 #[cfg(target_os = "macos")]
@@ -29,46 +46,53 @@ use devices::virtio::{port_io, MmioTransport, PortDescription, Vsock};
 #[cfg(target_os = "macos")]
 use hvf::MemoryMapping;
 
-#[cfg(feature = "tee")]
-use kbs_types::Tee;
-
 use crate::device_manager;
 #[cfg(feature = "tee")]
 use crate::resources::TeeConfig;
 #[cfg(target_os = "linux")]
-use crate::signal_handler::register_sigint_handler;
-#[cfg(target_os = "linux")]
 use crate::signal_handler::register_sigwinch_handler;
+#[cfg(target_os = "linux")]
+use crate::signal_handler::{register_sigint_handler, register_sigsys_handler};
 use crate::terminal::term_set_raw_mode;
 #[cfg(feature = "blk")]
 use crate::vmm_config::block::BlockBuilder;
-use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use crate::vmm_config::firmware::FirmwareConfig;
 #[cfg(not(feature = "tee"))]
 use crate::vmm_config::fs::FsDeviceConfig;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use crate::vmm_config::kernel_bundle::KernelBundle;
+#[cfg(target_os = "linux")]
+use crate::vmm_config::machine_config::{HugePageSize, HugePagesConfig};
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use crate::vmm_config::numa::{NumaConfig, NumaConfigError};
+#[cfg(target_arch = "x86_64")]
+use crate::vmm_config::watchdog::WatchdogAction;
 #[cfg(target_os = "linux")]
 use crate::vstate::KvmContext;
 #[cfg(all(target_os = "linux", feature = "tee"))]
 use crate::vstate::MeasuredRegion;
 use crate::vstate::{Error as VstateError, Vcpu, VcpuConfig, Vm};
 use arch::ArchMemoryInfo;
-#[cfg(feature = "tee")]
 use arch::InitrdConfig;
 use device_manager::shm::ShmManager;
 #[cfg(not(feature = "tee"))]
 use devices::virtio::{fs::ExportTable, VirtioShmRegion};
-#[cfg(feature = "tee")]
-use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
 use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
 use nix::unistd::isatty;
 use polly::event_manager::{Error as EventManagerError, EventManager};
+#[cfg(feature = "tee")]
+use utils::cancel::CancelToken;
 use utils::eventfd::EventFd;
-#[cfg(not(feature = "efi"))]
+#[cfg(any(not(feature = "efi"), target_os = "linux"))]
 use vm_memory::mmap::MmapRegion;
-#[cfg(not(feature = "tee"))]
 use vm_memory::Address;
-#[cfg(any(target_arch = "aarch64", feature = "tee"))]
 use vm_memory::Bytes;
-#[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use vm_memory::FileOffset;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use vm_memory::GuestMemoryRegion;
+#[cfg(any(all(target_arch = "x86_64", not(feature = "tee")), target_os = "linux"))]
 use vm_memory::GuestRegionMmap;
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
 
@@ -80,20 +104,47 @@ static EDK2_BINARY: &[u8] = include_bytes!("../../../edk2/KRUN_EFI.silent.fd");
 pub enum StartMicrovmError {
     /// Unable to attach block device to Vmm.
     AttachBlockDevice(io::Error),
+    /// The build of the microVM was cancelled.
+    Cancelled,
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Cannot start the timer of the watchdog device.
+    #[cfg(target_arch = "x86_64")]
+    CreateWatchdog(io::Error),
+    /// Cannot map the firmware varstore into the guest memory.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    FirmwareMmap(vm_memory::mmap::MmapRegionError),
+    /// Cannot read the firmware image or its varstore.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    FirmwareRead(io::Error),
+    /// A GPU render node is set, but virglrenderer doesn't use EGL to render with it.
+    #[cfg(feature = "gpu")]
+    GpuRenderNodeWithoutEgl,
     /// Memory regions are overlapping or mmap fails.
     GuestMemoryMmap(vm_memory::Error),
+    /// Cannot create the file backing the guest memory in the hugetlbfs mount.
+    HugePagesFile(io::Error),
+    /// Cannot map hugepages for the guest memory.
+    HugePagesMmap(io::Error),
     /// Cannot load initrd due to an invalid memory configuration.
     InitrdLoad,
     /// Cannot load initrd due to an invalid image.
     InitrdRead(io::Error),
     /// Internal error encountered while starting a microVM.
     Internal(Error),
+    /// The NUMA topology doesn't match the guest.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    InvalidNumaConfig(NumaConfigError),
+    /// An affinity is set for a vCPU the microVM doesn't have.
+    #[cfg(target_os = "linux")]
+    InvalidVcpuAffinity(u8),
     /// The kernel command line is invalid.
     KernelCmdline(String),
     /// Cannot inject the kernel into the guest memory due to a problem with the bundle.
     KernelBundle(vm_memory::mmap::MmapRegionError),
+    /// Cannot confine the VMM threads with Landlock.
+    #[cfg(target_os = "linux")]
+    Landlock(landlock::Error),
     /// Cannot load command line string.
     LoadCommandline(kernel::cmdline::Error),
     /// The start command was issued more than once.
@@ -104,10 +155,20 @@ pub enum StartMicrovmError {
     MissingMemSizeConfig,
     /// The net device configuration is missing the tap device.
     NetDeviceNotConfigured,
+    /// Cannot bind the memory of a NUMA node to its host node.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    NumaBind(io::Error),
     /// Cannot open the block device backing file.
     OpenBlockDevice(io::Error),
-    /// Cannot open console output file.
+    /// Cannot open the file or connect to the socket the console is redirected to.
     OpenConsoleFile(io::Error),
+    /// Cannot open the entropy source of the RNG device.
+    OpenRngSource(io::Error),
+    /// Cannot duplicate the file the guest panics are reported to.
+    OpenPanicOutput(io::Error),
+    /// Cannot start the software TPM backing the vTPM.
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    StartSwtpm(io::Error),
     /// Cannot initialize a MMIO Balloon device or add a device to the MMIO Bus.
     RegisterBalloonDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Block Device or add a device to the MMIO Bus.
@@ -125,14 +186,25 @@ pub enum StartMicrovmError {
     RegisterNetDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Rng device or add a device to the MMIO Bus.
     RegisterRngDevice(device_manager::mmio::Error),
+    /// Cannot register the SIGSYS handler reporting the syscalls seccomp traps.
+    #[cfg(target_os = "linux")]
+    RegisterSigsysHandler(utils::errno::Error),
     /// Cannot initialize a MMIO Snd device or add a device to the MMIO Bus.
     RegisterSndDevice(device_manager::mmio::Error),
+    /// Cannot add the TPM device to the MMIO Bus.
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    RegisterTpmDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus.
     RegisterVsockDevice(device_manager::mmio::Error),
     /// Cannot attest the VM in the Secure Virtualization context.
     SecureVirtAttest(VstateError),
     /// Cannot initialize the Secure Virtualization backend.
     SecureVirtPrepare(VstateError),
+    /// Cannot confine the thread running the event loop with seccomp.
+    #[cfg(target_os = "linux")]
+    Seccomp(seccomp::Error),
+    /// Cannot apply a resource limit to the VMM process.
+    SetRlimit(u32, io::Error),
     /// Error configuring an SHM region.
     ShmConfig(device_manager::shm::Error),
     /// Error creating an SHM region.
@@ -141,6 +213,9 @@ pub enum StartMicrovmError {
     ShmHostAddr(vm_memory::GuestMemoryError),
     /// The TEE specified is not supported.
     InvalidTee,
+    /// The TEE dry run completed, so the VM wasn't started.
+    #[cfg(feature = "tee")]
+    TeeDryRun,
 }
 
 /// It's convenient to automatically convert `kernel::cmdline::Error`s
@@ -158,7 +233,29 @@ impl Display for StartMicrovmError {
             AttachBlockDevice(ref err) => {
                 write!(f, "Unable to attach block device to Vmm. Error: {err}")
             }
+            Cancelled => write!(f, "The microVM build was cancelled"),
             CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {err}"),
+            #[cfg(target_arch = "x86_64")]
+            CreateWatchdog(ref err) => write!(f, "Cannot create the watchdog device: {err}"),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            FirmwareMmap(ref err) => {
+                write!(
+                    f,
+                    "Cannot map the firmware varstore in the guest memory: {err}"
+                )
+            }
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            FirmwareRead(ref err) => {
+                write!(
+                    f,
+                    "Cannot load the firmware due to an invalid image or varstore: {err}"
+                )
+            }
+            #[cfg(feature = "gpu")]
+            GpuRenderNodeWithoutEgl => write!(
+                f,
+                "The GPU render node is only honored with VIRGLRENDERER_USE_EGL."
+            ),
             GuestMemoryMmap(ref err) => {
                 // Remove imbricated quotes from error message.
                 let mut err_msg = format!("{err:?}");
@@ -169,8 +266,26 @@ impl Display for StartMicrovmError {
                 f,
                 "Cannot load initrd due to an invalid memory configuration."
             ),
+            HugePagesFile(ref err) => write!(
+                f,
+                "Cannot create the guest memory file in the hugetlbfs mount: {err}"
+            ),
+            HugePagesMmap(ref err) => write!(
+                f,
+                "Cannot allocate hugepages for the guest memory, check enough of them are \
+                 reserved in the host: {err}"
+            ),
             InitrdRead(ref err) => write!(f, "Cannot load initrd due to an invalid image: {err}"),
             Internal(ref err) => write!(f, "Internal error while starting microVM: {err:?}"),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            InvalidNumaConfig(ref err) => write!(f, "Invalid NUMA configuration: {err}"),
+            #[cfg(target_os = "linux")]
+            InvalidVcpuAffinity(vcpu) => {
+                write!(
+                    f,
+                    "Cannot set the affinity of vCPU {vcpu}, which doesn't exist"
+                )
+            }
             KernelCmdline(ref err) => write!(f, "Invalid kernel command line: {err}"),
             KernelBundle(ref err) => {
                 let mut err_msg = format!("{err}");
@@ -181,6 +296,8 @@ impl Display for StartMicrovmError {
                      bundle. {err_msg}"
                 )
             }
+            #[cfg(target_os = "linux")]
+            Landlock(ref err) => write!(f, "{err}"),
             LoadCommandline(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -194,6 +311,13 @@ impl Display for StartMicrovmError {
             NetDeviceNotConfigured => {
                 write!(f, "The net device configuration is missing the tap device.")
             }
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            NumaBind(ref err) => {
+                write!(
+                    f,
+                    "Cannot bind the memory of a NUMA node to its host node: {err}"
+                )
+            }
             OpenBlockDevice(ref err) => {
                 let mut err_msg = format!("{err:?}");
                 err_msg = err_msg.replace('\"', "");
@@ -204,7 +328,29 @@ impl Display for StartMicrovmError {
                 let mut err_msg = format!("{err:?}");
                 err_msg = err_msg.replace('\"', "");
 
-                write!(f, "Cannot open the console output file. {err_msg}")
+                write!(
+                    f,
+                    "Cannot open the file or socket the console is redirected to. {err_msg}"
+                )
+            }
+            OpenRngSource(ref err) => {
+                let mut err_msg = format!("{err:?}");
+                err_msg = err_msg.replace('\"', "");
+
+                write!(f, "Cannot open the RNG entropy source. {err_msg}")
+            }
+            OpenPanicOutput(ref err) => {
+                let mut err_msg = format!("{err:?}");
+                err_msg = err_msg.replace('\"', "");
+
+                write!(f, "Cannot open the guest panic output. {err_msg}")
+            }
+            #[cfg(all(target_os = "linux", not(feature = "tee")))]
+            StartSwtpm(ref err) => {
+                let mut err_msg = format!("{err:?}");
+                err_msg = err_msg.replace('\"', "");
+
+                write!(f, "Cannot start swtpm. {err_msg}")
             }
             RegisterBalloonDevice(ref err) => {
                 let mut err_msg = format!("{err}");
@@ -267,6 +413,8 @@ impl Display for StartMicrovmError {
                     "Cannot initialize a MMIO Rng Device or add a device to the MMIO Bus. {err_msg}"
                 )
             }
+            #[cfg(target_os = "linux")]
+            RegisterSigsysHandler(ref err) => write!(f, "Cannot register SIGSYS handler: {err}"),
             RegisterSndDevice(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -275,6 +423,12 @@ impl Display for StartMicrovmError {
                     "Cannot initialize a MMIO Snd Device or add a device to the MMIO Bus. {err_msg}"
                 )
             }
+            #[cfg(all(target_os = "linux", not(feature = "tee")))]
+            RegisterTpmDevice(ref err) => {
+                let mut err_msg = format!("{err}");
+                err_msg = err_msg.replace('\"', "");
+                write!(f, "Cannot add a TPM device to the MMIO Bus. {err_msg}")
+            }
             RegisterVsockDevice(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -302,6 +456,8 @@ impl Display for StartMicrovmError {
                     "Cannot initialize the Secure Virtualization backend. {err_msg}"
                 )
             }
+            #[cfg(target_os = "linux")]
+            Seccomp(ref err) => write!(f, "{err}"),
             ShmHostAddr(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace('\"', "");
@@ -311,6 +467,9 @@ impl Display for StartMicrovmError {
                     "Error obtaining the host address of an SHM region. {err_msg}"
                 )
             }
+            SetRlimit(resource, ref err) => {
+                write!(f, "Cannot set the limit of resource {resource}: {err}")
+            }
             ShmConfig(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace('\"', "");
@@ -326,6 +485,10 @@ impl Display for StartMicrovmError {
             InvalidTee => {
                 write!(f, "TEE selected is not currently supported")
             }
+            #[cfg(feature = "tee")]
+            TeeDryRun => {
+                write!(f, "TEE dry run completed, the VM was not started")
+            }
         }
     }
 }
@@ -355,6 +518,15 @@ pub fn build_microvm(
     _shutdown_efd: Option<EventFd>,
     #[cfg(target_os = "macos")] _map_sender: Sender<MemoryMapping>,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
+    check_cancelled(vm_resources)?;
+
+    // Applied first, as a memory lock limit may be needed to set up the guest memory.
+    for rlimit in vm_resources.host_rlimits.iter() {
+        rlimit
+            .apply()
+            .map_err(|e| StartMicrovmError::SetRlimit(rlimit.resource, e))?;
+    }
+
     #[cfg(not(feature = "efi"))]
     let kernel_bundle = vm_resources
         .kernel_bundle()
@@ -375,15 +547,35 @@ pub fn build_microvm(
         .initrd_bundle()
         .ok_or(StartMicrovmError::MissingKernelConfig)?;
 
+    // A user-provided firmware replaces qboot, and is measured in its place.
+    #[cfg(feature = "tee")]
+    let firmware_image = vm_resources
+        .firmware
+        .as_ref()
+        .map(FirmwareConfig::read_image)
+        .transpose()
+        .map_err(StartMicrovmError::FirmwareRead)?;
+    #[cfg(feature = "tee")]
+    let (firmware_host_addr, firmware_size) = match firmware_image {
+        Some(ref image) => (image.as_ptr() as u64, image.len()),
+        None => (qboot_bundle.host_addr, qboot_bundle.size),
+    };
+
+    // A user-provided initrd replaces the bundled one, and is loaded once the
+    // guest memory is laid out.
     #[cfg(feature = "tee")]
     let payload = Payload::Tee(
         kernel_region,
         kernel_bundle.guest_addr,
         kernel_bundle.size,
-        qboot_bundle.host_addr,
-        qboot_bundle.size,
+        firmware_host_addr,
+        firmware_size,
         initrd_bundle.host_addr,
-        initrd_bundle.size,
+        if vm_resources.initrd_path.is_some() {
+            0
+        } else {
+            initrd_bundle.size
+        },
     );
     #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
     let payload = Payload::KernelMmap(kernel_region, kernel_bundle.guest_addr, kernel_bundle.size);
@@ -403,61 +595,87 @@ pub fn build_microvm(
         Some(vm_resources),
         payload,
     )?;
+    check_cancelled(vm_resources)?;
     let vcpu_config = vm_resources.vcpu_config();
 
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    let numa_nodes = if vm_resources.numa.nodes.is_empty() {
+        Vec::new()
+    } else {
+        setup_numa_nodes(
+            &guest_memory,
+            &arch_memory_info,
+            &vm_resources.numa,
+            vm_resources.vm_config().mem_size_mib.unwrap(),
+            vcpu_config.vcpu_count,
+        )?
+    };
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee"))))]
+    let numa_nodes = Vec::new();
+
+    // Mapped past the NUMA layout, as the firmware isn't part of the guest RAM.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    let guest_memory = match vm_resources.firmware {
+        Some(ref firmware) => load_firmware(guest_memory, firmware)?,
+        None => guest_memory,
+    };
+
+    #[cfg(feature = "tee")]
+    let initrd_config = Some(match vm_resources.initrd_path.as_ref() {
+        Some(path) => load_initrd(&guest_memory, path)?,
+        None => InitrdConfig {
+            address: GuestAddress(arch::x86_64::layout::INITRD_SEV_START),
+            size: initrd_bundle.size,
+        },
+    });
+    #[cfg(not(feature = "tee"))]
+    let initrd_config = vm_resources
+        .initrd_path
+        .as_ref()
+        .map(|path| load_initrd(&guest_memory, path))
+        .transpose()?;
+
     // Clone the command-line so that a failed boot doesn't pollute the original.
     #[allow(unused_mut)]
     let mut kernel_cmdline = kernel::cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
-    match &vm_resources.boot_config.kernel_cmdline_prolog {
-        None => kernel_cmdline.insert_str(DEFAULT_KERNEL_CMDLINE).unwrap(),
-        Some(s) => kernel_cmdline.insert_str(s).unwrap(),
-    };
+    kernel_cmdline.insert_str(vm_resources.boot_config.kernel_cmdline())?;
+    // After the one of the embedder, so it takes precedence.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    if let Some(clocksource) = vm_resources.clocksource {
+        kernel_cmdline.insert_str(clocksource.cmdline())?;
+    }
 
     #[cfg(not(feature = "tee"))]
     #[allow(unused_mut)]
     let mut vm = setup_vm(&guest_memory)?;
 
     #[cfg(feature = "tee")]
-    let (kvm, mut vm) = {
-        let kvm = KvmContext::new()
-            .map_err(Error::KvmContext)
-            .map_err(StartMicrovmError::Internal)?;
-        let vm = setup_vm(&kvm, &guest_memory, vm_resources.tee_config())?;
-        (kvm, vm)
-    };
-
-    #[cfg(feature = "tee")]
-    let tee = vm_resources.tee_config().tee;
-
-    #[cfg(feature = "tee")]
-    let sev_launcher = match tee {
-        Tee::Sev => Some(
-            vm.sev_secure_virt_prepare(&guest_memory)
-                .map_err(StartMicrovmError::SecureVirtPrepare)?,
-        ),
-        _ => None,
-    };
+    let mut vm = setup_vm(
+        &guest_memory,
+        vm_resources.tee_config(),
+        &vm_resources.cancel,
+    )?;
+    check_cancelled(vm_resources)?;
 
     #[cfg(feature = "tee")]
-    let snp_launcher = match tee {
-        Tee::Snp => Some(
-            vm.snp_secure_virt_prepare(&guest_memory)
-                .map_err(StartMicrovmError::SecureVirtPrepare)?,
-        ),
-        _ => None,
-    };
+    vm.secure_virt_prepare(&guest_memory)
+        .map_err(StartMicrovmError::SecureVirtPrepare)?;
 
     #[cfg(feature = "tee")]
-    let measured_regions = {
+    let mut measured_regions = {
         println!("Injecting and measuring memory regions. This may take a while.");
 
-        let m = vec![
+        let initrd = initrd_config.as_ref().unwrap();
+        #[allow(unused_mut)]
+        let mut m = vec![
             MeasuredRegion {
-                guest_addr: arch::BIOS_START,
+                guest_addr: arch::x86_64::firmware_load_addr(firmware_size),
                 host_addr: guest_memory
-                    .get_host_address(GuestAddress(arch::BIOS_START))
+                    .get_host_address(GuestAddress(arch::x86_64::firmware_load_addr(
+                        firmware_size,
+                    )))
                     .unwrap() as u64,
-                size: qboot_bundle.size,
+                size: firmware_size,
             },
             MeasuredRegion {
                 guest_addr: kernel_bundle.guest_addr,
@@ -467,11 +685,9 @@ pub fn build_microvm(
                 size: kernel_bundle.size,
             },
             MeasuredRegion {
-                guest_addr: arch::x86_64::layout::INITRD_SEV_START,
-                host_addr: guest_memory
-                    .get_host_address(GuestAddress(arch::x86_64::layout::INITRD_SEV_START))
-                    .unwrap() as u64,
-                size: initrd_bundle.size,
+                guest_addr: initrd.address.raw_value(),
+                host_addr: guest_memory.get_host_address(initrd.address).unwrap() as u64,
+                size: initrd.size,
             },
             MeasuredRegion {
                 guest_addr: arch::x86_64::layout::ZERO_PAGE_START,
@@ -482,6 +698,19 @@ pub fn build_microvm(
             },
         ];
 
+        // Measured while still zeroed, so the guest knows anything it finds
+        // there was injected by the firmware.
+        #[cfg(feature = "amd-sev")]
+        if vm_resources.tee_config().tee == kbs_types::Tee::Sev {
+            m.push(MeasuredRegion {
+                guest_addr: arch::x86_64::layout::SEV_SECRET_START,
+                host_addr: guest_memory
+                    .get_host_address(GuestAddress(arch::x86_64::layout::SEV_SECRET_START))
+                    .unwrap() as u64,
+                size: arch::x86_64::layout::SEV_SECRET_SIZE,
+            });
+        }
+
         m
     };
 
@@ -516,6 +745,9 @@ pub fn build_microvm(
     .map_err(Error::CreateLegacyDevice)
     .map_err(StartMicrovmError::Internal)?;
 
+    #[cfg(target_arch = "x86_64")]
+    let watchdog_evt = attach_watchdog(vm_resources, &mut pio_device_manager, &mut kernel_cmdline)?;
+
     // Instantiate the MMIO device manager.
     // 'mmio_base' address has to be an address which is protected by the kernel
     // and is architectural specific.
@@ -525,6 +757,9 @@ pub fn build_microvm(
         (arch::IRQ_BASE, arch::IRQ_MAX),
     );
 
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    let panic_dump = attach_pvpanic(vm_resources, &mut mmio_device_manager)?;
+
     #[cfg(target_os = "macos")]
     let vcpu_list = {
         let cpu_count = vm_resources.vm_config().vcpu_count.unwrap();
@@ -578,6 +813,7 @@ pub fn build_microvm(
         setup_interrupt_controller(&mut vm, vcpu_config.vcpu_count)?;
         attach_legacy_devices(
             &vm,
+            vm_resources,
             &mut mmio_device_manager,
             &mut kernel_cmdline,
             serial_device,
@@ -604,6 +840,7 @@ pub fn build_microvm(
         setup_interrupt_controller(&mut vm, vcpu_config.vcpu_count)?;
         attach_legacy_devices(
             &vm,
+            vm_resources,
             &mut mmio_device_manager,
             &mut kernel_cmdline,
             intc.clone(),
@@ -622,20 +859,35 @@ pub fn build_microvm(
         exit_observers: Vec::new(),
         vm,
         mmio_device_manager,
+        #[cfg(not(feature = "tee"))]
+        balloon: None,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        #[cfg(target_arch = "x86_64")]
+        shutdown_evt: _shutdown_efd,
+        #[cfg(target_arch = "x86_64")]
+        shutdown_timeout: vm_resources.shutdown_timeout,
+        #[cfg(target_arch = "x86_64")]
+        shutdown_timeout_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
+            .map_err(Error::EventFd)
+            .map_err(StartMicrovmError::Internal)?,
+        #[cfg(target_arch = "x86_64")]
+        watchdog_evt,
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+        panic_dump,
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+        boot_state: None,
     };
 
     #[cfg(not(feature = "tee"))]
     attach_balloon_device(&mut vmm, event_manager, intc.clone())?;
     #[cfg(not(feature = "tee"))]
-    attach_rng_device(&mut vmm, event_manager, intc.clone())?;
-    attach_console_devices(
-        &mut vmm,
-        event_manager,
-        intc.clone(),
-        vm_resources.console_output.clone(),
-    )?;
+    attach_rng_device(&mut vmm, vm_resources, event_manager, intc.clone())?;
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    if let Some(ref state_dir) = vm_resources.tpm_state_dir {
+        attach_tpm_device(&mut vmm, state_dir)?;
+    }
+    attach_console_devices(&mut vmm, event_manager, intc.clone(), vm_resources)?;
 
     #[cfg(not(feature = "tee"))]
     let export_table: Option<ExportTable> = if cfg!(feature = "gpu") {
@@ -654,6 +906,7 @@ pub fn build_microvm(
             export_table.clone(),
             intc.clone(),
             virgl_flags,
+            vm_resources.gpu_render_node.clone(),
             #[cfg(target_os = "macos")]
             _map_sender.clone(),
         )?;
@@ -699,57 +952,105 @@ pub fn build_microvm(
 
     // Write the kernel command line to guest memory. This is x86_64 specific, since on
     // aarch64 the command line will be specified through the FDT.
-    #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+    #[cfg(target_arch = "x86_64")]
     load_cmdline(&vmm)?;
 
+    // The command line is only final once every device has been attached, so
+    // it's the last region to be measured.
     #[cfg(feature = "tee")]
-    let initrd_config = Some(InitrdConfig {
-        address: GuestAddress(arch::x86_64::layout::INITRD_SEV_START),
-        size: initrd_bundle.size,
+    measured_regions.push(MeasuredRegion {
+        guest_addr: arch::x86_64::layout::CMDLINE_START,
+        host_addr: vmm
+            .guest_memory
+            .get_host_address(GuestAddress(arch::x86_64::layout::CMDLINE_START))
+            .unwrap() as u64,
+        size: arch::round_up(vmm.kernel_cmdline.len() + 1, arch::PAGE_SIZE),
     });
 
-    #[cfg(not(feature = "tee"))]
-    let initrd_config = None;
-
     vmm.configure_system(
         vcpus.as_slice(),
         &initrd_config,
-        &vm_resources.smbios_oem_strings,
+        &vm_resources.smbios,
+        &numa_nodes,
     )
     .map_err(StartMicrovmError::Internal)?;
 
     #[cfg(feature = "tee")]
     {
-        match tee {
-            Tee::Sev => vmm
-                .kvm_vm()
-                .sev_secure_virt_attest(vmm.guest_memory(), measured_regions, sev_launcher.unwrap())
-                .map_err(StartMicrovmError::SecureVirtAttest)?,
-
-            Tee::Snp => {
-                let cpuid = kvm
-                    .fd()
-                    .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
-                    .map_err(VstateError::KvmCpuId)
-                    .map_err(StartMicrovmError::SecureVirtAttest)?;
-                vmm.kvm_vm()
-                    .snp_secure_virt_attest(
-                        cpuid,
-                        vmm.guest_memory(),
-                        measured_regions,
-                        snp_launcher.unwrap(),
-                    )
-                    .map_err(StartMicrovmError::SecureVirtAttest)?;
-            }
-            _ => return Err(StartMicrovmError::InvalidTee),
+        vmm.vm
+            .secure_virt_attest(
+                &vmm.guest_memory,
+                measured_regions,
+                vm_resources.vm_config().cpu_template,
+            )
+            .map_err(StartMicrovmError::SecureVirtAttest)?;
+
+        #[cfg(feature = "amd-sev")]
+        if vm_resources.tee_config().dry_run {
+            let evidence = vmm
+                .vm
+                .attestation_evidence()
+                .map_err(StartMicrovmError::SecureVirtAttest)?;
+            println!("TEE dry run completed. Attestation evidence:\n{evidence}");
+            return Err(StartMicrovmError::TeeDryRun);
         }
 
         println!("Starting TEE/microVM.");
     }
 
+    #[cfg(target_os = "linux")]
+    let mut vcpus = vcpus;
+    #[cfg(target_os = "linux")]
+    for (&id, cpus) in vm_resources.vcpu_affinity.iter() {
+        let vcpu = vcpus
+            .iter_mut()
+            .find(|vcpu| vcpu.cpu_index() == id)
+            .ok_or(StartMicrovmError::InvalidVcpuAffinity(id))?;
+        vcpu.set_affinity(cpus.clone());
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(filter) = vm_resources.seccomp_filter() {
+        register_sigsys_handler().map_err(StartMicrovmError::RegisterSigsysHandler)?;
+        for vcpu in vcpus.iter_mut() {
+            vcpu.set_seccomp_filter(filter.clone());
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    if let Some(ref reboot_evt) = vm_resources.reboot_evt {
+        vmm.boot_state = Some(save_boot_state(
+            &vmm,
+            &mut vcpus,
+            reboot_evt,
+            kernel_bundle,
+            vm_resources.firmware.is_some(),
+            initrd_config,
+            numa_nodes,
+        )?);
+    }
+
+    // Applied right before spawning the vCPU threads, so they inherit it.
+    #[cfg(target_os = "linux")]
+    if vm_resources.landlock {
+        apply_landlock(vm_resources)?;
+    }
+
+    // Last chance to give up before the guest starts running.
+    check_cancelled(vm_resources)?;
     vmm.start_vcpus(vcpus)
         .map_err(StartMicrovmError::Internal)?;
 
+    // The event loop runs on this thread, so confine it too, once the vcpu
+    // threads have set their affinity and installed their own filter. The
+    // device threads spawned by the vcpus when the guest activates them
+    // inherit theirs.
+    #[cfg(target_os = "linux")]
+    if let Some(filter) = vm_resources.seccomp_filter() {
+        filter.apply().map_err(StartMicrovmError::Seccomp)?;
+    }
+
+    vmm.resume_vcpus().map_err(StartMicrovmError::Internal)?;
+
     // Clippy thinks we don't need Arc<Mutex<...
     // but we don't want to change the event_manager interface
     #[allow(clippy::arc_with_non_send_sync)]
@@ -761,6 +1062,73 @@ pub fn build_microvm(
     Ok(vmm)
 }
 
+/// Saves what the guest starts again from when rebooting in place: the state
+/// of the VM and its vcpus, and the images loaded in the guest memory, which
+/// the guest is free to overwrite.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+fn save_boot_state(
+    vmm: &Vmm,
+    vcpus: &mut [Vcpu],
+    reboot_evt: &EventFd,
+    kernel_bundle: &KernelBundle,
+    firmware_boot: bool,
+    initrd: Option<InitrdConfig>,
+    numa_nodes: Vec<arch::NumaNode>,
+) -> std::result::Result<BootState, StartMicrovmError> {
+    for vcpu in vcpus.iter_mut() {
+        vcpu.save_boot_state()
+            .map_err(Error::Vcpu)
+            .map_err(StartMicrovmError::Internal)?;
+    }
+
+    let mut image_ranges = vec![(GuestAddress(kernel_bundle.guest_addr), kernel_bundle.size)];
+    if let Some(ref initrd) = initrd {
+        image_ranges.push((initrd.address, initrd.size));
+    }
+    // The firmware image is the region ending right below 4 GiB.
+    if firmware_boot {
+        if let Some(region) = vmm
+            .guest_memory
+            .find_region(GuestAddress(arch::x86_64::FIRST_ADDR_PAST_32BITS - 1))
+        {
+            image_ranges.push((region.start_addr(), region.len() as usize));
+        }
+    }
+    let mut images = Vec::with_capacity(image_ranges.len());
+    for (addr, size) in image_ranges {
+        let mut image = vec![0; size];
+        vmm.guest_memory
+            .read_slice(&mut image, addr)
+            .map_err(Error::BootImages)
+            .map_err(StartMicrovmError::Internal)?;
+        images.push((addr, image));
+    }
+
+    Ok(BootState {
+        reboot_evt: reboot_evt
+            .try_clone()
+            .map_err(Error::EventFd)
+            .map_err(StartMicrovmError::Internal)?,
+        vm_state: vmm
+            .vm
+            .save_state()
+            .map_err(Error::Vm)
+            .map_err(StartMicrovmError::Internal)?,
+        images,
+        initrd,
+        numa_nodes,
+    })
+}
+
+/// Stops the build once cancelled through the token of the resources. The
+/// parts already set up are torn down as they are dropped.
+fn check_cancelled(vm_resources: &VmResources) -> std::result::Result<(), StartMicrovmError> {
+    if vm_resources.cancel.is_cancelled() {
+        return Err(StartMicrovmError::Cancelled);
+    }
+    Ok(())
+}
+
 fn load_payload(
     guest_mem: GuestMemoryMmap,
     payload: Payload,
@@ -789,8 +1157,8 @@ fn load_payload(
             kernel_region,
             kernel_load_addr,
             kernel_size,
-            qboot_host_addr,
-            qboot_size,
+            firmware_host_addr,
+            firmware_size,
             initrd_host_addr,
             initrd_size,
         ) => {
@@ -800,10 +1168,13 @@ fn load_payload(
                 .write(kernel_data, GuestAddress(kernel_load_addr))
                 .unwrap();
 
-            let qboot_data =
-                unsafe { std::slice::from_raw_parts(qboot_host_addr as *mut u8, qboot_size) };
+            let firmware_data =
+                unsafe { std::slice::from_raw_parts(firmware_host_addr as *mut u8, firmware_size) };
             guest_mem
-                .write(qboot_data, GuestAddress(arch::BIOS_START))
+                .write(
+                    firmware_data,
+                    GuestAddress(arch::x86_64::firmware_load_addr(firmware_size)),
+                )
                 .unwrap();
 
             let initrd_data =
@@ -824,6 +1195,68 @@ fn load_payload(
     }
 }
 
+/// Maps the firmware image right below 4 GiB, and its varstore, if any, right
+/// below the image. The varstore is shared with its file, so the variables the
+/// guest sets are kept in it.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+fn load_firmware(
+    guest_mem: GuestMemoryMmap,
+    firmware: &FirmwareConfig,
+) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
+    let image = firmware
+        .read_image()
+        .map_err(StartMicrovmError::FirmwareRead)?;
+    let image_addr = GuestAddress(arch::x86_64::firmware_load_addr(image.len()));
+    let image_region = MmapRegion::new(image.len()).map_err(StartMicrovmError::FirmwareMmap)?;
+    let guest_mem = guest_mem
+        .insert_region(Arc::new(
+            GuestRegionMmap::new(image_region, image_addr)
+                .map_err(StartMicrovmError::GuestMemoryMmap)?,
+        ))
+        .map_err(StartMicrovmError::GuestMemoryMmap)?;
+    // The region was sized for the image.
+    guest_mem.write_slice(&image, image_addr).unwrap();
+
+    let Some((file, size)) = firmware
+        .open_varstore()
+        .map_err(StartMicrovmError::FirmwareRead)?
+    else {
+        return Ok(guest_mem);
+    };
+    let varstore_region = MmapRegion::build(
+        Some(FileOffset::new(file, 0)),
+        size as usize,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED,
+    )
+    .map_err(StartMicrovmError::FirmwareMmap)?;
+    guest_mem
+        .insert_region(Arc::new(
+            GuestRegionMmap::new(varstore_region, image_addr.unchecked_sub(size))
+                .map_err(StartMicrovmError::GuestMemoryMmap)?,
+        ))
+        .map_err(StartMicrovmError::GuestMemoryMmap)
+}
+
+/// Loads the initrd image at `path` into guest memory, at the address the
+/// architecture picks for its size.
+fn load_initrd(
+    guest_mem: &GuestMemoryMmap,
+    path: &Path,
+) -> std::result::Result<InitrdConfig, StartMicrovmError> {
+    let image = std::fs::read(path).map_err(StartMicrovmError::InitrdRead)?;
+    let address = arch::initrd_load_addr(guest_mem, image.len())
+        .map_err(|_| StartMicrovmError::InitrdLoad)?;
+    guest_mem
+        .write_slice(&image, GuestAddress(address))
+        .map_err(|_| StartMicrovmError::InitrdLoad)?;
+
+    Ok(InitrdConfig {
+        address: GuestAddress(address),
+        size: image.len(),
+    })
+}
+
 fn create_guest_memory(
     mem_size: usize,
     vm_resources: Option<&VmResources>,
@@ -842,18 +1275,22 @@ fn create_guest_memory(
             ref _kernel_region,
             kernel_load_addr,
             kernel_size,
-            _qboot_host_addr,
-            _qboot_size,
+            _firmware_host_addr,
+            firmware_size,
             _initrd_host_addr,
             _initrd_size,
-        ) => arch::arch_memory_regions(mem_size, kernel_load_addr, kernel_size),
-        #[cfg(test)]
+        ) => arch::arch_memory_regions(mem_size, kernel_load_addr, kernel_size, firmware_size),
+        #[cfg(all(test, not(feature = "tee")))]
         Payload::Empty => arch::arch_memory_regions(mem_size, 0, 0),
+        #[cfg(all(test, feature = "tee"))]
+        Payload::Empty => arch::arch_memory_regions(mem_size, 0, 0, arch::BIOS_SIZE),
     };
     #[cfg(target_arch = "aarch64")]
     let (arch_mem_info, mut arch_mem_regions) = arch::arch_memory_regions(mem_size);
 
     let mut shm_manager = ShmManager::new(&arch_mem_info);
+    #[cfg(target_os = "linux")]
+    let ram_regions = arch_mem_regions.len();
 
     if let Some(vm_resources) = vm_resources {
         #[cfg(not(feature = "tee"))]
@@ -874,6 +1311,13 @@ fn create_guest_memory(
         arch_mem_regions.extend(shm_manager.regions());
     }
 
+    #[cfg(target_os = "linux")]
+    let guest_mem = match vm_resources.and_then(|r| r.hugepages.as_ref()) {
+        Some(hugepages) => hugepages_guest_memory(&arch_mem_regions, ram_regions, hugepages)?,
+        None => GuestMemoryMmap::from_ranges(&arch_mem_regions)
+            .map_err(StartMicrovmError::GuestMemoryMmap)?,
+    };
+    #[cfg(not(target_os = "linux"))]
     let guest_mem = GuestMemoryMmap::from_ranges(&arch_mem_regions)
         .map_err(StartMicrovmError::GuestMemoryMmap)?;
 
@@ -882,7 +1326,156 @@ fn create_guest_memory(
     Ok((guest_mem, arch_mem_info, shm_manager))
 }
 
-#[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+/// Lays the NUMA nodes out over the guest RAM, and binds the memory of each one
+/// to its host node.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+fn setup_numa_nodes(
+    guest_mem: &GuestMemoryMmap,
+    arch_memory_info: &ArchMemoryInfo,
+    numa: &NumaConfig,
+    mem_size_mib: usize,
+    vcpu_count: u8,
+) -> std::result::Result<Vec<arch::NumaNode>, StartMicrovmError> {
+    numa.validate(mem_size_mib, vcpu_count)
+        .map_err(StartMicrovmError::InvalidNumaConfig)?;
+
+    // The kernel region fills the hole left for it, so the RAM is only split
+    // by the MMIO gap.
+    let mut ram: Vec<(GuestAddress, u64)> = Vec::new();
+    for region in guest_mem
+        .iter()
+        .filter(|region| region.start_addr().raw_value() < arch_memory_info.shm_start_addr)
+    {
+        match ram.last_mut() {
+            Some((start, len)) if start.raw_value() + *len == region.start_addr().raw_value() => {
+                *len += region.len()
+            }
+            _ => ram.push((region.start_addr(), region.len())),
+        }
+    }
+    let nodes = numa.layout(&ram);
+
+    for (node, config) in nodes.iter().zip(numa.nodes.iter()) {
+        for &(start, len) in node.memory.iter() {
+            for region in guest_mem.iter() {
+                let begin = start.raw_value().max(region.start_addr().raw_value());
+                let end = (start.raw_value() + len).min(region.last_addr().raw_value() + 1);
+                if begin >= end {
+                    continue;
+                }
+                // The range is part of the guest memory, as it comes from its regions.
+                let host_addr = guest_mem.get_host_address(GuestAddress(begin)).unwrap();
+                unsafe { config.bind(host_addr, (end - begin) as usize) }
+                    .map_err(StartMicrovmError::NumaBind)?;
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+/// Creates the guest memory with the first `ram_regions` of `regions`, the
+/// guest RAM, backed by hugepages. The remaining ones are SHM windows the
+/// devices map host memory into, so they are left as regular mappings.
+#[cfg(target_os = "linux")]
+fn hugepages_guest_memory(
+    regions: &[(GuestAddress, usize)],
+    ram_regions: usize,
+    hugepages: &HugePagesConfig,
+) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
+    let page_size = hugepages.page_size.bytes();
+    let (ram, shm) = regions.split_at(ram_regions);
+
+    // The RAM regions don't need to be aligned to the hugepage size, as they
+    // are laid out around the kernel and the MMIO hole, so each one is mapped
+    // rounded up to whole hugepages. All of them share the same file, one
+    // after the other.
+    let file = match &hugepages.hugetlbfs_path {
+        Some(path) => {
+            let file = hugetlbfs_file(path).map_err(StartMicrovmError::HugePagesFile)?;
+            let size: usize = ram
+                .iter()
+                .map(|(_, size)| arch::round_up(*size, page_size))
+                .sum();
+            file.set_len(size as u64)
+                .map_err(StartMicrovmError::HugePagesFile)?;
+            Some(file)
+        }
+        None => None,
+    };
+
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = match (&file, hugepages.page_size) {
+        (Some(_), _) => libc::MAP_SHARED,
+        (None, HugePageSize::Size2M) => {
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB
+        }
+        (None, HugePageSize::Size1G) => {
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_HUGE_1GB
+        }
+    };
+    let (fd, mut offset) = (file.as_ref().map_or(-1, |f| f.as_raw_fd()), 0);
+
+    let mut guest_regions = Vec::with_capacity(regions.len());
+    for &(addr, size) in ram {
+        let mapping_size = arch::round_up(size, page_size);
+        // No MAP_NORESERVE, so the mmap fails if there aren't enough hugepages
+        // instead of the VM being killed when touching the memory.
+        // Safe because a new mapping is created, without MAP_FIXED.
+        let host_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapping_size,
+                prot,
+                flags,
+                fd,
+                offset as libc::off_t,
+            )
+        };
+        if host_addr == libc::MAP_FAILED {
+            return Err(StartMicrovmError::HugePagesMmap(io::Error::last_os_error()));
+        }
+        offset += mapping_size;
+
+        // A hugetlb mapping can't be split in the middle of a hugepage, so
+        // changing the flags of a region that doesn't end on one fails. Mark
+        // the whole mapping as not copied to children here, so pinning the
+        // region when launching the encrypted guest leaves the flags unchanged.
+        #[cfg(feature = "tee")]
+        if unsafe { libc::madvise(host_addr, mapping_size, libc::MADV_DONTFORK) } < 0 {
+            return Err(StartMicrovmError::HugePagesMmap(io::Error::last_os_error()));
+        }
+
+        // The mapping is never unmapped, like the guest memory it backs, which
+        // is kept until the process exits. This makes it safe to use it for
+        // the region.
+        let region = unsafe { MmapRegion::build_raw(host_addr as *mut u8, size, prot, flags) }
+            .map_err(|e| StartMicrovmError::HugePagesMmap(io::Error::other(e)))?;
+        guest_regions
+            .push(GuestRegionMmap::new(region, addr).map_err(StartMicrovmError::GuestMemoryMmap)?);
+    }
+
+    for &(addr, size) in shm {
+        let region = MmapRegion::new(size)
+            .map_err(|e| StartMicrovmError::HugePagesMmap(io::Error::other(e)))?;
+        guest_regions
+            .push(GuestRegionMmap::new(region, addr).map_err(StartMicrovmError::GuestMemoryMmap)?);
+    }
+
+    GuestMemoryMmap::from_regions(guest_regions).map_err(StartMicrovmError::GuestMemoryMmap)
+}
+
+/// Creates an unlinked file in the hugetlbfs directory `path`.
+#[cfg(target_os = "linux")]
+fn hugetlbfs_file(path: &Path) -> io::Result<File> {
+    let (fd, file_path) = nix::unistd::mkstemp(&path.join("libkrun-XXXXXX"))?;
+    // Safe because the file descriptor was just created and isn't owned elsewhere.
+    let file = unsafe { File::from_raw_fd(fd) };
+    std::fs::remove_file(file_path)?;
+    Ok(file)
+}
+
+#[cfg(target_arch = "x86_64")]
 fn load_cmdline(vmm: &Vmm) -> std::result::Result<(), StartMicrovmError> {
     kernel::loader::load_cmdline(
         vmm.guest_memory(),
@@ -911,11 +1504,14 @@ pub(crate) fn setup_vm(
 }
 #[cfg(all(target_os = "linux", feature = "tee"))]
 pub(crate) fn setup_vm(
-    kvm: &KvmContext,
     guest_memory: &GuestMemoryMmap,
     tee_config: &TeeConfig,
+    cancel: &CancelToken,
 ) -> std::result::Result<Vm, StartMicrovmError> {
-    let mut vm = Vm::new(kvm.fd(), tee_config)
+    let kvm = KvmContext::new()
+        .map_err(Error::KvmContext)
+        .map_err(StartMicrovmError::Internal)?;
+    let mut vm = Vm::new(kvm.fd(), tee_config, cancel)
         .map_err(Error::Vm)
         .map_err(StartMicrovmError::Internal)?;
     vm.memory_init(guest_memory, kvm.max_memslots())
@@ -1008,9 +1604,121 @@ fn attach_legacy_devices(
     Ok(())
 }
 
+/// Confines the calling thread, and the threads it spawns from then on, to the
+/// host paths the microVM uses. Does nothing if the kernel lacks Landlock.
+#[cfg(target_os = "linux")]
+fn apply_landlock(vm_resources: &VmResources) -> std::result::Result<(), StartMicrovmError> {
+    // virglrenderer loads the host GPU drivers once the guest activates the device.
+    if vm_resources.gpu_virgl_flags.is_some() {
+        warn!("Not confining the VMM with Landlock, as the GPU device needs the host drivers");
+        return Ok(());
+    }
+
+    match LandlockRuleset::new(&vm_resources.landlock_rules())
+        .map_err(StartMicrovmError::Landlock)?
+    {
+        Some(ruleset) => ruleset.apply().map_err(StartMicrovmError::Landlock),
+        None => {
+            warn!("The kernel doesn't support Landlock, not confining the VMM");
+            Ok(())
+        }
+    }
+}
+
+/// Exposes the IB700 watchdog to the guest if configured. Returns the eventfd its
+/// expiry is signaled to and the exit code to stop the microVM with, unless the
+/// embedder handles the expiry itself.
+#[cfg(target_arch = "x86_64")]
+fn attach_watchdog(
+    vm_resources: &VmResources,
+    pio_device_manager: &mut PortIODeviceManager,
+    kernel_cmdline: &mut kernel::cmdline::Cmdline,
+) -> std::result::Result<Option<(EventFd, u8)>, StartMicrovmError> {
+    let Some(config) = vm_resources.watchdog.as_ref() else {
+        return Ok(None);
+    };
+
+    let exit_code = match config.action {
+        WatchdogAction::Reset => Some(FC_EXIT_CODE_OK),
+        WatchdogAction::PowerOff => Some(FC_EXIT_CODE_GENERIC_ERROR),
+        WatchdogAction::Notify(_) => None,
+    };
+    let expired_evt = match config.action {
+        WatchdogAction::Notify(ref evt) => evt.try_clone(),
+        _ => EventFd::new(utils::eventfd::EFD_NONBLOCK),
+    }
+    .map_err(Error::EventFd)
+    .map_err(StartMicrovmError::Internal)?;
+
+    let watchdog = devices::legacy::Ib700Watchdog::new(
+        expired_evt
+            .try_clone()
+            .map_err(Error::EventFd)
+            .map_err(StartMicrovmError::Internal)?,
+    )
+    .map_err(StartMicrovmError::CreateWatchdog)?;
+    pio_device_manager
+        .register_watchdog(Arc::new(Mutex::new(watchdog)))
+        .map_err(Error::LegacyIOBus)
+        .map_err(StartMicrovmError::Internal)?;
+
+    // The timeout is programmed by the guest driver.
+    kernel_cmdline.insert("ib700wdt.timeout", &config.timeout_secs.to_string())?;
+
+    Ok(exit_code.map(|exit_code| (expired_evt, exit_code)))
+}
+
+/// Exposes the pvpanic device to the guest if its panics are reported to the
+/// embedder or dumped. Returns the eventfd the panics are signaled to and the
+/// path to dump the guest to, if set.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+fn attach_pvpanic(
+    vm_resources: &VmResources,
+    mmio_device_manager: &mut MMIODeviceManager,
+) -> std::result::Result<Option<(EventFd, PathBuf)>, StartMicrovmError> {
+    if vm_resources.panic_output.is_none() && vm_resources.dump_on_crash.is_none() {
+        return Ok(None);
+    }
+
+    let output = match vm_resources.panic_output.as_ref() {
+        Some(output) => Some(
+            output
+                .try_clone()
+                .map_err(StartMicrovmError::OpenPanicOutput)?,
+        ),
+        None => None,
+    };
+    let panic_dump = match vm_resources.dump_on_crash.as_ref() {
+        Some(path) => Some((
+            EventFd::new(utils::eventfd::EFD_NONBLOCK)
+                .map_err(Error::EventFd)
+                .map_err(StartMicrovmError::Internal)?,
+            path.clone(),
+        )),
+        None => None,
+    };
+    let panic_evt = match panic_dump.as_ref() {
+        Some((panic_evt, _)) => Some(
+            panic_evt
+                .try_clone()
+                .map_err(Error::EventFd)
+                .map_err(StartMicrovmError::Internal)?,
+        ),
+        None => None,
+    };
+
+    mmio_device_manager
+        .register_mmio_pvpanic(output, panic_evt)
+        .map_err(Error::RegisterMMIODevice)
+        .map_err(StartMicrovmError::Internal)?;
+
+    Ok(panic_dump)
+}
+
 #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
 fn attach_legacy_devices(
     vm: &Vm,
+    vm_resources: &VmResources,
     mmio_device_manager: &mut MMIODeviceManager,
     kernel_cmdline: &mut kernel::cmdline::Cmdline,
     serial: Option<Arc<Mutex<Serial>>>,
@@ -1027,12 +1735,27 @@ fn attach_legacy_devices(
         .map_err(Error::RegisterMMIODevice)
         .map_err(StartMicrovmError::Internal)?;
 
+    if let Some(output) = vm_resources.panic_output.as_ref() {
+        mmio_device_manager
+            .register_mmio_pvpanic(
+                Some(
+                    output
+                        .try_clone()
+                        .map_err(StartMicrovmError::OpenPanicOutput)?,
+                ),
+                None,
+            )
+            .map_err(Error::RegisterMMIODevice)
+            .map_err(StartMicrovmError::Internal)?;
+    }
+
     Ok(())
 }
 
 #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
 fn attach_legacy_devices(
     vm: &Vm,
+    vm_resources: &VmResources,
     mmio_device_manager: &mut MMIODeviceManager,
     kernel_cmdline: &mut kernel::cmdline::Cmdline,
     intc: Option<GicV3>,
@@ -1052,6 +1775,17 @@ fn attach_legacy_devices(
         .map_err(Error::RegisterMMIODevice)
         .map_err(StartMicrovmError::Internal)?;
 
+    if let Some(output) = vm_resources.panic_output.as_ref() {
+        mmio_device_manager
+            .register_mmio_pvpanic(
+                output
+                    .try_clone()
+                    .map_err(StartMicrovmError::OpenPanicOutput)?,
+            )
+            .map_err(Error::RegisterMMIODevice)
+            .map_err(StartMicrovmError::Internal)?;
+    }
+
     mmio_device_manager
         .register_mmio_gic(vm, intc.clone())
         .map_err(Error::RegisterMMIODevice)
@@ -1172,6 +1906,7 @@ fn attach_mmio_device(
         .lock()
         .expect("Poisoned device lock")
         .device_type();
+    METRICS.register_device(&id, &device, vmm.guest_memory());
     let _cmdline = &mut vmm.kernel_cmdline;
 
     #[cfg(target_os = "linux")]
@@ -1212,6 +1947,11 @@ fn attach_fs_devices(
             fs.lock().unwrap().set_intc(intc.clone());
         }
 
+        fs.lock().unwrap().set_read_only(config.read_only);
+        fs.lock()
+            .unwrap()
+            .set_id_maps(config.uid_map.clone(), config.gid_map.clone());
+
         if let Some(shm_region) = shm_manager.fs_region(i) {
             fs.lock().unwrap().set_shm_region(VirtioShmRegion {
                 host_addr: vmm
@@ -1243,20 +1983,77 @@ fn attach_fs_devices(
     Ok(())
 }
 
+/// Opens the host end of a redirected console stream. If `path` is a unix
+/// socket, the VMM connects to it and also reads the guest input from it.
+/// Otherwise, the output is written to the file at `path`, created or
+/// truncated, and there's no input.
+fn open_console_target(
+    path: &Path,
+) -> io::Result<(
+    Box<dyn port_io::PortInput + Send>,
+    Box<dyn port_io::PortOutput + Send>,
+)> {
+    let is_socket = path
+        .metadata()
+        .map(|m| m.file_type().is_socket())
+        .unwrap_or(false);
+
+    if is_socket {
+        let stream = UnixStream::connect(path)?;
+        Ok((
+            port_io::input_from_raw_fd_dup(stream.as_raw_fd())?,
+            port_io::output_to_raw_fd_dup(stream.as_raw_fd())?,
+        ))
+    } else {
+        let file = File::create(path)?;
+        Ok((port_io::input_empty()?, port_io::output_file(file)?))
+    }
+}
+
 fn attach_console_devices(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
     intc: Option<GicV3>,
-    console_output: Option<PathBuf>,
+    vm_resources: &VmResources,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
-    let ports = if let Some(console_output) = console_output {
-        let file = File::create(console_output.as_path()).map_err(OpenConsoleFile)?;
-        vec![PortDescription::Console {
-            input: Some(port_io::input_empty().unwrap()),
-            output: Some(port_io::output_file(file).unwrap()),
-        }]
+    let redirected = vm_resources.console_output.is_some()
+        || vm_resources.stdout_output.is_some()
+        || vm_resources.stderr_output.is_some();
+
+    // When anything is redirected, the VMM leaves its own stdio alone, so the
+    // console output goes to the log unless it's redirected as well.
+    let mut ports = if redirected {
+        let (input, output) = match &vm_resources.console_output {
+            Some(path) => open_console_target(path).map_err(OpenConsoleFile)?,
+            None => (
+                port_io::input_empty().unwrap(),
+                port_io::output_to_log_as_err(),
+            ),
+        };
+        let mut ports = vec![PortDescription::Console {
+            input: Some(input),
+            output: Some(output),
+        }];
+
+        if let Some(path) = &vm_resources.stdout_output {
+            let (_, output) = open_console_target(path).map_err(OpenConsoleFile)?;
+            ports.push(PortDescription::OutputPipe {
+                name: "krun-stdout".into(),
+                output,
+            });
+        }
+
+        if let Some(path) = &vm_resources.stderr_output {
+            let (_, output) = open_console_target(path).map_err(OpenConsoleFile)?;
+            ports.push(PortDescription::OutputPipe {
+                name: "krun-stderr".into(),
+                output,
+            });
+        }
+
+        ports
     } else {
         let stdin_is_terminal = isatty(STDIN_FILENO).unwrap_or(false);
         let stdout_is_terminal = isatty(STDOUT_FILENO).unwrap_or(false);
@@ -1315,6 +2112,13 @@ fn attach_console_devices(
         ports
     };
 
+    if let Some(config) = &vm_resources.init_config {
+        ports.push(PortDescription::InputPipe {
+            name: "krun-config".into(),
+            input: port_io::input_from_bytes(config.clone()),
+        });
+    }
+
     let console = Arc::new(Mutex::new(devices::virtio::Console::new(ports).unwrap()));
 
     vmm.exit_observers.push(console.clone());
@@ -1391,6 +2195,8 @@ fn attach_unixsock_vsock_device(
     )
     .map_err(RegisterVsockDevice)?;
 
+    vmm.exit_observers.push(unix_vsock.clone());
+
     Ok(())
 }
 
@@ -1418,10 +2224,12 @@ fn attach_balloon_device(
     attach_mmio_device(
         vmm,
         id,
-        MmioTransport::new(vmm.guest_memory().clone(), balloon),
+        MmioTransport::new(vmm.guest_memory().clone(), balloon.clone()),
     )
     .map_err(RegisterBalloonDevice)?;
 
+    vmm.balloon = Some(balloon);
+
     Ok(())
 }
 
@@ -1455,12 +2263,20 @@ fn attach_block_devices(
 #[cfg(not(feature = "tee"))]
 fn attach_rng_device(
     vmm: &mut Vmm,
+    vm_resources: &VmResources,
     event_manager: &mut EventManager,
     intc: Option<GicV3>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
-    let rng = Arc::new(Mutex::new(devices::virtio::Rng::new().unwrap()));
+    let source = match vm_resources.rng_source {
+        Some(ref path) => Some(File::open(path).map_err(OpenRngSource)?),
+        None => None,
+    };
+
+    let rng = Arc::new(Mutex::new(
+        devices::virtio::Rng::new(source, vm_resources.rng_rate_limit).unwrap(),
+    ));
 
     event_manager
         .add_subscriber(rng.clone())
@@ -1479,6 +2295,22 @@ fn attach_rng_device(
     Ok(())
 }
 
+#[cfg(all(target_os = "linux", not(feature = "tee")))]
+fn attach_tpm_device(
+    vmm: &mut Vmm,
+    state_dir: &Path,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let backend = devices::legacy::start_swtpm(state_dir).map_err(StartSwtpm)?;
+
+    vmm.mmio_device_manager
+        .register_mmio_tpm(&mut vmm.kernel_cmdline, backend)
+        .map_err(RegisterTpmDevice)?;
+
+    Ok(())
+}
+
 #[cfg(feature = "gpu")]
 fn attach_gpu_device(
     vmm: &mut Vmm,
@@ -1487,13 +2319,21 @@ fn attach_gpu_device(
     #[cfg(not(feature = "tee"))] mut export_table: Option<ExportTable>,
     intc: Option<GicV3>,
     virgl_flags: u32,
+    render_node: Option<PathBuf>,
     #[cfg(target_os = "macos")] map_sender: Sender<MemoryMapping>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
+    // virglrenderer only renders with the render node it's handed through EGL.
+    const VIRGLRENDERER_USE_EGL: u32 = 1 << 0;
+    if render_node.is_some() && virgl_flags & VIRGLRENDERER_USE_EGL == 0 {
+        return Err(GpuRenderNodeWithoutEgl);
+    }
+
     let gpu = Arc::new(Mutex::new(
         devices::virtio::Gpu::new(
             virgl_flags,
+            render_node,
             #[cfg(target_os = "macos")]
             map_sender,
         )
@@ -1554,7 +2394,8 @@ fn attach_snd_device(
     Ok(())
 }
 
-#[cfg(test)]
+// These tests build plain VMs, which can't be created with a TEE enabled.
+#[cfg(all(test, not(feature = "tee")))]
 pub mod tests {
     use super::*;
 
@@ -1588,6 +2429,9 @@ pub mod tests {
             vcpu_count,
             ht_enabled: false,
             cpu_template: None,
+            cpu_topology: None,
+            firmware_boot: false,
+            tsc_khz: None,
         };
 
         // Dummy entry_addr, vcpus will not boot.
@@ -1617,6 +2461,7 @@ pub mod tests {
             vcpu_count,
             ht_enabled: false,
             cpu_template: None,
+            cpu_topology: None,
         };
 
         // Dummy entry_addr, vcpus will not boot.
@@ -1638,9 +2483,24 @@ pub mod tests {
         let err = AttachBlockDevice(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = Cancelled;
+        let _ = format!("{}{:?}", err, err);
+
         let err = CreateRateLimiter(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+        {
+            let err = FirmwareMmap(vm_memory::mmap::MmapRegionError::InvalidPointer);
+            let _ = format!("{}{:?}", err, err);
+        }
+
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        {
+            let err = FirmwareRead(io::Error::from_raw_os_error(0));
+            let _ = format!("{}{:?}", err, err);
+        }
+
         let err = Internal(Error::Serial(io::Error::from_raw_os_error(0)));
         let _ = format!("{}{:?}", err, err);
 
@@ -1689,6 +2549,19 @@ pub mod tests {
         let _ = format!("{}{:?}", err, err);
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_build_cancelled() {
+        let vm_resources = VmResources::default();
+        vm_resources.cancel.cancel();
+        let mut event_manager = EventManager::new().unwrap();
+
+        assert!(matches!(
+            build_microvm(&vm_resources, &mut event_manager, None),
+            Err(StartMicrovmError::Cancelled)
+        ));
+    }
+
     #[test]
     fn test_kernel_cmdline_err_to_startuvm_err() {
         let err = StartMicrovmError::from(kernel::cmdline::Error::HasSpace);
@@ -6,6 +6,8 @@
 // found in the THIRD-PARTY file.
 
 use std::collections::HashMap;
+#[cfg(target_arch = "aarch64")]
+use std::fs::File;
 use std::sync::{Arc, Mutex};
 use std::{fmt, io};
 
@@ -275,6 +277,30 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    #[cfg(target_arch = "aarch64")]
+    /// Register a MMIO pvpanic device reporting the guest panics to `output`.
+    pub fn register_mmio_pvpanic(&mut self, output: File) -> Result<()> {
+        let device = devices::legacy::PvPanic::new(Some(output), None);
+
+        self.bus
+            .insert(Arc::new(Mutex::new(device)), self.mmio_base, MMIO_LEN)
+            .map_err(Error::BusError)?;
+
+        let ret = self.mmio_base;
+        self.id_to_dev_info.insert(
+            (DeviceType::PvPanic, "pvpanic".to_string()),
+            MMIODeviceInfo {
+                addr: ret,
+                len: MMIO_LEN,
+                irq: 0,
+            },
+        );
+
+        self.mmio_base += MMIO_LEN;
+
+        Ok(())
+    }
+
     #[cfg(target_arch = "aarch64")]
     /// Gets the information of the devices registered up to some point in time.
     pub fn get_device_info(&self) -> &HashMap<(DeviceType, String), MMIODeviceInfo> {
@@ -6,6 +6,10 @@
 // found in the THIRD-PARTY file.
 
 use std::collections::HashMap;
+#[cfg(any(target_arch = "aarch64", not(feature = "tee")))]
+use std::fs::File;
+#[cfg(not(feature = "tee"))]
+use std::os::unix::net::UnixStream;
 use std::sync::{Arc, Mutex};
 use std::{fmt, io};
 
@@ -17,8 +21,10 @@ use devices;
 use devices::BusDevice;
 use kernel::cmdline as kernel_cmdline;
 use kvm_ioctls::{IoEventAddress, VmFd};
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", not(feature = "tee")))]
 use utils::eventfd::EventFd;
+#[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+use vm_memory::GuestAddress;
 
 /// Errors for MMIO device manager.
 #[allow(clippy::enum_variant_names)]
@@ -40,6 +46,9 @@ pub enum Error {
     DeviceNotFound,
     /// Failed to update the mmio device.
     UpdateFailed,
+    /// The device doesn't support being reset.
+    #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+    ResetUnsupported(String),
 }
 
 impl fmt::Display for Error {
@@ -55,6 +64,10 @@ impl fmt::Display for Error {
             Error::RegisterIrqFd(ref e) => write!(f, "failed to register irqfd: {e}"),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
+            #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+            Error::ResetUnsupported(ref id) => {
+                write!(f, "the device {id} doesn't support being reset")
+            }
         }
     }
 }
@@ -74,6 +87,8 @@ pub struct MMIODeviceManager {
     irq: u32,
     last_irq: u32,
     id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
+    #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+    transports: Vec<(String, Arc<Mutex<devices::virtio::MmioTransport>>)>,
 }
 
 impl MMIODeviceManager {
@@ -88,6 +103,8 @@ impl MMIODeviceManager {
             last_irq: irq_interval.1,
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
+            #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+            transports: Vec::new(),
         }
     }
 
@@ -120,9 +137,12 @@ impl MMIODeviceManager {
         vm.register_irqfd(mmio_device.locked_device().interrupt_evt(), self.irq)
             .map_err(Error::RegisterIrqFd)?;
 
+        let mmio_device = Arc::new(Mutex::new(mmio_device));
         self.bus
-            .insert(Arc::new(Mutex::new(mmio_device)), self.mmio_base, MMIO_LEN)
+            .insert(mmio_device.clone(), self.mmio_base, MMIO_LEN)
             .map_err(Error::BusError)?;
+        #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+        self.transports.push((device_id.clone(), mmio_device));
         let ret = (self.mmio_base, self.irq);
         self.id_to_dev_info.insert(
             (DeviceType::Virtio(type_id), device_id),
@@ -234,12 +254,114 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    #[cfg(any(target_arch = "aarch64", not(feature = "tee")))]
+    /// Register a MMIO pvpanic device reporting the guest panics to `output`
+    /// and signaling them to `panic_evt`.
+    pub fn register_mmio_pvpanic(
+        &mut self,
+        output: Option<File>,
+        panic_evt: Option<EventFd>,
+    ) -> Result<()> {
+        let device = devices::legacy::PvPanic::new(output, panic_evt);
+
+        self.bus
+            .insert(Arc::new(Mutex::new(device)), self.mmio_base, MMIO_LEN)
+            .map_err(Error::BusError)?;
+
+        let ret = self.mmio_base;
+        self.id_to_dev_info.insert(
+            (DeviceType::PvPanic, "pvpanic".to_string()),
+            MMIODeviceInfo {
+                addr: ret,
+                _len: MMIO_LEN,
+                _irq: 0,
+            },
+        );
+
+        self.mmio_base += MMIO_LEN;
+
+        Ok(())
+    }
+
+    /// Register a MMIO TPM device forwarding the guest commands to `backend`.
+    #[cfg(not(feature = "tee"))]
+    pub fn register_mmio_tpm(
+        &mut self,
+        cmdline: &mut kernel_cmdline::Cmdline,
+        backend: UnixStream,
+    ) -> Result<()> {
+        let device = devices::legacy::Tpm::new(backend);
+
+        // x86_64 guests have no firmware table describing the TPM, so the driver is forced to
+        // probe it at its standard address. aarch64 guests find it in the FDT instead.
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.bus
+                .insert(
+                    Arc::new(Mutex::new(device)),
+                    arch::x86_64::layout::TPM_TIS_START,
+                    devices::legacy::TPM_TIS_SIZE,
+                )
+                .map_err(Error::BusError)?;
+
+            cmdline
+                .insert_str("tpm_tis.force=1 tpm_tis.interrupts=0")
+                .map_err(Error::Cmdline)?;
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            let _ = cmdline;
+            self.bus
+                .insert(
+                    Arc::new(Mutex::new(device)),
+                    self.mmio_base,
+                    devices::legacy::TPM_TIS_SIZE,
+                )
+                .map_err(Error::BusError)?;
+
+            self.id_to_dev_info.insert(
+                (DeviceType::Tpm, "tpm".to_string()),
+                MMIODeviceInfo {
+                    addr: self.mmio_base,
+                    _len: devices::legacy::TPM_TIS_SIZE,
+                    _irq: 0,
+                },
+            );
+
+            self.mmio_base += devices::legacy::TPM_TIS_SIZE;
+        }
+
+        Ok(())
+    }
+
     #[cfg(target_arch = "aarch64")]
     /// Gets the information of the devices registered up to some point in time.
     pub fn get_device_info(&self) -> &HashMap<(DeviceType, String), MMIODeviceInfo> {
         &self.id_to_dev_info
     }
 
+    /// Gets the address range of the pvpanic device, if registered, for the
+    /// ACPI tables describing it.
+    #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+    pub fn pvpanic_range(&self) -> Option<(GuestAddress, u64)> {
+        self.id_to_dev_info
+            .get(&(DeviceType::PvPanic, "pvpanic".to_string()))
+            .map(|info| (GuestAddress(info.addr), info._len))
+    }
+
+    /// Resets all the virtio devices, as if their drivers had done it, so they
+    /// can be set up again by a rebooted guest.
+    #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+    pub fn reset_devices(&self) -> Result<()> {
+        for (device_id, transport) in &self.transports {
+            if !transport.lock().unwrap().reset_device() {
+                return Err(Error::ResetUnsupported(device_id.clone()));
+            }
+        }
+        Ok(())
+    }
+
     /// Gets the the specified device.
     pub fn get_device(
         &self,
@@ -279,7 +401,8 @@ impl DeviceInfoForFDT for MMIODeviceInfo {
     }
 }
 
-#[cfg(test)]
+// These tests build plain VMs, which can't be created with a TEE enabled.
+#[cfg(all(test, not(feature = "tee")))]
 mod tests {
     use super::super::super::super::builder;
     use super::*;
@@ -415,6 +538,9 @@ mod tests {
         assert!(device_manager
             .register_virtio_device(vm.fd(), guest_mem, dummy, &mut cmdline, 0, "dummy")
             .is_ok());
+        // The dummy device was never activated, so there's nothing to reset.
+        #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+        assert!(device_manager.reset_devices().is_ok());
     }
 
     #[test]
@@ -499,6 +625,11 @@ mod tests {
             format!("{}", Error::UpdateFailed),
             "failed to update the mmio device"
         );
+        #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+        assert_eq!(
+            format!("{}", Error::ResetUnsupported("net".to_string())),
+            "the device net doesn't support being reset"
+        );
         assert_eq!(
             format!("{}", Error::BusError(devices::BusError::Overlap)),
             format!(
@@ -121,6 +121,20 @@ impl PortIODeviceManager {
             .map_err(Error::BusError)?;
         Ok(())
     }
+
+    /// Registers the IB700 watchdog at its standard ports.
+    pub fn register_watchdog(
+        &mut self,
+        watchdog: Arc<Mutex<devices::legacy::Ib700Watchdog>>,
+    ) -> Result<()> {
+        self.io_bus
+            .insert(
+                watchdog,
+                devices::legacy::IB700_BASE,
+                devices::legacy::IB700_LEN,
+            )
+            .map_err(Error::BusError)
+    }
 }
 
 #[cfg(test)]
@@ -3,6 +3,10 @@ use std::collections::BTreeMap;
 use arch::{round_up, ArchMemoryInfo};
 use vm_memory::GuestAddress;
 
+/// virtio-fs maps files into the DAX window in chunks of this size.
+#[cfg(not(feature = "tee"))]
+pub const FS_DAX_ALIGNMENT: usize = 2 << 20;
+
 #[derive(Debug)]
 pub enum Error {
     DuplicatedGpuRegion,
@@ -64,11 +68,14 @@ impl ShmManager {
             size,
         };
 
-        if let Some(addr) = self.next_guest_addr.checked_add(size as u64) {
-            self.next_guest_addr = addr;
-            Ok(region)
-        } else {
-            Err(Error::OutOfSpace)
+        match self.next_guest_addr.checked_add(size as u64) {
+            #[cfg(target_arch = "x86_64")]
+            Some(addr) if addr > arch::x86_64::layout::GUEST_PHYS_END => Err(Error::OutOfSpace),
+            Some(addr) => {
+                self.next_guest_addr = addr;
+                Ok(region)
+            }
+            None => Err(Error::OutOfSpace),
         }
     }
 
@@ -81,10 +88,52 @@ impl ShmManager {
         }
     }
 
+    /// Creates the DAX window of a virtio-fs device, rounded up to `FS_DAX_ALIGNMENT`.
     #[cfg(not(feature = "tee"))]
     pub fn create_fs_region(&mut self, index: usize, size: usize) -> Result<(), Error> {
-        let region = self.create_region(size)?;
+        let region = self.create_region(round_up(size, FS_DAX_ALIGNMENT))?;
         self.fs_regions.insert(index, region);
         Ok(())
     }
 }
+
+#[cfg(all(test, not(feature = "tee")))]
+mod tests {
+    use super::*;
+
+    fn shm_manager() -> ShmManager {
+        ShmManager::new(&ArchMemoryInfo {
+            ram_last_addr: 1 << 30,
+            shm_start_addr: 1 << 32,
+            page_size: 4096,
+        })
+    }
+
+    #[test]
+    fn test_fs_region_rounded_to_dax_alignment() {
+        let mut shm_manager = shm_manager();
+
+        shm_manager.create_fs_region(0, 0x1000).unwrap();
+        shm_manager.create_fs_region(1, 3 << 20).unwrap();
+
+        let first = shm_manager.fs_region(0).unwrap();
+        assert_eq!(first.guest_addr, GuestAddress(1 << 32));
+        assert_eq!(first.size, FS_DAX_ALIGNMENT);
+        let second = shm_manager.fs_region(1).unwrap();
+        assert_eq!(second.guest_addr, GuestAddress((1 << 32) + (2 << 20)));
+        assert_eq!(second.size, 4 << 20);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_fs_region_out_of_space() {
+        let mut shm_manager = shm_manager();
+        let available = arch::x86_64::layout::GUEST_PHYS_END - (1 << 32);
+
+        assert!(matches!(
+            shm_manager.create_fs_region(0, available as usize + 1),
+            Err(Error::OutOfSpace)
+        ));
+        shm_manager.create_fs_region(0, available as usize).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+//! Landlock ruleset confining the VMM threads to the host paths they use.
+//!
+//! Landlock only restricts opening and creating files, so the ruleset only
+//! needs rules for the paths accessed once the microVM is built. The kernel,
+//! the disks, the firmware and the TEE certificates and caches are opened while
+//! building it, before the ruleset is applied.
+
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+// Filesystem access rights, as of Landlock ABI v1.
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+/// Moving files to another directory, handled since ABI v2.
+const ACCESS_FS_REFER: u64 = 1 << 13;
+/// Truncating files, handled since ABI v3.
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// The rights a rule on a file, rather than a directory, may grant.
+const ACCESS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+/// `struct landlock_ruleset_attr`, up to the field ABI v1 knows of.
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr`.
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot add the rule of a path to the ruleset.
+    AddRule(PathBuf, io::Error),
+    /// Cannot create the ruleset.
+    CreateRuleset(io::Error),
+    /// Cannot forbid the thread from gaining privileges.
+    NoNewPrivs(io::Error),
+    /// Cannot open a path to add its rule.
+    OpenPath(PathBuf, io::Error),
+    /// Cannot confine the thread with the ruleset.
+    RestrictSelf(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AddRule(path, e) => write!(f, "Cannot add the Landlock rule of {path:?}: {e}"),
+            CreateRuleset(e) => write!(f, "Cannot create the Landlock ruleset: {e}"),
+            NoNewPrivs(e) => write!(f, "Cannot set PR_SET_NO_NEW_PRIVS: {e}"),
+            OpenPath(path, e) => write!(f, "Cannot open {path:?}: {e}"),
+            RestrictSelf(e) => write!(f, "Cannot apply the Landlock ruleset: {e}"),
+        }
+    }
+}
+
+/// What the VMM threads may do beneath a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathAccess {
+    /// Read files and list directories.
+    ReadOnly,
+    /// Do anything but executing files.
+    ReadWrite,
+    /// Create regular files and write them.
+    CreateFiles,
+    /// Create unix sockets and remove them.
+    Sockets,
+}
+
+impl PathAccess {
+    fn rights(self) -> u64 {
+        match self {
+            PathAccess::ReadOnly => ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+            PathAccess::ReadWrite => {
+                (ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE) & !ACCESS_FS_EXECUTE
+            }
+            PathAccess::CreateFiles => {
+                ACCESS_FS_MAKE_REG | ACCESS_FS_WRITE_FILE | ACCESS_FS_TRUNCATE
+            }
+            PathAccess::Sockets => ACCESS_FS_MAKE_SOCK | ACCESS_FS_REMOVE_FILE,
+        }
+    }
+}
+
+/// A Landlock ruleset denying the filesystem accesses its rules don't allow.
+pub struct LandlockRuleset(OwnedFd);
+
+/// Returns the Landlock ABI version of the kernel, or `None` if it doesn't
+/// support Landlock or has it disabled.
+fn abi_version() -> Option<u32> {
+    // Safe because a null attribute with this flag only queries the version.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    (ret > 0).then_some(ret as u32)
+}
+
+impl LandlockRuleset {
+    /// Builds a ruleset allowing only the accesses of `rules`, each applying to a
+    /// path and everything beneath it. Returns `None` if the kernel doesn't
+    /// support Landlock.
+    pub fn new(rules: &[(PathBuf, PathAccess)]) -> Result<Option<Self>, Error> {
+        let Some(abi) = abi_version() else {
+            return Ok(None);
+        };
+        // Rights of later ABIs aren't handled, and so stay allowed.
+        let handled = match abi {
+            1 => ACCESS_FS_V1,
+            2 => ACCESS_FS_V1 | ACCESS_FS_REFER,
+            _ => ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE,
+        };
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // Safe because `attr` outlives the call and its size is passed along.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::CreateRuleset(io::Error::last_os_error()));
+        }
+        // Safe because the kernel just returned this fd, which nothing else owns.
+        let ruleset = LandlockRuleset(unsafe { OwnedFd::from_raw_fd(ret as i32) });
+
+        for (path, access) in rules {
+            ruleset.add_rule(path, access.rights() & handled)?;
+        }
+
+        Ok(Some(ruleset))
+    }
+
+    fn add_rule(&self, path: &Path, mut rights: u64) -> Result<(), Error> {
+        let open_error = |e| Error::OpenPath(path.to_path_buf(), e);
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| open_error(io::Error::from(io::ErrorKind::InvalidInput)))?;
+        // Safe because `c_path` is a valid C string, and the returned fd is checked.
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(open_error(io::Error::last_os_error()));
+        }
+        // Safe because the fd was just opened, and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        if !path.is_dir() {
+            rights &= ACCESS_FILE;
+        }
+        let attr = PathBeneathAttr {
+            allowed_access: rights,
+            parent_fd: fd.as_raw_fd(),
+        };
+        // Safe because `attr` outlives the call, and the kernel validates it.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.0.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::AddRule(
+                path.to_path_buf(),
+                io::Error::last_os_error(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Confines the calling thread with the ruleset. The threads it spawns from
+    /// then on inherit it.
+    pub fn apply(&self) -> Result<(), Error> {
+        // Safe because this only sets a flag on the calling thread.
+        let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+        if ret != 0 {
+            return Err(Error::NoNewPrivs(io::Error::last_os_error()));
+        }
+
+        // Safe because the ruleset fd is valid for the whole call.
+        let ret = unsafe { libc::syscall(libc::SYS_landlock_restrict_self, self.0.as_raw_fd(), 0) };
+        if ret != 0 {
+            return Err(Error::RestrictSelf(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn test_rights() {
+        assert_eq!(PathAccess::ReadWrite.rights() & ACCESS_FS_EXECUTE, 0);
+        assert_ne!(PathAccess::ReadWrite.rights() & ACCESS_FS_REFER, 0);
+        assert_eq!(PathAccess::ReadOnly.rights() & ACCESS_FS_WRITE_FILE, 0);
+        assert_eq!(
+            PathAccess::CreateFiles.rights() & !ACCESS_FILE,
+            ACCESS_FS_MAKE_REG
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let dir = std::env::temp_dir().join(format!("krun-landlock-{}", std::process::id()));
+        let allowed = dir.join("allowed");
+        fs::create_dir_all(&allowed).unwrap();
+        fs::write(dir.join("denied"), b"").unwrap();
+
+        let rules = [(allowed.clone(), PathAccess::ReadWrite)];
+        // The ruleset outlives the thread it's applied to, so don't touch the
+        // test runner one.
+        let confined = thread::spawn(move || {
+            let Some(ruleset) = LandlockRuleset::new(&rules).unwrap() else {
+                return false;
+            };
+            ruleset.apply().unwrap();
+            fs::write(allowed.join("file"), b"").unwrap();
+            let denied = fs::read(allowed.parent().unwrap().join("denied")).unwrap_err();
+            assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied);
+            true
+        })
+        .join()
+        .unwrap();
+
+        if confined {
+            assert!(dir.join("allowed/file").exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_path() {
+        let rules = [(PathBuf::from("/nonexistent/krun"), PathAccess::ReadOnly)];
+        match LandlockRuleset::new(&rules) {
+            Ok(None) => {}
+            Err(Error::OpenPath(path, _)) => assert_eq!(path, rules[0].0),
+            _ => panic!("Unexpected result"),
+        }
+    }
+}
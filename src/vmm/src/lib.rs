@@ -16,8 +16,16 @@ extern crate log;
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
 pub(crate) mod device_manager;
+/// Landlock ruleset for the VMM threads.
+#[cfg(target_os = "linux")]
+pub mod landlock;
+/// Counters of what the VMM is doing.
+pub mod metrics;
 /// Resource store for configured microVM resources.
 pub mod resources;
+/// Seccomp filters for the VMM threads.
+#[cfg(target_os = "linux")]
+pub mod seccomp;
 /// Signal handling utilities.
 #[cfg(target_os = "linux")]
 pub mod signal_handler;
@@ -26,8 +34,20 @@ pub mod vmm_config;
 
 #[cfg(target_os = "linux")]
 mod linux;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use crate::linux::coredump;
+#[cfg(all(target_os = "linux", feature = "amd-sev"))]
+pub use crate::linux::tee::amdsev::{platform_status, Phase, PlatformState, PlatformStatus};
+#[cfg(all(target_os = "linux", feature = "amd-sev"))]
+pub use crate::linux::tee::amdsnp::maa_token;
+#[cfg(all(target_os = "linux", feature = "amd-sev"))]
+pub use crate::linux::tee::http::{HttpClient, HttpError};
+#[cfg(all(target_os = "linux", feature = "amd-sev"))]
+pub use crate::linux::tee::{LaunchMeasurement, LaunchMetrics, TeeKind};
 #[cfg(target_os = "linux")]
 use crate::linux::vstate;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub use crate::linux::vstate::VcpuRegs;
 #[cfg(target_os = "macos")]
 mod macos;
 mod terminal;
@@ -40,7 +60,11 @@ use macos::vstate;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::os::unix::io::AsRawFd;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+#[cfg(target_arch = "x86_64")]
+use std::thread;
 #[cfg(target_os = "linux")]
 use std::time::Duration;
 
@@ -55,6 +79,8 @@ use crate::vstate::{Vcpu, VcpuHandle, VcpuResponse, Vm};
 use arch::{ArchMemoryInfo, DeviceType, InitrdConfig};
 #[cfg(target_os = "macos")]
 use crossbeam_channel::Sender;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::Balloon;
 use devices::virtio::VmmExitObserver;
 use devices::BusDevice;
 use kernel::cmdline::Cmdline as KernelCmdline;
@@ -85,8 +111,14 @@ pub const FC_EXIT_CODE_ARG_PARSING: u8 = 153;
 /// have permissions to open the KVM fd).
 #[derive(Debug)]
 pub enum Error {
+    /// Cannot save or restore the images the guest boots from.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    BootImages(vm_memory::GuestMemoryError),
     /// This error is thrown by the minimal boot loader implementation.
     ConfigureSystem(arch::Error),
+    /// Cannot write the guest crash dump.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    CoreDump(coredump::Error),
     /// Legacy devices work with Event file descriptors and the creation can fail because
     /// of resource exhaustion.
     #[cfg(target_arch = "x86_64")]
@@ -107,10 +139,19 @@ pub enum Error {
     LegacyIOBus(device_manager::legacy::Error),
     /// Cannot load command line.
     LoadCommandline(kernel::cmdline::Error),
+    /// The guest can't be rebooted in place, as it wasn't enabled.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    RebootDisabled,
     /// Cannot add a device to the MMIO Bus.
     RegisterMMIODevice(device_manager::mmio::Error),
+    /// Cannot reset the devices for the guest to reboot.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    ResetDevices(device_manager::mmio::Error),
     /// Write to the serial console failed.
     Serial(io::Error),
+    /// Cannot spawn the thread enforcing the shutdown timeout.
+    #[cfg(target_arch = "x86_64")]
+    ShutdownTimer(io::Error),
     /// Cannot create Timer file descriptor.
     TimerFd(io::Error),
     /// Vcpu error.
@@ -119,6 +160,14 @@ pub enum Error {
     VcpuEvent(vstate::Error),
     /// Cannot create a vCPU handle.
     VcpuHandle(vstate::Error),
+    /// There's no vCPU with this index.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    VcpuIndex(usize),
+    /// vCPU pause failed.
+    VcpuPause,
+    /// The vCPU registers are encrypted by the TEE.
+    #[cfg(feature = "amd-sev")]
+    VcpuRegsUnavailable,
     /// vCPU resume failed.
     VcpuResume,
     /// Cannot spawn a new Vcpu thread.
@@ -136,7 +185,11 @@ impl Display for Error {
         use self::Error::*;
 
         match self {
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            BootImages(e) => write!(f, "Cannot save or restore the boot images: {e}"),
             ConfigureSystem(e) => write!(f, "System configuration error: {e:?}"),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            CoreDump(e) => write!(f, "Cannot write the guest crash dump: {e}"),
             #[cfg(target_arch = "x86_64")]
             CreateLegacyDevice(e) => write!(f, "Error creating legacy device: {e:?}"),
             EventFd(e) => write!(f, "Event fd error: {e}"),
@@ -147,12 +200,23 @@ impl Display for Error {
             #[cfg(target_arch = "x86_64")]
             LegacyIOBus(e) => write!(f, "Cannot add devices to the legacy I/O Bus. {e}"),
             LoadCommandline(e) => write!(f, "Cannot load command line: {e}"),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            RebootDisabled => write!(f, "Rebooting the guest in place isn't enabled"),
             RegisterMMIODevice(e) => write!(f, "Cannot add a device to the MMIO Bus. {e}"),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            ResetDevices(e) => write!(f, "Cannot reset the devices: {e}"),
             Serial(e) => write!(f, "Error writing to the serial console: {e:?}"),
+            #[cfg(target_arch = "x86_64")]
+            ShutdownTimer(e) => write!(f, "Cannot spawn the shutdown timer thread: {e}"),
             TimerFd(e) => write!(f, "Error creating timer fd: {e}"),
             Vcpu(e) => write!(f, "Vcpu error: {e}"),
             VcpuEvent(e) => write!(f, "Cannot send event to vCPU. {e:?}"),
             VcpuHandle(e) => write!(f, "Cannot create a vCPU handle. {e}"),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            VcpuIndex(index) => write!(f, "There's no vCPU {index}"),
+            VcpuPause => write!(f, "vCPUs pause failed."),
+            #[cfg(feature = "amd-sev")]
+            VcpuRegsUnavailable => write!(f, "vCPU registers are unavailable under TEE"),
             VcpuResume => write!(f, "vCPUs resume failed."),
             VcpuSpawn(e) => write!(f, "Cannot spawn Vcpu thread: {e}"),
             Vm(e) => write!(f, "Vm error: {e}"),
@@ -182,6 +246,18 @@ pub trait VmmEventsObserver {
 /// Shorthand result type for internal VMM commands.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// What a guest rebooting in place starts again from.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+pub(crate) struct BootState {
+    /// Signaled by the user to reboot the guest.
+    pub(crate) reboot_evt: EventFd,
+    pub(crate) vm_state: vstate::VmState,
+    /// The kernel, initrd and firmware, as they were loaded in the guest memory.
+    pub(crate) images: Vec<(vm_memory::GuestAddress, Vec<u8>)>,
+    pub(crate) initrd: Option<InitrdConfig>,
+    pub(crate) numa_nodes: Vec<arch::NumaNode>,
+}
+
 /// Contains the state and associated methods required for the Firecracker VMM.
 pub struct Vmm {
     // Guest VM core resources.
@@ -197,8 +273,31 @@ pub struct Vmm {
 
     // Guest VM devices.
     mmio_device_manager: MMIODeviceManager,
+    #[cfg(not(feature = "tee"))]
+    balloon: Option<Arc<Mutex<Balloon>>>,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+
+    // Orderly shutdown requested by the user, and the deadline for the guest to honor it.
+    #[cfg(target_arch = "x86_64")]
+    shutdown_evt: Option<EventFd>,
+    #[cfg(target_arch = "x86_64")]
+    shutdown_timeout: Option<Duration>,
+    #[cfg(target_arch = "x86_64")]
+    shutdown_timeout_evt: EventFd,
+
+    // Expiry of the guest watchdog, and the exit code to stop the microVM with.
+    #[cfg(target_arch = "x86_64")]
+    watchdog_evt: Option<(EventFd, u8)>,
+
+    // Panics reported by the guest through pvpanic, and where to dump the guest to on them
+    // and on crashes.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    panic_dump: Option<(EventFd, PathBuf)>,
+
+    // What the guest starts again from when rebooting in place, if enabled.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    boot_state: Option<BootState>,
 }
 
 impl Vmm {
@@ -211,7 +310,8 @@ impl Vmm {
         self.mmio_device_manager.get_device(device_type, device_id)
     }
 
-    /// Starts the microVM vcpus.
+    /// Starts the microVM vcpus, in the `Paused` state. They run once resumed
+    /// with `resume_vcpus`.
     pub fn start_vcpus(&mut self, mut vcpus: Vec<Vcpu>) -> Result<()> {
         let vcpu_count = vcpus.len();
 
@@ -226,9 +326,6 @@ impl Vmm {
                 .push(vcpu.start_threaded().map_err(Error::VcpuHandle)?);
         }
 
-        // The vcpus start off in the `Paused` state, let them run.
-        self.resume_vcpus()?;
-
         Ok(())
     }
 
@@ -257,28 +354,208 @@ impl Vmm {
         Ok(())
     }
 
+    /// Sends a pause command to the vcpus.
+    #[cfg(target_os = "linux")]
+    pub fn pause_vcpus(&mut self) -> Result<()> {
+        for handle in self.vcpus_handles.iter() {
+            handle
+                .send_event(VcpuEvent::Pause)
+                .map_err(Error::VcpuEvent)?;
+        }
+        for handle in self.vcpus_handles.iter() {
+            match handle
+                .response_receiver()
+                .recv_timeout(Duration::from_millis(1000))
+            {
+                Ok(VcpuResponse::Paused) => (),
+                _ => return Err(Error::VcpuPause),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the general-purpose, segment and control registers of the vcpu
+    /// `index`, to diagnose the guest. The vcpu must be paused, e.g. with
+    /// `pause_vcpus`, or stopped by a crash. The registers of SEV-ES and SNP
+    /// guests are encrypted, so they are unavailable.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn get_vcpu_regs(&self, index: usize) -> Result<VcpuRegs> {
+        let handle = self.regs_vcpu_handle(index)?;
+        let (regs_sender, regs_receiver) = crossbeam_channel::bounded(1);
+        handle
+            .send_event(VcpuEvent::GetRegs(regs_sender))
+            .map_err(Error::VcpuEvent)?;
+        regs_receiver
+            .recv_timeout(Duration::from_millis(1000))
+            .map_err(|_| Error::VcpuPause)?
+            .map_err(Error::Vcpu)
+    }
+
+    /// Overwrites the registers of the vcpu `index`, which must be paused as
+    /// for `get_vcpu_regs`. Only available in debug builds.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", debug_assertions))]
+    pub fn set_vcpu_regs(&self, index: usize, regs: VcpuRegs) -> Result<()> {
+        let handle = self.regs_vcpu_handle(index)?;
+        let (result_sender, result_receiver) = crossbeam_channel::bounded(1);
+        handle
+            .send_event(VcpuEvent::SetRegs(Box::new(regs), result_sender))
+            .map_err(Error::VcpuEvent)?;
+        result_receiver
+            .recv_timeout(Duration::from_millis(1000))
+            .map_err(|_| Error::VcpuPause)?
+            .map_err(Error::Vcpu)
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn regs_vcpu_handle(&self, index: usize) -> Result<&VcpuHandle> {
+        #[cfg(feature = "amd-sev")]
+        if self.vm.encrypted_state() {
+            return Err(Error::VcpuRegsUnavailable);
+        }
+        self.vcpus_handles.get(index).ok_or(Error::VcpuIndex(index))
+    }
+
+    /// Reads `buf.len()` bytes of guest memory at `guest_addr` in clear, for
+    /// debugging SEV guests whose launch allowed it.
+    #[cfg(feature = "amd-sev")]
+    pub fn debug_read_guest_memory(&self, guest_addr: u64, buf: &mut [u8]) -> Result<()> {
+        self.vm
+            .debug_decrypt(&self.guest_memory, vm_memory::GuestAddress(guest_addr), buf)
+            .map_err(Error::Vm)
+    }
+
+    /// Writes `buf` to guest memory at `guest_addr` in clear, for debugging
+    /// SEV guests whose launch allowed it.
+    #[cfg(feature = "amd-sev")]
+    pub fn debug_write_guest_memory(&self, guest_addr: u64, buf: &[u8]) -> Result<()> {
+        self.vm
+            .debug_encrypt(&self.guest_memory, vm_memory::GuestAddress(guest_addr), buf)
+            .map_err(Error::Vm)
+    }
+
+    /// Writes the guest RAM and the registers of the vcpus to `path` as an ELF
+    /// core file. The vcpus are left paused.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    pub fn dump_guest_core(&mut self, path: &Path) -> Result<()> {
+        self.pause_vcpus()?;
+        let vcpu_regs = (0..self.vcpus_handles.len())
+            .map(|index| self.get_vcpu_regs(index))
+            .collect::<Result<Vec<_>>>()?;
+
+        let file =
+            std::fs::File::create(path).map_err(|e| Error::CoreDump(coredump::Error::Io(e)))?;
+        let mut writer = io::BufWriter::new(file);
+        coredump::write_core(
+            &mut writer,
+            &vcpu_regs,
+            &self.guest_memory,
+            vm_memory::GuestAddress(self.arch_memory_info.shm_start_addr),
+        )
+        .map_err(Error::CoreDump)?;
+        io::Write::flush(&mut writer).map_err(|e| Error::CoreDump(coredump::Error::Io(e)))
+    }
+
+    /// Dumps the guest if it reported a panic it wasn't dumped for yet, and
+    /// lets it go on with whatever its kernel does after panicking.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    fn dump_on_panic(&mut self) {
+        let Some((panic_evt, path)) = self.panic_dump.as_ref() else {
+            return;
+        };
+        // The eventfd is nonblocking, so this fails unless there was a panic.
+        if panic_evt.read().is_err() {
+            return;
+        }
+
+        let path = path.clone();
+        match self.dump_guest_core(&path) {
+            Ok(()) => info!("Wrote the guest crash dump to {}", path.display()),
+            Err(e) => error!("Failed to dump the panicked guest: {e}"),
+        }
+        if let Err(e) = self.resume_vcpus() {
+            error!("Failed to resume the guest after dumping it: {e}");
+        }
+    }
+
+    /// Reboots the guest without tearing down the microVM: the devices and
+    /// the vcpus are reset, and the images the guest booted from are loaded
+    /// again. Fails if a device doesn't support being reset.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    pub fn reboot(&mut self) -> Result<()> {
+        if self.boot_state.is_none() {
+            return Err(Error::RebootDisabled);
+        }
+        self.pause_vcpus()?;
+        self.mmio_device_manager
+            .reset_devices()
+            .map_err(Error::ResetDevices)?;
+
+        let boot_state = self.boot_state.as_ref().unwrap();
+        for (addr, image) in boot_state.images.iter() {
+            vm_memory::Bytes::write_slice(&self.guest_memory, image, *addr)
+                .map_err(Error::BootImages)?;
+        }
+        kernel::loader::load_cmdline(
+            &self.guest_memory,
+            vm_memory::GuestAddress(arch::x86_64::layout::CMDLINE_START),
+            &self
+                .kernel_cmdline
+                .as_cstring()
+                .map_err(Error::LoadCommandline)?,
+        )
+        .map_err(Error::LoadCommandline)?;
+        arch::x86_64::configure_system(
+            &self.guest_memory,
+            &self.arch_memory_info,
+            vm_memory::GuestAddress(arch::x86_64::layout::CMDLINE_START),
+            self.kernel_cmdline.len() + 1,
+            &boot_state.initrd,
+            self.vcpus_handles.len() as u8,
+            &boot_state.numa_nodes,
+            self.mmio_device_manager.pvpanic_range(),
+        )
+        .map_err(Error::ConfigureSystem)?;
+        self.vm
+            .restore_state(&boot_state.vm_state)
+            .map_err(Error::Vm)?;
+
+        for handle in self.vcpus_handles.iter() {
+            let (result_sender, result_receiver) = crossbeam_channel::bounded(1);
+            handle
+                .send_event(VcpuEvent::Reset(result_sender))
+                .map_err(Error::VcpuEvent)?;
+            result_receiver
+                .recv_timeout(Duration::from_millis(1000))
+                .map_err(|_| Error::VcpuPause)?
+                .map_err(Error::Vcpu)?;
+        }
+
+        info!("Rebooting the guest in place");
+        self.resume_vcpus()
+    }
+
     /// Configures the system for boot.
     pub fn configure_system(
         &self,
         vcpus: &[Vcpu],
         initrd: &Option<InitrdConfig>,
-        _smbios_oem_strings: &Option<Vec<String>>,
+        _smbios: &smbios::SmbiosConfig,
+        _numa_nodes: &[arch::NumaNode],
     ) -> Result<()> {
         #[cfg(target_arch = "x86_64")]
         {
-            let cmdline_len = if cfg!(feature = "tee") {
-                arch::x86_64::layout::CMDLINE_SEV_SIZE
-            } else {
-                self.kernel_cmdline.len() + 1
-            };
-
             arch::x86_64::configure_system(
                 &self.guest_memory,
                 &self.arch_memory_info,
                 vm_memory::GuestAddress(arch::x86_64::layout::CMDLINE_START),
-                cmdline_len,
+                self.kernel_cmdline.len() + 1,
                 initrd,
                 vcpus.len() as u8,
+                _numa_nodes,
+                #[cfg(not(feature = "tee"))]
+                self.mmio_device_manager.pvpanic_range(),
+                #[cfg(feature = "tee")]
+                None,
             )
             .map_err(Error::ConfigureSystem)?;
         }
@@ -294,7 +571,7 @@ impl Vmm {
                 self.mmio_device_manager.get_device_info(),
                 self.vm.get_irqchip(),
                 initrd,
-                _smbios_oem_strings,
+                _smbios,
             )
             .map_err(Error::ConfigureSystem)?;
         }
@@ -310,7 +587,7 @@ impl Vmm {
                 self.mmio_device_manager.get_device_info(),
                 self.vm.get_irqchip(),
                 initrd,
-                _smbios_oem_strings,
+                _smbios,
             )
             .map_err(Error::ConfigureSystem)?;
         }
@@ -333,6 +610,31 @@ impl Vmm {
             .map_err(Error::I8042Error)
     }
 
+    /// Asks the guest to shut down by injecting CTRL+ALT+DEL and, if a shutdown timeout
+    /// is configured, arms a timer that stops the microVM once it expires.
+    #[cfg(target_arch = "x86_64")]
+    pub fn request_shutdown(&mut self) -> Result<()> {
+        self.send_ctrl_alt_del()?;
+
+        if let Some(timeout) = self.shutdown_timeout {
+            let timeout_evt = self
+                .shutdown_timeout_evt
+                .try_clone()
+                .map_err(Error::EventFd)?;
+            thread::Builder::new()
+                .name("shutdown timer".into())
+                .spawn(move || {
+                    thread::sleep(timeout);
+                    if let Err(e) = timeout_evt.write(1) {
+                        error!("Failed to signal the shutdown timeout: {e}");
+                    }
+                })
+                .map_err(Error::ShutdownTimer)?;
+        }
+
+        Ok(())
+    }
+
     /// Waits for all vCPUs to exit and terminates the Firecracker process.
     pub fn stop(&mut self, exit_code: i32) {
         info!("Vmm is stopping.");
@@ -355,6 +657,12 @@ impl Vmm {
         }
     }
 
+    /// Returns the balloon device, to resize it at runtime.
+    #[cfg(not(feature = "tee"))]
+    pub fn balloon(&self) -> Option<Arc<Mutex<Balloon>>> {
+        self.balloon.clone()
+    }
+
     /// Returns a reference to the inner KVM Vm object.
     pub fn kvm_vm(&self) -> &Vm {
         &self.vm
@@ -384,30 +692,151 @@ impl Subscriber for Vmm {
         let source = event.fd();
         let event_set = event.event_set();
 
+        #[cfg(target_arch = "x86_64")]
+        if let Some(shutdown_evt) = self.shutdown_evt.as_ref() {
+            if source == shutdown_evt.as_raw_fd() && event_set == EventSet::IN {
+                let _ = shutdown_evt.read();
+                if let Err(e) = self.request_shutdown() {
+                    error!("Failed to request the guest to shut down: {e}");
+                }
+                return;
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if source == self.shutdown_timeout_evt.as_raw_fd() && event_set == EventSet::IN {
+            let _ = self.shutdown_timeout_evt.read();
+            warn!("Guest didn't shut down in time, stopping the microVM");
+            self.stop(i32::from(FC_EXIT_CODE_GENERIC_ERROR));
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some((watchdog_evt, exit_code)) = self.watchdog_evt.as_ref() {
+            if source == watchdog_evt.as_raw_fd() && event_set == EventSet::IN {
+                let _ = watchdog_evt.read();
+                let exit_code = *exit_code;
+                warn!("Guest watchdog expired, stopping the microVM");
+                self.stop(i32::from(exit_code));
+            }
+        }
+
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+        if let Some((panic_evt, _)) = self.panic_dump.as_ref() {
+            if source == panic_evt.as_raw_fd() && event_set == EventSet::IN {
+                self.dump_on_panic();
+                return;
+            }
+        }
+
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+        if let Some(boot_state) = self.boot_state.as_ref() {
+            if source == boot_state.reboot_evt.as_raw_fd() && event_set == EventSet::IN {
+                let _ = boot_state.reboot_evt.read();
+                if let Err(e) = self.reboot() {
+                    error!("Failed to reboot the guest, stopping the microVM: {e}");
+                    self.stop(i32::from(FC_EXIT_CODE_GENERIC_ERROR));
+                }
+                return;
+            }
+        }
+
         if source == self.exit_evt.as_raw_fd() && event_set == EventSet::IN {
             let _ = self.exit_evt.read();
             // Query each vcpu for the exit_code.
             // If the exit_code can't be found on any vcpu, it means that the exit signal
             // has been issued by the i8042 controller in which case we exit with
             // FC_EXIT_CODE_OK.
-            let exit_code = self
-                .vcpus_handles
-                .iter()
-                .find_map(|handle| match handle.response_receiver().try_recv() {
-                    Ok(VcpuResponse::Exited(exit_code)) => Some(exit_code),
+            let response = self.vcpus_handles.iter().find_map(|handle| {
+                match handle.response_receiver().try_recv() {
+                    Ok(response @ VcpuResponse::Exited(_)) => Some(response),
+                    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+                    Ok(response @ VcpuResponse::Crashed(_)) => Some(response),
                     _ => None,
-                })
-                .unwrap_or(FC_EXIT_CODE_OK);
-            self.stop(i32::from(exit_code));
+                }
+            });
+
+            // A vcpu crashing is the guest hitting a triple fault, or KVM failing
+            // to run it.
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            if let (Some(VcpuResponse::Crashed(_)), Some((_, path))) =
+                (&response, self.panic_dump.as_ref())
+            {
+                let path = path.clone();
+                match self.dump_guest_core(&path) {
+                    Ok(()) => info!("Wrote the guest crash dump to {}", path.display()),
+                    Err(e) => error!("Failed to dump the crashed guest: {e}"),
+                }
+            }
+
+            let exit_code = match response {
+                Some(VcpuResponse::Exited(exit_code)) => Some(exit_code),
+                #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+                Some(VcpuResponse::Crashed(exit_code)) => Some(exit_code),
+                _ => None,
+            };
+
+            // A reset from the i8042 controller, rather than a vcpu exiting, is
+            // the guest rebooting. A guest rebooting right after a panic may get
+            // here before the panic is handled, so it's dumped first.
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            if exit_code.is_none() {
+                self.dump_on_panic();
+                if self.boot_state.is_some() {
+                    match self.reboot() {
+                        Ok(()) => return,
+                        Err(e) => {
+                            error!("Failed to reboot the guest, stopping the microVM: {e}")
+                        }
+                    }
+                }
+            }
+
+            self.stop(i32::from(exit_code.unwrap_or(FC_EXIT_CODE_OK)));
         } else {
             error!("Spurious EventManager event for handler: Vmm");
         }
     }
 
     fn interest_list(&self) -> Vec<EpollEvent> {
-        vec![EpollEvent::new(
+        #[allow(unused_mut)]
+        let mut events = vec![EpollEvent::new(
             EventSet::IN,
             self.exit_evt.as_raw_fd() as u64,
-        )]
+        )];
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(shutdown_evt) = self.shutdown_evt.as_ref() {
+                events.push(EpollEvent::new(
+                    EventSet::IN,
+                    shutdown_evt.as_raw_fd() as u64,
+                ));
+            }
+            events.push(EpollEvent::new(
+                EventSet::IN,
+                self.shutdown_timeout_evt.as_raw_fd() as u64,
+            ));
+            if let Some((watchdog_evt, _)) = self.watchdog_evt.as_ref() {
+                events.push(EpollEvent::new(
+                    EventSet::IN,
+                    watchdog_evt.as_raw_fd() as u64,
+                ));
+            }
+        }
+
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+        if let Some((panic_evt, _)) = self.panic_dump.as_ref() {
+            events.push(EpollEvent::new(EventSet::IN, panic_evt.as_raw_fd() as u64));
+        }
+
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+        if let Some(boot_state) = self.boot_state.as_ref() {
+            events.push(EpollEvent::new(
+                EventSet::IN,
+                boot_state.reboot_evt.as_raw_fd() as u64,
+            ));
+        }
+
+        events
     }
 }
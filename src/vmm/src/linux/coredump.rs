@@ -0,0 +1,409 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest crash dumps in the ELF core format.
+//!
+//! A dump holds the guest RAM as PT_LOAD segments at their guest physical
+//! addresses, and the registers of each vCPU as an NT_PRSTATUS note followed
+//! by a QEMU CPU state note, the same layout as QEMU's dump-guest-memory, so
+//! dumps can be analyzed with the crash utility or gdb.
+//!
+//! Confidential guests can't be dumped, as their memory and register state are
+//! encrypted with keys that never leave the secure processor.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+
+use kvm_bindings::kvm_segment;
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+use super::vstate::VcpuRegs;
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 7;
+const NT_PRSTATUS: u32 = 1;
+
+/// Version and size of the QEMUCPUState structure of the "QEMU" notes.
+const QEMU_CPU_STATE_VERSION: u32 = 1;
+const QEMU_CPU_STATE_SIZE: u32 = 440;
+
+/// Guest memory is copied through a buffer of this size.
+const MEMORY_CHUNK_SIZE: usize = 1 << 20;
+
+/// Errors associated with writing crash dumps.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot write the dump.
+    Io(io::Error),
+    /// Cannot access the guest memory.
+    Memory(GuestMemoryError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Io(e) => write!(f, "Cannot write the crash dump: {e}"),
+            Memory(e) => write!(f, "Cannot access the guest memory: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Serializes the little-endian ELF structures.
+#[derive(Default)]
+struct Buf(Vec<u8>);
+
+impl Buf {
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    fn zeros(&mut self, len: usize) -> &mut Self {
+        self.0.resize(self.0.len() + len, 0);
+        self
+    }
+
+    fn align(&mut self, align: usize) -> &mut Self {
+        self.0.resize(self.0.len().next_multiple_of(align), 0);
+        self
+    }
+}
+
+fn write_note(buf: &mut Buf, name: &str, note_type: u32, desc: &[u8]) {
+    buf.u32(name.len() as u32 + 1)
+        .u32(desc.len() as u32)
+        .u32(note_type)
+        .bytes(name.as_bytes())
+        .zeros(1)
+        .align(4)
+        .bytes(desc)
+        .align(4);
+}
+
+fn write_program_header(
+    buf: &mut Buf,
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    paddr: u64,
+    size: u64,
+) {
+    buf.u32(segment_type)
+        .u32(flags)
+        .u64(offset)
+        .u64(0)
+        .u64(paddr)
+        .u64(size)
+        .u64(size)
+        .u64(0);
+}
+
+/// The `elf_prstatus` of a vCPU, whose registers are the `user_regs_struct` ones.
+fn prstatus(index: usize, state: &VcpuRegs) -> Vec<u8> {
+    let regs = &state.regs;
+    let sregs = &state.sregs;
+
+    let mut buf = Buf::default();
+    // pr_info, pr_cursig, pr_sigpend and pr_sighold.
+    buf.zeros(32);
+    // pr_pid, followed by pr_ppid, pr_pgrp and pr_sid.
+    buf.u32(index as u32 + 1).zeros(12);
+    // pr_utime, pr_stime, pr_cutime and pr_cstime.
+    buf.zeros(64);
+    for reg in [
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        // orig_rax
+        0,
+        regs.rip,
+        sregs.cs.selector.into(),
+        regs.rflags,
+        regs.rsp,
+        sregs.ss.selector.into(),
+        sregs.fs.base,
+        sregs.gs.base,
+        sregs.ds.selector.into(),
+        sregs.es.selector.into(),
+        sregs.fs.selector.into(),
+        sregs.gs.selector.into(),
+    ] {
+        buf.u64(reg);
+    }
+    // pr_fpvalid
+    buf.zeros(8);
+    buf.0
+}
+
+fn write_qemu_segment(buf: &mut Buf, segment: &kvm_segment) {
+    // The attributes as found in the high word of a segment descriptor.
+    let flags = u32::from(segment.type_) << 8
+        | u32::from(segment.s) << 12
+        | u32::from(segment.dpl) << 13
+        | u32::from(segment.present) << 15
+        | u32::from(segment.avl) << 20
+        | u32::from(segment.l) << 21
+        | u32::from(segment.db) << 22
+        | u32::from(segment.g) << 23;
+
+    buf.u32(segment.selector.into())
+        .u32(segment.limit)
+        .u32(flags)
+        .u32(0)
+        .u64(segment.base);
+}
+
+/// The `QEMUCPUState` of a vCPU, with the system registers the crash utility
+/// needs to translate the kernel virtual addresses.
+fn qemu_cpu_state(state: &VcpuRegs) -> Vec<u8> {
+    let regs = &state.regs;
+    let sregs = &state.sregs;
+
+    let mut buf = Buf::default();
+    buf.u32(QEMU_CPU_STATE_VERSION).u32(QEMU_CPU_STATE_SIZE);
+    for reg in [
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rsp,
+        regs.rbp,
+        regs.r8,
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12,
+        regs.r13,
+        regs.r14,
+        regs.r15,
+        regs.rip,
+        regs.rflags,
+    ] {
+        buf.u64(reg);
+    }
+    for segment in [
+        &sregs.cs, &sregs.ds, &sregs.es, &sregs.fs, &sregs.gs, &sregs.ss, &sregs.ldt, &sregs.tr,
+    ] {
+        write_qemu_segment(&mut buf, segment);
+    }
+    for table in [&sregs.gdt, &sregs.idt] {
+        buf.u32(0)
+            .u32(table.limit.into())
+            .u32(0)
+            .u32(0)
+            .u64(table.base);
+    }
+    for cr in [sregs.cr0, 0, sregs.cr2, sregs.cr3, sregs.cr4] {
+        buf.u64(cr);
+    }
+    // The guest is dumped when its kernel is running, whose GS base is the
+    // one in the segment registers, so the swapped out one isn't collected.
+    buf.u64(0);
+    buf.0
+}
+
+/// Writes a crash dump with the state of the vCPUs and the guest memory
+/// regions starting below `ram_end`. The regions above it are SHM windows
+/// the host maps files into, so they aren't part of the guest state.
+pub fn write_core<W: Write>(
+    writer: &mut W,
+    vcpu_states: &[VcpuRegs],
+    guest_mem: &GuestMemoryMmap,
+    ram_end: GuestAddress,
+) -> Result<()> {
+    let mut notes = Buf::default();
+    for (index, state) in vcpu_states.iter().enumerate() {
+        write_note(&mut notes, "CORE", NT_PRSTATUS, &prstatus(index, state));
+        write_note(&mut notes, "QEMU", 0, &qemu_cpu_state(state));
+    }
+
+    let regions: Vec<_> = guest_mem
+        .iter()
+        .filter(|region| region.start_addr() < ram_end)
+        .collect();
+    let phnum = regions.len() + 1;
+    let notes_offset = ELF_HEADER_SIZE + phnum as u64 * PROGRAM_HEADER_SIZE;
+
+    let mut headers = Buf::default();
+    headers
+        // ELF magic, 64-bit, little-endian, version 1.
+        .bytes(&[0x7f, b'E', b'L', b'F', 2, 1, 1])
+        .zeros(9)
+        .u16(ET_CORE)
+        .u16(EM_X86_64)
+        .u32(1)
+        // e_entry, e_phoff, e_shoff and e_flags.
+        .u64(0)
+        .u64(ELF_HEADER_SIZE)
+        .u64(0)
+        .u32(0)
+        .u16(ELF_HEADER_SIZE as u16)
+        .u16(PROGRAM_HEADER_SIZE as u16)
+        .u16(phnum as u16)
+        // e_shentsize, e_shnum and e_shstrndx.
+        .zeros(6);
+
+    write_program_header(
+        &mut headers,
+        PT_NOTE,
+        0,
+        notes_offset,
+        0,
+        notes.0.len() as u64,
+    );
+    let mut offset = notes_offset + notes.0.len() as u64;
+    for region in regions.iter() {
+        write_program_header(
+            &mut headers,
+            PT_LOAD,
+            PF_RWX,
+            offset,
+            region.start_addr().raw_value(),
+            region.len(),
+        );
+        offset += region.len();
+    }
+
+    writer.write_all(&headers.0)?;
+    writer.write_all(&notes.0)?;
+
+    let mut buf = vec![0u8; MEMORY_CHUNK_SIZE];
+    for region in regions {
+        let mut offset = 0;
+        while offset < region.len() {
+            let len = std::cmp::min(MEMORY_CHUNK_SIZE as u64, region.len() - offset) as usize;
+            guest_mem
+                .read_slice(&mut buf[..len], region.start_addr().unchecked_add(offset))
+                .map_err(Error::Memory)?;
+            writer.write_all(&buf[..len])?;
+            offset += len as u64;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vcpu_state(rip: u64) -> VcpuRegs {
+        let mut state = VcpuRegs::default();
+        state.regs.rip = rip;
+        state.sregs.cr3 = 0x1000;
+        state
+    }
+
+    fn read_u16(buf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_note_sizes() {
+        assert_eq!(prstatus(0, &vcpu_state(0)).len(), 336);
+        assert_eq!(
+            qemu_cpu_state(&vcpu_state(0)).len(),
+            QEMU_CPU_STATE_SIZE as usize
+        );
+    }
+
+    #[test]
+    fn test_write_core() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x2000),
+            (GuestAddress(0x10000), 0x1000),
+            // SHM window, left out of the dump.
+            (GuestAddress(0x20000), 0x1000),
+        ])
+        .unwrap();
+        guest_mem
+            .write_slice(&[0xaa; 4], GuestAddress(0x10000))
+            .unwrap();
+
+        let mut dump = Vec::new();
+        write_core(
+            &mut dump,
+            &[vcpu_state(0x1234), vcpu_state(0x5678)],
+            &guest_mem,
+            GuestAddress(0x20000),
+        )
+        .unwrap();
+
+        assert_eq!(&dump[..4], b"\x7fELF");
+        assert_eq!(read_u16(&dump, 16), ET_CORE);
+        assert_eq!(read_u16(&dump, 18), EM_X86_64);
+        assert_eq!(read_u16(&dump, 56), 3);
+
+        let note_phdr = ELF_HEADER_SIZE as usize;
+        assert_eq!(read_u32(&dump, note_phdr), PT_NOTE);
+        let notes_offset = read_u64(&dump, note_phdr + 8) as usize;
+        let notes_size = read_u64(&dump, note_phdr + 32) as usize;
+        // Two notes per vCPU, each with a 12 bytes header and a 8 bytes name.
+        assert_eq!(notes_size, 2 * (2 * 20 + 336 + 440));
+        // The rip of the first vCPU, in its NT_PRSTATUS note.
+        assert_eq!(read_u32(&dump, notes_offset + 8), NT_PRSTATUS);
+        assert_eq!(read_u64(&dump, notes_offset + 20 + 112 + 16 * 8), 0x1234);
+
+        let load_phdr = note_phdr + 2 * PROGRAM_HEADER_SIZE as usize;
+        assert_eq!(read_u32(&dump, load_phdr), PT_LOAD);
+        assert_eq!(read_u64(&dump, load_phdr + 24), 0x10000);
+        let offset = read_u64(&dump, load_phdr + 8) as usize;
+        assert_eq!(offset, notes_offset + notes_size + 0x2000);
+        assert_eq!(&dump[offset..offset + 4], &[0xaa; 4]);
+        assert_eq!(dump.len(), offset + 0x1000);
+    }
+}
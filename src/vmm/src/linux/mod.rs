@@ -1,4 +1,6 @@
 #[cfg(feature = "tee")]
 pub mod tee;
 
+#[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+pub mod coredump;
 pub mod vstate;
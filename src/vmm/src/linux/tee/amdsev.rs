@@ -1,49 +1,94 @@
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs::File;
-use std::io::Read;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use super::super::super::resources::TeeConfig;
+use super::super::super::resources::{
+    AttestationProtocol, Error as ResourcesError, ProgressCallback, TeeConfig,
+};
 use super::super::vstate::MeasuredRegion;
+use super::http::{self, HttpClient, HttpError};
+use super::teekey::{self, TeeKey};
+use super::{ConfidentialVm, Error as TeeError, LaunchMeasurement, LaunchMetrics, TeeKind};
 
+use base64::prelude::*;
 use codicon::{Decoder, Encoder};
-use curl::easy::{Easy, List};
-use kbs_types::{Attestation, Challenge, Request, SevChallenge, SevRequest, TeePubKey};
-use kvm_bindings::{kvm_enc_region, kvm_sev_cmd};
+use kbs_types::{
+    Attestation, Challenge, Request, Response, SevChallenge, SevRequest, Tee, TeePubKey,
+};
+use kvm_bindings::{
+    kvm_enable_cap, kvm_enc_region, kvm_sev_cmd, kvm_sev_launch_measure, kvm_sev_launch_secret,
+    kvm_sev_launch_start, kvm_sev_receive_start, kvm_sev_send_start, CpuId,
+    KVM_CAP_VM_COPY_ENC_CONTEXT_FROM,
+};
 use kvm_ioctls::VmFd;
 use procfs::CpuInfo;
 use serde::{Deserialize, Serialize};
 use sev::certs;
-use sev::firmware::host::Firmware;
+use sev::certs::sev::Verifiable;
+use sev::firmware::host::{Firmware, PlatformStatusFlags, State};
 use sev::launch::sev::*;
 use sev::session::Session;
-use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use utils::cancel::CancelToken;
+use utils::tempfile::TempFile;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 #[derive(Debug)]
 pub enum Error {
-    AttestationRequest(curl::Error),
+    ApJumpTableSetup(arch::Error),
+    AttestationRequest(HttpError),
+    AttestationServerUnavailable,
+    Cancelled,
+    ChainCpuMismatch(CpuModel),
+    ChallengeExpired,
     DecodeAskArk,
     DecodeCek,
-    DecodeChain,
-    DownloadCek(curl::Error),
-    DownloadAskArk(curl::Error),
+    DecodeChain(ChainFormat),
+    DebugForbiddenByPolicy,
+    DebugMisaligned(u64, usize),
+    DebugNotAllowed,
+    DebugTooLarge(usize),
+    DecodeSecretFile,
+    DownloadCek(HttpError),
+    DownloadAskArk(HttpError),
     EncodeChain,
     FetchIdentifier,
+    InvalidTeeConfig(ResourcesError),
     InvalidCpuData,
+    InvalidGuestRange,
+    KeylimeRequest(HttpError),
+    MigrationForbiddenByPolicy,
+    MigrationNotAllowed,
+    MirrorEncryptionContext(kvm_ioctls::Error),
     OpenChainFile(std::io::Error),
     OpenFirmware(std::io::Error),
+    OpenSecretFile(std::io::Error),
     OpenTmpFile,
-    ParseAttestationSecret(serde_json::Error),
+    PinGuestMemory(u64, std::io::Error),
+    MissingChain,
+    MissingMeasurement,
+    ParseAttestationSecret(serde_json::error::Category),
     ParseSevCertConfig(serde_json::Error),
     ParseSessionResponse(serde_json::Error),
     PlatformStatus,
     MemoryEncryptRegion,
+    NetworkAccessOffline,
     ReadingCpuData(procfs::ProcError),
     ReadingCoreData,
     SessionFromPolicy(rdrand::ErrorCode),
-    SessionRequest(curl::Error),
+    SecretRegionOverflow(u64),
+    SecretTooLarge(usize),
+    SecretExceedsSlot(usize, usize),
+    TooManySecrets(usize),
+    SessionRequest(HttpError),
+    SerializeEvidence(serde_json::Error),
+    SerializeSessionCache(serde_json::Error),
+    SevDbgDecrypt(kvm_ioctls::Error),
+    SevDbgEncrypt(kvm_ioctls::Error),
     SevInit(kvm_ioctls::Error),
     SevInjectSecret(kvm_ioctls::Error),
     SevLaunchFinish(kvm_ioctls::Error),
@@ -51,98 +96,44 @@ pub enum Error {
     SevLaunchStart(kvm_ioctls::Error),
     SevLaunchUpdateData(kvm_ioctls::Error),
     SevLaunchUpdateVmsa(kvm_ioctls::Error),
+    SevReceiveStart(kvm_ioctls::Error),
+    SevSendStart(kvm_ioctls::Error),
     StartFromSession(sev::error::SessionError),
+    TeeKey(teekey::Error),
+    UnexpectedLaunchCommand,
     UnknownCpuModel,
+    WriteSessionCache(std::io::Error),
 }
 
-struct CurlAgent {
-    easy: Easy,
-    session_id: Option<String>,
-}
-
-fn extract_session_id(header: &[u8]) -> Option<String> {
-    let header = match std::str::from_utf8(header) {
-        Ok(h) => h,
-        Err(_) => return None,
-    };
-
-    if !header.contains("session_id") {
-        return None;
-    }
-
-    let parts: Vec<&str> = header.split(';').collect();
-    for p in parts {
-        let elems: Vec<&str> = p.split('=').collect();
-        if elems.len() == 2 && elems[0].contains("session_id") {
-            return Some(elems[1].to_string());
-        }
-    }
-
-    None
+/// Encoding of a certificate chain file, as detected from its contents.
+#[derive(Debug)]
+pub enum ChainFormat {
+    /// The binary encoding of the `sev` crate.
+    Binary,
+    /// Concatenated PEM blocks, each one holding the binary encoding of a certificate.
+    Pem,
 }
 
-impl CurlAgent {
-    fn new() -> Self {
-        CurlAgent {
-            easy: Easy::new(),
-            session_id: None,
+impl Error {
+    /// Maps the failure of a request, unless it was cancelled.
+    fn from_request(request_error: fn(HttpError) -> Error) -> impl Fn(HttpError) -> Error {
+        move |e| match e {
+            HttpError::Cancelled => Error::Cancelled,
+            e => request_error(e),
         }
     }
 
-    fn get(&mut self, url: &str) -> Result<Vec<u8>, curl::Error> {
-        let mut rsp = Vec::new();
-
-        self.easy.post(false)?;
-        self.easy.url(url)?;
-
-        let mut transfer = self.easy.transfer();
-        transfer.write_function(|data| {
-            rsp.extend_from_slice(data);
-            Ok(data.len())
-        })?;
-        transfer.perform()?;
-        drop(transfer);
-
-        Ok(rsp)
-    }
-
-    fn post(&mut self, url: &str, mut data: &[u8]) -> Result<Vec<u8>, curl::Error> {
-        let mut rsp = Vec::new();
-
-        let mut headers = List::new();
-        headers.append("Accept: application/json")?;
-        headers.append("Content-Type: application/json; charset=utf-8")?;
-        if let Some(session_id) = &self.session_id {
-            headers.append(&format!("Cookie: session_id={}", session_id))?;
+    /// Maps the failure of a request to a list of attestation servers.
+    fn from_servers(request_error: fn(HttpError) -> Error) -> impl Fn(HttpError) -> Error {
+        move |e| match e {
+            HttpError::Unavailable(_) => Error::AttestationServerUnavailable,
+            e => Error::from_request(request_error)(e),
         }
-
-        self.easy.post(true)?;
-        self.easy.post_field_size(data.len() as u64)?;
-        self.easy.url(url)?;
-        self.easy.http_headers(headers)?;
-
-        let mut transfer = self.easy.transfer();
-        transfer.read_function(|buf| Ok(data.read(buf).unwrap_or(0)))?;
-        transfer.write_function(|data| {
-            rsp.extend_from_slice(data);
-            Ok(data.len())
-        })?;
-        transfer
-            .header_function(|header| {
-                if let Some(session_id) = extract_session_id(header) {
-                    self.session_id = Some(session_id);
-                }
-                true
-            })
-            .unwrap();
-        transfer.perform()?;
-        drop(transfer);
-
-        Ok(rsp)
     }
 }
 
-enum CpuModel {
+#[derive(Debug)]
+pub enum CpuModel {
     Naples,
     Rome,
     Milan,
@@ -158,6 +149,17 @@ impl fmt::Display for CpuModel {
     }
 }
 
+impl CpuModel {
+    /// The AMD Root Key certificate of this CPU generation.
+    fn ark(&self) -> &'static [u8] {
+        match self {
+            CpuModel::Naples => certs::sev::builtin::naples::ARK,
+            CpuModel::Rome => certs::sev::builtin::rome::ARK,
+            CpuModel::Milan => certs::sev::builtin::milan::ARK,
+        }
+    }
+}
+
 fn find_cpu_model() -> Result<CpuModel, Error> {
     let cpuinfo = CpuInfo::new().map_err(Error::ReadingCpuData)?;
     let coreinfo = cpuinfo.get_info(0);
@@ -189,64 +191,492 @@ fn find_cpu_model() -> Result<CpuModel, Error> {
     }
 }
 
-fn fetch_chain(fw: &mut Firmware, curl_agent: &mut CurlAgent) -> Result<certs::sev::Chain, Error> {
-    const CEK_SVC: &str = "https://kdsintf.amd.com/cek/id";
-    const ASK_ARK_SVC: &str = "https://developer.amd.com/wp-content/resources/";
+/// Certificates of a chip downloaded from the AMD Key Distribution Service,
+/// in their binary encoding.
+struct KdsCerts {
+    cek: Vec<u8>,
+    ask_ark: Vec<u8>,
+}
+
+type KdsCertsCache = BTreeMap<String, Arc<Mutex<Option<Arc<KdsCerts>>>>>;
+
+/// The certificates downloaded for each chip identifier, shared by all the
+/// launches of the process so they're only downloaded once per chip.
+static KDS_CERTS: Mutex<KdsCertsCache> = Mutex::new(BTreeMap::new());
 
+/// Returns the certificates of the chip with identifier `id`, downloading them
+/// unless another launch already did.
+fn kds_certs(
+    id: &str,
+    cpu_model: &CpuModel,
+    tee_config: &TeeConfig,
+    http_client: &mut dyn HttpClient,
+) -> Result<Arc<KdsCerts>, Error> {
+    let entry = Arc::clone(KDS_CERTS.lock().unwrap().entry(id.to_string()).or_default());
+    // Concurrent launches on the same chip wait for the first one to download
+    // the certificates instead of racing it.
+    let mut cached = entry.lock().unwrap();
+    if let Some(certs) = cached.as_ref() {
+        debug!("Reusing the SEV certificates downloaded for chip {}", id);
+        return Ok(Arc::clone(certs));
+    }
+
+    let cek = http_client
+        .get(&format!(
+            "{}/{}",
+            tee_config.cek_url.trim_end_matches('/'),
+            id
+        ))
+        .map_err(Error::from_request(Error::DownloadCek))?;
+    certs::sev::sev::Certificate::decode(&mut cek.as_slice(), ()).map_err(|_| Error::DecodeCek)?;
+
+    let ask_ark = http_client
+        .get(&format!(
+            "{}/ask_ark_{}.cert",
+            tee_config.ask_ark_url.trim_end_matches('/'),
+            cpu_model
+        ))
+        .map_err(Error::from_request(Error::DownloadAskArk))?;
+    certs::sev::ca::Chain::decode(&mut ask_ark.as_slice(), ()).map_err(|_| Error::DecodeAskArk)?;
+
+    let certs = Arc::new(KdsCerts { cek, ask_ark });
+    *cached = Some(Arc::clone(&certs));
+
+    Ok(certs)
+}
+
+fn fetch_chain(
+    fw: &mut Firmware,
+    tee_config: &TeeConfig,
+    http_client: &mut dyn HttpClient,
+) -> Result<certs::sev::Chain, Error> {
     let mut chain = fw
         .pdh_cert_export()
         .expect("unable to export SEV certificates");
 
     let id = fw.get_identifier().map_err(|_| Error::FetchIdentifier)?;
 
-    let rsp = curl_agent
-        .get(&format!("{}/{}", CEK_SVC, id))
-        .map_err(Error::DownloadCek)?;
-
-    chain.cek = (certs::sev::sev::Certificate::decode(&mut rsp.as_slice(), ()))
-        .map_err(|_| Error::DecodeCek)?;
-
     let cpu_model = find_cpu_model()?;
+    debug!("Detected CPU model {}", cpu_model);
 
-    let rsp = curl_agent
-        .get(&format!("{}/ask_ark_{}.cert", ASK_ARK_SVC, cpu_model))
-        .map_err(Error::DownloadCek)?;
+    let kds_certs = kds_certs(&id.to_string(), &cpu_model, tee_config, http_client)?;
+    chain.cek = certs::sev::sev::Certificate::decode(&mut kds_certs.cek.as_slice(), ())
+        .map_err(|_| Error::DecodeCek)?;
 
     Ok(certs::sev::Chain {
-        ca: certs::sev::ca::Chain::decode(&mut rsp.as_slice(), ())
+        ca: certs::sev::ca::Chain::decode(&mut kds_certs.ask_ark.as_slice(), ())
             .map_err(|_| Error::DecodeAskArk)?,
         sev: chain,
     })
 }
 
+/// Stores a copy of the chain in `path` for other tools, replacing the file in
+/// one go so concurrent launches don't interleave their writes.
+fn store_chain(chain: &certs::sev::Chain, path: &Path) -> Result<(), Error> {
+    let mut prefix = path.as_os_str().to_owned();
+    prefix.push(".");
+    let tmp_file = TempFile::new_with_prefix(prefix).map_err(|_| Error::OpenTmpFile)?;
+    chain
+        .encode(tmp_file.as_file(), ())
+        .map_err(|_| Error::EncodeChain)?;
+    // The temporary file left behind on failure is removed when dropped.
+    std::fs::rename(tmp_file.as_path(), path).map_err(|_| Error::OpenTmpFile)
+}
+
 #[derive(Serialize, Deserialize)]
 struct SevCertConfig {
     pub vendor_chain: String,
     pub attestation_server_pubkey: String,
+    /// SEV policy to launch the guest with when there's no attestation server.
+    #[serde(default)]
+    pub policy: Option<u32>,
 }
 
 fn get_and_store_chain(
     fw: &mut Firmware,
     tee_config: &TeeConfig,
-    curl_agent: &mut CurlAgent,
+    cert_config: &SevCertConfig,
+    http_client: &mut dyn HttpClient,
 ) -> Result<certs::sev::Chain, Error> {
-    let cert_config: SevCertConfig =
-        serde_json::from_str(&tee_config.tee_data).map_err(Error::ParseSevCertConfig)?;
-
-    if !cert_config.vendor_chain.is_empty() {
+    if !tee_config.vendor_chain_data.is_empty() {
+        debug!("Using the SEV certificate chain supplied by the embedder");
+        let chain = decode_chain(&tee_config.vendor_chain_data)?;
+        check_chain_cpu_model(&chain)?;
+        Ok(chain)
+    } else if !cert_config.vendor_chain.is_empty() {
+        debug!(
+            "Loading SEV certificate chain from {}",
+            cert_config.vendor_chain
+        );
         let filepath = Path::new(&cert_config.vendor_chain);
-        let mut file = File::open(filepath).map_err(Error::OpenChainFile)?;
-        Ok(certs::sev::Chain::decode(&mut file, ()).map_err(|_| Error::DecodeChain)?)
+        let data = std::fs::read(filepath).map_err(Error::OpenChainFile)?;
+        let chain = decode_chain(&data)?;
+        check_chain_cpu_model(&chain)?;
+        Ok(chain)
+    } else if tee_config.offline {
+        Err(Error::NetworkAccessOffline)
     } else {
-        let chain = fetch_chain(fw, curl_agent)?;
-        let mut file = File::create("/tmp/libkrun-sev.chain").map_err(|_| Error::OpenTmpFile)?;
-        chain
-            .encode(&mut file, ())
-            .map_err(|_| Error::EncodeChain)?;
+        let now = Instant::now();
+        let chain = fetch_chain(fw, tee_config, http_client)?;
+        info!("Fetched SEV certificate chain in {:?}", now.elapsed());
+        check_chain_cpu_model(&chain)?;
+
+        store_chain(&chain, Path::new("/tmp/libkrun-sev.chain"))?;
         Ok(chain)
     }
 }
 
+/// Checks the ASK and ARK of `chain` belong to the CPU generation of this host,
+/// and that they endorse its CEK.
+fn check_chain_cpu_model(chain: &certs::sev::Chain) -> Result<(), Error> {
+    let cpu_model = match find_cpu_model() {
+        Ok(cpu_model) => cpu_model,
+        Err(Error::UnknownCpuModel) => {
+            warn!("Unknown CPU model, can't check the SEV certificate chain matches it");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let encode_ark = |ark: &certs::sev::ca::Certificate| {
+        let mut encoded = Vec::new();
+        ark.encode(&mut encoded, ()).map(|_| encoded)
+    };
+    let ark = certs::sev::ca::Certificate::decode(&mut cpu_model.ark(), ())
+        .map_err(|_| Error::DecodeAskArk)?;
+    let same_ark = encode_ark(&ark).map_err(|_| Error::EncodeChain)?
+        == encode_ark(&chain.ca.ark).map_err(|_| Error::EncodeChain)?;
+
+    if !same_ark
+        || (&chain.ca).verify().is_err()
+        || (&chain.ca.ask, &chain.sev.cek).verify().is_err()
+    {
+        return Err(Error::ChainCpuMismatch(cpu_model));
+    }
+
+    Ok(())
+}
+
+/// Decodes a certificate chain, detecting whether it's in the binary format or PEM.
+fn decode_chain(data: &[u8]) -> Result<certs::sev::Chain, Error> {
+    let (format, binary) = if data.trim_ascii_start().starts_with(b"-----BEGIN ") {
+        let binary = pem_to_binary(data).ok_or(Error::DecodeChain(ChainFormat::Pem))?;
+        (ChainFormat::Pem, binary)
+    } else {
+        (ChainFormat::Binary, data.to_vec())
+    };
+
+    certs::sev::Chain::decode(&mut binary.as_slice(), ()).map_err(|_| Error::DecodeChain(format))
+}
+
+/// Concatenates the payloads of the PEM blocks in `pem`, in order. Returns `None`
+/// if there's anything other than well-formed PEM blocks.
+fn pem_to_binary(pem: &[u8]) -> Option<Vec<u8>> {
+    let mut binary = Vec::new();
+    let mut block: Option<String> = None;
+
+    for line in std::str::from_utf8(pem).ok()?.lines().map(str::trim) {
+        if line.starts_with("-----BEGIN ") {
+            if block.replace(String::new()).is_some() {
+                return None;
+            }
+        } else if line.starts_with("-----END ") {
+            binary.extend(BASE64_STANDARD.decode(block.take()?).ok()?);
+        } else if let Some(block) = block.as_mut() {
+            block.push_str(line);
+        } else if !line.is_empty() {
+            return None;
+        }
+    }
+
+    if block.is_some() || binary.is_empty() {
+        return None;
+    }
+
+    Some(binary)
+}
+
+/// Reads a secret to be injected into the guest from a local file.
+///
+/// The file must contain the binary encoding of a launch secret packet: the
+/// 52-byte header (4 bytes of little-endian flags, a 16-byte IV and a 32-byte
+/// MAC) immediately followed by the encrypted secret, which extends up to the
+/// end of the file.
+fn read_secret_file(path: &str) -> Result<Secret, Error> {
+    let mut file = File::open(Path::new(path)).map_err(Error::OpenSecretFile)?;
+    Secret::decode(&mut file, ()).map_err(|_| Error::DecodeSecretFile)
+}
+
+/// Returns the host address of the `len` bytes of guest memory at `guest_addr`
+/// a secret is injected into, once checked they're all in the same region, as
+/// the firmware writes them in one go.
+fn secret_host_addr(
+    guest_mem: &GuestMemoryMmap,
+    guest_addr: u64,
+    len: usize,
+) -> Result<u64, Error> {
+    let addr = GuestAddress(guest_addr);
+    let region = guest_mem
+        .find_region(addr)
+        .ok_or(Error::SecretRegionOverflow(guest_addr))?;
+    let available = region.len() - (guest_addr - region.start_addr().raw_value());
+    if len as u64 > available {
+        return Err(Error::SecretRegionOverflow(guest_addr));
+    }
+
+    Ok(guest_mem.get_host_address(addr).unwrap() as u64)
+}
+
+/// Initial delay before retrying a command the firmware was too busy to serve.
+const FIRMWARE_BUSY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Firmware status reported when the platform is busy serving other guests.
+const SEV_RET_HWSEV_RET_PLATFORM: u32 = 0x13;
+
+/// Firmware status reported when a buffer is too small, as when querying its size.
+const SEV_RET_INVALID_LEN: u32 = 0x04;
+
+/// Returns whether a failed SEV command may succeed if issued again later.
+fn firmware_busy(err: &kvm_ioctls::Error, cmd: &kvm_sev_cmd) -> bool {
+    matches!(err.errno(), libc::EBUSY | libc::EAGAIN) || cmd.error == SEV_RET_HWSEV_RET_PLATFORM
+}
+
+/// Local SEV session persisted across launches, so they can be reproduced.
+///
+/// Only the `Start` is kept, deliberately without the session secret, the
+/// transport keys the `Start` wraps for the firmware. Nothing on this host
+/// needs them once the launch started, as the measurement isn't verified here
+/// and secrets come wrapped by their owner. Storing them would let whoever
+/// reads the cache forge secrets for every launch reusing it.
+#[derive(Serialize, Deserialize)]
+struct SessionCache {
+    chain: serde_json::Value,
+    start: Start,
+}
+
+/// Returns the cached `Start`, as long as it was created for the same policy and
+/// certificate chain.
+fn load_session_cache(path: &str, policy: &Policy, chain: &serde_json::Value) -> Option<Start> {
+    let file = match File::open(Path::new(path)) {
+        Ok(file) => file,
+        Err(e) => {
+            debug!("No usable SEV session cache at {}: {}", path, e);
+            return None;
+        }
+    };
+
+    let cache: SessionCache = match serde_json::from_reader(file) {
+        Ok(cache) => cache,
+        Err(e) => {
+            warn!("Ignoring corrupted SEV session cache {}: {}", path, e);
+            return None;
+        }
+    };
+
+    if cache.start.policy != *policy || cache.chain != *chain {
+        info!("SEV policy or certificate chain changed, invalidating the session cache");
+        return None;
+    }
+
+    Some(cache.start)
+}
+
+/// Stores the session in `path`, replacing the file in one go so concurrent
+/// launches never read a partial one.
+fn store_session_cache(path: &str, chain: &serde_json::Value, start: &Start) -> Result<(), Error> {
+    let cache = SessionCache {
+        chain: chain.clone(),
+        start: *start,
+    };
+
+    let mut prefix = Path::new(path).as_os_str().to_owned();
+    prefix.push(".");
+    // Created private to the user, whatever the mode of a previous cache.
+    let tmp_file = TempFile::new_with_prefix(prefix).map_err(|_| Error::OpenTmpFile)?;
+    serde_json::to_writer(tmp_file.as_file(), &cache).map_err(Error::SerializeSessionCache)?;
+    std::fs::rename(tmp_file.as_path(), path).map_err(Error::WriteSessionCache)
+}
+
+/// Keylime REST API version spoken with the registrar and the verifier.
+const KEYLIME_API_VERSION: &str = "v2.1";
+
+/// Workload registration sent to the Keylime registrar.
+#[derive(Serialize)]
+struct KeylimeRegistration<'a> {
+    tee: Tee,
+    build: sev::Build,
+    chain: &'a serde_json::Value,
+}
+
+/// Registers the workload as a Keylime agent, identified by its workload id.
+/// Returns the URL of the registrar that accepted it.
+fn keylime_register(
+    http_client: &mut dyn HttpClient,
+    tee_config: &TeeConfig,
+    build: sev::Build,
+    chain: &serde_json::Value,
+) -> Result<String, Error> {
+    let registration = KeylimeRegistration {
+        tee: tee_config.tee,
+        build,
+        chain,
+    };
+    let body = serde_json::to_vec(&registration).map_err(Error::SerializeEvidence)?;
+
+    let now = Instant::now();
+    let (url, _) = http::post_first_available(
+        http_client,
+        &tee_config.attestation_url,
+        &format!("/{}/agents/{}", KEYLIME_API_VERSION, tee_config.workload_id),
+        &body,
+    )
+    .map_err(Error::from_servers(Error::KeylimeRequest))?;
+    info!(
+        "Workload {} registered with Keylime registrar {} in {:?}",
+        tee_config.workload_id,
+        url,
+        now.elapsed()
+    );
+
+    Ok(url)
+}
+
+/// Version of the `AttestationEvidence` document. Bump it on any change that
+/// isn't backwards compatible for the verifiers consuming it.
+pub const EVIDENCE_VERSION: u32 = 1;
+
+/// Attestation evidence handed to external verifiers.
+#[derive(Serialize)]
+struct AttestationEvidence<'a> {
+    version: u32,
+    measurement: &'a Measurement,
+    build: &'a sev::Build,
+    policy: &'a Policy,
+    chain: &'a serde_json::Value,
+}
+
+/// Challenge received from the KBS on session request.
+///
+/// Fields this client doesn't know about are kept, both at the top level and
+/// in `extra_params`, so brokers ahead of it don't break the session and an
+/// `expires-in` field can be honoured wherever it's sent.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KbsChallenge {
+    #[serde(flatten)]
+    pub challenge: Challenge,
+    #[serde(flatten)]
+    pub unknown: serde_json::Map<String, serde_json::Value>,
+}
+
+impl KbsChallenge {
+    /// How long the challenge may be answered after it was issued, if the KBS
+    /// said so in an `expires-in` field, in seconds, either at the top level
+    /// or in `extra_params`.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.unknown
+            .get("expires-in")
+            .or_else(|| self.challenge.extra_params.get("expires-in"))
+            .and_then(|value| value.as_u64())
+            .map(Duration::from_secs)
+    }
+}
+
+/// Session established with a KBS by answering its challenge.
+struct KbsSession {
+    url: String,
+    start: Start,
+    sev_es: bool,
+    /// When the challenge stops being accepted by the KBS, if it ever does.
+    deadline: Option<Instant>,
+}
+
+/// Builds the KBS session request, leaving the certificate chain out for
+/// brokers fetching it themselves.
+fn kbs_auth_request(
+    tee_config: &TeeConfig,
+    build: sev::Build,
+    chain: Option<certs::sev::Chain>,
+) -> Request {
+    let extra_params = match chain {
+        Some(chain) => serde_json::json!(SevRequest {
+            build,
+            chain,
+            workload_id: tee_config.workload_id.clone(),
+        }),
+        None => serde_json::json!({
+            "build": build,
+            "workload_id": tee_config.workload_id,
+        }),
+    };
+
+    Request {
+        version: "0.0.0".to_string(),
+        tee: tee_config.tee,
+        extra_params,
+    }
+}
+
+/// Requests a challenge from the first available attestation server in the
+/// TEE config, and parses the launch parameters it sent along.
+fn kbs_auth(
+    http_client: &mut dyn HttpClient,
+    tee_config: &TeeConfig,
+    build: sev::Build,
+    chain: Option<certs::sev::Chain>,
+) -> Result<KbsSession, Error> {
+    let request = kbs_auth_request(tee_config, build, chain);
+
+    let body = serde_json::json!(request).to_string();
+
+    // The session is bound to the server that answers the challenge, so it
+    // must also serve the attestation and the secret.
+    let now = Instant::now();
+    let (url, response) = http::post_first_available(
+        http_client,
+        &tee_config.attestation_url,
+        "/kbs/v0/auth",
+        body.as_bytes(),
+    )
+    .map_err(Error::from_servers(Error::SessionRequest))?;
+    info!(
+        "Attestation session started with {} in {:?}",
+        url,
+        now.elapsed()
+    );
+
+    let challenge: KbsChallenge =
+        serde_json::from_slice(&response).map_err(Error::ParseSessionResponse)?;
+    if !challenge.unknown.is_empty() {
+        debug!(
+            "Attestation challenge has unknown fields: {:?}",
+            challenge.unknown.keys().collect::<Vec<_>>()
+        );
+    }
+    // Counted from the request, as the KBS may have issued it any time since.
+    let deadline = challenge.expires_in().map(|expires_in| now + expires_in);
+    let sev_challenge: SevChallenge =
+        serde_json::from_value(challenge.challenge.extra_params.clone())
+            .map_err(Error::ParseSessionResponse)?;
+
+    let sev_es = sev_challenge
+        .start
+        .policy
+        .flags
+        .contains(PolicyFlags::ENCRYPTED_STATE);
+    debug!(
+        "Attestation challenge parsed, policy: {:?}, expires in: {:?}",
+        sev_challenge.start.policy,
+        challenge.expires_in()
+    );
+
+    Ok(KbsSession {
+        url,
+        start: sev_challenge.start,
+        sev_es,
+        deadline,
+    })
+}
+
 /// Payload sent to the attestation server on session request.
 #[derive(Serialize, Deserialize)]
 struct SessionRequest {
@@ -261,194 +691,2695 @@ struct SessionResponse {
     start: Start,
 }
 
-pub struct AmdSev {
-    tee_config: TeeConfig,
-    fw: Firmware,
-    start: Start,
-    sev_es: bool,
-    curl_agent: Arc<Mutex<CurlAgent>>,
+/// State of the SEV platform, as reported by the PSP firmware.
+#[derive(Debug, Serialize)]
+pub struct PlatformStatus {
+    /// Version of the firmware API, as `(major, minor)`.
+    pub api_version: (u8, u8),
+    /// Firmware build number.
+    pub build: u8,
+    /// Whether the platform is uninitialized, initialized or running guests.
+    pub state: PlatformState,
+    /// Whether the platform is owned by an external entity, instead of self-owned.
+    pub owned: bool,
+    /// Whether SEV-ES guests can be launched.
+    pub sev_es: bool,
+    /// Whether SEV-SNP guests can be launched.
+    pub snp: bool,
+    /// Number of guests currently managed by the firmware.
+    pub guests: u32,
 }
 
-impl AmdSev {
-    pub fn new(tee_config: &TeeConfig) -> Result<Self, Error> {
-        let mut fw = Firmware::open().map_err(Error::OpenFirmware)?;
-        let mut curl_agent = CurlAgent::new();
-        let chain = get_and_store_chain(&mut fw, tee_config, &mut curl_agent)?;
-        let mut sev_es = false;
-
-        let start = if !tee_config.attestation_url.is_empty() {
-            let build = fw
-                .platform_status()
-                .map_err(|_| Error::PlatformStatus)?
-                .build;
-
-            let sev_request = SevRequest {
-                build,
-                chain,
-                workload_id: tee_config.workload_id.clone(),
-            };
-            let request = Request {
-                version: "0.0.0".to_string(),
-                tee: tee_config.tee,
-                extra_params: serde_json::json!(sev_request),
-            };
+/// Lifecycle state of the SEV platform.
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub enum PlatformState {
+    Uninitialized,
+    Initialized,
+    Working,
+}
 
-            let response = curl_agent
-                .post(
-                    format!("{}/kbs/v0/auth", tee_config.attestation_url).as_str(),
-                    serde_json::json!(request).to_string().as_bytes(),
-                )
-                .map_err(Error::SessionRequest)?;
-
-            let challenge: Challenge =
-                serde_json::from_slice(&response).map_err(Error::ParseSessionResponse)?;
-            let sev_challenge: SevChallenge = serde_json::from_value(challenge.extra_params)
-                .map_err(Error::ParseSessionResponse)?;
-
-            if sev_challenge
-                .start
-                .policy
-                .flags
-                .contains(PolicyFlags::ENCRYPTED_STATE)
-            {
-                sev_es = true;
-            }
+fn query_platform_status(fw: &mut Firmware) -> Result<PlatformStatus, Error> {
+    let status = fw.platform_status().map_err(|_| Error::PlatformStatus)?;
+    // SNP is only usable once the firmware has initialized the RMP table.
+    let snp = fw
+        .snp_platform_status()
+        .map(|snp_status| snp_status.is_rmp_init != 0)
+        .unwrap_or(false);
+
+    Ok(PlatformStatus {
+        api_version: (status.build.version.major, status.build.version.minor),
+        build: status.build.build,
+        state: match status.state {
+            State::Uninitialized => PlatformState::Uninitialized,
+            State::Initialized => PlatformState::Initialized,
+            State::Working => PlatformState::Working,
+        },
+        owned: status.flags.contains(PlatformStatusFlags::OWNED),
+        sev_es: status.flags.contains(PlatformStatusFlags::ENCRYPTED_STATE),
+        snp,
+        guests: status.guests,
+    })
+}
 
-            sev_challenge.start
-        } else {
-            let policy = Policy::default();
-            let session = Session::try_from(policy).map_err(Error::SessionFromPolicy)?;
-            session.start(chain).map_err(Error::StartFromSession)?
-        };
+/// Queries the SEV platform status without preparing a launch, so callers can
+/// probe the host capabilities before configuring a confidential VM.
+pub fn platform_status() -> Result<PlatformStatus, Error> {
+    let mut fw = Firmware::open().map_err(Error::OpenFirmware)?;
+    query_platform_status(&mut fw)
+}
 
-        Ok(AmdSev {
-            tee_config: tee_config.clone(),
+/// Phases of the SEV launch, each one with the time elapsed since the launch
+/// started being set up.
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+    /// The certificate chain was fetched or loaded.
+    ChainFetched(Duration),
+    /// The launch session was established, with the attestation server or locally.
+    SessionEstablished(Duration),
+    /// The initial guest memory was measured.
+    RegionsMeasured(Duration),
+    /// The firmware computed the launch measurement.
+    MeasurementComputed(Duration),
+    /// The secret was injected into the guest.
+    SecretInjected(Duration),
+    /// The launch finished, and the guest may start running.
+    Finished(Duration),
+}
+
+/// The firmware and KVM commands issued while launching a SEV guest.
+///
+/// `AmdSev` only drives the launch through this trait, so the sequence of
+/// commands can be checked without SEV hardware.
+pub trait SevFirmware: Send {
+    /// Registers a range of guest memory as encrypted with KVM.
+    fn register_memory(&mut self, vm_fd: &VmFd, host_addr: u64, size: u64) -> Result<(), Error>;
+
+    /// Initializes SEV, or SEV-ES if `sev_es` is set, and starts the launch.
+    fn launch_start(&mut self, vm_fd: &VmFd, start: Start, sev_es: bool) -> Result<(), Error>;
+
+    /// Encrypts and measures `size` bytes of guest memory at `host_addr`.
+    fn launch_update_data(
+        &mut self,
+        vm_fd: &VmFd,
+        host_addr: u64,
+        size: usize,
+    ) -> Result<(), Error>;
+
+    /// Encrypts and measures the initial register state of the vCPUs.
+    fn launch_update_vmsa(&mut self, vm_fd: &VmFd) -> Result<(), Error>;
+
+    /// Returns the measurement of everything encrypted since the launch started.
+    fn launch_measure(&mut self, vm_fd: &VmFd) -> Result<Measurement, Error>;
+
+    /// Injects a secret packet into guest memory at `host_addr`.
+    fn launch_secret(&mut self, vm_fd: &VmFd, secret: &Secret, host_addr: u64)
+        -> Result<(), Error>;
+
+    /// Finishes the launch, after which the guest may run.
+    fn launch_finish(&mut self, vm_fd: &VmFd) -> Result<(), Error>;
+
+    /// Decrypts `len` bytes of guest memory at `src_uaddr` into `dst_uaddr`.
+    /// `src_uaddr` and `len` must be 16-byte aligned.
+    fn dbg_decrypt(
+        &mut self,
+        vm_fd: &VmFd,
+        src_uaddr: u64,
+        dst_uaddr: u64,
+        len: usize,
+    ) -> Result<(), Error>;
+
+    /// Encrypts `len` bytes at `src_uaddr` into guest memory at `dst_uaddr`.
+    /// `dst_uaddr` and `len` must be 16-byte aligned.
+    fn dbg_encrypt(
+        &mut self,
+        vm_fd: &VmFd,
+        src_uaddr: u64,
+        dst_uaddr: u64,
+        len: usize,
+    ) -> Result<(), Error>;
+
+    /// Starts sending the launched guest to the platform owning `target`.
+    fn send_start(
+        &mut self,
+        vm_fd: &VmFd,
+        target: &MigrationCerts,
+    ) -> Result<MigrationSession, Error>;
+
+    /// Initializes SEV, or SEV-ES if `sev_es` is set, and starts receiving a guest
+    /// sent from the platform owning `source_pdh`.
+    fn receive_start(
+        &mut self,
+        vm_fd: &VmFd,
+        source_pdh: &[u8],
+        session: &MigrationSession,
+        sev_es: bool,
+    ) -> Result<(), Error>;
+
+    /// Shares the encryption context of the guest in `vm_fd` with `mirror_fd`.
+    fn mirror_context(&mut self, vm_fd: &VmFd, mirror_fd: &VmFd) -> Result<(), Error>;
+}
+
+/// Certificates of the platform a guest is migrated to, as exported by its firmware.
+#[derive(Clone, Debug, Default)]
+pub struct MigrationCerts {
+    /// Platform Diffie-Hellman key certificate.
+    pub pdh_cert: Vec<u8>,
+    /// PEK and OCA certificates.
+    pub plat_certs: Vec<u8>,
+    /// ASK and ARK certificates.
+    pub amd_certs: Vec<u8>,
+}
+
+/// Transport session of a guest being migrated, created by the firmware of the
+/// source platform for the destination one.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationSession {
+    /// Policy the guest was launched with.
+    pub policy: u32,
+    /// Transport keys, wrapped for the destination platform.
+    pub session: Vec<u8>,
+}
+
+enum LaunchState {
+    New,
+    Started,
+    Measured,
+    Finished,
+    Sending,
+    Receiving,
+}
+
+/// The SEV device, which KVM forwards the commands for a guest to.
+pub trait SevDevice: AsRawFd + Send {
+    /// Issues `cmd` for the guest in `vm_fd`.
+    fn issue(&self, vm_fd: &VmFd, cmd: &mut kvm_sev_cmd) -> Result<(), kvm_ioctls::Error> {
+        vm_fd.encrypt_op_sev(cmd)
+    }
+}
+
+impl SevDevice for Firmware {}
+
+/// Policy in the layout the firmware expects, with the minimum firmware
+/// version in the upper half.
+fn policy_bits(policy: &Policy) -> u32 {
+    u32::from(policy.flags.bits())
+        | u32::from(policy.minfw.major) << 16
+        | u32::from(policy.minfw.minor) << 24
+}
+
+/// `SevFirmware` implementation issuing the commands to the SEV device through KVM.
+///
+/// All the commands go through `encrypt_op_sev`, so they are retried the same
+/// way while the firmware is busy.
+pub struct KvmSevFirmware<D: SevDevice = Firmware> {
+    fw: D,
+    firmware_retries: u32,
+    state: LaunchState,
+    /// Stops the retries on a busy firmware.
+    cancel: CancelToken,
+}
+
+// The firmware decrypts and encrypts guest memory in 16-byte blocks.
+fn check_dbg_alignment(guest_uaddr: u64, len: usize) -> Result<(), Error> {
+    if !guest_uaddr.is_multiple_of(16) || !len.is_multiple_of(16) {
+        return Err(Error::DebugMisaligned(guest_uaddr, len));
+    }
+    Ok(())
+}
+
+// The sizes reach the firmware as u32, which mustn't truncate them.
+fn check_secret_size(size: usize) -> Result<(), Error> {
+    u32::try_from(size)
+        .map(|_| ())
+        .map_err(|_| Error::SecretTooLarge(size))
+}
+
+impl<D: SevDevice> KvmSevFirmware<D> {
+    pub fn new(fw: D, firmware_retries: u32, cancel: CancelToken) -> Self {
+        KvmSevFirmware {
             fw,
-            start,
-            sev_es,
-            curl_agent: Arc::new(Mutex::new(curl_agent)),
-        })
+            firmware_retries,
+            state: LaunchState::New,
+            cancel,
+        }
+    }
+
+    /// Issues a SEV command, retrying with exponential backoff while the firmware
+    /// reports being busy, until the launch is cancelled. Any other failure is
+    /// returned right away.
+    fn encrypt_op_sev(&self, vm_fd: &VmFd, cmd: &mut kvm_sev_cmd) -> Result<(), kvm_ioctls::Error> {
+        let mut backoff = FIRMWARE_BUSY_BACKOFF;
+        let mut retries = 0;
+
+        loop {
+            cmd.error = 0;
+            match self.fw.issue(vm_fd, cmd) {
+                Err(e) if firmware_busy(&e, cmd) && self.cancel.is_cancelled() => {
+                    return Err(kvm_ioctls::Error::new(libc::ECANCELED));
+                }
+                Err(e) if firmware_busy(&e, cmd) && retries < self.firmware_retries => {
+                    retries += 1;
+                    warn!(
+                        "SEV firmware busy on command {}, retrying in {:?} ({}/{})",
+                        cmd.id, backoff, retries, self.firmware_retries
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
     }
 
-    fn sev_launch_update_data(
+    /// Issues a SEV_DBG_DECRYPT or SEV_DBG_ENCRYPT command, mapping its failure
+    /// with `err`.
+    fn sev_dbg(
         &self,
         vm_fd: &VmFd,
-        data_uaddr: u64,
-        data_size: usize,
-    ) -> Result<(), kvm_ioctls::Error> {
+        id: u32,
+        src_uaddr: u64,
+        dst_uaddr: u64,
+        len: usize,
+        err: fn(kvm_ioctls::Error) -> Error,
+    ) -> Result<(), Error> {
         #[repr(C)]
         struct Data {
-            addr: u64,
-            size: u32,
+            src_uaddr: u64,
+            dst_uaddr: u64,
+            len: u32,
         }
 
         let mut data = Data {
-            addr: data_uaddr,
-            size: data_size as u32,
+            src_uaddr,
+            dst_uaddr,
+            len: u32::try_from(len).map_err(|_| Error::DebugTooLarge(len))?,
         };
 
         let mut cmd = kvm_sev_cmd {
-            id: 3, // SEV_LAUNCH_UPDATE_DATA
+            id,
             pad0: 0,
             data: &mut data as *mut _ as u64,
             error: 0,
             sev_fd: self.fw.as_raw_fd() as u32,
         };
 
-        vm_fd.encrypt_op_sev(&mut cmd)
+        self.encrypt_op_sev(vm_fd, &mut cmd).map_err(err)
     }
+}
 
-    pub fn vm_prepare(
-        &self,
-        vm_fd: &VmFd,
-        guest_mem: &GuestMemoryMmap,
-    ) -> Result<Launcher<Started, RawFd, RawFd>, Error> {
-        let vm_rfd = vm_fd.as_raw_fd();
-        let fw_rfd = self.fw.as_raw_fd();
-
-        let launcher = if self.sev_es {
-            Launcher::new_es(vm_rfd, fw_rfd).unwrap()
-        } else {
-            Launcher::new(vm_rfd, fw_rfd).unwrap()
+impl<D: SevDevice> SevFirmware for KvmSevFirmware<D> {
+    fn register_memory(&mut self, vm_fd: &VmFd, host_addr: u64, size: u64) -> Result<(), Error> {
+        let enc_region = kvm_enc_region {
+            addr: host_addr,
+            size,
         };
+        vm_fd
+            .register_enc_memory_region(&enc_region)
+            .map_err(|_| Error::MemoryEncryptRegion)
+    }
 
-        for region in guest_mem.iter() {
-            // It's safe to unwrap because the guest address is valid.
-            let host_addr = guest_mem.get_host_address(region.start_addr()).unwrap();
-            let enc_region = kvm_enc_region {
-                addr: host_addr as u64,
-                size: region.len(),
-            };
-            vm_fd
-                .register_enc_memory_region(&enc_region)
-                .map_err(|_| Error::MemoryEncryptRegion)?;
+    fn launch_start(&mut self, vm_fd: &VmFd, start: Start, sev_es: bool) -> Result<(), Error> {
+        if !matches!(self.state, LaunchState::New) {
+            return Err(Error::UnexpectedLaunchCommand);
         }
 
-        let launcher = launcher.start(self.start).unwrap();
+        let mut cmd = kvm_sev_cmd {
+            id: if sev_es { 1 } else { 0 }, // SEV_ES_INIT or SEV_INIT
+            pad0: 0,
+            data: 0,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevInit)?;
+
+        let mut data = kvm_sev_launch_start {
+            policy: policy_bits(&start.policy),
+            dh_uaddr: &start.cert as *const _ as u64,
+            dh_len: std::mem::size_of_val(&start.cert) as u32,
+            session_uaddr: &start.session as *const _ as u64,
+            session_len: std::mem::size_of_val(&start.session) as u32,
+            ..Default::default()
+        };
+
+        let mut cmd = kvm_sev_cmd {
+            id: 2, // SEV_LAUNCH_START
+            pad0: 0,
+            data: &mut data as *mut _ as u64,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevLaunchStart)?;
+        self.state = LaunchState::Started;
 
-        Ok(launcher)
+        Ok(())
     }
 
-    pub fn vm_attest(
-        &self,
+    fn launch_update_data(
+        &mut self,
         vm_fd: &VmFd,
-        guest_mem: &GuestMemoryMmap,
-        measured_regions: Vec<MeasuredRegion>,
-        mut launcher: Launcher<Started, RawFd, RawFd>,
+        host_addr: u64,
+        size: usize,
     ) -> Result<(), Error> {
-        for region in measured_regions {
-            self.sev_launch_update_data(vm_fd, region.host_addr, region.size)
-                .map_err(Error::SevLaunchUpdateData)?;
+        if !matches!(self.state, LaunchState::Started) {
+            return Err(Error::UnexpectedLaunchCommand);
         }
 
-        if self.sev_es {
-            launcher.update_vmsa().unwrap()
+        #[repr(C)]
+        struct Data {
+            addr: u64,
+            size: u32,
         }
 
-        let mut launcher = launcher.measure().unwrap();
-        let measurement = launcher.measurement();
+        let mut data = Data {
+            addr: host_addr,
+            size: size as u32,
+        };
 
-        if !self.tee_config.attestation_url.is_empty() {
-            let tee_pubkey = TeePubKey::RSA {
-                alg: "".to_string(),
-                k_mod: "".to_string(),
-                k_exp: "".to_string(),
-            };
+        let mut cmd = kvm_sev_cmd {
+            id: 3, // SEV_LAUNCH_UPDATE_DATA
+            pad0: 0,
+            data: &mut data as *mut _ as u64,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
 
-            let attestation = Attestation {
-                tee_pubkey,
-                tee_evidence: serde_json::json!(measurement),
-            };
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevLaunchUpdateData)
+    }
 
-            let mut curl_agent = self.curl_agent.lock().unwrap();
-            curl_agent
-                .post(
-                    &format!("{}/kbs/v0/attest", self.tee_config.attestation_url,),
-                    serde_json::json!(attestation).to_string().as_bytes(),
-                )
-                .map_err(Error::AttestationRequest)?;
-
-            let secret_resp = curl_agent
-                .get(&format!(
-                    "{}/kbs/v0/key/{}",
-                    self.tee_config.attestation_url, self.tee_config.workload_id,
-                ))
-                .map_err(Error::AttestationRequest)?;
-
-            let secret: Secret =
-                serde_json::from_slice(&secret_resp).map_err(Error::ParseAttestationSecret)?;
-
-            let secret_host_addr = guest_mem
-                .get_host_address(GuestAddress(arch::x86_64::layout::CMDLINE_START))
-                .unwrap() as u64;
-
-            launcher
-                .inject(&secret, secret_host_addr.try_into().unwrap())
-                .unwrap();
+    fn launch_update_vmsa(&mut self, vm_fd: &VmFd) -> Result<(), Error> {
+        if !matches!(self.state, LaunchState::Started) {
+            return Err(Error::UnexpectedLaunchCommand);
         }
 
-        let _handle = launcher.finish();
-
-        Ok(())
+        let mut cmd = kvm_sev_cmd {
+            id: 4, // SEV_LAUNCH_UPDATE_VMSA
+            pad0: 0,
+            data: 0,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevLaunchUpdateVmsa)
+    }
+
+    fn launch_measure(&mut self, vm_fd: &VmFd) -> Result<Measurement, Error> {
+        if !matches!(self.state, LaunchState::Started) {
+            return Err(Error::UnexpectedLaunchCommand);
+        }
+
+        let mut measurement = Measurement {
+            measure: [0; 32],
+            mnonce: [0; 16],
+        };
+        let mut data = kvm_sev_launch_measure {
+            uaddr: &mut measurement as *mut _ as u64,
+            len: std::mem::size_of::<Measurement>() as u32,
+            ..Default::default()
+        };
+
+        let mut cmd = kvm_sev_cmd {
+            id: 6, // SEV_LAUNCH_MEASURE
+            pad0: 0,
+            data: &mut data as *mut _ as u64,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+        // The launch can't go on once the firmware failed to measure it.
+        self.state = LaunchState::Finished;
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevLaunchMeasure)?;
+        self.state = LaunchState::Measured;
+
+        Ok(measurement)
+    }
+
+    fn launch_secret(
+        &mut self,
+        vm_fd: &VmFd,
+        secret: &Secret,
+        host_addr: u64,
+    ) -> Result<(), Error> {
+        check_secret_size(secret.ciphertext.len())?;
+        if !matches!(self.state, LaunchState::Measured) {
+            return Err(Error::UnexpectedLaunchCommand);
+        }
+
+        let mut data = kvm_sev_launch_secret {
+            hdr_uaddr: &secret.header as *const _ as u64,
+            hdr_len: std::mem::size_of::<Header>() as u32,
+            guest_uaddr: host_addr,
+            guest_len: secret.ciphertext.len() as u32,
+            trans_uaddr: secret.ciphertext.as_ptr() as u64,
+            trans_len: secret.ciphertext.len() as u32,
+            ..Default::default()
+        };
+
+        let mut cmd = kvm_sev_cmd {
+            id: 5, // SEV_LAUNCH_SECRET
+            pad0: 0,
+            data: &mut data as *mut _ as u64,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevInjectSecret)
+    }
+
+    fn launch_finish(&mut self, vm_fd: &VmFd) -> Result<(), Error> {
+        if !matches!(self.state, LaunchState::Measured) {
+            return Err(Error::UnexpectedLaunchCommand);
+        }
+
+        let mut cmd = kvm_sev_cmd {
+            id: 7, // SEV_LAUNCH_FINISH
+            pad0: 0,
+            data: 0,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+        self.state = LaunchState::Finished;
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevLaunchFinish)
+    }
+
+    fn dbg_decrypt(
+        &mut self,
+        vm_fd: &VmFd,
+        src_uaddr: u64,
+        dst_uaddr: u64,
+        len: usize,
+    ) -> Result<(), Error> {
+        check_dbg_alignment(src_uaddr, len)?;
+        // SEV_DBG_DECRYPT
+        self.sev_dbg(vm_fd, 17, src_uaddr, dst_uaddr, len, Error::SevDbgDecrypt)
+    }
+
+    fn dbg_encrypt(
+        &mut self,
+        vm_fd: &VmFd,
+        src_uaddr: u64,
+        dst_uaddr: u64,
+        len: usize,
+    ) -> Result<(), Error> {
+        check_dbg_alignment(dst_uaddr, len)?;
+        // SEV_DBG_ENCRYPT
+        self.sev_dbg(vm_fd, 18, src_uaddr, dst_uaddr, len, Error::SevDbgEncrypt)
+    }
+
+    fn send_start(
+        &mut self,
+        vm_fd: &VmFd,
+        target: &MigrationCerts,
+    ) -> Result<MigrationSession, Error> {
+        if !matches!(self.state, LaunchState::Finished) {
+            return Err(Error::UnexpectedLaunchCommand);
+        }
+
+        let mut data = kvm_sev_send_start {
+            pdh_cert_uaddr: target.pdh_cert.as_ptr() as u64,
+            pdh_cert_len: target.pdh_cert.len() as u32,
+            plat_certs_uaddr: target.plat_certs.as_ptr() as u64,
+            plat_certs_len: target.plat_certs.len() as u32,
+            amd_certs_uaddr: target.amd_certs.as_ptr() as u64,
+            amd_certs_len: target.amd_certs.len() as u32,
+            ..Default::default()
+        };
+
+        let mut cmd = kvm_sev_cmd {
+            id: 8, // SEV_SEND_START
+            pad0: 0,
+            data: &mut data as *mut _ as u64,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+
+        // Without a session buffer, the firmware only reports the size it needs.
+        match self.encrypt_op_sev(vm_fd, &mut cmd) {
+            Err(_) if cmd.error == SEV_RET_INVALID_LEN => {}
+            result => result.map_err(Error::SevSendStart)?,
+        }
+
+        let mut session = vec![0u8; data.session_len as usize];
+        data.session_uaddr = session.as_mut_ptr() as u64;
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevSendStart)?;
+        self.state = LaunchState::Sending;
+
+        Ok(MigrationSession {
+            policy: data.policy,
+            session,
+        })
+    }
+
+    fn receive_start(
+        &mut self,
+        vm_fd: &VmFd,
+        source_pdh: &[u8],
+        session: &MigrationSession,
+        sev_es: bool,
+    ) -> Result<(), Error> {
+        if !matches!(self.state, LaunchState::New) {
+            return Err(Error::UnexpectedLaunchCommand);
+        }
+
+        let mut cmd = kvm_sev_cmd {
+            id: if sev_es { 1 } else { 0 }, // SEV_ES_INIT or SEV_INIT
+            pad0: 0,
+            data: 0,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevInit)?;
+
+        let mut data = kvm_sev_receive_start {
+            policy: session.policy,
+            pdh_uaddr: source_pdh.as_ptr() as u64,
+            pdh_len: source_pdh.len() as u32,
+            session_uaddr: session.session.as_ptr() as u64,
+            session_len: session.session.len() as u32,
+            ..Default::default()
+        };
+
+        let mut cmd = kvm_sev_cmd {
+            id: 12, // SEV_RECEIVE_START
+            pad0: 0,
+            data: &mut data as *mut _ as u64,
+            error: 0,
+            sev_fd: self.fw.as_raw_fd() as u32,
+        };
+        self.encrypt_op_sev(vm_fd, &mut cmd)
+            .map_err(Error::SevReceiveStart)?;
+        self.state = LaunchState::Receiving;
+
+        Ok(())
+    }
+
+    fn mirror_context(&mut self, vm_fd: &VmFd, mirror_fd: &VmFd) -> Result<(), Error> {
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_VM_COPY_ENC_CONTEXT_FROM,
+            ..Default::default()
+        };
+        cap.args[0] = vm_fd.as_raw_fd() as u64;
+
+        mirror_fd
+            .enable_cap(&cap)
+            .map_err(Error::MirrorEncryptionContext)
+    }
+}
+
+/// Merges consecutive regions contiguous both in guest and host memory, so they
+/// are measured with fewer commands. The order is kept, as it's part of the
+/// measurement, and SEV measures all the regions with the same attributes.
+fn coalesce_regions(regions: Vec<MeasuredRegion>) -> Vec<MeasuredRegion> {
+    let mut coalesced: Vec<MeasuredRegion> = Vec::with_capacity(regions.len());
+
+    for region in regions {
+        if let Some(last) = coalesced.last_mut() {
+            let end = last.size as u64;
+            // The length of a LAUNCH_UPDATE_DATA command is 32 bits wide.
+            if last.guest_addr + end == region.guest_addr
+                && last.host_addr + end == region.host_addr
+                && last.size + region.size <= u32::MAX as usize
+            {
+                last.size += region.size;
+                continue;
+            }
+        }
+        coalesced.push(region);
+    }
+
+    coalesced
+}
+
+/// Guest memory kept resident while the launch is measured, as migrating or
+/// swapping the pages would invalidate the measurement. Unlocked when dropped.
+struct PinnedMemory {
+    regions: Vec<(u64, usize)>,
+}
+
+impl PinnedMemory {
+    /// Locks every region of `guest_mem`. If a region can't be locked, the ones
+    /// locked so far are released and the guest address of the failing one is
+    /// reported.
+    fn pin(guest_mem: &GuestMemoryMmap) -> Result<Self, Error> {
+        let mut pinned = PinnedMemory {
+            regions: Vec::new(),
+        };
+
+        for region in guest_mem.iter() {
+            // It's safe to unwrap because the guest address is valid.
+            let host_addr = guest_mem.get_host_address(region.start_addr()).unwrap();
+            let size = region.len() as usize;
+            Self::pin_region(host_addr as *mut libc::c_void, size)
+                .map_err(|e| Error::PinGuestMemory(region.start_addr().0, e))?;
+            pinned.regions.push((host_addr as u64, size));
+        }
+
+        Ok(pinned)
+    }
+
+    fn pin_region(addr: *mut libc::c_void, size: usize) -> std::io::Result<()> {
+        // Safe because the range is guest memory, mapped for the lifetime of the VM.
+        // Encrypted pages must be neither shared with children nor merged by
+        // KSM, so these are kept after the launch.
+        if unsafe { libc::madvise(addr, size, libc::MADV_DONTFORK) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // EINVAL means the kernel was built without KSM, so nothing gets merged.
+        if unsafe { libc::madvise(addr, size, libc::MADV_UNMERGEABLE) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINVAL) {
+                return Err(err);
+            }
+        }
+        if unsafe { libc::mlock(addr, size) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PinnedMemory {
+    fn drop(&mut self) {
+        for (host_addr, size) in self.regions.drain(..) {
+            // Safe because the range was locked by `pin_region`.
+            if unsafe { libc::munlock(host_addr as *const libc::c_void, size) } < 0 {
+                warn!(
+                    "Failed to unlock guest memory at {:#x}: {}",
+                    host_addr,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
+
+pub struct AmdSev {
+    tee_config: TeeConfig,
+    /// Commands on the same firmware fd must not interleave, and this VM may be
+    /// driven from several threads in a process running many VMs.
+    fw: Mutex<Box<dyn SevFirmware>>,
+    start: Start,
+    sev_es: bool,
+    http_client: Arc<Mutex<Box<dyn HttpClient + Send>>>,
+    launch_started: bool,
+    /// Guest memory locked between the registration and the end of the launch.
+    pinned: Option<PinnedMemory>,
+    build: sev::Build,
+    chain_json: serde_json::Value,
+    measurement: Option<Measurement>,
+    /// The attestation server that answered the challenge, or the Keylime
+    /// registrar the workload was registered with, if any.
+    kbs_url: String,
+    /// When the KBS stops accepting answers to its challenge.
+    challenge_deadline: Option<Instant>,
+    started: Instant,
+    progress_callback: Option<ProgressCallback>,
+    metrics: LaunchMetrics,
+    /// Aborts the launch at the next step once triggered.
+    cancel: CancelToken,
+}
+
+impl AmdSev {
+    pub fn new(tee_config: &TeeConfig, cancel: &CancelToken) -> Result<Self, Error> {
+        Self::with_http_client(tee_config, http::new_client(tee_config, cancel), cancel)
+    }
+
+    /// Like `new`, talking to the attestation servers and the AMD certificate
+    /// services through the given HTTP client.
+    pub fn with_http_client(
+        tee_config: &TeeConfig,
+        mut http_client: Box<dyn HttpClient + Send>,
+        cancel: &CancelToken,
+    ) -> Result<Self, Error> {
+        tee_config.validate().map_err(Error::InvalidTeeConfig)?;
+
+        if tee_config.offline && !tee_config.attestation_url.is_empty() {
+            return Err(Error::NetworkAccessOffline);
+        }
+
+        let cert_config: SevCertConfig =
+            serde_json::from_str(&tee_config.tee_data).map_err(Error::ParseSevCertConfig)?;
+
+        let started = Instant::now();
+        let report_progress = |phase: Phase| {
+            if let Some(callback) = &tee_config.progress_callback {
+                (callback.0)(phase);
+            }
+        };
+        let mut fw = Firmware::open().map_err(Error::OpenFirmware)?;
+        let chain = if tee_config.skip_chain {
+            debug!("Not using a SEV certificate chain, the KBS fetches it itself");
+            None
+        } else {
+            Some(get_and_store_chain(
+                &mut fw,
+                tee_config,
+                &cert_config,
+                http_client.as_mut(),
+            )?)
+        };
+        let metrics = LaunchMetrics {
+            chain_fetch: started.elapsed(),
+            ..Default::default()
+        };
+        if chain.is_some() {
+            report_progress(Phase::ChainFetched(metrics.chain_fetch));
+        }
+        let chain_json = serde_json::to_value(&chain).map_err(Error::SerializeEvidence)?;
+        let build = fw
+            .platform_status()
+            .map_err(|_| Error::PlatformStatus)?
+            .build;
+        let sev_es;
+        let mut kbs_url = String::new();
+        let mut challenge_deadline = None;
+
+        let start = if !tee_config.attestation_url.is_empty()
+            && tee_config.attestation_protocol == AttestationProtocol::Kbs
+        {
+            let session = kbs_auth(http_client.as_mut(), tee_config, build, chain)?;
+            sev_es = session.sev_es;
+            kbs_url = session.url;
+            challenge_deadline = session.deadline;
+
+            session.start
+        } else {
+            let policy = cert_config.policy.map(Policy::from).unwrap_or_default();
+            sev_es = policy.flags.contains(PolicyFlags::ENCRYPTED_STATE);
+
+            let cache_path = &tee_config.session_cache;
+            let cached_start = if cache_path.is_empty() {
+                None
+            } else {
+                load_session_cache(cache_path, &policy, &chain_json)
+            };
+            match cached_start {
+                Some(start) => {
+                    debug!("Reusing cached SEV session from {}", cache_path);
+                    start
+                }
+                None => {
+                    debug!("Starting local SEV session with policy {:?}", policy);
+                    // The config is validated not to skip the chain here.
+                    let chain = chain.ok_or(Error::MissingChain)?;
+                    let session = Session::try_from(policy).map_err(Error::SessionFromPolicy)?;
+                    let start = session.start(chain).map_err(Error::StartFromSession)?;
+                    if !cache_path.is_empty() {
+                        store_session_cache(cache_path, &chain_json, &start)?;
+                    }
+                    start
+                }
+            }
+        };
+
+        report_progress(Phase::SessionEstablished(started.elapsed()));
+
+        if tee_config.attestation_protocol == AttestationProtocol::Keylime {
+            kbs_url = keylime_register(http_client.as_mut(), tee_config, build, &chain_json)?;
+        }
+
+        if tee_config.allow_debug {
+            warn!(
+                "SEV debugging is enabled: the host may read and write the guest memory in clear"
+            );
+        }
+
+        Ok(AmdSev {
+            tee_config: tee_config.clone(),
+            fw: Mutex::new(Box::new(KvmSevFirmware::new(
+                fw,
+                tee_config.firmware_retries,
+                cancel.clone(),
+            ))),
+            start,
+            sev_es,
+            http_client: Arc::new(Mutex::new(http_client)),
+            launch_started: false,
+            pinned: None,
+            build,
+            chain_json,
+            measurement: None,
+            kbs_url,
+            challenge_deadline,
+            started,
+            progress_callback: tee_config.progress_callback.clone(),
+            metrics,
+            cancel: cancel.clone(),
+        })
+    }
+
+    /// Whether the guest is launched with SEV-ES, as requested by the policy
+    /// of the attestation server or of the TEE config.
+    pub fn is_sev_es(&self) -> bool {
+        self.sev_es
+    }
+
+    fn check_cancelled(&self) -> Result<(), Error> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn report_progress(&self, phase: fn(Duration) -> Phase) {
+        if let Some(callback) = &self.progress_callback {
+            (callback.0)(phase(self.started.elapsed()));
+        }
+    }
+
+    /// Checks both the user and the guest owner agreed to let the host see the
+    /// guest memory in clear.
+    fn check_debug_allowed(&self) -> Result<(), Error> {
+        if !self.tee_config.allow_debug {
+            return Err(Error::DebugNotAllowed);
+        }
+
+        if self.start.policy.flags.contains(PolicyFlags::NO_DEBUG) {
+            return Err(Error::DebugForbiddenByPolicy);
+        }
+
+        Ok(())
+    }
+
+    fn debug_host_addr(
+        guest_mem: &GuestMemoryMmap,
+        guest_addr: GuestAddress,
+        len: usize,
+    ) -> Result<u64, Error> {
+        if !guest_mem.check_range(guest_addr, len) {
+            return Err(Error::InvalidGuestRange);
+        }
+
+        guest_mem
+            .get_host_address(guest_addr)
+            .map(|addr| addr as u64)
+            .map_err(|_| Error::InvalidGuestRange)
+    }
+
+    /// Decrypts `buf.len()` bytes of guest memory at `guest_addr` into `buf`.
+    ///
+    /// Requires `allow_debug` in the TEE config and a launch policy without
+    /// `NO_DEBUG`. Both the address and the length must be 16-byte aligned.
+    fn sev_dbg_decrypt(
+        &self,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        guest_addr: GuestAddress,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        self.check_debug_allowed()?;
+        warn!(
+            "DEBUG: decrypting {} bytes of SEV guest memory at {:#x}",
+            buf.len(),
+            guest_addr.0
+        );
+
+        let src_uaddr = Self::debug_host_addr(guest_mem, guest_addr, buf.len())?;
+        self.fw
+            .lock()
+            .unwrap()
+            .dbg_decrypt(vm_fd, src_uaddr, buf.as_mut_ptr() as u64, buf.len())
+    }
+
+    /// Encrypts `buf` into guest memory at `guest_addr`.
+    ///
+    /// Same requirements as `sev_dbg_decrypt`.
+    fn sev_dbg_encrypt(
+        &self,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        guest_addr: GuestAddress,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        self.check_debug_allowed()?;
+        warn!(
+            "DEBUG: encrypting {} bytes into SEV guest memory at {:#x}",
+            buf.len(),
+            guest_addr.0
+        );
+
+        let dst_uaddr = Self::debug_host_addr(guest_mem, guest_addr, buf.len())?;
+        self.fw
+            .lock()
+            .unwrap()
+            .dbg_encrypt(vm_fd, buf.as_ptr() as u64, dst_uaddr, buf.len())
+    }
+
+    /// Pins the guest memory and registers it as encrypted.
+    fn register_memory(
+        fw: &mut dyn SevFirmware,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        metrics: &mut LaunchMetrics,
+    ) -> Result<PinnedMemory, Error> {
+        let now = Instant::now();
+        let pinned = PinnedMemory::pin(guest_mem)?;
+        // Hugepage-backed regions may end in the middle of a hugepage, which
+        // KVM pins as a whole, so registering the guest range is enough.
+        for region in guest_mem.iter() {
+            // It's safe to unwrap because the guest address is valid.
+            let host_addr = guest_mem.get_host_address(region.start_addr()).unwrap();
+            fw.register_memory(vm_fd, host_addr as u64, region.len())?;
+            metrics.registered_regions += 1;
+            metrics.registered_bytes += region.len();
+        }
+        metrics.memory_registration = now.elapsed();
+
+        Ok(pinned)
+    }
+
+    fn challenge_expired(&self) -> bool {
+        self.challenge_deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Answers a new KBS challenge if the current one expired. This is only
+    /// possible until the launch starts, as the session is then bound to it.
+    fn refresh_challenge(&mut self) -> Result<(), Error> {
+        if !self.challenge_expired() {
+            return Ok(());
+        }
+
+        info!(
+            "Attestation challenge from {} expired, starting a new session",
+            self.kbs_url
+        );
+        let session = serde_json::from_value(self.chain_json.clone())
+            .map_err(Error::ParseSessionResponse)
+            .and_then(|chain| {
+                let mut http_client = self.http_client.lock().unwrap();
+                kbs_auth(http_client.as_mut(), &self.tee_config, self.build, chain)
+            });
+        let session = match session {
+            Ok(session) => session,
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(e) => {
+                warn!("Attestation session could not be renewed: {:?}", e);
+                return Err(Error::ChallengeExpired);
+            }
+        };
+
+        self.start = session.start;
+        self.sev_es = session.sev_es;
+        self.kbs_url = session.url;
+        self.challenge_deadline = session.deadline;
+
+        Ok(())
+    }
+
+    fn launch_start(&mut self, vm_fd: &VmFd, guest_mem: &GuestMemoryMmap) -> Result<(), Error> {
+        self.check_cancelled()?;
+        self.refresh_challenge()?;
+        let mut fw = self.fw.lock().unwrap();
+
+        self.pinned = Some(Self::register_memory(
+            &mut **fw,
+            vm_fd,
+            guest_mem,
+            &mut self.metrics,
+        )?);
+        fw.launch_start(vm_fd, self.start, self.sev_es)?;
+        debug!("SEV launch started, SEV-ES: {}", self.sev_es);
+
+        Ok(())
+    }
+
+    /// Checks both the user and the guest owner agreed to let the guest be
+    /// migrated, with `policy` being the one the guest was launched with.
+    fn check_migration_allowed(&self, policy: &Policy) -> Result<(), Error> {
+        if !self.tee_config.allow_migration {
+            return Err(Error::MigrationNotAllowed);
+        }
+
+        if policy.flags.contains(PolicyFlags::NO_SEND) {
+            return Err(Error::MigrationForbiddenByPolicy);
+        }
+
+        Ok(())
+    }
+
+    /// Starts sending the guest to another platform, whose firmware exported
+    /// `target`. The returned session must be handed to `receive_start` on the
+    /// destination, along with the PDH certificate of this platform.
+    ///
+    /// Requires `allow_migration` in the TEE config, a launch policy without
+    /// `NO_SEND`, and the launch to be finished.
+    #[allow(unused)]
+    pub fn send_start(
+        &self,
+        vm_fd: &VmFd,
+        target: &MigrationCerts,
+    ) -> Result<MigrationSession, Error> {
+        self.check_migration_allowed(&self.start.policy)?;
+        let session = self.fw.lock().unwrap().send_start(vm_fd, target)?;
+        info!("SEV migration started, policy: {:#x}", session.policy);
+
+        Ok(session)
+    }
+
+    /// Starts receiving a guest sent from the platform owning `source_pdh`,
+    /// instead of launching a new one.
+    ///
+    /// Requires `allow_migration` in the TEE config and a policy without `NO_SEND`
+    /// in `session`.
+    #[allow(unused)]
+    pub fn receive_start(
+        &mut self,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        source_pdh: &[u8],
+        session: &MigrationSession,
+    ) -> Result<(), Error> {
+        let policy = Policy::from(session.policy);
+        self.check_migration_allowed(&policy)?;
+
+        if self.launch_started {
+            return Err(Error::UnexpectedLaunchCommand);
+        }
+        self.check_cancelled()?;
+
+        let sev_es = policy.flags.contains(PolicyFlags::ENCRYPTED_STATE);
+        let mut fw = self.fw.lock().unwrap();
+
+        self.pinned = Some(Self::register_memory(
+            &mut **fw,
+            vm_fd,
+            guest_mem,
+            &mut self.metrics,
+        )?);
+        fw.receive_start(vm_fd, source_pdh, session, sev_es)?;
+        debug!("SEV migration receive started, SEV-ES: {}", sev_es);
+
+        Ok(())
+    }
+
+    /// Shares the encryption context (ASID) of the guest with `mirror_fd`, an
+    /// otherwise empty VM running a migration agent, so the agent can access
+    /// the encrypted guest memory.
+    ///
+    /// Same requirements as `send_start`, except for the launch being finished.
+    #[allow(unused)]
+    pub fn setup_migration_agent(&self, vm_fd: &VmFd, mirror_fd: &VmFd) -> Result<(), Error> {
+        self.check_migration_allowed(&self.start.policy)?;
+        self.fw.lock().unwrap().mirror_context(vm_fd, mirror_fd)
+    }
+
+    /// The KBS resources to request once the launch is attested.
+    fn resource_ids(&self) -> Vec<&str> {
+        if self.tee_config.resource_ids.is_empty() {
+            vec![self.tee_config.workload_id.as_str()]
+        } else {
+            self.tee_config
+                .resource_ids
+                .iter()
+                .map(String::as_str)
+                .collect()
+        }
+    }
+
+    fn fetch_secrets(&self, measurement: Measurement) -> Result<Vec<Secret>, Error> {
+        // The launch was measured against this challenge, so it can't be renewed.
+        if self.challenge_expired() {
+            return Err(Error::ChallengeExpired);
+        }
+
+        let tee_key = self
+            .tee_config
+            .key_wrap_algorithm
+            .map(TeeKey::generate)
+            .transpose()
+            .map_err(Error::TeeKey)?;
+        let tee_pubkey = match &tee_key {
+            Some(tee_key) => tee_key.public_key().map_err(Error::TeeKey)?,
+            // The resources are served unwrapped.
+            None => TeePubKey::RSA {
+                alg: "".to_string(),
+                k_mod: "".to_string(),
+                k_exp: "".to_string(),
+            },
+        };
+
+        let attestation = Attestation {
+            tee_pubkey,
+            tee_evidence: serde_json::json!(measurement),
+        };
+
+        let mut http_client = self.http_client.lock().unwrap();
+
+        info!("Sending attestation evidence to {}", self.kbs_url);
+        let now = Instant::now();
+        http_client
+            .post(
+                &format!("{}/kbs/v0/attest", self.kbs_url),
+                serde_json::json!(attestation).to_string().as_bytes(),
+            )
+            .map_err(Error::from_request(Error::AttestationRequest))?;
+        info!("Attestation evidence accepted in {:?}", now.elapsed());
+
+        let mut secrets = Vec::new();
+        for resource_id in self.resource_ids() {
+            info!(
+                "Requesting secret {} for workload {} from {}",
+                resource_id, self.tee_config.workload_id, self.kbs_url
+            );
+            let now = Instant::now();
+            let secret_resp = http_client
+                .get(&format!("{}/kbs/v0/key/{}", self.kbs_url, resource_id))
+                .map_err(Error::from_request(Error::AttestationRequest))?;
+
+            let secret_resp = match &tee_key {
+                Some(tee_key) => {
+                    let response: Response = serde_json::from_slice(&secret_resp)
+                        .map_err(|e| Error::ParseAttestationSecret(e.classify()))?;
+                    tee_key.unwrap(&response).map_err(Error::TeeKey)?
+                }
+                None => secret_resp,
+            };
+
+            // Don't keep the parser error itself, as it may quote the secret.
+            let secret = serde_json::from_slice(&secret_resp)
+                .map_err(|e| Error::ParseAttestationSecret(e.classify()))?;
+            info!(
+                "Attestation secret {} served by {} in {:?}",
+                resource_id,
+                self.kbs_url,
+                now.elapsed()
+            );
+            secrets.push(secret);
+        }
+
+        Ok(secrets)
+    }
+
+    /// Submits the launch evidence to the Keylime verifier, for the workload
+    /// registered when the instance was created.
+    fn submit_keylime_evidence(&self) -> Result<(), Error> {
+        let evidence = self.evidence()?;
+        let url = format!(
+            "{}/{}/agents/{}",
+            self.tee_config.keylime_verifier_url, KEYLIME_API_VERSION, self.tee_config.workload_id
+        );
+
+        info!(
+            "Submitting attestation evidence to Keylime verifier {}",
+            url
+        );
+        let now = Instant::now();
+        self.http_client
+            .lock()
+            .unwrap()
+            .post(&url, evidence.as_bytes())
+            .map_err(Error::from_request(Error::KeylimeRequest))?;
+        info!("Attestation evidence submitted in {:?}", now.elapsed());
+
+        Ok(())
+    }
+
+    fn evidence(&self) -> Result<String, Error> {
+        let measurement = self.measurement.as_ref().ok_or(Error::MissingMeasurement)?;
+
+        let evidence = AttestationEvidence {
+            version: EVIDENCE_VERSION,
+            measurement,
+            build: &self.build,
+            policy: &self.start.policy,
+            chain: &self.chain_json,
+        };
+
+        serde_json::to_string(&evidence).map_err(Error::SerializeEvidence)
+    }
+
+    /// Gets the secret to inject from the attestation server or the secret
+    /// file, if there's any.
+    fn get_secrets(&self, measurement: Measurement) -> Result<Vec<Secret>, Error> {
+        if !self.tee_config.attestation_url.is_empty()
+            && self.tee_config.attestation_protocol == AttestationProtocol::Kbs
+        {
+            self.fetch_secrets(measurement)
+        } else if !self.tee_config.secret_file.is_empty() {
+            debug!("Reading secret from {}", self.tee_config.secret_file);
+            Ok(vec![read_secret_file(&self.tee_config.secret_file)?])
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn launch_finish(
+        &mut self,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        measured_regions: Vec<MeasuredRegion>,
+    ) -> Result<(), Error> {
+        let launch_start = Instant::now();
+
+        // Don't hold the firmware while talking to the attestation server.
+        let mut fw = self.fw.lock().unwrap();
+        let now = Instant::now();
+        for region in coalesce_regions(measured_regions) {
+            self.check_cancelled()?;
+            debug!(
+                "Measuring region at {:#x} ({} bytes)",
+                region.guest_addr, region.size
+            );
+            fw.launch_update_data(vm_fd, region.host_addr, region.size)?;
+            self.metrics.updated_regions += 1;
+            self.metrics.updated_bytes += region.size as u64;
+        }
+
+        if self.sev_es {
+            // APs are brought up through the jump table, so it must be measured
+            // along with the BSP VMSA.
+            arch::x86_64::setup_sev_ap_jump_table(guest_mem).map_err(Error::ApJumpTableSetup)?;
+            let jump_table_addr = arch::x86_64::layout::SEV_AP_JUMP_TABLE_START;
+            debug!("Measuring SEV-ES AP jump table at {:#x}", jump_table_addr);
+            fw.launch_update_data(
+                vm_fd,
+                guest_mem
+                    .get_host_address(GuestAddress(jump_table_addr))
+                    .unwrap() as u64,
+                arch::x86_64::layout::SEV_AP_JUMP_TABLE_SIZE,
+            )?;
+            self.metrics.updated_regions += 1;
+            self.metrics.updated_bytes += arch::x86_64::layout::SEV_AP_JUMP_TABLE_SIZE as u64;
+
+            fw.launch_update_vmsa(vm_fd)?;
+        }
+        self.metrics.update_data = now.elapsed();
+        self.report_progress(Phase::RegionsMeasured);
+
+        let now = Instant::now();
+        let measurement = fw.launch_measure(vm_fd)?;
+        self.metrics.measure = now.elapsed();
+        drop(fw);
+        self.measurement = Some(measurement);
+        self.report_progress(Phase::MeasurementComputed);
+        info!(
+            "SEV launch measured in {:?}: {:?}",
+            launch_start.elapsed(),
+            measurement.measure
+        );
+
+        self.check_cancelled()?;
+        if self.tee_config.attestation_protocol == AttestationProtocol::Keylime {
+            self.submit_keylime_evidence()?;
+        }
+
+        let now = Instant::now();
+        let secrets = match self.get_secrets(measurement) {
+            Err(e) if !self.tee_config.secret_required => {
+                warn!(
+                    "Couldn't get the attestation secret, launching without it: {:?}",
+                    e
+                );
+                Vec::new()
+            }
+            result => result?,
+        };
+        self.metrics.secret_fetch = now.elapsed();
+        self.check_cancelled()?;
+
+        if self.tee_config.dry_run {
+            info!("SEV dry run: not injecting the secret nor finishing the launch");
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut fw = self.fw.lock().unwrap();
+        if !secrets.is_empty() {
+            // Each secret has its own slot in the secret area, so the guest
+            // finds them at fixed offsets whatever their sizes.
+            let slot_size = arch::x86_64::layout::SEV_SECRET_SLOT_SIZE;
+            if secrets.len() > arch::x86_64::layout::SEV_SECRET_SIZE / slot_size {
+                return Err(Error::TooManySecrets(secrets.len()));
+            }
+            if let Some(secret) = secrets.iter().find(|s| s.ciphertext.len() > slot_size) {
+                return Err(Error::SecretExceedsSlot(secret.ciphertext.len(), slot_size));
+            }
+
+            for (slot, secret) in secrets.iter().enumerate() {
+                let secret_addr =
+                    arch::x86_64::layout::SEV_SECRET_START + (slot * slot_size) as u64;
+                let secret_host_addr =
+                    secret_host_addr(guest_mem, secret_addr, secret.ciphertext.len())?;
+
+                debug!("Injecting secret at guest address {:#x}", secret_addr);
+                fw.launch_secret(vm_fd, secret, secret_host_addr)?;
+            }
+            self.report_progress(Phase::SecretInjected);
+        }
+
+        fw.launch_finish(vm_fd)?;
+        self.metrics.finish = now.elapsed();
+        info!("SEV launch finished in {:?}", launch_start.elapsed());
+        info!("SEV launch metrics: {:?}", self.metrics);
+        self.report_progress(Phase::Finished);
+
+        Ok(())
+    }
+}
+
+impl ConfidentialVm for AmdSev {
+    fn vm_prepare(&mut self, vm_fd: &VmFd, guest_mem: &GuestMemoryMmap) -> Result<(), TeeError> {
+        self.launch_start(vm_fd, guest_mem).map_err(TeeError::Sev)?;
+        self.launch_started = true;
+
+        Ok(())
+    }
+
+    fn vm_attest(
+        &mut self,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        _cpuid: &CpuId,
+        measured_regions: Vec<MeasuredRegion>,
+    ) -> Result<(), TeeError> {
+        if !std::mem::take(&mut self.launch_started) {
+            return Err(TeeError::NotPrepared);
+        }
+
+        let result = self.launch_finish(vm_fd, guest_mem, measured_regions);
+        // The measurement is done, whether the launch succeeded or not.
+        self.pinned = None;
+
+        result.map_err(TeeError::Sev)
+    }
+
+    fn tee_kind(&self) -> TeeKind {
+        if self.is_sev_es() {
+            TeeKind::SevEs
+        } else {
+            TeeKind::Sev
+        }
+    }
+
+    fn attestation_evidence(&self) -> Result<String, TeeError> {
+        self.evidence().map_err(TeeError::Sev)
+    }
+
+    fn launch_metrics(&self) -> LaunchMetrics {
+        self.metrics.clone()
+    }
+
+    fn encrypted_state(&self) -> bool {
+        self.sev_es
+    }
+
+    fn debug_decrypt(
+        &self,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        guest_addr: GuestAddress,
+        buf: &mut [u8],
+    ) -> Result<(), TeeError> {
+        self.sev_dbg_decrypt(vm_fd, guest_mem, guest_addr, buf)
+            .map_err(TeeError::Sev)
+    }
+
+    fn debug_encrypt(
+        &self,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        guest_addr: GuestAddress,
+        buf: &[u8],
+    ) -> Result<(), TeeError> {
+        self.sev_dbg_encrypt(vm_fd, guest_mem, guest_addr, buf)
+            .map_err(TeeError::Sev)
+    }
+
+    fn launch_measurement(&self) -> Result<LaunchMeasurement, TeeError> {
+        let measurement = self
+            .measurement
+            .as_ref()
+            .ok_or(TeeError::Sev(Error::MissingMeasurement))?;
+
+        Ok(LaunchMeasurement {
+            measurement: measurement.measure.to_vec(),
+            nonce: measurement.mnonce.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kvm_ioctls::Kvm;
+    use std::cell::Cell;
+    use std::os::unix::io::RawFd;
+    use vm_memory::Bytes;
+
+    const MEM_SIZE: usize = 0x40000;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Command {
+        RegisterMemory(u64),
+        LaunchStart { sev_es: bool },
+        LaunchUpdateData(u64, usize),
+        LaunchUpdateVmsa,
+        LaunchMeasure,
+        LaunchSecret(Secret, u64),
+        LaunchFinish,
+        DbgDecrypt(u64, usize),
+        DbgEncrypt(u64, usize),
+        SendStart,
+        ReceiveStart { sev_es: bool },
+        MirrorContext,
+    }
+
+    /// Records the commands issued by `AmdSev` instead of sending them to the firmware.
+    ///
+    /// The measurement is the SHA-256 digest of all the data measured, in order.
+    #[derive(Default)]
+    struct FakeFirmware {
+        commands: Arc<Mutex<Vec<Command>>>,
+        fail_update_data: bool,
+        measured_data: Vec<u8>,
+    }
+
+    impl FakeFirmware {
+        fn record(&self, command: Command) {
+            self.commands.lock().unwrap().push(command);
+        }
+    }
+
+    const NONCE: [u8; 16] = [0x55; 16];
+
+    impl SevFirmware for FakeFirmware {
+        fn register_memory(&mut self, _: &VmFd, host_addr: u64, _: u64) -> Result<(), Error> {
+            self.record(Command::RegisterMemory(host_addr));
+            Ok(())
+        }
+
+        fn launch_start(&mut self, _: &VmFd, _: Start, sev_es: bool) -> Result<(), Error> {
+            self.record(Command::LaunchStart { sev_es });
+            Ok(())
+        }
+
+        fn launch_update_data(
+            &mut self,
+            _: &VmFd,
+            host_addr: u64,
+            size: usize,
+        ) -> Result<(), Error> {
+            if self.fail_update_data {
+                return Err(Error::SevLaunchUpdateData(kvm_ioctls::Error::new(
+                    libc::EIO,
+                )));
+            }
+            self.record(Command::LaunchUpdateData(host_addr, size));
+            // Safe because the tests only measure ranges of their guest memory.
+            let data = unsafe { std::slice::from_raw_parts(host_addr as *const u8, size) };
+            self.measured_data.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn launch_update_vmsa(&mut self, _: &VmFd) -> Result<(), Error> {
+            self.record(Command::LaunchUpdateVmsa);
+            Ok(())
+        }
+
+        fn launch_measure(&mut self, _: &VmFd) -> Result<Measurement, Error> {
+            self.record(Command::LaunchMeasure);
+            Ok(Measurement {
+                measure: openssl::sha::sha256(&self.measured_data),
+                mnonce: NONCE,
+            })
+        }
+
+        fn launch_secret(
+            &mut self,
+            _: &VmFd,
+            secret: &Secret,
+            host_addr: u64,
+        ) -> Result<(), Error> {
+            self.record(Command::LaunchSecret(secret.clone(), host_addr));
+            Ok(())
+        }
+
+        fn launch_finish(&mut self, _: &VmFd) -> Result<(), Error> {
+            self.record(Command::LaunchFinish);
+            Ok(())
+        }
+
+        fn dbg_decrypt(&mut self, _: &VmFd, src: u64, _: u64, len: usize) -> Result<(), Error> {
+            self.record(Command::DbgDecrypt(src, len));
+            Ok(())
+        }
+
+        fn dbg_encrypt(&mut self, _: &VmFd, _: u64, dst: u64, len: usize) -> Result<(), Error> {
+            self.record(Command::DbgEncrypt(dst, len));
+            Ok(())
+        }
+
+        fn send_start(&mut self, _: &VmFd, _: &MigrationCerts) -> Result<MigrationSession, Error> {
+            self.record(Command::SendStart);
+            Ok(MigrationSession {
+                policy: 0,
+                session: vec![0xaa; 16],
+            })
+        }
+
+        fn receive_start(
+            &mut self,
+            _: &VmFd,
+            _: &[u8],
+            _: &MigrationSession,
+            sev_es: bool,
+        ) -> Result<(), Error> {
+            self.record(Command::ReceiveStart { sev_es });
+            Ok(())
+        }
+
+        fn mirror_context(&mut self, _: &VmFd, _: &VmFd) -> Result<(), Error> {
+            self.record(Command::MirrorContext);
+            Ok(())
+        }
+    }
+
+    /// Plays a SEV device busy on the first `busy` times command `id` is issued,
+    /// and recording the ids of the commands issued to it.
+    struct BusyDevice {
+        id: u32,
+        busy: Cell<u32>,
+        issued: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl BusyDevice {
+        fn new(id: u32, busy: u32) -> Self {
+            BusyDevice {
+                id,
+                busy: Cell::new(busy),
+                issued: Arc::default(),
+            }
+        }
+    }
+
+    impl AsRawFd for BusyDevice {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    impl SevDevice for BusyDevice {
+        fn issue(&self, _: &VmFd, cmd: &mut kvm_sev_cmd) -> Result<(), kvm_ioctls::Error> {
+            self.issued.lock().unwrap().push(cmd.id);
+            if cmd.id == self.id && self.busy.get() > 0 {
+                self.busy.set(self.busy.get() - 1);
+                return Err(kvm_ioctls::Error::new(libc::EBUSY));
+            }
+            Ok(())
+        }
+    }
+
+    /// Serves `secret` to every GET, failing if it's empty, and accepts every POST.
+    struct FakeHttpClient {
+        requests: Arc<Mutex<Vec<String>>>,
+        secret: Vec<u8>,
+    }
+
+    impl HttpClient for FakeHttpClient {
+        fn get(&mut self, url: &str) -> Result<Vec<u8>, HttpError> {
+            self.requests.lock().unwrap().push(format!("GET {}", url));
+            if self.secret.is_empty() {
+                return Err(HttpError::Request("no secret".to_string()));
+            }
+            Ok(self.secret.clone())
+        }
+
+        fn post(&mut self, url: &str, _data: &[u8]) -> Result<Vec<u8>, HttpError> {
+            self.requests.lock().unwrap().push(format!("POST {}", url));
+            Ok(Vec::new())
+        }
+    }
+
+    fn test_secret() -> Secret {
+        Secret {
+            header: Header {
+                flags: HeaderFlags::default(),
+                iv: [1; 16],
+                mac: [2; 32],
+            },
+            ciphertext: vec![3; 64],
+        }
+    }
+
+    fn amd_sev(
+        tee_config: TeeConfig,
+        flags: PolicyFlags,
+        fw: FakeFirmware,
+        http_client: FakeHttpClient,
+    ) -> AmdSev {
+        let mut start = Start::decode(&[0u8; std::mem::size_of::<Start>()][..], ()).unwrap();
+        start.policy.flags = flags;
+
+        AmdSev {
+            sev_es: flags.contains(PolicyFlags::ENCRYPTED_STATE),
+            kbs_url: tee_config.attestation_url.clone(),
+            progress_callback: tee_config.progress_callback.clone(),
+            tee_config,
+            fw: Mutex::new(Box::new(fw)),
+            start,
+            http_client: Arc::new(Mutex::new(Box::new(http_client))),
+            launch_started: false,
+            pinned: None,
+            build: sev::Build::default(),
+            chain_json: serde_json::Value::Null,
+            measurement: None,
+            challenge_deadline: None,
+            started: Instant::now(),
+            metrics: LaunchMetrics::default(),
+            cancel: CancelToken::new(),
+        }
+    }
+
+    fn no_http() -> FakeHttpClient {
+        FakeHttpClient {
+            requests: Arc::default(),
+            secret: Vec::new(),
+        }
+    }
+
+    fn host_addr(guest_mem: &GuestMemoryMmap, guest_addr: u64) -> u64 {
+        guest_mem
+            .get_host_address(GuestAddress(guest_addr))
+            .unwrap() as u64
+    }
+
+    fn measured_regions(guest_mem: &GuestMemoryMmap) -> Vec<MeasuredRegion> {
+        vec![MeasuredRegion {
+            guest_addr: 0x10000,
+            host_addr: host_addr(guest_mem, 0x10000),
+            size: 0x1000,
+        }]
+    }
+
+    /// Prepares and attests a VM with a fake firmware, returning the commands
+    /// it received along with the result of the attestation.
+    fn launch(
+        tee_config: TeeConfig,
+        flags: PolicyFlags,
+        http_client: FakeHttpClient,
+    ) -> (Vec<Command>, GuestMemoryMmap, Result<(), TeeError>) {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let fw = FakeFirmware::default();
+        let commands = fw.commands.clone();
+
+        let mut sev = amd_sev(tee_config, flags, fw, http_client);
+        sev.vm_prepare(&vm_fd, &guest_mem).unwrap();
+        let result = sev.vm_attest(
+            &vm_fd,
+            &guest_mem,
+            &CpuId::new(0).unwrap(),
+            measured_regions(&guest_mem),
+        );
+
+        let commands = commands.lock().unwrap().clone();
+        (commands, guest_mem, result)
+    }
+
+    #[test]
+    fn test_launch_sequence() {
+        let (commands, guest_mem, result) =
+            launch(TeeConfig::default(), PolicyFlags::empty(), no_http());
+        result.unwrap();
+
+        assert_eq!(
+            commands,
+            vec![
+                Command::RegisterMemory(host_addr(&guest_mem, 0)),
+                Command::LaunchStart { sev_es: false },
+                Command::LaunchUpdateData(host_addr(&guest_mem, 0x10000), 0x1000),
+                Command::LaunchMeasure,
+                Command::LaunchFinish,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_debug_access() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let debug_config = TeeConfig {
+            allow_debug: true,
+            ..Default::default()
+        };
+        let mut buf = [0u8; 32];
+
+        let sev = amd_sev(
+            TeeConfig::default(),
+            PolicyFlags::empty(),
+            FakeFirmware::default(),
+            no_http(),
+        );
+        assert!(matches!(
+            sev.debug_decrypt(&vm_fd, &guest_mem, GuestAddress(0x1000), &mut buf),
+            Err(TeeError::Sev(Error::DebugNotAllowed))
+        ));
+
+        let sev = amd_sev(
+            debug_config.clone(),
+            PolicyFlags::NO_DEBUG,
+            FakeFirmware::default(),
+            no_http(),
+        );
+        assert!(matches!(
+            sev.debug_encrypt(&vm_fd, &guest_mem, GuestAddress(0x1000), &buf),
+            Err(TeeError::Sev(Error::DebugForbiddenByPolicy))
+        ));
+
+        let fw = FakeFirmware::default();
+        let commands = fw.commands.clone();
+        let sev = amd_sev(debug_config, PolicyFlags::empty(), fw, no_http());
+        assert!(matches!(
+            sev.debug_decrypt(&vm_fd, &guest_mem, GuestAddress(MEM_SIZE as u64), &mut buf),
+            Err(TeeError::Sev(Error::InvalidGuestRange))
+        ));
+        sev.debug_decrypt(&vm_fd, &guest_mem, GuestAddress(0x1000), &mut buf)
+            .unwrap();
+        sev.debug_encrypt(&vm_fd, &guest_mem, GuestAddress(0x2000), &buf)
+            .unwrap();
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                Command::DbgDecrypt(host_addr(&guest_mem, 0x1000), 32),
+                Command::DbgEncrypt(host_addr(&guest_mem, 0x2000), 32),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_launch_measurement() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let mut sev = amd_sev(
+            TeeConfig::default(),
+            PolicyFlags::empty(),
+            FakeFirmware::default(),
+            no_http(),
+        );
+
+        sev.vm_prepare(&vm_fd, &guest_mem).unwrap();
+        sev.vm_attest(&vm_fd, &guest_mem, &CpuId::new(0).unwrap(), Vec::new())
+            .unwrap();
+
+        let launch_measurement = sev.launch_measurement().unwrap();
+        assert_eq!(launch_measurement.measurement, openssl::sha::sha256(&[]));
+        assert_eq!(launch_measurement.nonce, NONCE);
+    }
+
+    #[test]
+    fn test_coalesce_regions() {
+        let region = |guest_addr, host_addr, size| MeasuredRegion {
+            guest_addr,
+            host_addr,
+            size,
+        };
+
+        let coalesced = coalesce_regions(vec![
+            region(0x1000, 0x10000, 0x1000),
+            region(0x2000, 0x11000, 0x1000),
+            // Contiguous in guest memory only.
+            region(0x3000, 0x20000, 0x1000),
+            // Contiguous in host memory only.
+            region(0x5000, 0x21000, 0x1000),
+            region(0x6000, 0x22000, 0x2000),
+        ]);
+
+        let coalesced: Vec<_> = coalesced
+            .iter()
+            .map(|r| (r.guest_addr, r.host_addr, r.size))
+            .collect();
+        assert_eq!(
+            coalesced,
+            vec![
+                (0x1000, 0x10000, 0x2000),
+                (0x3000, 0x20000, 0x1000),
+                (0x5000, 0x21000, 0x3000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_coalesced_measurement_matches() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        for (i, byte) in (0x10000..0x14000).enumerate() {
+            guest_mem.write_obj(i as u8, GuestAddress(byte)).unwrap();
+        }
+
+        let measure = |fw: FakeFirmware, regions: Vec<MeasuredRegion>| {
+            let commands = fw.commands.clone();
+            let mut sev = amd_sev(TeeConfig::default(), PolicyFlags::empty(), fw, no_http());
+            sev.vm_prepare(&vm_fd, &guest_mem).unwrap();
+            sev.vm_attest(&vm_fd, &guest_mem, &CpuId::new(0).unwrap(), regions)
+                .unwrap();
+
+            let updates = commands
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|c| matches!(c, Command::LaunchUpdateData(..)))
+                .count();
+            (sev.measurement.unwrap().measure, updates)
+        };
+        let split: Vec<_> = (0..4)
+            .map(|i| MeasuredRegion {
+                guest_addr: 0x10000 + i * 0x1000,
+                host_addr: host_addr(&guest_mem, 0x10000 + i * 0x1000),
+                size: 0x1000,
+            })
+            .collect();
+
+        // Measuring the regions one by one, as the firmware would if they
+        // weren't coalesced, gives the same digest as the single command issued.
+        let mut unmerged = FakeFirmware::default();
+        for region in &split {
+            unmerged
+                .launch_update_data(&vm_fd, region.host_addr, region.size)
+                .unwrap();
+        }
+        let expected = unmerged.launch_measure(&vm_fd).unwrap().measure;
+
+        let (measurement, updates) = measure(FakeFirmware::default(), split);
+        assert_eq!(measurement, expected);
+        assert_eq!(updates, 1);
+    }
+
+    #[test]
+    fn test_guest_memory_pinned_during_launch() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), MEM_SIZE),
+            (GuestAddress(0x100000), MEM_SIZE),
+        ])
+        .unwrap();
+        let mut sev = amd_sev(
+            TeeConfig::default(),
+            PolicyFlags::empty(),
+            FakeFirmware::default(),
+            no_http(),
+        );
+
+        sev.vm_prepare(&vm_fd, &guest_mem).unwrap();
+        let pinned = &sev.pinned.as_ref().unwrap().regions;
+        assert_eq!(
+            *pinned,
+            vec![
+                (host_addr(&guest_mem, 0), MEM_SIZE),
+                (host_addr(&guest_mem, 0x100000), MEM_SIZE),
+            ]
+        );
+
+        sev.vm_attest(
+            &vm_fd,
+            &guest_mem,
+            &CpuId::new(0).unwrap(),
+            measured_regions(&guest_mem),
+        )
+        .unwrap();
+        assert!(sev.pinned.is_none());
+    }
+
+    #[test]
+    fn test_launch_metrics() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let mut sev = amd_sev(
+            TeeConfig::default(),
+            PolicyFlags::ENCRYPTED_STATE,
+            FakeFirmware::default(),
+            no_http(),
+        );
+
+        sev.vm_prepare(&vm_fd, &guest_mem).unwrap();
+        sev.vm_attest(
+            &vm_fd,
+            &guest_mem,
+            &CpuId::new(0).unwrap(),
+            measured_regions(&guest_mem),
+        )
+        .unwrap();
+
+        // The measured regions plus the AP jump table.
+        let metrics = sev.launch_metrics();
+        assert_eq!(metrics.registered_regions, 1);
+        assert_eq!(metrics.registered_bytes, MEM_SIZE as u64);
+        assert_eq!(metrics.updated_regions, 2);
+        assert_eq!(
+            metrics.updated_bytes,
+            0x1000 + arch::x86_64::layout::SEV_AP_JUMP_TABLE_SIZE as u64
+        );
+    }
+
+    #[test]
+    fn test_launch_progress() {
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let recorded = phases.clone();
+        let tee_config = TeeConfig {
+            progress_callback: Some(ProgressCallback(Arc::new(move |phase| {
+                recorded.lock().unwrap().push(phase)
+            }))),
+            ..Default::default()
+        };
+
+        let (_, _, result) = launch(tee_config, PolicyFlags::empty(), no_http());
+        result.unwrap();
+
+        let phases = phases.lock().unwrap();
+        assert_eq!(phases.len(), 3);
+        assert!(matches!(phases[0], Phase::RegionsMeasured(_)));
+        assert!(matches!(phases[1], Phase::MeasurementComputed(_)));
+        assert!(matches!(phases[2], Phase::Finished(_)));
+    }
+
+    #[test]
+    fn test_tee_kind() {
+        let sev = amd_sev(
+            TeeConfig::default(),
+            PolicyFlags::empty(),
+            FakeFirmware::default(),
+            no_http(),
+        );
+        assert!(!sev.is_sev_es());
+        assert_eq!(sev.tee_kind(), TeeKind::Sev);
+
+        let sev = amd_sev(
+            TeeConfig::default(),
+            PolicyFlags::ENCRYPTED_STATE,
+            FakeFirmware::default(),
+            no_http(),
+        );
+        assert!(sev.is_sev_es());
+        assert_eq!(sev.tee_kind(), TeeKind::SevEs);
+        assert_eq!(sev.tee_kind().to_string(), "SEV-ES");
+    }
+
+    #[test]
+    fn test_launch_sequence_sev_es() {
+        let (commands, guest_mem, result) = launch(
+            TeeConfig::default(),
+            PolicyFlags::ENCRYPTED_STATE,
+            no_http(),
+        );
+        result.unwrap();
+
+        let jump_table_addr = arch::x86_64::layout::SEV_AP_JUMP_TABLE_START;
+        assert_eq!(
+            commands,
+            vec![
+                Command::RegisterMemory(host_addr(&guest_mem, 0)),
+                Command::LaunchStart { sev_es: true },
+                Command::LaunchUpdateData(host_addr(&guest_mem, 0x10000), 0x1000),
+                Command::LaunchUpdateData(
+                    host_addr(&guest_mem, jump_table_addr),
+                    arch::x86_64::layout::SEV_AP_JUMP_TABLE_SIZE,
+                ),
+                Command::LaunchUpdateVmsa,
+                Command::LaunchMeasure,
+                Command::LaunchFinish,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_launch_injects_secret_file() {
+        let secret = test_secret();
+        let secret_file = TempFile::new().unwrap();
+        secret.encode(secret_file.as_file(), ()).unwrap();
+
+        let tee_config = TeeConfig {
+            secret_file: secret_file.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let (commands, guest_mem, result) = launch(tee_config, PolicyFlags::empty(), no_http());
+        result.unwrap();
+
+        let secret_addr = host_addr(&guest_mem, arch::x86_64::layout::SEV_SECRET_START);
+        assert_eq!(
+            &commands[3..],
+            &[
+                Command::LaunchMeasure,
+                Command::LaunchSecret(secret, secret_addr),
+                Command::LaunchFinish,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_launch_injects_kbs_secret() {
+        let secret = test_secret();
+        let requests = Arc::default();
+        let http_client = FakeHttpClient {
+            requests: Arc::clone(&requests),
+            secret: serde_json::to_vec(&secret).unwrap(),
+        };
+
+        let tee_config = TeeConfig {
+            attestation_url: "http://kbs".to_string(),
+            workload_id: "workload".to_string(),
+            ..Default::default()
+        };
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let fw = FakeFirmware::default();
+        let commands = fw.commands.clone();
+        let mut sev = amd_sev(tee_config, PolicyFlags::empty(), fw, http_client);
+
+        sev.vm_prepare(&vm_fd, &guest_mem).unwrap();
+        sev.vm_attest(
+            &vm_fd,
+            &guest_mem,
+            &CpuId::new(0).unwrap(),
+            measured_regions(&guest_mem),
+        )
+        .unwrap();
+
+        // The secret is only requested once the measurement was sent.
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "POST http://kbs/kbs/v0/attest".to_string(),
+                "GET http://kbs/kbs/v0/key/workload".to_string(),
+            ]
+        );
+        let secret_addr = host_addr(&guest_mem, arch::x86_64::layout::SEV_SECRET_START);
+        assert_eq!(
+            commands.lock().unwrap()[3..],
+            [
+                Command::LaunchMeasure,
+                Command::LaunchSecret(secret, secret_addr),
+                Command::LaunchFinish,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_launch_injects_kbs_resources() {
+        let secret = test_secret();
+        let requests = Arc::default();
+        let http_client = FakeHttpClient {
+            requests: Arc::clone(&requests),
+            secret: serde_json::to_vec(&secret).unwrap(),
+        };
+
+        let tee_config = TeeConfig {
+            attestation_url: "http://kbs".to_string(),
+            workload_id: "workload".to_string(),
+            resource_ids: vec!["disk-key".to_string(), "api-token".to_string()],
+            ..Default::default()
+        };
+        let (commands, guest_mem, result) = launch(tee_config, PolicyFlags::empty(), http_client);
+        result.unwrap();
+
+        assert_eq!(
+            requests.lock().unwrap()[1..],
+            [
+                "GET http://kbs/kbs/v0/key/disk-key".to_string(),
+                "GET http://kbs/kbs/v0/key/api-token".to_string(),
+            ]
+        );
+        let secret_addr = host_addr(&guest_mem, arch::x86_64::layout::SEV_SECRET_START);
+        let next_addr = secret_addr + arch::x86_64::layout::SEV_SECRET_SLOT_SIZE as u64;
+        assert_eq!(
+            commands[3..],
+            [
+                Command::LaunchMeasure,
+                Command::LaunchSecret(secret.clone(), secret_addr),
+                Command::LaunchSecret(secret, next_addr),
+                Command::LaunchFinish,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oversized_secret_aborts_launch() {
+        let secret = Secret {
+            ciphertext: vec![3; arch::x86_64::layout::SEV_SECRET_SLOT_SIZE + 1],
+            ..test_secret()
+        };
+        let http_client = FakeHttpClient {
+            requests: Arc::default(),
+            secret: serde_json::to_vec(&secret).unwrap(),
+        };
+
+        let tee_config = TeeConfig {
+            attestation_url: "http://kbs".to_string(),
+            workload_id: "workload".to_string(),
+            ..Default::default()
+        };
+        let (commands, _, result) = launch(tee_config, PolicyFlags::empty(), http_client);
+
+        assert!(matches!(
+            result,
+            Err(TeeError::Sev(Error::SecretExceedsSlot(size, slot_size)))
+                if size == arch::x86_64::layout::SEV_SECRET_SLOT_SIZE + 1
+                    && slot_size == arch::x86_64::layout::SEV_SECRET_SLOT_SIZE
+        ));
+        assert_eq!(commands.last(), Some(&Command::LaunchMeasure));
+    }
+
+    #[test]
+    fn test_check_secret_size() {
+        assert!(check_secret_size(u32::MAX as usize).is_ok());
+        assert!(matches!(
+            check_secret_size(u32::MAX as usize + 1),
+            Err(Error::SecretTooLarge(size)) if size == u32::MAX as usize + 1
+        ));
+    }
+
+    #[test]
+    fn test_too_many_secrets_abort_launch() {
+        let http_client = FakeHttpClient {
+            requests: Arc::default(),
+            secret: serde_json::to_vec(&test_secret()).unwrap(),
+        };
+        let slots =
+            arch::x86_64::layout::SEV_SECRET_SIZE / arch::x86_64::layout::SEV_SECRET_SLOT_SIZE;
+
+        let tee_config = TeeConfig {
+            attestation_url: "http://kbs".to_string(),
+            workload_id: "workload".to_string(),
+            resource_ids: (0..=slots).map(|i| format!("key-{i}")).collect(),
+            ..Default::default()
+        };
+        let (commands, _, result) = launch(tee_config, PolicyFlags::empty(), http_client);
+
+        assert!(matches!(
+            result,
+            Err(TeeError::Sev(Error::TooManySecrets(count))) if count == slots + 1
+        ));
+        assert_eq!(commands.last(), Some(&Command::LaunchMeasure));
+    }
+
+    #[test]
+    fn test_secret_host_addr() {
+        let secret_start = arch::x86_64::layout::SEV_SECRET_START;
+        let guest_mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), secret_start as usize + 0x100),
+            (GuestAddress(secret_start + 0x100), 0x1000),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            secret_host_addr(&guest_mem, secret_start, 0x100).unwrap(),
+            host_addr(&guest_mem, secret_start)
+        );
+        // The next region isn't contiguous on the host.
+        assert!(matches!(
+            secret_host_addr(&guest_mem, secret_start, 0x101),
+            Err(Error::SecretRegionOverflow(addr)) if addr == secret_start
+        ));
+        assert!(matches!(
+            secret_host_addr(&guest_mem, secret_start + 0x1100, 1),
+            Err(Error::SecretRegionOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_missing_required_secret_aborts_launch() {
+        let tee_config = TeeConfig {
+            attestation_url: "http://kbs".to_string(),
+            ..Default::default()
+        };
+        let (commands, _, result) = launch(tee_config, PolicyFlags::empty(), no_http());
+
+        assert!(matches!(
+            result,
+            Err(TeeError::Sev(Error::AttestationRequest(_)))
+        ));
+        assert_eq!(commands.last(), Some(&Command::LaunchMeasure));
+    }
+
+    #[test]
+    fn test_missing_optional_secret_finishes_launch() {
+        let tee_config = TeeConfig {
+            attestation_url: "http://kbs".to_string(),
+            secret_required: false,
+            ..Default::default()
+        };
+        let (commands, _, result) = launch(tee_config, PolicyFlags::empty(), no_http());
+        result.unwrap();
+
+        assert_eq!(
+            &commands[3..],
+            &[Command::LaunchMeasure, Command::LaunchFinish]
+        );
+    }
+
+    /// Serves the certificates of a chip as the AMD Key Distribution Service.
+    struct FakeKds {
+        requests: Arc<Mutex<Vec<String>>>,
+        cek: Vec<u8>,
+        ask_ark: Vec<u8>,
+    }
+
+    impl HttpClient for FakeKds {
+        fn get(&mut self, url: &str) -> Result<Vec<u8>, HttpError> {
+            self.requests.lock().unwrap().push(format!("GET {}", url));
+            if url.contains("/ask_ark_") {
+                Ok(self.ask_ark.clone())
+            } else {
+                Ok(self.cek.clone())
+            }
+        }
+
+        fn post(&mut self, url: &str, _data: &[u8]) -> Result<Vec<u8>, HttpError> {
+            Err(HttpError::Request(format!("unexpected POST {}", url)))
+        }
+    }
+
+    #[test]
+    fn test_kds_certs_shared_by_launches() {
+        let (cek, _) = certs::sev::sev::Certificate::generate(certs::sev::sev::Usage::CEK).unwrap();
+        let mut cek_data = Vec::new();
+        cek.encode(&mut cek_data, ()).unwrap();
+        let ask_ark = [
+            certs::sev::builtin::milan::ASK,
+            certs::sev::builtin::milan::ARK,
+        ]
+        .concat();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let tee_config = TeeConfig::default();
+
+        // Concurrent launches on the same chip only download its certificates once.
+        let launches: Vec<_> = (0..4)
+            .map(|_| {
+                let mut kds = FakeKds {
+                    requests: Arc::clone(&requests),
+                    cek: cek_data.clone(),
+                    ask_ark: ask_ark.clone(),
+                };
+                let tee_config = tee_config.clone();
+                thread::spawn(move || {
+                    kds_certs("shared-chip", &CpuModel::Milan, &tee_config, &mut kds).unwrap()
+                })
+            })
+            .collect();
+        for launch in launches {
+            let certs = launch.join().unwrap();
+            assert_eq!(certs.cek, cek_data);
+            assert_eq!(certs.ask_ark, ask_ark);
+        }
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // Another chip has its own certificates.
+        let mut kds = FakeKds {
+            requests: Arc::clone(&requests),
+            cek: cek_data.clone(),
+            ask_ark: ask_ark.clone(),
+        };
+        kds_certs("other-chip", &CpuModel::Milan, &tee_config, &mut kds).unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[2..],
+            [
+                format!(
+                    "GET {}/other-chip",
+                    tee_config.cek_url.trim_end_matches('/')
+                ),
+                format!(
+                    "GET {}/ask_ark_milan.cert",
+                    tee_config.ask_ark_url.trim_end_matches('/')
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kds_certs_not_cached_on_failure() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let mut kds = FakeKds {
+            requests: Arc::clone(&requests),
+            cek: b"not a certificate".to_vec(),
+            ask_ark: Vec::new(),
+        };
+        let tee_config = TeeConfig::default();
+
+        for _ in 0..2 {
+            assert!(matches!(
+                kds_certs("broken-chip", &CpuModel::Milan, &tee_config, &mut kds),
+                Err(Error::DecodeCek)
+            ));
+        }
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_session_cache() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let path = dir.as_path().join("session");
+        let path = path.to_str().unwrap();
+        let (pdh, _) = certs::sev::sev::Certificate::generate(certs::sev::sev::Usage::PDH).unwrap();
+        let start = Session::try_from(Policy::default())
+            .unwrap()
+            .start_pdh(pdh)
+            .unwrap();
+        let chain = serde_json::json!({"cek": "1"});
+
+        // A previous cache readable by others doesn't keep its mode.
+        std::fs::write(path, "{}").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        store_session_cache(path, &chain, &start).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let cache: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        assert!(cache.get("tek").is_none());
+        assert!(cache.get("tik").is_none());
+
+        assert!(load_session_cache(path, &start.policy, &chain).is_some());
+        assert!(
+            load_session_cache(path, &start.policy, &serde_json::json!({"cek": "2"})).is_none()
+        );
+    }
+
+    #[test]
+    fn test_kbs_auth_request_without_chain() {
+        let tee_config = TeeConfig {
+            workload_id: "workload".to_string(),
+            ..Default::default()
+        };
+
+        let request = kbs_auth_request(&tee_config, sev::Build::default(), None);
+        assert_eq!(request.tee, Tee::Sev);
+        assert_eq!(request.extra_params["workload_id"], "workload");
+        assert!(request.extra_params["build"].is_object());
+        assert!(request.extra_params.get("chain").is_none());
+    }
+
+    #[test]
+    fn test_kbs_challenge_keeps_unknown_fields() {
+        let json = serde_json::json!({
+            "nonce": "42",
+            "extra-params": {"id": "session", "report-data": "sha384"},
+            "accepted-tees": ["sev", "snp"],
+        });
+
+        let challenge: KbsChallenge = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(challenge.challenge.nonce, "42");
+        assert_eq!(challenge.challenge.extra_params["report-data"], "sha384");
+        assert_eq!(
+            challenge.unknown.keys().collect::<Vec<_>>(),
+            vec!["accepted-tees"]
+        );
+        assert_eq!(serde_json::to_value(&challenge).unwrap(), json);
+    }
+
+    #[test]
+    fn test_kbs_challenge_expires_in() {
+        let challenge = |json| serde_json::from_value::<KbsChallenge>(json).unwrap();
+
+        assert_eq!(
+            challenge(serde_json::json!({
+                "nonce": "42",
+                "extra-params": {},
+                "expires-in": 30,
+            }))
+            .expires_in(),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            challenge(serde_json::json!({
+                "nonce": "42",
+                "extra-params": {"expires-in": 5},
+            }))
+            .expires_in(),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            challenge(serde_json::json!({"nonce": "42", "extra-params": {}})).expires_in(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_expired_challenge_not_renewed_aborts_launch() {
+        let tee_config = TeeConfig {
+            attestation_url: "http://kbs".to_string(),
+            ..Default::default()
+        };
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let fw = FakeFirmware::default();
+        let commands = fw.commands.clone();
+        let mut sev = amd_sev(tee_config, PolicyFlags::empty(), fw, no_http());
+        sev.challenge_deadline = Some(Instant::now());
+
+        assert!(matches!(
+            sev.vm_prepare(&vm_fd, &guest_mem),
+            Err(TeeError::Sev(Error::ChallengeExpired))
+        ));
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_challenge_expired_after_launch_start() {
+        let requests = Arc::default();
+        let http_client = FakeHttpClient {
+            requests: Arc::clone(&requests),
+            secret: serde_json::to_vec(&test_secret()).unwrap(),
+        };
+        let tee_config = TeeConfig {
+            attestation_url: "http://kbs".to_string(),
+            workload_id: "workload".to_string(),
+            ..Default::default()
+        };
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let mut sev = amd_sev(
+            tee_config,
+            PolicyFlags::empty(),
+            FakeFirmware::default(),
+            http_client,
+        );
+
+        sev.vm_prepare(&vm_fd, &guest_mem).unwrap();
+        sev.challenge_deadline = Some(Instant::now());
+        assert!(matches!(
+            sev.vm_attest(
+                &vm_fd,
+                &guest_mem,
+                &CpuId::new(0).unwrap(),
+                measured_regions(&guest_mem),
+            ),
+            Err(TeeError::Sev(Error::ChallengeExpired))
+        ));
+        // The evidence isn't sent for a challenge the KBS no longer accepts.
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dry_run_stops_after_measure() {
+        let tee_config = TeeConfig {
+            dry_run: true,
+            ..Default::default()
+        };
+        let (commands, _, result) = launch(tee_config, PolicyFlags::empty(), no_http());
+        result.unwrap();
+
+        assert_eq!(commands.last(), Some(&Command::LaunchMeasure));
+    }
+
+    #[test]
+    fn test_attest_without_prepare() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let fw = FakeFirmware::default();
+        let commands = fw.commands.clone();
+        let mut sev = amd_sev(TeeConfig::default(), PolicyFlags::empty(), fw, no_http());
+
+        assert!(matches!(
+            sev.vm_attest(&vm_fd, &guest_mem, &CpuId::new(0).unwrap(), Vec::new()),
+            Err(TeeError::NotPrepared)
+        ));
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_update_data_failure_aborts_launch() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let fw = FakeFirmware {
+            fail_update_data: true,
+            ..Default::default()
+        };
+        let commands = fw.commands.clone();
+        let mut sev = amd_sev(TeeConfig::default(), PolicyFlags::empty(), fw, no_http());
+
+        sev.vm_prepare(&vm_fd, &guest_mem).unwrap();
+        assert!(matches!(
+            sev.vm_attest(
+                &vm_fd,
+                &guest_mem,
+                &CpuId::new(0).unwrap(),
+                measured_regions(&guest_mem),
+            ),
+            Err(TeeError::Sev(Error::SevLaunchUpdateData(_)))
+        ));
+        assert_eq!(
+            commands.lock().unwrap().last(),
+            Some(&Command::LaunchStart { sev_es: false })
+        );
+        assert!(sev.attestation_evidence().is_err());
+        assert!(sev.launch_measurement().is_err());
+    }
+
+    #[test]
+    fn test_launch_start_retried_while_busy() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let start = Start::decode(&[0u8; std::mem::size_of::<Start>()][..], ()).unwrap();
+        let device = BusyDevice::new(2, 2); // SEV_LAUNCH_START
+        let issued = device.issued.clone();
+        let mut fw = KvmSevFirmware::new(device, 2, CancelToken::new());
+
+        fw.launch_start(&vm_fd, start, false).unwrap();
+        assert!(matches!(fw.state, LaunchState::Started));
+        // SEV_INIT, then SEV_LAUNCH_START until the firmware isn't busy anymore.
+        assert_eq!(*issued.lock().unwrap(), [0, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_launch_start_fails_when_busy_for_too_long() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let start = Start::decode(&[0u8; std::mem::size_of::<Start>()][..], ()).unwrap();
+        let device = BusyDevice::new(2, 3); // SEV_LAUNCH_START
+        let issued = device.issued.clone();
+        let mut fw = KvmSevFirmware::new(device, 2, CancelToken::new());
+
+        match fw.launch_start(&vm_fd, start, false) {
+            Err(Error::SevLaunchStart(e)) => assert_eq!(e.errno(), libc::EBUSY),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(matches!(fw.state, LaunchState::New));
+        assert_eq!(*issued.lock().unwrap(), [0, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_dbg_misaligned() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let device = BusyDevice::new(0, 0);
+        let issued = device.issued.clone();
+        let mut fw = KvmSevFirmware::new(device, 0, CancelToken::new());
+
+        assert!(matches!(
+            fw.dbg_decrypt(&vm_fd, 0x1008, 0x2000, 32),
+            Err(Error::DebugMisaligned(0x1008, 32))
+        ));
+        assert!(matches!(
+            fw.dbg_encrypt(&vm_fd, 0x2000, 0x1000, 20),
+            Err(Error::DebugMisaligned(0x1000, 20))
+        ));
+        // Only the guest side must be aligned.
+        fw.dbg_decrypt(&vm_fd, 0x1000, 0x2008, 32).unwrap();
+        fw.dbg_encrypt(&vm_fd, 0x2008, 0x1000, 32).unwrap();
+        assert_eq!(*issued.lock().unwrap(), [17, 18]);
+    }
+
+    #[test]
+    fn test_dbg_too_large() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let device = BusyDevice::new(0, 0);
+        let issued = device.issued.clone();
+        let mut fw = KvmSevFirmware::new(device, 0, CancelToken::new());
+        let len = u32::MAX as usize + 1;
+
+        assert!(matches!(
+            fw.dbg_decrypt(&vm_fd, 0x1000, 0x2000, len),
+            Err(Error::DebugTooLarge(size)) if size == len
+        ));
+        assert!(issued.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migration_not_allowed() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let fw = FakeFirmware::default();
+        let commands = fw.commands.clone();
+        let sev = amd_sev(TeeConfig::default(), PolicyFlags::empty(), fw, no_http());
+
+        assert!(matches!(
+            sev.send_start(&vm_fd, &MigrationCerts::default()),
+            Err(Error::MigrationNotAllowed)
+        ));
+        assert!(matches!(
+            sev.setup_migration_agent(&vm_fd, &vm_fd),
+            Err(Error::MigrationNotAllowed)
+        ));
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migration_forbidden_by_policy() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let tee_config = TeeConfig {
+            allow_migration: true,
+            ..Default::default()
+        };
+        let fw = FakeFirmware::default();
+        let commands = fw.commands.clone();
+        let mut sev = amd_sev(tee_config, PolicyFlags::NO_SEND, fw, no_http());
+
+        assert!(matches!(
+            sev.send_start(&vm_fd, &MigrationCerts::default()),
+            Err(Error::MigrationForbiddenByPolicy)
+        ));
+        let session = MigrationSession {
+            policy: PolicyFlags::NO_SEND.bits() as u32,
+            session: Vec::new(),
+        };
+        assert!(matches!(
+            sev.receive_start(&vm_fd, &guest_mem, &[], &session),
+            Err(Error::MigrationForbiddenByPolicy)
+        ));
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migration_sequence() {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let mirror_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let tee_config = TeeConfig {
+            allow_migration: true,
+            ..Default::default()
+        };
+        let fw = FakeFirmware::default();
+        let commands = fw.commands.clone();
+        let mut sev = amd_sev(tee_config, PolicyFlags::empty(), fw, no_http());
+
+        let session = MigrationSession {
+            policy: PolicyFlags::ENCRYPTED_STATE.bits() as u32,
+            session: vec![0xaa; 16],
+        };
+        sev.receive_start(&vm_fd, &guest_mem, &[], &session)
+            .unwrap();
+        sev.setup_migration_agent(&vm_fd, &mirror_fd).unwrap();
+        assert_eq!(
+            sev.send_start(&vm_fd, &MigrationCerts::default()).unwrap(),
+            MigrationSession {
+                policy: 0,
+                session: vec![0xaa; 16],
+            }
+        );
+
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                Command::RegisterMemory(host_addr(&guest_mem, 0)),
+                Command::ReceiveStart { sev_es: true },
+                Command::MirrorContext,
+                Command::SendStart,
+            ]
+        );
     }
 }
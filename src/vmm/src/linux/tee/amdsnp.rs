@@ -1,36 +1,83 @@
 use std::{
     os::unix::io::{AsRawFd, RawFd},
     slice,
+    time::Instant,
 };
 
+use super::http::{self, HttpClient, HttpError};
+use super::{ConfidentialVm, Error as TeeError, LaunchMetrics, TeeKind};
+use crate::resources::{TcbVersion, TeeConfig};
 use crate::vstate::MeasuredRegion;
 use arch::x86_64::layout::*;
 
-use sev::firmware::{guest::GuestPolicy, host::Firmware};
+pub use sev::firmware::guest::AttestationReport;
+use sev::firmware::{guest::GuestPolicy, host, host::Firmware};
 use sev::launch::snp::*;
 
+use base64::prelude::*;
 use kvm_bindings::{kvm_enc_region, CpuId, KVM_CPUID_FLAG_SIGNIFCANT_INDEX};
 use kvm_ioctls::VmFd;
+use openssl::x509::{CrlStatus, X509Crl, X509};
+use procfs::CpuInfo;
+use utils::cancel::CancelToken;
 use vm_memory::{
     Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
 };
 
 #[derive(Debug)]
 pub enum Error {
+    Cancelled,
     CpuIdWrite,
     CpuIdFull,
+    CertificateRevoked,
     CreateLauncher(std::io::Error),
+    DecodeCertificate(openssl::error::ErrorStack),
+    DownloadCertificate(HttpError),
+    FetchIdentifier,
     GuestMemoryWrite(vm_memory::GuestMemoryError),
     GuestMemoryRead(vm_memory::GuestMemoryError),
+    InvalidCrl,
+    InvalidReportSize(usize),
     LaunchStart(std::io::Error),
     LaunchUpdate(std::io::Error),
     LaunchFinish(std::io::Error),
+    MaaRequest(HttpError),
     MemoryEncryptRegion,
+    MigrationUnsupported,
     OpenFirmware(std::io::Error),
+    ParseMaaResponse,
+    PlatformStatus,
+    TcbTooOld {
+        reported: TcbVersion,
+        required: TcbVersion,
+    },
+    UnknownCpuModel,
 }
 
 const COUNT_MAX: usize = 80;
 
+/// Size of an attestation report, as defined by the SEV-SNP firmware ABI.
+pub const ATTESTATION_REPORT_SIZE: usize = 1184;
+
+const _: () = assert!(std::mem::size_of::<AttestationReport>() == ATTESTATION_REPORT_SIZE);
+
+/// Parses a raw attestation report into its typed fields (version, guest SVN,
+/// policy, family and image IDs, report data, measurement, host data, chip ID,
+/// TCB versions and signature).
+///
+/// Attestation reports can only be requested from inside the guest, which is
+/// what init does through /dev/sev-guest, so this is meant for whoever handles
+/// the reports the guest hands to the attestation server.
+pub fn parse_attestation_report(data: &[u8]) -> Result<AttestationReport, Error> {
+    if data.len() != ATTESTATION_REPORT_SIZE {
+        return Err(Error::InvalidReportSize(data.len()));
+    }
+
+    // Safe because we checked the buffer holds a full report, and every bit
+    // pattern is valid for its fields.
+    Ok(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const AttestationReport) })
+}
+
 fn as_u32_le(array: &[u8; 4]) -> u32 {
     (array[0] as u32)
         + ((array[1] as u32) << 8)
@@ -70,19 +117,231 @@ struct CpuidPageEntry {
     functions: [CpuidFunctionEntry; COUNT_MAX],
 }
 
+/// Name of the host processor family in the AMD KDS.
+fn kds_product() -> Result<&'static str, Error> {
+    let cpuinfo = CpuInfo::new().map_err(|_| Error::UnknownCpuModel)?;
+    let coreinfo = cpuinfo.get_info(0).ok_or(Error::UnknownCpuModel)?;
+
+    match (coreinfo.get("cpu family"), coreinfo.get("model")) {
+        (Some(&"25"), Some(&"1")) => Ok("Milan"),
+        (Some(&"25"), Some(&"17")) => Ok("Genoa"),
+        _ => Err(Error::UnknownCpuModel),
+    }
+}
+
+/// URL of the KDS serving the endorsement certificates of the host processor family.
+fn kds_url(tee_config: &TeeConfig) -> Result<String, Error> {
+    Ok(format!(
+        "{}/{}",
+        tee_config.vcek_url.trim_end_matches('/'),
+        kds_product()?
+    ))
+}
+
+/// Downloads a certificate or CRL from the KDS.
+fn kds_download(http_client: &mut dyn HttpClient, url: &str) -> Result<Vec<u8>, Error> {
+    http_client.get(url).map_err(|e| match e {
+        HttpError::Cancelled => Error::Cancelled,
+        e => Error::DownloadCertificate(e),
+    })
+}
+
+/// Downloads the VCEK of the chip `chip_id` at the TCB `tcb`, along with the
+/// chain endorsing it, which holds the ASK followed by the ARK.
+fn fetch_vcek(
+    http_client: &mut dyn HttpClient,
+    kds_url: &str,
+    chip_id: &[u8],
+    tcb: &host::TcbVersion,
+) -> Result<(X509, Vec<X509>), Error> {
+    let chip_id: String = chip_id.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let vcek = X509::from_der(&kds_download(
+        http_client,
+        &format!(
+            "{}/{}?blSPL={:02}&teeSPL={:02}&snpSPL={:02}&ucodeSPL={:02}",
+            kds_url, chip_id, tcb.bootloader, tcb.tee, tcb.snp, tcb.microcode
+        ),
+    )?)
+    .map_err(Error::DecodeCertificate)?;
+    let chain = X509::stack_from_pem(&kds_download(
+        http_client,
+        &format!("{}/cert_chain", kds_url),
+    )?)
+    .map_err(Error::DecodeCertificate)?;
+
+    Ok((vcek, chain))
+}
+
+/// Checks AMD didn't revoke the VCEK of this chip at its reported TCB, nor the
+/// ASK endorsing it, according to the CRL published by the KDS.
+fn check_revocation(
+    fw: &mut Firmware,
+    tee_config: &TeeConfig,
+    cancel: &CancelToken,
+) -> Result<(), Error> {
+    let kds_url = kds_url(tee_config)?;
+    let chip_id = fw.get_identifier().map_err(|_| Error::FetchIdentifier)?;
+    let tcb = fw
+        .snp_platform_status()
+        .map_err(|_| Error::PlatformStatus)?
+        .reported_tcb_version;
+
+    let mut http_client = http::new_client(tee_config, cancel);
+    let (vcek, chain) = fetch_vcek(http_client.as_mut(), &kds_url, &chip_id.0, &tcb)?;
+    let crl = X509Crl::from_der(&kds_download(
+        http_client.as_mut(),
+        &format!("{}/crl", kds_url),
+    )?)
+    .map_err(Error::DecodeCertificate)?;
+
+    let ark = chain.last().ok_or(Error::InvalidCrl)?;
+    let ark_key = ark.public_key().map_err(Error::DecodeCertificate)?;
+    if !crl.verify(&ark_key).map_err(Error::DecodeCertificate)? {
+        return Err(Error::InvalidCrl);
+    }
+
+    for cert in chain.iter().take(1).chain(std::iter::once(&vcek)) {
+        if let CrlStatus::Revoked(_) = crl.get_by_cert(cert) {
+            return Err(Error::CertificateRevoked);
+        }
+    }
+
+    debug!("SNP endorsement certificates aren't revoked");
+    Ok(())
+}
+
+/// Endpoint of Microsoft Azure Attestation verifying SEV-SNP reports.
+const MAA_ATTEST_PATH: &str = "/attest/SevSnpVm?api-version=2022-08-01";
+
+/// Formats the request asking MAA to attest `report`, endorsed by the PEM
+/// certificates of `vcek_chain`, and bound to `runtime_data`.
+fn maa_request(report: &[u8], vcek_chain: &[u8], runtime_data: &[u8]) -> String {
+    let report = serde_json::json!({
+        "SnpReport": BASE64_URL_SAFE_NO_PAD.encode(report),
+        "VcekCertChain": BASE64_URL_SAFE_NO_PAD.encode(vcek_chain),
+    });
+
+    serde_json::json!({
+        "report": BASE64_URL_SAFE_NO_PAD.encode(report.to_string()),
+        "runtimeData": {
+            "data": BASE64_URL_SAFE_NO_PAD.encode(runtime_data),
+            "dataType": "JSON",
+        },
+    })
+    .to_string()
+}
+
+/// Extracts the JWT from a MAA response.
+fn maa_token_from_response(response: &[u8]) -> Result<String, Error> {
+    let response: serde_json::Value =
+        serde_json::from_slice(response).map_err(|_| Error::ParseMaaResponse)?;
+    response["token"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or(Error::ParseMaaResponse)
+}
+
+/// Submits an attestation report the guest requested to Microsoft Azure
+/// Attestation at the `attestation_url` of `tee_config`, returning the JWT it
+/// issues. `runtime_data` is the JSON document whose hash the guest put in the
+/// report data, e.g. holding the public key the secrets are to be wrapped with.
+///
+/// The VCEK endorsing the report is fetched from the KDS at `vcek_url`.
+pub fn maa_token(
+    tee_config: &TeeConfig,
+    report: &[u8],
+    runtime_data: &[u8],
+    cancel: &CancelToken,
+) -> Result<String, Error> {
+    let parsed = parse_attestation_report(report)?;
+    let mut http_client = http::new_client(tee_config, cancel);
+
+    let (vcek, chain) = fetch_vcek(
+        http_client.as_mut(),
+        &kds_url(tee_config)?,
+        &parsed.chip_id,
+        &parsed.reported_tcb,
+    )?;
+    let mut vcek_chain = Vec::new();
+    for cert in std::iter::once(&vcek).chain(&chain) {
+        vcek_chain.extend(cert.to_pem().map_err(Error::DecodeCertificate)?);
+    }
+
+    let now = Instant::now();
+    let (url, response) = http::post_first_available(
+        http_client.as_mut(),
+        &tee_config.attestation_url,
+        MAA_ATTEST_PATH,
+        maa_request(report, &vcek_chain, runtime_data).as_bytes(),
+    )
+    .map_err(|e| match e {
+        HttpError::Cancelled => Error::Cancelled,
+        e => Error::MaaRequest(e),
+    })?;
+    info!("MAA token issued by {} in {:?}", url, now.elapsed());
+
+    maa_token_from_response(&response)
+}
+
+/// Checks the TCB reported by the firmware, which is the one attestation reports
+/// carry, is at least `required`.
+fn check_tcb(fw: &mut Firmware, required: &TcbVersion) -> Result<(), Error> {
+    let tcb = fw
+        .snp_platform_status()
+        .map_err(|_| Error::PlatformStatus)?
+        .reported_tcb_version;
+    let reported = TcbVersion {
+        bootloader: tcb.bootloader,
+        tee: tcb.tee,
+        snp: tcb.snp,
+        microcode: tcb.microcode,
+    };
+
+    if !reported.meets(required) {
+        return Err(Error::TcbTooOld {
+            reported,
+            required: *required,
+        });
+    }
+
+    debug!("SNP reported TCB {:?} meets {:?}", reported, required);
+    Ok(())
+}
+
 pub struct AmdSnp {
     fw: Firmware,
+    launcher: Option<Launcher<Started, RawFd, RawFd>>,
+    metrics: LaunchMetrics,
 }
 
 impl AmdSnp {
-    pub fn new() -> Result<Self, Error> {
-        let fw = Firmware::open().map_err(Error::OpenFirmware)?;
+    pub fn new(tee_config: &TeeConfig, cancel: &CancelToken) -> Result<Self, Error> {
+        // KVM doesn't support the migration agent SNP guests need to be migrated.
+        if tee_config.allow_migration {
+            return Err(Error::MigrationUnsupported);
+        }
+
+        let mut fw = Firmware::open().map_err(Error::OpenFirmware)?;
 
-        Ok(AmdSnp { fw })
+        // Fail before the guest reaches the attestation server.
+        if let Some(min_tcb) = &tee_config.min_tcb {
+            check_tcb(&mut fw, min_tcb)?;
+        }
+
+        if tee_config.check_revocation {
+            check_revocation(&mut fw, tee_config, cancel)?;
+        }
+
+        Ok(AmdSnp {
+            fw,
+            launcher: None,
+            metrics: LaunchMetrics::default(),
+        })
     }
 
-    pub fn vm_prepare(
-        &self,
+    fn launch_start(
+        &mut self,
         vm_fd: &VmFd,
         guest_mem: &GuestMemoryMmap,
     ) -> Result<Launcher<Started, RawFd, RawFd>, Error> {
@@ -91,6 +350,7 @@ impl AmdSnp {
 
         let launcher = Launcher::new(vm_rfd, fw_rfd).map_err(Error::CreateLauncher)?;
 
+        let now = Instant::now();
         for region in guest_mem.iter() {
             // It's safe to unwrap because the guest address is valid.
             let host_addr = guest_mem.get_host_address(region.start_addr()).unwrap();
@@ -102,7 +362,10 @@ impl AmdSnp {
             vm_fd
                 .register_enc_memory_region(&enc_region)
                 .map_err(|_| Error::MemoryEncryptRegion)?;
+            self.metrics.registered_regions += 1;
+            self.metrics.registered_bytes += region.len();
         }
+        self.metrics.memory_registration = now.elapsed();
 
         let mut policy = GuestPolicy(0);
         policy.set_smt_allowed(1);
@@ -116,7 +379,7 @@ impl AmdSnp {
 
     fn write_cpuid_page(
         &self,
-        cpuid: CpuId,
+        cpuid: &CpuId,
         guest_mem: &GuestMemoryMmap,
     ) -> Result<CpuidPageEntry, Error> {
         let mut cpuid_entry = CpuidPageEntry {
@@ -275,7 +538,7 @@ impl AmdSnp {
     }
 
     fn add_region(
-        &self,
+        &mut self,
         guest_mem: &GuestMemoryMmap,
         region: MeasuredRegion,
         launcher: &mut Launcher<Started, RawFd, RawFd>,
@@ -304,16 +567,21 @@ impl AmdSnp {
             (dp, dp, dp),
         );
 
-        launcher.update_data(update).map_err(Error::LaunchUpdate)
+        launcher.update_data(update).map_err(Error::LaunchUpdate)?;
+        self.metrics.updated_regions += 1;
+        self.metrics.updated_bytes += region.size as u64;
+
+        Ok(())
     }
 
-    pub fn vm_measure(
-        &self,
-        cpuid: CpuId,
+    fn launch_finish(
+        &mut self,
+        cpuid: &CpuId,
         guest_mem: &GuestMemoryMmap,
         measured_regions: Vec<MeasuredRegion>,
         mut launcher: Launcher<Started, RawFd, RawFd>,
     ) -> Result<(), Error> {
+        let now = Instant::now();
         for region in measured_regions {
             self.add_region(guest_mem, region, &mut launcher, PageType::Normal)?;
         }
@@ -394,10 +662,86 @@ impl AmdSnp {
             PageType::Zero,
         )?;
 
+        self.metrics.update_data = now.elapsed();
+
+        let now = Instant::now();
         let finish = Finish::new(None, None, [0; 32]);
 
         let (_vmfd, _fwfd) = launcher.finish(finish).map_err(Error::LaunchFinish)?;
+        self.metrics.finish = now.elapsed();
+        info!("SNP launch metrics: {:?}", self.metrics);
 
         Ok(())
     }
 }
+
+impl ConfidentialVm for AmdSnp {
+    fn vm_prepare(&mut self, vm_fd: &VmFd, guest_mem: &GuestMemoryMmap) -> Result<(), TeeError> {
+        let launcher = self.launch_start(vm_fd, guest_mem).map_err(TeeError::Snp)?;
+        self.launcher = Some(launcher);
+
+        Ok(())
+    }
+
+    fn vm_attest(
+        &mut self,
+        _vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        cpuid: &CpuId,
+        measured_regions: Vec<MeasuredRegion>,
+    ) -> Result<(), TeeError> {
+        let launcher = self.launcher.take().ok_or(TeeError::NotPrepared)?;
+
+        self.launch_finish(cpuid, guest_mem, measured_regions, launcher)
+            .map_err(TeeError::Snp)
+    }
+
+    fn tee_kind(&self) -> TeeKind {
+        TeeKind::Snp
+    }
+
+    fn launch_metrics(&self) -> LaunchMetrics {
+        self.metrics.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_json(data: &str) -> serde_json::Value {
+        serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_maa_request() {
+        let request: serde_json::Value =
+            serde_json::from_str(&maa_request(&[0xaa; 8], b"-----BEGIN", b"{\"k\":1}")).unwrap();
+
+        let report = decode_json(request["report"].as_str().unwrap());
+        assert_eq!(report["SnpReport"], "qqqqqqqqqqo");
+        assert_eq!(
+            BASE64_URL_SAFE_NO_PAD
+                .decode(report["VcekCertChain"].as_str().unwrap())
+                .unwrap(),
+            b"-----BEGIN"
+        );
+        assert_eq!(
+            decode_json(request["runtimeData"]["data"].as_str().unwrap()),
+            serde_json::json!({ "k": 1 })
+        );
+        assert_eq!(request["runtimeData"]["dataType"], "JSON");
+    }
+
+    #[test]
+    fn test_maa_token_from_response() {
+        assert_eq!(
+            maa_token_from_response(br#"{"token": "header.claims.signature"}"#).unwrap(),
+            "header.claims.signature"
+        );
+        assert!(matches!(
+            maa_token_from_response(br#"{"error": "invalid report"}"#),
+            Err(Error::ParseMaaResponse)
+        ));
+    }
+}
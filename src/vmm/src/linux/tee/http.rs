@@ -0,0 +1,660 @@
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use super::super::super::resources::TeeConfig;
+
+use curl::easy::{Easy, List};
+use utils::cancel::CancelToken;
+use utils::sensitive::is_sensitive;
+
+/// Errors returned by an `HttpClient`.
+#[derive(Debug)]
+pub enum HttpError {
+    /// The server couldn't be reached, or failed with an internal error, so the
+    /// request may succeed on another server.
+    Unavailable(String),
+    /// The request failed for any other reason.
+    Request(String),
+    /// The request was aborted through the cancellation token of the client.
+    Cancelled,
+}
+
+/// HTTP client used to talk to the attestation servers and the AMD certificate
+/// services.
+///
+/// `CurlAgent` is the default implementation, but embedders may provide their
+/// own, e.g. to avoid linking libcurl or to drive the attestation in tests.
+pub trait HttpClient {
+    /// Fetches `url`, returning the response body.
+    fn get(&mut self, url: &str) -> Result<Vec<u8>, HttpError>;
+
+    /// Posts a JSON document to `url`, returning the response body.
+    fn post(&mut self, url: &str, data: &[u8]) -> Result<Vec<u8>, HttpError>;
+
+    /// Forgets the session state, such as cookies, kept from previous requests.
+    fn reset_session(&mut self) {}
+}
+
+/// Creates the HTTP client for the given TEE config, aborting its requests
+/// once `cancel` is triggered.
+pub fn new_client(tee_config: &TeeConfig, cancel: &CancelToken) -> Box<dyn HttpClient + Send> {
+    if tee_config.offline {
+        Box::new(OfflineClient)
+    } else {
+        Box::new(CurlAgent::new(cancel.clone()))
+    }
+}
+
+/// Posts `body` to `path` on each of the comma-separated `servers` in order,
+/// only moving to the next one if the current one can't be reached. Returns
+/// the URL of the server that answered along with its response.
+pub fn post_first_available(
+    client: &mut dyn HttpClient,
+    servers: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<(String, Vec<u8>), HttpError> {
+    for url in attestation_urls(servers) {
+        debug!("Trying attestation server {}", url);
+        match client.post(&format!("{}{}", url, path), body) {
+            Err(HttpError::Unavailable(e)) => {
+                warn!(
+                    "Attestation server {} is unavailable ({}), trying the next one",
+                    url, e
+                );
+                client.reset_session();
+            }
+            result => return result.map(|response| (url.to_string(), response)),
+        }
+    }
+
+    Err(HttpError::Unavailable(format!(
+        "no attestation server available in {}",
+        servers
+    )))
+}
+
+/// Splits a comma-separated list of attestation server URLs.
+fn attestation_urls(attestation_url: &str) -> impl Iterator<Item = &str> {
+    attestation_url
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+}
+
+/// Splits a `unix:///path/to/socket/request/path` URL into the path of the
+/// socket and the HTTP URL to request on it. As the request path is appended
+/// to the socket's, the socket is the leading part of the path that names one,
+/// or the whole of it if none does.
+fn unix_socket_url(url: &str) -> Option<(&str, String)> {
+    let path = url.strip_prefix("unix://")?;
+    let is_socket = |path: &str| {
+        fs::metadata(path)
+            .map(|metadata| metadata.file_type().is_socket())
+            .unwrap_or(false)
+    };
+    let socket_end = path
+        .match_indices('/')
+        .map(|(i, _)| i)
+        .filter(|&i| i > 0)
+        .chain([path.len()])
+        .find(|&i| is_socket(&path[..i]))
+        .unwrap_or(path.len());
+
+    let (socket, request_path) = path.split_at(socket_end);
+    let request_path = if request_path.is_empty() {
+        "/"
+    } else {
+        request_path
+    };
+    Some((socket, format!("http://localhost{}", request_path)))
+}
+
+/// Wrapper to log HTTP bodies with their sensitive values redacted.
+///
+/// JSON bodies are printed with the values of the sensitive keys replaced, at any
+/// nesting level. Anything else is reduced to its length.
+struct Redacted<'a>(&'a [u8]);
+
+impl Redacted<'_> {
+    fn redact(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if is_sensitive(key) {
+                        *value = serde_json::Value::String("<redacted>".to_string());
+                    } else {
+                        Self::redact(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(Self::redact),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match serde_json::from_slice::<serde_json::Value>(self.0) {
+            Ok(mut value) => {
+                Self::redact(&mut value);
+                write!(f, "{}", value)
+            }
+            Err(_) => write!(f, "<{} bytes>", self.0.len()),
+        }
+    }
+}
+
+/// Client refusing any request, for launches forbidden to access the network.
+struct OfflineClient;
+
+impl HttpClient for OfflineClient {
+    fn get(&mut self, url: &str) -> Result<Vec<u8>, HttpError> {
+        Err(HttpError::Request(format!(
+            "network access is disabled: GET {}",
+            url
+        )))
+    }
+
+    fn post(&mut self, url: &str, _data: &[u8]) -> Result<Vec<u8>, HttpError> {
+        Err(HttpError::Request(format!(
+            "network access is disabled: POST {}",
+            url
+        )))
+    }
+}
+
+pub struct CurlAgent {
+    easy: Easy,
+    session_id: Option<String>,
+    cancel: CancelToken,
+}
+
+/// Returns the session cookie set by a response header, if any.
+fn extract_session_id(header: &[u8]) -> Option<String> {
+    let header = std::str::from_utf8(header).ok()?;
+    let (name, value) = header.split_once(':')?;
+    if !name.trim().eq_ignore_ascii_case("set-cookie") {
+        return None;
+    }
+
+    // Only the first pair is the cookie itself, the rest are its attributes.
+    let cookie = value.split(';').next()?;
+    match cookie.split_once('=') {
+        Some((name, value)) if name.trim() == "session_id" => Some(value.trim().to_string()),
+        _ => None,
+    }
+}
+
+impl CurlAgent {
+    /// Creates an agent aborting the transfer in progress once `cancel` is triggered.
+    pub fn new(cancel: CancelToken) -> Self {
+        CurlAgent {
+            easy: Easy::new(),
+            session_id: None,
+            cancel,
+        }
+    }
+
+    /// Points the handle at `url`, going through a Unix socket for `unix://`
+    /// URLs and back to TCP for any other.
+    fn set_url(&mut self, url: &str) -> Result<(), curl::Error> {
+        match unix_socket_url(url) {
+            Some((socket, http_url)) => {
+                self.easy.unix_socket_path(Some(socket))?;
+                self.easy.url(&http_url)
+            }
+            None => {
+                self.easy.unix_socket_path(None::<&Path>)?;
+                self.easy.url(url)
+            }
+        }
+    }
+
+    /// Appends the cookie of the current session, if any, to `headers`.
+    fn append_session_cookie(&self, headers: &mut List) -> Result<(), curl::Error> {
+        if let Some(session_id) = &self.session_id {
+            headers.append(&format!("Cookie: session_id={}", session_id))?;
+        }
+
+        Ok(())
+    }
+
+    fn curl_get(&mut self, url: &str) -> Result<Vec<u8>, curl::Error> {
+        let mut rsp = Vec::new();
+
+        let mut headers = List::new();
+        self.append_session_cookie(&mut headers)?;
+
+        self.easy.get(true)?;
+        // Let curl advertise and decode every encoding it supports.
+        self.easy.accept_encoding("")?;
+        self.set_url(url)?;
+        self.easy.http_headers(headers)?;
+        self.easy.progress(true)?;
+
+        let mut transfer = self.easy.transfer();
+        transfer.progress_function(|_, _, _, _| !self.cancel.is_cancelled())?;
+        transfer.write_function(|data| {
+            rsp.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        // The server may rotate the session cookie on any response.
+        transfer.header_function(|header| {
+            if let Some(session_id) = extract_session_id(header) {
+                self.session_id = Some(session_id);
+            }
+            true
+        })?;
+        transfer.perform()?;
+        drop(transfer);
+
+        Ok(rsp)
+    }
+
+    /// Posts `data` to `url`, returning the response body along with the
+    /// number of bytes of `data` curl actually sent.
+    fn curl_post(&mut self, url: &str, data: &[u8]) -> Result<(Vec<u8>, usize), curl::Error> {
+        let mut rsp = Vec::new();
+        let mut sent = 0;
+
+        let mut headers = List::new();
+        headers.append("Accept: application/json")?;
+        headers.append("Content-Type: application/json; charset=utf-8")?;
+        self.append_session_cookie(&mut headers)?;
+
+        self.easy.post(true)?;
+        self.easy.post_field_size(data.len() as u64)?;
+        self.easy.accept_encoding("")?;
+        self.set_url(url)?;
+        self.easy.http_headers(headers)?;
+        self.easy.progress(true)?;
+
+        let mut transfer = self.easy.transfer();
+        transfer.progress_function(|_, _, _, _| !self.cancel.is_cancelled())?;
+        transfer.read_function(|buf| {
+            let len = buf.len().min(data.len() - sent);
+            buf[..len].copy_from_slice(&data[sent..sent + len]);
+            sent += len;
+            Ok(len)
+        })?;
+        transfer.write_function(|data| {
+            rsp.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.header_function(|header| {
+            if let Some(session_id) = extract_session_id(header) {
+                self.session_id = Some(session_id);
+            }
+            true
+        })?;
+        transfer.perform()?;
+        drop(transfer);
+
+        Ok((rsp, sent))
+    }
+
+    /// Tells apart the failures worth retrying with another server: the server
+    /// couldn't be reached or hit an internal error.
+    fn check_response<T>(&mut self, result: Result<T, curl::Error>) -> Result<T, HttpError> {
+        match result {
+            Ok(rsp) => match self.easy.response_code() {
+                Ok(code) if code >= 500 => Err(HttpError::Unavailable(format!("HTTP {}", code))),
+                _ => Ok(rsp),
+            },
+            Err(e) if e.is_aborted_by_callback() => Err(HttpError::Cancelled),
+            Err(e)
+                if e.is_couldnt_resolve_host()
+                    || e.is_couldnt_connect()
+                    || e.is_operation_timedout()
+                    || e.is_send_error()
+                    || e.is_recv_error()
+                    || e.is_got_nothing() =>
+            {
+                Err(HttpError::Unavailable(e.to_string()))
+            }
+            Err(e) => Err(HttpError::Request(e.to_string())),
+        }
+    }
+}
+
+impl HttpClient for CurlAgent {
+    fn get(&mut self, url: &str) -> Result<Vec<u8>, HttpError> {
+        debug!("GET {}", url);
+        let result = self.curl_get(url);
+        let rsp = self.check_response(result)?;
+        trace!("GET {} response: {}", url, Redacted(&rsp));
+
+        Ok(rsp)
+    }
+
+    fn post(&mut self, url: &str, data: &[u8]) -> Result<Vec<u8>, HttpError> {
+        debug!("POST {}", url);
+        trace!("POST {} request: {}", url, Redacted(data));
+        let result = self.curl_post(url, data);
+        let (rsp, sent) = self.check_response(result)?;
+        // Don't let a truncated body, such as attestation evidence, pass for
+        // a complete one.
+        if sent != data.len() {
+            return Err(HttpError::Request(format!(
+                "only {} of the {} bytes of the request body were sent",
+                sent,
+                data.len()
+            )));
+        }
+        trace!("POST {} response: {}", url, Redacted(&rsp));
+
+        Ok(rsp)
+    }
+
+    fn reset_session(&mut self) {
+        self.session_id = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    /// A canned response from the test server.
+    #[derive(Default)]
+    struct Reply {
+        set_cookie: Option<&'static str>,
+        content_encoding: Option<&'static str>,
+        body: Vec<u8>,
+    }
+
+    /// Serves one request per connection, answering the n-th one with the n-th
+    /// reply. Returns the headers of each request, with lowercase names, along
+    /// with its request line as `request-line` and its body as `body`.
+    fn serve<S: Read + Write>(
+        mut accept: impl FnMut() -> S,
+        replies: Vec<Reply>,
+    ) -> Vec<HashMap<String, String>> {
+        let mut received = Vec::new();
+
+        for reply in replies {
+            let mut reader = BufReader::new(accept());
+            let mut headers = HashMap::new();
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            headers.insert(
+                "request-line".to_string(),
+                request_line.trim_end().to_string(),
+            );
+
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.insert(name.to_lowercase(), value.trim().to_string());
+                }
+            }
+            let content_length = headers
+                .get("content-length")
+                .map(|len| len.parse().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            headers.insert("body".to_string(), String::from_utf8(body).unwrap());
+            received.push(headers);
+
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n",
+                reply.body.len()
+            );
+            if let Some(set_cookie) = reply.set_cookie {
+                response += &format!("Set-Cookie: session_id={}; Path=/\r\n", set_cookie);
+            }
+            if let Some(encoding) = reply.content_encoding {
+                response += &format!("Content-Encoding: {}\r\n", encoding);
+            }
+            response += "\r\n";
+            let stream = reader.get_mut();
+            stream.write_all(response.as_bytes()).unwrap();
+            stream.write_all(&reply.body).unwrap();
+        }
+
+        received
+    }
+
+    #[test]
+    fn test_extract_session_id() {
+        assert_eq!(
+            extract_session_id(b"Set-Cookie: session_id=abc; Path=/\r\n"),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            extract_session_id(b"set-cookie: session_id=abc\r\n"),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            extract_session_id(b"Set-Cookie: other=abc; session_id=def\r\n"),
+            None
+        );
+        assert_eq!(extract_session_id(b"X-Session: session_id=abc\r\n"), None);
+    }
+
+    #[test]
+    fn test_session_cookie_rotation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let replies = vec![
+            Reply {
+                set_cookie: Some("first"),
+                ..Default::default()
+            },
+            Reply {
+                set_cookie: Some("second"),
+                ..Default::default()
+            },
+            Reply::default(),
+        ];
+        let server = thread::spawn(move || serve(|| listener.accept().unwrap().0, replies));
+
+        let mut agent = CurlAgent::new(CancelToken::new());
+        agent.post(&format!("{}/kbs/v0/auth", url), b"{}").unwrap();
+        agent
+            .post(&format!("{}/kbs/v0/attest", url), b"{}")
+            .unwrap();
+        agent.get(&format!("{}/kbs/v0/key/id", url)).unwrap();
+
+        // The key is requested with the cookie rotated by the attestation response.
+        let cookies: Vec<_> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|headers| headers.get("cookie").cloned())
+            .collect();
+        assert_eq!(
+            cookies,
+            vec![
+                None,
+                Some("session_id=first".to_string()),
+                Some("session_id=second".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_chunk_post() {
+        // Well beyond the upload buffer of curl, so the body is read in chunks.
+        let body: String = (0..1 << 20)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server =
+            thread::spawn(move || serve(|| listener.accept().unwrap().0, vec![Reply::default()]));
+
+        let mut agent = CurlAgent::new(CancelToken::new());
+        agent
+            .post(&format!("{}/kbs/v0/attest", url), body.as_bytes())
+            .unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0]["content-length"], body.len().to_string());
+        assert!(requests[0]["body"] == body);
+    }
+
+    #[test]
+    fn test_compressed_responses() {
+        let json = br#"{"nonce": "42", "extra-params": {}}"#;
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(json).unwrap();
+        let mut deflate = ZlibEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(json).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let replies = vec![
+            Reply {
+                content_encoding: Some("gzip"),
+                body: gzip.finish().unwrap(),
+                ..Default::default()
+            },
+            Reply {
+                content_encoding: Some("deflate"),
+                body: deflate.finish().unwrap(),
+                ..Default::default()
+            },
+            Reply {
+                body: json.to_vec(),
+                ..Default::default()
+            },
+        ];
+        let server = thread::spawn(move || serve(|| listener.accept().unwrap().0, replies));
+
+        let mut agent = CurlAgent::new(CancelToken::new());
+        let responses = vec![
+            agent.post(&format!("{}/kbs/v0/auth", url), b"{}").unwrap(),
+            agent.get(&format!("{}/kbs/v0/key/id", url)).unwrap(),
+            agent.get(&format!("{}/kbs/v0/key/id", url)).unwrap(),
+        ];
+
+        for response in responses {
+            let value: serde_json::Value = serde_json::from_slice(&response).unwrap();
+            assert_eq!(value["nonce"], "42");
+        }
+        for headers in server.join().unwrap() {
+            let encodings = &headers["accept-encoding"];
+            assert!(encodings.contains("gzip") && encodings.contains("deflate"));
+        }
+    }
+
+    #[test]
+    fn test_unix_socket_url() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.as_path().join("kbs.sock");
+        let _listener = UnixListener::bind(&socket).unwrap();
+        let socket = socket.to_str().unwrap();
+
+        assert_eq!(
+            unix_socket_url(&format!("unix://{}/kbs/v0/auth", socket)),
+            Some((socket, "http://localhost/kbs/v0/auth".to_string()))
+        );
+        assert_eq!(
+            unix_socket_url(&format!("unix://{}", socket)),
+            Some((socket, "http://localhost/".to_string()))
+        );
+        // Without a socket to find, the whole path is taken as one.
+        assert_eq!(
+            unix_socket_url("unix:///run/missing.sock"),
+            Some(("/run/missing.sock", "http://localhost/".to_string()))
+        );
+        assert_eq!(unix_socket_url("http://kbs/kbs/v0/auth"), None);
+    }
+
+    #[test]
+    fn test_unix_socket_requests() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.as_path().join("kbs.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let url = format!("unix://{}", socket.to_str().unwrap());
+        let replies = vec![
+            Reply {
+                set_cookie: Some("first"),
+                ..Default::default()
+            },
+            Reply {
+                body: b"secret".to_vec(),
+                ..Default::default()
+            },
+        ];
+        let server = thread::spawn(move || serve(|| listener.accept().unwrap().0, replies));
+
+        let mut agent = CurlAgent::new(CancelToken::new());
+        agent.post(&format!("{}/kbs/v0/auth", url), b"{}").unwrap();
+        assert_eq!(
+            agent.get(&format!("{}/kbs/v0/key/id", url)).unwrap(),
+            b"secret"
+        );
+
+        // The HTTP semantics, session cookie included, are kept on the socket.
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0]["request-line"], "POST /kbs/v0/auth HTTP/1.1");
+        assert_eq!(requests[1]["request-line"], "GET /kbs/v0/key/id HTTP/1.1");
+        assert_eq!(requests[1]["cookie"], "session_id=first");
+    }
+
+    #[test]
+    fn test_cancelled_request() {
+        // The server never answers, so only the cancellation ends the request.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let cancel = CancelToken::new();
+        let mut agent = CurlAgent::new(cancel.clone());
+        cancel.cancel();
+        assert!(matches!(
+            agent.get(&format!("{}/kbs/v0/key/id", url)),
+            Err(HttpError::Cancelled)
+        ));
+        drop(listener);
+    }
+
+    #[test]
+    fn test_redacted_kbs_key_response() {
+        // A resource released by the KBS, wrapped as a JWE.
+        let response = br#"{
+            "protected": "eyJhbGciOiJSU0EtT0FFUCIsImVuYyI6IkEyNTZHQ00ifQ",
+            "encrypted_key": "OKOawDo13gRp2ojaHV7LFpZcgV7T6DVZKTyKOMTYUmKoTCVJRgckCL9kiMT03JGe",
+            "iv": "48V1_ALb6US04U3b",
+            "ciphertext": "5eym8TW_c8SuK0ltJ3rpYIzOeDQz7TALvtu6UG9oMo4vpzs9tX_EFShS8iB7j6ji",
+            "tag": "XFBoMYUZodetZdvTiFvSkQ"
+        }"#;
+
+        let logged: serde_json::Value =
+            serde_json::from_str(&Redacted(response).to_string()).unwrap();
+        assert_eq!(
+            logged,
+            serde_json::json!({
+                "protected": "eyJhbGciOiJSU0EtT0FFUCIsImVuYyI6IkEyNTZHQ00ifQ",
+                "encrypted_key": "<redacted>",
+                "iv": "<redacted>",
+                "ciphertext": "<redacted>",
+                "tag": "<redacted>",
+            })
+        );
+    }
+}
@@ -3,3 +3,155 @@ pub mod amdsev;
 
 #[cfg(feature = "amd-sev")]
 pub mod amdsnp;
+
+#[cfg(feature = "amd-sev")]
+pub mod http;
+
+#[cfg(feature = "amd-sev")]
+pub mod teekey;
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::vstate::MeasuredRegion;
+
+use kvm_bindings::CpuId;
+use kvm_ioctls::VmFd;
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The VM was attested before being prepared for the confidential launch.
+    NotPrepared,
+    /// The backend doesn't produce attestation evidence.
+    EvidenceUnsupported,
+    /// The backend can't access the guest memory in clear.
+    DebugUnsupported,
+    #[cfg(feature = "amd-sev")]
+    Sev(amdsev::Error),
+    #[cfg(feature = "amd-sev")]
+    Snp(amdsnp::Error),
+}
+
+/// The launch measurement of a confidential guest, as computed by the firmware.
+#[derive(Clone, Debug)]
+pub struct LaunchMeasurement {
+    /// Digest of the initial guest state.
+    pub measurement: Vec<u8>,
+    /// Nonce the firmware mixed into the measurement.
+    pub nonce: Vec<u8>,
+}
+
+/// Time spent in each phase of a confidential launch, along with the amount of
+/// guest memory involved. Phases a backend doesn't go through are left at zero.
+#[derive(Clone, Debug, Default)]
+pub struct LaunchMetrics {
+    /// Fetching or loading the certificate chain.
+    pub chain_fetch: Duration,
+    /// Registering the guest memory as encrypted.
+    pub memory_registration: Duration,
+    /// Number of guest memory regions registered.
+    pub registered_regions: usize,
+    /// Bytes of guest memory registered.
+    pub registered_bytes: u64,
+    /// Encrypting and measuring the initial guest memory.
+    pub update_data: Duration,
+    /// Number of memory ranges encrypted and measured.
+    pub updated_regions: usize,
+    /// Bytes of guest memory encrypted and measured.
+    pub updated_bytes: u64,
+    /// Computing the launch measurement.
+    pub measure: Duration,
+    /// Getting the secret from the attestation server or the secret file.
+    pub secret_fetch: Duration,
+    /// Injecting the secret and finishing the launch.
+    pub finish: Duration,
+}
+
+/// The confidentiality mode a guest is launched in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TeeKind {
+    /// SEV, with only the guest memory encrypted.
+    Sev,
+    /// SEV-ES, with the vCPU state encrypted too.
+    SevEs,
+    /// SEV-SNP, adding integrity protection of the guest memory.
+    Snp,
+}
+
+impl fmt::Display for TeeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TeeKind::Sev => write!(f, "SEV"),
+            TeeKind::SevEs => write!(f, "SEV-ES"),
+            TeeKind::Snp => write!(f, "SEV-SNP"),
+        }
+    }
+}
+
+/// A Trusted Execution Environment backend able to launch a confidential guest.
+///
+/// `vstate` only talks to the TEE through this trait, so adding a new backend
+/// doesn't require touching the VM state machine.
+pub trait ConfidentialVm: Send {
+    /// Registers the guest memory as encrypted and starts the launch sequence.
+    fn vm_prepare(&mut self, vm_fd: &VmFd, guest_mem: &GuestMemoryMmap) -> Result<(), Error>;
+
+    /// Measures the guest memory regions, attests the guest and finishes the launch.
+    fn vm_attest(
+        &mut self,
+        vm_fd: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+        cpuid: &CpuId,
+        measured_regions: Vec<MeasuredRegion>,
+    ) -> Result<(), Error>;
+
+    /// The confidentiality mode in effect for the guest.
+    fn tee_kind(&self) -> TeeKind;
+
+    /// Returns the evidence gathered while attesting the guest as a versioned
+    /// JSON document, so it can be submitted to an external verifier.
+    fn attestation_evidence(&self) -> Result<String, Error> {
+        Err(Error::EvidenceUnsupported)
+    }
+
+    /// Returns the measurement computed while attesting the guest.
+    fn launch_measurement(&self) -> Result<LaunchMeasurement, Error> {
+        Err(Error::EvidenceUnsupported)
+    }
+
+    /// Returns the timing of the launch phases completed so far.
+    fn launch_metrics(&self) -> LaunchMetrics {
+        LaunchMetrics::default()
+    }
+
+    /// Whether the state of the vCPUs is encrypted, so the VMM can't access
+    /// their registers.
+    fn encrypted_state(&self) -> bool {
+        true
+    }
+
+    /// Decrypts `buf.len()` bytes of guest memory at `guest_addr` into `buf`,
+    /// for debugging guests whose launch allowed it.
+    fn debug_decrypt(
+        &self,
+        _vm_fd: &VmFd,
+        _guest_mem: &GuestMemoryMmap,
+        _guest_addr: GuestAddress,
+        _buf: &mut [u8],
+    ) -> Result<(), Error> {
+        Err(Error::DebugUnsupported)
+    }
+
+    /// Encrypts `buf` into guest memory at `guest_addr`, with the same
+    /// requirements as `debug_decrypt`.
+    fn debug_encrypt(
+        &self,
+        _vm_fd: &VmFd,
+        _guest_mem: &GuestMemoryMmap,
+        _guest_addr: GuestAddress,
+        _buf: &[u8],
+    ) -> Result<(), Error> {
+        Err(Error::DebugUnsupported)
+    }
+}
@@ -0,0 +1,368 @@
+use super::super::super::resources::KeyWrapAlgorithm;
+
+use base64::prelude::*;
+use kbs_types::{Response, TeePubKey};
+use openssl::aes::{unwrap_key, AesKey};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::{Padding, Rsa};
+use openssl::sha::Sha256;
+use openssl::symm::{decrypt_aead, Cipher};
+use serde::Deserialize;
+
+/// Size of the RSA keys generated for `KeyWrapAlgorithm::RsaOaep`.
+const RSA_KEY_BITS: u32 = 2048;
+
+/// The only content encryption the resources may be wrapped with.
+const CONTENT_ENCRYPTION: &str = "A256GCM";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The broker wrapped the resource with an algorithm other than the one
+    /// advertised in the TEE public key.
+    UnsupportedWrapAlgorithm(String),
+    GenerateKey(ErrorStack),
+    EncodePublicKey(ErrorStack),
+    DecodeResponse,
+    UnwrapKey(ErrorStack),
+    InvalidWrappedKey,
+    Decrypt(ErrorStack),
+}
+
+impl KeyWrapAlgorithm {
+    /// Name of the algorithm in the JWE header (RFC 7518).
+    fn name(&self) -> &'static str {
+        match self {
+            KeyWrapAlgorithm::RsaOaep => "RSA-OAEP",
+            KeyWrapAlgorithm::EcdhEsA256kw => "ECDH-ES+A256KW",
+        }
+    }
+}
+
+/// Ephemeral key pair the KBS wraps the released resources with, so they're
+/// only readable by the host that attested the launch.
+pub struct TeeKey {
+    algorithm: KeyWrapAlgorithm,
+    key: PKey<Private>,
+}
+
+/// Protected header of the JWE a resource is served as.
+#[derive(Deserialize)]
+struct ProtectedHeader {
+    alg: String,
+    enc: String,
+    /// Ephemeral public key of the broker, for ECDH-ES.
+    epk: Option<EphemeralKey>,
+    apu: Option<String>,
+    apv: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EphemeralKey {
+    crv: String,
+    x: String,
+    y: String,
+}
+
+fn decode(field: &str) -> Result<Vec<u8>, Error> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(field)
+        .map_err(|_| Error::DecodeResponse)
+}
+
+fn encode(data: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(data)
+}
+
+fn p256() -> Result<EcGroup, ErrorStack> {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+}
+
+/// Derives the 256-bit key wrapping key from the ECDH shared secret `z`, with
+/// the Concat KDF of NIST SP 800-56A as profiled by RFC 7518, section 4.6.2.
+fn concat_kdf(z: &[u8], alg: &str, apu: &[u8], apv: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(&1u32.to_be_bytes());
+    hasher.update(z);
+    for field in [alg.as_bytes(), apu, apv] {
+        hasher.update(&(field.len() as u32).to_be_bytes());
+        hasher.update(field);
+    }
+    hasher.update(&256u32.to_be_bytes());
+    hasher.finish()
+}
+
+impl TeeKey {
+    pub fn generate(algorithm: KeyWrapAlgorithm) -> Result<Self, Error> {
+        let key = match algorithm {
+            KeyWrapAlgorithm::RsaOaep => Rsa::generate(RSA_KEY_BITS).and_then(PKey::from_rsa),
+            KeyWrapAlgorithm::EcdhEsA256kw => p256()
+                .and_then(|group| EcKey::generate(&group))
+                .and_then(PKey::from_ec_key),
+        }
+        .map_err(Error::GenerateKey)?;
+
+        Ok(TeeKey { algorithm, key })
+    }
+
+    /// The public key to send along with the attestation evidence, advertising
+    /// the algorithm the resources are to be wrapped with.
+    pub fn public_key(&self) -> Result<TeePubKey, Error> {
+        let alg = self.algorithm.name().to_string();
+        match self.algorithm {
+            KeyWrapAlgorithm::RsaOaep => {
+                let rsa = self.key.rsa().map_err(Error::EncodePublicKey)?;
+                Ok(TeePubKey::RSA {
+                    alg,
+                    k_mod: encode(&rsa.n().to_vec()),
+                    k_exp: encode(&rsa.e().to_vec()),
+                })
+            }
+            KeyWrapAlgorithm::EcdhEsA256kw => {
+                let (x, y) = self.ec_coordinates().map_err(Error::EncodePublicKey)?;
+                Ok(TeePubKey::EC {
+                    crv: "P-256".to_string(),
+                    alg,
+                    x: encode(&x),
+                    y: encode(&y),
+                })
+            }
+        }
+    }
+
+    fn ec_coordinates(&self) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+        let ec = self.key.ec_key()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        let mut ctx = BigNumContext::new()?;
+        ec.public_key()
+            .affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx)?;
+        Ok((x.to_vec_padded(32)?, y.to_vec_padded(32)?))
+    }
+
+    /// Decrypts a resource served by the KBS, checking it was wrapped with the
+    /// advertised algorithm.
+    pub fn unwrap(&self, response: &Response) -> Result<Vec<u8>, Error> {
+        let header: ProtectedHeader = serde_json::from_slice(&decode(&response.protected)?)
+            .map_err(|_| Error::DecodeResponse)?;
+        if header.alg != self.algorithm.name() {
+            return Err(Error::UnsupportedWrapAlgorithm(header.alg));
+        }
+        if header.enc != CONTENT_ENCRYPTION {
+            return Err(Error::UnsupportedWrapAlgorithm(header.enc));
+        }
+
+        let encrypted_key = decode(&response.encrypted_key)?;
+        let cek = match self.algorithm {
+            KeyWrapAlgorithm::RsaOaep => self.unwrap_rsa_oaep(&encrypted_key)?,
+            KeyWrapAlgorithm::EcdhEsA256kw => self.unwrap_ecdh_es(&header, &encrypted_key)?,
+        };
+        if cek.len() != Cipher::aes_256_gcm().key_len() {
+            return Err(Error::InvalidWrappedKey);
+        }
+
+        // The encoded protected header is authenticated along with the content.
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &cek,
+            Some(&decode(&response.iv)?),
+            response.protected.as_bytes(),
+            &decode(&response.ciphertext)?,
+            &decode(&response.tag)?,
+        )
+        .map_err(Error::Decrypt)
+    }
+
+    fn unwrap_rsa_oaep(&self, encrypted_key: &[u8]) -> Result<Vec<u8>, Error> {
+        let rsa = self.key.rsa().map_err(Error::UnwrapKey)?;
+        let mut cek = vec![0; rsa.size() as usize];
+        let len = rsa
+            .private_decrypt(encrypted_key, &mut cek, Padding::PKCS1_OAEP)
+            .map_err(Error::UnwrapKey)?;
+        cek.truncate(len);
+        Ok(cek)
+    }
+
+    fn unwrap_ecdh_es(
+        &self,
+        header: &ProtectedHeader,
+        encrypted_key: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let epk = header.epk.as_ref().ok_or(Error::DecodeResponse)?;
+        if epk.crv != "P-256" {
+            return Err(Error::UnsupportedWrapAlgorithm(epk.crv.clone()));
+        }
+        // RFC 3394 wrapping adds an 8-byte integrity check to the key.
+        if encrypted_key.len() <= 8 || !encrypted_key.len().is_multiple_of(8) {
+            return Err(Error::InvalidWrappedKey);
+        }
+
+        let x = BigNum::from_slice(&decode(&epk.x)?).map_err(Error::UnwrapKey)?;
+        let y = BigNum::from_slice(&decode(&epk.y)?).map_err(Error::UnwrapKey)?;
+        let z = p256()
+            .and_then(|group| EcKey::from_public_key_affine_coordinates(&group, &x, &y))
+            .and_then(PKey::from_ec_key)
+            .and_then(|peer| {
+                let mut deriver = Deriver::new(&self.key)?;
+                deriver.set_peer(&peer)?;
+                deriver.derive_to_vec()
+            })
+            .map_err(Error::UnwrapKey)?;
+
+        let apu = header.apu.as_deref().map(decode).transpose()?;
+        let apv = header.apv.as_deref().map(decode).transpose()?;
+        let kek = concat_kdf(
+            &z,
+            self.algorithm.name(),
+            apu.as_deref().unwrap_or_default(),
+            apv.as_deref().unwrap_or_default(),
+        );
+
+        let kek = AesKey::new_decrypt(&kek).map_err(|_| Error::InvalidWrappedKey)?;
+        let mut cek = vec![0; encrypted_key.len() - 8];
+        unwrap_key(&kek, None, &mut cek, encrypted_key).map_err(|_| Error::InvalidWrappedKey)?;
+        Ok(cek)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::aes::wrap_key;
+    use openssl::pkey::Public;
+    use openssl::rand::rand_bytes;
+    use openssl::symm::encrypt_aead;
+
+    const PLAINTEXT: &[u8] = b"{\"header\":\"secret\"}";
+
+    fn ec_public_key(x: &str, y: &str) -> PKey<Public> {
+        let x = BigNum::from_slice(&decode(x).unwrap()).unwrap();
+        let y = BigNum::from_slice(&decode(y).unwrap()).unwrap();
+        let ec = EcKey::from_public_key_affine_coordinates(&p256().unwrap(), &x, &y).unwrap();
+        PKey::from_ec_key(ec).unwrap()
+    }
+
+    /// Wraps `plaintext` for `pubkey` as a KBS would, with `alg` in the header.
+    fn wrap(pubkey: &TeePubKey, alg: &str, plaintext: &[u8]) -> Response {
+        let mut cek = [0u8; 32];
+        rand_bytes(&mut cek).unwrap();
+
+        let (header, encrypted_key) = match pubkey {
+            TeePubKey::RSA { k_mod, k_exp, .. } => {
+                let rsa = Rsa::from_public_components(
+                    BigNum::from_slice(&decode(k_mod).unwrap()).unwrap(),
+                    BigNum::from_slice(&decode(k_exp).unwrap()).unwrap(),
+                )
+                .unwrap();
+                let mut encrypted_key = vec![0; rsa.size() as usize];
+                rsa.public_encrypt(&cek, &mut encrypted_key, Padding::PKCS1_OAEP)
+                    .unwrap();
+                (
+                    serde_json::json!({ "alg": alg, "enc": "A256GCM" }),
+                    encrypted_key,
+                )
+            }
+            TeePubKey::EC { x, y, .. } => {
+                let ephemeral =
+                    PKey::from_ec_key(EcKey::generate(&p256().unwrap()).unwrap()).unwrap();
+                let peer = ec_public_key(x, y);
+                let mut deriver = Deriver::new(&ephemeral).unwrap();
+                deriver.set_peer(&peer).unwrap();
+                let z = deriver.derive_to_vec().unwrap();
+                drop(deriver);
+                let kek = concat_kdf(&z, alg, b"", b"");
+
+                let mut encrypted_key = vec![0; cek.len() + 8];
+                wrap_key(
+                    &AesKey::new_encrypt(&kek).unwrap(),
+                    None,
+                    &mut encrypted_key,
+                    &cek,
+                )
+                .unwrap();
+
+                let epk = TeeKey {
+                    algorithm: KeyWrapAlgorithm::EcdhEsA256kw,
+                    key: ephemeral,
+                };
+                let (epk_x, epk_y) = epk.ec_coordinates().unwrap();
+                let header = serde_json::json!({
+                    "alg": alg,
+                    "enc": "A256GCM",
+                    "epk": { "kty": "EC", "crv": "P-256", "x": encode(&epk_x), "y": encode(&epk_y) },
+                });
+                (header, encrypted_key)
+            }
+        };
+
+        let protected = encode(header.to_string().as_bytes());
+        let mut iv = [0u8; 12];
+        rand_bytes(&mut iv).unwrap();
+        let mut tag = [0u8; 16];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &cek,
+            Some(&iv),
+            protected.as_bytes(),
+            plaintext,
+            &mut tag,
+        )
+        .unwrap();
+
+        Response {
+            protected,
+            encrypted_key: encode(&encrypted_key),
+            iv: encode(&iv),
+            ciphertext: encode(&ciphertext),
+            tag: encode(&tag),
+        }
+    }
+
+    #[test]
+    fn test_unwrap_rsa_oaep() {
+        let key = TeeKey::generate(KeyWrapAlgorithm::RsaOaep).unwrap();
+        let pubkey = key.public_key().unwrap();
+        assert!(matches!(&pubkey, TeePubKey::RSA { alg, .. } if alg == "RSA-OAEP"));
+
+        let response = wrap(&pubkey, "RSA-OAEP", PLAINTEXT);
+        assert_eq!(key.unwrap(&response).unwrap(), PLAINTEXT);
+    }
+
+    #[test]
+    fn test_unwrap_ecdh_es_a256kw() {
+        let key = TeeKey::generate(KeyWrapAlgorithm::EcdhEsA256kw).unwrap();
+        let pubkey = key.public_key().unwrap();
+        assert!(matches!(&pubkey, TeePubKey::EC { alg, crv, .. }
+            if alg == "ECDH-ES+A256KW" && crv == "P-256"));
+
+        let response = wrap(&pubkey, "ECDH-ES+A256KW", PLAINTEXT);
+        assert_eq!(key.unwrap(&response).unwrap(), PLAINTEXT);
+    }
+
+    #[test]
+    fn test_unwrap_unadvertised_algorithm() {
+        let key = TeeKey::generate(KeyWrapAlgorithm::RsaOaep).unwrap();
+        let response = wrap(&key.public_key().unwrap(), "RSA1_5", PLAINTEXT);
+
+        assert!(matches!(
+            key.unwrap(&response),
+            Err(Error::UnsupportedWrapAlgorithm(alg)) if alg == "RSA1_5"
+        ));
+    }
+
+    #[test]
+    fn test_unwrap_tampered_ciphertext() {
+        let key = TeeKey::generate(KeyWrapAlgorithm::EcdhEsA256kw).unwrap();
+        let mut response = wrap(&key.public_key().unwrap(), "ECDH-ES+A256KW", PLAINTEXT);
+        let mut ciphertext = decode(&response.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        response.ciphertext = encode(&ciphertext);
+
+        assert!(matches!(key.unwrap(&response), Err(Error::Decrypt(_))));
+    }
+}
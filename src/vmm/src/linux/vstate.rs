@@ -11,14 +11,13 @@ use std::cell::Cell;
 use std::fmt::{Display, Formatter};
 use std::io;
 
-#[cfg(feature = "tee")]
-use std::os::unix::io::RawFd;
-
 use std::result;
 use std::sync::atomic::{fence, Ordering};
 #[cfg(not(test))]
 use std::sync::Barrier;
 use std::thread;
+#[cfg(target_arch = "x86_64")]
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::super::{FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK};
 
@@ -28,25 +27,35 @@ use super::tee::amdsev::{AmdSev, Error as SevError};
 #[cfg(feature = "amd-sev")]
 use super::tee::amdsnp::{AmdSnp, Error as SnpError};
 
+#[cfg(feature = "amd-sev")]
+use super::tee::{LaunchMeasurement, LaunchMetrics, TeeKind};
+
+#[cfg(feature = "tee")]
+use super::tee::{ConfidentialVm, Error as TeeError};
+
 #[cfg(feature = "tee")]
 use kbs_types::Tee;
 
+use crate::metrics::METRICS;
 #[cfg(feature = "tee")]
 use crate::resources::TeeConfig;
-use crate::vmm_config::machine_config::CpuFeaturesTemplate;
+use crate::seccomp::SeccompFilter;
+use crate::vmm_config::machine_config::{CpuFeaturesTemplate, CpuTopology};
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::GICDevice;
 #[cfg(target_arch = "x86_64")]
-use cpuid::{c3, filter_cpuid, t2, VmSpec};
+use cpuid::{c3, filter_cpuid, t2, x86_64_v2, x86_64_v3, VmSpec};
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
     kvm_clock_data, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_pit_config,
     kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, MsrList,
-    Msrs, KVM_CLOCK_TSC_STABLE, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+    Msrs, KVM_CLOCK_REALTIME, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
     KVM_MAX_CPUID_ENTRIES, KVM_PIT_SPEAKER_DUMMY,
 };
 use kvm_bindings::{kvm_userspace_memory_region, KVM_API_VERSION};
 use kvm_ioctls::*;
+#[cfg(feature = "amd-sev")]
+use utils::cancel::CancelToken;
 use utils::eventfd::EventFd;
 use utils::signal::{register_signal_handler, sigrtmin, Killable};
 use utils::sm::StateMachine;
@@ -54,12 +63,6 @@ use vm_memory::{
     Address, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
 };
 
-#[cfg(feature = "amd-sev")]
-use sev::launch::sev as sev_launch;
-
-#[cfg(feature = "amd-sev")]
-use sev::launch::snp;
-
 /// Signal number (SIGRTMIN) used to kick Vcpus.
 pub(crate) const VCPU_RTSIG_OFFSET: i32 = 0;
 
@@ -85,9 +88,6 @@ pub enum Error {
     KvmApiVersion(i32),
     /// Cannot initialize the KVM context due to missing capabilities.
     KvmCap(kvm_ioctls::Cap),
-    #[cfg(feature = "amd-sev")]
-    /// Cannot read the CPUID entries from KVM.
-    KvmCpuId(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
     /// Cannot set the local interruption due to bad configuration.
     LocalIntConfiguration(arch::x86_64::interrupts::Error),
@@ -108,6 +108,10 @@ pub enum Error {
     #[cfg(target_arch = "aarch64")]
     /// Error setting up the global interrupt controller.
     SetupGIC(arch::aarch64::gic::Error),
+    /// Cannot install the seccomp filter on a vcpu thread.
+    SeccompFilter(crate::seccomp::Error),
+    /// Cannot pin a vcpu thread to its host CPUs.
+    SetAffinity(io::Error),
     /// Cannot set the memory regions.
     SetUserMemoryRegion(kvm_ioctls::Error),
     /// Error creating memory map for SHM region.
@@ -116,20 +120,20 @@ pub enum Error {
     /// Error initializing the Secure Virtualization Backend (SEV).
     SevSecVirtInit(SevError),
     #[cfg(feature = "amd-sev")]
-    /// Error preparing the VM for Secure Virtualization (SEV).
-    SevSecVirtPrepare(SevError),
-    #[cfg(feature = "amd-sev")]
-    /// Error attesting the Secure VM (SEV).
-    SevSecVirtAttest(SevError),
-    #[cfg(feature = "amd-sev")]
     /// Error initializing the Secure Virtualization Backend (SNP).
     SnpSecVirtInit(SnpError),
-    #[cfg(feature = "amd-sev")]
-    /// Error preparing the VM for Secure Virtualization (SNP).
-    SnpSecVirtPrepare(SnpError),
-    #[cfg(feature = "amd-sev")]
-    /// Error attesting the Secure VM (SNP).
-    SnpSecVirtAttest(SnpError),
+    #[cfg(feature = "tee")]
+    /// Error preparing the VM for Secure Virtualization.
+    SecVirtPrepare(TeeError),
+    #[cfg(feature = "tee")]
+    /// Error attesting the Secure VM.
+    SecVirtAttest(TeeError),
+    #[cfg(feature = "tee")]
+    /// Error retrieving the attestation evidence of the Secure VM.
+    SecVirtEvidence(TeeError),
+    #[cfg(feature = "tee")]
+    /// Error accessing the memory of the Secure VM in clear.
+    SecVirtDebug(TeeError),
     #[cfg(feature = "tee")]
     /// The TEE specified is not supported.
     InvalidTee,
@@ -167,6 +171,9 @@ pub enum Error {
     /// Failed to get KVM vcpu sregs.
     VcpuGetSregs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
+    /// Failed to get the TSC frequency of the KVM vcpu.
+    VcpuGetTscKhz(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
     /// Failed to get KVM vcpu event.
     VcpuGetVcpuEvents(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -175,6 +182,9 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     /// Failed to get KVM vcpu xsave.
     VcpuGetXsave(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// The state the vcpu booted with wasn't saved, so it can't be reset.
+    VcpuNoBootState,
     /// Cannot run the VCPUs.
     VcpuRun(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -199,6 +209,9 @@ pub enum Error {
     /// Failed to set KVM vcpu sregs.
     VcpuSetSregs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
+    /// Failed to set the TSC frequency of the KVM vcpu.
+    VcpuSetTscKhz(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
     /// Failed to set KVM vcpu event.
     VcpuSetVcpuEvents(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -254,8 +267,6 @@ impl Display for Error {
                 write!(f, "The host kernel reports an invalid KVM API version: {v}")
             }
             KvmCap(cap) => write!(f, "Missing KVM capabilities: {cap:?}"),
-            #[cfg(feature = "amd-sev")]
-            KvmCpuId(e) => write!(f, "Cannot read CPUID entries from KVM: {e}"),
             VcpuCountNotInitialized => write!(f, "vCPU count is not initialized"),
             VmFd(e) => write!(f, "Cannot open the VM file descriptor: {e}"),
             VcpuFd(e) => write!(f, "Cannot open the VCPU file descriptor: {e}"),
@@ -280,28 +291,33 @@ impl Display for Error {
                 )
             }
             #[cfg(feature = "tee")]
-            SevSecVirtPrepare(e) => write!(
+            SnpSecVirtInit(e) => write!(
                 f,
-                "Error preparing the VM for Secure Virtualization (SEV): {e:?}"
+                "Error initializing the Secure Virtualization Backend (SNP): {e:?}"
             ),
+
             #[cfg(feature = "tee")]
-            SevSecVirtAttest(e) => write!(f, "Error attesting the Secure VM (SEV): {e:?}"),
+            SecVirtPrepare(e) => {
+                write!(f, "Error preparing the VM for Secure Virtualization: {e:?}")
+            }
 
             #[cfg(feature = "tee")]
-            SnpSecVirtInit(e) => write!(
-                f,
-                "Error initializing the Secure Virtualization Backend (SEV): {e:?}"
-            ),
+            SecVirtAttest(e) => write!(f, "Error attesting the Secure VM: {e:?}"),
 
             #[cfg(feature = "tee")]
-            SnpSecVirtPrepare(e) => write!(
+            SecVirtEvidence(e) => write!(
                 f,
-                "Error preparing the VM for Secure Virtualization (SNP): {e:?}"
+                "Error retrieving the attestation evidence of the Secure VM: {e:?}"
             ),
 
             #[cfg(feature = "tee")]
-            SnpSecVirtAttest(e) => write!(f, "Error attesting the Secure VM (SNP): {e:?}"),
+            SecVirtDebug(e) => write!(
+                f,
+                "Error accessing the memory of the Secure VM in clear: {e:?}"
+            ),
 
+            SeccompFilter(e) => write!(f, "Cannot install the vcpu seccomp filter: {e}"),
+            SetAffinity(e) => write!(f, "Cannot set the CPU affinity of the vcpu thread: {e}"),
             SignalVcpu(e) => write!(f, "Failed to signal Vcpu: {e}"),
             #[cfg(feature = "tee")]
             MissingTeeConfig => write!(f, "Missing TEE configuration"),
@@ -337,12 +353,16 @@ impl Display for Error {
             #[cfg(target_arch = "x86_64")]
             VcpuGetSregs(e) => write!(f, "Failed to get KVM vcpu sregs: {e}"),
             #[cfg(target_arch = "x86_64")]
+            VcpuGetTscKhz(e) => write!(f, "Failed to get KVM vcpu TSC frequency: {e}"),
+            #[cfg(target_arch = "x86_64")]
             VcpuGetVcpuEvents(e) => write!(f, "Failed to get KVM vcpu event: {e}"),
             #[cfg(target_arch = "x86_64")]
             VcpuGetXcrs(e) => write!(f, "Failed to get KVM vcpu xcrs: {e}"),
             #[cfg(target_arch = "x86_64")]
             VcpuGetXsave(e) => write!(f, "Failed to get KVM vcpu xsave: {e}"),
             #[cfg(target_arch = "x86_64")]
+            VcpuNoBootState => write!(f, "No boot state to reset the vcpu to"),
+            #[cfg(target_arch = "x86_64")]
             VcpuSetCpuid(e) => write!(f, "Failed to set KVM vcpu cpuid: {e}"),
             #[cfg(target_arch = "x86_64")]
             VcpuSetDebugRegs(e) => write!(f, "Failed to set KVM vcpu debug regs: {e}"),
@@ -357,6 +377,8 @@ impl Display for Error {
             #[cfg(target_arch = "x86_64")]
             VcpuSetSregs(e) => write!(f, "Failed to set KVM vcpu sregs: {e}"),
             #[cfg(target_arch = "x86_64")]
+            VcpuSetTscKhz(e) => write!(f, "Failed to set KVM vcpu TSC frequency: {e}"),
+            #[cfg(target_arch = "x86_64")]
             VcpuSetVcpuEvents(e) => write!(f, "Failed to set KVM vcpu event: {e}"),
             #[cfg(target_arch = "x86_64")]
             VcpuSetXcrs(e) => write!(f, "Failed to set KVM vcpu xcrs: {e}"),
@@ -469,10 +491,7 @@ pub struct Vm {
     irqchip_handle: Option<Box<dyn GICDevice>>,
 
     #[cfg(feature = "amd-sev")]
-    sev: Option<AmdSev>,
-
-    #[cfg(feature = "amd-sev")]
-    snp: Option<AmdSnp>,
+    confidential_vm: Box<dyn ConfidentialVm>,
 
     #[cfg(feature = "amd-sev")]
     pub tee: Tee,
@@ -506,7 +525,7 @@ impl Vm {
     }
 
     #[cfg(feature = "amd-sev")]
-    pub fn new(kvm: &Kvm, tee_config: &TeeConfig) -> Result<Self> {
+    pub fn new(kvm: &Kvm, tee_config: &TeeConfig, cancel: &CancelToken) -> Result<Self> {
         //create fd for interacting with kvm-vm specific functions
         let vm_fd = kvm.create_vm().map_err(Error::VmFd)?;
 
@@ -517,22 +536,19 @@ impl Vm {
         let supported_msrs =
             arch::x86_64::msr::supported_guest_msrs(kvm).map_err(Error::GuestMSRs)?;
 
-        let (sev, snp) = match tee_config.tee {
-            Tee::Sev => (
-                Some(AmdSev::new(tee_config).map_err(Error::SevSecVirtInit)?),
-                None,
-            ),
-            Tee::Snp => (None, Some(AmdSnp::new().map_err(Error::SnpSecVirtInit)?)),
+        let confidential_vm: Box<dyn ConfidentialVm> = match tee_config.tee {
+            Tee::Sev => Box::new(AmdSev::new(tee_config, cancel).map_err(Error::SevSecVirtInit)?),
+            Tee::Snp => Box::new(AmdSnp::new(tee_config, cancel).map_err(Error::SnpSecVirtInit)?),
             _ => return Err(Error::InvalidTee),
         };
+        info!("Confidential guest mode: {}", confidential_vm.tee_kind());
 
         Ok(Vm {
             fd: vm_fd,
             next_mem_slot: 0,
             supported_cpuid,
             supported_msrs,
-            sev,
-            snp,
+            confidential_vm,
             tee: tee_config.tee,
         })
     }
@@ -588,60 +604,93 @@ impl Vm {
     }
 
     #[cfg(feature = "amd-sev")]
-    pub fn sev_secure_virt_prepare(
-        &mut self,
-        guest_mem: &GuestMemoryMmap,
-    ) -> Result<sev_launch::Launcher<sev_launch::Started, RawFd, RawFd>> {
-        match &self.sev {
-            Some(s) => s
-                .vm_prepare(&self.fd, guest_mem)
-                .map_err(Error::SevSecVirtPrepare),
-            None => Err(Error::InvalidTee),
-        }
+    pub fn secure_virt_prepare(&mut self, guest_mem: &GuestMemoryMmap) -> Result<()> {
+        self.confidential_vm
+            .vm_prepare(&self.fd, guest_mem)
+            .map_err(Error::SecVirtPrepare)
     }
 
+    /// Completes the launch of the Secure VM. The CPUID features it's measured
+    /// with, for SEV-SNP, are masked with `cpu_template` like the vCPUs' are.
     #[cfg(feature = "amd-sev")]
-    pub fn sev_secure_virt_attest(
-        &self,
+    pub fn secure_virt_attest(
+        &mut self,
         guest_mem: &GuestMemoryMmap,
         measured_regions: Vec<MeasuredRegion>,
-        launcher: sev_launch::Launcher<sev_launch::Started, RawFd, RawFd>,
+        cpu_template: Option<CpuFeaturesTemplate>,
     ) -> Result<()> {
-        match &self.sev {
-            Some(s) => s
-                .vm_attest(&self.fd, guest_mem, measured_regions, launcher)
-                .map_err(Error::SevSecVirtAttest),
-            None => Err(Error::InvalidTee),
+        let mut cpuid = self.supported_cpuid.clone();
+        if let Some(template) = cpu_template {
+            let vm_spec = VmSpec::new(0, 1, false).map_err(Error::CpuId)?;
+            apply_cpu_template(&mut cpuid, template, &vm_spec)?;
         }
+
+        self.confidential_vm
+            .vm_attest(&self.fd, guest_mem, &cpuid, measured_regions)
+            .map_err(Error::SecVirtAttest)
+    }
+
+    /// Returns the attestation evidence of the Secure VM as a JSON document.
+    #[cfg(feature = "amd-sev")]
+    pub fn attestation_evidence(&self) -> Result<String> {
+        self.confidential_vm
+            .attestation_evidence()
+            .map_err(Error::SecVirtEvidence)
+    }
+
+    /// Returns the launch measurement of the Secure VM.
+    #[cfg(feature = "amd-sev")]
+    pub fn launch_measurement(&self) -> Result<LaunchMeasurement> {
+        self.confidential_vm
+            .launch_measurement()
+            .map_err(Error::SecVirtEvidence)
+    }
+
+    /// Returns the timing of the Secure VM launch phases.
+    #[cfg(feature = "amd-sev")]
+    pub fn launch_metrics(&self) -> LaunchMetrics {
+        self.confidential_vm.launch_metrics()
+    }
+
+    /// The confidentiality mode in effect for the Secure VM.
+    #[cfg(feature = "amd-sev")]
+    pub fn tee_kind(&self) -> TeeKind {
+        self.confidential_vm.tee_kind()
+    }
+
+    /// Whether the vCPU registers of the Secure VM are encrypted, as with
+    /// SEV-ES and SNP.
+    #[cfg(feature = "amd-sev")]
+    pub fn encrypted_state(&self) -> bool {
+        self.confidential_vm.encrypted_state()
     }
 
+    /// Reads the memory of the Secure VM in clear, if its launch allowed
+    /// debugging.
     #[cfg(feature = "amd-sev")]
-    pub fn snp_secure_virt_prepare(
+    pub fn debug_decrypt(
         &self,
         guest_mem: &GuestMemoryMmap,
-    ) -> Result<snp::Launcher<snp::Started, RawFd, RawFd>> {
-        match &self.snp {
-            Some(s) => s
-                .vm_prepare(&self.fd, guest_mem)
-                .map_err(Error::SnpSecVirtPrepare),
-            None => Err(Error::InvalidTee),
-        }
+        guest_addr: GuestAddress,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        self.confidential_vm
+            .debug_decrypt(&self.fd, guest_mem, guest_addr, buf)
+            .map_err(Error::SecVirtDebug)
     }
 
+    /// Writes to the memory of the Secure VM in clear, if its launch allowed
+    /// debugging.
     #[cfg(feature = "amd-sev")]
-    pub fn snp_secure_virt_attest(
+    pub fn debug_encrypt(
         &self,
-        cpuid: CpuId,
         guest_mem: &GuestMemoryMmap,
-        measured_regions: Vec<MeasuredRegion>,
-        launcher: snp::Launcher<snp::Started, RawFd, RawFd>,
+        guest_addr: GuestAddress,
+        buf: &[u8],
     ) -> Result<()> {
-        match &self.snp {
-            Some(s) => s
-                .vm_measure(cpuid, guest_mem, measured_regions, launcher)
-                .map_err(Error::SnpSecVirtAttest),
-            None => Err(Error::InvalidTee),
-        }
+        self.confidential_vm
+            .debug_encrypt(&self.fd, guest_mem, guest_addr, buf)
+            .map_err(Error::SecVirtDebug)
     }
 
     /// Creates the irq chip and an in-kernel device model for the PIT.
@@ -678,15 +727,21 @@ impl Vm {
         &self.fd
     }
 
-    #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
     /// Saves and returns the Kvm Vm state.
     pub fn save_state(&self) -> Result<VmState> {
         let pitstate = self.fd.get_pit2().map_err(Error::VmGetPit2)?;
 
         let mut clock = self.fd.get_clock().map_err(Error::VmGetClock)?;
-        // This bit is not accepted in SET_CLOCK, clear it.
-        clock.flags &= !KVM_CLOCK_TSC_STABLE;
+        // Older kernels don't report the host time the clock was read at,
+        // which restore_state needs to compensate for the time elapsed since.
+        if clock.flags & KVM_CLOCK_REALTIME == 0 {
+            clock.realtime = realtime_ns();
+        }
+        // Only the clock value is restored, as SET_CLOCK doesn't accept
+        // KVM_CLOCK_TSC_STABLE and would otherwise apply KVM_CLOCK_REALTIME
+        // itself.
+        clock.flags = 0;
 
         let mut pic_master = kvm_irqchip {
             chip_id: KVM_IRQCHIP_PIC_MASTER,
@@ -728,7 +783,11 @@ impl Vm {
         self.fd
             .set_pit2(&state.pitstate)
             .map_err(Error::VmSetPit2)?;
-        self.fd.set_clock(&state.clock).map_err(Error::VmSetClock)?;
+        // Advance the clock by the host time elapsed since it was saved, so
+        // the guest clock stays monotonic and in step with the host's.
+        let mut clock = state.clock;
+        clock.clock += realtime_ns().saturating_sub(clock.realtime);
+        self.fd.set_clock(&clock).map_err(Error::VmSetClock)?;
         self.fd
             .set_irqchip(&state.pic_master)
             .map_err(Error::VmSetIrqChip)?;
@@ -742,7 +801,6 @@ impl Vm {
     }
 }
 
-#[allow(unused)]
 #[cfg(target_arch = "x86_64")]
 /// Structure holding VM kvm state.
 pub struct VmState {
@@ -753,6 +811,30 @@ pub struct VmState {
     ioapic: kvm_irqchip,
 }
 
+/// Host CLOCK_REALTIME in nanoseconds, as KVM_GET_CLOCK reports it.
+#[cfg(target_arch = "x86_64")]
+fn realtime_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Masks the CPUID features left out of `template`.
+#[cfg(target_arch = "x86_64")]
+fn apply_cpu_template(
+    cpuid: &mut CpuId,
+    template: CpuFeaturesTemplate,
+    vm_spec: &VmSpec,
+) -> Result<()> {
+    match template {
+        CpuFeaturesTemplate::T2 => t2::set_cpuid_entries(cpuid, vm_spec),
+        CpuFeaturesTemplate::C3 => c3::set_cpuid_entries(cpuid, vm_spec),
+        CpuFeaturesTemplate::X86_64V2 => x86_64_v2::set_cpuid_entries(cpuid, vm_spec),
+        CpuFeaturesTemplate::X86_64V3 => x86_64_v3::set_cpuid_entries(cpuid, vm_spec),
+    }
+    .map_err(Error::CpuId)
+}
+
 /// Encapsulates configuration parameters for the guest vCPUS.
 #[derive(Debug, Eq, PartialEq)]
 pub struct VcpuConfig {
@@ -762,6 +844,16 @@ pub struct VcpuConfig {
     pub ht_enabled: bool,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Layout of the vCPUs in sockets, cores and threads, if not the one
+    /// implied by `ht_enabled`.
+    pub cpu_topology: Option<CpuTopology>,
+    /// Leave the vCPUs in their reset state, for a firmware to boot the guest
+    /// from the reset vector.
+    #[cfg(target_arch = "x86_64")]
+    pub firmware_boot: bool,
+    /// Frequency the guest TSC runs at, in kHz, if not the host's.
+    #[cfg(target_arch = "x86_64")]
+    pub tsc_khz: Option<u32>,
 }
 
 // Using this for easier explicit type-casting to help IDEs interpret the code.
@@ -772,6 +864,8 @@ pub struct Vcpu {
     fd: VcpuFd,
     id: u8,
     mmio_bus: Option<devices::Bus>,
+    seccomp_filter: Option<SeccompFilter>,
+    affinity: Option<Vec<usize>>,
     #[allow(dead_code)]
     #[cfg_attr(all(test, target_arch = "aarch64"), allow(unused))]
     exit_evt: EventFd,
@@ -782,6 +876,9 @@ pub struct Vcpu {
     cpuid: CpuId,
     #[cfg(target_arch = "x86_64")]
     msr_list: MsrList,
+    // What the vcpu is reset to when the guest reboots in place.
+    #[cfg(target_arch = "x86_64")]
+    boot_state: Option<VcpuState>,
 
     #[cfg(target_arch = "aarch64")]
     mpidr: u64,
@@ -907,10 +1004,13 @@ impl Vcpu {
             fd: kvm_vcpu,
             id,
             mmio_bus: None,
+            seccomp_filter: None,
+            affinity: None,
             exit_evt,
             io_bus,
             cpuid,
             msr_list,
+            boot_state: None,
             event_receiver,
             event_sender: Some(event_sender),
             response_receiver: Some(response_receiver),
@@ -936,6 +1036,8 @@ impl Vcpu {
             fd: kvm_vcpu,
             id,
             mmio_bus: None,
+            seccomp_filter: None,
+            affinity: None,
             exit_evt,
             mpidr: 0,
             event_receiver,
@@ -961,6 +1063,36 @@ impl Vcpu {
         self.mmio_bus = Some(mmio_bus);
     }
 
+    /// Sets the seccomp filter to install on this vcpu thread.
+    pub fn set_seccomp_filter(&mut self, seccomp_filter: SeccompFilter) {
+        self.seccomp_filter = Some(seccomp_filter);
+    }
+
+    /// Sets the host CPUs this vcpu thread is allowed to run on.
+    pub fn set_affinity(&mut self, cpus: Vec<usize>) {
+        self.affinity = Some(cpus);
+    }
+
+    fn apply_affinity(&self) -> Result<()> {
+        let Some(cpus) = &self.affinity else {
+            return Ok(());
+        };
+
+        // Safe because an all-zeroes cpu_set_t is an empty set.
+        let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in cpus {
+            // Safe because the CPU numbers are checked to fit in the set when configured.
+            unsafe { libc::CPU_SET(cpu, &mut cpu_set) };
+        }
+        // Safe because we pass a valid set along with its size.
+        let ret =
+            unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) };
+        if ret < 0 {
+            return Err(Error::SetAffinity(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[allow(unused_variables)]
     /// Configures a x86_64 specific vcpu and should be called once per vcpu.
@@ -976,8 +1108,16 @@ impl Vcpu {
         kernel_start_addr: GuestAddress,
         vcpu_config: &VcpuConfig,
     ) -> Result<()> {
-        let cpuid_vm_spec = VmSpec::new(self.id, vcpu_config.vcpu_count, vcpu_config.ht_enabled)
-            .map_err(Error::CpuId)?;
+        let cpuid_vm_spec = match vcpu_config.cpu_topology {
+            Some(topology) => VmSpec::with_topology(
+                self.id,
+                vcpu_config.vcpu_count,
+                topology.threads_per_core,
+                topology.cores_per_socket,
+            ),
+            None => VmSpec::new(self.id, vcpu_config.vcpu_count, vcpu_config.ht_enabled),
+        }
+        .map_err(Error::CpuId)?;
 
         filter_cpuid(&mut self.cpuid, &cpuid_vm_spec).map_err(|e| {
             error!("Failure in configuring CPUID for vcpu {}: {:?}", self.id, e);
@@ -985,26 +1125,25 @@ impl Vcpu {
         })?;
 
         if let Some(template) = vcpu_config.cpu_template {
-            match template {
-                CpuFeaturesTemplate::T2 => {
-                    t2::set_cpuid_entries(&mut self.cpuid, &cpuid_vm_spec).map_err(Error::CpuId)?
-                }
-                CpuFeaturesTemplate::C3 => {
-                    c3::set_cpuid_entries(&mut self.cpuid, &cpuid_vm_spec).map_err(Error::CpuId)?
-                }
-            }
+            apply_cpu_template(&mut self.cpuid, template, &cpuid_vm_spec)?;
         }
 
         self.fd
             .set_cpuid2(&self.cpuid)
             .map_err(Error::VcpuSetCpuid)?;
 
+        if let Some(tsc_khz) = vcpu_config.tsc_khz {
+            self.fd.set_tsc_khz(tsc_khz).map_err(Error::VcpuSetTscKhz)?;
+        }
+
         arch::x86_64::msr::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
-        arch::x86_64::regs::setup_regs(&self.fd, kernel_start_addr.raw_value(), self.id)
-            .map_err(Error::REGSConfiguration)?;
         arch::x86_64::regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
-        arch::x86_64::regs::setup_sregs(guest_mem, &self.fd, self.id)
-            .map_err(Error::SREGSConfiguration)?;
+        if !vcpu_config.firmware_boot {
+            arch::x86_64::regs::setup_regs(&self.fd, kernel_start_addr.raw_value(), self.id)
+                .map_err(Error::REGSConfiguration)?;
+            arch::x86_64::regs::setup_sregs(guest_mem, &self.fd, self.id)
+                .map_err(Error::SREGSConfiguration)?;
+        }
         arch::x86_64::interrupts::set_lint(&self.fd).map_err(Error::LocalIntConfiguration)?;
         Ok(())
     }
@@ -1057,9 +1196,20 @@ impl Vcpu {
                 self.init_thread_local_data()
                     .expect("Cannot cleanly initialize vcpu TLS.");
 
+                // The affinity is set first, as the seccomp filter doesn't allow it.
+                let filter_result =
+                    self.apply_affinity()
+                        .and_then(|()| match self.seccomp_filter.take() {
+                            Some(filter) => filter.apply().map_err(Error::SeccompFilter),
+                            None => Ok(()),
+                        });
+                let filtered = filter_result.is_ok();
                 init_tls_sender
-                    .send(true)
+                    .send(filter_result)
                     .expect("Cannot notify vcpu TLS initialization.");
+                if !filtered {
+                    return;
+                }
 
                 self.run();
             })
@@ -1067,7 +1217,7 @@ impl Vcpu {
 
         init_tls_receiver
             .recv()
-            .expect("Error waiting for TLS initialization.");
+            .expect("Error waiting for TLS initialization.")?;
 
         Ok(VcpuHandle::new(
             event_sender,
@@ -1076,7 +1226,6 @@ impl Vcpu {
         ))
     }
 
-    #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
     fn save_state(&self) -> Result<VcpuState> {
         /*
@@ -1120,6 +1269,7 @@ impl Vcpu {
         let xcrs = self.fd.get_xcrs().map_err(Error::VcpuGetXcrs)?;
         let debug_regs = self.fd.get_debug_regs().map_err(Error::VcpuGetDebugRegs)?;
         let lapic = self.fd.get_lapic().map_err(Error::VcpuGetLapic)?;
+        let tsc_khz = self.fd.get_tsc_khz().map_err(Error::VcpuGetTscKhz)?;
         let nmsrs = self.fd.get_msrs(&mut msrs).map_err(Error::VcpuGetMsrs)?;
         assert_eq!(nmsrs, num_msrs);
         let vcpu_events = self
@@ -1134,15 +1284,46 @@ impl Vcpu {
             mp_state,
             regs,
             sregs,
+            tsc_khz,
             vcpu_events,
             xcrs,
             xsave,
         })
     }
 
-    #[allow(unused)]
+    /// Keeps the state of the configured vcpu, so it can be reset to it when
+    /// the guest reboots in place. Must be called before the vcpu runs.
+    #[cfg(target_arch = "x86_64")]
+    pub fn save_boot_state(&mut self) -> Result<()> {
+        self.boot_state = Some(self.save_state()?);
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
-    fn restore_state(&self, state: VcpuState) -> Result<()> {
+    fn reset_to_boot_state(&self) -> Result<()> {
+        let state = self.boot_state.as_ref().ok_or(Error::VcpuNoBootState)?;
+        self.restore_state(state)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_regs(&self) -> Result<VcpuRegs> {
+        Ok(VcpuRegs {
+            regs: self.fd.get_regs().map_err(Error::VcpuGetRegs)?,
+            sregs: self.fd.get_sregs().map_err(Error::VcpuGetSregs)?,
+        })
+    }
+
+    #[cfg(all(target_arch = "x86_64", debug_assertions))]
+    fn set_regs(&self, regs: &VcpuRegs) -> Result<()> {
+        // SREGS first, as SET_REGS depends on the mode it sets.
+        self.fd
+            .set_sregs(&regs.sregs)
+            .map_err(Error::VcpuSetSregs)?;
+        self.fd.set_regs(&regs.regs).map_err(Error::VcpuSetRegs)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn restore_state(&self, state: &VcpuState) -> Result<()> {
         /*
          * Ordering requirements:
          *
@@ -1164,10 +1345,21 @@ impl Vcpu {
          *
          * SET_LAPIC must come before SET_MSRS, because the TSC deadline MSR
          * only restores successfully, when the LAPIC is correctly configured.
+         *
+         * SET_TSC_KHZ must come before SET_MSRS, as the TSC MSR is scaled to
+         * the frequency set at the time.
          */
         self.fd
             .set_cpuid2(&state.cpuid)
             .map_err(Error::VcpuSetCpuid)?;
+        // Keep the frequency the guest calibrated against, which may not be
+        // the one of this host. Scaling it needs KVM_CAP_TSC_CONTROL, so
+        // don't set it when it wouldn't change.
+        if state.tsc_khz != self.fd.get_tsc_khz().map_err(Error::VcpuGetTscKhz)? {
+            self.fd
+                .set_tsc_khz(state.tsc_khz)
+                .map_err(Error::VcpuSetTscKhz)?;
+        }
         self.fd
             .set_mp_state(state.mp_state)
             .map_err(Error::VcpuSetMpState)?;
@@ -1196,49 +1388,67 @@ impl Vcpu {
     ///
     /// Returns error or enum specifying whether emulation was handled or interrupted.
     fn run_emulation(&mut self) -> Result<VcpuEmulation> {
+        let exits = &METRICS.vcpu_exits;
         match self.fd.run() {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoIn(addr, data) => {
+                    exits.io_in.inc();
                     self.io_bus.read(0, u64::from(addr), data);
                     Ok(VcpuEmulation::Handled)
                 }
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoOut(addr, data) => {
+                    exits.io_out.inc();
                     self.io_bus.write(0, u64::from(addr), data);
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioRead(addr, data) => {
+                    exits.mmio_read.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.read(0, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    exits.mmio_write.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.write(0, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::Hlt => {
+                    exits.halt.inc();
                     info!("Received KVM_EXIT_HLT signal");
                     Ok(VcpuEmulation::Stopped)
                 }
+                // On x86_64 the guest only gets here through a triple fault.
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Shutdown => {
+                    exits.shutdown.inc();
+                    info!("Received KVM_EXIT_SHUTDOWN signal");
+                    Ok(VcpuEmulation::TripleFault)
+                }
+                #[cfg(not(target_arch = "x86_64"))]
                 VcpuExit::Shutdown => {
+                    exits.shutdown.inc();
                     info!("Received KVM_EXIT_SHUTDOWN signal");
                     Ok(VcpuEmulation::Stopped)
                 }
                 // Documentation specifies that below kvm exits are considered
                 // errors.
                 VcpuExit::FailEntry(reason, vcpu) => {
+                    exits.other.inc();
                     error!("Received KVM_EXIT_FAIL_ENTRY signal: reason={reason}, vcpu={vcpu}");
                     Err(Error::VcpuUnhandledKvmExit)
                 }
                 VcpuExit::InternalError => {
+                    exits.other.inc();
                     error!("Received KVM_EXIT_INTERNAL_ERROR signal");
                     Err(Error::VcpuUnhandledKvmExit)
                 }
                 r => {
+                    exits.other.inc();
                     // TODO: Are we sure we want to finish running a vcpu upon
                     // receiving a vm exit that is not necessarily an error?
                     error!("Unexpected exit reason on vcpu run: {:?}", r);
@@ -1251,6 +1461,7 @@ impl Vcpu {
                 match e.errno() {
                     libc::EAGAIN => Ok(VcpuEmulation::Handled),
                     libc::EINTR => {
+                        exits.interrupted.inc();
                         self.fd.set_kvm_immediate_exit(0);
                         // Notify that this KVM_RUN was interrupted.
                         Ok(VcpuEmulation::Interrupted)
@@ -1292,7 +1503,14 @@ impl Vcpu {
                 // seccomp failure because musl calls `sigprocmask` as part of `pthread_exit`.
                 // So we pause vCPU0 and send a signal to the emulation thread to stop the VMM.
                 Ok(VcpuEmulation::Stopped) => return self.exit(FC_EXIT_CODE_OK),
+                // A triple fault stops the guest like a reboot, but the VMM may want to dump it
+                // first.
+                #[cfg(target_arch = "x86_64")]
+                Ok(VcpuEmulation::TripleFault) => return self.crash(FC_EXIT_CODE_OK),
                 // Emulation errors lead to vCPU exit.
+                #[cfg(target_arch = "x86_64")]
+                Err(_) => return self.crash(FC_EXIT_CODE_GENERIC_ERROR),
+                #[cfg(not(target_arch = "x86_64"))]
                 Err(_) => return self.exit(FC_EXIT_CODE_GENERIC_ERROR),
             }
         }
@@ -1320,6 +1538,12 @@ impl Vcpu {
                     .send(VcpuResponse::Resumed)
                     .expect("failed to send resume status");
             }
+            // Only a paused vCPU can be reset or inspected, dropping the
+            // channel reports the failure.
+            #[cfg(target_arch = "x86_64")]
+            Ok(VcpuEvent::Reset(_)) | Ok(VcpuEvent::GetRegs(_)) => (),
+            #[cfg(all(target_arch = "x86_64", debug_assertions))]
+            Ok(VcpuEvent::SetRegs(..)) => (),
             // Unhandled exit of the other end.
             Err(TryRecvError::Disconnected) => {
                 // Move to 'exited' state.
@@ -1335,6 +1559,13 @@ impl Vcpu {
     // This is the main loop of the `Paused` state.
     fn paused(&mut self) -> StateMachine<Self> {
         match self.event_receiver.recv() {
+            // A crashed vCPU is already paused when the VMM pauses the others.
+            Ok(VcpuEvent::Pause) => {
+                self.response_sender
+                    .send(VcpuResponse::Paused)
+                    .expect("failed to send pause status");
+                StateMachine::next(Self::paused)
+            }
             // Paused ---- Resume ----> Running
             Ok(VcpuEvent::Resume) => {
                 // Nothing special to do.
@@ -1344,7 +1575,29 @@ impl Vcpu {
                 // Move to 'running' state.
                 StateMachine::next(Self::running)
             }
+            #[cfg(target_arch = "x86_64")]
+            Ok(VcpuEvent::Reset(result_sender)) => {
+                if result_sender.send(self.reset_to_boot_state()).is_err() {
+                    error!("Failed to send the vCPU reset result");
+                }
+                StateMachine::next(Self::paused)
+            }
+            #[cfg(target_arch = "x86_64")]
+            Ok(VcpuEvent::GetRegs(regs_sender)) => {
+                if regs_sender.send(self.get_regs()).is_err() {
+                    error!("Failed to send the vCPU registers");
+                }
+                StateMachine::next(Self::paused)
+            }
+            #[cfg(all(target_arch = "x86_64", debug_assertions))]
+            Ok(VcpuEvent::SetRegs(regs, result_sender)) => {
+                if result_sender.send(self.set_regs(&regs)).is_err() {
+                    error!("Failed to send the vCPU registers update result");
+                }
+                StateMachine::next(Self::paused)
+            }
             // All other events have no effect on current 'paused' state.
+            #[cfg(not(target_arch = "x86_64"))]
             Ok(_) => StateMachine::next(Self::paused),
             // Unhandled exit of the other end.
             Err(_) => {
@@ -1369,6 +1622,21 @@ impl Vcpu {
         StateMachine::next(Self::exited)
     }
 
+    #[cfg(all(target_arch = "x86_64", not(test)))]
+    // Transition to the paused state, keeping the vCPU state around so the VMM can dump it
+    // before stopping.
+    fn crash(&mut self, exit_code: u8) -> StateMachine<Self> {
+        self.response_sender
+            .send(VcpuResponse::Crashed(exit_code))
+            .expect("failed to send Crashed status");
+
+        if let Err(e) = self.exit_evt.write(1) {
+            error!("Failed signaling vcpu exit event: {}", e);
+        }
+
+        StateMachine::next(Self::paused)
+    }
+
     #[cfg(not(test))]
     // This is the main loop of the `Exited` state.
     fn exited(&mut self) -> StateMachine<Self> {
@@ -1388,6 +1656,11 @@ impl Vcpu {
         // State machine reached its end.
         StateMachine::finish()
     }
+
+    #[cfg(all(target_arch = "x86_64", test))]
+    fn crash(&mut self, _: u8) -> StateMachine<Self> {
+        StateMachine::finish()
+    }
 }
 
 impl Drop for Vcpu {
@@ -1406,11 +1679,22 @@ pub struct VcpuState {
     mp_state: kvm_mp_state,
     regs: kvm_regs,
     sregs: kvm_sregs,
+    tsc_khz: u32,
     vcpu_events: kvm_vcpu_events,
     xcrs: kvm_xcrs,
     xsave: kvm_xsave,
 }
 
+#[cfg(target_arch = "x86_64")]
+/// General-purpose, segment and control registers of a VCPU.
+#[derive(Clone, Debug, Default)]
+pub struct VcpuRegs {
+    /// General-purpose registers, along with rip and rflags.
+    pub regs: kvm_regs,
+    /// Segment, descriptor table and control registers.
+    pub sregs: kvm_sregs,
+}
+
 // Allow currently unused Pause and Exit events. These will be used by the vmm later on.
 #[allow(unused)]
 #[derive(Debug)]
@@ -1421,6 +1705,15 @@ pub enum VcpuEvent {
     /// Event that should resume the Vcpu.
     Resume,
     // Serialize and Deserialize to follow after we get the support from kvm-ioctls.
+    /// Reset the paused Vcpu to the state it booted with, for the guest to reboot.
+    #[cfg(target_arch = "x86_64")]
+    Reset(Sender<Result<()>>),
+    /// Read the registers of the paused Vcpu and send them through the channel.
+    #[cfg(target_arch = "x86_64")]
+    GetRegs(Sender<Result<VcpuRegs>>),
+    /// Overwrite the registers of the paused Vcpu, to debug the guest.
+    #[cfg(all(target_arch = "x86_64", debug_assertions))]
+    SetRegs(Box<VcpuRegs>, Sender<Result<()>>),
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -1432,6 +1725,9 @@ pub enum VcpuResponse {
     Resumed,
     /// Vcpu is stopped.
     Exited(u8),
+    /// Vcpu hit a triple fault or a fatal KVM exit, and is paused so its state can be saved.
+    #[cfg(target_arch = "x86_64")]
+    Crashed(u8),
 }
 
 /// Wrapper over Vcpu that hides the underlying interactions with the Vcpu thread.
@@ -1480,9 +1776,12 @@ enum VcpuEmulation {
     Handled,
     Interrupted,
     Stopped,
+    #[cfg(target_arch = "x86_64")]
+    TripleFault,
 }
 
-#[cfg(test)]
+// These tests build plain VMs, which can't be created with a TEE enabled.
+#[cfg(all(test, not(feature = "tee")))]
 mod tests {
     use crossbeam_channel::unbounded;
     use std::sync::{Arc, Barrier};
@@ -1631,6 +1930,9 @@ mod tests {
             vcpu_count: 1,
             ht_enabled: false,
             cpu_template: None,
+            cpu_topology: None,
+            firmware_boot: false,
+            tsc_khz: None,
         };
 
         assert!(vcpu
@@ -1648,6 +1950,124 @@ mod tests {
         assert!(vcpu
             .configure_x86_64(&vm_mem, GuestAddress(0), &vcpu_config)
             .is_ok());
+
+        // Test configure while using the x86-64 microarchitecture levels.
+        vcpu_config.cpu_template = Some(CpuFeaturesTemplate::X86_64V3);
+        assert!(vcpu
+            .configure_x86_64(&vm_mem, GuestAddress(0), &vcpu_config)
+            .is_ok());
+        vcpu_config.cpu_template = Some(CpuFeaturesTemplate::X86_64V2);
+        assert!(vcpu
+            .configure_x86_64(&vm_mem, GuestAddress(0), &vcpu_config)
+            .is_ok());
+        let leaf_0x1 = vcpu
+            .cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 1)
+            .unwrap();
+        // AVX isn't part of x86-64-v2.
+        assert_eq!(leaf_0x1.ecx & (1 << 28), 0);
+
+        // The registers are left alone when booting a firmware.
+        let (_vm, mut vcpu, vm_mem) = setup_vcpu(0x10000);
+        vcpu_config.firmware_boot = true;
+        assert!(vcpu
+            .configure_x86_64(&vm_mem, GuestAddress(0x100_0000), &vcpu_config)
+            .is_ok());
+        assert_eq!(vcpu.fd.get_regs().unwrap().rip, arch::RESET_VECTOR);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_reset_to_boot_state() {
+        let (_vm, mut vcpu, vm_mem) = setup_vcpu(0x10000);
+        let vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            ht_enabled: false,
+            cpu_template: None,
+            cpu_topology: None,
+            firmware_boot: false,
+            tsc_khz: None,
+        };
+        vcpu.configure_x86_64(&vm_mem, GuestAddress(0x1000), &vcpu_config)
+            .unwrap();
+        assert!(matches!(
+            vcpu.reset_to_boot_state(),
+            Err(Error::VcpuNoBootState)
+        ));
+
+        vcpu.save_boot_state().unwrap();
+        let mut regs = vcpu.fd.get_regs().unwrap();
+        regs.rip = 0x2000;
+        vcpu.fd.set_regs(&regs).unwrap();
+        vcpu.reset_to_boot_state().unwrap();
+        assert_eq!(vcpu.fd.get_regs().unwrap().rip, 0x1000);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_tsc_khz() {
+        let (_vm, mut vcpu, vm_mem) = setup_vcpu(0x10000);
+        // The host frequency, as scaling to another one needs hardware support.
+        let tsc_khz = vcpu.fd.get_tsc_khz().unwrap();
+        let vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            ht_enabled: false,
+            cpu_template: None,
+            cpu_topology: None,
+            firmware_boot: false,
+            tsc_khz: Some(tsc_khz),
+        };
+        vcpu.configure_x86_64(&vm_mem, GuestAddress(0x1000), &vcpu_config)
+            .unwrap();
+
+        let state = vcpu.save_state().unwrap();
+        assert_eq!(state.tsc_khz, tsc_khz);
+        vcpu.restore_state(&state).unwrap();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vm_clock_restore() {
+        let (vm, _vcpu, _vm_mem) = setup_vcpu(0x10000);
+
+        let mut state = vm.save_state().unwrap();
+        assert_eq!(state.clock.flags, 0);
+        // As if the state was saved a second earlier.
+        state.clock.realtime -= 1_000_000_000;
+        let saved = state.clock.clock;
+        vm.restore_state(&state).unwrap();
+        assert!(vm.fd.get_clock().unwrap().clock >= saved + 1_000_000_000);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_regs() {
+        let (_vm, mut vcpu, vm_mem) = setup_vcpu(0x10000);
+        let vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            ht_enabled: false,
+            cpu_template: None,
+            cpu_topology: None,
+            firmware_boot: false,
+            tsc_khz: None,
+        };
+        vcpu.configure_x86_64(&vm_mem, GuestAddress(0x1000), &vcpu_config)
+            .unwrap();
+
+        let regs = vcpu.get_regs().unwrap();
+        assert_eq!(regs.regs.rip, 0x1000);
+        // The kernel is entered in 64-bit mode.
+        assert_eq!(regs.sregs.cs.l, 1);
+
+        #[cfg(debug_assertions)]
+        {
+            let mut regs = regs;
+            regs.regs.rip = 0x2000;
+            vcpu.set_regs(&regs).unwrap();
+            assert_eq!(vcpu.get_regs().unwrap().regs.rip, 0x2000);
+        }
     }
 
     #[cfg(target_arch = "aarch64")]
@@ -15,7 +15,8 @@ use std::thread;
 use std::time::Duration;
 
 use super::super::{FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK};
-use crate::vmm_config::machine_config::CpuFeaturesTemplate;
+use crate::metrics::METRICS;
+use crate::vmm_config::machine_config::{CpuFeaturesTemplate, CpuTopology};
 
 use arch::aarch64::gic::GICDevice;
 use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
@@ -190,6 +191,9 @@ pub struct VcpuConfig {
     pub ht_enabled: bool,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Layout of the vCPUs in sockets, cores and threads, if not the one
+    /// implied by `ht_enabled`.
+    pub cpu_topology: Option<CpuTopology>,
 }
 
 // Using this for easier explicit type-casting to help IDEs interpret the code.
@@ -377,18 +381,22 @@ impl Vcpu {
     /// Returns error or enum specifying whether emulation was handled or interrupted.
     fn run_emulation(&mut self, hvf_vcpu: &mut HvfVcpu) -> Result<VcpuEmulation> {
         let vcpuid = hvf_vcpu.id();
+        let exits = &METRICS.vcpu_exits;
 
         match hvf_vcpu.run(self.vcpu_list.clone()) {
             Ok(exit) => match exit {
                 VcpuExit::Breakpoint => {
+                    exits.other.inc();
                     debug!("vCPU {} breakpoint", vcpuid);
                     Ok(VcpuEmulation::Interrupted)
                 }
                 VcpuExit::Canceled => {
+                    exits.interrupted.inc();
                     debug!("vCPU {} canceled", vcpuid);
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::CpuOn(mpidr, entry, context_id) => {
+                    exits.other.inc();
                     debug!(
                         "CpuOn: mpidr=0x{:x} entry=0x{:x} context_id={}",
                         mpidr, entry, context_id
@@ -404,10 +412,12 @@ impl Vcpu {
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::HypervisorCall => {
+                    exits.other.inc();
                     debug!("vCPU {} HVC", vcpuid);
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioRead(addr, data) => {
+                    exits.mmio_read.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         debug!("vCPU {} MMIO read 0x{:x}", vcpuid, addr);
                         mmio_bus.read(vcpuid, addr, data);
@@ -415,37 +425,45 @@ impl Vcpu {
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    exits.mmio_write.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.write(vcpuid, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::SecureMonitorCall => {
+                    exits.other.inc();
                     debug!("vCPU {} SMC", vcpuid);
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::Shutdown => {
+                    exits.shutdown.inc();
                     info!("vCPU {} received shutdown signal", vcpuid);
                     Ok(VcpuEmulation::Stopped)
                 }
                 VcpuExit::SystemRegister => {
+                    exits.other.inc();
                     debug!("vCPU {} accessed a system register", vcpuid);
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::VtimerActivated => {
+                    exits.other.inc();
                     debug!("vCPU {} VtimerActivated", vcpuid);
                     self.vcpu_list.set_vtimer_irq(vcpuid);
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::WaitForEvent => {
+                    exits.halt.inc();
                     debug!("vCPU {} WaitForEvent", vcpuid);
                     Ok(VcpuEmulation::WaitForEvent)
                 }
                 VcpuExit::WaitForEventExpired => {
+                    exits.halt.inc();
                     debug!("vCPU {} WaitForEventExpired", vcpuid);
                     Ok(VcpuEmulation::WaitForEventExpired)
                 }
                 VcpuExit::WaitForEventTimeout(duration) => {
+                    exits.halt.inc();
                     debug!("vCPU {} WaitForEventTimeout timeout={:?}", vcpuid, duration);
                     Ok(VcpuEmulation::WaitForEventTimeout(duration))
                 }
@@ -770,6 +788,7 @@ mod tests {
             vcpu_count: 1,
             ht_enabled: false,
             cpu_template: None,
+            cpu_topology: None,
         };
 
         assert!(vcpu
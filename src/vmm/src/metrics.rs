@@ -0,0 +1,169 @@
+//! Counters of what the VMM is doing. They're plain relaxed atomics, so they can be
+//! updated on the vCPU and device hot paths without taking any lock.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::{MmioTransport, VirtioDevice};
+use vm_memory::GuestMemoryMmap;
+
+/// A monotonically increasing counter.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Exits of the vCPUs to the VMM, by reason.
+pub struct VcpuExitMetrics {
+    /// Port I/O reads, x86_64 only.
+    pub io_in: Counter,
+    /// Port I/O writes, x86_64 only.
+    pub io_out: Counter,
+    pub mmio_read: Counter,
+    pub mmio_write: Counter,
+    /// HLT on x86_64, WFI/WFE on aarch64.
+    pub halt: Counter,
+    pub shutdown: Counter,
+    /// Exits requested by the VMM, e.g. to pause the vCPU.
+    pub interrupted: Counter,
+    /// Any other exit, including errors.
+    pub other: Counter,
+}
+
+impl VcpuExitMetrics {
+    const fn new() -> Self {
+        VcpuExitMetrics {
+            io_in: Counter::new(),
+            io_out: Counter::new(),
+            mmio_read: Counter::new(),
+            mmio_write: Counter::new(),
+            halt: Counter::new(),
+            shutdown: Counter::new(),
+            interrupted: Counter::new(),
+            other: Counter::new(),
+        }
+    }
+}
+
+struct DeviceMetrics {
+    id: String,
+    device: Arc<Mutex<dyn VirtioDevice>>,
+    mem: GuestMemoryMmap,
+    interrupt_acks: Arc<AtomicU64>,
+}
+
+pub struct Metrics {
+    pub vcpu_exits: VcpuExitMetrics,
+    // Only locked when registering a device or taking a snapshot.
+    devices: Mutex<Vec<DeviceMetrics>>,
+}
+
+/// The metrics of the VMM of this process.
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            vcpu_exits: VcpuExitMetrics::new(),
+            devices: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn register_device(
+        &self,
+        id: &str,
+        transport: &MmioTransport,
+        mem: &GuestMemoryMmap,
+    ) {
+        self.devices.lock().unwrap().push(DeviceMetrics {
+            id: id.to_string(),
+            device: transport.device(),
+            mem: mem.clone(),
+            interrupt_acks: transport.interrupt_acks(),
+        });
+    }
+
+    /// Renders the metrics in the Prometheus text format. The depth of a device queue
+    /// is the number of requests the guest made that the device hasn't completed yet,
+    /// sampled when taking the snapshot.
+    pub fn snapshot(&self) -> String {
+        let exits = &self.vcpu_exits;
+        let mut out = String::new();
+        for (reason, counter) in [
+            ("io_in", &exits.io_in),
+            ("io_out", &exits.io_out),
+            ("mmio_read", &exits.mmio_read),
+            ("mmio_write", &exits.mmio_write),
+            ("halt", &exits.halt),
+            ("shutdown", &exits.shutdown),
+            ("interrupted", &exits.interrupted),
+            ("other", &exits.other),
+        ] {
+            let _ = writeln!(out, "vcpu_exits{{reason=\"{reason}\"}} {}", counter.get());
+        }
+
+        for dev in self.devices.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "device_interrupts{{device=\"{}\"}} {}",
+                dev.id,
+                dev.interrupt_acks.load(Ordering::Relaxed)
+            );
+
+            let device = dev.device.lock().unwrap();
+            if !device.is_activated() {
+                continue;
+            }
+            for (index, queue) in device.queues().iter().enumerate() {
+                if !queue.ready {
+                    continue;
+                }
+                if let Ok(depth) = queue.in_flight(&dev.mem) {
+                    let _ = writeln!(
+                        out,
+                        "device_queue_depth{{device=\"{}\",queue=\"{index}\"}} {depth}",
+                        dev.id
+                    );
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter() {
+        let counter = Counter::new();
+        counter.inc();
+        counter.inc();
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let metrics = Metrics::new();
+        metrics.vcpu_exits.mmio_write.inc();
+
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.contains("vcpu_exits{reason=\"mmio_write\"} 1\n"));
+        assert!(snapshot.contains("vcpu_exits{reason=\"halt\"} 0\n"));
+    }
+}
@@ -3,30 +3,64 @@
 
 //#![deny(warnings)]
 
-#[cfg(feature = "tee")]
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
 use std::fs::File;
 #[cfg(feature = "tee")]
 use std::io::BufReader;
+#[cfg(not(feature = "tee"))]
+use std::io::Read;
+#[cfg(any(feature = "tee", target_os = "linux"))]
+use std::path::Path;
 use std::path::PathBuf;
+#[cfg(feature = "amd-sev")]
+use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(feature = "tee")]
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "tee")]
-use kbs_types::Tee;
+pub use kbs_types::Tee;
+
+#[cfg(feature = "amd-sev")]
+use crate::linux::tee::amdsev::Phase;
+
+use smbios::SmbiosConfig;
+use utils::cancel::CancelToken;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use utils::eventfd::EventFd;
+
+#[cfg(not(feature = "tee"))]
+use devices::virtio::{IdRange, RngRateLimit};
 
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "linux")]
+use crate::landlock::PathAccess;
+use crate::seccomp::{self, SeccompAction, SeccompFilter};
 #[cfg(feature = "blk")]
 use crate::vmm_config::block::{BlockBuilder, BlockConfigError, BlockDeviceConfig};
 use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use crate::vmm_config::firmware::FirmwareConfig;
 #[cfg(not(feature = "tee"))]
 use crate::vmm_config::fs::*;
+#[cfg(target_os = "linux")]
+use crate::vmm_config::host_limits::MAX_HOST_CPUS;
+use crate::vmm_config::host_limits::{HostLimitsError, HostRlimit};
 #[cfg(feature = "tee")]
 use crate::vmm_config::kernel_bundle::{InitrdBundle, QbootBundle, QbootBundleError};
 use crate::vmm_config::kernel_bundle::{KernelBundle, KernelBundleError};
-use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use crate::vmm_config::machine_config::Clocksource;
+use crate::vmm_config::machine_config::{HugePagesConfig, VmConfig, VmConfigError};
 #[cfg(feature = "net")]
 use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig, NetworkInterfaceError};
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+use crate::vmm_config::numa::{NumaConfig, NumaConfigError, NumaNodeConfig};
 use crate::vmm_config::vsock::*;
+#[cfg(target_arch = "x86_64")]
+use crate::vmm_config::watchdog::WatchdogConfig;
 use crate::vstate::VcpuConfig;
 
 type Result<E> = std::result::Result<(), E>;
@@ -44,12 +78,66 @@ pub enum Error {
     /// Error parsing TEE config file.
     #[cfg(feature = "tee")]
     ParseTeeConfig(serde_json::Error),
+    /// A field of the TEE config has an invalid value.
+    #[cfg(feature = "tee")]
+    InvalidTeeConfig(TeeConfigField),
     /// microVM vCpus or memory configuration error.
     VmConfig(VmConfigError),
     /// Vsock device configuration error.
     VsockDevice(VsockConfigError),
 }
 
+/// Protocol spoken with the attestation servers.
+#[cfg(feature = "tee")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttestationProtocol {
+    /// Key Broker Service, releasing the secret once the launch is attested.
+    #[default]
+    Kbs,
+    /// Keylime, registering the workload with the registrar at `attestation_url`
+    /// and submitting its evidence to the verifier at `keylime_verifier_url`.
+    Keylime,
+    /// Microsoft Azure Attestation, issuing a JWT for the SEV-SNP reports the
+    /// guest requests. See `maa_token`.
+    Maa,
+}
+
+/// Algorithm the KBS is asked to wrap the released resources with, so only
+/// the attested host can read them.
+#[cfg(feature = "tee")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyWrapAlgorithm {
+    /// RSA-OAEP key encryption, with a 2048-bit RSA key.
+    #[serde(rename = "RSA-OAEP")]
+    RsaOaep,
+    /// ECDH-ES key agreement on the P-256 curve, with AES key wrap.
+    #[serde(rename = "ECDH-ES+A256KW")]
+    EcdhEsA256kw,
+}
+
+/// Security version numbers of the components of the SEV-SNP firmware's
+/// Trusted Computing Base.
+#[cfg(feature = "tee")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcbVersion {
+    pub bootloader: u8,
+    pub tee: u8,
+    pub snp: u8,
+    pub microcode: u8,
+}
+
+#[cfg(feature = "tee")]
+impl TcbVersion {
+    /// Whether every component is at least at the version required by `min`.
+    pub fn meets(&self, min: &TcbVersion) -> bool {
+        self.bootloader >= min.bootloader
+            && self.tee >= min.tee
+            && self.snp >= min.snp
+            && self.microcode >= min.microcode
+    }
+}
+
 #[cfg(feature = "tee")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TeeConfig {
@@ -58,7 +146,327 @@ pub struct TeeConfig {
     pub ram_mib: usize,
     pub tee: Tee,
     pub tee_data: String,
+    /// Attestation server URL, or a comma-separated list of them to be tried in order.
+    /// A `unix:///path/to/socket` URL speaks HTTP to a server on a Unix socket.
     pub attestation_url: String,
+    /// Forbid any network access while launching the TEE.
+    #[serde(default)]
+    pub offline: bool,
+    /// File holding a secret to inject into the guest when there's no attestation server.
+    #[serde(default)]
+    pub secret_file: String,
+    /// Allow reading and writing the encrypted guest memory from the host, for
+    /// debugging. Only honored if the launch policy permits it.
+    #[serde(default)]
+    pub allow_debug: bool,
+    /// Allow sending the guest to another host, or receiving it from one, through
+    /// the firmware migration commands. Only honored if the launch policy permits it.
+    #[serde(default)]
+    pub allow_migration: bool,
+    /// File to persist the local SEV session in, and to reuse it from on later
+    /// launches with the same policy and certificate chain. The transport keys
+    /// of the session are not stored.
+    #[serde(default)]
+    pub session_cache: String,
+    /// How many times to retry a firmware command while the firmware is busy.
+    #[serde(default = "default_firmware_retries")]
+    pub firmware_retries: u32,
+    /// Go through the whole attestation up to the launch measurement, then tear
+    /// the VM down instead of injecting the secret and starting it.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Protocol spoken with the servers in `attestation_url`: "kbs", "keylime" or "maa".
+    #[serde(default)]
+    pub attestation_protocol: AttestationProtocol,
+    /// Keylime verifier URL, when using the Keylime protocol.
+    #[serde(default)]
+    pub keylime_verifier_url: String,
+    /// Base URL of the AMD Key Distribution Service serving the CEK certificates.
+    #[serde(default = "default_cek_url")]
+    pub cek_url: String,
+    /// Base URL serving the ASK and ARK certificates of each CPU model.
+    #[serde(default = "default_ask_ark_url")]
+    pub ask_ark_url: String,
+    /// Check AMD didn't revoke the SNP endorsement certificates of this host.
+    #[serde(default)]
+    pub check_revocation: bool,
+    /// Base URL of the AMD Key Distribution Service serving the VCEK certificates,
+    /// their chain and the CRL.
+    #[serde(default = "default_vcek_url")]
+    pub vcek_url: String,
+    /// Abort the launch if the secret can't be fetched or read. Otherwise the
+    /// guest is started without it, for measure-only attestation policies.
+    #[serde(default = "default_secret_required")]
+    pub secret_required: bool,
+    /// Names of the KBS resources to request once the launch is attested, so
+    /// several can be released to the same workload. Defaults to a single
+    /// resource named after `workload_id`.
+    #[serde(default)]
+    pub resource_ids: Vec<String>,
+    /// Have the KBS wrap the released resources with a key generated for each
+    /// launch. Otherwise they're expected to be served unwrapped.
+    #[serde(default)]
+    pub key_wrap_algorithm: Option<KeyWrapAlgorithm>,
+    /// Refuse to launch on hosts whose SNP firmware reports an older TCB.
+    #[serde(default)]
+    pub min_tcb: Option<TcbVersion>,
+    /// Don't fetch or load the SEV certificate chain, and request the KBS
+    /// session without it, for brokers fetching the certificates themselves.
+    #[serde(default)]
+    pub skip_chain: bool,
+    /// Encoded SEV certificate chain, used instead of the `vendor_chain` file
+    /// of `tee_data`. Only set from the API, as it's not part of the file.
+    #[serde(skip)]
+    pub vendor_chain_data: Vec<u8>,
+    /// Invoked on each phase of the SEV launch. Only set from the API, as
+    /// it's not part of the file.
+    #[cfg(feature = "amd-sev")]
+    #[serde(skip)]
+    pub progress_callback: Option<ProgressCallback>,
+}
+
+/// Callback invoked on each phase of a SEV launch, to show its progress.
+#[cfg(feature = "amd-sev")]
+#[derive(Clone)]
+pub struct ProgressCallback(pub Arc<dyn Fn(Phase) + Send + Sync>);
+
+#[cfg(feature = "amd-sev")]
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ProgressCallback")
+    }
+}
+
+#[cfg(feature = "tee")]
+fn default_firmware_retries() -> u32 {
+    5
+}
+
+#[cfg(feature = "tee")]
+fn default_secret_required() -> bool {
+    true
+}
+
+#[cfg(feature = "tee")]
+fn default_cek_url() -> String {
+    "https://kdsintf.amd.com/cek/id".to_string()
+}
+
+#[cfg(feature = "tee")]
+fn default_vcek_url() -> String {
+    "https://kdsintf.amd.com/vcek/v1".to_string()
+}
+
+#[cfg(feature = "tee")]
+fn default_ask_ark_url() -> String {
+    "https://developer.amd.com/wp-content/resources/".to_string()
+}
+
+/// Fields of a `TeeConfig` that may fail validation.
+#[cfg(feature = "tee")]
+#[derive(Debug, PartialEq, Eq)]
+pub enum TeeConfigField {
+    WorkloadId,
+    Cpus,
+    RamMib,
+    Tee,
+    TeeData,
+    AttestationUrl,
+    SecretFile,
+    SessionCache,
+    DryRun,
+    AttestationProtocol,
+    KeylimeVerifierUrl,
+    CekUrl,
+    AskArkUrl,
+    CheckRevocation,
+    VcekUrl,
+    SecretRequired,
+    ResourceIds,
+    KeyWrapAlgorithm,
+    MinTcb,
+    VendorChainData,
+    SkipChain,
+}
+
+#[cfg(feature = "tee")]
+impl TeeConfig {
+    /// Checks the configuration is complete and well-formed for the selected TEE,
+    /// before starting any work on the firmware or the attestation servers.
+    pub fn validate(&self) -> Result<Error> {
+        let invalid = |field| Err(Error::InvalidTeeConfig(field));
+
+        if self.cpus == 0 {
+            return invalid(TeeConfigField::Cpus);
+        }
+
+        if self.ram_mib == 0 {
+            return invalid(TeeConfigField::RamMib);
+        }
+
+        let tee_data: serde_json::Value = match serde_json::from_str(&self.tee_data) {
+            Ok(tee_data @ serde_json::Value::Object(_)) => tee_data,
+            _ => return invalid(TeeConfigField::TeeData),
+        };
+
+        match self.tee {
+            Tee::Sev => {
+                if !tee_data["vendor_chain"].is_string()
+                    || !tee_data["attestation_server_pubkey"].is_string()
+                {
+                    return invalid(TeeConfigField::TeeData);
+                }
+                // SEV doesn't produce attestation reports.
+                if self.attestation_protocol == AttestationProtocol::Maa {
+                    return invalid(TeeConfigField::AttestationProtocol);
+                }
+            }
+            Tee::Snp => {
+                if !self.secret_file.is_empty() {
+                    return invalid(TeeConfigField::SecretFile);
+                }
+                if !self.session_cache.is_empty() {
+                    return invalid(TeeConfigField::SessionCache);
+                }
+                // The SNP endorsement certificates are fetched or read from
+                // the host firmware.
+                if !self.vendor_chain_data.is_empty() {
+                    return invalid(TeeConfigField::VendorChainData);
+                }
+                if self.dry_run {
+                    return invalid(TeeConfigField::DryRun);
+                }
+                // The secret is requested by the guest, not injected at launch.
+                if !self.secret_required {
+                    return invalid(TeeConfigField::SecretRequired);
+                }
+                if !self.resource_ids.is_empty() {
+                    return invalid(TeeConfigField::ResourceIds);
+                }
+                if self.key_wrap_algorithm.is_some() {
+                    return invalid(TeeConfigField::KeyWrapAlgorithm);
+                }
+                // SNP evidence is gathered by the guest itself.
+                if self.attestation_protocol == AttestationProtocol::Keylime {
+                    return invalid(TeeConfigField::AttestationProtocol);
+                }
+            }
+            _ => return invalid(TeeConfigField::Tee),
+        }
+
+        let valid_url = |url: &str| {
+            ["http://", "https://"].iter().any(|scheme| {
+                url.strip_prefix(scheme)
+                    .is_some_and(|rest| !rest.is_empty() && !rest.contains(char::is_whitespace))
+            })
+        };
+
+        // Attestation servers may also be reached through a local Unix socket.
+        let valid_attestation_url = |url: &str| {
+            valid_url(url)
+                || url.strip_prefix("unix://").is_some_and(|path| {
+                    path.len() > 1 && path.starts_with('/') && !path.contains(char::is_whitespace)
+                })
+        };
+
+        if !self.attestation_url.is_empty() {
+            if !self
+                .attestation_url
+                .split(',')
+                .map(str::trim)
+                .all(valid_attestation_url)
+            {
+                return invalid(TeeConfigField::AttestationUrl);
+            }
+
+            if self.workload_id.is_empty() {
+                return invalid(TeeConfigField::WorkloadId);
+            }
+
+            // The session is negotiated with the KBS on each launch.
+            if self.attestation_protocol == AttestationProtocol::Kbs
+                && !self.session_cache.is_empty()
+            {
+                return invalid(TeeConfigField::SessionCache);
+            }
+        }
+
+        if !valid_url(&self.cek_url) {
+            return invalid(TeeConfigField::CekUrl);
+        }
+
+        if !valid_url(&self.ask_ark_url) {
+            return invalid(TeeConfigField::AskArkUrl);
+        }
+
+        if !valid_url(&self.vcek_url) {
+            return invalid(TeeConfigField::VcekUrl);
+        }
+
+        if self.min_tcb.is_some() && self.tee != Tee::Snp {
+            return invalid(TeeConfigField::MinTcb);
+        }
+
+        // Revocation is only published for the SNP endorsement certificates.
+        if self.check_revocation && (self.tee != Tee::Snp || self.offline) {
+            return invalid(TeeConfigField::CheckRevocation);
+        }
+
+        // Only the KBS gets by without the chain, the local session and the
+        // Keylime registration need it.
+        if self.skip_chain
+            && (self.tee != Tee::Sev
+                || self.attestation_url.is_empty()
+                || self.attestation_protocol != AttestationProtocol::Kbs)
+        {
+            return invalid(TeeConfigField::SkipChain);
+        }
+
+        if self
+            .resource_ids
+            .iter()
+            .any(|id| id.is_empty() || id.contains(char::is_whitespace))
+        {
+            return invalid(TeeConfigField::ResourceIds);
+        }
+
+        match self.attestation_protocol {
+            AttestationProtocol::Kbs => {
+                if !self.keylime_verifier_url.is_empty() {
+                    return invalid(TeeConfigField::KeylimeVerifierUrl);
+                }
+            }
+            AttestationProtocol::Keylime => {
+                // Keylime doesn't release any resources.
+                if !self.resource_ids.is_empty() {
+                    return invalid(TeeConfigField::ResourceIds);
+                }
+                if self.key_wrap_algorithm.is_some() {
+                    return invalid(TeeConfigField::KeyWrapAlgorithm);
+                }
+                if self.attestation_url.is_empty() {
+                    return invalid(TeeConfigField::AttestationUrl);
+                }
+                if !valid_url(&self.keylime_verifier_url) {
+                    return invalid(TeeConfigField::KeylimeVerifierUrl);
+                }
+            }
+            AttestationProtocol::Maa => {
+                if !self.keylime_verifier_url.is_empty() {
+                    return invalid(TeeConfigField::KeylimeVerifierUrl);
+                }
+                if self.attestation_url.is_empty() {
+                    return invalid(TeeConfigField::AttestationUrl);
+                }
+            }
+        }
+
+        if !self.secret_file.is_empty() && !Path::new(&self.secret_file).is_file() {
+            return invalid(TeeConfigField::SecretFile);
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(feature = "tee")]
@@ -71,7 +479,217 @@ impl Default for TeeConfig {
             tee: Tee::Sev,
             tee_data: "".to_string(),
             attestation_url: "".to_string(),
+            offline: false,
+            secret_file: "".to_string(),
+            allow_debug: false,
+            allow_migration: false,
+            session_cache: "".to_string(),
+            firmware_retries: default_firmware_retries(),
+            dry_run: false,
+            attestation_protocol: AttestationProtocol::Kbs,
+            keylime_verifier_url: "".to_string(),
+            cek_url: default_cek_url(),
+            ask_ark_url: default_ask_ark_url(),
+            check_revocation: false,
+            vcek_url: default_vcek_url(),
+            secret_required: default_secret_required(),
+            resource_ids: Vec::new(),
+            key_wrap_algorithm: None,
+            min_tcb: None,
+            skip_chain: false,
+            vendor_chain_data: Vec::new(),
+            #[cfg(feature = "amd-sev")]
+            progress_callback: None,
+        }
+    }
+}
+
+/// Builds a validated `TeeConfig` from Rust, rather than deserializing it.
+///
+/// The settings carried in `tee_data` get their own methods, and are encoded
+/// for the selected TEE when building.
+#[cfg(feature = "tee")]
+#[derive(Default)]
+pub struct TeeConfigBuilder {
+    config: TeeConfig,
+    tee_data: serde_json::Map<String, serde_json::Value>,
+}
+
+#[cfg(feature = "tee")]
+impl TeeConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tee(mut self, tee: Tee) -> Self {
+        self.config.tee = tee;
+        self
+    }
+
+    pub fn workload_id(mut self, workload_id: &str) -> Self {
+        self.config.workload_id = workload_id.to_string();
+        self
+    }
+
+    pub fn cpus(mut self, cpus: u8) -> Self {
+        self.config.cpus = cpus;
+        self
+    }
+
+    pub fn ram_mib(mut self, ram_mib: usize) -> Self {
+        self.config.ram_mib = ram_mib;
+        self
+    }
+
+    /// Sets the attestation server, or a comma-separated list of them.
+    pub fn attestation_url(mut self, attestation_url: &str) -> Self {
+        self.config.attestation_url = attestation_url.to_string();
+        self
+    }
+
+    pub fn attestation_protocol(mut self, protocol: AttestationProtocol) -> Self {
+        self.config.attestation_protocol = protocol;
+        self
+    }
+
+    pub fn keylime_verifier_url(mut self, url: &str) -> Self {
+        self.config.keylime_verifier_url = url.to_string();
+        self
+    }
+
+    pub fn resource_ids(mut self, resource_ids: &[&str]) -> Self {
+        self.config.resource_ids = resource_ids.iter().map(|id| id.to_string()).collect();
+        self
+    }
+
+    pub fn key_wrap_algorithm(mut self, algorithm: KeyWrapAlgorithm) -> Self {
+        self.config.key_wrap_algorithm = Some(algorithm);
+        self
+    }
+
+    pub fn secret_file(mut self, secret_file: &str) -> Self {
+        self.config.secret_file = secret_file.to_string();
+        self
+    }
+
+    pub fn secret_required(mut self, secret_required: bool) -> Self {
+        self.config.secret_required = secret_required;
+        self
+    }
+
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.config.offline = offline;
+        self
+    }
+
+    pub fn allow_debug(mut self, allow_debug: bool) -> Self {
+        self.config.allow_debug = allow_debug;
+        self
+    }
+
+    pub fn allow_migration(mut self, allow_migration: bool) -> Self {
+        self.config.allow_migration = allow_migration;
+        self
+    }
+
+    pub fn session_cache(mut self, session_cache: &str) -> Self {
+        self.config.session_cache = session_cache.to_string();
+        self
+    }
+
+    pub fn firmware_retries(mut self, firmware_retries: u32) -> Self {
+        self.config.firmware_retries = firmware_retries;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    pub fn cek_url(mut self, url: &str) -> Self {
+        self.config.cek_url = url.to_string();
+        self
+    }
+
+    pub fn ask_ark_url(mut self, url: &str) -> Self {
+        self.config.ask_ark_url = url.to_string();
+        self
+    }
+
+    pub fn vcek_url(mut self, url: &str) -> Self {
+        self.config.vcek_url = url.to_string();
+        self
+    }
+
+    pub fn check_revocation(mut self, check_revocation: bool) -> Self {
+        self.config.check_revocation = check_revocation;
+        self
+    }
+
+    pub fn min_tcb(mut self, min_tcb: TcbVersion) -> Self {
+        self.config.min_tcb = Some(min_tcb);
+        self
+    }
+
+    /// SEV policy to launch the guest with when there's no attestation server.
+    pub fn policy(mut self, policy: u32) -> Self {
+        self.tee_data.insert("policy".to_string(), policy.into());
+        self
+    }
+
+    /// File holding the SEV certificate chain, instead of fetching it from AMD.
+    pub fn vendor_chain(mut self, vendor_chain: &str) -> Self {
+        self.tee_data
+            .insert("vendor_chain".to_string(), vendor_chain.into());
+        self
+    }
+
+    /// Don't load or fetch the SEV certificate chain at all.
+    pub fn skip_chain(mut self, skip_chain: bool) -> Self {
+        self.config.skip_chain = skip_chain;
+        self
+    }
+
+    /// Encoded SEV certificate chain, instead of reading it from the
+    /// `vendor_chain` file or fetching it from AMD.
+    pub fn vendor_chain_data(mut self, data: &[u8]) -> Self {
+        self.config.vendor_chain_data = data.to_vec();
+        self
+    }
+
+    /// Callback invoked on each phase of the SEV launch, from the thread
+    /// building the microVM.
+    #[cfg(feature = "amd-sev")]
+    pub fn progress_callback(mut self, callback: impl Fn(Phase) + Send + Sync + 'static) -> Self {
+        self.config.progress_callback = Some(ProgressCallback(Arc::new(callback)));
+        self
+    }
+
+    pub fn attestation_server_pubkey(mut self, pubkey: &str) -> Self {
+        self.tee_data
+            .insert("attestation_server_pubkey".to_string(), pubkey.into());
+        self
+    }
+
+    /// SNP processor generation, e.g. "milan" or "genoa".
+    pub fn generation(mut self, generation: &str) -> Self {
+        self.tee_data.insert("gen".to_string(), generation.into());
+        self
+    }
+
+    /// Returns the configuration, once validated.
+    pub fn build(mut self) -> std::result::Result<TeeConfig, Error> {
+        if self.config.tee == Tee::Sev {
+            // An empty chain is fetched from AMD, and the pubkey is optional.
+            for key in ["vendor_chain", "attestation_server_pubkey"] {
+                self.tee_data.entry(key).or_insert_with(|| "".into());
+            }
         }
+        self.config.tee_data = serde_json::Value::Object(self.tee_data).to_string();
+
+        self.config.validate()?;
+        Ok(self.config)
     }
 }
 
@@ -108,13 +726,87 @@ pub struct VmResources {
     /// Flags for the virtio-gpu device.
     pub gpu_virgl_flags: Option<u32>,
     pub gpu_shm_size: Option<usize>,
+    /// DRM render node for the virtio-gpu device to render with, instead of
+    /// the first usable one.
+    pub gpu_render_node: Option<PathBuf>,
     #[cfg(feature = "snd")]
     /// Enable the virtio-snd device.
     pub snd_device: bool,
     /// File to send console output.
     pub console_output: Option<PathBuf>,
-    /// SMBIOS OEM Strings
-    pub smbios_oem_strings: Option<Vec<String>>,
+    /// File or unix socket the guest process writes its stdout to, instead
+    /// of the console.
+    pub stdout_output: Option<PathBuf>,
+    /// File or unix socket the guest process writes its stderr to, instead
+    /// of the console.
+    pub stderr_output: Option<PathBuf>,
+    /// Contents of the SMBIOS tables.
+    pub smbios: SmbiosConfig,
+    /// Configuration the guest init reads from the "krun-config" console
+    /// port, for what doesn't fit in the kernel command line.
+    pub init_config: Option<Vec<u8>>,
+    /// Entropy source of the RNG device, the host's default one if not set.
+    #[cfg(not(feature = "tee"))]
+    pub rng_source: Option<PathBuf>,
+    /// Maximum amount of entropy the guest may consume over time.
+    #[cfg(not(feature = "tee"))]
+    pub rng_rate_limit: Option<RngRateLimit>,
+    /// Directory the vTPM keeps its state in. No vTPM is exposed if not set.
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    pub tpm_state_dir: Option<PathBuf>,
+    /// Initramfs image to boot the kernel with, replacing the bundled one if any.
+    pub initrd_path: Option<PathBuf>,
+    /// UEFI firmware booting the guest, from the reset vector. In a TEE it
+    /// replaces the qboot bundle.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub firmware: Option<FirmwareConfig>,
+    /// Clocksource selected on the guest kernel command line, if not the
+    /// kernel's default.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub clocksource: Option<Clocksource>,
+    /// Frequency the guest TSC runs at, in kHz, if not the host's.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub tsc_khz: Option<u32>,
+    /// File the pvpanic device reports the guest panics to. aarch64 guests
+    /// discover the device through the FDT, x86_64 ones through ACPI.
+    pub panic_output: Option<File>,
+    /// Hugepages backing the guest RAM, if any.
+    pub hugepages: Option<HugePagesConfig>,
+    /// How long the guest is given to shut down after a shutdown request
+    /// before the VMM stops it.
+    pub shutdown_timeout: Option<Duration>,
+    /// Watchdog exposed to the guest, if any.
+    #[cfg(target_arch = "x86_64")]
+    pub watchdog: Option<WatchdogConfig>,
+    /// File the guest RAM and vCPU registers are written to, as an ELF core
+    /// file, when the guest kernel reports a panic through pvpanic or the guest
+    /// crashes on a triple fault.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    pub dump_on_crash: Option<PathBuf>,
+    /// Signaled to reboot the guest in place. If set, the guest rebooting
+    /// itself doesn't stop the microVM either.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    pub reboot_evt: Option<EventFd>,
+    /// Resource limits applied to the VMM process before building the microVM.
+    pub host_rlimits: Vec<HostRlimit>,
+    /// Host CPUs each vCPU thread is pinned to, by vCPU index.
+    #[cfg(target_os = "linux")]
+    pub vcpu_affinity: HashMap<u8, Vec<usize>>,
+    /// NUMA topology of the guest. The guest has a single node if empty.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    pub numa: NumaConfig,
+    /// Aborts the build of the microVM, including the TEE attestation, once
+    /// triggered.
+    pub cancel: CancelToken,
+    /// What happens when a VMM thread makes a syscall out of the allowlist.
+    #[cfg(target_os = "linux")]
+    pub seccomp_action: SeccompAction,
+    /// BPF program replacing the default seccomp filter.
+    #[cfg(target_os = "linux")]
+    pub seccomp_filter: Option<SeccompFilter>,
+    /// Confine the VMM threads to the host paths the microVM uses with Landlock.
+    #[cfg(target_os = "linux")]
+    pub landlock: bool,
 }
 
 impl VmResources {
@@ -126,6 +818,13 @@ impl VmResources {
             vcpu_count: self.vm_config().vcpu_count.unwrap(),
             ht_enabled: self.vm_config().ht_enabled.unwrap(),
             cpu_template: self.vm_config().cpu_template,
+            cpu_topology: self.vm_config().cpu_topology,
+            // In a TEE the firmware replaces qboot, which already boots from
+            // the reset vector.
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            firmware_boot: cfg!(not(feature = "tee")) && self.firmware.is_some(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            tsc_khz: self.tsc_khz,
         }
     }
 
@@ -148,9 +847,22 @@ impl VmResources {
             .ht_enabled
             .unwrap_or_else(|| self.vm_config.ht_enabled.unwrap());
 
-        let vcpu_count_value = machine_config
-            .vcpu_count
-            .unwrap_or_else(|| self.vm_config.vcpu_count.unwrap());
+        let cpu_topology = machine_config.cpu_topology.or(self.vm_config.cpu_topology);
+
+        // A new topology implies the vcpu count, unless it's given as well.
+        let vcpu_count_value = match (machine_config.vcpu_count, machine_config.cpu_topology) {
+            (Some(vcpu_count), _) => vcpu_count,
+            (None, Some(topology)) => {
+                u8::try_from(topology.vcpu_count()).map_err(|_| VmConfigError::InvalidVcpuCount)?
+            }
+            (None, None) => self.vm_config.vcpu_count.unwrap(),
+        };
+
+        if let Some(topology) = cpu_topology {
+            if !topology.is_valid() || topology.vcpu_count() != u16::from(vcpu_count_value) {
+                return Err(VmConfigError::InvalidCpuTopology);
+            }
+        }
 
         // If hyperthreading is enabled or is to be enabled in this call
         // only allow vcpu count to be 1 or even.
@@ -161,6 +873,7 @@ impl VmResources {
         // Update all the fields that have a new value.
         self.vm_config.vcpu_count = Some(vcpu_count_value);
         self.vm_config.ht_enabled = Some(ht_enabled);
+        self.vm_config.cpu_topology = cpu_topology;
 
         if machine_config.mem_size_mib.is_some() {
             self.vm_config.mem_size_mib = machine_config.mem_size_mib;
@@ -233,9 +946,50 @@ impl VmResources {
         Ok(())
     }
 
+    /// Adds a virtio-fs device, exposed to the guest under its own tag.
+    #[cfg(not(feature = "tee"))]
+    pub fn add_fs_device(&mut self, config: FsDeviceConfig) -> Result<FsConfigError> {
+        if config.fs_id.is_empty() || config.fs_id.len() > FS_TAG_MAX_LEN {
+            return Err(FsConfigError::InvalidTag(config.fs_id));
+        }
+        if self.fs.iter().any(|fs| fs.fs_id == config.fs_id) {
+            return Err(FsConfigError::DuplicateTag(config.fs_id));
+        }
+        if config.shm_size == Some(0) {
+            return Err(FsConfigError::InvalidShmSize);
+        }
+
+        self.fs.push(config);
+        Ok(())
+    }
+
+    /// Maps a range of user IDs between the guest and the host on a virtio-fs device.
     #[cfg(not(feature = "tee"))]
-    pub fn add_fs_device(&mut self, config: FsDeviceConfig) {
-        self.fs.push(config)
+    pub fn add_fs_uid_mapping(&mut self, fs_id: &str, range: IdRange) -> Result<FsConfigError> {
+        let fs = self.fs_device_mut(fs_id)?;
+        fs.uid_map
+            .add(range)
+            .map_err(FsConfigError::InvalidIdMapping)
+    }
+
+    /// Maps a range of group IDs between the guest and the host on a virtio-fs device.
+    #[cfg(not(feature = "tee"))]
+    pub fn add_fs_gid_mapping(&mut self, fs_id: &str, range: IdRange) -> Result<FsConfigError> {
+        let fs = self.fs_device_mut(fs_id)?;
+        fs.gid_map
+            .add(range)
+            .map_err(FsConfigError::InvalidIdMapping)
+    }
+
+    #[cfg(not(feature = "tee"))]
+    fn fs_device_mut(
+        &mut self,
+        fs_id: &str,
+    ) -> std::result::Result<&mut FsDeviceConfig, FsConfigError> {
+        self.fs
+            .iter_mut()
+            .find(|fs| fs.fs_id == fs_id)
+            .ok_or_else(|| FsConfigError::UnknownTag(fs_id.to_string()))
     }
 
     #[cfg(feature = "blk")]
@@ -256,6 +1010,10 @@ impl VmResources {
         self.gpu_shm_size = Some(shm_size);
     }
 
+    pub fn set_gpu_render_node(&mut self, render_node: PathBuf) {
+        self.gpu_render_node = Some(render_node);
+    }
+
     #[cfg(feature = "snd")]
     pub fn set_snd_device(&mut self, enabled: bool) {
         self.snd_device = enabled;
@@ -265,6 +1023,155 @@ impl VmResources {
         self.console_output = Some(console_output);
     }
 
+    /// Loads the BPF program to install on the VMM threads instead of the
+    /// default seccomp filter.
+    #[cfg(target_os = "linux")]
+    pub fn set_seccomp_filter(&mut self, path: &Path) -> std::result::Result<(), seccomp::Error> {
+        self.seccomp_filter = Some(SeccompFilter::from_file(path)?);
+        Ok(())
+    }
+
+    /// Returns the seccomp filter to install on the VMM threads, if any.
+    #[cfg(target_os = "linux")]
+    pub fn seccomp_filter(&self) -> Option<SeccompFilter> {
+        match self.seccomp_filter {
+            Some(ref filter) => Some(filter.clone()),
+            None => seccomp::default_filter(self.seccomp_action),
+        }
+    }
+
+    /// Returns the host paths the VMM threads access once the microVM is built,
+    /// along with what they do there.
+    #[cfg(target_os = "linux")]
+    pub fn landlock_rules(&self) -> Vec<(PathBuf, PathAccess)> {
+        let parent_dir = |path: &Path| match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut rules = Vec::new();
+
+        #[cfg(not(feature = "tee"))]
+        for fs in self.fs.iter() {
+            let access = if fs.read_only {
+                PathAccess::ReadOnly
+            } else {
+                PathAccess::ReadWrite
+            };
+            rules.push((PathBuf::from(&fs.shared_dir), access));
+        }
+        // The sockets are only created once the guest listens on their ports.
+        if let Some(vsock) = self.vsock.get() {
+            for path in vsock.lock().unwrap().listening_socket_paths() {
+                rules.push((parent_dir(&path), PathAccess::Sockets));
+            }
+        }
+        #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+        if let Some(ref path) = self.dump_on_crash {
+            rules.push((parent_dir(path), PathAccess::CreateFiles));
+        }
+
+        rules
+    }
+
+    /// Sets the initramfs image to boot the kernel with, after checking it's a
+    /// non-empty file that can be read from.
+    pub fn set_initrd_path(&mut self, path: PathBuf) -> std::io::Result<()> {
+        if File::open(&path)?.metadata()?.len() == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        }
+
+        self.initrd_path = Some(path);
+        Ok(())
+    }
+
+    /// Sets the UEFI firmware booting the guest.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn set_firmware(&mut self, firmware: FirmwareConfig) {
+        self.firmware = Some(firmware);
+    }
+
+    /// Sets the hugepages backing the guest RAM, after checking the hugetlbfs
+    /// path, if any, is a directory. Hugepages are only supported on Linux.
+    pub fn set_hugepages(&mut self, hugepages: HugePagesConfig) -> std::io::Result<()> {
+        if cfg!(not(target_os = "linux")) {
+            return Err(std::io::Error::from(std::io::ErrorKind::Unsupported));
+        }
+        if let Some(path) = &hugepages.hugetlbfs_path {
+            if !path.metadata()?.is_dir() {
+                return Err(std::io::Error::from_raw_os_error(libc::ENOTDIR));
+            }
+        }
+
+        self.hugepages = Some(hugepages);
+        Ok(())
+    }
+
+    /// Sets the entropy source of the RNG device, after checking it can be
+    /// read from.
+    #[cfg(not(feature = "tee"))]
+    pub fn set_rng_source(&mut self, source: PathBuf) -> std::io::Result<()> {
+        let mut byte = [0u8; 1];
+        if File::open(&source)?.read(&mut byte)? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+
+        self.rng_source = Some(source);
+        Ok(())
+    }
+
+    /// Sets the directory the vTPM keeps its state in, after checking it's a
+    /// directory.
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    pub fn set_tpm_state_dir(&mut self, dir: PathBuf) -> std::io::Result<()> {
+        if !std::fs::metadata(&dir)?.is_dir() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        self.tpm_state_dir = Some(dir);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tee"))]
+    pub fn set_rng_rate_limit(&mut self, rate_limit: RngRateLimit) {
+        self.rng_rate_limit = Some(rate_limit);
+    }
+
+    /// Sets a resource limit of the VMM process, replacing any previous one on the same resource.
+    pub fn set_host_rlimit(&mut self, rlimit: HostRlimit) {
+        self.host_rlimits.retain(|r| r.resource != rlimit.resource);
+        self.host_rlimits.push(rlimit);
+    }
+
+    /// Pins the thread of a vCPU to a set of host CPUs.
+    #[cfg(target_os = "linux")]
+    pub fn set_vcpu_affinity(&mut self, vcpu: u8, cpus: Vec<usize>) -> Result<HostLimitsError> {
+        if cpus.is_empty() {
+            return Err(HostLimitsError::EmptyAffinity);
+        }
+        if let Some(&cpu) = cpus.iter().find(|&&cpu| cpu >= MAX_HOST_CPUS) {
+            return Err(HostLimitsError::InvalidCpu(cpu));
+        }
+
+        self.vcpu_affinity.insert(vcpu, cpus);
+        Ok(())
+    }
+
+    /// Adds a NUMA node to the guest, after the ones already added.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    pub fn add_numa_node(&mut self, node: NumaNodeConfig) {
+        self.numa.nodes.push(node);
+    }
+
+    /// Sets the distances between the NUMA nodes of the guest.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    pub fn set_numa_distances(
+        &mut self,
+        distances: Vec<u8>,
+        num_nodes: usize,
+    ) -> Result<NumaConfigError> {
+        self.numa.set_distances(distances, num_nodes)
+    }
+
     /// Sets a network device to be attached when the VM starts.
     #[cfg(feature = "net")]
     pub fn add_network_interface(
@@ -287,12 +1194,21 @@ impl VmResources {
         let tee_config: TeeConfig =
             serde_json::from_reader(reader).map_err(Error::ParseTeeConfig)?;
 
+        self.apply_tee_config(tee_config)
+    }
+
+    /// Sets a TEE configuration built by the caller, rather than read from a file.
+    #[cfg(feature = "tee")]
+    pub fn apply_tee_config(&mut self, tee_config: TeeConfig) -> Result<Error> {
+        tee_config.validate()?;
+
         // Override VmConfig with TeeConfig values
         self.set_vm_config(&VmConfig {
             vcpu_count: Some(tee_config.cpus),
             mem_size_mib: Some(tee_config.ram_mib),
             ht_enabled: Some(false),
             cpu_template: None,
+            cpu_topology: None,
         })
         .map_err(Error::VmConfig)?;
 
@@ -304,16 +1220,28 @@ impl VmResources {
 
 #[cfg(test)]
 mod tests {
+    #[cfg(target_os = "linux")]
+    use std::collections::HashMap;
+
     use crate::resources::VmResources;
+    #[cfg(target_os = "linux")]
+    use crate::seccomp::SeccompAction;
     use crate::vmm_config::boot_source::BootSourceConfig;
-    use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
+    use crate::vmm_config::machine_config::{
+        CpuFeaturesTemplate, CpuTopology, VmConfig, VmConfigError,
+    };
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    use crate::vmm_config::numa::NumaConfig;
     use crate::vmm_config::vsock::tests::{default_config, TempSockFile};
     use crate::vstate::VcpuConfig;
+    use smbios::SmbiosConfig;
+    use utils::cancel::CancelToken;
     use utils::tempfile::TempFile;
 
     fn default_boot_cfg() -> BootSourceConfig {
         BootSourceConfig {
             kernel_cmdline_prolog: None,
+            kernel_cmdline_append: None,
             kernel_cmdline_epilog: None,
         }
     }
@@ -323,16 +1251,63 @@ mod tests {
             vm_config: VmConfig::default(),
             boot_config: default_boot_cfg(),
             kernel_bundle: Default::default(),
+            #[cfg(feature = "tee")]
+            qboot_bundle: Default::default(),
+            #[cfg(feature = "tee")]
+            initrd_bundle: Default::default(),
+            #[cfg(not(feature = "tee"))]
             fs: Default::default(),
             vsock: Default::default(),
+            #[cfg(feature = "blk")]
+            block: Default::default(),
             #[cfg(feature = "net")]
             net_builder: Default::default(),
+            #[cfg(feature = "tee")]
+            tee_config: Default::default(),
             gpu_virgl_flags: None,
             gpu_shm_size: None,
+            gpu_render_node: None,
             #[cfg(feature = "snd")]
             enable_snd: False,
             console_output: None,
-            smbios_oem_strings: None,
+            stdout_output: None,
+            stderr_output: None,
+            smbios: SmbiosConfig::default(),
+            init_config: None,
+            #[cfg(not(feature = "tee"))]
+            rng_source: None,
+            #[cfg(not(feature = "tee"))]
+            rng_rate_limit: None,
+            #[cfg(all(target_os = "linux", not(feature = "tee")))]
+            tpm_state_dir: None,
+            initrd_path: None,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            firmware: None,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            clocksource: None,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            tsc_khz: None,
+            panic_output: None,
+            hugepages: None,
+            shutdown_timeout: None,
+            #[cfg(target_arch = "x86_64")]
+            watchdog: None,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            dump_on_crash: None,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            reboot_evt: None,
+            host_rlimits: Vec::new(),
+            #[cfg(target_os = "linux")]
+            vcpu_affinity: HashMap::new(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            numa: NumaConfig::default(),
+            cancel: CancelToken::new(),
+            #[cfg(target_os = "linux")]
+            seccomp_action: SeccompAction::Disabled,
+            #[cfg(target_os = "linux")]
+            seccomp_filter: None,
+            #[cfg(target_os = "linux")]
+            landlock: false,
         }
     }
 
@@ -343,6 +1318,11 @@ mod tests {
             vcpu_count: vm_resources.vm_config().vcpu_count.unwrap(),
             ht_enabled: vm_resources.vm_config().ht_enabled.unwrap(),
             cpu_template: vm_resources.vm_config().cpu_template,
+            cpu_topology: vm_resources.vm_config().cpu_topology,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            firmware_boot: false,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            tsc_khz: None,
         };
 
         let vcpu_config = vm_resources.vcpu_config();
@@ -365,6 +1345,7 @@ mod tests {
             mem_size_mib: Some(512),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpu_topology: None,
         };
 
         assert_ne!(vm_resources.vm_config, aux_vm_config);
@@ -390,6 +1371,288 @@ mod tests {
             vm_resources.set_vm_config(&aux_vm_config),
             Err(VmConfigError::InvalidMemorySize)
         );
+        aux_vm_config.mem_size_mib = Some(512);
+
+        // The topology must match the vcpu count.
+        aux_vm_config.cpu_topology = Some(CpuTopology {
+            sockets: 2,
+            cores_per_socket: 4,
+            threads_per_core: 2,
+        });
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidCpuTopology)
+        );
+
+        // A topology alone sets the vcpu count.
+        vm_resources
+            .set_vm_config(&VmConfig {
+                vcpu_count: None,
+                mem_size_mib: None,
+                ht_enabled: None,
+                cpu_template: None,
+                cpu_topology: aux_vm_config.cpu_topology,
+            })
+            .unwrap();
+        assert_eq!(vm_resources.vm_config.vcpu_count, Some(16));
+        assert_eq!(
+            vm_resources.vcpu_config().cpu_topology,
+            aux_vm_config.cpu_topology
+        );
+    }
+
+    #[cfg(feature = "tee")]
+    #[test]
+    fn test_tcb_version_meets() {
+        use crate::resources::TcbVersion;
+
+        let min = TcbVersion {
+            bootloader: 3,
+            tee: 0,
+            snp: 8,
+            microcode: 115,
+        };
+
+        assert!(min.meets(&min));
+        assert!(TcbVersion {
+            microcode: 209,
+            ..min
+        }
+        .meets(&min));
+        // Every component is checked on its own, a newer one doesn't make up
+        // for an older one.
+        assert!(!TcbVersion {
+            bootloader: 4,
+            snp: 7,
+            ..min
+        }
+        .meets(&min));
+        assert!(!TcbVersion::default().meets(&min));
+    }
+
+    #[cfg(feature = "tee")]
+    #[test]
+    fn test_tee_config_builder() {
+        use crate::resources::{
+            AttestationProtocol, Error, KeyWrapAlgorithm, Tee, TeeConfigBuilder, TeeConfigField,
+        };
+
+        let config = TeeConfigBuilder::new()
+            .cpus(2)
+            .ram_mib(1024)
+            .attestation_url("http://kbs")
+            .workload_id("workload")
+            .policy(0x1)
+            .vendor_chain_data(&[0xaa, 0x55])
+            .build()
+            .unwrap();
+        assert_eq!(config.attestation_url, "http://kbs");
+        assert_eq!(config.workload_id, "workload");
+        assert_eq!(config.vendor_chain_data, [0xaa, 0x55]);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&config.tee_data).unwrap(),
+            serde_json::json!({
+                "policy": 1,
+                "vendor_chain": "",
+                "attestation_server_pubkey": "",
+            })
+        );
+
+        let config = TeeConfigBuilder::new()
+            .cpus(2)
+            .ram_mib(1024)
+            .attestation_url("unix:///run/kbs.sock, http://kbs")
+            .workload_id("workload")
+            .build()
+            .unwrap();
+        assert_eq!(config.attestation_url, "unix:///run/kbs.sock, http://kbs");
+
+        let config = TeeConfigBuilder::new()
+            .cpus(2)
+            .ram_mib(1024)
+            .attestation_url("http://kbs")
+            .workload_id("workload")
+            .skip_chain(true)
+            .build()
+            .unwrap();
+        assert!(config.skip_chain);
+
+        // The result is validated.
+        assert!(matches!(
+            TeeConfigBuilder::new()
+                .cpus(2)
+                .ram_mib(1024)
+                .attestation_url("unix://run/kbs.sock")
+                .workload_id("workload")
+                .build(),
+            Err(Error::InvalidTeeConfig(TeeConfigField::AttestationUrl))
+        ));
+        assert!(matches!(
+            TeeConfigBuilder::new()
+                .cpus(2)
+                .ram_mib(1024)
+                .attestation_url("http://kbs")
+                .build(),
+            Err(Error::InvalidTeeConfig(TeeConfigField::WorkloadId))
+        ));
+        assert!(matches!(
+            TeeConfigBuilder::new()
+                .tee(Tee::Snp)
+                .cpus(2)
+                .ram_mib(1024)
+                .vendor_chain_data(&[0xaa, 0x55])
+                .build(),
+            Err(Error::InvalidTeeConfig(TeeConfigField::VendorChainData))
+        ));
+        assert!(matches!(
+            TeeConfigBuilder::new()
+                .tee(Tee::Snp)
+                .cpus(2)
+                .ram_mib(1024)
+                .key_wrap_algorithm(KeyWrapAlgorithm::RsaOaep)
+                .build(),
+            Err(Error::InvalidTeeConfig(TeeConfigField::KeyWrapAlgorithm))
+        ));
+        assert!(matches!(
+            TeeConfigBuilder::new()
+                .cpus(2)
+                .ram_mib(1024)
+                .skip_chain(true)
+                .build(),
+            Err(Error::InvalidTeeConfig(TeeConfigField::SkipChain))
+        ));
+        assert!(matches!(
+            TeeConfigBuilder::new()
+                .cpus(2)
+                .ram_mib(1024)
+                .attestation_url("http://kbs")
+                .workload_id("workload")
+                .attestation_protocol(AttestationProtocol::Keylime)
+                .keylime_verifier_url("http://verifier")
+                .skip_chain(true)
+                .build(),
+            Err(Error::InvalidTeeConfig(TeeConfigField::SkipChain))
+        ));
+        assert!(matches!(
+            TeeConfigBuilder::new()
+                .cpus(2)
+                .ram_mib(1024)
+                .attestation_url("https://maa")
+                .workload_id("workload")
+                .attestation_protocol(AttestationProtocol::Maa)
+                .build(),
+            Err(Error::InvalidTeeConfig(TeeConfigField::AttestationProtocol))
+        ));
+    }
+
+    #[cfg(not(feature = "tee"))]
+    #[test]
+    fn test_add_fs_device() {
+        use crate::vmm_config::fs::{FsConfigError, FsDeviceConfig};
+
+        let fs = |tag: &str| FsDeviceConfig {
+            fs_id: tag.to_string(),
+            shared_dir: "/tmp".to_string(),
+            shm_size: None,
+            read_only: false,
+            uid_map: Default::default(),
+            gid_map: Default::default(),
+        };
+        let mut vmr = default_vm_resources();
+
+        vmr.add_fs_device(fs("data")).unwrap();
+        vmr.add_fs_device(fs("cache")).unwrap();
+        assert_eq!(
+            vmr.add_fs_device(fs("data")),
+            Err(FsConfigError::DuplicateTag("data".to_string()))
+        );
+        assert_eq!(
+            vmr.add_fs_device(fs("")),
+            Err(FsConfigError::InvalidTag("".to_string()))
+        );
+        assert!(matches!(
+            vmr.add_fs_device(fs(&"t".repeat(37))),
+            Err(FsConfigError::InvalidTag(_))
+        ));
+
+        assert_eq!(
+            vmr.add_fs_device(FsDeviceConfig {
+                shm_size: Some(0),
+                ..fs("empty-dax")
+            }),
+            Err(FsConfigError::InvalidShmSize)
+        );
+
+        let tags: Vec<_> = vmr.fs.iter().map(|fs| fs.fs_id.as_str()).collect();
+        assert_eq!(tags, ["data", "cache"]);
+    }
+
+    #[cfg(not(feature = "tee"))]
+    #[test]
+    fn test_add_fs_id_mapping() {
+        use crate::vmm_config::fs::{FsConfigError, FsDeviceConfig};
+        use devices::virtio::{IdMapError, IdRange};
+
+        let mut vmr = default_vm_resources();
+        vmr.add_fs_device(FsDeviceConfig {
+            fs_id: "data".to_string(),
+            shared_dir: "/tmp".to_string(),
+            shm_size: None,
+            read_only: false,
+            uid_map: Default::default(),
+            gid_map: Default::default(),
+        })
+        .unwrap();
+
+        let range = IdRange {
+            guest_id: 0,
+            host_id: 100000,
+            count: 65536,
+        };
+        vmr.add_fs_uid_mapping("data", range).unwrap();
+        vmr.add_fs_gid_mapping("data", range).unwrap();
+        assert_eq!(vmr.fs[0].uid_map.to_host(0), Some(100000));
+        assert_eq!(vmr.fs[0].gid_map.to_guest(100001), Some(1));
+
+        assert_eq!(
+            vmr.add_fs_uid_mapping("data", range),
+            Err(FsConfigError::InvalidIdMapping(IdMapError::RangeOverlap))
+        );
+        assert_eq!(
+            vmr.add_fs_uid_mapping("cache", range),
+            Err(FsConfigError::UnknownTag("cache".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_host_limits() {
+        use crate::vmm_config::host_limits::{HostLimitsError, HostRlimit};
+
+        let mut vmr = default_vm_resources();
+        assert_eq!(
+            HostRlimit::new(7, 2048, 1024),
+            Err(HostLimitsError::InvalidRlimit)
+        );
+        vmr.set_host_rlimit(HostRlimit::new(7, 1024, 1024).unwrap());
+        vmr.set_host_rlimit(HostRlimit::new(7, 1024, 4096).unwrap());
+        assert_eq!(vmr.host_rlimits.len(), 1);
+        assert_eq!(vmr.host_rlimits[0].max, 4096);
+
+        #[cfg(target_os = "linux")]
+        {
+            use crate::vmm_config::host_limits::MAX_HOST_CPUS;
+
+            vmr.set_vcpu_affinity(1, vec![2, 3]).unwrap();
+            assert_eq!(vmr.vcpu_affinity[&1], [2, 3]);
+            assert_eq!(
+                vmr.set_vcpu_affinity(0, vec![]),
+                Err(HostLimitsError::EmptyAffinity)
+            );
+            assert_eq!(
+                vmr.set_vcpu_affinity(0, vec![0, MAX_HOST_CPUS]),
+                Err(HostLimitsError::InvalidCpu(MAX_HOST_CPUS))
+            );
+        }
     }
 
     #[test]
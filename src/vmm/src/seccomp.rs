@@ -0,0 +1,404 @@
+//! Seccomp-BPF filters confining the VMM threads to the syscalls they use.
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use libc::{
+    sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W,
+    SECCOMP_RET_ALLOW, SECCOMP_RET_LOG, SECCOMP_RET_TRAP, SECCOMP_SET_MODE_FILTER,
+};
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e; // AUDIT_ARCH_X86_64
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7; // AUDIT_ARCH_AARCH64
+
+/// Syscalls numbered from here belong to the x32 ABI on x86_64.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Offsets of the fields of `struct seccomp_data`.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// Maximum number of instructions of a BPF program (BPF_MAXINSNS).
+const BPF_MAX_LEN: usize = 4096;
+
+/// Syscalls used by the vCPU threads, the device threads they spawn and the
+/// thread running the event loop.
+const DEFAULT_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_close,
+    libc::SYS_connect,
+    libc::SYS_copy_file_range,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_fallocate,
+    libc::SYS_fchdir,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_fcntl,
+    libc::SYS_fdatasync,
+    libc::SYS_fgetxattr,
+    libc::SYS_flistxattr,
+    libc::SYS_flock,
+    libc::SYS_fremovexattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_fstat,
+    libc::SYS_fstatfs,
+    libc::SYS_fsync,
+    libc::SYS_ftruncate,
+    libc::SYS_futex,
+    libc::SYS_getcwd,
+    libc::SYS_getdents64,
+    libc::SYS_getegid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_getresgid,
+    libc::SYS_getresuid,
+    libc::SYS_getsockname,
+    libc::SYS_getsockopt,
+    libc::SYS_gettid,
+    libc::SYS_gettimeofday,
+    libc::SYS_getuid,
+    libc::SYS_getxattr,
+    libc::SYS_ioctl,
+    libc::SYS_lgetxattr,
+    libc::SYS_linkat,
+    libc::SYS_listen,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_lseek,
+    libc::SYS_lsetxattr,
+    libc::SYS_madvise,
+    libc::SYS_memfd_create,
+    libc::SYS_mkdirat,
+    libc::SYS_mknodat,
+    libc::SYS_mlock,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_munlock,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_newfstatat,
+    libc::SYS_openat,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_prctl,
+    libc::SYS_pread64,
+    libc::SYS_preadv,
+    libc::SYS_prlimit64,
+    libc::SYS_pwrite64,
+    libc::SYS_pwritev,
+    libc::SYS_read,
+    libc::SYS_readlinkat,
+    libc::SYS_readv,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_removexattr,
+    libc::SYS_renameat2,
+    libc::SYS_restart_syscall,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_set_robust_list,
+    libc::SYS_setresgid,
+    libc::SYS_setresuid,
+    libc::SYS_setsockopt,
+    libc::SYS_setxattr,
+    libc::SYS_shutdown,
+    libc::SYS_sigaltstack,
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_statfs,
+    libc::SYS_statx,
+    libc::SYS_symlinkat,
+    libc::SYS_sysinfo,
+    libc::SYS_tgkill,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_gettime,
+    libc::SYS_timerfd_settime,
+    libc::SYS_umask,
+    libc::SYS_uname,
+    libc::SYS_unlinkat,
+    libc::SYS_utimensat,
+    libc::SYS_write,
+    libc::SYS_writev,
+    // Legacy syscalls, only present on x86_64, which the C library may still use.
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_link,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_renameat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_symlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+];
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot install the filter.
+    Install(io::Error),
+    /// The BPF program is empty, too long or not made of whole instructions.
+    InvalidFilter(usize),
+    /// Cannot forbid the thread from gaining privileges.
+    NoNewPrivs(io::Error),
+    /// Cannot read the BPF program.
+    ReadFilter(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Install(e) => write!(f, "Cannot install the seccomp filter: {e}"),
+            InvalidFilter(len) => write!(f, "Invalid BPF program of {len} bytes"),
+            NoNewPrivs(e) => write!(f, "Cannot set PR_SET_NO_NEW_PRIVS: {e}"),
+            ReadFilter(e) => write!(f, "Cannot read the BPF program: {e}"),
+        }
+    }
+}
+
+/// What happens when a thread makes a syscall out of the allowlist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SeccompAction {
+    /// Don't confine the threads.
+    #[default]
+    Disabled,
+    /// Allow the syscall, but have the kernel log it, to find out what the
+    /// allowlist is missing.
+    Log,
+    /// Raise SIGSYS, which terminates the VMM with `FC_EXIT_CODE_BAD_SYSCALL`.
+    Trap,
+}
+
+/// A BPF program to install as a seccomp filter.
+#[derive(Clone)]
+pub struct SeccompFilter(Vec<sock_filter>);
+
+fn bpf_stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+impl SeccompFilter {
+    /// Builds a filter allowing `syscalls`, and taking `action` on any other one,
+    /// including the syscalls from another architecture or ABI.
+    pub fn allowlist(syscalls: &[libc::c_long], action: SeccompAction) -> Option<Self> {
+        let action = match action {
+            SeccompAction::Disabled => return None,
+            SeccompAction::Log => SECCOMP_RET_LOG,
+            SeccompAction::Trap => SECCOMP_RET_TRAP,
+        };
+
+        let mut program = vec![
+            bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH),
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            bpf_stmt(BPF_RET | BPF_K, action),
+            bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR),
+            bpf_jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+            bpf_stmt(BPF_RET | BPF_K, action),
+        ];
+        for &syscall in syscalls {
+            program.push(bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, syscall as u32, 0, 1));
+            program.push(bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        }
+        program.push(bpf_stmt(BPF_RET | BPF_K, action));
+
+        Some(SeccompFilter(program))
+    }
+
+    /// Loads a BPF program from `path`, made of `struct sock_filter` instructions
+    /// in host byte order, replacing the default allowlist.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let data = fs::read(path).map_err(Error::ReadFilter)?;
+
+        let insn_size = std::mem::size_of::<sock_filter>();
+        if data.is_empty() || data.len() % insn_size != 0 || data.len() / insn_size > BPF_MAX_LEN {
+            return Err(Error::InvalidFilter(data.len()));
+        }
+
+        let program = data
+            .chunks_exact(insn_size)
+            .map(|insn| sock_filter {
+                code: u16::from_ne_bytes([insn[0], insn[1]]),
+                jt: insn[2],
+                jf: insn[3],
+                k: u32::from_ne_bytes([insn[4], insn[5], insn[6], insn[7]]),
+            })
+            .collect();
+
+        Ok(SeccompFilter(program))
+    }
+
+    /// Installs the filter on the calling thread. The threads it spawns from
+    /// then on inherit it.
+    pub fn apply(&self) -> Result<(), Error> {
+        // Safe because this only sets a flag on the calling thread.
+        let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+        if ret != 0 {
+            return Err(Error::NoNewPrivs(io::Error::last_os_error()));
+        }
+
+        let prog = sock_fprog {
+            len: self.0.len() as u16,
+            filter: self.0.as_ptr() as *mut sock_filter,
+        };
+
+        // Safe because `prog` points to a program that outlives the call, and
+        // the kernel validates it.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                0,
+                &prog as *const sock_fprog,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Install(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the filter allowing the syscalls the VMM threads use, taking `action`
+/// on any other one.
+pub fn default_filter(action: SeccompAction) -> Option<SeccompFilter> {
+    SeccompFilter::allowlist(DEFAULT_SYSCALLS, action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::thread;
+
+    #[test]
+    fn test_allowlist() {
+        assert!(SeccompFilter::allowlist(&[libc::SYS_read], SeccompAction::Disabled).is_none());
+
+        let filter = SeccompFilter::allowlist(&[libc::SYS_read], SeccompAction::Trap).unwrap();
+        let program = &filter.0;
+        assert_eq!(program.len(), 9);
+        assert_eq!(program[1].k, AUDIT_ARCH);
+        assert_eq!(program[2].k, SECCOMP_RET_TRAP);
+        assert_eq!(program[5].k, SECCOMP_RET_TRAP);
+        assert_eq!(program[6].k, libc::SYS_read as u32);
+        assert_eq!(program[7].k, SECCOMP_RET_ALLOW);
+        assert_eq!(program[8].k, SECCOMP_RET_TRAP);
+
+        // Only logged in this mode, even from another architecture or ABI.
+        let filter = SeccompFilter::allowlist(&[libc::SYS_read], SeccompAction::Log).unwrap();
+        let program = &filter.0;
+        assert_eq!(program[2].k, SECCOMP_RET_LOG);
+        assert_eq!(program[5].k, SECCOMP_RET_LOG);
+        assert_eq!(program[8].k, SECCOMP_RET_LOG);
+    }
+
+    #[test]
+    fn test_from_file() {
+        let filter = SeccompFilter::allowlist(&[libc::SYS_read], SeccompAction::Log).unwrap();
+        let mut data = Vec::new();
+        for insn in &filter.0 {
+            data.extend_from_slice(&insn.code.to_ne_bytes());
+            data.extend_from_slice(&[insn.jt, insn.jf]);
+            data.extend_from_slice(&insn.k.to_ne_bytes());
+        }
+
+        let dir = std::env::temp_dir().join(format!("krun-seccomp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("filter.bpf");
+        fs::File::create(&path).unwrap().write_all(&data).unwrap();
+        let loaded = SeccompFilter::from_file(&path).unwrap();
+        assert_eq!(loaded.0.len(), filter.0.len());
+        assert!(loaded
+            .0
+            .iter()
+            .zip(&filter.0)
+            .all(|(a, b)| a.code == b.code && a.jt == b.jt && a.jf == b.jf && a.k == b.k));
+
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(matches!(
+            SeccompFilter::from_file(&path),
+            Err(Error::InvalidFilter(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply() {
+        // The filter outlives the thread it's installed on, so don't touch the
+        // test runner one.
+        thread::spawn(|| {
+            default_filter(SeccompAction::Log).unwrap().apply().unwrap();
+            assert!(std::process::id() > 0);
+        })
+        .join()
+        .unwrap();
+    }
+}
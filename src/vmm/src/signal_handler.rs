@@ -126,6 +126,11 @@ pub fn register_sigint_handler(sigint_fd: RawFd) -> utils::errno::Result<()> {
     Ok(())
 }
 
+/// Registers the handler reporting the syscalls trapped by a seccomp filter.
+pub fn register_sigsys_handler() -> utils::errno::Result<()> {
+    register_signal_handler(SIGSYS, sigsys_handler)
+}
+
 /// Registers all the required signal handlers.
 ///
 /// Custom handlers are installed for: `SIGBUS`, `SIGSEGV`, `SIGSYS`.
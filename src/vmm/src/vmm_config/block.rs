@@ -2,12 +2,14 @@ use std::collections::VecDeque;
 use std::fmt;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{block::ImageType, Block, CacheType};
+use devices::virtio::{block::ImageType, Block, CacheType, IoRateLimit};
 
 #[derive(Debug)]
 pub enum BlockConfigError {
     /// Failed to create the block device.
     CreateBlockDevice(std::io::Error),
+    /// Another block device already uses this ID.
+    DuplicateBlockId(String),
 }
 
 impl fmt::Display for BlockConfigError {
@@ -15,6 +17,7 @@ impl fmt::Display for BlockConfigError {
         use self::BlockConfigError::*;
         match *self {
             CreateBlockDevice(ref e) => write!(f, "Cannot create block device: {:?}", e),
+            DuplicateBlockId(ref id) => write!(f, "Duplicate block device ID: {}", id),
         }
     }
 }
@@ -28,6 +31,7 @@ pub struct BlockDeviceConfig {
     pub disk_image_path: String,
     pub disk_image_format: ImageType,
     pub is_disk_read_only: bool,
+    pub rate_limit: Option<IoRateLimit>,
 }
 
 #[derive(Default)]
@@ -42,14 +46,24 @@ impl BlockBuilder {
         }
     }
 
+    /// Adds a block device. The guest sees the devices in the order they were
+    /// inserted, i.e. the first one as /dev/vda, the second one as /dev/vdb...
     pub fn insert(&mut self, config: BlockDeviceConfig) -> Result<()> {
+        if self
+            .list
+            .iter()
+            .any(|block| *block.lock().unwrap().id() == config.block_id)
+        {
+            return Err(BlockConfigError::DuplicateBlockId(config.block_id));
+        }
+
         let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
         self.list.push_back(block_dev);
         Ok(())
     }
 
     pub fn create_block(config: BlockDeviceConfig) -> Result<Block> {
-        devices::virtio::Block::new(
+        let mut block = devices::virtio::Block::new(
             config.block_id,
             None,
             config.cache_type,
@@ -57,6 +71,10 @@ impl BlockBuilder {
             config.disk_image_format,
             config.is_disk_read_only,
         )
-        .map_err(BlockConfigError::CreateBlockDevice)
+        .map_err(BlockConfigError::CreateBlockDevice)?;
+        if let Some(rate_limit) = config.rate_limit {
+            block.set_rate_limit(rate_limit);
+        }
+        Ok(block)
     }
 }
@@ -20,9 +20,82 @@ pub struct BootSourceConfig {
     /// The boot arguments to pass to the kernel. If this field is uninitialized, the default
     /// kernel command line is used: `reboot=k panic=1 pci=off nomodule 8250.nr_uarts=0`.
     pub kernel_cmdline_prolog: Option<String>,
+    /// Kernel parameters added after the prolog. A parameter with the same key
+    /// as one in the prolog, such as `console=`, replaces it.
+    pub kernel_cmdline_append: Option<String>,
     pub kernel_cmdline_epilog: Option<String>,
 }
 
+impl BootSourceConfig {
+    /// Returns the kernel command line preceding the epilog: the prolog, or the
+    /// default one, with the appended parameters merged in.
+    pub fn kernel_cmdline(&self) -> String {
+        let prolog = self
+            .kernel_cmdline_prolog
+            .as_deref()
+            .unwrap_or(DEFAULT_KERNEL_CMDLINE);
+
+        match &self.kernel_cmdline_append {
+            Some(append) => merge_kernel_params(prolog, append),
+            None => prolog.to_string(),
+        }
+    }
+}
+
+/// Splits a kernel command line into its parameters, keeping double-quoted
+/// values containing spaces together.
+fn split_kernel_params(cmdline: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+
+    for (i, c) in cmdline.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(s) = start.take() {
+                    params.push(&cmdline[s..i]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(s) = start {
+        params.push(&cmdline[s..]);
+    }
+
+    params
+}
+
+/// Returns the key of a kernel parameter, that is the whole parameter for flags.
+fn kernel_param_key(param: &str) -> &str {
+    param.split_once('=').map(|(key, _)| key).unwrap_or(param)
+}
+
+/// Appends `extra` to `base`, dropping the parameters of both that are
+/// overridden by a later one with the same key.
+fn merge_kernel_params(base: &str, extra: &str) -> String {
+    let params: Vec<&str> = split_kernel_params(base)
+        .into_iter()
+        .chain(split_kernel_params(extra))
+        .collect();
+
+    params
+        .iter()
+        .enumerate()
+        .filter(|(i, param)| {
+            let key = kernel_param_key(param);
+            !params[i + 1..]
+                .iter()
+                .any(|later| kernel_param_key(later) == key)
+        })
+        .map(|(_, param)| *param)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
 /// Errors associated with actions on `BootSourceConfig`.
 #[derive(Debug)]
 pub enum BootSourceConfigError {
@@ -40,3 +113,33 @@ impl Display for BootSourceConfigError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_kernel_params() {
+        assert_eq!(
+            merge_kernel_params("console=hvc0 rw quiet", "console=ttyS0 quiet debug"),
+            "rw console=ttyS0 quiet debug"
+        );
+        assert_eq!(
+            merge_kernel_params("A=\"x y\" rw", "  A=\"z w\"  B=1 B=2 "),
+            "rw A=\"z w\" B=2"
+        );
+        assert_eq!(merge_kernel_params("rw quiet", ""), "rw quiet");
+    }
+
+    #[test]
+    fn test_kernel_cmdline() {
+        let mut config = BootSourceConfig {
+            kernel_cmdline_prolog: Some("console=hvc0 rw".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(config.kernel_cmdline(), "console=hvc0 rw");
+
+        config.kernel_cmdline_append = Some("ro console=ttyS0".to_string());
+        assert_eq!(config.kernel_cmdline(), "rw ro console=ttyS0");
+    }
+}
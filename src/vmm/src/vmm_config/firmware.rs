@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+/// Largest size of the firmware image and its varstore together, so they fit
+/// in the top 16 MiB of the 32-bit address space.
+pub const FIRMWARE_MAX_SIZE: u64 = 16 << 20;
+
+const FIRMWARE_ALIGNMENT: u64 = 4096;
+
+/// Configuration of a UEFI firmware (e.g. OVMF) booting the guest in place of
+/// the kernel bundle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirmwareConfig {
+    /// Firmware image, mapped right below 4 GiB so it runs from the reset vector.
+    pub path: PathBuf,
+    /// NVRAM varstore holding the EFI variables, mapped right below the image.
+    /// The variables the guest writes to it are kept in the file.
+    pub varstore: Option<PathBuf>,
+}
+
+impl FirmwareConfig {
+    /// Checks the image and the varstore, if any, are non-empty files whose
+    /// sizes are page multiples and fit in `FIRMWARE_MAX_SIZE` together. The
+    /// varstore must be writable.
+    pub fn new(path: PathBuf, varstore: Option<PathBuf>) -> io::Result<Self> {
+        let mut size = firmware_file_size(&File::open(&path)?)?;
+        if let Some(varstore) = &varstore {
+            // The contents of the varstore wouldn't be part of the measurement.
+            if cfg!(feature = "tee") {
+                return Err(io::Error::from(io::ErrorKind::Unsupported));
+            }
+            let file = OpenOptions::new().read(true).write(true).open(varstore)?;
+            size += firmware_file_size(&file)?;
+        }
+        if size > FIRMWARE_MAX_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        Ok(FirmwareConfig { path, varstore })
+    }
+
+    /// Reads the firmware image, checking it still fits in `FIRMWARE_MAX_SIZE`.
+    pub fn read_image(&self) -> io::Result<Vec<u8>> {
+        let mut file = File::open(&self.path)?;
+        let size = firmware_file_size(&file)?;
+        if size > FIRMWARE_MAX_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let mut image = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut image)?;
+        Ok(image)
+    }
+
+    /// Opens the varstore, if any, along with its size.
+    pub fn open_varstore(&self) -> io::Result<Option<(File, u64)>> {
+        let Some(path) = &self.varstore else {
+            return Ok(None);
+        };
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let size = firmware_file_size(&file)?;
+        Ok(Some((file, size)))
+    }
+}
+
+fn firmware_file_size(file: &File) -> io::Result<u64> {
+    let size = file.metadata()?.len();
+    if size == 0 || size % FIRMWARE_ALIGNMENT != 0 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempfile::TempFile;
+
+    fn sized_file(size: u64) -> TempFile {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(size).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_firmware_config() {
+        let image = sized_file(2 << 20);
+        let varstore = sized_file(512 << 10);
+
+        let config = FirmwareConfig::new(image.as_path().to_path_buf(), None).unwrap();
+        assert_eq!(config.varstore, None);
+        assert_eq!(config.read_image().unwrap().len(), 2 << 20);
+        assert!(config.open_varstore().unwrap().is_none());
+        #[cfg(not(feature = "tee"))]
+        {
+            let config = FirmwareConfig::new(
+                image.as_path().to_path_buf(),
+                Some(varstore.as_path().to_path_buf()),
+            )
+            .unwrap();
+            let (_file, size) = config.open_varstore().unwrap().unwrap();
+            assert_eq!(size, 512 << 10);
+        }
+        #[cfg(feature = "tee")]
+        assert_eq!(
+            FirmwareConfig::new(
+                image.as_path().to_path_buf(),
+                Some(varstore.as_path().to_path_buf()),
+            )
+            .unwrap_err()
+            .kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_invalid_firmware_config() {
+        let invalid = |size| {
+            let image = sized_file(size);
+            FirmwareConfig::new(image.as_path().to_path_buf(), None)
+                .unwrap_err()
+                .kind()
+        };
+
+        assert_eq!(invalid(0), io::ErrorKind::InvalidInput);
+        assert_eq!(invalid(0x1800), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            invalid(FIRMWARE_MAX_SIZE + 0x1000),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            FirmwareConfig::new(PathBuf::from("/nonexistent/OVMF.fd"), None)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+
+        #[cfg(not(feature = "tee"))]
+        {
+            let image = sized_file(FIRMWARE_MAX_SIZE - 0x1000);
+            let varstore = sized_file(0x2000);
+            assert_eq!(
+                FirmwareConfig::new(
+                    image.as_path().to_path_buf(),
+                    Some(varstore.as_path().to_path_buf()),
+                )
+                .unwrap_err()
+                .kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+}
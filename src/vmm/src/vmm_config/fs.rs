@@ -1,6 +1,51 @@
+use std::fmt;
+
+use devices::virtio::{IdMap, IdMapError};
+
+/// Size of the tag field in the virtio-fs config space.
+pub const FS_TAG_MAX_LEN: usize = 36;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FsConfigError {
+    /// Another virtio-fs device already uses this tag.
+    DuplicateTag(String),
+    /// The tag is empty or doesn't fit in the device config space.
+    InvalidTag(String),
+    /// The DAX window can't be empty.
+    InvalidShmSize,
+    /// No virtio-fs device uses this tag.
+    UnknownTag(String),
+    /// The ID mapping range is invalid or overlaps another one.
+    InvalidIdMapping(IdMapError),
+}
+
+impl fmt::Display for FsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FsConfigError::*;
+        match *self {
+            DuplicateTag(ref tag) => write!(f, "Duplicate virtio-fs tag: {}", tag),
+            InvalidTag(ref tag) => write!(
+                f,
+                "Invalid virtio-fs tag {:?}, it must be 1 to {} bytes long",
+                tag, FS_TAG_MAX_LEN
+            ),
+            InvalidShmSize => write!(f, "The virtio-fs DAX window can't be empty"),
+            UnknownTag(ref tag) => write!(f, "No virtio-fs device with tag {:?}", tag),
+            InvalidIdMapping(ref e) => write!(f, "Invalid virtio-fs ID mapping: {}", e),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FsDeviceConfig {
     pub fs_id: String,
     pub shared_dir: String,
+    /// Size of the DAX window in bytes, rounded up to 2 MiB. No window if unset.
     pub shm_size: Option<usize>,
+    /// Reject any modification of the shared directory from the guest.
+    pub read_only: bool,
+    /// Translation of the user IDs between the guest and the host.
+    pub uid_map: IdMap,
+    /// Translation of the group IDs between the guest and the host.
+    pub gid_map: IdMap,
 }
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::io;
+
+/// Number of host CPUs a vCPU thread can be pinned to.
+#[cfg(target_os = "linux")]
+pub const MAX_HOST_CPUS: usize = libc::CPU_SETSIZE as usize;
+
+/// Errors associated with the limits applied to the VMM process.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostLimitsError {
+    /// The soft limit of a resource is above its hard limit.
+    InvalidRlimit,
+    /// The affinity of a vCPU doesn't include any host CPU.
+    EmptyAffinity,
+    /// A host CPU can't be part of an affinity mask.
+    InvalidCpu(usize),
+}
+
+impl fmt::Display for HostLimitsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::HostLimitsError::*;
+        match self {
+            InvalidRlimit => write!(f, "The soft limit is above the hard limit"),
+            EmptyAffinity => write!(f, "The vCPU affinity doesn't include any CPU"),
+            InvalidCpu(cpu) => write!(f, "Host CPU {cpu} can't be part of a vCPU affinity"),
+        }
+    }
+}
+
+/// Limit on a resource of the VMM process, as applied by `setrlimit(2)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostRlimit {
+    /// One of the host's `RLIMIT_*` resources.
+    pub resource: u32,
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl HostRlimit {
+    pub fn new(resource: u32, cur: u64, max: u64) -> Result<Self, HostLimitsError> {
+        if cur > max {
+            return Err(HostLimitsError::InvalidRlimit);
+        }
+        Ok(HostRlimit { resource, cur, max })
+    }
+
+    /// Applies the limit to the current process.
+    pub fn apply(&self) -> io::Result<()> {
+        let rlim = libc::rlimit {
+            rlim_cur: self.cur,
+            rlim_max: self.max,
+        };
+        // Safe because we pass a valid rlimit struct, and the kernel validates the resource.
+        if unsafe { libc::setrlimit(self.resource as _, &rlim) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt;
+use std::path::PathBuf;
 
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
@@ -15,6 +16,8 @@ pub enum VmConfigError {
     InvalidVcpuCount,
     /// The memory size is invalid. The memory can only be an unsigned integer.
     InvalidMemorySize,
+    /// The CPU topology doesn't match the vcpu count, or can't be expressed with the APIC IDs.
+    InvalidCpuTopology,
 }
 
 impl fmt::Display for VmConfigError {
@@ -27,6 +30,13 @@ impl fmt::Display for VmConfigError {
                  be 1 or an even number when hyperthreading is enabled.",
             ),
             InvalidMemorySize => write!(f, "The memory size (MiB) is invalid.",),
+            InvalidCpuTopology => write!(
+                f,
+                "The CPU topology is invalid! The sockets, cores per socket and \
+                 threads per core must multiply to the vCPU number, and the threads \
+                 per core, as well as the cores per socket when there are several \
+                 sockets, must be powers of 2.",
+            ),
         }
     }
 }
@@ -43,6 +53,9 @@ pub struct VmConfig {
     pub ht_enabled: Option<bool>,
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// How the vCPUs are laid out in sockets, cores and threads. Takes precedence over
+    /// `ht_enabled`, which puts every vCPU in a single socket.
+    pub cpu_topology: Option<CpuTopology>,
 }
 
 impl Default for VmConfig {
@@ -52,6 +65,7 @@ impl Default for VmConfig {
             mem_size_mib: Some(128),
             ht_enabled: Some(false),
             cpu_template: None,
+            cpu_topology: None,
         }
     }
 }
@@ -69,14 +83,118 @@ impl fmt::Display for VmConfig {
     }
 }
 
-/// Template types available for configuring the CPU features that map
-/// to EC2 instances.
+/// Layout of the vCPUs exposed to the guest through CPUID. The vCPUs are
+/// numbered thread first, then core, then socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CpuTopology {
+    pub sockets: u8,
+    pub cores_per_socket: u8,
+    pub threads_per_core: u8,
+}
+
+impl CpuTopology {
+    /// Returns the number of vCPUs in this topology.
+    pub fn vcpu_count(&self) -> u16 {
+        u16::from(self.sockets)
+            * u16::from(self.cores_per_socket)
+            * u16::from(self.threads_per_core)
+    }
+
+    /// Whether the APIC ID of each vCPU can be its index. This requires the
+    /// threads per core, and the cores per socket when there are several
+    /// sockets, to be powers of 2, so there are no gaps between the IDs.
+    pub fn is_valid(&self) -> bool {
+        self.sockets > 0
+            && self.cores_per_socket > 0
+            && self.threads_per_core.is_power_of_two()
+            && (self.sockets == 1 || self.cores_per_socket.is_power_of_two())
+    }
+}
+
+/// Size of the hugepages backing the guest memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HugePageSize {
+    /// 2 MiB pages.
+    Size2M,
+    /// 1 GiB pages.
+    Size1G,
+}
+
+impl HugePageSize {
+    /// Returns the page size from its value in KiB, if supported.
+    pub fn from_kib(kib: u32) -> Option<Self> {
+        match kib {
+            0x800 => Some(HugePageSize::Size2M),
+            0x10_0000 => Some(HugePageSize::Size1G),
+            _ => None,
+        }
+    }
+
+    /// Returns the page size in bytes.
+    pub fn bytes(&self) -> usize {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+}
+
+/// Hugepages configuration for the guest memory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HugePagesConfig {
+    /// Size of the pages, which must divide the size of each guest memory region.
+    pub page_size: HugePageSize,
+    /// Directory in a hugetlbfs mount the memory is allocated from. If not set,
+    /// anonymous hugepages are used.
+    pub hugetlbfs_path: Option<PathBuf>,
+}
+
+/// Clocksource the guest kernel keeps time with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Clocksource {
+    /// The paravirtualized KVM clock, which the guest prefers by default.
+    KvmClock,
+    /// The TSC, trusted by the guest without watchdog checks. It's cheaper to
+    /// read than the KVM clock, but only stays correct across hosts when its
+    /// frequency is pinned.
+    Tsc,
+}
+
+impl Clocksource {
+    /// Returns the kernel command line selecting this clocksource.
+    pub fn cmdline(&self) -> &'static str {
+        match self {
+            Clocksource::KvmClock => "clocksource=kvm-clock",
+            Clocksource::Tsc => "clocksource=tsc tsc=reliable",
+        }
+    }
+}
+
+/// Template types available for configuring the CPU features, either to map
+/// to EC2 instances or to a microarchitecture level of the x86-64 psABI.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum CpuFeaturesTemplate {
     /// C3 Template.
     C3,
     /// T2 Template.
     T2,
+    /// Up to SSE4.2 and POPCNT, masking AVX and later extensions.
+    X86_64V2,
+    /// Up to AVX2, FMA and BMI2, masking AVX-512.
+    X86_64V3,
+}
+
+impl CpuFeaturesTemplate {
+    /// Returns the template with the given name, as displayed.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "C3" => Some(CpuFeaturesTemplate::C3),
+            "T2" => Some(CpuFeaturesTemplate::T2),
+            "x86-64-v2" => Some(CpuFeaturesTemplate::X86_64V2),
+            "x86-64-v3" => Some(CpuFeaturesTemplate::X86_64V3),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for CpuFeaturesTemplate {
@@ -84,6 +202,8 @@ impl fmt::Display for CpuFeaturesTemplate {
         match self {
             CpuFeaturesTemplate::C3 => write!(f, "C3"),
             CpuFeaturesTemplate::T2 => write!(f, "T2"),
+            CpuFeaturesTemplate::X86_64V2 => write!(f, "x86-64-v2"),
+            CpuFeaturesTemplate::X86_64V3 => write!(f, "x86-64-v3"),
         }
     }
 }
@@ -96,6 +216,26 @@ mod tests {
     fn test_display_cpu_features_template() {
         assert_eq!(CpuFeaturesTemplate::C3.to_string(), "C3".to_string());
         assert_eq!(CpuFeaturesTemplate::T2.to_string(), "T2".to_string());
+        assert_eq!(
+            CpuFeaturesTemplate::X86_64V2.to_string(),
+            "x86-64-v2".to_string()
+        );
+    }
+
+    #[test]
+    fn test_cpu_features_template_from_name() {
+        for template in [
+            CpuFeaturesTemplate::C3,
+            CpuFeaturesTemplate::T2,
+            CpuFeaturesTemplate::X86_64V2,
+            CpuFeaturesTemplate::X86_64V3,
+        ] {
+            assert_eq!(
+                CpuFeaturesTemplate::from_name(&template.to_string()),
+                Some(template)
+            );
+        }
+        assert_eq!(CpuFeaturesTemplate::from_name("x86-64-v4"), None);
     }
 
     #[test]
@@ -106,5 +246,54 @@ mod tests {
 
         let expected_str = "The memory size (MiB) is invalid.";
         assert_eq!(VmConfigError::InvalidMemorySize.to_string(), expected_str);
+
+        let expected_str = "The CPU topology is invalid! The sockets, cores per socket and \
+                            threads per core must multiply to the vCPU number, and the threads \
+                            per core, as well as the cores per socket when there are several \
+                            sockets, must be powers of 2.";
+        assert_eq!(VmConfigError::InvalidCpuTopology.to_string(), expected_str);
+    }
+
+    #[test]
+    fn test_cpu_topology() {
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 4,
+            threads_per_core: 2,
+        };
+        assert!(topology.is_valid());
+        assert_eq!(topology.vcpu_count(), 16);
+
+        // Cores per socket only need to be a power of 2 with several sockets.
+        assert!(CpuTopology {
+            sockets: 1,
+            cores_per_socket: 3,
+            threads_per_core: 1,
+        }
+        .is_valid());
+        assert!(!CpuTopology {
+            cores_per_socket: 3,
+            ..topology
+        }
+        .is_valid());
+        assert!(!CpuTopology {
+            threads_per_core: 3,
+            ..topology
+        }
+        .is_valid());
+        assert!(!CpuTopology {
+            sockets: 0,
+            ..topology
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_hugepage_size() {
+        assert_eq!(HugePageSize::from_kib(2048), Some(HugePageSize::Size2M));
+        assert_eq!(HugePageSize::from_kib(1 << 20), Some(HugePageSize::Size1G));
+        assert_eq!(HugePageSize::from_kib(4), None);
+        assert_eq!(HugePageSize::Size2M.bytes(), 0x20_0000);
+        assert_eq!(HugePageSize::Size1G.bytes(), 0x4000_0000);
     }
 }
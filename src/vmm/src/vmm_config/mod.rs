@@ -8,10 +8,17 @@ pub mod block;
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
 
+/// Wrapper for configuring the UEFI firmware booting the microVM.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub mod firmware;
+
 /// Wrapper for configuring the Fs devices attached to the microVM.
 #[cfg(not(feature = "tee"))]
 pub mod fs;
 
+/// Wrapper for configuring the limits applied to the VMM process and its threads.
+pub mod host_limits;
+
 /// Wrapper over the microVM general information attached to the microVM.
 pub mod instance_info;
 
@@ -24,6 +31,14 @@ pub mod machine_config;
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
 
+/// Wrapper for configuring the NUMA topology of the microVM.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+pub mod numa;
+
 /// Wrapper for configuring the network devices attached to the microVM.
 #[cfg(feature = "net")]
 pub mod net;
+
+/// Wrapper for configuring the guest watchdog.
+#[cfg(target_arch = "x86_64")]
+pub mod watchdog;
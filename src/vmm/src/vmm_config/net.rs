@@ -6,7 +6,7 @@ use std::result;
 use std::sync::{Arc, Mutex};
 
 use devices::virtio::net::device::VirtioNetBackend;
-use devices::virtio::Net;
+use devices::virtio::{IoRateLimit, Net};
 
 pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
@@ -15,6 +15,12 @@ pub struct NetworkInterfaceConfig {
     pub backend: VirtioNetBackend,
     /// MAC address.
     pub mac: [u8; 6],
+    /// Limit of the traffic in each direction.
+    pub rate_limit: Option<IoRateLimit>,
+    /// MTU advertised to the guest.
+    pub mtu: Option<u16>,
+    /// Whether to move the datapath to vhost-net.
+    pub vhost: bool,
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -66,18 +72,18 @@ impl NetBuilder {
     /// Builds a network device based on a network interface config. Keeps a device reference
     /// in the builder's internal list.
     pub fn build(&mut self, netif_config: NetworkInterfaceConfig) -> Result<Arc<Mutex<Net>>> {
-        // If this is an update, just remove the old one.
-        if let Some(index) = self
+        // If this is an update, replace the old one in place so the order the
+        // guest enumerates the devices in doesn't change.
+        let index = self
             .net_devices
             .iter()
-            .position(|net| net.lock().expect("Poisoned lock").id() == netif_config.iface_id)
-        {
-            self.net_devices.swap_remove(index);
-        }
+            .position(|net| net.lock().expect("Poisoned lock").id() == netif_config.iface_id);
 
-        // Add new device.
         let net = Arc::new(Mutex::new(Self::create_net(netif_config)?));
-        self.net_devices.push(net.clone());
+        match index {
+            Some(index) => self.net_devices[index] = net.clone(),
+            None => self.net_devices.push(net.clone()),
+        }
 
         Ok(net)
     }
@@ -85,7 +91,17 @@ impl NetBuilder {
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
         // Create and return the Net device
-        Net::new(cfg.iface_id, cfg.backend, cfg.mac)
-            .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let mut net = Net::new(cfg.iface_id, cfg.backend, cfg.mac)
+            .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if let Some(rate_limit) = cfg.rate_limit {
+            net.set_rate_limit(rate_limit);
+        }
+        if let Some(mtu) = cfg.mtu {
+            net.set_mtu(mtu)
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        net.set_vhost(cfg.vhost)
+            .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        Ok(net)
     }
 }
@@ -0,0 +1,332 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::io;
+
+use vm_memory::GuestAddress;
+
+/// Number of host NUMA nodes a guest node can be bound to.
+pub const MAX_HOST_NODES: u32 = 1024;
+
+/// Distance from a node to itself.
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance between nodes when not set.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+// From linux/mempolicy.h.
+const MPOL_BIND: libc::c_ulong = 2;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// Errors associated with the NUMA topology of the guest.
+#[derive(Debug, PartialEq, Eq)]
+pub enum NumaConfigError {
+    /// A node has no memory or no vCPUs.
+    EmptyNode,
+    /// A host node can't be bound to.
+    InvalidHostNode(u32),
+    /// The distances aren't a valid matrix for the nodes.
+    InvalidDistances,
+    /// The memory of the nodes doesn't add up to the guest memory.
+    MemorySizeMismatch,
+    /// A vCPU isn't part of any node.
+    UnassignedVcpu(u8),
+    /// A vCPU is part of more than one node.
+    DuplicateVcpu(u8),
+    /// A vCPU of a node doesn't exist.
+    UnknownVcpu(u8),
+}
+
+impl fmt::Display for NumaConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::NumaConfigError::*;
+        match self {
+            EmptyNode => write!(f, "A NUMA node needs memory and vCPUs"),
+            InvalidHostNode(node) => write!(f, "Host NUMA node {node} can't be bound to"),
+            InvalidDistances => write!(f, "The NUMA distances are invalid"),
+            MemorySizeMismatch => write!(
+                f,
+                "The memory of the NUMA nodes doesn't match the guest memory"
+            ),
+            UnassignedVcpu(vcpu) => write!(f, "vCPU {vcpu} isn't part of any NUMA node"),
+            DuplicateVcpu(vcpu) => write!(f, "vCPU {vcpu} is part of more than one NUMA node"),
+            UnknownVcpu(vcpu) => write!(f, "vCPU {vcpu} of a NUMA node doesn't exist"),
+        }
+    }
+}
+
+/// A NUMA node of the guest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NumaNodeConfig {
+    /// Memory of the node, in MiB.
+    pub mem_size_mib: usize,
+    /// Indexes of the vCPUs of the node.
+    pub vcpus: Vec<u8>,
+    /// Host node the memory of the node is bound to, if any.
+    pub host_node: Option<u32>,
+}
+
+impl NumaNodeConfig {
+    pub fn new(
+        mem_size_mib: usize,
+        vcpus: Vec<u8>,
+        host_node: Option<u32>,
+    ) -> Result<Self, NumaConfigError> {
+        if mem_size_mib == 0 || vcpus.is_empty() {
+            return Err(NumaConfigError::EmptyNode);
+        }
+        if let Some(node) = host_node.filter(|&node| node >= MAX_HOST_NODES) {
+            return Err(NumaConfigError::InvalidHostNode(node));
+        }
+        Ok(NumaNodeConfig {
+            mem_size_mib,
+            vcpus,
+            host_node,
+        })
+    }
+
+    /// Binds the host memory backing a range of the node to its host node, moving the
+    /// pages already allocated.
+    ///
+    /// # Safety
+    ///
+    /// The range must be part of a mapping backing the guest memory.
+    pub unsafe fn bind(&self, host_addr: *mut u8, len: usize) -> io::Result<()> {
+        let node = match self.host_node {
+            Some(node) => node as usize,
+            None => return Ok(()),
+        };
+        let bits = libc::c_ulong::BITS as usize;
+        let mut nodemask = vec![0 as libc::c_ulong; MAX_HOST_NODES as usize / bits];
+        nodemask[node / bits] |= 1 << (node % bits);
+
+        // The kernel checks the mask against the number of nodes we pass.
+        let ret = libc::syscall(
+            libc::SYS_mbind,
+            host_addr,
+            len,
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            MAX_HOST_NODES as libc::c_ulong,
+            MPOL_MF_MOVE,
+        );
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The NUMA topology of the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NumaConfig {
+    pub nodes: Vec<NumaNodeConfig>,
+    /// Distances between the nodes, row by row.
+    pub distances: Option<Vec<u8>>,
+}
+
+impl NumaConfig {
+    /// Sets the distances between the nodes, given as a `num_nodes` x `num_nodes` matrix.
+    pub fn set_distances(
+        &mut self,
+        distances: Vec<u8>,
+        num_nodes: usize,
+    ) -> Result<(), NumaConfigError> {
+        if num_nodes == 0 || distances.len() != num_nodes * num_nodes {
+            return Err(NumaConfigError::InvalidDistances);
+        }
+        for (i, row) in distances.chunks(num_nodes).enumerate() {
+            for (j, &distance) in row.iter().enumerate() {
+                let valid = if i == j {
+                    distance == LOCAL_DISTANCE
+                } else {
+                    distance > LOCAL_DISTANCE && distance != u8::MAX
+                };
+                if !valid {
+                    return Err(NumaConfigError::InvalidDistances);
+                }
+            }
+        }
+
+        self.distances = Some(distances);
+        Ok(())
+    }
+
+    /// Checks the nodes cover the memory and the vCPUs of the guest, each only once.
+    pub fn validate(&self, mem_size_mib: usize, vcpu_count: u8) -> Result<(), NumaConfigError> {
+        let total: usize = self.nodes.iter().map(|node| node.mem_size_mib).sum();
+        if total != mem_size_mib {
+            return Err(NumaConfigError::MemorySizeMismatch);
+        }
+
+        let mut assigned = vec![false; vcpu_count as usize];
+        for &vcpu in self.nodes.iter().flat_map(|node| node.vcpus.iter()) {
+            match assigned.get_mut(vcpu as usize) {
+                None => return Err(NumaConfigError::UnknownVcpu(vcpu)),
+                Some(true) => return Err(NumaConfigError::DuplicateVcpu(vcpu)),
+                Some(assigned) => *assigned = true,
+            }
+        }
+        if let Some(vcpu) = assigned.iter().position(|assigned| !assigned) {
+            return Err(NumaConfigError::UnassignedVcpu(vcpu as u8));
+        }
+
+        if let Some(distances) = &self.distances {
+            if distances.len() != self.nodes.len() * self.nodes.len() {
+                return Err(NumaConfigError::InvalidDistances);
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the guest RAM, given as ranges in address order, between the nodes. The memory
+    /// of each node is taken in order, and the last node also gets what the architecture adds
+    /// to the configured size, such as the kernel region.
+    pub fn layout(&self, ram: &[(GuestAddress, u64)]) -> Vec<arch::NumaNode> {
+        let num_nodes = self.nodes.len();
+        let mut ram = ram.iter().copied();
+        let mut range = ram.next();
+
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| {
+                let mut memory = Vec::new();
+                let mut remaining = if index == num_nodes - 1 {
+                    u64::MAX
+                } else {
+                    (node.mem_size_mib as u64) << 20
+                };
+                while let Some((start, len)) = range {
+                    if remaining == 0 {
+                        break;
+                    }
+                    let size = len.min(remaining);
+                    memory.push((start, size));
+                    remaining -= size;
+                    range = if size == len {
+                        ram.next()
+                    } else {
+                        Some((GuestAddress(start.0 + size), len - size))
+                    };
+                }
+
+                let distances = match &self.distances {
+                    Some(distances) => {
+                        distances[index * num_nodes..(index + 1) * num_nodes].to_vec()
+                    }
+                    None => (0..num_nodes)
+                        .map(|i| {
+                            if i == index {
+                                LOCAL_DISTANCE
+                            } else {
+                                REMOTE_DISTANCE
+                            }
+                        })
+                        .collect(),
+                };
+
+                arch::NumaNode {
+                    memory,
+                    vcpus: node.vcpus.clone(),
+                    distances,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> NumaConfig {
+        NumaConfig {
+            nodes: vec![
+                NumaNodeConfig::new(1024, vec![0, 1], None).unwrap(),
+                NumaNodeConfig::new(3072, vec![2, 3], Some(1)).unwrap(),
+            ],
+            distances: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = config();
+        assert!(config.validate(4096, 4).is_ok());
+        assert_eq!(
+            config.validate(2048, 4),
+            Err(NumaConfigError::MemorySizeMismatch)
+        );
+        assert_eq!(
+            config.validate(4096, 5),
+            Err(NumaConfigError::UnassignedVcpu(4))
+        );
+        assert_eq!(
+            config.validate(4096, 3),
+            Err(NumaConfigError::UnknownVcpu(3))
+        );
+
+        config.nodes[1].vcpus = vec![1, 2, 3];
+        assert_eq!(
+            config.validate(4096, 4),
+            Err(NumaConfigError::DuplicateVcpu(1))
+        );
+
+        assert_eq!(
+            NumaNodeConfig::new(0, vec![0], None),
+            Err(NumaConfigError::EmptyNode)
+        );
+        assert_eq!(
+            NumaNodeConfig::new(1024, vec![0], Some(MAX_HOST_NODES)),
+            Err(NumaConfigError::InvalidHostNode(MAX_HOST_NODES))
+        );
+    }
+
+    #[test]
+    fn test_set_distances() {
+        let mut config = config();
+        assert_eq!(
+            config.set_distances(vec![10, 20, 20], 2),
+            Err(NumaConfigError::InvalidDistances)
+        );
+        assert_eq!(
+            config.set_distances(vec![10, 20, 20, 20], 2),
+            Err(NumaConfigError::InvalidDistances)
+        );
+        assert_eq!(
+            config.set_distances(vec![10, 5, 20, 10], 2),
+            Err(NumaConfigError::InvalidDistances)
+        );
+        config.set_distances(vec![10, 21, 21, 10], 2).unwrap();
+        assert_eq!(config.validate(4096, 4), Ok(()));
+
+        config.nodes.pop();
+        assert_eq!(
+            config.validate(1024, 2),
+            Err(NumaConfigError::InvalidDistances)
+        );
+    }
+
+    #[test]
+    fn test_layout() {
+        let gap_start = 0xc000_0000;
+        let ram = [
+            (GuestAddress(0), gap_start),
+            (GuestAddress(1 << 32), (4 << 30) - gap_start),
+        ];
+        let nodes = config().layout(&ram);
+
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].memory, [(GuestAddress(0), 1 << 30)]);
+        assert_eq!(nodes[0].vcpus, [0, 1]);
+        assert_eq!(nodes[0].distances, [10, 20]);
+        assert_eq!(
+            nodes[1].memory,
+            [
+                (GuestAddress(1 << 30), gap_start - (1 << 30)),
+                (GuestAddress(1 << 32), (4 << 30) - gap_start),
+            ]
+        );
+        assert_eq!(nodes[1].distances, [20, 10]);
+    }
+}
@@ -0,0 +1,22 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use utils::eventfd::EventFd;
+
+/// What the VMM does when the guest stops kicking its watchdog.
+#[derive(Debug)]
+pub enum WatchdogAction {
+    /// Stop the microVM as a guest reboot does, with a zero exit code.
+    Reset,
+    /// Stop the microVM with a non-zero exit code.
+    PowerOff,
+    /// Signal the eventfd, leaving the guest running.
+    Notify(EventFd),
+}
+
+/// Configuration of the guest watchdog.
+#[derive(Debug)]
+pub struct WatchdogConfig {
+    /// Seconds the guest may go without kicking the watchdog, from 1 to 30.
+    pub timeout_secs: u32,
+    pub action: WatchdogAction,
+}